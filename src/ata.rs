@@ -1,33 +1,35 @@
 // src/ata.rs
 
-use crate::println;
+use crate::block::{check_request, BlockDevice, BlockError, SECTOR_SIZE};
+use crate::{println, serial_println};
 use x86_64::instructions::port::Port;
 use alloc::vec::Vec;
 use alloc::vec;
+use spin::Mutex;
 
 /// Primary IDE channel base port addresses (for master and slave)
 const PRIMARY_DATA_PORT: u16 = 0x1F0;
 #[allow(dead_code)]
 const PRIMARY_ERROR_PORT: u16 = 0x1F1; // Read-only
-#[allow(dead_code)]
-const PRIMARY_FEATURES_PORT: u16 = 0x1F2; // Write-only, for error recovery features (not used)
-const PRIMARY_SECTOR_COUNT_PORT: u16 = 0x1F3;
-const PRIMARY_LBA_LOW_PORT: u16 = 0x1F4;
-const PRIMARY_LBA_MID_PORT: u16 = 0x1F5;
-const PRIMARY_LBA_HIGH_PORT: u16 = 0x1F6; // Read/write, contains LBA bits and device select
-const PRIMARY_COMMAND_PORT: u16 = 0x1F7;
+const PRIMARY_FEATURES_PORT: u16 = 0x1F1; // Write-only; selects PIO or DMA for ATAPI packets
+const PRIMARY_SECTOR_COUNT_PORT: u16 = 0x1F2;
+const PRIMARY_LBA_LOW_PORT: u16 = 0x1F3;
+const PRIMARY_LBA_MID_PORT: u16 = 0x1F4;
+const PRIMARY_LBA_HIGH_PORT: u16 = 0x1F5;
+const PRIMARY_DRIVE_PORT: u16 = 0x1F6; // Device select and LBA bits 24-27
+const PRIMARY_COMMAND_PORT: u16 = 0x1F7; // Status when read
 
 /// Secondary IDE channel base port addresses (for master and slave)
 const SECONDARY_DATA_PORT: u16 = 0x170;
 #[allow(dead_code)]
 const SECONDARY_ERROR_PORT: u16 = 0x171; // Read-only
-#[allow(dead_code)]
-const SECONDARY_FEATURES_PORT: u16 = 0x172; // Write-only, for error recovery features (not used)
-const SECONDARY_SECTOR_COUNT_PORT: u16 = 0x173;
-const SECONDARY_LBA_LOW_PORT: u16 = 0x174;
-const SECONDARY_LBA_MID_PORT: u16 = 0x175;
-const SECONDARY_LBA_HIGH_PORT: u16 = 0x176; // Read/write, contains LBA bits and device select
-const SECONDARY_COMMAND_PORT: u16 = 0x177;
+const SECONDARY_FEATURES_PORT: u16 = 0x171; // Write-only; selects PIO or DMA for ATAPI packets
+const SECONDARY_SECTOR_COUNT_PORT: u16 = 0x172;
+const SECONDARY_LBA_LOW_PORT: u16 = 0x173;
+const SECONDARY_LBA_MID_PORT: u16 = 0x174;
+const SECONDARY_LBA_HIGH_PORT: u16 = 0x175;
+const SECONDARY_DRIVE_PORT: u16 = 0x176; // Device select and LBA bits 24-27
+const SECONDARY_COMMAND_PORT: u16 = 0x177; // Status when read
 
 /// ATA command codes (from the specification)
 #[allow(dead_code)]
@@ -35,12 +37,19 @@ enum AtaCommand {
     IdentifyDevice = 0xEC,
     ReadSectorsWithRetry = 0x20,
     WriteSectorsWithRetry = 0x30,
+    CacheFlush = 0xE7,
+    /// Send an ATAPI command packet
+    Packet = 0xA0,
+    IdentifyPacketDevice = 0xA1,
 }
 
+/// SCSI commands sent to ATAPI devices in a 12-byte packet
+const SCSI_READ_CAPACITY: u8 = 0x25;
+const SCSI_READ_10: u8 = 0x28;
+
 // Status register bits
 const STATUS_BSY: u8 = 1 << 7; // Busy bit (set when device is busy)
 const STATUS_DRDY: u8 = 1 << 6; // Device ready bit
-#[allow(dead_code)]
 const STATUS_DF: u8 = 1 << 5; // Drive fault error flag
 #[allow(dead_code)]
 const STATUS_DSC: u8 = 1 << 4; // Seek complete - not used in PIO mode
@@ -49,238 +58,548 @@ const STATUS_DRQ: u8 = 1 << 3; // Data request (device ready to transfer data)
 const STATUS_CORR: u8 = 1 << 2; // Corrected error bit
 #[allow(dead_code)]
 const STATUS_IDX: u8 = 1 << 1; // Index - not used in PIO mode
-#[allow(dead_code)]
 const STATUS_ERR: u8 = 1 << 0; // Error flag
 
-// Device select bits (in LBA_HIGH_PORT)
-#[allow(dead_code)]
-const DEVICE_MASTER: u8 = 0x00;
-#[allow(dead_code)]
-const DEVICE_SLAVE: u8 = 0x10;
+// Device select bits (in DRIVE_PORT)
+const DEVICE_MASTER: u8 = 0xA0; // Bits 5 and 7 are always set
+const DEVICE_SLAVE: u8 = 0xB0;
+const DEVICE_LBA: u8 = 0x40; // Address is an LBA, not CHS
+
+/// Status polls before a command is given up as timed out
+const POLL_LIMIT: u32 = 1_000_000;
+
+/// Highest sector count one LBA28 command can transfer
+const MAX_SECTORS_PER_COMMAND: usize = 256;
+
+/// Sectors addressable with 28-bit LBA
+const LBA28_LIMIT: u64 = 1 << 28;
+
+/// Signature an ATAPI device leaves in LBA mid/high after a reset or a
+/// rejected IDENTIFY
+const ATAPI_SIGNATURE: (u8, u8) = (0x14, 0xEB);
+
+/// Block size of CD and DVD media
+const ATAPI_SECTOR_SIZE: usize = 2048;
+
+/// Most ATAPI sectors read by one packet command
+const MAX_PACKET_SECTORS: usize = 32;
+
+/// Most bytes an ATAPI device may hand over per data request (the byte
+/// count limit, which must be even)
+const PACKET_BYTE_LIMIT: u16 = 0xF800;
+
+/// Tries for a packet command: the first after a reset or media change
+/// fails with a unit attention
+const PACKET_TRIES: usize = 3;
+
+/// One command at a time per channel: master and slave share the registers
+static PRIMARY_LOCK: Mutex<()> = Mutex::new(());
+static SECONDARY_LOCK: Mutex<()> = Mutex::new(());
 
 /// ATA/IDE device structure to represent a disk
 pub struct AtaDisk {
     pub channel: Channel,
     pub is_slave: bool,
+    /// An ATAPI (packet interface) device such as a CD-ROM drive: read-only
+    /// here, in `ATAPI_SECTOR_SIZE` blocks, through SCSI commands
+    pub atapi: bool,
+    /// Addressable sectors, from IDENTIFY or, for ATAPI, READ CAPACITY (0
+    /// until `init` succeeds)
+    pub sectors: u64,
 }
 
 impl AtaDisk {
     /// Create a new AtaDisk instance for the specified channel and slave/master status.
     pub fn new(channel: Channel, is_slave: bool) -> Self {
-        Self { channel, is_slave }
+        Self { channel, is_slave, atapi: false, sectors: 0 }
+    }
+
+    /// Conventional device name: hda/hdb on the primary channel, hdc/hdd on the secondary
+    pub fn name(&self) -> &'static str {
+        match (&self.channel, self.is_slave) {
+            (Channel::Primary, false) => "hda",
+            (Channel::Primary, true) => "hdb",
+            (Channel::Secondary, false) => "hdc",
+            (Channel::Secondary, true) => "hdd",
+        }
+    }
+
+    /// Port address of a task-file register on this disk's channel
+    fn port(&self, primary: u16, secondary: u16) -> u16 {
+        match self.channel {
+            Channel::Primary => primary,
+            Channel::Secondary => secondary,
+        }
+    }
+
+    fn channel_lock(&self) -> &'static Mutex<()> {
+        match self.channel {
+            Channel::Primary => &PRIMARY_LOCK,
+            Channel::Secondary => &SECONDARY_LOCK,
+        }
+    }
+
+    fn status(&self) -> u8 {
+        unsafe { Port::<u8>::new(self.port(PRIMARY_COMMAND_PORT, SECONDARY_COMMAND_PORT)).read() }
     }
 
     /// Initialize the disk by sending an IDENTIFY command to get device information
-    pub fn init(&self) -> Result<AtaDeviceIdentifyInfo, &'static str> {
-        // Wait for any previous operation to complete and ensure we're not busy
-        self.wait_for_ready()?;
+    pub fn init(&mut self) -> Result<AtaDeviceIdentifyInfo, &'static str> {
+        let _channel = self.channel_lock().lock();
+
+        // A floating bus reads as all ones: nothing is attached
+        if self.status() == 0xFF {
+            return Err("No device");
+        }
 
-        let mut port = Port::new(match self.channel {
-            Channel::Primary => PRIMARY_COMMAND_PORT,
-            Channel::Secondary => SECONDARY_COMMAND_PORT,
-        });
+        unsafe {
+            Port::<u8>::new(self.port(PRIMARY_DRIVE_PORT, SECONDARY_DRIVE_PORT))
+                .write(if self.is_slave { DEVICE_SLAVE } else { DEVICE_MASTER });
+            for (primary, secondary) in [
+                (PRIMARY_SECTOR_COUNT_PORT, SECONDARY_SECTOR_COUNT_PORT),
+                (PRIMARY_LBA_LOW_PORT, SECONDARY_LBA_LOW_PORT),
+                (PRIMARY_LBA_MID_PORT, SECONDARY_LBA_MID_PORT),
+                (PRIMARY_LBA_HIGH_PORT, SECONDARY_LBA_HIGH_PORT),
+            ] {
+                Port::<u8>::new(self.port(primary, secondary)).write(0);
+            }
+        }
+        self.settle();
 
         // Send the IDENTIFY command (0xEC)
         unsafe {
-            port.write(AtaCommand::IdentifyDevice as u8);
+            Port::<u8>::new(self.port(PRIMARY_COMMAND_PORT, SECONDARY_COMMAND_PORT))
+                .write(AtaCommand::IdentifyDevice as u8);
+        }
+
+        // A status of zero means no device answered
+        if self.status() == 0 {
+            return Err("No device");
+        }
+        let status = self.wait_not_busy()?;
+
+        // ATAPI and SATA devices reject IDENTIFY and put a signature here
+        let signature = self.signature();
+        if signature == ATAPI_SIGNATURE {
+            return self.init_atapi();
+        }
+        if signature != (0, 0) {
+            return Err("Not an ATA device");
+        }
+        if status & (STATUS_ERR | STATUS_DF) != 0 {
+            return Err("Device error");
         }
 
         // Wait for DRQ bit to be set, indicating data is ready
         self.wait_for_drq()?;
+        let words = self.read_identify()?;
+
+        let mut info = AtaDeviceIdentifyInfo {
+            // Word 0 bit 7: removable media
+            device_type: if words[0] & 0x80 != 0 { 0x4 } else { 0 },
+            heads: words[3],
+            sectors_per_track: words[6],
+            // Words 60-61: sectors addressable with LBA28
+            total_sectors: words[60] as u32 | (words[61] as u32) << 16,
+            model_number: [0; 41],
+            serial_number: [0; 21],
+            firmware_revision: [0; 9],
+        };
+        identify_string(&words[27..47], &mut info.model_number);
+        identify_string(&words[10..20], &mut info.serial_number);
+        identify_string(&words[23..27], &mut info.firmware_revision);
+
+        self.sectors = info.total_sectors as u64;
+        Ok(info)
+    }
+
+    /// Identify an ATAPI device and read the capacity of its medium; the
+    /// channel lock must be held
+    fn init_atapi(&mut self) -> Result<AtaDeviceIdentifyInfo, &'static str> {
+        unsafe {
+            Port::<u8>::new(self.port(PRIMARY_COMMAND_PORT, SECONDARY_COMMAND_PORT))
+                .write(AtaCommand::IdentifyPacketDevice as u8);
+        }
+        self.settle();
+        self.wait_for_drq()?;
+        let words = self.read_identify()?;
+        self.atapi = true;
+
+        // READ CAPACITY: the last block's address and the block size, big-endian
+        let mut capacity = [0u8; 8];
+        self.packet_with_retry(&[SCSI_READ_CAPACITY, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], &mut capacity)
+            .map_err(|_| "No medium")?;
+        let last = u32::from_be_bytes([capacity[0], capacity[1], capacity[2], capacity[3]]);
+        let block_size = u32::from_be_bytes([capacity[4], capacity[5], capacity[6], capacity[7]]);
+        if block_size as usize != ATAPI_SECTOR_SIZE {
+            return Err("Unsupported ATAPI block size");
+        }
+        self.sectors = last as u64 + 1;
 
-        let info = AtaDeviceIdentifyInfo {
-            device_type: 0,
-            sectors_per_track: 0,
+        let mut info = AtaDeviceIdentifyInfo {
+            // Word 0 bit 7: removable media
+            device_type: if words[0] & 0x80 != 0 { 0x4 } else { 0 },
             heads: 0,
-            total_sectors: 0,
+            sectors_per_track: 0,
+            total_sectors: self.sectors as u32,
             model_number: [0; 41],
             serial_number: [0; 21],
             firmware_revision: [0; 9],
         };
+        identify_string(&words[27..47], &mut info.model_number);
+        identify_string(&words[10..20], &mut info.serial_number);
+        identify_string(&words[23..27], &mut info.firmware_revision);
+        Ok(info)
+    }
 
-        // Read the identify data (512 bytes = 256 words) and discard it
-        // Since we're not parsing the actual IDENTIFY data structure,
-        // we'll just read and discard the data to clear the buffer
-        for _i in 0..256 {
-            let _ = self.read_word()?;
+    /// LBA mid and high: the device signature after a reset or rejected IDENTIFY
+    fn signature(&self) -> (u8, u8) {
+        unsafe {
+            (
+                Port::new(self.port(PRIMARY_LBA_MID_PORT, SECONDARY_LBA_MID_PORT)).read(),
+                Port::new(self.port(PRIMARY_LBA_HIGH_PORT, SECONDARY_LBA_HIGH_PORT)).read(),
+            )
         }
+    }
 
-        Ok(info)
+    /// Read the 256 words of IDENTIFY data once DRQ is set
+    fn read_identify(&self) -> Result<[u16; 256], &'static str> {
+        let mut words = [0u16; 256];
+        for word in words.iter_mut() {
+            *word = self.read_word()?;
+        }
+        Ok(words)
+    }
+
+    /// Give the drive the customary 400ns after selecting it (four status reads)
+    fn settle(&self) {
+        for _ in 0..4 {
+            self.status();
+        }
+    }
+
+    /// Wait until the busy bit clears, returning the status
+    fn wait_not_busy(&self) -> Result<u8, &'static str> {
+        for _ in 0..POLL_LIMIT {
+            let status = self.status();
+            if status & STATUS_BSY == 0 {
+                return Ok(status);
+            }
+        }
+        Err("Device timed out")
+    }
+
+    /// Wait until the busy bit clears, failing on error or timeout
+    fn wait_while_busy(&self) -> Result<u8, &'static str> {
+        let status = self.wait_not_busy()?;
+        if status & (STATUS_ERR | STATUS_DF) != 0 {
+            return Err("Device error");
+        }
+        Ok(status)
     }
 
     /// Wait for device to be ready (not busy and DRDY set).
     fn wait_for_ready(&self) -> Result<(), &'static str> {
-        loop {
-            let status: u8 = unsafe {
-                Port::new(match self.channel {
-                    Channel::Primary => PRIMARY_COMMAND_PORT,
-                    Channel::Secondary => SECONDARY_COMMAND_PORT,
-                }).read()
-            };
-
-            if (status & (STATUS_BSY | STATUS_DRDY)) == STATUS_DRDY {
-                break; // DRDY set and not busy
+        for _ in 0..POLL_LIMIT {
+            if (self.status() & (STATUS_BSY | STATUS_DRDY)) == STATUS_DRDY {
+                return Ok(()); // DRDY set and not busy
             }
         }
-
-        Ok(())
+        Err("Device not ready")
     }
 
     /// Wait for the device to be ready to transfer data.
     fn wait_for_drq(&self) -> Result<(), &'static str> {
-        loop {
-            let status: u8 = unsafe {
-                Port::new(match self.channel {
-                    Channel::Primary => PRIMARY_COMMAND_PORT,
-                    Channel::Secondary => SECONDARY_COMMAND_PORT,
-                }).read()
-            };
-
-            if (status & STATUS_DRQ) != 0 && (status & STATUS_BSY) == 0 {
-                break;
+        for _ in 0..POLL_LIMIT {
+            let status = self.status();
+            if status & STATUS_BSY != 0 {
+                continue;
+            }
+            if status & (STATUS_ERR | STATUS_DF) != 0 {
+                return Err("Device error");
+            }
+            if status & STATUS_DRQ != 0 {
+                return Ok(());
             }
         }
-
-        Ok(())
+        Err("Device timed out")
     }
 
     /// Read a word from the data port.
     fn read_word(&self) -> Result<u16, &'static str> {
-        let mut port: Port<u16> = Port::new(match self.channel {
-            Channel::Primary => PRIMARY_DATA_PORT,
-            Channel::Secondary => SECONDARY_DATA_PORT,
-        });
+        let mut port: Port<u16> = Port::new(self.port(PRIMARY_DATA_PORT, SECONDARY_DATA_PORT));
 
         unsafe {
             Ok(port.read())
         }
     }
 
-    /// Read a sector from the disk using LBA addressing.
-    pub fn read_sector(&self, lba: u64) -> Result<[u8; 512], &'static str> {
-        // Wait for device to be ready
-        self.wait_for_ready()?;
+    /// Select the drive and program an LBA28 transfer of `count` sectors
+    /// (1..=256) at `lba`, then issue `command`
+    fn start_transfer(&self, lba: u64, count: usize, command: AtaCommand) -> Result<(), &'static str> {
+        if count == 0 || count > MAX_SECTORS_PER_COMMAND {
+            return Err("Bad sector count");
+        }
+        if lba + count as u64 > LBA28_LIMIT.min(self.sectors) {
+            return Err("Sector out of range");
+        }
 
-        let mut command_port: Port<u8> = Port::new(match self.channel {
-            Channel::Primary => PRIMARY_COMMAND_PORT,
-            Channel::Secondary => SECONDARY_COMMAND_PORT,
-        });
+        self.wait_for_ready()?;
 
         unsafe {
-            // Set up the LBA address (28 bits)
-            Port::<u8>::new(match self.channel {
-                Channel::Primary => PRIMARY_LBA_HIGH_PORT,
-                Channel::Secondary => SECONDARY_LBA_HIGH_PORT,
-            }).write(0x40 | if self.is_slave { 0x10 } else { 0x00 } | ((lba >> 24) & 0x0F) as u8);
-
-            // Write LBA bits to the appropriate ports in order
-            Port::<u8>::new(match self.channel {
-                Channel::Primary => PRIMARY_LBA_LOW_PORT,
-                Channel::Secondary => SECONDARY_LBA_LOW_PORT,
-            }).write((lba & 0xFF) as u8);
-
-            Port::<u8>::new(match self.channel {
-                Channel::Primary => PRIMARY_LBA_MID_PORT,
-                Channel::Secondary => SECONDARY_LBA_MID_PORT,
-            }).write(((lba >> 8) & 0xFF) as u8);
-
-            Port::<u8>::new(match self.channel {
-                Channel::Primary => PRIMARY_LBA_HIGH_PORT,
-                Channel::Secondary => SECONDARY_LBA_HIGH_PORT,
-            }).write(((lba >> 16) & 0xFF) as u8 | 0x40 | if self.is_slave { 0x10 } else { 0x00 });
-
-            // Set sector count to 1
-            Port::<u8>::new(match self.channel {
-                Channel::Primary => PRIMARY_SECTOR_COUNT_PORT,
-                Channel::Secondary => SECONDARY_SECTOR_COUNT_PORT,
-            }).write(1);
-        }
-
-        // Send the READ SECTORS command (0x20)
+            let select = if self.is_slave { DEVICE_SLAVE } else { DEVICE_MASTER };
+            Port::<u8>::new(self.port(PRIMARY_DRIVE_PORT, SECONDARY_DRIVE_PORT))
+                .write(select | DEVICE_LBA | ((lba >> 24) & 0x0F) as u8);
+            self.settle();
+
+            // A count of 0 means 256 sectors
+            Port::<u8>::new(self.port(PRIMARY_SECTOR_COUNT_PORT, SECONDARY_SECTOR_COUNT_PORT))
+                .write((count % MAX_SECTORS_PER_COMMAND) as u8);
+            Port::<u8>::new(self.port(PRIMARY_LBA_LOW_PORT, SECONDARY_LBA_LOW_PORT))
+                .write((lba & 0xFF) as u8);
+            Port::<u8>::new(self.port(PRIMARY_LBA_MID_PORT, SECONDARY_LBA_MID_PORT))
+                .write(((lba >> 8) & 0xFF) as u8);
+            Port::<u8>::new(self.port(PRIMARY_LBA_HIGH_PORT, SECONDARY_LBA_HIGH_PORT))
+                .write(((lba >> 16) & 0xFF) as u8);
+
+            Port::<u8>::new(self.port(PRIMARY_COMMAND_PORT, SECONDARY_COMMAND_PORT))
+                .write(command as u8);
+        }
+        Ok(())
+    }
+
+    /// Send the SCSI command `command` to an ATAPI device and read what it
+    /// returns into `buffer`, returning the bytes read; the channel lock
+    /// must be held
+    ///
+    /// Data past the end of `buffer` is read and dropped.
+    fn packet(&self, command: &[u8; 12], buffer: &mut [u8]) -> Result<usize, &'static str> {
+        self.wait_not_busy()?;
         unsafe {
-            command_port.write(AtaCommand::ReadSectorsWithRetry as u8);
+            let select = if self.is_slave { DEVICE_SLAVE } else { DEVICE_MASTER };
+            Port::<u8>::new(self.port(PRIMARY_DRIVE_PORT, SECONDARY_DRIVE_PORT)).write(select);
+            self.settle();
+
+            // PIO transfer, in pieces of at most the byte count limit
+            Port::<u8>::new(self.port(PRIMARY_FEATURES_PORT, SECONDARY_FEATURES_PORT)).write(0);
+            Port::<u8>::new(self.port(PRIMARY_LBA_MID_PORT, SECONDARY_LBA_MID_PORT))
+                .write((PACKET_BYTE_LIMIT & 0xFF) as u8);
+            Port::<u8>::new(self.port(PRIMARY_LBA_HIGH_PORT, SECONDARY_LBA_HIGH_PORT))
+                .write((PACKET_BYTE_LIMIT >> 8) as u8);
+            Port::<u8>::new(self.port(PRIMARY_COMMAND_PORT, SECONDARY_COMMAND_PORT))
+                .write(AtaCommand::Packet as u8);
         }
 
+        // The device asks for the packet, then for each piece of data
         self.wait_for_drq()?;
+        let mut data_port: Port<u16> = Port::new(self.port(PRIMARY_DATA_PORT, SECONDARY_DATA_PORT));
+        for pair in command.chunks(2) {
+            unsafe {
+                data_port.write(u16::from_le_bytes([pair[0], pair[1]]));
+            }
+        }
+        self.settle();
 
-        let mut buffer = [0u8; 512];
+        let mut done = 0;
+        loop {
+            let status = self.wait_not_busy()?;
+            if status & (STATUS_ERR | STATUS_DF) != 0 {
+                return Err("Device error");
+            }
+            if status & STATUS_DRQ == 0 {
+                return Ok(done.min(buffer.len()));
+            }
+            let (low, high) = self.signature();
+            let count = u16::from_le_bytes([low, high]) as usize;
+            for _ in 0..count.div_ceil(2) {
+                let bytes = self.read_word()?.to_le_bytes();
+                for byte in bytes {
+                    if let Some(slot) = buffer.get_mut(done) {
+                        *slot = byte;
+                    }
+                    done += 1;
+                }
+            }
+            self.settle();
+        }
+    }
+
+    /// `packet`, tried again after a failure such as a unit attention
+    fn packet_with_retry(&self, command: &[u8; 12], buffer: &mut [u8]) -> Result<usize, &'static str> {
+        let mut result = Err("Device error");
+        for _ in 0..PACKET_TRIES {
+            result = self.packet(command, buffer);
+            if result.is_ok() {
+                break;
+            }
+        }
+        result
+    }
+
+    /// Read consecutive 2048-byte sectors of an ATAPI device starting at
+    /// `lba` into `buffer`
+    fn read_packet_sectors(&self, lba: u64, buffer: &mut [u8]) -> Result<(), &'static str> {
+        if !buffer.len().is_multiple_of(ATAPI_SECTOR_SIZE) {
+            return Err("Buffer is not a whole number of sectors");
+        }
+        let _channel = self.channel_lock().lock();
+
+        for (chunk_index, chunk) in buffer.chunks_mut(MAX_PACKET_SECTORS * ATAPI_SECTOR_SIZE).enumerate() {
+            let chunk_lba = lba + (chunk_index * MAX_PACKET_SECTORS) as u64;
+            let lba = u32::try_from(chunk_lba).map_err(|_| "Sector out of range")?.to_be_bytes();
+            let count = ((chunk.len() / ATAPI_SECTOR_SIZE) as u16).to_be_bytes();
+            let command = [SCSI_READ_10, 0, lba[0], lba[1], lba[2], lba[3], 0, count[0], count[1], 0, 0, 0];
+            if self.packet_with_retry(&command, chunk)? != chunk.len() {
+                return Err("Short read");
+            }
+        }
+        Ok(())
+    }
 
-        // Read 256 words (512 bytes)
-        for i in 0..256 {
-            let word = self.read_word()?;
-            buffer[i * 2] = (word & 0xFF) as u8;
-            buffer[i * 2 + 1] = ((word >> 8) & 0xFF) as u8;
+    /// Read consecutive sectors starting at `lba` into `buffer`, whose
+    /// length must be a multiple of 512
+    pub fn read_sectors(&self, lba: u64, buffer: &mut [u8]) -> Result<(), &'static str> {
+        if !buffer.len().is_multiple_of(512) {
+            return Err("Buffer is not a whole number of sectors");
         }
+        let _channel = self.channel_lock().lock();
+
+        for (chunk_index, chunk) in buffer.chunks_mut(MAX_SECTORS_PER_COMMAND * 512).enumerate() {
+            let chunk_lba = lba + (chunk_index * MAX_SECTORS_PER_COMMAND) as u64;
+            self.start_transfer(chunk_lba, chunk.len() / 512, AtaCommand::ReadSectorsWithRetry)?;
+
+            // The drive raises DRQ once per sector
+            for sector in chunk.chunks_mut(512) {
+                self.wait_for_drq()?;
+                for pair in sector.chunks_mut(2) {
+                    pair.copy_from_slice(&self.read_word()?.to_le_bytes());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Write consecutive sectors from `data`, whose length must be a
+    /// multiple of 512, starting at `lba`
+    pub fn write_sectors(&self, lba: u64, data: &[u8]) -> Result<(), &'static str> {
+        if !data.len().is_multiple_of(512) {
+            return Err("Buffer is not a whole number of sectors");
+        }
+        let _channel = self.channel_lock().lock();
+        let mut data_port: Port<u16> = Port::new(self.port(PRIMARY_DATA_PORT, SECONDARY_DATA_PORT));
+
+        for (chunk_index, chunk) in data.chunks(MAX_SECTORS_PER_COMMAND * 512).enumerate() {
+            let chunk_lba = lba + (chunk_index * MAX_SECTORS_PER_COMMAND) as u64;
+            self.start_transfer(chunk_lba, chunk.len() / 512, AtaCommand::WriteSectorsWithRetry)?;
+
+            for sector in chunk.chunks(512) {
+                self.wait_for_drq()?;
+                for pair in sector.chunks(2) {
+                    unsafe {
+                        data_port.write(u16::from_le_bytes([pair[0], pair[1]]));
+                    }
+                }
+            }
+            // Wait for the last sector to be taken
+            self.wait_while_busy()?;
+        }
+        Ok(())
+    }
 
+    /// Read a sector from the disk using LBA addressing.
+    pub fn read_sector(&self, lba: u64) -> Result<[u8; 512], &'static str> {
+        let mut buffer = [0u8; 512];
+        self.read_sectors(lba, &mut buffer)?;
         Ok(buffer)
     }
 
     /// Write a sector to the disk using LBA addressing.
     #[allow(dead_code)]
     pub fn write_sector(&self, lba: u64, data: &[u8; 512]) -> Result<(), &'static str> {
-        // Wait for device to be ready
-        self.wait_for_ready()?;
-
-        let mut command_port: Port<u8> = Port::new(match self.channel {
-            Channel::Primary => PRIMARY_COMMAND_PORT,
-            Channel::Secondary => SECONDARY_COMMAND_PORT,
-        });
+        self.write_sectors(lba, data)
+    }
 
+    /// Commit the drive's write cache to the medium
+    pub fn flush_cache(&self) -> Result<(), &'static str> {
+        let _channel = self.channel_lock().lock();
+        self.wait_for_ready()?;
         unsafe {
-            // Set up the LBA address (28 bits)
-            Port::<u8>::new(match self.channel {
-                Channel::Primary => PRIMARY_LBA_HIGH_PORT,
-                Channel::Secondary => SECONDARY_LBA_HIGH_PORT,
-            }).write(0x40 | if self.is_slave { 0x10 } else { 0x00 } | ((lba >> 24) & 0x0F) as u8);
-
-            // Write LBA bits to the appropriate ports in order
-            Port::<u8>::new(match self.channel {
-                Channel::Primary => PRIMARY_LBA_LOW_PORT,
-                Channel::Secondary => SECONDARY_LBA_LOW_PORT,
-            }).write((lba & 0xFF) as u8);
-
-            Port::<u8>::new(match self.channel {
-                Channel::Primary => PRIMARY_LBA_MID_PORT,
-                Channel::Secondary => SECONDARY_LBA_MID_PORT,
-            }).write(((lba >> 8) & 0xFF) as u8);
-
-            Port::<u8>::new(match self.channel {
-                Channel::Primary => PRIMARY_LBA_HIGH_PORT,
-                Channel::Secondary => SECONDARY_LBA_HIGH_PORT,
-            }).write(((lba >> 16) & 0xFF) as u8 | 0x40 | if self.is_slave { 0x10 } else { 0x00 });
-
-            // Set sector count to 1
-            Port::<u8>::new(match self.channel {
-                Channel::Primary => PRIMARY_SECTOR_COUNT_PORT,
-                Channel::Secondary => SECONDARY_SECTOR_COUNT_PORT,
-            }).write(1);
-        }
-
-        // Send the WRITE SECTORS command (0x30)
-        unsafe {
-            command_port.write(AtaCommand::WriteSectorsWithRetry as u8);
+            let select = if self.is_slave { DEVICE_SLAVE } else { DEVICE_MASTER };
+            Port::<u8>::new(self.port(PRIMARY_DRIVE_PORT, SECONDARY_DRIVE_PORT)).write(select);
+            self.settle();
+            Port::<u8>::new(self.port(PRIMARY_COMMAND_PORT, SECONDARY_COMMAND_PORT))
+                .write(AtaCommand::CacheFlush as u8);
         }
+        self.wait_while_busy()?;
+        Ok(())
+    }
+}
 
-        self.wait_for_drq()?;
+impl BlockDevice for AtaDisk {
+    fn block_size(&self) -> usize {
+        if self.atapi {
+            ATAPI_SECTOR_SIZE
+        } else {
+            SECTOR_SIZE
+        }
+    }
 
-        // Write 256 words (512 bytes)
-        let mut data_port: Port<u16> = Port::new(match self.channel {
-            Channel::Primary => PRIMARY_DATA_PORT,
-            Channel::Secondary => SECONDARY_DATA_PORT,
-        });
+    fn block_count(&self) -> u64 {
+        if self.atapi {
+            self.sectors
+        } else {
+            self.sectors.min(LBA28_LIMIT)
+        }
+    }
 
-        for i in 0..256 {
-            let word = (data[i * 2] as u16) | ((data[i * 2 + 1] as u16) << 8);
-            unsafe {
-                data_port.write(word);
-            }
+    fn read_blocks(&self, start: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self, start, buffer.len())?;
+        let result = if self.atapi {
+            self.read_packet_sectors(start, buffer)
+        } else {
+            self.read_sectors(start, buffer)
+        };
+        result.map_err(|e| {
+            serial_println!("{}: read at sector {} failed: {}", self.name(), start, e);
+            BlockError::Io
+        })
+    }
+
+    fn write_blocks(&self, start: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        check_request(self, start, buffer.len())?;
+        if self.atapi {
+            return Err(BlockError::ReadOnly);
         }
+        self.write_sectors(start, buffer).map_err(|e| {
+            serial_println!("{}: write at sector {} failed: {}", self.name(), start, e);
+            BlockError::Io
+        })
+    }
 
-        // Wait for the operation to complete
-        self.wait_for_ready()?;
+    fn flush(&self) -> Result<(), BlockError> {
+        if self.atapi {
+            return Ok(()); // never written
+        }
+        self.flush_cache().map_err(|e| {
+            serial_println!("{}: cache flush failed: {}", self.name(), e);
+            BlockError::Io
+        })
+    }
+}
 
-        Ok(())
+/// Copy an IDENTIFY string (two characters per word, high byte first,
+/// space padded) into a null-terminated buffer
+fn identify_string(words: &[u16], out: &mut [u8]) {
+    let mut len = 0;
+    for (i, word) in words.iter().enumerate() {
+        out[i * 2] = (word >> 8) as u8;
+        out[i * 2 + 1] = (word & 0xFF) as u8;
+        len = i * 2 + 2;
     }
+    while len > 0 && matches!(out[len - 1], b' ' | 0) {
+        len -= 1;
+    }
+    out[len..].fill(0);
 }
 
 /// Channel enumeration for primary and secondary IDE channels.
@@ -320,94 +639,49 @@ pub struct AtaDeviceIdentifyInfo {
 impl AtaDeviceIdentifyInfo {
     // Helper method to get the model name as a &str (if valid)
     pub fn model_name(&self) -> Option<&str> {
-        let bytes = self.model_number.as_slice();
-        
-        if !bytes.is_empty() && bytes[0] != 0x20 { // Check for non-space first byte
-            return unsafe { 
-                core::slice::from_raw_parts(bytes.as_ptr(), bytes.len())
-                    .split(|&b| b == 0)
-                    .next()
-                    .map(|s| {
-                        let s = &s[1..]; // Skip the leading space (if any) - some devices have it
-                        if !s.is_empty() && *s.first().unwrap_or(&0x20) != 0x20 { 
-                            core::str::from_utf8_unchecked(s)
-                        } else {
-                            ""
-                        }
-                    })
-            };
-        }
-
-        None
+        identify_str(&self.model_number)
     }
 
     // Helper method to get the serial number as a &str (if valid)
     pub fn serial_number_str(&self) -> Option<&str> {
-        let bytes = self.serial_number.as_slice();
-        
-        if !bytes.is_empty() && bytes[0] != 0x20 { // Check for non-space first byte
-            return unsafe { 
-                core::slice::from_raw_parts(bytes.as_ptr(), bytes.len())
-                    .split(|&b| b == 0)
-                    .next()
-                    .map(|s| {
-                        let s = &s[1..]; // Skip the leading space (if any) - some devices have it
-                        if !s.is_empty() && *s.first().unwrap_or(&0x20) != 0x20 { 
-                            core::str::from_utf8_unchecked(s)
-                        } else {
-                            ""
-                        }
-                    })
-            };
-        }
-
-        None
+        identify_str(&self.serial_number)
     }
 
     // Helper method to get the firmware revision as a &str (if valid)
     #[allow(dead_code)]
     fn firmware_revision_str(&self) -> Option<&str> {
-        let bytes = self.firmware_revision.as_slice();
-        
-        if !bytes.is_empty() && bytes[0] != 0x20 { // Check for non-space first byte
-            return unsafe { 
-                core::slice::from_raw_parts(bytes.as_ptr(), bytes.len())
-                    .split(|&b| b == 0)
-                    .next()
-                    .map(|s| {
-                        let s = &s[1..]; // Skip the leading space (if any) - some devices have it
-                        if !s.is_empty() && *s.first().unwrap_or(&0x20) != 0x20 { 
-                            core::str::from_utf8_unchecked(s)
-                        } else {
-                            ""
-                        }
-                    })
-            };
-        }
-
-        None
+        identify_str(&self.firmware_revision)
     }
 }
 
+/// The text of a null-terminated identify string, None if empty or not UTF-8
+fn identify_str(bytes: &[u8]) -> Option<&str> {
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    core::str::from_utf8(&bytes[..len]).ok().filter(|s| !s.is_empty())
+}
+
 /// Initialize the ATA/IDE disk driver.
 pub fn init_ata() -> Vec<AtaDisk> {
     let mut disks = vec![];
 
     // Check primary channel (master and slave devices)
     for is_slave in [false, true] {
-        let master_disk = AtaDisk::new(Channel::Primary, is_slave);
+        let mut master_disk = AtaDisk::new(Channel::Primary, is_slave);
         
         match master_disk.init() {
             Ok(info) => {
                 println!(
-                    "ATA Disk: {} - Model '{}', Serial '{}' ({})",
+                    "{} Disk: {} ({}) - Model '{}', Serial '{}' ({}, {} sectors)",
+                    if master_disk.atapi { "ATAPI" } else { "ATA" },
+                    master_disk.name(),
                     if is_slave { "Slave" } else { "Master" },
                     info.model_name().unwrap_or("Unknown"),
                     info.serial_number_str().unwrap_or("Unknown"),
                     match info.device_type & 0x4 {
                         0 => "Fixed Disk", 
                         _ => "Removable"
-                    }
+                    },
+                    info.total_sectors
                 );
                 
                 disks.push(master_disk);
@@ -418,19 +692,22 @@ pub fn init_ata() -> Vec<AtaDisk> {
 
     // Check secondary channel (master and slave devices)
     for is_slave in [false, true] {
-        let master_disk = AtaDisk::new(Channel::Secondary, is_slave);
+        let mut master_disk = AtaDisk::new(Channel::Secondary, is_slave);
 
         match master_disk.init() {
             Ok(info) => {
                 println!(
-                    "ATA Disk: {} - Model '{}', Serial '{}' ({})",
+                    "{} Disk: {} ({}) - Model '{}', Serial '{}' ({}, {} sectors)",
+                    if master_disk.atapi { "ATAPI" } else { "ATA" },
+                    master_disk.name(),
                     if is_slave { "Slave" } else { "Master" },
                     info.model_name().unwrap_or("Unknown"),
                     info.serial_number_str().unwrap_or("Unknown"),
                     match info.device_type & 0x4 {
                         0 => "Fixed Disk", 
                         _ => "Removable"
-                    }
+                    },
+                    info.total_sectors
                 );
                 
                 disks.push(master_disk);
@@ -441,11 +718,11 @@ pub fn init_ata() -> Vec<AtaDisk> {
 
     println!("ATA/IDE driver initialized: {} disk(s) found.", disks.len());
     
-    // Test reading a sector from the first available drive
-    if let Some(disk) = disks.first() {
-        match disk.read_sector(0x12345678) {
-            Ok(data) => println!("Successfully read sector 0x{:X} (first 16 bytes: {:?})", 
-                0x12345678, &data[..16]),
+    // Test reading a sector from the first available hard disk
+    if let Some(disk) = disks.iter().find(|disk| !disk.atapi) {
+        match disk.read_sector(0) {
+            Ok(data) => println!("Successfully read sector 0 (first 16 bytes: {:?})",
+                &data[..16]),
             Err(e) => println!("Failed to read from disk: {}", e),
         }
     }
@@ -0,0 +1,402 @@
+//! Buffer cache for block devices
+//!
+//! Filesystems read and write their metadata blocks through here instead
+//! of going to the device each time. `bread` returns a pinned `Buffer`
+//! holding the block, reading it only on a miss; `bwrite` marks it dirty
+//! (a delayed write) and `brelse` unpins it. Dirty buffers reach the
+//! device on `sync`, which writes them in (device, block) order so the
+//! request queue can merge neighbours, or when the cache needs the slot.
+//!
+//! Buffers live in a fixed pool outside the kernel heap. When the pool is
+//! full the least recently used unpinned buffer is reused, after writing
+//! it back if it is dirty.
+//!
+//! A buffer is addressed by device and block number in units of its own
+//! size, which may be any multiple of the device block size up to
+//! `BUFFER_SIZE`. It is cached under its byte offset and size, so two
+//! sizes never alias one entry, but buffers of different sizes covering
+//! the same bytes are not kept coherent: a filesystem should stick to
+//! one size, and unmounting drops a device's buffers (`invalidate`)
+//! before the next filesystem on it can use another.
+
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use spin::{Mutex, MutexGuard};
+
+use crate::block::BlockDevice;
+use crate::vfs::VfsError;
+
+/// Largest buffer, in bytes
+pub const BUFFER_SIZE: usize = 4096;
+
+/// Number of buffers in the pool
+const POOL_BUFFERS: usize = 32;
+
+/// Backing memory of the buffers, kept out of the (small) kernel heap
+#[repr(C, align(4096))]
+struct BufferPool([[u8; BUFFER_SIZE]; POOL_BUFFERS]);
+
+static mut BUFFER_POOL: BufferPool = BufferPool([[0; BUFFER_SIZE]; POOL_BUFFERS]);
+
+/// The first `size` bytes of pool slot `slot`
+///
+/// Safety: the caller must hold the state lock of the buffer owning the
+/// slot for as long as the slice is used.
+unsafe fn slot_data(slot: usize, size: usize) -> &'static mut [u8] {
+    let pool = &mut *core::ptr::addr_of_mut!(BUFFER_POOL);
+    &mut pool.0[slot][..size]
+}
+
+/// Cache key: device address, byte offset, then buffer size
+type Key = (usize, u64, usize);
+
+fn device_key(device: &Arc<dyn BlockDevice>) -> usize {
+    Arc::as_ptr(device) as *const () as usize
+}
+
+/// One cached block
+struct BufferHead {
+    device: Arc<dyn BlockDevice>,
+    block: u64,
+    size: usize,
+    /// Pool slot holding the data; owned by this head while it is cached
+    slot: usize,
+    /// Held while the data is read or changed. Lock order: the cache lock
+    /// may be held when taking this, never the other way round
+    state: Mutex<BufferState>,
+}
+
+struct BufferState {
+    /// The data has been read from the device (or fully overwritten)
+    valid: bool,
+    dirty: bool,
+}
+
+impl BufferHead {
+    /// First device block of the buffer
+    fn device_block(&self) -> u64 {
+        self.block * (self.size / self.device.block_size()) as u64
+    }
+
+    /// Write the buffer back if it is dirty; `state` must be this head's lock
+    fn write_back(&self, state: &mut BufferState) -> Result<(), VfsError> {
+        if !state.dirty {
+            return Ok(());
+        }
+        // Safety: the state lock is held, which gives access to the slot
+        let data = unsafe { slot_data(self.slot, self.size) };
+        self.device.write_blocks(self.device_block(), data)?;
+        state.dirty = false;
+        WRITEBACKS.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+/// A pinned buffer, from `bread` or `bget`
+///
+/// While a `Buffer` exists its block stays cached. Dropping it is the
+/// same as `brelse`.
+pub struct Buffer {
+    head: Arc<BufferHead>,
+}
+
+impl Buffer {
+    /// Block number, in units of the buffer size
+    pub fn block(&self) -> u64 {
+        self.head.block
+    }
+
+    /// Lock the buffer's data for reading or changing it
+    ///
+    /// Call `bwrite` after changing it so the change is written back.
+    pub fn data(&self) -> BufferData<'_> {
+        let state = self.head.state.lock();
+        // Safety: the state lock is held for as long as the slice lives
+        let data = unsafe { slot_data(self.head.slot, self.head.size) };
+        BufferData { _state: state, data }
+    }
+
+    #[allow(dead_code)]
+    pub fn is_dirty(&self) -> bool {
+        self.head.state.lock().dirty
+    }
+}
+
+/// The locked contents of a buffer
+pub struct BufferData<'a> {
+    _state: MutexGuard<'a, BufferState>,
+    data: &'a mut [u8],
+}
+
+impl Deref for BufferData<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.data
+    }
+}
+
+impl DerefMut for BufferData<'_> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.data
+    }
+}
+
+/// Cache hit/miss and eviction counters
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BufferCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+}
+
+/// Buffers written back; counted apart from the cache lock, which must
+/// not be taken while a buffer is locked
+static WRITEBACKS: AtomicU64 = AtomicU64::new(0);
+
+struct CacheEntry {
+    head: Arc<BufferHead>,
+    last_used: u64,
+}
+
+struct BufferCache {
+    buffers: BTreeMap<Key, CacheEntry>,
+    free_slots: Vec<usize>,
+    clock: u64,
+    stats: BufferCacheStats,
+}
+
+impl BufferCache {
+    /// The least recently used buffer nobody has pinned
+    fn lru_victim(&self) -> Option<Key> {
+        self.buffers
+            .iter()
+            .filter(|(_, entry)| Arc::strong_count(&entry.head) == 1)
+            .min_by_key(|(_, entry)| entry.last_used)
+            .map(|(&key, _)| key)
+    }
+}
+
+lazy_static! {
+    static ref BUFFER_CACHE: Mutex<BufferCache> = Mutex::new(BufferCache {
+        buffers: BTreeMap::new(),
+        free_slots: (0..POOL_BUFFERS).rev().collect(),
+        clock: 0,
+        stats: BufferCacheStats::default(),
+    });
+}
+
+/// Find or create the buffer for `block`, pinned
+fn get_buffer(device: &Arc<dyn BlockDevice>, block: u64, size: usize) -> Result<Buffer, VfsError> {
+    let block_size = device.block_size();
+    if size == 0 || size > BUFFER_SIZE || !size.is_multiple_of(block_size) {
+        return Err(VfsError::InvalidArgument);
+    }
+    let offset = block.checked_mul(size as u64).ok_or(VfsError::InvalidArgument)?;
+    let key = (device_key(device), offset, size);
+
+    loop {
+        let mut cache = BUFFER_CACHE.lock();
+        cache.clock += 1;
+        let clock = cache.clock;
+
+        if let Some(entry) = cache.buffers.get_mut(&key) {
+            entry.last_used = clock;
+            let head = entry.head.clone();
+            cache.stats.hits += 1;
+            return Ok(Buffer { head });
+        }
+
+        if let Some(slot) = cache.free_slots.pop() {
+            let head = Arc::new(BufferHead {
+                device: device.clone(),
+                block,
+                size,
+                slot,
+                state: Mutex::new(BufferState { valid: false, dirty: false }),
+            });
+            cache.buffers.insert(key, CacheEntry { head: head.clone(), last_used: clock });
+            cache.stats.misses += 1;
+            return Ok(Buffer { head });
+        }
+
+        // Reuse the least recently used buffer, writing it back first.
+        // The write happens without the cache lock, then we start over.
+        let victim_key = cache.lru_victim().ok_or(VfsError::NoSpace)?;
+        let victim = cache.buffers[&victim_key].head.clone();
+        if !victim.state.lock().dirty {
+            cache.buffers.remove(&victim_key);
+            cache.free_slots.push(victim.slot);
+            cache.stats.evictions += 1;
+            continue;
+        }
+        drop(cache);
+        let mut state = victim.state.lock();
+        victim.write_back(&mut state)?;
+    }
+}
+
+/// Read a block through the cache, returning it pinned
+///
+/// `block` counts in units of `size` bytes.
+pub fn bread(device: &Arc<dyn BlockDevice>, block: u64, size: usize) -> Result<Buffer, VfsError> {
+    let buffer = get_buffer(device, block, size)?;
+    {
+        let head = &buffer.head;
+        let mut state = head.state.lock();
+        if !state.valid {
+            // Safety: the state lock is held, which gives access to the slot
+            let data = unsafe { slot_data(head.slot, size) };
+            device.read_blocks(head.device_block(), data)?;
+            state.valid = true;
+        }
+    }
+    Ok(buffer)
+}
+
+/// Get a block's buffer without reading it, for a caller that will
+/// overwrite all of it; a block not yet cached (or forgotten) comes back
+/// zero-filled
+#[allow(dead_code)]
+pub fn bget(device: &Arc<dyn BlockDevice>, block: u64, size: usize) -> Result<Buffer, VfsError> {
+    let buffer = get_buffer(device, block, size)?;
+    {
+        let head = &buffer.head;
+        let mut state = head.state.lock();
+        if !state.valid {
+            // Safety: the state lock is held, which gives access to the slot
+            unsafe { slot_data(head.slot, size) }.fill(0);
+            state.valid = true;
+        }
+    }
+    Ok(buffer)
+}
+
+/// Mark a buffer dirty; it is written back by `sync` or on eviction
+pub fn bwrite(buffer: &Buffer) {
+    let mut state = buffer.head.state.lock();
+    state.valid = true;
+    state.dirty = true;
+}
+
+/// Unpin a buffer, leaving it cached
+pub fn brelse(buffer: Buffer) {
+    drop(buffer);
+}
+
+/// Unpin a buffer and throw away its contents, changes included, so the
+/// block is read from the device again next time (for a journal
+/// transaction that is abandoned)
+#[allow(dead_code)]
+pub fn bforget(buffer: Buffer) {
+    let mut state = buffer.head.state.lock();
+    state.valid = false;
+    state.dirty = false;
+}
+
+/// Write one buffer back now if it is dirty (for ordered writes such as a
+/// journal commit), then flush the device
+#[allow(dead_code)]
+pub fn bflush(buffer: &Buffer) -> Result<(), VfsError> {
+    let head = &buffer.head;
+    head.write_back(&mut head.state.lock())?;
+    Ok(head.device.flush()?)
+}
+
+/// Write back dirty buffers selected by `filter`, in (device, block) order
+fn write_back_where(filter: impl Fn(&BufferHead) -> bool) -> Result<(), VfsError> {
+    let dirty: Vec<Arc<BufferHead>> = {
+        let cache = BUFFER_CACHE.lock();
+        // The map is ordered by (device, offset): exactly the dispatch order
+        cache
+            .buffers
+            .values()
+            .filter(|entry| filter(&entry.head))
+            .map(|entry| entry.head.clone())
+            .collect()
+    };
+
+    let mut result = Ok(());
+    let mut devices: Vec<Arc<dyn BlockDevice>> = Vec::new();
+    for head in dirty {
+        let mut state = head.state.lock();
+        if !state.dirty {
+            continue;
+        }
+        match head.write_back(&mut state) {
+            Ok(()) => {
+                if !devices.iter().any(|device| Arc::ptr_eq(device, &head.device)) {
+                    devices.push(head.device.clone());
+                }
+            }
+            Err(e) => result = Err(e),
+        }
+    }
+
+    for device in devices {
+        device.flush()?;
+    }
+    result
+}
+
+/// Write back every dirty buffer, in (device, block) order
+pub fn sync() -> Result<(), VfsError> {
+    write_back_where(|_| true)
+}
+
+/// Write back the dirty buffers of one device
+pub fn sync_device(device: &Arc<dyn BlockDevice>) -> Result<(), VfsError> {
+    let key = device_key(device);
+    write_back_where(|head| device_key(&head.device) == key)
+}
+
+/// Write back and drop every unpinned buffer of a device, e.g. on unmount
+pub fn invalidate(device: &Arc<dyn BlockDevice>) -> Result<(), VfsError> {
+    sync_device(device)?;
+    let key = device_key(device);
+    let mut cache = BUFFER_CACHE.lock();
+    let stale: Vec<Key> = cache
+        .buffers
+        .iter()
+        .filter(|(&(owner, _, _), entry)| owner == key && Arc::strong_count(&entry.head) == 1)
+        .map(|(&key, _)| key)
+        .collect();
+    for key in stale {
+        if let Some(entry) = cache.buffers.remove(&key) {
+            cache.free_slots.push(entry.head.slot);
+        }
+    }
+    Ok(())
+}
+
+/// Render the cache state for /proc/buffers
+pub fn render_stats() -> String {
+    let cache = BUFFER_CACHE.lock();
+    let dirty = cache.buffers.values().filter(|entry| entry.head.state.lock().dirty).count();
+    let pinned = cache.buffers.values().filter(|entry| Arc::strong_count(&entry.head) > 1).count();
+
+    format!(
+        "buffers:    {}\n\
+         cached:     {}\n\
+         dirty:      {}\n\
+         pinned:     {}\n\
+         hits:       {}\n\
+         misses:     {}\n\
+         writebacks: {}\n\
+         evictions:  {}\n",
+        POOL_BUFFERS,
+        cache.buffers.len(),
+        dirty,
+        pinned,
+        cache.stats.hits,
+        cache.stats.misses,
+        WRITEBACKS.load(Ordering::Relaxed),
+        cache.stats.evictions,
+    )
+}
@@ -0,0 +1,439 @@
+//! Block device layer
+//!
+//! Disk drivers implement `BlockDevice`, and filesystems are written
+//! against the same trait, so they run unchanged on an ATA disk or on a
+//! `RamDisk`. Every registered device sits behind a `RequestQueue`: writes
+//! are held back briefly, merged with queued writes to neighbouring blocks
+//! and dispatched in block order as one driver request per contiguous run.
+//! Reads always see queued writes. The queues are drained when they grow
+//! past a limit, by the page cache flusher, and by sync.
+//!
+//! Devices stacked on another registered device, such as partitions, are
+//! registered with `register_stacked`: their queue passes writes straight
+//! through, so the disk's queue is the only place they wait.
+
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::ptr::addr_of_mut;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use crate::vfs::VfsError;
+
+/// Size of a disk sector, and of a RamDisk block
+pub const SECTOR_SIZE: usize = 512;
+
+/// Queued write data above which a queue is dispatched (the heap is small)
+const QUEUE_LIMIT: usize = 8 * 1024;
+
+/// Blocks of the boot-time RAM disk, `/dev/ram0` (1 MiB, kept out of the heap)
+const RAMDISK_BLOCKS: usize = 2048;
+
+/// Errors from block I/O
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The request reaches past the end of the device
+    OutOfRange,
+    /// The buffer is not a whole number of blocks
+    Misaligned,
+    /// The device reported an error
+    Io,
+    /// The device cannot be written, such as a CD-ROM drive
+    ReadOnly,
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlockError::OutOfRange => write!(f, "Block out of range"),
+            BlockError::Misaligned => write!(f, "Request is not a whole number of blocks"),
+            BlockError::Io => write!(f, "Device I/O error"),
+            BlockError::ReadOnly => write!(f, "Device is read-only"),
+        }
+    }
+}
+
+impl From<BlockError> for VfsError {
+    fn from(e: BlockError) -> Self {
+        match e {
+            BlockError::OutOfRange | BlockError::Misaligned => VfsError::InvalidArgument,
+            BlockError::Io => VfsError::IoError,
+            BlockError::ReadOnly => VfsError::ReadOnly,
+        }
+    }
+}
+
+/// A device addressed in fixed-size blocks
+pub trait BlockDevice: Send + Sync {
+    /// Bytes per block
+    fn block_size(&self) -> usize;
+
+    /// Number of blocks on the device
+    fn block_count(&self) -> u64;
+
+    /// Read whole blocks starting at block `start` into `buffer`
+    fn read_blocks(&self, start: u64, buffer: &mut [u8]) -> Result<(), BlockError>;
+
+    /// Write whole blocks from `buffer` starting at block `start`
+    fn write_blocks(&self, start: u64, buffer: &[u8]) -> Result<(), BlockError>;
+
+    /// Make completed writes durable (e.g. flush the drive's write cache)
+    fn flush(&self) -> Result<(), BlockError> {
+        Ok(())
+    }
+
+    /// Size of the device in bytes
+    fn size(&self) -> u64 {
+        self.block_count() * self.block_size() as u64
+    }
+}
+
+/// Check that a request of `len` bytes at block `start` fits `device`,
+/// returning the number of blocks it covers
+pub fn check_request(device: &dyn BlockDevice, start: u64, len: usize) -> Result<u64, BlockError> {
+    let block_size = device.block_size();
+    if !len.is_multiple_of(block_size) {
+        return Err(BlockError::Misaligned);
+    }
+    let count = (len / block_size) as u64;
+    match start.checked_add(count) {
+        Some(end) if end <= device.block_count() => Ok(count),
+        _ => Err(BlockError::OutOfRange),
+    }
+}
+
+/// Read `buffer.len()` bytes at byte `offset`, any alignment
+///
+/// Stops at the end of the device; returns the number of bytes read.
+pub fn read_bytes(device: &dyn BlockDevice, offset: u64, buffer: &mut [u8]) -> Result<usize, BlockError> {
+    let block_size = device.block_size() as u64;
+    let len = (buffer.len() as u64).min(device.size().saturating_sub(offset)) as usize;
+
+    let mut done = 0;
+    let mut scratch = Vec::new();
+    while done < len {
+        let position = offset + done as u64;
+        let block = position / block_size;
+        let within = (position % block_size) as usize;
+
+        if within == 0 && len - done >= block_size as usize {
+            // Whole blocks go straight into the caller's buffer
+            let whole = (len - done) / block_size as usize * block_size as usize;
+            device.read_blocks(block, &mut buffer[done..done + whole])?;
+            done += whole;
+        } else {
+            scratch.resize(block_size as usize, 0);
+            device.read_blocks(block, &mut scratch)?;
+            let count = (block_size as usize - within).min(len - done);
+            buffer[done..done + count].copy_from_slice(&scratch[within..within + count]);
+            done += count;
+        }
+    }
+    Ok(len)
+}
+
+/// Write `buffer` at byte `offset`, any alignment
+///
+/// Partial blocks are read, patched and written back. Stops at the end of
+/// the device; returns the number of bytes written.
+pub fn write_bytes(device: &dyn BlockDevice, offset: u64, buffer: &[u8]) -> Result<usize, BlockError> {
+    let block_size = device.block_size() as u64;
+    let len = (buffer.len() as u64).min(device.size().saturating_sub(offset)) as usize;
+
+    let mut done = 0;
+    let mut scratch = Vec::new();
+    while done < len {
+        let position = offset + done as u64;
+        let block = position / block_size;
+        let within = (position % block_size) as usize;
+
+        if within == 0 && len - done >= block_size as usize {
+            let whole = (len - done) / block_size as usize * block_size as usize;
+            device.write_blocks(block, &buffer[done..done + whole])?;
+            done += whole;
+        } else {
+            scratch.resize(block_size as usize, 0);
+            device.read_blocks(block, &mut scratch)?;
+            let count = (block_size as usize - within).min(len - done);
+            scratch[within..within + count].copy_from_slice(&buffer[done..done + count]);
+            device.write_blocks(block, &scratch)?;
+            done += count;
+        }
+    }
+    Ok(len)
+}
+
+/// A block device kept in memory
+///
+/// Handy for testing filesystems: the storage is either a buffer handed
+/// over for good or a leaked heap allocation.
+pub struct RamDisk {
+    block_size: usize,
+    data: Mutex<&'static mut [u8]>,
+}
+
+impl RamDisk {
+    /// A zeroed RAM disk of `block_count` blocks on the heap
+    #[allow(dead_code)]
+    pub fn new(block_count: usize, block_size: usize) -> Self {
+        Self::from_static(vec![0u8; block_count * block_size].leak(), block_size)
+    }
+
+    /// A RAM disk over existing memory, e.g. a disk image
+    pub fn from_static(data: &'static mut [u8], block_size: usize) -> Self {
+        Self {
+            block_size,
+            data: Mutex::new(data),
+        }
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        (self.data.lock().len() / self.block_size) as u64
+    }
+
+    fn read_blocks(&self, start: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self, start, buffer.len())?;
+        let offset = start as usize * self.block_size;
+        buffer.copy_from_slice(&self.data.lock()[offset..offset + buffer.len()]);
+        Ok(())
+    }
+
+    fn write_blocks(&self, start: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        check_request(self, start, buffer.len())?;
+        let offset = start as usize * self.block_size;
+        self.data.lock()[offset..offset + buffer.len()].copy_from_slice(buffer);
+        Ok(())
+    }
+}
+
+/// Counters for one request queue
+#[derive(Debug, Clone, Copy, Default)]
+pub struct QueueStats {
+    /// Read requests served
+    pub reads: u64,
+    /// Write requests queued
+    pub writes: u64,
+    /// Queued writes folded into a neighbouring one
+    pub merges: u64,
+    /// Write requests sent to the driver
+    pub dispatched: u64,
+}
+
+/// A registered device with its queue of pending writes
+pub struct RequestQueue {
+    name: String,
+    device: Arc<dyn BlockDevice>,
+    /// Hold writes back for merging (false for stacked devices)
+    queued: bool,
+    /// Pending writes by first block; runs never overlap or touch
+    pending: Mutex<BTreeMap<u64, Vec<u8>>>,
+    stats: Mutex<QueueStats>,
+}
+
+impl RequestQueue {
+    fn new(name: &str, device: Arc<dyn BlockDevice>, queued: bool) -> Self {
+        Self {
+            name: name.to_string(),
+            device,
+            queued,
+            pending: Mutex::new(BTreeMap::new()),
+            stats: Mutex::new(QueueStats::default()),
+        }
+    }
+
+    /// Device name, as under /dev
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        *self.stats.lock()
+    }
+
+    /// Send every pending write to the driver, in block order
+    pub fn unplug(&self) -> Result<(), BlockError> {
+        let mut pending = self.pending.lock();
+        while let Some((start, data)) = pending.pop_first() {
+            if let Err(e) = self.device.write_blocks(start, &data) {
+                // Keep the run so a later sync can retry it
+                pending.insert(start, data);
+                return Err(e);
+            }
+            self.stats.lock().dispatched += 1;
+        }
+        Ok(())
+    }
+
+    /// Queue a write, merging it with pending writes it overlaps or touches
+    fn queue_write(&self, start: u64, data: &[u8]) -> Result<(), BlockError> {
+        let block_size = self.device.block_size() as u64;
+        let mut pending = self.pending.lock();
+
+        // Pending runs that overlap or are adjacent to [start, end)
+        let end = start + data.len() as u64 / block_size;
+        let neighbours: Vec<u64> = pending
+            .range(..=end)
+            .filter(|(&run_start, run)| run_start + run.len() as u64 / block_size >= start)
+            .map(|(&run_start, _)| run_start)
+            .collect();
+
+        let mut merged_start = start;
+        let mut merged_end = end;
+        for run_start in &neighbours {
+            let run_end = run_start + pending[run_start].len() as u64 / block_size;
+            merged_start = merged_start.min(*run_start);
+            merged_end = merged_end.max(run_end);
+        }
+
+        // Older data first, then the new write on top
+        let mut merged = vec![0u8; ((merged_end - merged_start) * block_size) as usize];
+        for run_start in &neighbours {
+            let run = pending.remove(run_start).unwrap_or_default();
+            let at = ((run_start - merged_start) * block_size) as usize;
+            merged[at..at + run.len()].copy_from_slice(&run);
+        }
+        let at = ((start - merged_start) * block_size) as usize;
+        merged[at..at + data.len()].copy_from_slice(data);
+        pending.insert(merged_start, merged);
+
+        let queued: usize = pending.values().map(Vec::len).sum();
+        drop(pending);
+
+        {
+            let mut stats = self.stats.lock();
+            stats.writes += 1;
+            stats.merges += neighbours.len() as u64;
+        }
+
+        if queued > QUEUE_LIMIT {
+            self.unplug()?;
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for RequestQueue {
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.device.block_count()
+    }
+
+    /// Read from the device, then lay queued writes over the result
+    fn read_blocks(&self, start: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        let count = check_request(self, start, buffer.len())?;
+        let block_size = self.device.block_size() as u64;
+        let end = start + count;
+
+        let pending = self.pending.lock();
+        self.device.read_blocks(start, buffer)?;
+        for (&run_start, run) in pending.range(..end) {
+            let run_end = run_start + run.len() as u64 / block_size;
+            if run_end <= start {
+                continue;
+            }
+            let from = run_start.max(start);
+            let to = run_end.min(end);
+            let dst = ((from - start) * block_size) as usize;
+            let src = ((from - run_start) * block_size) as usize;
+            let len = ((to - from) * block_size) as usize;
+            buffer[dst..dst + len].copy_from_slice(&run[src..src + len]);
+        }
+        drop(pending);
+
+        self.stats.lock().reads += 1;
+        Ok(())
+    }
+
+    fn write_blocks(&self, start: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        check_request(self, start, buffer.len())?;
+        if buffer.is_empty() {
+            return Ok(());
+        }
+        if !self.queued {
+            self.device.write_blocks(start, buffer)?;
+            let mut stats = self.stats.lock();
+            stats.writes += 1;
+            stats.dispatched += 1;
+            return Ok(());
+        }
+        self.queue_write(start, buffer)
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.unplug()?;
+        self.device.flush()
+    }
+}
+
+lazy_static! {
+    /// Registered devices in registration order; entries are never removed
+    static ref DEVICES: Mutex<Vec<Arc<RequestQueue>>> = Mutex::new(Vec::new());
+}
+
+/// Register a device under `name`, returning its queue
+pub fn register(name: &str, device: Arc<dyn BlockDevice>) -> Result<Arc<RequestQueue>, VfsError> {
+    add_device(RequestQueue::new(name, device, true))
+}
+
+/// Register a device layered on another registered device
+///
+/// Writes go straight through to `device`, which is expected to reach the
+/// lower device's queue, so that queue sees and merges all of them.
+pub fn register_stacked(name: &str, device: Arc<dyn BlockDevice>) -> Result<Arc<RequestQueue>, VfsError> {
+    add_device(RequestQueue::new(name, device, false))
+}
+
+fn add_device(queue: RequestQueue) -> Result<Arc<RequestQueue>, VfsError> {
+    let mut devices = DEVICES.lock();
+    if devices.iter().any(|existing| existing.name == queue.name) {
+        return Err(VfsError::AlreadyExists);
+    }
+    let queue = Arc::new(queue);
+    devices.push(queue.clone());
+    Ok(queue)
+}
+
+/// Find a registered device by name
+pub fn get(name: &str) -> Option<Arc<RequestQueue>> {
+    DEVICES.lock().iter().find(|queue| queue.name == name).cloned()
+}
+
+/// All registered devices, in registration order
+pub fn devices() -> Vec<Arc<RequestQueue>> {
+    DEVICES.lock().clone()
+}
+
+/// Dispatch the pending writes of every device
+pub fn unplug_all() -> Result<(), BlockError> {
+    devices().iter().try_for_each(|queue| queue.unplug())
+}
+
+/// Dispatch pending writes and flush every device
+pub fn sync_all() -> Result<(), BlockError> {
+    devices().iter().try_for_each(|queue| queue.flush())
+}
+
+/// Storage for /dev/ram0
+static mut RAMDISK_STORAGE: [u8; RAMDISK_BLOCKS * SECTOR_SIZE] = [0; RAMDISK_BLOCKS * SECTOR_SIZE];
+
+/// Register the boot-time RAM disk as `ram0`
+///
+/// Must be called only once.
+pub fn init_ramdisk() -> Result<Arc<RequestQueue>, VfsError> {
+    // SAFETY: called once at boot, so this is the only reference to the storage
+    let storage: &'static mut [u8] = unsafe { &mut *addr_of_mut!(RAMDISK_STORAGE) };
+    register("ram0", Arc::new(RamDisk::from_static(storage, SECTOR_SIZE)))
+}
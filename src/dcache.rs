@@ -0,0 +1,320 @@
+//! Dentry cache
+//!
+//! A dentry names an inode by its parent dentry and entry name. Path walks
+//! step from dentry to dentry, so they can start at any directory (such as
+//! a process's working directory) and `..` is just the parent pointer.
+//!
+//! Lookups are cached per (parent, name), including negative entries for
+//! names that do not exist. The VFS entry points in `mount` invalidate the
+//! entries they change; the least recently used entries are evicted once
+//! the cache is full.
+//!
+//! Dentries still in use (a working directory, or the parent of one) are
+//! also tracked apart from the LRU, so a lookup finds the same dentry after
+//! its cache entry was evicted, and a rename moves it wherever it is held.
+//!
+//! Code that changes a directory without going through `mount` (such as
+//! overlay copy-up writing into the upper tree) reports the change with
+//! `entry_added`/`entry_removed`, so no stale entries are left behind.
+
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use crate::vfs::Inode;
+
+/// Parent id under which root dentries are tracked (real ids start at 1)
+const ROOT_PARENT: u64 = 0;
+
+/// Maximum number of cached (parent, name) entries; kept small since
+/// every entry lives on the kernel heap
+const DCACHE_CAPACITY: usize = 64;
+
+/// A named reference to an inode
+pub struct Dentry {
+    /// Unique identity, used as the parent half of cache keys
+    id: u64,
+    inode: Arc<dyn Inode>,
+    /// Where the dentry sits in the tree; updated in place by renames
+    link: Mutex<DentryLink>,
+}
+
+struct DentryLink {
+    name: String,
+    /// `None` only for the root
+    parent: Option<Arc<Dentry>>,
+}
+
+impl Dentry {
+    fn new(name: &str, parent: Option<Arc<Dentry>>, inode: Arc<dyn Inode>) -> Arc<Self> {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        Arc::new(Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            inode,
+            link: Mutex::new(DentryLink {
+                name: name.to_string(),
+                parent,
+            }),
+        })
+    }
+
+    /// Create the root dentry for the root filesystem
+    pub fn new_root(inode: Arc<dyn Inode>) -> Arc<Self> {
+        let root = Self::new("/", None, inode);
+        DCACHE.lock().track((ROOT_PARENT, String::from("/")), &root);
+        root
+    }
+
+    /// The inode this dentry names
+    pub fn inode(&self) -> &Arc<dyn Inode> {
+        &self.inode
+    }
+
+    /// The containing directory, or `None` at the root
+    pub fn parent(&self) -> Option<Arc<Dentry>> {
+        self.link.lock().parent.clone()
+    }
+
+    /// Absolute path of this dentry, rebuilt from the parent chain
+    pub fn path(&self) -> String {
+        let mut names: Vec<String> = Vec::new();
+        let (mut name, mut parent) = {
+            let link = self.link.lock();
+            (link.name.clone(), link.parent.clone())
+        };
+        while let Some(dentry) = parent {
+            names.push(name);
+            let link = dentry.link.lock();
+            name = link.name.clone();
+            parent = link.parent.clone();
+        }
+
+        if names.is_empty() {
+            return String::from("/");
+        }
+        let mut path = String::new();
+        for name in names.iter().rev() {
+            path.push('/');
+            path.push_str(name);
+        }
+        path
+    }
+}
+
+/// A cached lookup: `None` records that the name does not exist
+type CacheEntry = Option<Arc<Dentry>>;
+
+/// (parent dentry id, name)
+type DentryKey = (u64, String);
+
+/// A dentry tracked while anything holds it
+struct LiveDentry {
+    dentry: Weak<Dentry>,
+    id: u64,
+    /// Address of its inode, to find the dentries of a directory
+    inode: usize,
+}
+
+/// Cache of (parent dentry, name) lookups with LRU eviction
+pub struct DentryCache {
+    /// Entries with the stamp of their last use
+    entries: BTreeMap<DentryKey, (u64, CacheEntry)>,
+    /// Last-use stamps in order, oldest first
+    lru: BTreeMap<u64, DentryKey>,
+    next_stamp: u64,
+    /// Every dentry created through the cache, whether cached or not
+    live: BTreeMap<DentryKey, LiveDentry>,
+}
+
+impl DentryCache {
+    pub const fn new() -> Self {
+        Self {
+            entries: BTreeMap::new(),
+            lru: BTreeMap::new(),
+            next_stamp: 0,
+            live: BTreeMap::new(),
+        }
+    }
+
+    fn stamp(&mut self) -> u64 {
+        self.next_stamp += 1;
+        self.next_stamp
+    }
+
+    fn get(&mut self, key: &DentryKey) -> Option<CacheEntry> {
+        let stamp = self.stamp();
+        let (old_stamp, entry) = self.entries.get_mut(key)?;
+        self.lru.remove(old_stamp);
+        *old_stamp = stamp;
+        self.lru.insert(stamp, key.clone());
+        Some(entry.clone())
+    }
+
+    fn insert(&mut self, key: DentryKey, entry: CacheEntry) {
+        self.remove(&key);
+        while self.entries.len() >= DCACHE_CAPACITY {
+            let Some((_, oldest)) = self.lru.pop_first() else {
+                break;
+            };
+            self.entries.remove(&oldest);
+        }
+
+        let stamp = self.stamp();
+        self.lru.insert(stamp, key.clone());
+        self.entries.insert(key, (stamp, entry));
+    }
+
+    fn remove(&mut self, key: &DentryKey) -> Option<CacheEntry> {
+        let (stamp, entry) = self.entries.remove(key)?;
+        self.lru.remove(&stamp);
+        Some(entry)
+    }
+
+    fn clear_lookups(&mut self) {
+        self.entries.clear();
+        self.lru.clear();
+    }
+
+    fn track(&mut self, key: DentryKey, dentry: &Arc<Dentry>) {
+        // Forget dentries nobody holds any more before the table grows
+        if self.live.len() >= DCACHE_CAPACITY {
+            self.live.retain(|_, live| live.dentry.strong_count() > 0);
+        }
+        let inode = Arc::as_ptr(&dentry.inode) as *const () as usize;
+        self.live.insert(key, LiveDentry { dentry: Arc::downgrade(dentry), id: dentry.id, inode });
+    }
+
+    fn live(&self, key: &DentryKey) -> Option<Arc<Dentry>> {
+        self.live.get(key)?.dentry.upgrade()
+    }
+
+    /// Ids of the live dentries naming `inode`
+    fn dentries_of(&self, inode: &dyn Inode) -> Vec<u64> {
+        let address = inode as *const dyn Inode as *const () as usize;
+        self.live
+            .values()
+            .filter(|live| live.inode == address && live.dentry.strong_count() > 0)
+            .map(|live| live.id)
+            .collect()
+    }
+}
+
+impl Default for DentryCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+lazy_static! {
+    /// Global dentry cache
+    static ref DCACHE: Mutex<DentryCache> = Mutex::new(DentryCache::new());
+}
+
+fn key(parent: &Dentry, name: &str) -> DentryKey {
+    (parent.id, name.to_string())
+}
+
+/// Look up a cached child of `parent`
+///
+/// Returns `None` on a miss, `Some(None)` for a cached negative entry.
+pub fn lookup(parent: &Dentry, name: &str) -> Option<CacheEntry> {
+    DCACHE.lock().get(&key(parent, name))
+}
+
+/// Get the dentry for a child found by a lookup
+///
+/// A dentry that was evicted but is still in use for the same inode is
+/// handed out again, so there is only one to keep up to date. The result
+/// is cached unless the parent directory asks not to be.
+pub fn insert(parent: &Arc<Dentry>, name: &str, inode: Arc<dyn Inode>) -> Arc<Dentry> {
+    if !parent.inode.cache_lookups() {
+        return Dentry::new(name, Some(parent.clone()), inode);
+    }
+
+    let key = key(parent, name);
+    let mut cache = DCACHE.lock();
+    let dentry = match cache.live(&key) {
+        Some(dentry) if Arc::as_ptr(&dentry.inode) as *const () == Arc::as_ptr(&inode) as *const () => dentry,
+        _ => {
+            let dentry = Dentry::new(name, Some(parent.clone()), inode);
+            cache.track(key.clone(), &dentry);
+            dentry
+        }
+    };
+    cache.insert(key, Some(dentry.clone()));
+    dentry
+}
+
+/// Record that `name` does not exist in `parent`
+pub fn insert_negative(parent: &Arc<Dentry>, name: &str) {
+    if parent.inode.cache_lookups() {
+        DCACHE.lock().insert(key(parent, name), None);
+    }
+}
+
+/// Forget the cached lookup of `name` in `parent`
+pub fn invalidate(parent: &Dentry, name: &str) {
+    let key = key(parent, name);
+    let mut cache = DCACHE.lock();
+    cache.remove(&key);
+    cache.live.remove(&key);
+}
+
+/// Update the cache after an entry was renamed
+///
+/// The dentry for the old name is moved rather than dropped, even if it
+/// was evicted, so holders such as working directories see the new path.
+pub fn rename(old_parent: &Dentry, old_name: &str, new_parent: &Arc<Dentry>, new_name: &str) {
+    let (old_key, new_key) = (key(old_parent, old_name), key(new_parent, new_name));
+    let mut cache = DCACHE.lock();
+    cache.remove(&new_key);
+    cache.live.remove(&new_key);
+    cache.remove(&old_key);
+
+    let Some(dentry) = cache.live.remove(&old_key).and_then(|live| live.dentry.upgrade()) else {
+        return;
+    };
+    {
+        let mut link = dentry.link.lock();
+        link.name = new_name.to_string();
+        link.parent = Some(new_parent.clone());
+    }
+    cache.track(new_key.clone(), &dentry);
+    cache.insert(new_key, Some(dentry));
+}
+
+/// Report that `name` was created in the directory `dir` by code that does
+/// not go through `mount`, dropping negative entries for it
+pub fn entry_added(dir: &dyn Inode, name: &str) {
+    let mut cache = DCACHE.lock();
+    for parent in cache.dentries_of(dir) {
+        let key = (parent, name.to_string());
+        if let Some((_, None)) = cache.entries.get(&key) {
+            cache.remove(&key);
+        }
+    }
+}
+
+/// Report that `name` was removed from the directory `dir` by code that
+/// does not go through `mount`, dropping any entry for it
+pub fn entry_removed(dir: &dyn Inode, name: &str) {
+    let mut cache = DCACHE.lock();
+    for parent in cache.dentries_of(dir) {
+        let key = (parent, name.to_string());
+        cache.remove(&key);
+        cache.live.remove(&key);
+    }
+}
+
+/// Drop every cached lookup (after mounting or unmounting)
+///
+/// Live dentries stay tracked: each still names the inode it did, and one
+/// held across the mount, such as a working directory, must keep being
+/// found again and moved by renames.
+pub fn flush_lookups() {
+    DCACHE.lock().clear_lookups();
+}
@@ -1,11 +1,18 @@
 //! Device Filesystem (devfs)
 //!
-//! Provides special device files like /dev/null, /dev/zero, /dev/tty
+//! Provides special device files like /dev/null, /dev/zero, /dev/tty, and
+//! a node for every registered block device (/dev/hda, /dev/ram0, ...)
 
-use crate::vfs::{Inode, FileType, VfsError};
+use crate::block::{self, BlockDevice, RequestQueue};
+use crate::keyboard;
+use crate::time::Timespec;
+use crate::vfs::{current_time, next_ino, DirEntry, Filesystem, Inode, FileType, Metadata, Readiness, VfsError};
+use crate::wait::{wait_event, WaitQueue, WaitResult};
+use alloc::collections::BTreeMap;
 use alloc::string::String;
 use alloc::vec::Vec;
 use alloc::sync::Arc;
+use lazy_static::lazy_static;
 use spin::Mutex;
 
 /// Device types supported by devfs
@@ -21,6 +28,8 @@ pub struct DeviceNode {
     dev_type: DeviceType,
     #[allow(dead_code)]
     name: String,
+    ino: u64,
+    created: Timespec,
 }
 
 impl DeviceNode {
@@ -28,6 +37,8 @@ impl DeviceNode {
         Arc::new(Mutex::new(Self {
             dev_type,
             name: String::from(name),
+            ino: next_ino(),
+            created: current_time(),
         }))
     }
 }
@@ -48,9 +59,17 @@ impl Inode for Mutex<DeviceNode> {
                 Ok(buffer.len())
             }
             DeviceType::Console => {
-                // For now, console read is not implemented
-                // TODO: Implement keyboard buffer reading
-                Err(VfsError::NotImplemented)
+                drop(dev);
+                if buffer.is_empty() {
+                    return Ok(0);
+                }
+
+                // Block until something has been typed
+                let result = wait_event(&[&keyboard::INPUT_WAIT], None, keyboard::input_available);
+                if result == WaitResult::Interrupted {
+                    return Err(VfsError::Interrupted);
+                }
+                Ok(keyboard::read_input(buffer))
             }
         }
     }
@@ -105,17 +124,194 @@ impl Inode for Mutex<DeviceNode> {
     fn remove(&self, _name: &str) -> Result<(), VfsError> {
         Err(VfsError::NotADirectory)
     }
+
+    fn metadata(&self) -> Metadata {
+        let dev = self.lock();
+        Metadata::synthesized(dev.ino, FileType::Device, 0, dev.created)
+    }
+
+    fn poll(&self) -> Readiness {
+        match self.lock().dev_type {
+            DeviceType::Console => Readiness {
+                readable: keyboard::input_available(),
+                writable: true,
+                hangup: false,
+            },
+            DeviceType::Null | DeviceType::Zero => Readiness::always(),
+        }
+    }
+
+    fn wait_queue(&self) -> Option<&WaitQueue> {
+        match self.lock().dev_type {
+            DeviceType::Console => Some(&keyboard::INPUT_WAIT),
+            DeviceType::Null | DeviceType::Zero => None,
+        }
+    }
+}
+
+/// A block device node, reading and writing through the device's request queue
+pub struct BlockDeviceNode {
+    queue: Arc<RequestQueue>,
+    ino: u64,
+    created: Timespec,
+}
+
+lazy_static! {
+    /// One node per block device, shared by every devfs instance so a
+    /// device keeps a single inode identity
+    static ref BLOCK_NODES: Mutex<BTreeMap<String, Arc<dyn Inode>>> = Mutex::new(BTreeMap::new());
+}
+
+/// The node for the registered block device `queue`
+fn block_node(queue: Arc<RequestQueue>) -> Arc<dyn Inode> {
+    BLOCK_NODES
+        .lock()
+        .entry(String::from(queue.name()))
+        .or_insert_with(|| {
+            Arc::new(BlockDeviceNode {
+                queue,
+                ino: next_ino(),
+                created: current_time(),
+            })
+        })
+        .clone()
+}
+
+impl Inode for BlockDeviceNode {
+    fn read(&self, offset: usize, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        Ok(block::read_bytes(self.queue.as_ref(), offset as u64, buffer)?)
+    }
+
+    fn write(&self, offset: usize, buffer: &[u8]) -> Result<usize, VfsError> {
+        if offset as u64 >= self.queue.size() && !buffer.is_empty() {
+            return Err(VfsError::NoSpace);
+        }
+        Ok(block::write_bytes(self.queue.as_ref(), offset as u64, buffer)?)
+    }
+
+    fn file_type(&self) -> FileType {
+        FileType::BlockDevice
+    }
+
+    fn size(&self) -> usize {
+        self.queue.size() as usize
+    }
+
+    fn lookup(&self, _name: &str) -> Result<Arc<dyn Inode>, VfsError> {
+        Err(VfsError::NotADirectory)
+    }
+
+    fn create(&self, _name: &str, _file_type: FileType) -> Result<Arc<dyn Inode>, VfsError> {
+        Err(VfsError::NotADirectory)
+    }
+
+    fn list(&self) -> Result<Vec<String>, VfsError> {
+        Err(VfsError::NotADirectory)
+    }
+
+    fn truncate(&self, _size: usize) -> Result<(), VfsError> {
+        Err(VfsError::InvalidOperation)
+    }
+
+    fn remove(&self, _name: &str) -> Result<(), VfsError> {
+        Err(VfsError::NotADirectory)
+    }
+
+    fn metadata(&self) -> Metadata {
+        Metadata::synthesized(self.ino, FileType::BlockDevice, self.queue.size(), self.created)
+    }
+}
+
+/// Root directory of devfs, holding the device nodes
+pub struct DevFsRoot {
+    devices: Mutex<Vec<(String, Arc<dyn Inode>)>>,
+    ino: u64,
+    created: Timespec,
+}
+
+impl Inode for DevFsRoot {
+    fn read(&self, _offset: usize, _buffer: &mut [u8]) -> Result<usize, VfsError> {
+        Err(VfsError::IsADirectory)
+    }
+
+    fn write(&self, _offset: usize, _buffer: &[u8]) -> Result<usize, VfsError> {
+        Err(VfsError::IsADirectory)
+    }
+
+    fn file_type(&self) -> FileType {
+        FileType::Directory
+    }
+
+    fn size(&self) -> usize {
+        0
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, VfsError> {
+        let devices = self.devices.lock();
+        for (dev_name, dev_inode) in devices.iter() {
+            if dev_name == name {
+                return Ok(dev_inode.clone());
+            }
+        }
+        block::get(name).map(block_node).ok_or(VfsError::NotFound)
+    }
+
+    fn create(&self, _name: &str, _file_type: FileType) -> Result<Arc<dyn Inode>, VfsError> {
+        Err(VfsError::PermissionDenied)
+    }
+
+    fn list(&self) -> Result<Vec<String>, VfsError> {
+        let devices = self.devices.lock();
+        let mut names: Vec<String> = devices.iter().map(|(name, _)| name.clone()).collect();
+        names.extend(block::devices().iter().map(|queue| String::from(queue.name())));
+        Ok(names)
+    }
+
+    /// Fixed devices come first, then block devices in registration order.
+    /// Both are only ever added to, so the position is a stable cursor.
+    fn readdir(&self, cursor: u64) -> Result<Option<(DirEntry, u64)>, VfsError> {
+        let devices = self.devices.lock();
+        let (name, device) = match devices.get(cursor as usize) {
+            Some((name, device)) => (name.clone(), device.clone()),
+            None => match block::devices().into_iter().nth(cursor as usize - devices.len()) {
+                Some(queue) => (String::from(queue.name()), block_node(queue)),
+                None => return Ok(None),
+            },
+        };
+        let entry = DirEntry {
+            name,
+            ino: device.metadata().ino,
+            file_type: device.file_type(),
+        };
+        Ok(Some((entry, cursor + 1)))
+    }
+
+    fn truncate(&self, _size: usize) -> Result<(), VfsError> {
+        Err(VfsError::IsADirectory)
+    }
+
+    fn remove(&self, _name: &str) -> Result<(), VfsError> {
+        Err(VfsError::PermissionDenied)
+    }
+
+    fn metadata(&self) -> Metadata {
+        Metadata::synthesized(self.ino, FileType::Directory, 0, self.created)
+    }
 }
 
 /// DevFS - Device filesystem
 pub struct DevFs {
-    devices: Mutex<Vec<(String, Arc<dyn Inode>)>>,
+    root: Arc<DevFsRoot>,
 }
 
 impl DevFs {
     pub fn new() -> Self {
         let devfs = Self {
-            devices: Mutex::new(Vec::new()),
+            root: Arc::new(DevFsRoot {
+                devices: Mutex::new(Vec::new()),
+                ino: next_ino(),
+                created: current_time(),
+            }),
         };
 
         // Create standard device nodes
@@ -128,22 +324,25 @@ impl DevFs {
     }
 
     fn add_device(&self, name: &str, device: Arc<dyn Inode>) {
-        let mut devices = self.devices.lock();
+        let mut devices = self.root.devices.lock();
         devices.push((String::from(name), device));
     }
 
     pub fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, VfsError> {
-        let devices = self.devices.lock();
-        for (dev_name, dev_inode) in devices.iter() {
-            if dev_name == name {
-                return Ok(dev_inode.clone());
-            }
-        }
-        Err(VfsError::NotFound)
+        self.root.lookup(name)
     }
 
     pub fn list(&self) -> Result<Vec<String>, VfsError> {
-        let devices = self.devices.lock();
-        Ok(devices.iter().map(|(name, _)| name.clone()).collect())
+        self.root.list()
+    }
+}
+
+impl Filesystem for DevFs {
+    fn name(&self) -> &'static str {
+        "devfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
     }
 }
@@ -0,0 +1,251 @@
+//! epoll: scalable readiness notification
+//!
+//! An epoll instance keeps an interest list of descriptors and a ready
+//! list. Adding a descriptor registers a callback on its inode's wait
+//! queue; whenever the queue wakes, the descriptor is put on the ready
+//! list. `epoll_wait` then only checks descriptors on the ready list
+//! instead of polling every registered one. Notification is
+//! level-triggered: a descriptor stays on the ready list while it is ready.
+
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::time::Timespec;
+use crate::vfs::{current_time, next_ino, FileType, Inode, Metadata, Readiness, VfsError};
+use crate::wait::{wait_event, WaitQueue, WaitResult, Wakeable};
+
+/// epoll_ctl operations
+pub const EPOLL_CTL_ADD: u64 = 1;
+pub const EPOLL_CTL_DEL: u64 = 2;
+pub const EPOLL_CTL_MOD: u64 = 3;
+
+/// Event bits (`EpollEvent::events`)
+pub const EPOLLIN: u32 = 0x001;
+pub const EPOLLOUT: u32 = 0x004;
+pub const EPOLLERR: u32 = 0x008;
+pub const EPOLLHUP: u32 = 0x010;
+
+/// An event as laid out in user memory (struct epoll_event, packed on x86_64)
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EpollEvent {
+    pub events: u32,
+    pub data: u64,
+}
+
+/// A registered descriptor
+struct Interest {
+    inode: Arc<dyn Inode>,
+    events: u32,
+    data: u64,
+}
+
+/// An epoll instance
+pub struct Epoll {
+    me: Weak<Epoll>,
+    ino: u64,
+    created: Timespec,
+    interests: Mutex<BTreeMap<usize, Interest>>,
+    /// Descriptors that may be ready; locked from interrupt context
+    ready: Mutex<BTreeSet<usize>>,
+    /// Woken when a descriptor is added to the ready list
+    wait: WaitQueue,
+}
+
+impl Epoll {
+    pub fn new() -> Arc<Self> {
+        Arc::new_cyclic(|me| Self {
+            me: me.clone(),
+            ino: next_ino(),
+            created: current_time(),
+            interests: Mutex::new(BTreeMap::new()),
+            ready: Mutex::new(BTreeSet::new()),
+            wait: WaitQueue::new(),
+        })
+    }
+
+    fn callback(&self) -> Weak<dyn Wakeable> {
+        self.me.clone()
+    }
+
+    fn mark_ready(&self, fd: usize) {
+        without_interrupts(|| self.ready.lock().insert(fd));
+        self.wait.wake_all();
+    }
+
+    /// Start watching `fd` (EPOLL_CTL_ADD)
+    pub fn add(&self, fd: usize, inode: Arc<dyn Inode>, event: EpollEvent) -> Result<(), VfsError> {
+        // An instance watching itself could never make progress
+        if core::ptr::addr_eq(Arc::as_ptr(&inode), self as *const Self) {
+            return Err(VfsError::InvalidArgument);
+        }
+
+        let mut interests = self.interests.lock();
+        if interests.contains_key(&fd) {
+            return Err(VfsError::AlreadyExists);
+        }
+
+        if let Some(queue) = inode.wait_queue() {
+            queue.add_callback(self.callback(), fd as u64);
+        }
+        interests.insert(fd, Interest { inode, events: event.events, data: event.data });
+        drop(interests);
+
+        // Check the initial state on the next wait
+        self.mark_ready(fd);
+        Ok(())
+    }
+
+    /// Change the events and data for `fd` (EPOLL_CTL_MOD)
+    pub fn modify(&self, fd: usize, event: EpollEvent) -> Result<(), VfsError> {
+        let mut interests = self.interests.lock();
+        let interest = interests.get_mut(&fd).ok_or(VfsError::NotFound)?;
+        interest.events = event.events;
+        interest.data = event.data;
+        drop(interests);
+
+        self.mark_ready(fd);
+        Ok(())
+    }
+
+    /// Stop watching `fd` (EPOLL_CTL_DEL)
+    pub fn delete(&self, fd: usize) -> Result<(), VfsError> {
+        let interest = self.interests.lock().remove(&fd).ok_or(VfsError::NotFound)?;
+        if let Some(queue) = interest.inode.wait_queue() {
+            queue.remove_callback(&self.callback(), fd as u64);
+        }
+        without_interrupts(|| self.ready.lock().remove(&fd));
+        Ok(())
+    }
+
+    /// Gather up to `max` events from descriptors on the ready list
+    fn collect(&self, max: usize) -> Vec<EpollEvent> {
+        let candidates: Vec<usize> = without_interrupts(|| self.ready.lock().iter().copied().collect());
+        let interests = self.interests.lock();
+        let mut events = Vec::new();
+
+        for fd in candidates {
+            if events.len() >= max {
+                break;
+            }
+
+            // Take the descriptor off the list before checking it, so a
+            // wake-up racing with the check puts it back
+            without_interrupts(|| self.ready.lock().remove(&fd));
+
+            let Some(interest) = interests.get(&fd) else {
+                continue;
+            };
+
+            let occurred = readiness_events(interest.inode.poll()) & (interest.events | EPOLLERR | EPOLLHUP);
+            if occurred != 0 {
+                events.push(EpollEvent { events: occurred, data: interest.data });
+                // Level-triggered: report it again next time if still ready
+                without_interrupts(|| self.ready.lock().insert(fd));
+            }
+        }
+
+        events
+    }
+
+    /// Wait for up to `max` events
+    ///
+    /// `deadline` is an absolute monotonic time; `None` waits forever.
+    /// Returns an empty list if the deadline passed.
+    pub fn wait(&self, max: usize, deadline: Option<u64>) -> Result<Vec<EpollEvent>, VfsError> {
+        let mut events = Vec::new();
+
+        let result = wait_event(&[&self.wait], deadline, || {
+            events = self.collect(max);
+            !events.is_empty()
+        });
+
+        match result {
+            WaitResult::Interrupted => Err(VfsError::Interrupted),
+            WaitResult::Ready | WaitResult::TimedOut => Ok(events),
+        }
+    }
+}
+
+impl Wakeable for Epoll {
+    fn wake(&self, key: u64) {
+        self.mark_ready(key as usize);
+    }
+}
+
+impl Inode for Epoll {
+    fn read(&self, _offset: usize, _buffer: &mut [u8]) -> Result<usize, VfsError> {
+        Err(VfsError::InvalidOperation)
+    }
+
+    fn write(&self, _offset: usize, _buffer: &[u8]) -> Result<usize, VfsError> {
+        Err(VfsError::InvalidOperation)
+    }
+
+    fn file_type(&self) -> FileType {
+        FileType::Device
+    }
+
+    fn size(&self) -> usize {
+        0
+    }
+
+    fn lookup(&self, _name: &str) -> Result<Arc<dyn Inode>, VfsError> {
+        Err(VfsError::NotADirectory)
+    }
+
+    fn create(&self, _name: &str, _file_type: FileType) -> Result<Arc<dyn Inode>, VfsError> {
+        Err(VfsError::NotADirectory)
+    }
+
+    fn list(&self) -> Result<Vec<String>, VfsError> {
+        Err(VfsError::NotADirectory)
+    }
+
+    fn truncate(&self, _size: usize) -> Result<(), VfsError> {
+        Err(VfsError::InvalidOperation)
+    }
+
+    fn remove(&self, _name: &str) -> Result<(), VfsError> {
+        Err(VfsError::NotADirectory)
+    }
+
+    fn metadata(&self) -> Metadata {
+        let mut meta = Metadata::synthesized(self.ino, FileType::Device, 0, self.created);
+        meta.mode = 0o600;
+        meta
+    }
+
+    /// An epoll instance is readable when any watched descriptor is ready,
+    /// so instances can be nested or polled
+    fn poll(&self) -> Readiness {
+        Readiness {
+            readable: !self.collect(1).is_empty(),
+            writable: false,
+            hangup: false,
+        }
+    }
+
+    fn wait_queue(&self) -> Option<&WaitQueue> {
+        Some(&self.wait)
+    }
+}
+
+/// Translate inode readiness into epoll event bits
+fn readiness_events(readiness: Readiness) -> u32 {
+    let mut events = 0;
+    if readiness.readable {
+        events |= EPOLLIN;
+    }
+    if readiness.writable {
+        events |= EPOLLOUT;
+    }
+    if readiness.hangup {
+        events |= EPOLLHUP;
+    }
+    events
+}
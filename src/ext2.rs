@@ -0,0 +1,1828 @@
+//! ext2 filesystem
+//!
+//! Reads and writes ext2 volumes such as those made by `mke2fs -t ext2`:
+//! the superblock and block group descriptors, block and inode bitmaps,
+//! inode tables, files mapped through direct, indirect, double and triple
+//! indirect blocks, and linear directories. Owners, permissions, symbolic
+//! links and hard links are stored as on Linux.
+//!
+//! Volumes with incompatible features other than directory entry file
+//! types are refused. Unknown read-only compatible features (such as
+//! group descriptor checksums) make the mount read-only. Directories with
+//! a hash index are read linearly, and the index flag is dropped when
+//! such a directory is changed, as the index would no longer match.
+//!
+//! File data goes through the page cache: writes reserve the blocks they
+//! need right away, so running out of space is reported by `write`, and
+//! the data reaches the blocks when the page is written back. Metadata
+//! goes through the buffer cache. Both reach the disk on `sync` or
+//! unmount, when the superblock counters are written too. Access times
+//! are not updated on reads.
+//!
+//! As on Linux, an inode whose last link is removed while it is still in
+//! use is freed when the last reference goes away.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, Ordering};
+use spin::Mutex;
+
+use crate::bcache::{self, Buffer};
+use crate::block::{self, BlockDevice};
+use crate::pagecache;
+use crate::time::Timespec;
+use crate::vfs::{
+    current_time, downcast_inode, DirEntry, FileType, Filesystem, Inode, Metadata, SetAttr, VfsError, MODE_PERMISSION_MASK,
+    S_IFBLK, S_IFCHR, S_IFDIR, S_IFIFO, S_IFLNK, S_IFSOCK,
+};
+
+/// The superblock is 1024 bytes at byte 1024, whatever the block size
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const EXT2_MAGIC: u16 = 0xEF53;
+
+/// Superblock `s_state` bits
+const STATE_VALID: u16 = 1;
+const STATE_ERROR: u16 = 2;
+
+/// Feature flags this driver understands
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+const SUPPORTED_INCOMPAT: u32 = INCOMPAT_FILETYPE;
+const RO_COMPAT_SPARSE_SUPER: u32 = 0x0001;
+const RO_COMPAT_LARGE_FILE: u32 = 0x0002;
+const SUPPORTED_RO_COMPAT: u32 = RO_COMPAT_SPARSE_SUPER | RO_COMPAT_LARGE_FILE;
+
+/// Values for revision 0 volumes, which have no such superblock fields
+const GOOD_OLD_REV: u32 = 0;
+const GOOD_OLD_FIRST_INO: u32 = 11;
+const GOOD_OLD_INODE_SIZE: usize = 128;
+
+const ROOT_INO: u32 = 2;
+const GROUP_DESC_SIZE: usize = 32;
+/// Largest supported block size: one buffer cache buffer
+const MAX_BLOCK_SIZE: usize = bcache::BUFFER_SIZE;
+
+/// Slots of `i_block`: 12 direct blocks, then single, double and triple
+/// indirect blocks
+const DIRECT_BLOCKS: usize = 12;
+const BLOCK_SLOTS: usize = 15;
+/// Symbolic link targets shorter than this are kept in `i_block`
+const FAST_SYMLINK_MAX: usize = BLOCK_SLOTS * 4;
+
+/// Inode flag: the directory has a hash index
+const INDEX_FL: u32 = 0x1000;
+
+/// Most links an inode may have
+const LINK_MAX: u16 = 32000;
+const NAME_MAX: usize = 255;
+/// Header of a directory entry: inode, record length, name length, type
+const DIR_ENTRY_HEADER: usize = 8;
+
+/// Directory entry file types
+const FT_UNKNOWN: u8 = 0;
+const FT_REG_FILE: u8 = 1;
+const FT_DIR: u8 = 2;
+const FT_CHRDEV: u8 = 3;
+const FT_BLKDEV: u8 = 4;
+const FT_FIFO: u8 = 5;
+const FT_SOCK: u8 = 6;
+const FT_SYMLINK: u8 = 7;
+
+/// Extended attribute block header
+const XATTR_MAGIC: u32 = 0xEA02_0000;
+
+const S_IFMT: u16 = 0o170000;
+
+fn read_u16(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([bytes[at], bytes[at + 1]])
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}
+
+fn put_u16(bytes: &mut [u8], at: usize, value: u16) {
+    bytes[at..at + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(bytes: &mut [u8], at: usize, value: u32) {
+    bytes[at..at + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// ext2 times are 32-bit seconds since the epoch
+fn to_ext2_time(time: Timespec) -> u32 {
+    time.tv_sec.clamp(0, u32::MAX as i64) as u32
+}
+
+fn from_ext2_time(secs: u32) -> Timespec {
+    Timespec {
+        tv_sec: secs as i64,
+        tv_nsec: 0,
+    }
+}
+
+fn file_type_of(mode: u16) -> FileType {
+    match (mode & S_IFMT) as u32 {
+        S_IFDIR => FileType::Directory,
+        S_IFLNK => FileType::Symlink,
+        S_IFCHR => FileType::Device,
+        S_IFBLK => FileType::BlockDevice,
+        S_IFIFO => FileType::Fifo,
+        S_IFSOCK => FileType::Socket,
+        _ => FileType::Regular,
+    }
+}
+
+/// The `file_type` byte of a directory entry
+fn entry_type(file_type: FileType) -> u8 {
+    match file_type {
+        FileType::Regular => FT_REG_FILE,
+        FileType::Directory => FT_DIR,
+        FileType::Device => FT_CHRDEV,
+        FileType::BlockDevice => FT_BLKDEV,
+        FileType::Fifo => FT_FIFO,
+        FileType::Socket => FT_SOCK,
+        FileType::Symlink => FT_SYMLINK,
+    }
+}
+
+fn entry_file_type(file_type: u8) -> Option<FileType> {
+    match file_type {
+        FT_REG_FILE => Some(FileType::Regular),
+        FT_DIR => Some(FileType::Directory),
+        FT_CHRDEV => Some(FileType::Device),
+        FT_BLKDEV => Some(FileType::BlockDevice),
+        FT_FIFO => Some(FileType::Fifo),
+        FT_SOCK => Some(FileType::Socket),
+        FT_SYMLINK => Some(FileType::Symlink),
+        _ => None,
+    }
+}
+
+/// Space a directory entry with a name of `name_len` bytes takes up
+fn entry_size(name_len: usize) -> usize {
+    (DIR_ENTRY_HEADER + name_len).next_multiple_of(4)
+}
+
+fn validate_name(name: &str) -> Result<(), VfsError> {
+    if name.is_empty() || name == "." || name == ".." || name.len() > NAME_MAX || name.contains(['/', '\0']) {
+        return Err(VfsError::InvalidArgument);
+    }
+    Ok(())
+}
+
+/// A directory entry record within a block
+struct Record {
+    /// Offset in the block
+    at: usize,
+    ino: u32,
+    rec_len: usize,
+    name_len: usize,
+    file_type: u8,
+}
+
+impl Record {
+    fn parse(data: &[u8], at: usize, filetype: bool) -> Result<Self, VfsError> {
+        if at + DIR_ENTRY_HEADER > data.len() {
+            return Err(VfsError::IoError);
+        }
+        let rec_len = read_u16(data, at + 4) as usize;
+        // Without the filetype feature the name length is 16 bits
+        let (name_len, file_type) = if filetype {
+            (data[at + 6] as usize, data[at + 7])
+        } else {
+            (read_u16(data, at + 6) as usize, FT_UNKNOWN)
+        };
+        if rec_len < DIR_ENTRY_HEADER
+            || !rec_len.is_multiple_of(4)
+            || at + rec_len > data.len()
+            || DIR_ENTRY_HEADER + name_len > rec_len
+        {
+            crate::serial_println!("ext2: corrupt directory entry at offset {}", at);
+            return Err(VfsError::IoError);
+        }
+        Ok(Self {
+            at,
+            ino: read_u32(data, at),
+            rec_len,
+            name_len,
+            file_type,
+        })
+    }
+
+    fn name<'a>(&self, data: &'a [u8]) -> &'a [u8] {
+        &data[self.at + DIR_ENTRY_HEADER..self.at + DIR_ENTRY_HEADER + self.name_len]
+    }
+
+    /// Space the entry needs; a free entry needs none
+    fn used(&self) -> usize {
+        if self.ino == 0 {
+            0
+        } else {
+            entry_size(self.name_len)
+        }
+    }
+}
+
+/// Write a directory entry at `at` of a directory block
+fn write_record(data: &mut [u8], at: usize, ino: u32, rec_len: usize, name: &[u8], file_type: u8) {
+    put_u32(data, at, ino);
+    put_u16(data, at + 4, rec_len as u16);
+    data[at + 6] = name.len() as u8;
+    data[at + 7] = file_type;
+    data[at + DIR_ENTRY_HEADER..at + DIR_ENTRY_HEADER + name.len()].copy_from_slice(name);
+}
+
+/// A block group descriptor
+#[derive(Debug, Clone, Copy)]
+struct GroupDesc {
+    block_bitmap: u32,
+    inode_bitmap: u32,
+    inode_table: u32,
+    free_blocks: u16,
+    free_inodes: u16,
+    used_dirs: u16,
+}
+
+/// Allocation state, written back to the superblock on sync
+struct SuperState {
+    groups: Vec<GroupDesc>,
+    free_blocks: u32,
+    free_inodes: u32,
+    ro_compat: u32,
+}
+
+/// A mounted ext2 volume: geometry, bitmaps and inode tables
+struct Volume {
+    device: Arc<dyn BlockDevice>,
+    block_size: usize,
+    first_data_block: u32,
+    blocks_count: u32,
+    blocks_per_group: u32,
+    inodes_per_group: u32,
+    inodes_count: u32,
+    inode_size: usize,
+    /// First inode not reserved for the filesystem itself
+    first_ino: u32,
+    revision: u32,
+    /// Directory entries carry the file type
+    filetype: bool,
+    read_only: bool,
+    state: Mutex<SuperState>,
+    /// Serializes changes to directories. Lock order: this, then the
+    /// inode table, then an inode's state, then the superblock state
+    dirs: Mutex<()>,
+    inodes: Mutex<BTreeMap<u32, Weak<Ext2Inode>>>,
+    next_generation: AtomicU32,
+}
+
+impl Volume {
+    fn bread(&self, block: u32) -> Result<Buffer, VfsError> {
+        bcache::bread(&self.device, block as u64, self.block_size)
+    }
+
+    fn check_writable(&self) -> Result<(), VfsError> {
+        if self.read_only {
+            Err(VfsError::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn pointers_per_block(&self) -> u64 {
+        (self.block_size / 4) as u64
+    }
+
+    /// 512-byte units of `i_blocks` per filesystem block
+    fn sectors_per_block(&self) -> u32 {
+        (self.block_size / 512) as u32
+    }
+
+    fn group_of_inode(&self, ino: u32) -> u32 {
+        (ino - 1) / self.inodes_per_group
+    }
+
+    fn blocks_in_group(&self, group: u32) -> u32 {
+        let start = self.first_data_block + group * self.blocks_per_group;
+        (self.blocks_count - start).min(self.blocks_per_group)
+    }
+
+    /// Largest file size the block map can address
+    fn max_file_size(&self) -> u64 {
+        let p = self.pointers_per_block();
+        let blocks = DIRECT_BLOCKS as u64 + p + p * p + p * p * p;
+        blocks.saturating_mul(self.block_size as u64)
+    }
+
+    /// Write group descriptor `group` back to the descriptor table
+    fn write_group(&self, group: u32, desc: &GroupDesc) -> Result<(), VfsError> {
+        let offset = group as usize * GROUP_DESC_SIZE;
+        let buffer = self.bread(self.first_data_block + 1 + (offset / self.block_size) as u32)?;
+        {
+            let mut data = buffer.data();
+            let at = offset % self.block_size;
+            put_u32(&mut data, at, desc.block_bitmap);
+            put_u32(&mut data, at + 4, desc.inode_bitmap);
+            put_u32(&mut data, at + 8, desc.inode_table);
+            put_u16(&mut data, at + 12, desc.free_blocks);
+            put_u16(&mut data, at + 14, desc.free_inodes);
+            put_u16(&mut data, at + 16, desc.used_dirs);
+        }
+        bcache::bwrite(&buffer);
+        Ok(())
+    }
+
+    /// Claim the first clear bit below `limit` in a bitmap block
+    fn claim_bit(&self, bitmap: u32, limit: u32) -> Result<Option<u32>, VfsError> {
+        let buffer = self.bread(bitmap)?;
+        let found = {
+            let mut data = buffer.data();
+            let found = data
+                .iter()
+                .enumerate()
+                .take(limit.div_ceil(8) as usize)
+                .find(|(_, &byte)| byte != 0xFF)
+                .map(|(index, &byte)| index as u32 * 8 + (!byte).trailing_zeros())
+                .filter(|&bit| bit < limit);
+            if let Some(bit) = found {
+                data[bit as usize / 8] |= 1 << (bit % 8);
+            }
+            found
+        };
+        if found.is_some() {
+            bcache::bwrite(&buffer);
+        }
+        Ok(found)
+    }
+
+    /// Clear a bit in a bitmap block, reporting whether it was set
+    fn release_bit(&self, bitmap: u32, bit: u32) -> Result<bool, VfsError> {
+        let buffer = self.bread(bitmap)?;
+        let was_set = {
+            let mut data = buffer.data();
+            let mask = 1 << (bit % 8);
+            let was_set = data[bit as usize / 8] & mask != 0;
+            data[bit as usize / 8] &= !mask;
+            was_set
+        };
+        bcache::bwrite(&buffer);
+        Ok(was_set)
+    }
+
+    /// Allocate a block, preferably in group `goal`
+    fn allocate_block(&self, goal: u32) -> Result<u32, VfsError> {
+        let mut state = self.state.lock();
+        if state.free_blocks == 0 {
+            return Err(VfsError::NoSpace);
+        }
+
+        let count = state.groups.len() as u32;
+        for group in (0..count).map(|i| (goal + i) % count) {
+            let desc = state.groups[group as usize];
+            if desc.free_blocks == 0 {
+                continue;
+            }
+            let Some(bit) = self.claim_bit(desc.block_bitmap, self.blocks_in_group(group))? else {
+                continue;
+            };
+            let desc = &mut state.groups[group as usize];
+            desc.free_blocks -= 1;
+            let desc = *desc;
+            self.write_group(group, &desc)?;
+            state.free_blocks -= 1;
+            return Ok(self.first_data_block + group * self.blocks_per_group + bit);
+        }
+        Err(VfsError::NoSpace)
+    }
+
+    /// Allocate a block filled with zeros, e.g. an indirect block
+    fn allocate_zeroed_block(&self, goal: u32) -> Result<u32, VfsError> {
+        let block = self.allocate_block(goal)?;
+        let buffer = bcache::bget(&self.device, block as u64, self.block_size)?;
+        buffer.data().fill(0);
+        bcache::bwrite(&buffer);
+        Ok(block)
+    }
+
+    fn free_block(&self, block: u32) -> Result<(), VfsError> {
+        if block < self.first_data_block || block >= self.blocks_count {
+            crate::serial_println!("ext2: freeing block {} out of range", block);
+            return Err(VfsError::IoError);
+        }
+        let mut state = self.state.lock();
+        let group = (block - self.first_data_block) / self.blocks_per_group;
+        let bit = (block - self.first_data_block) % self.blocks_per_group;
+        if !self.release_bit(state.groups[group as usize].block_bitmap, bit)? {
+            crate::serial_println!("ext2: block {} was already free", block);
+            return Ok(());
+        }
+        let desc = &mut state.groups[group as usize];
+        desc.free_blocks += 1;
+        let desc = *desc;
+        self.write_group(group, &desc)?;
+        state.free_blocks += 1;
+        Ok(())
+    }
+
+    /// Allocate an inode. Files go near their directory; directories go to
+    /// the group with the most free inodes, to spread them out
+    fn allocate_inode(&self, parent_group: u32, directory: bool) -> Result<u32, VfsError> {
+        let mut state = self.state.lock();
+        if state.free_inodes == 0 {
+            return Err(VfsError::NoSpace);
+        }
+
+        let count = state.groups.len() as u32;
+        let mut order: Vec<u32> = (0..count).map(|i| (parent_group + i) % count).collect();
+        if directory {
+            order.sort_by_key(|&group| core::cmp::Reverse(state.groups[group as usize].free_inodes));
+        }
+        for group in order {
+            let desc = state.groups[group as usize];
+            if desc.free_inodes == 0 {
+                continue;
+            }
+            let Some(bit) = self.claim_bit(desc.inode_bitmap, self.inodes_per_group)? else {
+                continue;
+            };
+            let ino = group * self.inodes_per_group + bit + 1;
+            if ino < self.first_ino {
+                // A reserved inode that was never marked in use: leave it
+                // marked and look again
+                crate::serial_println!("ext2: reserved inode {} was free in the bitmap", ino);
+                continue;
+            }
+            let desc = &mut state.groups[group as usize];
+            desc.free_inodes -= 1;
+            if directory {
+                desc.used_dirs += 1;
+            }
+            let desc = *desc;
+            self.write_group(group, &desc)?;
+            state.free_inodes -= 1;
+            return Ok(ino);
+        }
+        Err(VfsError::NoSpace)
+    }
+
+    fn free_inode(&self, ino: u32, directory: bool) -> Result<(), VfsError> {
+        let mut state = self.state.lock();
+        let group = self.group_of_inode(ino);
+        let bit = (ino - 1) % self.inodes_per_group;
+        if !self.release_bit(state.groups[group as usize].inode_bitmap, bit)? {
+            crate::serial_println!("ext2: inode {} was already free", ino);
+            return Ok(());
+        }
+        let desc = &mut state.groups[group as usize];
+        desc.free_inodes += 1;
+        if directory {
+            desc.used_dirs = desc.used_dirs.saturating_sub(1);
+        }
+        let desc = *desc;
+        self.write_group(group, &desc)?;
+        state.free_inodes += 1;
+        Ok(())
+    }
+
+    /// Block and offset of inode `ino` in its group's inode table
+    fn inode_location(&self, ino: u32) -> Result<(u32, usize), VfsError> {
+        if ino == 0 || ino > self.inodes_count {
+            crate::serial_println!("ext2: inode number {} out of range", ino);
+            return Err(VfsError::IoError);
+        }
+        let table = self.state.lock().groups[self.group_of_inode(ino) as usize].inode_table;
+        let offset = ((ino - 1) % self.inodes_per_group) as usize * self.inode_size;
+        Ok((table + (offset / self.block_size) as u32, offset % self.block_size))
+    }
+
+    fn read_inode(&self, ino: u32) -> Result<InodeState, VfsError> {
+        let (block, at) = self.inode_location(ino)?;
+        let buffer = self.bread(block)?;
+        let data = buffer.data();
+        let raw = &data[at..at + GOOD_OLD_INODE_SIZE];
+
+        let mode = read_u16(raw, 0);
+        let mut size = read_u32(raw, 4) as u64;
+        // For regular files of revision 1 volumes this is the high half of
+        // the size (for directories it held an ACL, never used)
+        if self.revision > GOOD_OLD_REV && file_type_of(mode) == FileType::Regular {
+            size |= (read_u32(raw, 108) as u64) << 32;
+        }
+        let mut block_map = [0u32; BLOCK_SLOTS];
+        for (slot, value) in block_map.iter_mut().enumerate() {
+            *value = read_u32(raw, 40 + slot * 4);
+        }
+        Ok(InodeState {
+            mode,
+            uid: read_u16(raw, 2) as u32 | (read_u16(raw, 120) as u32) << 16,
+            gid: read_u16(raw, 24) as u32 | (read_u16(raw, 122) as u32) << 16,
+            size,
+            atime: read_u32(raw, 8),
+            ctime: read_u32(raw, 12),
+            mtime: read_u32(raw, 16),
+            dtime: read_u32(raw, 20),
+            links: read_u16(raw, 26),
+            blocks: read_u32(raw, 28),
+            flags: read_u32(raw, 32),
+            block: block_map,
+            generation: read_u32(raw, 100),
+            file_acl: read_u32(raw, 104),
+        })
+    }
+
+    /// Write an inode back; `fresh` clears the whole on-disk inode first,
+    /// for a newly allocated one
+    fn write_inode(&self, ino: u32, state: &InodeState, fresh: bool) -> Result<(), VfsError> {
+        let (block, at) = self.inode_location(ino)?;
+        let buffer = self.bread(block)?;
+        {
+            let mut data = buffer.data();
+            if fresh {
+                data[at..at + self.inode_size].fill(0);
+            }
+            let raw = &mut data[at..at + GOOD_OLD_INODE_SIZE];
+            put_u16(raw, 0, state.mode);
+            put_u16(raw, 2, state.uid as u16);
+            put_u32(raw, 4, state.size as u32);
+            put_u32(raw, 8, state.atime);
+            put_u32(raw, 12, state.ctime);
+            put_u32(raw, 16, state.mtime);
+            put_u32(raw, 20, state.dtime);
+            put_u16(raw, 24, state.gid as u16);
+            put_u16(raw, 26, state.links);
+            put_u32(raw, 28, state.blocks);
+            put_u32(raw, 32, state.flags);
+            for (slot, &value) in state.block.iter().enumerate() {
+                put_u32(raw, 40 + slot * 4, value);
+            }
+            put_u32(raw, 100, state.generation);
+            put_u32(raw, 104, state.file_acl);
+            if self.revision > GOOD_OLD_REV && state.file_type() == FileType::Regular {
+                put_u32(raw, 108, (state.size >> 32) as u32);
+            }
+            put_u16(raw, 120, (state.uid >> 16) as u16);
+            put_u16(raw, 122, (state.gid >> 16) as u16);
+        }
+        bcache::bwrite(&buffer);
+        Ok(())
+    }
+
+    /// Note that a file has grown past 2 GiB, which needs the large file
+    /// feature (set on sync)
+    fn require_large_file(&self) -> Result<(), VfsError> {
+        if self.revision == GOOD_OLD_REV {
+            return Err(VfsError::NoSpace);
+        }
+        self.state.lock().ro_compat |= RO_COMPAT_LARGE_FILE;
+        Ok(())
+    }
+
+    /// Drop one reference to an extended attribute block
+    fn release_xattr_block(&self, block: u32) -> Result<(), VfsError> {
+        let buffer = self.bread(block)?;
+        let remaining = {
+            let mut data = buffer.data();
+            if read_u32(&data, 0) != XATTR_MAGIC {
+                crate::serial_println!("ext2: bad extended attribute block {}", block);
+                return Err(VfsError::IoError);
+            }
+            let refs = read_u32(&data, 4).saturating_sub(1);
+            put_u32(&mut data, 4, refs);
+            refs
+        };
+        bcache::bwrite(&buffer);
+        drop(buffer);
+        if remaining == 0 {
+            self.free_block(block)?;
+        }
+        Ok(())
+    }
+
+    /// Update the superblock: counters, features and `changes` to the raw
+    /// bytes
+    fn write_superblock(&self, changes: impl FnOnce(&mut [u8])) -> Result<(), VfsError> {
+        let block = (SUPERBLOCK_OFFSET / self.block_size as u64) as u32;
+        let at = (SUPERBLOCK_OFFSET % self.block_size as u64) as usize;
+        let buffer = self.bread(block)?;
+        {
+            let state = self.state.lock();
+            let mut data = buffer.data();
+            let raw = &mut data[at..at + SUPERBLOCK_SIZE];
+            put_u32(raw, 12, state.free_blocks);
+            put_u32(raw, 16, state.free_inodes);
+            if self.revision > GOOD_OLD_REV {
+                put_u32(raw, 100, state.ro_compat);
+            }
+            changes(raw);
+        }
+        bcache::bwrite(&buffer);
+        Ok(())
+    }
+
+    /// Look a child up in the live inode table, or load it from disk
+    fn get_inode(self: &Arc<Self>, ino: u32) -> Result<Arc<Ext2Inode>, VfsError> {
+        let mut inodes = self.inodes.lock();
+        if let Some(inode) = inodes.get(&ino).and_then(Weak::upgrade) {
+            return Ok(inode);
+        }
+        inodes.retain(|_, inode| inode.strong_count() > 0);
+
+        let state = self.read_inode(ino)?;
+        let inode = Arc::new_cyclic(|this| Ext2Inode {
+            volume: self.clone(),
+            ino,
+            state: Mutex::new(state),
+            this: this.clone(),
+        });
+        inodes.insert(ino, Arc::downgrade(&inode));
+        Ok(inode)
+    }
+}
+
+/// An inode as kept in memory
+struct InodeState {
+    /// Type and permission bits
+    mode: u16,
+    uid: u32,
+    gid: u32,
+    size: u64,
+    atime: u32,
+    ctime: u32,
+    mtime: u32,
+    /// Deletion time, set when the inode is freed
+    dtime: u32,
+    links: u16,
+    /// 512-byte units allocated, including indirect blocks
+    blocks: u32,
+    flags: u32,
+    block: [u32; BLOCK_SLOTS],
+    generation: u32,
+    /// Extended attribute block
+    file_acl: u32,
+}
+
+impl InodeState {
+    fn file_type(&self) -> FileType {
+        file_type_of(self.mode)
+    }
+
+    fn touch(&mut self) {
+        let now = to_ext2_time(current_time());
+        self.mtime = now;
+        self.ctime = now;
+    }
+
+    /// A symbolic link whose target is kept in `i_block`
+    fn is_fast_symlink(&self, volume: &Volume) -> bool {
+        let xattr_blocks = if self.file_acl != 0 { volume.sectors_per_block() } else { 0 };
+        self.file_type() == FileType::Symlink && self.blocks == xattr_blocks
+    }
+
+    /// Whether `i_block` holds a block map (not a fast symlink's target)
+    fn has_block_map(&self, volume: &Volume) -> bool {
+        matches!(self.file_type(), FileType::Regular | FileType::Directory | FileType::Symlink) && !self.is_fast_symlink(volume)
+    }
+}
+
+/// A file, directory or symbolic link on an ext2 volume
+pub struct Ext2Inode {
+    volume: Arc<Volume>,
+    ino: u32,
+    /// Lock order: the page cache's page locks, then this
+    state: Mutex<InodeState>,
+    /// This inode, as the page cache keys pages by it
+    this: Weak<Ext2Inode>,
+}
+
+impl Ext2Inode {
+    fn cached(&self) -> Result<Arc<dyn Inode>, VfsError> {
+        let inode: Arc<dyn Inode> = self.this.upgrade().ok_or(VfsError::IoError)?;
+        Ok(inode)
+    }
+
+    fn store(&self, state: &InodeState) -> Result<(), VfsError> {
+        self.volume.write_inode(self.ino, state, false)
+    }
+
+    fn check_directory(&self) -> Result<(), VfsError> {
+        let state = self.state.lock();
+        if state.file_type() != FileType::Directory {
+            return Err(VfsError::NotADirectory);
+        }
+        if state.links == 0 {
+            return Err(VfsError::NotFound); // removed
+        }
+        Ok(())
+    }
+
+    /// Where block `index` of the file is found: the `i_block` slot, then
+    /// the offset within each level of indirect blocks
+    fn block_path(&self, index: u64) -> Option<(usize, [u64; 3], usize)> {
+        let p = self.volume.pointers_per_block();
+        let direct = DIRECT_BLOCKS as u64;
+        if index < direct {
+            return Some((index as usize, [0; 3], 0));
+        }
+        let index = index - direct;
+        if index < p {
+            return Some((DIRECT_BLOCKS, [index, 0, 0], 1));
+        }
+        let index = index - p;
+        if index < p * p {
+            return Some((DIRECT_BLOCKS + 1, [index / p, index % p, 0], 2));
+        }
+        let index = index - p * p;
+        if index < p * p * p {
+            return Some((DIRECT_BLOCKS + 2, [index / (p * p), index / p % p, index % p], 3));
+        }
+        None
+    }
+
+    /// The disk block holding block `index` of the file. With `create`,
+    /// missing blocks are allocated and the bool says the data block is
+    /// new (and not yet zeroed)
+    fn map_block(&self, state: &mut InodeState, index: u64, create: bool) -> Result<Option<(u32, bool)>, VfsError> {
+        let volume = &self.volume;
+        let Some((slot, offsets, depth)) = self.block_path(index) else {
+            return if create { Err(VfsError::NoSpace) } else { Ok(None) };
+        };
+        let goal = volume.group_of_inode(self.ino);
+
+        let mut fresh = false;
+        let mut block = state.block[slot];
+        if block == 0 {
+            if !create {
+                return Ok(None);
+            }
+            block = if depth > 0 { volume.allocate_zeroed_block(goal)? } else { volume.allocate_block(goal)? };
+            state.block[slot] = block;
+            state.blocks += volume.sectors_per_block();
+            fresh = true;
+        }
+
+        for (level, &offset) in offsets.iter().enumerate().take(depth) {
+            let at = offset as usize * 4;
+            let buffer = volume.bread(block)?;
+            let next = read_u32(&buffer.data(), at);
+            if next != 0 {
+                block = next;
+                continue;
+            }
+            if !create {
+                return Ok(None);
+            }
+            let last = level + 1 == depth;
+            let next = if last { volume.allocate_block(goal)? } else { volume.allocate_zeroed_block(goal)? };
+            put_u32(&mut buffer.data(), at, next);
+            bcache::bwrite(&buffer);
+            state.blocks += volume.sectors_per_block();
+            block = next;
+            fresh = true;
+        }
+        Ok(Some((block, fresh)))
+    }
+
+    fn pointer(&self, block: u32, index: u64) -> Result<u32, VfsError> {
+        let buffer = self.volume.bread(block)?;
+        let value = read_u32(&buffer.data(), index as usize * 4);
+        Ok(value)
+    }
+
+    fn clear_pointer(&self, block: u32, index: u64) -> Result<(), VfsError> {
+        let buffer = self.volume.bread(block)?;
+        put_u32(&mut buffer.data(), index as usize * 4, 0);
+        bcache::bwrite(&buffer);
+        Ok(())
+    }
+
+    /// Free `block` and, for an indirect block of `depth` levels,
+    /// everything below it; returns the number of blocks freed
+    fn free_tree(&self, block: u32, depth: usize) -> Result<u32, VfsError> {
+        let mut freed = 0;
+        if depth > 0 {
+            for index in 0..self.volume.pointers_per_block() {
+                let child = self.pointer(block, index)?;
+                if child != 0 {
+                    freed += self.free_tree(child, depth - 1)?;
+                }
+            }
+        }
+        self.volume.free_block(block)?;
+        Ok(freed + 1)
+    }
+
+    /// Free the blocks under an indirect block of `depth` levels from
+    /// data block `keep` on (relative to the first it maps); the indirect
+    /// block itself stays
+    fn trim_tree(&self, block: u32, depth: usize, keep: u64) -> Result<u32, VfsError> {
+        let p = self.volume.pointers_per_block();
+        let span = p.pow(depth as u32 - 1);
+        let mut freed = 0;
+        for index in keep / span..p {
+            let child = self.pointer(block, index)?;
+            if child == 0 {
+                continue;
+            }
+            let start = index * span;
+            if start >= keep {
+                freed += self.free_tree(child, depth - 1)?;
+                self.clear_pointer(block, index)?;
+            } else if depth > 1 {
+                freed += self.trim_tree(child, depth - 1, keep - start)?;
+            }
+        }
+        Ok(freed)
+    }
+
+    /// Free every block past the first `keep` of the file
+    fn truncate_blocks(&self, state: &mut InodeState, keep: u64) -> Result<(), VfsError> {
+        let mut freed = 0;
+        for slot in (keep as usize).min(DIRECT_BLOCKS)..DIRECT_BLOCKS {
+            if state.block[slot] != 0 {
+                self.volume.free_block(state.block[slot])?;
+                state.block[slot] = 0;
+                freed += 1;
+            }
+        }
+
+        let p = self.volume.pointers_per_block();
+        let mut start = DIRECT_BLOCKS as u64;
+        let mut span = p;
+        for depth in 1..=3 {
+            let slot = DIRECT_BLOCKS + depth - 1;
+            let block = state.block[slot];
+            if block != 0 {
+                if keep <= start {
+                    freed += self.free_tree(block, depth)?;
+                    state.block[slot] = 0;
+                } else if keep < start + span {
+                    freed += self.trim_tree(block, depth, keep - start)?;
+                }
+            }
+            start += span;
+            span *= p;
+        }
+        state.blocks = state.blocks.saturating_sub(freed * self.volume.sectors_per_block());
+        Ok(())
+    }
+
+    /// Zero the rest of the block holding byte `size`, so growing the file
+    /// later reads zeros there
+    fn zero_tail(&self, state: &mut InodeState, size: u64) -> Result<(), VfsError> {
+        let block_size = self.volume.block_size as u64;
+        let within = (size % block_size) as usize;
+        if within == 0 {
+            return Ok(());
+        }
+        if let Some((block, _)) = self.map_block(state, size / block_size, false)? {
+            let buffer = self.volume.bread(block)?;
+            buffer.data()[within..].fill(0);
+            bcache::bwrite(&buffer);
+        }
+        Ok(())
+    }
+
+    fn read_locked(&self, state: &mut InodeState, offset: u64, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        if offset >= state.size {
+            return Ok(0);
+        }
+        let total = buffer.len().min((state.size - offset) as usize);
+        let block_size = self.volume.block_size;
+
+        let mut done = 0;
+        while done < total {
+            let pos = offset + done as u64;
+            let within = (pos % block_size as u64) as usize;
+            let count = (block_size - within).min(total - done);
+            match self.map_block(state, pos / block_size as u64, false)? {
+                Some((block, _)) => {
+                    let data = self.volume.bread(block)?;
+                    buffer[done..done + count].copy_from_slice(&data.data()[within..within + count]);
+                }
+                // A hole reads as zeros
+                None => buffer[done..done + count].fill(0),
+            }
+            done += count;
+        }
+        Ok(total)
+    }
+
+    /// Check that `len` bytes can be written at `offset`
+    fn check_range(&self, offset: u64, len: usize) -> Result<(), VfsError> {
+        let end = offset
+            .checked_add(len as u64)
+            .filter(|&end| end <= self.volume.max_file_size())
+            .ok_or(VfsError::NoSpace)?;
+        if end > i32::MAX as u64 {
+            self.volume.require_large_file()?;
+        }
+        Ok(())
+    }
+
+    /// Allocate the blocks under `len` bytes at `offset` and grow the file
+    /// over them, for a write that goes through the page cache
+    ///
+    /// Returns how many bytes fit; fails only if none do. New blocks are
+    /// zeroed, so what the page cache has not written back reads as zeros.
+    fn reserve(&self, state: &mut InodeState, offset: u64, len: usize) -> Result<usize, VfsError> {
+        self.volume.check_writable()?;
+        match state.file_type() {
+            FileType::Regular => {}
+            FileType::Directory => return Err(VfsError::IsADirectory),
+            _ => return Err(VfsError::InvalidOperation),
+        }
+        if len == 0 {
+            return Ok(0);
+        }
+        self.check_range(offset, len)?;
+
+        let block_size = self.volume.block_size as u64;
+        let end = offset + len as u64;
+        let mut reserved = offset;
+        let mut result = Ok(());
+        for index in offset / block_size..end.div_ceil(block_size) {
+            let mapped = self.map_block(state, index, true);
+            match mapped.and_then(|mapped| mapped.ok_or(VfsError::NoSpace)) {
+                Ok((block, fresh)) => {
+                    if fresh {
+                        let data = bcache::bget(&self.volume.device, block as u64, block_size as usize)?;
+                        data.data().fill(0);
+                        bcache::bwrite(&data);
+                    }
+                    reserved = ((index + 1) * block_size).min(end);
+                }
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+
+        let done = (reserved - offset) as usize;
+        if done > 0 {
+            state.size = state.size.max(reserved);
+            state.touch();
+        }
+        self.store(state)?;
+        match result {
+            Err(e) if done == 0 => Err(e),
+            _ => Ok(done),
+        }
+    }
+
+    /// Write through the block map, allocating blocks as needed. After
+    /// running out of space, what was written so far is kept. Timestamps
+    /// are left to the caller, as page writeback must not change them
+    fn write_data(&self, state: &mut InodeState, offset: u64, buffer: &[u8]) -> Result<usize, VfsError> {
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+        self.check_range(offset, buffer.len())?;
+
+        let block_size = self.volume.block_size;
+        let mut done = 0;
+        let mut result = Ok(());
+        while done < buffer.len() {
+            let pos = offset + done as u64;
+            let within = (pos % block_size as u64) as usize;
+            let count = (block_size - within).min(buffer.len() - done);
+
+            let mapped = self.map_block(state, pos / block_size as u64, true);
+            let (block, fresh) = match mapped.and_then(|mapped| mapped.ok_or(VfsError::NoSpace)) {
+                Ok(mapped) => mapped,
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            };
+            let data = if fresh || count == block_size {
+                bcache::bget(&self.volume.device, block as u64, block_size)?
+            } else {
+                self.volume.bread(block)?
+            };
+            {
+                let mut data = data.data();
+                if fresh {
+                    data.fill(0);
+                }
+                data[within..within + count].copy_from_slice(&buffer[done..done + count]);
+            }
+            bcache::bwrite(&data);
+            done += count;
+        }
+
+        if done > 0 {
+            state.size = state.size.max(offset + done as u64);
+        }
+        self.store(state)?;
+        match result {
+            Err(e) if done == 0 => Err(e),
+            _ => Ok(done),
+        }
+    }
+
+    /// Call `visit` with (end position, inode, name, entry type) for each
+    /// entry in use from byte `start` of the directory, until it returns a
+    /// value
+    fn scan_entries<T>(
+        &self,
+        state: &mut InodeState,
+        start: u64,
+        mut visit: impl FnMut(u64, u32, &[u8], u8) -> Option<T>,
+    ) -> Result<Option<T>, VfsError> {
+        let block_size = self.volume.block_size as u64;
+        // Entries are walked from the start of the block, as `start` may
+        // point into an entry that has since been merged with the one
+        // before it
+        let mut block_start = start - start % block_size;
+        while block_start < state.size {
+            let (block, _) = self.map_block(state, block_start / block_size, false)?.ok_or(VfsError::IoError)?;
+            let buffer = self.volume.bread(block)?;
+            let data = buffer.data();
+            let mut at = 0;
+            while at < data.len() {
+                let record = Record::parse(&data, at, self.volume.filetype)?;
+                let pos = block_start + at as u64;
+                if pos >= start && record.ino != 0 {
+                    let end = pos + record.rec_len as u64;
+                    if let Some(found) = visit(end, record.ino, record.name(&data), record.file_type) {
+                        return Ok(Some(found));
+                    }
+                }
+                at += record.rec_len;
+            }
+            block_start += block_size;
+        }
+        Ok(None)
+    }
+
+    /// Find `name` in this directory: its inode and entry type
+    fn find_entry(&self, name: &str) -> Result<Option<(u32, u8)>, VfsError> {
+        let mut state = self.state.lock();
+        self.scan_entries(&mut state, 0, |_, ino, entry_name, file_type| {
+            (entry_name == name.as_bytes()).then_some((ino, file_type))
+        })
+    }
+
+    fn is_empty_directory(&self) -> Result<bool, VfsError> {
+        let mut state = self.state.lock();
+        let other = self.scan_entries(&mut state, 0, |_, _, name, _| (name != b"." && name != b"..").then_some(()))?;
+        Ok(other.is_none())
+    }
+
+    /// Add an entry `name` for inode `ino` to this directory
+    fn add_entry(&self, name: &str, ino: u32, file_type: FileType) -> Result<(), VfsError> {
+        let volume = &self.volume;
+        let block_size = volume.block_size;
+        let needed = entry_size(name.len());
+        let type_byte = if volume.filetype { entry_type(file_type) } else { FT_UNKNOWN };
+
+        let mut state = self.state.lock();
+        let blocks = state.size / block_size as u64;
+        for index in 0..blocks {
+            let (block, _) = self.map_block(&mut state, index, false)?.ok_or(VfsError::IoError)?;
+            let buffer = volume.bread(block)?;
+            let placed = {
+                let mut data = buffer.data();
+                let mut at = 0;
+                let mut placed = false;
+                while at < block_size {
+                    let record = Record::parse(&data, at, volume.filetype)?;
+                    let used = record.used();
+                    if record.rec_len - used >= needed {
+                        // Split the free space off the end of this entry
+                        if used > 0 {
+                            put_u16(&mut data, at + 4, used as u16);
+                        }
+                        write_record(&mut data, at + used, ino, record.rec_len - used, name.as_bytes(), type_byte);
+                        placed = true;
+                        break;
+                    }
+                    at += record.rec_len;
+                }
+                placed
+            };
+            if placed {
+                bcache::bwrite(&buffer);
+                return self.finish_change(&mut state);
+            }
+        }
+
+        // No room: add a block holding just the new entry
+        let (block, _) = self.map_block(&mut state, blocks, true)?.ok_or(VfsError::NoSpace)?;
+        let buffer = bcache::bget(&volume.device, block as u64, block_size)?;
+        {
+            let mut data = buffer.data();
+            data.fill(0);
+            write_record(&mut data, 0, ino, block_size, name.as_bytes(), type_byte);
+        }
+        bcache::bwrite(&buffer);
+        state.size += block_size as u64;
+        self.finish_change(&mut state)
+    }
+
+    /// Update a directory's times after changing its entries. A hash
+    /// index would now be stale, so the directory becomes a plain one
+    fn finish_change(&self, state: &mut InodeState) -> Result<(), VfsError> {
+        state.flags &= !INDEX_FL;
+        state.touch();
+        self.store(state)
+    }
+
+    /// Apply `change` to the raw entry `name` of this directory; `previous`
+    /// is the entry before it in the same block, if any
+    fn edit_entry(&self, name: &str, change: impl FnOnce(&mut [u8], usize, Option<usize>)) -> Result<(), VfsError> {
+        let volume = &self.volume;
+        let mut state = self.state.lock();
+        let blocks = state.size / volume.block_size as u64;
+        for index in 0..blocks {
+            let (block, _) = self.map_block(&mut state, index, false)?.ok_or(VfsError::IoError)?;
+            let buffer = volume.bread(block)?;
+            let found = {
+                let data = buffer.data();
+                let mut at = 0;
+                let mut previous = None;
+                let mut found = None;
+                while at < data.len() {
+                    let record = Record::parse(&data, at, volume.filetype)?;
+                    if record.ino != 0 && record.name(&data) == name.as_bytes() {
+                        found = Some((at, previous));
+                        break;
+                    }
+                    previous = Some(at);
+                    at += record.rec_len;
+                }
+                found
+            };
+            if let Some((at, previous)) = found {
+                change(&mut buffer.data(), at, previous);
+                bcache::bwrite(&buffer);
+                return self.finish_change(&mut state);
+            }
+        }
+        Err(VfsError::NotFound)
+    }
+
+    /// Remove the entry `name` from this directory
+    fn remove_entry(&self, name: &str) -> Result<(), VfsError> {
+        self.edit_entry(name, |data, at, previous| match previous {
+            // Merge the space into the entry before
+            Some(previous) => {
+                let merged = read_u16(data, previous + 4) + read_u16(data, at + 4);
+                put_u16(data, previous + 4, merged);
+            }
+            None => put_u32(data, at, 0),
+        })
+    }
+
+    /// Point the existing entry `name` at another inode
+    fn replace_entry(&self, name: &str, ino: u32, file_type: FileType) -> Result<(), VfsError> {
+        let type_byte = if self.volume.filetype { entry_type(file_type) } else { FT_UNKNOWN };
+        self.edit_entry(name, |data, at, _| {
+            put_u32(data, at, ino);
+            data[at + 7] = type_byte;
+        })
+    }
+
+    /// The parent of this directory, from its `..` entry
+    fn parent_ino(&self) -> Result<u32, VfsError> {
+        let mut state = self.state.lock();
+        self.scan_entries(&mut state, 0, |_, ino, name, _| (name == b"..").then_some(ino))?
+            .ok_or(VfsError::IoError)
+    }
+
+    fn change_links(&self, delta: i32) -> Result<(), VfsError> {
+        let mut state = self.state.lock();
+        state.links = (state.links as i32 + delta).clamp(0, u16::MAX as i32) as u16;
+        state.ctime = to_ext2_time(current_time());
+        self.store(&state)
+    }
+
+    /// Allocate and set up a new inode of `file_type` for this directory's
+    /// entry `name`, and link it in
+    fn new_child(&self, name: &str, file_type: FileType, mode: u16) -> Result<Arc<Ext2Inode>, VfsError> {
+        let volume = &self.volume;
+        let directory = file_type == FileType::Directory;
+        if directory && self.state.lock().links >= LINK_MAX {
+            return Err(VfsError::InvalidOperation);
+        }
+
+        let ino = volume.allocate_inode(volume.group_of_inode(self.ino), directory)?;
+        let now = to_ext2_time(current_time());
+        let state = InodeState {
+            mode: file_type.mode_bits() as u16 | (mode & MODE_PERMISSION_MASK),
+            uid: 0,
+            gid: 0,
+            size: 0,
+            atime: now,
+            ctime: now,
+            mtime: now,
+            dtime: 0,
+            links: if directory { 2 } else { 1 },
+            blocks: 0,
+            flags: 0,
+            block: [0; BLOCK_SLOTS],
+            generation: volume.next_generation.fetch_add(1, Ordering::Relaxed),
+            file_acl: 0,
+        };
+        if let Err(e) = volume.write_inode(ino, &state, true) {
+            volume.free_inode(ino, directory)?;
+            return Err(e);
+        }
+
+        let inode = Arc::new_cyclic(|this| Ext2Inode {
+            volume: volume.clone(),
+            ino,
+            state: Mutex::new(state),
+            this: this.clone(),
+        });
+        volume.inodes.lock().insert(ino, Arc::downgrade(&inode));
+
+        let linked = (|| {
+            if directory {
+                inode.add_entry(".", ino, FileType::Directory)?;
+                inode.add_entry("..", self.ino, FileType::Directory)?;
+            }
+            self.add_entry(name, ino, file_type)
+        })();
+        if let Err(e) = linked {
+            // Dropping the unlinked inode frees it
+            inode.state.lock().links = 0;
+            return Err(e);
+        }
+        if directory {
+            self.change_links(1)?;
+        }
+        Ok(inode)
+    }
+
+    /// Free the inode and its blocks once nothing links to it
+    fn release(&self, state: &mut InodeState) -> Result<(), VfsError> {
+        if state.has_block_map(&self.volume) {
+            self.truncate_blocks(state, 0)?;
+        }
+        if state.file_acl != 0 {
+            self.volume.release_xattr_block(state.file_acl)?;
+            state.file_acl = 0;
+        }
+        state.blocks = 0;
+        state.size = 0;
+        state.dtime = to_ext2_time(current_time()).max(1);
+        self.store(state)?;
+        self.volume.free_inode(self.ino, state.file_type() == FileType::Directory)
+    }
+
+    /// Whether this directory is `ancestor` or lies below it
+    fn is_within(&self, ancestor: u32) -> Result<bool, VfsError> {
+        let mut ino = self.ino;
+        for _ in 0..self.volume.inodes_count {
+            if ino == ancestor {
+                return Ok(true);
+            }
+            if ino == ROOT_INO {
+                return Ok(false);
+            }
+            ino = self.volume.get_inode(ino)?.parent_ino()?;
+        }
+        Err(VfsError::IoError)
+    }
+}
+
+impl Drop for Ext2Inode {
+    fn drop(&mut self) {
+        let mut state = self.state.lock();
+        if state.links != 0 || self.volume.read_only {
+            return;
+        }
+        if let Err(e) = self.release(&mut state) {
+            crate::serial_println!("ext2: freeing inode {} failed: {}", self.ino, e);
+        }
+    }
+}
+
+impl Inode for Ext2Inode {
+    fn read(&self, offset: usize, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        match self.file_type() {
+            FileType::Regular => pagecache::read(&self.cached()?, offset, buffer),
+            FileType::Directory => Err(VfsError::IsADirectory),
+            _ => Err(VfsError::InvalidOperation),
+        }
+    }
+
+    fn write(&self, offset: usize, buffer: &[u8]) -> Result<usize, VfsError> {
+        let reserved = self.reserve(&mut self.state.lock(), offset as u64, buffer.len())?;
+        pagecache::write(&self.cached()?, offset, &buffer[..reserved])
+    }
+
+    fn append(&self, buffer: &[u8]) -> Result<usize, VfsError> {
+        // Growing the file under the lock keeps concurrent appends apart
+        let (offset, reserved) = {
+            let mut state = self.state.lock();
+            let offset = state.size;
+            (offset as usize, self.reserve(&mut state, offset, buffer.len())?)
+        };
+        let written = pagecache::write(&self.cached()?, offset, &buffer[..reserved])?;
+        Ok(offset + written)
+    }
+
+    fn read_page(&self, index: u64, buffer: &mut [u8]) -> Result<(), VfsError> {
+        let mut state = self.state.lock();
+        self.read_locked(&mut state, index * buffer.len() as u64, buffer)?;
+        Ok(())
+    }
+
+    fn write_page(&self, index: u64, buffer: &[u8]) -> Result<(), VfsError> {
+        self.volume.check_writable()?;
+        let mut state = self.state.lock();
+        let offset = index * buffer.len() as u64;
+        let count = state.size.saturating_sub(offset).min(buffer.len() as u64) as usize;
+        if count > 0 {
+            self.write_data(&mut state, offset, &buffer[..count])?;
+        }
+        Ok(())
+    }
+
+    fn file_type(&self) -> FileType {
+        self.state.lock().file_type()
+    }
+
+    fn size(&self) -> usize {
+        self.state.lock().size as usize
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, VfsError> {
+        self.check_directory()?;
+        let _dirs = self.volume.dirs.lock();
+        let (ino, _) = self.find_entry(name)?.ok_or(VfsError::NotFound)?;
+        Ok(self.volume.get_inode(ino)?)
+    }
+
+    fn create(&self, name: &str, file_type: FileType) -> Result<Arc<dyn Inode>, VfsError> {
+        self.volume.check_writable()?;
+        self.check_directory()?;
+        validate_name(name)?;
+        if !matches!(file_type, FileType::Regular | FileType::Directory) {
+            return Err(VfsError::NotImplemented);
+        }
+        let _dirs = self.volume.dirs.lock();
+        if self.find_entry(name)?.is_some() {
+            return Err(VfsError::AlreadyExists);
+        }
+        Ok(self.new_child(name, file_type, file_type.default_mode())?)
+    }
+
+    fn list(&self) -> Result<Vec<String>, VfsError> {
+        let mut names = Vec::new();
+        let mut cursor = 0;
+        while let Some((entry, next)) = self.readdir(cursor)? {
+            names.push(entry.name);
+            cursor = next;
+        }
+        Ok(names)
+    }
+
+    /// The cursor is the byte position in the directory of the next entry
+    /// to look at
+    fn readdir(&self, cursor: u64) -> Result<Option<(DirEntry, u64)>, VfsError> {
+        self.check_directory()?;
+        let _dirs = self.volume.dirs.lock();
+        let found = {
+            let mut state = self.state.lock();
+            self.scan_entries(&mut state, cursor, |end, ino, name, file_type| {
+                (name != b"." && name != b"..").then(|| (end, ino, String::from_utf8_lossy(name).into_owned(), file_type))
+            })?
+        };
+        let Some((end, ino, name, file_type)) = found else {
+            return Ok(None);
+        };
+
+        // Without the filetype feature the type is in the inode
+        let file_type = match entry_file_type(file_type) {
+            Some(file_type) => file_type,
+            None => self.volume.get_inode(ino)?.file_type(),
+        };
+        let entry = DirEntry {
+            name,
+            ino: ino as u64,
+            file_type,
+        };
+        Ok(Some((entry, end)))
+    }
+
+    fn truncate(&self, size: usize) -> Result<(), VfsError> {
+        self.volume.check_writable()?;
+        let mut state = self.state.lock();
+        match state.file_type() {
+            FileType::Regular => {}
+            FileType::Directory => return Err(VfsError::IsADirectory),
+            _ => return Err(VfsError::InvalidOperation),
+        }
+        let size = size as u64;
+        if size > self.volume.max_file_size() {
+            return Err(VfsError::NoSpace);
+        }
+        if size > i32::MAX as u64 {
+            self.volume.require_large_file()?;
+        }
+
+        if size < state.size {
+            let block_size = self.volume.block_size as u64;
+            self.truncate_blocks(&mut state, size.div_ceil(block_size))?;
+            self.zero_tail(&mut state, size)?;
+        }
+        // Growing leaves a hole, which reads as zeros
+        state.size = size;
+        state.touch();
+        self.store(&state)?;
+        drop(state);
+
+        pagecache::truncate(&self.cached()?, size as usize);
+        Ok(())
+    }
+
+    fn remove(&self, name: &str) -> Result<(), VfsError> {
+        self.volume.check_writable()?;
+        self.check_directory()?;
+        if name == "." || name == ".." {
+            return Err(VfsError::InvalidArgument);
+        }
+        let _dirs = self.volume.dirs.lock();
+        let (ino, _) = self.find_entry(name)?.ok_or(VfsError::NotFound)?;
+        let child = self.volume.get_inode(ino)?;
+        let directory = child.file_type() == FileType::Directory;
+        if directory && !child.is_empty_directory()? {
+            return Err(VfsError::DirectoryNotEmpty);
+        }
+
+        self.remove_entry(name)?;
+        if directory {
+            // Its `.` and our entry go; so does its `..` link to us
+            child.change_links(-2)?;
+            self.change_links(-1)?;
+        } else {
+            child.change_links(-1)?;
+        }
+        Ok(())
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> Result<Arc<dyn Inode>, VfsError> {
+        self.volume.check_writable()?;
+        self.check_directory()?;
+        validate_name(name)?;
+        if target.is_empty() || target.len() >= self.volume.block_size {
+            return Err(VfsError::InvalidArgument);
+        }
+        let _dirs = self.volume.dirs.lock();
+        if self.find_entry(name)?.is_some() {
+            return Err(VfsError::AlreadyExists);
+        }
+
+        let inode = self.new_child(name, FileType::Symlink, FileType::Symlink.default_mode())?;
+        let stored = {
+            let mut state = inode.state.lock();
+            if target.len() < FAST_SYMLINK_MAX {
+                let mut bytes = [0u8; FAST_SYMLINK_MAX];
+                bytes[..target.len()].copy_from_slice(target.as_bytes());
+                for (slot, chunk) in state.block.iter_mut().zip(bytes.chunks_exact(4)) {
+                    *slot = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+                }
+                state.size = target.len() as u64;
+                inode.store(&state)
+            } else {
+                // Longer targets are stored like file data
+                inode.write_data(&mut state, 0, target.as_bytes()).and_then(|written| {
+                    if written == target.len() { Ok(()) } else { Err(VfsError::NoSpace) }
+                })
+            }
+        };
+        if let Err(e) = stored {
+            // Dropping the unlinked inode frees it
+            self.remove_entry(name)?;
+            inode.state.lock().links = 0;
+            return Err(e);
+        }
+        Ok(inode)
+    }
+
+    fn readlink(&self) -> Result<String, VfsError> {
+        let mut state = self.state.lock();
+        if state.file_type() != FileType::Symlink {
+            return Err(VfsError::InvalidArgument);
+        }
+        let size = state.size as usize;
+        let target = if state.is_fast_symlink(&self.volume) {
+            let bytes: Vec<u8> = state.block.iter().flat_map(|slot| slot.to_le_bytes()).collect();
+            bytes[..size.min(FAST_SYMLINK_MAX)].to_vec()
+        } else {
+            let mut bytes = alloc::vec![0u8; size.min(self.volume.block_size)];
+            let read = self.read_locked(&mut state, 0, &mut bytes)?;
+            bytes.truncate(read);
+            bytes
+        };
+        String::from_utf8(target).map_err(|_| VfsError::IoError)
+    }
+
+    fn link(&self, name: &str, inode: &Arc<dyn Inode>) -> Result<(), VfsError> {
+        let target = downcast_inode::<Ext2Inode>(inode).ok_or(VfsError::CrossDevice)?;
+        if !Arc::ptr_eq(&self.volume, &target.volume) {
+            return Err(VfsError::CrossDevice);
+        }
+        self.volume.check_writable()?;
+        self.check_directory()?;
+        validate_name(name)?;
+        let file_type = target.file_type();
+        // Directory hard links would make the tree a graph
+        if file_type == FileType::Directory {
+            return Err(VfsError::PermissionDenied);
+        }
+
+        let _dirs = self.volume.dirs.lock();
+        if self.find_entry(name)?.is_some() {
+            return Err(VfsError::AlreadyExists);
+        }
+        {
+            let state = target.state.lock();
+            if state.links == 0 {
+                return Err(VfsError::NotFound);
+            }
+            if state.links >= LINK_MAX {
+                return Err(VfsError::InvalidOperation);
+            }
+        }
+        self.add_entry(name, target.ino, file_type)?;
+        target.change_links(1)
+    }
+
+    fn rename(&self, old_name: &str, new_dir: &Arc<dyn Inode>, new_name: &str) -> Result<(), VfsError> {
+        let new_dir = downcast_inode::<Ext2Inode>(new_dir).ok_or(VfsError::CrossDevice)?;
+        if !Arc::ptr_eq(&self.volume, &new_dir.volume) {
+            return Err(VfsError::CrossDevice);
+        }
+        self.volume.check_writable()?;
+        self.check_directory()?;
+        new_dir.check_directory()?;
+        validate_name(new_name)?;
+        let volume = &self.volume;
+        let _dirs = volume.dirs.lock();
+
+        let (ino, _) = self.find_entry(old_name)?.ok_or(VfsError::NotFound)?;
+        let same_dir = self.ino == new_dir.ino;
+        if same_dir && old_name == new_name {
+            return Ok(());
+        }
+        let source = volume.get_inode(ino)?;
+        let file_type = source.file_type();
+        let directory = file_type == FileType::Directory;
+        // A directory cannot be moved below itself
+        if directory && new_dir.is_within(ino)? {
+            return Err(VfsError::InvalidArgument);
+        }
+
+        match new_dir.find_entry(new_name)? {
+            // Both names already refer to the same inode
+            Some((target_ino, _)) if target_ino == ino => return Ok(()),
+            Some((target_ino, _)) => {
+                let target = volume.get_inode(target_ino)?;
+                match (directory, target.file_type() == FileType::Directory) {
+                    (true, true) => {
+                        if !target.is_empty_directory()? {
+                            return Err(VfsError::DirectoryNotEmpty);
+                        }
+                    }
+                    (true, false) => return Err(VfsError::NotADirectory),
+                    (false, true) => return Err(VfsError::IsADirectory),
+                    (false, false) => {}
+                }
+                new_dir.replace_entry(new_name, ino, file_type)?;
+                if directory {
+                    target.change_links(-2)?;
+                    new_dir.change_links(-1)?;
+                } else {
+                    target.change_links(-1)?;
+                }
+            }
+            None => {
+                if directory && !same_dir && new_dir.state.lock().links >= LINK_MAX {
+                    return Err(VfsError::InvalidOperation);
+                }
+                new_dir.add_entry(new_name, ino, file_type)?;
+            }
+        }
+        self.remove_entry(old_name)?;
+
+        if directory && !same_dir {
+            source.replace_entry("..", new_dir.ino, FileType::Directory)?;
+            self.change_links(-1)?;
+            new_dir.change_links(1)?;
+        }
+        source.change_links(0)
+    }
+
+    fn metadata(&self) -> Metadata {
+        let state = self.state.lock();
+        Metadata {
+            ino: self.ino as u64,
+            file_type: state.file_type(),
+            mode: state.mode & MODE_PERMISSION_MASK,
+            uid: state.uid,
+            gid: state.gid,
+            nlink: state.links as u32,
+            size: state.size,
+            blocks: state.blocks as u64,
+            atime: from_ext2_time(state.atime),
+            mtime: from_ext2_time(state.mtime),
+            ctime: from_ext2_time(state.ctime),
+        }
+    }
+
+    fn setattr(&self, attr: &SetAttr) -> Result<(), VfsError> {
+        self.volume.check_writable()?;
+        let mut state = self.state.lock();
+        if let Some(mode) = attr.mode {
+            state.mode = (state.mode & S_IFMT) | (mode & MODE_PERMISSION_MASK);
+        }
+        if let Some(uid) = attr.uid {
+            state.uid = uid;
+        }
+        if let Some(gid) = attr.gid {
+            state.gid = gid;
+        }
+        if let Some(atime) = attr.atime {
+            state.atime = to_ext2_time(atime);
+        }
+        if let Some(mtime) = attr.mtime {
+            state.mtime = to_ext2_time(mtime);
+        }
+        state.ctime = to_ext2_time(current_time());
+        self.store(&state)
+    }
+}
+
+/// A mounted ext2 volume
+pub struct Ext2Fs {
+    volume: Arc<Volume>,
+    root: Arc<Ext2Inode>,
+}
+
+impl Ext2Fs {
+    /// Mount the ext2 volume on `device`
+    pub fn mount(device: Arc<dyn BlockDevice>) -> Result<Self, VfsError> {
+        let mut sb = [0u8; SUPERBLOCK_SIZE];
+        if block::read_bytes(device.as_ref(), SUPERBLOCK_OFFSET, &mut sb)? != sb.len() {
+            return Err(VfsError::InvalidArgument);
+        }
+        if read_u16(&sb, 56) != EXT2_MAGIC {
+            return Err(VfsError::InvalidArgument);
+        }
+
+        let inodes_count = read_u32(&sb, 0);
+        let blocks_count = read_u32(&sb, 4);
+        let first_data_block = read_u32(&sb, 20);
+        let log_block_size = read_u32(&sb, 24);
+        let blocks_per_group = read_u32(&sb, 32);
+        let inodes_per_group = read_u32(&sb, 40);
+        let fs_state = read_u16(&sb, 58);
+        let revision = read_u32(&sb, 76);
+        // Compatible features (at byte 92) can be ignored
+        let (first_ino, inode_size, incompat, ro_compat) = if revision == GOOD_OLD_REV {
+            (GOOD_OLD_FIRST_INO, GOOD_OLD_INODE_SIZE, 0, 0)
+        } else {
+            (read_u32(&sb, 84), read_u16(&sb, 88) as usize, read_u32(&sb, 96), read_u32(&sb, 100))
+        };
+
+        if log_block_size > 2 {
+            return Err(VfsError::InvalidArgument);
+        }
+        let block_size = 1024usize << log_block_size;
+        let valid = block_size <= MAX_BLOCK_SIZE
+            && block_size.is_multiple_of(device.block_size())
+            && blocks_per_group > 0
+            && blocks_per_group as usize <= block_size * 8
+            && inodes_per_group > 0
+            && inodes_per_group as usize <= block_size * 8
+            && inode_size >= GOOD_OLD_INODE_SIZE
+            && inode_size <= block_size
+            && inode_size.is_power_of_two()
+            && first_data_block < blocks_count
+            && blocks_count as u64 * block_size as u64 <= device.size();
+        if !valid {
+            return Err(VfsError::InvalidArgument);
+        }
+        if incompat & !SUPPORTED_INCOMPAT != 0 {
+            crate::serial_println!("ext2: unsupported incompatible features {:#x}", incompat & !SUPPORTED_INCOMPAT);
+            return Err(VfsError::NotImplemented);
+        }
+        let read_only = ro_compat & !SUPPORTED_RO_COMPAT != 0;
+        if read_only {
+            crate::serial_println!("ext2: unsupported features {:#x}, mounting read-only", ro_compat & !SUPPORTED_RO_COMPAT);
+        }
+        if fs_state & STATE_VALID == 0 || fs_state & STATE_ERROR != 0 {
+            crate::serial_println!("ext2: volume was not cleanly unmounted or has errors; run e2fsck");
+        }
+
+        let group_count = (blocks_count - first_data_block).div_ceil(blocks_per_group);
+        if inodes_per_group as u64 * group_count as u64 != inodes_count as u64 {
+            return Err(VfsError::InvalidArgument);
+        }
+
+        // The descriptor table follows the superblock's block
+        let descriptor_block = first_data_block + 1;
+        let mut groups = Vec::with_capacity(group_count as usize);
+        let mut free_blocks = 0;
+        let mut free_inodes = 0;
+        for group in 0..group_count {
+            let offset = group as usize * GROUP_DESC_SIZE;
+            let buffer = bcache::bread(&device, (descriptor_block + (offset / block_size) as u32) as u64, block_size)?;
+            let data = buffer.data();
+            let at = offset % block_size;
+            let desc = GroupDesc {
+                block_bitmap: read_u32(&data, at),
+                inode_bitmap: read_u32(&data, at + 4),
+                inode_table: read_u32(&data, at + 8),
+                free_blocks: read_u16(&data, at + 12),
+                free_inodes: read_u16(&data, at + 14),
+                used_dirs: read_u16(&data, at + 16),
+            };
+            let table_blocks = (inodes_per_group as usize * inode_size).div_ceil(block_size) as u32;
+            if [desc.block_bitmap, desc.inode_bitmap, desc.inode_table + table_blocks - 1]
+                .iter()
+                .any(|&block| block < first_data_block || block >= blocks_count)
+            {
+                crate::serial_println!("ext2: bad descriptor for group {}", group);
+                return Err(VfsError::InvalidArgument);
+            }
+            free_blocks += desc.free_blocks as u32;
+            free_inodes += desc.free_inodes as u32;
+            groups.push(desc);
+        }
+
+        let now = to_ext2_time(current_time());
+        let volume = Arc::new(Volume {
+            device,
+            block_size,
+            first_data_block,
+            blocks_count,
+            blocks_per_group,
+            inodes_per_group,
+            inodes_count,
+            inode_size,
+            first_ino,
+            revision,
+            filetype: incompat & INCOMPAT_FILETYPE != 0,
+            read_only,
+            state: Mutex::new(SuperState {
+                groups,
+                free_blocks,
+                free_inodes,
+                ro_compat,
+            }),
+            dirs: Mutex::new(()),
+            inodes: Mutex::new(BTreeMap::new()),
+            next_generation: AtomicU32::new(now),
+        });
+
+        let root = volume.get_inode(ROOT_INO)?;
+        if root.file_type() != FileType::Directory {
+            return Err(VfsError::InvalidArgument);
+        }
+
+        if !read_only {
+            volume.write_superblock(|raw| {
+                put_u32(raw, 44, now); // last mount
+                put_u16(raw, 52, read_u16(raw, 52).wrapping_add(1)); // mount count
+            })?;
+        }
+        Ok(Self { volume, root })
+    }
+
+    /// Free space in bytes
+    #[allow(dead_code)]
+    pub fn free_bytes(&self) -> u64 {
+        self.volume.state.lock().free_blocks as u64 * self.volume.block_size as u64
+    }
+}
+
+impl Filesystem for Ext2Fs {
+    fn name(&self) -> &'static str {
+        "ext2"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+
+    fn sync(&self) -> Result<(), VfsError> {
+        pagecache::writeback_all()?;
+        if !self.volume.read_only {
+            let now = to_ext2_time(current_time());
+            self.volume.write_superblock(|raw| put_u32(raw, 48, now))?; // last write
+        }
+        bcache::sync_device(&self.volume.device)
+    }
+
+    fn device(&self) -> Option<Arc<dyn BlockDevice>> {
+        Some(self.volume.device.clone())
+    }
+}
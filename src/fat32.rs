@@ -0,0 +1,1499 @@
+//! FAT32 filesystem
+//!
+//! Reads and writes FAT32 volumes on a block device, such as the images
+//! made by `tools/mkimage`. Directories carry VFAT long file names; a name
+//! that fits 8.3 is stored as a short entry alone, using the NT case bits
+//! for all-lowercase parts, and any other name gets long name entries in
+//! front of a generated `BASIS~N` short name.
+//!
+//! All sectors go through the buffer cache, so metadata and file data are
+//! written back together by `sync` or on unmount. Clusters are allocated
+//! from the FSInfo next-free hint, and its free count is kept exact.
+//!
+//! FAT stores no inodes. An inode is identified by the position of its
+//! short entry, which also gives its inode number, and the volume keeps
+//! one live `FatInode` per entry so every lookup returns the same object.
+//! A file removed while still in use keeps its clusters until the last
+//! reference goes away.
+//!
+//! There are no owners, permissions or links: files report 0644 (0444
+//! with the read-only attribute) and directories 0755, and chmod only
+//! toggles the read-only attribute.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use crate::bcache::{self, Buffer};
+use crate::block::{self, BlockDevice};
+use crate::rtc::DateTime;
+use crate::time::Timespec;
+use crate::vfs::{current_time, downcast_inode, DirEntry, FileType, Filesystem, Inode, Metadata, SetAttr, VfsError, STAT_BLOCK_SIZE};
+
+const DIR_ENTRY_SIZE: u32 = 32;
+/// UTF-16 units held by one long name entry
+const LFN_UNITS: usize = 13;
+/// Longest long name, in UTF-16 units
+const MAX_NAME_UNITS: usize = 255;
+/// A directory may hold at most 65536 entries
+const MAX_DIR_BYTES: u32 = 65536 * DIR_ENTRY_SIZE;
+
+const ATTR_READ_ONLY: u8 = 0x01;
+const ATTR_HIDDEN: u8 = 0x02;
+const ATTR_SYSTEM: u8 = 0x04;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = ATTR_READ_ONLY | ATTR_HIDDEN | ATTR_SYSTEM | ATTR_VOLUME_ID;
+
+/// NT case bits in byte 12 of a short entry: base or extension is lower case
+const CASE_LOWER_BASE: u8 = 0x08;
+const CASE_LOWER_EXT: u8 = 0x10;
+
+/// First byte of a deleted entry, and of the entry ending a directory
+const ENTRY_DELETED: u8 = 0xE5;
+const ENTRY_END: u8 = 0x00;
+/// Stored in place of a leading 0xE5 of a real name
+const ENTRY_KANJI_E5: u8 = 0x05;
+
+/// FAT entries: the low 28 bits count
+const FAT_MASK: u32 = 0x0FFF_FFFF;
+const FAT_BAD: u32 = 0x0FFF_FFF7;
+/// Entries from here up end a chain
+const FAT_EOC_MIN: u32 = 0x0FFF_FFF8;
+const FAT_EOC: u32 = 0x0FFF_FFFF;
+
+const FSINFO_LEAD_SIG: u32 = 0x4161_5252;
+const FSINFO_STRUCT_SIG: u32 = 0x6141_7272;
+const FSINFO_UNKNOWN: u32 = 0xFFFF_FFFF;
+
+/// Inode number of the root directory
+const ROOT_INO: u64 = 1;
+
+/// Characters allowed in a short name besides letters and digits
+const SHORT_NAME_SPECIALS: &[u8] = b"$%'-_@~`!(){}^#&";
+/// Characters never allowed in a long name
+const INVALID_NAME_CHARS: &[char] = &['"', '*', '/', ':', '<', '>', '?', '\\', '|'];
+
+fn read_u16(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([bytes[at], bytes[at + 1]])
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}
+
+fn put_u16(bytes: &mut [u8], at: usize, value: u16) {
+    bytes[at..at + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(bytes: &mut [u8], at: usize, value: u32) {
+    bytes[at..at + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Convert to a FAT (date, time, 10 ms units) triple; FAT dates run 1980-2107
+fn to_fat_time(time: Timespec) -> (u16, u16, u8) {
+    let dt = DateTime::from_unix(time.tv_sec.max(0) as u64);
+    if dt.year < 1980 {
+        return (0x21, 0, 0); // 1980-01-01 00:00:00
+    }
+    if dt.year > 2107 {
+        return (0xFF9F, 0xBF7D, 199); // 2107-12-31 23:59:59
+    }
+    let date = ((dt.year - 1980) as u16) << 9 | (dt.month as u16) << 5 | dt.day as u16;
+    let time_of_day = (dt.hour as u16) << 11 | (dt.minute as u16) << 5 | (dt.second / 2) as u16;
+    let tenths = (dt.second % 2) * 100 + (time.tv_nsec / 10_000_000) as u8;
+    (date, time_of_day, tenths)
+}
+
+/// Convert a FAT date and time; a zero date (never set) is the Unix epoch
+fn from_fat_time(date: u16, time: u16, tenths: u8) -> Timespec {
+    if date == 0 {
+        return Timespec { tv_sec: 0, tv_nsec: 0 };
+    }
+    let dt = DateTime {
+        year: 1980 + (date >> 9) as u32,
+        month: ((date >> 5) & 0x0F).clamp(1, 12) as u8,
+        day: (date & 0x1F).max(1) as u8,
+        hour: (time >> 11) as u8,
+        minute: ((time >> 5) & 0x3F) as u8,
+        second: ((time & 0x1F) * 2) as u8,
+    };
+    let tenths = tenths.min(199) as i64;
+    Timespec {
+        tv_sec: dt.to_unix() as i64 + tenths / 100,
+        tv_nsec: tenths % 100 * 10_000_000,
+    }
+}
+
+/// Checksum of a short name, stored in each of its long name entries
+fn short_name_checksum(short: &[u8]) -> u8 {
+    short.iter().fold(0u8, |sum, &byte| sum.rotate_right(1).wrapping_add(byte))
+}
+
+fn is_short_char(byte: u8) -> bool {
+    byte.is_ascii_uppercase() || byte.is_ascii_digit() || SHORT_NAME_SPECIALS.contains(&byte) || byte >= 0x80
+}
+
+/// The displayed form of a short entry: "README.TXT", or "readme.txt"
+/// with the NT lower-case bits
+fn short_display_name(entry: &[u8]) -> String {
+    let case = entry[12];
+    let mut name = String::new();
+    for (i, &byte) in entry[0..8].iter().enumerate() {
+        let byte = if i == 0 && byte == ENTRY_KANJI_E5 { ENTRY_DELETED } else { byte };
+        name.push(if case & CASE_LOWER_BASE != 0 { byte.to_ascii_lowercase() } else { byte } as char);
+    }
+    let base_len = name.trim_end_matches(' ').len();
+    name.truncate(base_len);
+
+    let ext: String = entry[8..11]
+        .iter()
+        .map(|&byte| if case & CASE_LOWER_EXT != 0 { byte.to_ascii_lowercase() } else { byte } as char)
+        .collect();
+    let ext = ext.trim_end_matches(' ');
+    if !ext.is_empty() {
+        name.push('.');
+        name.push_str(ext);
+    }
+    name
+}
+
+/// Store `name` directly as a short entry if it is a plain 8.3 name whose
+/// base and extension are each all upper or all lower case
+fn exact_short_name(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, ext) = match name.rfind('.') {
+        Some(dot) => (&name[..dot], &name[dot + 1..]),
+        None => (name, ""),
+    };
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 || (name.contains('.') && ext.is_empty()) {
+        return None;
+    }
+
+    let mut short = [b' '; 11];
+    let mut case = 0;
+    let (base_slots, ext_slots) = short.split_at_mut(8);
+    for (part, slots, lower_bit) in [(base, base_slots, CASE_LOWER_BASE), (ext, ext_slots, CASE_LOWER_EXT)] {
+        let has_lower = part.bytes().any(|b| b.is_ascii_lowercase());
+        let has_upper = part.bytes().any(|b| b.is_ascii_uppercase());
+        if has_lower && has_upper {
+            return None;
+        }
+        if has_lower {
+            case |= lower_bit;
+        }
+        for (slot, byte) in slots.iter_mut().zip(part.bytes()) {
+            let byte = byte.to_ascii_uppercase();
+            if !is_short_char(byte) || byte >= 0x80 {
+                return None;
+            }
+            *slot = byte;
+        }
+    }
+    if short[0] == ENTRY_DELETED {
+        short[0] = ENTRY_KANJI_E5;
+    }
+    Some((short, case))
+}
+
+/// Base and extension a generated short name is made from: upper case,
+/// with characters that are not allowed replaced by '_'
+fn short_name_basis(name: &str) -> (Vec<u8>, Vec<u8>) {
+    let name = name.trim_start_matches('.');
+    let (base, ext) = match name.rfind('.') {
+        Some(dot) => (&name[..dot], &name[dot + 1..]),
+        None => (name, ""),
+    };
+    let convert = |part: &str, limit: usize| -> Vec<u8> {
+        part.chars()
+            .filter(|&c| c != ' ' && c != '.')
+            .map(|c| {
+                let byte = if c.is_ascii() { c.to_ascii_uppercase() as u8 } else { b'_' };
+                if is_short_char(byte) && byte < 0x80 { byte } else { b'_' }
+            })
+            .take(limit)
+            .collect()
+    };
+    let mut base = convert(base, 8);
+    if base.is_empty() {
+        base.push(b'_');
+    }
+    (base, convert(ext, 3))
+}
+
+/// Whether `name` can be stored at all
+fn validate_name(name: &str) -> Result<(), VfsError> {
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(VfsError::InvalidArgument);
+    }
+    if name.encode_utf16().count() > MAX_NAME_UNITS {
+        return Err(VfsError::InvalidArgument);
+    }
+    if name.chars().any(|c| (c as u32) < 0x20 || INVALID_NAME_CHARS.contains(&c)) {
+        return Err(VfsError::InvalidArgument);
+    }
+    // Windows drops trailing dots and spaces, so such names could not be
+    // looked up again there
+    if name.ends_with('.') || name.ends_with(' ') {
+        return Err(VfsError::InvalidArgument);
+    }
+    Ok(())
+}
+
+/// FAT compares names without regard to case
+fn names_match(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.chars().zip(b.chars()).all(|(x, y)| x.to_uppercase().eq(y.to_uppercase()))
+}
+
+/// Position of a file's entries in its parent directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct EntryRef {
+    /// First cluster of the directory holding the entry
+    dir: u32,
+    /// Byte position of the short entry
+    pos: u32,
+    /// Byte position of the first long name entry (`pos` if there are none)
+    first: u32,
+}
+
+impl EntryRef {
+    /// Inode number derived from the position: stable across lookups and
+    /// the same as readdir reports
+    fn ino(&self) -> u64 {
+        (self.dir as u64) << 32 | (self.pos / DIR_ENTRY_SIZE) as u64
+    }
+}
+
+/// A directory entry as found by `Volume::next_entry`
+struct DirSlot {
+    name: String,
+    /// The 11-byte short name, to check for collisions
+    short: [u8; 11],
+    attr: u8,
+    cluster: u32,
+    size: u32,
+    created: Timespec,
+    modified: Timespec,
+    accessed: Timespec,
+    entry: EntryRef,
+}
+
+impl DirSlot {
+    fn file_type(&self) -> FileType {
+        if self.attr & ATTR_DIRECTORY != 0 {
+            FileType::Directory
+        } else {
+            FileType::Regular
+        }
+    }
+}
+
+/// Long name entries collected while scanning towards their short entry
+struct LongName {
+    units: [u16; 20 * LFN_UNITS],
+    /// Sequence number expected next (counting down to 1), 0 when complete
+    expected: u8,
+    checksum: u8,
+    first: u32,
+    valid: bool,
+}
+
+impl LongName {
+    fn new() -> Self {
+        Self {
+            units: [0; 20 * LFN_UNITS],
+            expected: 0,
+            checksum: 0,
+            first: 0,
+            valid: false,
+        }
+    }
+
+    fn push(&mut self, entry: &[u8], pos: u32) {
+        let order = entry[0] & 0x1F;
+        if entry[0] & 0x40 != 0 {
+            // The last piece comes first and starts a new name
+            self.valid = (1..=20).contains(&order);
+            self.checksum = entry[13];
+            self.first = pos;
+            self.units = [0; 20 * LFN_UNITS];
+        } else if !(self.valid && order == self.expected && entry[13] == self.checksum) {
+            self.valid = false;
+            return;
+        }
+        if !self.valid {
+            return;
+        }
+
+        let base = (order as usize - 1) * LFN_UNITS;
+        let offsets = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+        for (i, &at) in offsets.iter().enumerate() {
+            self.units[base + i] = read_u16(entry, at);
+        }
+        self.expected = order - 1;
+    }
+
+    /// The name, if a complete sequence for the short entry `short` was seen
+    fn take(&mut self, short: &[u8]) -> Option<(String, u32)> {
+        let complete = self.valid && self.expected == 0 && self.checksum == short_name_checksum(short);
+        self.valid = false;
+        if !complete {
+            return None;
+        }
+        let len = self.units.iter().position(|&unit| unit == 0 || unit == 0xFFFF).unwrap_or(self.units.len());
+        Some((String::from_utf16_lossy(&self.units[..len]), self.first))
+    }
+}
+
+/// Follows a cluster chain, remembering where it is so sequential access
+/// does not start over from the first cluster each time
+#[derive(Debug, Clone, Copy)]
+struct Walker {
+    first: u32,
+    index: u32,
+    cluster: u32,
+}
+
+impl Walker {
+    fn new(first: u32) -> Self {
+        Self { first, index: 0, cluster: first }
+    }
+
+    /// Cluster number `index` of the chain, None past its end
+    fn seek(&mut self, volume: &Volume, index: u32) -> Result<Option<u32>, VfsError> {
+        if self.first == 0 {
+            return Ok(None);
+        }
+        if index < self.index {
+            *self = Walker::new(self.first);
+        }
+        while self.index < index {
+            if self.index > volume.cluster_count {
+                return Err(VfsError::IoError); // the chain loops
+            }
+            match volume.next_cluster(self.cluster)? {
+                Some(next) => {
+                    self.cluster = next;
+                    self.index += 1;
+                }
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(self.cluster))
+    }
+}
+
+/// Allocation state, kept in step with the FSInfo sector
+struct AllocState {
+    free_clusters: u32,
+    next_free: u32,
+}
+
+/// A mounted FAT32 volume: geometry, FAT access and directory entries
+struct Volume {
+    device: Arc<dyn BlockDevice>,
+    sector_size: usize,
+    sectors_per_cluster: u32,
+    reserved_sectors: u32,
+    fat_count: u32,
+    fat_sectors: u32,
+    /// The one FAT in use when mirroring is off
+    active_fat: Option<u32>,
+    root_cluster: u32,
+    fsinfo_sector: Option<u32>,
+    /// First sector of cluster 2
+    data_start: u32,
+    /// Number of data clusters (numbered from 2)
+    cluster_count: u32,
+    alloc: Mutex<AllocState>,
+    /// Serializes changes to directories. Lock order: this, then the
+    /// inode table, then an inode's state, then the allocation state
+    dirs: Mutex<()>,
+    /// Live inodes by (directory cluster, short entry position)
+    inodes: Mutex<BTreeMap<(u32, u32), Weak<FatInode>>>,
+}
+
+impl Volume {
+    fn cluster_bytes(&self) -> u32 {
+        self.sectors_per_cluster * self.sector_size as u32
+    }
+
+    fn is_data_cluster(&self, cluster: u32) -> bool {
+        (2..self.cluster_count + 2).contains(&cluster)
+    }
+
+    fn cluster_sector(&self, cluster: u32) -> u64 {
+        self.data_start as u64 + (cluster - 2) as u64 * self.sectors_per_cluster as u64
+    }
+
+    fn bread(&self, sector: u64) -> Result<Buffer, VfsError> {
+        bcache::bread(&self.device, sector, self.sector_size)
+    }
+
+    /// Sector and offset of `cluster`'s entry in FAT number `fat`
+    fn fat_position(&self, fat: u32, cluster: u32) -> (u64, usize) {
+        let offset = cluster as usize * 4;
+        let sector = self.reserved_sectors + fat * self.fat_sectors + (offset / self.sector_size) as u32;
+        (sector as u64, offset % self.sector_size)
+    }
+
+    fn fat_entry(&self, cluster: u32) -> Result<u32, VfsError> {
+        let (sector, at) = self.fat_position(self.active_fat.unwrap_or(0), cluster);
+        let buffer = self.bread(sector)?;
+        let value = read_u32(&buffer.data(), at) & FAT_MASK;
+        Ok(value)
+    }
+
+    /// Set `cluster`'s entry in every FAT copy (the top 4 bits are kept)
+    fn set_fat_entry(&self, cluster: u32, value: u32) -> Result<(), VfsError> {
+        let fats = match self.active_fat {
+            Some(fat) => fat..fat + 1,
+            None => 0..self.fat_count,
+        };
+        for fat in fats {
+            let (sector, at) = self.fat_position(fat, cluster);
+            let buffer = self.bread(sector)?;
+            {
+                let mut data = buffer.data();
+                let old = read_u32(&data, at);
+                put_u32(&mut data, at, (old & !FAT_MASK) | (value & FAT_MASK));
+            }
+            bcache::bwrite(&buffer);
+        }
+        Ok(())
+    }
+
+    /// The cluster after `cluster` in its chain, None at the end
+    fn next_cluster(&self, cluster: u32) -> Result<Option<u32>, VfsError> {
+        match self.fat_entry(cluster)? {
+            entry if entry >= FAT_EOC_MIN => Ok(None),
+            entry if entry == FAT_BAD || !self.is_data_cluster(entry) => {
+                crate::serial_println!("fat32: bad FAT entry {:#x} for cluster {}", entry, cluster);
+                Err(VfsError::IoError)
+            }
+            entry => Ok(Some(entry)),
+        }
+    }
+
+    /// Allocate a cluster, appending it to the chain ending at `previous`
+    fn allocate_cluster(&self, previous: Option<u32>) -> Result<u32, VfsError> {
+        let mut alloc = self.alloc.lock();
+        if alloc.free_clusters == 0 {
+            return Err(VfsError::NoSpace);
+        }
+
+        let start = if self.is_data_cluster(alloc.next_free) { alloc.next_free } else { 2 };
+        let mut found = None;
+        for i in 0..self.cluster_count {
+            let cluster = 2 + (start - 2 + i) % self.cluster_count;
+            if self.fat_entry(cluster)? == 0 {
+                found = Some(cluster);
+                break;
+            }
+        }
+        let cluster = found.ok_or(VfsError::NoSpace)?;
+
+        self.set_fat_entry(cluster, FAT_EOC)?;
+        if let Some(previous) = previous {
+            self.set_fat_entry(previous, cluster)?;
+        }
+        alloc.free_clusters = alloc.free_clusters.saturating_sub(1);
+        alloc.next_free = cluster + 1;
+        self.write_fsinfo(&alloc)?;
+        Ok(cluster)
+    }
+
+    /// Free the chain starting at `cluster`
+    fn free_chain(&self, mut cluster: u32) -> Result<(), VfsError> {
+        let mut alloc = self.alloc.lock();
+        let mut freed = 0;
+        while self.is_data_cluster(cluster) && freed <= self.cluster_count {
+            let next = self.fat_entry(cluster)?;
+            self.set_fat_entry(cluster, 0)?;
+            freed += 1;
+            if next >= FAT_EOC_MIN {
+                break;
+            }
+            cluster = next;
+        }
+        alloc.free_clusters = (alloc.free_clusters + freed).min(self.cluster_count);
+        self.write_fsinfo(&alloc)
+    }
+
+    /// Cut the chain starting at `first` down to `keep` clusters (at least 1)
+    fn truncate_chain(&self, first: u32, keep: u32) -> Result<(), VfsError> {
+        let mut walker = Walker::new(first);
+        let Some(last) = walker.seek(self, keep - 1)? else {
+            return Ok(()); // already that short
+        };
+        if let Some(rest) = self.next_cluster(last)? {
+            self.set_fat_entry(last, FAT_EOC)?;
+            self.free_chain(rest)?;
+        }
+        Ok(())
+    }
+
+    fn write_fsinfo(&self, alloc: &AllocState) -> Result<(), VfsError> {
+        let Some(sector) = self.fsinfo_sector else {
+            return Ok(());
+        };
+        let buffer = self.bread(sector as u64)?;
+        {
+            let mut data = buffer.data();
+            if read_u32(&data, 0) != FSINFO_LEAD_SIG || read_u32(&data, 484) != FSINFO_STRUCT_SIG {
+                return Ok(());
+            }
+            put_u32(&mut data, 488, alloc.free_clusters);
+            put_u32(&mut data, 492, alloc.next_free);
+        }
+        bcache::bwrite(&buffer);
+        Ok(())
+    }
+
+    /// Fill a cluster with zeros, e.g. a new directory cluster
+    fn zero_cluster(&self, cluster: u32) -> Result<(), VfsError> {
+        let first = self.cluster_sector(cluster);
+        for sector in first..first + self.sectors_per_cluster as u64 {
+            let buffer = bcache::bget(&self.device, sector, self.sector_size)?;
+            buffer.data().fill(0);
+            bcache::bwrite(&buffer);
+        }
+        Ok(())
+    }
+
+    /// Sector holding byte `pos` of the chain `walker` follows, and the
+    /// offset within it; None past the end of the chain
+    fn chain_sector(&self, walker: &mut Walker, pos: u32) -> Result<Option<(u64, usize)>, VfsError> {
+        let cluster_bytes = self.cluster_bytes();
+        let Some(cluster) = walker.seek(self, pos / cluster_bytes)? else {
+            return Ok(None);
+        };
+        let within = (pos % cluster_bytes) as usize;
+        Ok(Some((self.cluster_sector(cluster) + (within / self.sector_size) as u64, within % self.sector_size)))
+    }
+
+    /// The raw 32-byte entry at `pos` of a directory
+    fn read_entry(&self, walker: &mut Walker, pos: u32) -> Result<Option<[u8; 32]>, VfsError> {
+        if pos >= MAX_DIR_BYTES {
+            return Ok(None);
+        }
+        let Some((sector, at)) = self.chain_sector(walker, pos)? else {
+            return Ok(None);
+        };
+        let buffer = self.bread(sector)?;
+        let mut entry = [0u8; 32];
+        entry.copy_from_slice(&buffer.data()[at..at + 32]);
+        Ok(Some(entry))
+    }
+
+    /// Change the raw entry at `pos` of the directory starting at `dir`
+    fn update_entry(&self, dir: u32, pos: u32, change: impl FnOnce(&mut [u8])) -> Result<(), VfsError> {
+        let (sector, at) = self.chain_sector(&mut Walker::new(dir), pos)?.ok_or(VfsError::IoError)?;
+        let buffer = self.bread(sector)?;
+        change(&mut buffer.data()[at..at + 32]);
+        bcache::bwrite(&buffer);
+        Ok(())
+    }
+
+    /// The next entry of directory `dir` at or after byte `pos`, skipping
+    /// free slots, the volume label and `.`/`..`
+    fn next_entry(&self, dir: u32, mut pos: u32) -> Result<Option<DirSlot>, VfsError> {
+        let mut walker = Walker::new(dir);
+        let mut long_name = LongName::new();
+
+        while let Some(raw) = self.read_entry(&mut walker, pos)? {
+            match raw[0] {
+                ENTRY_END => break,
+                ENTRY_DELETED => long_name.valid = false,
+                _ if raw[11] & 0x3F == ATTR_LONG_NAME => long_name.push(&raw, pos),
+                _ if raw[11] & ATTR_VOLUME_ID != 0 || raw[0] == b'.' => long_name.valid = false,
+                _ => {
+                    let (name, first) = long_name.take(&raw[0..11]).unwrap_or_else(|| (short_display_name(&raw), pos));
+                    let mut short = [0u8; 11];
+                    short.copy_from_slice(&raw[0..11]);
+                    return Ok(Some(DirSlot {
+                        name,
+                        short,
+                        attr: raw[11],
+                        cluster: (read_u16(&raw, 20) as u32) << 16 | read_u16(&raw, 26) as u32,
+                        size: read_u32(&raw, 28),
+                        created: from_fat_time(read_u16(&raw, 16), read_u16(&raw, 14), raw[13]),
+                        modified: from_fat_time(read_u16(&raw, 24), read_u16(&raw, 22), 0),
+                        accessed: from_fat_time(read_u16(&raw, 18), 0, 0),
+                        entry: EntryRef { dir, pos, first },
+                    }));
+                }
+            }
+            pos += DIR_ENTRY_SIZE;
+        }
+        Ok(None)
+    }
+
+    /// Find `name` in directory `dir`
+    fn find_entry(&self, dir: u32, name: &str) -> Result<Option<DirSlot>, VfsError> {
+        let mut pos = 0;
+        while let Some(slot) = self.next_entry(dir, pos)? {
+            if names_match(&slot.name, name) || names_match(&short_display_name_of(&slot.short), name) {
+                return Ok(Some(slot));
+            }
+            pos = slot.entry.pos + DIR_ENTRY_SIZE;
+        }
+        Ok(None)
+    }
+
+    fn is_empty_directory(&self, dir: u32) -> Result<bool, VfsError> {
+        Ok(self.next_entry(dir, 0)?.is_none())
+    }
+
+    fn short_name_taken(&self, dir: u32, short: &[u8; 11]) -> Result<bool, VfsError> {
+        let mut walker = Walker::new(dir);
+        let mut pos = 0;
+        while let Some(raw) = self.read_entry(&mut walker, pos)? {
+            if raw[0] == ENTRY_END {
+                break;
+            }
+            if raw[0] != ENTRY_DELETED && raw[11] & 0x3F != ATTR_LONG_NAME && raw[0..11] == short[..] {
+                return Ok(true);
+            }
+            pos += DIR_ENTRY_SIZE;
+        }
+        Ok(false)
+    }
+
+    /// Pick the short name for a new entry `name` in `dir`; the bool says
+    /// whether long name entries are needed
+    fn choose_short_name(&self, dir: u32, name: &str) -> Result<([u8; 11], u8, bool), VfsError> {
+        if let Some((short, case)) = exact_short_name(name) {
+            if !self.short_name_taken(dir, &short)? {
+                return Ok((short, case, false));
+            }
+        }
+
+        let (base, ext) = short_name_basis(name);
+        for n in 1..1_000_000u32 {
+            let mut tail = [0u8; 8];
+            let tail_len = {
+                let mut digits = [0u8; 7];
+                let mut count = 0;
+                let mut value = n;
+                while value > 0 {
+                    digits[count] = b'0' + (value % 10) as u8;
+                    value /= 10;
+                    count += 1;
+                }
+                tail[0] = b'~';
+                for i in 0..count {
+                    tail[1 + i] = digits[count - 1 - i];
+                }
+                count + 1
+            };
+
+            let mut short = [b' '; 11];
+            let keep = base.len().min(8 - tail_len);
+            short[..keep].copy_from_slice(&base[..keep]);
+            short[keep..keep + tail_len].copy_from_slice(&tail[..tail_len]);
+            short[8..8 + ext.len()].copy_from_slice(&ext);
+            if !self.short_name_taken(dir, &short)? {
+                return Ok((short, 0, true));
+            }
+        }
+        Err(VfsError::AlreadyExists)
+    }
+
+    /// Last cluster of the chain starting at `first`
+    fn last_cluster(&self, first: u32) -> Result<u32, VfsError> {
+        let mut cluster = first;
+        for _ in 0..=self.cluster_count {
+            match self.next_cluster(cluster)? {
+                Some(next) => cluster = next,
+                None => return Ok(cluster),
+            }
+        }
+        Err(VfsError::IoError)
+    }
+
+    /// Find `count` consecutive free entries in `dir`, growing it if needed;
+    /// returns the position of the first
+    fn find_free_entries(&self, dir: u32, count: u32) -> Result<u32, VfsError> {
+        let mut walker = Walker::new(dir);
+        let mut pos = 0;
+        let mut run_start = 0;
+        let mut run = 0;
+        loop {
+            if pos >= MAX_DIR_BYTES {
+                return Err(VfsError::NoSpace);
+            }
+            match self.read_entry(&mut walker, pos)? {
+                None => {
+                    // Past the end of the chain: add a zeroed cluster
+                    let cluster = self.allocate_cluster(Some(self.last_cluster(dir)?))?;
+                    self.zero_cluster(cluster)?;
+                    continue;
+                }
+                Some(raw) if raw[0] == ENTRY_END || raw[0] == ENTRY_DELETED => {
+                    if run == 0 {
+                        run_start = pos;
+                    }
+                    run += 1;
+                    if run == count {
+                        return Ok(run_start);
+                    }
+                }
+                Some(_) => run = 0,
+            }
+            pos += DIR_ENTRY_SIZE;
+        }
+    }
+
+    /// Write the entries for `name` into `dir`
+    fn add_entry(&self, dir: u32, name: &str, attr: u8, cluster: u32, size: u32, time: Timespec) -> Result<EntryRef, VfsError> {
+        validate_name(name)?;
+        let (short, case, long) = self.choose_short_name(dir, name)?;
+
+        let units: Vec<u16> = if long { name.encode_utf16().collect() } else { Vec::new() };
+        let long_entries = units.len().div_ceil(LFN_UNITS) as u32;
+        let first = self.find_free_entries(dir, long_entries + 1)?;
+        let pos = first + long_entries * DIR_ENTRY_SIZE;
+
+        // Long name entries, last piece first
+        let checksum = short_name_checksum(&short);
+        for k in 0..long_entries {
+            let order = long_entries - k;
+            self.update_entry(dir, first + k * DIR_ENTRY_SIZE, |entry| {
+                entry.fill(0);
+                entry[0] = order as u8 | if k == 0 { 0x40 } else { 0 };
+                entry[11] = ATTR_LONG_NAME;
+                entry[13] = checksum;
+                let offsets = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+                for (i, &at) in offsets.iter().enumerate() {
+                    let index = (order as usize - 1) * LFN_UNITS + i;
+                    // The name is 0x0000 terminated (unless it fills the
+                    // last entry exactly), then padded with 0xFFFF
+                    let unit = match index.cmp(&units.len()) {
+                        core::cmp::Ordering::Less => units[index],
+                        core::cmp::Ordering::Equal => 0x0000,
+                        core::cmp::Ordering::Greater => 0xFFFF,
+                    };
+                    put_u16(entry, at, unit);
+                }
+            })?;
+        }
+
+        let (date, time_of_day, tenths) = to_fat_time(time);
+        self.update_entry(dir, pos, |entry| {
+            entry.fill(0);
+            entry[0..11].copy_from_slice(&short);
+            entry[11] = attr;
+            entry[12] = case;
+            entry[13] = tenths;
+            put_u16(entry, 14, time_of_day);
+            put_u16(entry, 16, date);
+            put_u16(entry, 18, date);
+            put_u16(entry, 20, (cluster >> 16) as u16);
+            put_u16(entry, 22, time_of_day);
+            put_u16(entry, 24, date);
+            put_u16(entry, 26, cluster as u16);
+            put_u32(entry, 28, size);
+        })?;
+
+        Ok(EntryRef { dir, pos, first })
+    }
+
+    /// Mark an entry and its long name entries free
+    fn delete_entry(&self, entry: EntryRef) -> Result<(), VfsError> {
+        let mut pos = entry.first;
+        while pos <= entry.pos {
+            self.update_entry(entry.dir, pos, |raw| raw[0] = ENTRY_DELETED)?;
+            pos += DIR_ENTRY_SIZE;
+        }
+        Ok(())
+    }
+
+    /// Write the `.` and `..` entries of a new directory
+    fn write_dot_entries(&self, cluster: u32, parent: u32, time: Timespec) -> Result<(), VfsError> {
+        // `..` of a directory in the root points at cluster 0
+        let parent = if parent == self.root_cluster { 0 } else { parent };
+        let (date, time_of_day, tenths) = to_fat_time(time);
+        for (index, name, target) in [(0, b".          ", cluster), (1, b"..         ", parent)] {
+            self.update_entry(cluster, index * DIR_ENTRY_SIZE, |entry| {
+                entry.fill(0);
+                entry[0..11].copy_from_slice(name);
+                entry[11] = ATTR_DIRECTORY;
+                entry[13] = tenths;
+                put_u16(entry, 14, time_of_day);
+                put_u16(entry, 16, date);
+                put_u16(entry, 18, date);
+                put_u16(entry, 20, (target >> 16) as u16);
+                put_u16(entry, 22, time_of_day);
+                put_u16(entry, 24, date);
+                put_u16(entry, 26, target as u16);
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Whether directory `dir` is `ancestor` or lies below it
+    fn is_within(&self, mut dir: u32, ancestor: u32) -> Result<bool, VfsError> {
+        for _ in 0..=self.cluster_count {
+            if dir == ancestor {
+                return Ok(true);
+            }
+            if dir == self.root_cluster {
+                return Ok(false);
+            }
+            // Follow `..`, the second entry
+            let raw = self.read_entry(&mut Walker::new(dir), DIR_ENTRY_SIZE)?.ok_or(VfsError::IoError)?;
+            let parent = (read_u16(&raw, 20) as u32) << 16 | read_u16(&raw, 26) as u32;
+            dir = if parent == 0 { self.root_cluster } else { parent };
+        }
+        Err(VfsError::IoError)
+    }
+}
+
+/// The displayed form of a raw 11-byte short name (without case bits)
+fn short_display_name_of(short: &[u8; 11]) -> String {
+    let mut entry = [0u8; 32];
+    entry[0..11].copy_from_slice(short);
+    short_display_name(&entry)
+}
+
+/// Live inode for a directory entry, creating it if none is in use
+fn inode_for(volume: &Arc<Volume>, slot: &DirSlot) -> Arc<FatInode> {
+    let key = (slot.entry.dir, slot.entry.pos);
+    let mut inodes = volume.inodes.lock();
+    if let Some(inode) = inodes.get(&key).and_then(Weak::upgrade) {
+        return inode;
+    }
+    inodes.retain(|_, inode| inode.strong_count() > 0);
+
+    let inode = Arc::new(FatInode {
+        volume: volume.clone(),
+        ino: slot.entry.ino(),
+        state: Mutex::new(FatState {
+            entry: Some(slot.entry),
+            first_cluster: slot.cluster,
+            size: if slot.attr & ATTR_DIRECTORY != 0 { 0 } else { slot.size },
+            attr: slot.attr,
+            created: slot.created,
+            modified: slot.modified,
+            accessed: slot.accessed,
+            walker: Walker::new(slot.cluster),
+            orphan: false,
+        }),
+    });
+    inodes.insert(key, Arc::downgrade(&inode));
+    inode
+}
+
+/// A file or directory on a FAT32 volume
+pub struct FatInode {
+    volume: Arc<Volume>,
+    ino: u64,
+    state: Mutex<FatState>,
+}
+
+struct FatState {
+    /// Where the entry is; None for the root and for removed files
+    entry: Option<EntryRef>,
+    first_cluster: u32,
+    size: u32,
+    attr: u8,
+    created: Timespec,
+    modified: Timespec,
+    accessed: Timespec,
+    /// Position in the cluster chain of the last access
+    walker: Walker,
+    /// Removed while in use: free the clusters when the inode goes away
+    orphan: bool,
+}
+
+impl FatState {
+    fn is_directory(&self) -> bool {
+        self.attr & ATTR_DIRECTORY != 0
+    }
+
+    /// Clusters the chain should have for the current size
+    fn clusters_for_size(&self, cluster_bytes: u32) -> u32 {
+        if self.first_cluster == 0 {
+            0
+        } else {
+            self.size.div_ceil(cluster_bytes).max(1)
+        }
+    }
+}
+
+impl FatInode {
+    /// Cluster of a directory (constant for its lifetime)
+    fn dir_cluster(&self) -> Result<u32, VfsError> {
+        let state = self.state.lock();
+        if !state.is_directory() {
+            return Err(VfsError::NotADirectory);
+        }
+        if state.orphan {
+            return Err(VfsError::NotFound);
+        }
+        Ok(state.first_cluster)
+    }
+
+    /// Write the size, first cluster, attributes and times back to the entry
+    fn store_entry(&self, state: &FatState) -> Result<(), VfsError> {
+        let Some(entry) = state.entry else {
+            return Ok(());
+        };
+        let (date, time_of_day, _) = to_fat_time(state.modified);
+        let (access_date, _, _) = to_fat_time(state.accessed);
+        let size = if state.is_directory() { 0 } else { state.size };
+        self.volume.update_entry(entry.dir, entry.pos, |raw| {
+            raw[11] = state.attr;
+            put_u16(raw, 18, access_date);
+            put_u16(raw, 20, (state.first_cluster >> 16) as u16);
+            put_u16(raw, 22, time_of_day);
+            put_u16(raw, 24, date);
+            put_u16(raw, 26, state.first_cluster as u16);
+            put_u32(raw, 28, size);
+        })
+    }
+
+    /// Make the chain long enough for `size` bytes
+    fn grow_chain(&self, state: &mut FatState, size: u32) -> Result<(), VfsError> {
+        let volume = &self.volume;
+        let have = state.clusters_for_size(volume.cluster_bytes());
+        let need = size.div_ceil(volume.cluster_bytes());
+        if need <= have {
+            return Ok(());
+        }
+
+        let mut last = match have {
+            0 => None,
+            n => Some(state.walker.seek(volume, n - 1)?.ok_or(VfsError::IoError)?),
+        };
+        for added in 0..need - have {
+            match volume.allocate_cluster(last) {
+                Ok(cluster) => {
+                    if state.first_cluster == 0 {
+                        state.first_cluster = cluster;
+                        state.walker = Walker::new(cluster);
+                    }
+                    last = Some(cluster);
+                }
+                Err(e) => {
+                    // Give back what was added so the chain matches the size
+                    if have == 0 && added > 0 {
+                        volume.free_chain(state.first_cluster)?;
+                        state.first_cluster = 0;
+                        state.walker = Walker::new(0);
+                    } else if have > 0 {
+                        volume.truncate_chain(state.first_cluster, have)?;
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Copy `buffer` into the file at `offset`; the chain must already
+    /// cover it
+    fn write_data(&self, state: &mut FatState, offset: u32, buffer: &[u8]) -> Result<(), VfsError> {
+        let volume = &self.volume;
+        let sector_size = volume.sector_size;
+        let mut done = 0;
+        while done < buffer.len() {
+            let pos = offset + done as u32;
+            let (sector, at) = volume.chain_sector(&mut state.walker, pos)?.ok_or(VfsError::IoError)?;
+            let count = (sector_size - at).min(buffer.len() - done);
+
+            let block = if count == sector_size {
+                bcache::bget(&volume.device, sector, sector_size)?
+            } else {
+                volume.bread(sector)?
+            };
+            block.data()[at..at + count].copy_from_slice(&buffer[done..done + count]);
+            bcache::bwrite(&block);
+            done += count;
+        }
+        Ok(())
+    }
+
+    /// Fill the file with zeros from `from` to `to`
+    fn zero_range(&self, state: &mut FatState, from: u32, to: u32) -> Result<(), VfsError> {
+        const ZEROS: [u8; 512] = [0; 512];
+        let mut pos = from;
+        while pos < to {
+            let count = (to - pos).min(ZEROS.len() as u32);
+            self.write_data(state, pos, &ZEROS[..count as usize])?;
+            pos += count;
+        }
+        Ok(())
+    }
+
+    fn write_locked(&self, state: &mut FatState, offset: usize, buffer: &[u8]) -> Result<usize, VfsError> {
+        if state.is_directory() {
+            return Err(VfsError::IsADirectory);
+        }
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+        // FAT sizes are 32 bits
+        let end = offset
+            .checked_add(buffer.len())
+            .filter(|&end| end <= u32::MAX as usize)
+            .ok_or(VfsError::NoSpace)? as u32;
+        let offset = offset as u32;
+
+        self.grow_chain(state, end)?;
+        if offset > state.size {
+            let size = state.size;
+            self.zero_range(state, size, offset)?;
+        }
+        self.write_data(state, offset, buffer)?;
+
+        state.size = state.size.max(end);
+        state.modified = current_time();
+        state.attr |= ATTR_ARCHIVE;
+        self.store_entry(state)?;
+        Ok(buffer.len())
+    }
+}
+
+impl Drop for FatInode {
+    fn drop(&mut self) {
+        let state = self.state.get_mut();
+        if state.orphan && state.first_cluster != 0 {
+            if let Err(e) = self.volume.free_chain(state.first_cluster) {
+                crate::serial_println!("fat32: freeing removed file failed: {}", e);
+            }
+        }
+    }
+}
+
+impl Inode for FatInode {
+    fn read(&self, offset: usize, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        let mut state = self.state.lock();
+        if state.is_directory() {
+            return Err(VfsError::IsADirectory);
+        }
+        let size = state.size as usize;
+        if offset >= size {
+            return Ok(0);
+        }
+        let total = buffer.len().min(size - offset);
+
+        let volume = &self.volume;
+        let mut done = 0;
+        while done < total {
+            let pos = (offset + done) as u32;
+            let (sector, at) = volume.chain_sector(&mut state.walker, pos)?.ok_or(VfsError::IoError)?;
+            let count = (volume.sector_size - at).min(total - done);
+            let block = volume.bread(sector)?;
+            buffer[done..done + count].copy_from_slice(&block.data()[at..at + count]);
+            done += count;
+        }
+        Ok(total)
+    }
+
+    fn write(&self, offset: usize, buffer: &[u8]) -> Result<usize, VfsError> {
+        let mut state = self.state.lock();
+        self.write_locked(&mut state, offset, buffer)
+    }
+
+    fn append(&self, buffer: &[u8]) -> Result<usize, VfsError> {
+        let mut state = self.state.lock();
+        let offset = state.size as usize;
+        self.write_locked(&mut state, offset, buffer)?;
+        Ok(state.size as usize)
+    }
+
+    fn file_type(&self) -> FileType {
+        if self.state.lock().is_directory() {
+            FileType::Directory
+        } else {
+            FileType::Regular
+        }
+    }
+
+    fn size(&self) -> usize {
+        self.state.lock().size as usize
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, VfsError> {
+        let dir = self.dir_cluster()?;
+        let _dirs = self.volume.dirs.lock();
+        let slot = self.volume.find_entry(dir, name)?.ok_or(VfsError::NotFound)?;
+        Ok(inode_for(&self.volume, &slot))
+    }
+
+    fn create(&self, name: &str, file_type: FileType) -> Result<Arc<dyn Inode>, VfsError> {
+        let dir = self.dir_cluster()?;
+        let volume = &self.volume;
+        let _dirs = volume.dirs.lock();
+        validate_name(name)?;
+        if volume.find_entry(dir, name)?.is_some() {
+            return Err(VfsError::AlreadyExists);
+        }
+
+        let now = current_time();
+        let entry = match file_type {
+            FileType::Regular => volume.add_entry(dir, name, ATTR_ARCHIVE, 0, 0, now)?,
+            FileType::Directory => {
+                let cluster = volume.allocate_cluster(None)?;
+                let entry = volume
+                    .zero_cluster(cluster)
+                    .and_then(|()| volume.write_dot_entries(cluster, dir, now))
+                    .and_then(|()| volume.add_entry(dir, name, ATTR_DIRECTORY, cluster, 0, now));
+                match entry {
+                    Ok(entry) => entry,
+                    Err(e) => {
+                        volume.free_chain(cluster)?;
+                        return Err(e);
+                    }
+                }
+            }
+            _ => return Err(VfsError::NotImplemented),
+        };
+
+        let slot = volume.next_entry(dir, entry.pos)?.ok_or(VfsError::IoError)?;
+        Ok(inode_for(volume, &slot))
+    }
+
+    fn list(&self) -> Result<Vec<String>, VfsError> {
+        let mut names = Vec::new();
+        let mut cursor = 0;
+        while let Some((entry, next)) = self.readdir(cursor)? {
+            names.push(entry.name);
+            cursor = next;
+        }
+        Ok(names)
+    }
+
+    /// The cursor is the byte position in the directory of the next entry
+    /// to look at; entries never move, so it stays valid
+    fn readdir(&self, cursor: u64) -> Result<Option<(DirEntry, u64)>, VfsError> {
+        let dir = self.dir_cluster()?;
+        let _dirs = self.volume.dirs.lock();
+        let Some(slot) = self.volume.next_entry(dir, cursor.min(MAX_DIR_BYTES as u64) as u32)? else {
+            return Ok(None);
+        };
+        let entry = DirEntry {
+            name: slot.name.clone(),
+            ino: slot.entry.ino(),
+            file_type: slot.file_type(),
+        };
+        Ok(Some((entry, (slot.entry.pos + DIR_ENTRY_SIZE) as u64)))
+    }
+
+    fn truncate(&self, size: usize) -> Result<(), VfsError> {
+        let mut state = self.state.lock();
+        if state.is_directory() {
+            return Err(VfsError::IsADirectory);
+        }
+        let size = u32::try_from(size).map_err(|_| VfsError::NoSpace)?;
+        let volume = &self.volume;
+
+        if size > state.size {
+            self.grow_chain(&mut state, size)?;
+            let old = state.size;
+            self.zero_range(&mut state, old, size)?;
+        } else if size == 0 {
+            if state.first_cluster != 0 {
+                volume.free_chain(state.first_cluster)?;
+            }
+            state.first_cluster = 0;
+        } else {
+            volume.truncate_chain(state.first_cluster, size.div_ceil(volume.cluster_bytes()))?;
+        }
+
+        state.walker = Walker::new(state.first_cluster);
+        state.size = size;
+        state.modified = current_time();
+        state.attr |= ATTR_ARCHIVE;
+        self.store_entry(&state)
+    }
+
+    fn remove(&self, name: &str) -> Result<(), VfsError> {
+        let dir = self.dir_cluster()?;
+        let volume = &self.volume;
+        let _dirs = volume.dirs.lock();
+        let slot = volume.find_entry(dir, name)?.ok_or(VfsError::NotFound)?;
+        if slot.attr & ATTR_DIRECTORY != 0 && !volume.is_empty_directory(slot.cluster)? {
+            return Err(VfsError::DirectoryNotEmpty);
+        }
+        volume.delete_entry(slot.entry)?;
+
+        // An inode still in use keeps the clusters until it is dropped
+        let live = volume.inodes.lock().remove(&(slot.entry.dir, slot.entry.pos)).and_then(|inode| inode.upgrade());
+        match live {
+            Some(inode) => {
+                let mut state = inode.state.lock();
+                state.entry = None;
+                state.orphan = true;
+            }
+            None if slot.cluster != 0 => volume.free_chain(slot.cluster)?,
+            None => {}
+        }
+        Ok(())
+    }
+
+    fn rename(&self, old_name: &str, new_dir: &Arc<dyn Inode>, new_name: &str) -> Result<(), VfsError> {
+        let target_dir = downcast_inode::<FatInode>(new_dir).ok_or(VfsError::CrossDevice)?;
+        if !Arc::ptr_eq(&self.volume, &target_dir.volume) {
+            return Err(VfsError::CrossDevice);
+        }
+        let from = self.dir_cluster()?;
+        let to = target_dir.dir_cluster()?;
+        let volume = &self.volume;
+        let _dirs = volume.dirs.lock();
+
+        let slot = volume.find_entry(from, old_name)?.ok_or(VfsError::NotFound)?;
+        let is_dir = slot.attr & ATTR_DIRECTORY != 0;
+        if is_dir && volume.is_within(to, slot.cluster)? {
+            return Err(VfsError::InvalidArgument);
+        }
+
+        // A target that is the entry itself (a change of case) is not replaced
+        let target = volume.find_entry(to, new_name)?.filter(|target| target.entry != slot.entry);
+        if let Some(target) = &target {
+            match (is_dir, target.attr & ATTR_DIRECTORY != 0) {
+                (false, true) => return Err(VfsError::IsADirectory),
+                (true, false) => return Err(VfsError::NotADirectory),
+                (true, true) if !volume.is_empty_directory(target.cluster)? => return Err(VfsError::DirectoryNotEmpty),
+                _ => {}
+            }
+        }
+        if from == to && old_name == new_name {
+            return Ok(());
+        }
+
+        // Write the new entry before dropping the old ones, so a failure
+        // leaves the file where it was
+        let entry = volume.add_entry(to, new_name, slot.attr, slot.cluster, slot.size, slot.created)?;
+        if let Some(target) = &target {
+            volume.delete_entry(target.entry)?;
+        }
+        volume.delete_entry(slot.entry)?;
+        // Keep the original times
+        let (date, time_of_day, _) = to_fat_time(slot.modified);
+        volume.update_entry(entry.dir, entry.pos, |raw| {
+            put_u16(raw, 22, time_of_day);
+            put_u16(raw, 24, date);
+        })?;
+
+        if is_dir && from != to {
+            let parent = if to == volume.root_cluster { 0 } else { to };
+            volume.update_entry(slot.cluster, DIR_ENTRY_SIZE, |raw| {
+                put_u16(raw, 20, (parent >> 16) as u16);
+                put_u16(raw, 26, parent as u16);
+            })?;
+        }
+
+        let mut inodes = volume.inodes.lock();
+        // The replaced target keeps its clusters while in use
+        if let Some(target) = target {
+            match inodes.remove(&(target.entry.dir, target.entry.pos)).and_then(|inode| inode.upgrade()) {
+                Some(inode) => {
+                    let mut state = inode.state.lock();
+                    state.entry = None;
+                    state.orphan = true;
+                }
+                None if target.cluster != 0 => volume.free_chain(target.cluster)?,
+                None => {}
+            }
+        }
+        if let Some(inode) = inodes.remove(&(slot.entry.dir, slot.entry.pos)).and_then(|inode| inode.upgrade()) {
+            inode.state.lock().entry = Some(entry);
+            inodes.insert((entry.dir, entry.pos), Arc::downgrade(&inode));
+        }
+        Ok(())
+    }
+
+    fn metadata(&self) -> Metadata {
+        let state = self.state.lock();
+        let file_type = if state.is_directory() { FileType::Directory } else { FileType::Regular };
+        let cluster_bytes = self.volume.cluster_bytes() as u64;
+        let allocated = state.clusters_for_size(cluster_bytes as u32) as u64 * cluster_bytes;
+
+        let mut meta = Metadata::synthesized(self.ino, file_type, state.size as u64, state.modified);
+        if state.attr & ATTR_READ_ONLY != 0 {
+            meta.mode &= !0o222;
+        }
+        meta.blocks = allocated / STAT_BLOCK_SIZE;
+        meta.atime = state.accessed;
+        meta.ctime = state.created;
+        meta
+    }
+
+    /// Only the owner write bit (as the read-only attribute) and the times
+    /// can be stored; changing the owner is refused
+    fn setattr(&self, attr: &SetAttr) -> Result<(), VfsError> {
+        if attr.uid.is_some_and(|uid| uid != 0) || attr.gid.is_some_and(|gid| gid != 0) {
+            return Err(VfsError::PermissionDenied);
+        }
+        let mut state = self.state.lock();
+        if state.entry.is_none() {
+            return Err(VfsError::PermissionDenied);
+        }
+        if let Some(mode) = attr.mode {
+            if mode & 0o200 == 0 {
+                state.attr |= ATTR_READ_ONLY;
+            } else {
+                state.attr &= !ATTR_READ_ONLY;
+            }
+        }
+        if let Some(mtime) = attr.mtime {
+            state.modified = mtime;
+        }
+        if let Some(atime) = attr.atime {
+            state.accessed = atime;
+        }
+        self.store_entry(&state)
+    }
+}
+
+/// A mounted FAT32 volume
+pub struct Fat32Fs {
+    volume: Arc<Volume>,
+    root: Arc<FatInode>,
+}
+
+impl Fat32Fs {
+    /// Mount the FAT32 volume on `device`
+    pub fn mount(device: Arc<dyn BlockDevice>) -> Result<Self, VfsError> {
+        let mut boot = [0u8; 512];
+        if block::read_bytes(device.as_ref(), 0, &mut boot)? != boot.len() {
+            return Err(VfsError::InvalidArgument);
+        }
+        if boot[510] != 0x55 || boot[511] != 0xAA {
+            return Err(VfsError::InvalidArgument);
+        }
+
+        let sector_size = read_u16(&boot, 11) as usize;
+        let sectors_per_cluster = boot[13] as u32;
+        let reserved_sectors = read_u16(&boot, 14) as u32;
+        let fat_count = boot[16] as u32;
+        let root_entries = read_u16(&boot, 17);
+        let total_sectors = match read_u16(&boot, 19) {
+            0 => read_u32(&boot, 32),
+            small => small as u32,
+        };
+        let fat_sectors = read_u32(&boot, 36);
+        let ext_flags = read_u16(&boot, 40);
+        let root_cluster = read_u32(&boot, 44);
+        let fsinfo_sector = read_u16(&boot, 48) as u32;
+
+        let valid = matches!(sector_size, 512 | 1024 | 2048 | 4096)
+            && sector_size.is_multiple_of(device.block_size())
+            && sectors_per_cluster.is_power_of_two()
+            && reserved_sectors > 0
+            && fat_count > 0
+            // FAT32 has no fixed root directory and no 16-bit FAT size
+            && root_entries == 0
+            && read_u16(&boot, 22) == 0
+            && fat_sectors > 0
+            && total_sectors as u64 * sector_size as u64 <= device.size();
+        if !valid {
+            return Err(VfsError::InvalidArgument);
+        }
+
+        let data_start = reserved_sectors + fat_count * fat_sectors;
+        let data_sectors = total_sectors.checked_sub(data_start).ok_or(VfsError::InvalidArgument)?;
+        // The FAT may have room for fewer entries than the data area has clusters
+        let fat_entries = (fat_sectors as u64 * sector_size as u64 / 4).saturating_sub(2);
+        let cluster_count = (data_sectors / sectors_per_cluster).min(fat_entries.min(FAT_BAD as u64 - 2) as u32);
+        if cluster_count == 0 || !(2..cluster_count + 2).contains(&root_cluster) {
+            return Err(VfsError::InvalidArgument);
+        }
+
+        let volume = Arc::new(Volume {
+            device,
+            sector_size,
+            sectors_per_cluster,
+            reserved_sectors,
+            fat_count,
+            fat_sectors,
+            // Bit 7 set: only the FAT numbered in bits 0-3 is in use
+            active_fat: (ext_flags & 0x80 != 0).then_some((ext_flags & 0x0F) as u32).filter(|&fat| fat < fat_count),
+            root_cluster,
+            fsinfo_sector: (fsinfo_sector != 0 && fsinfo_sector != 0xFFFF && fsinfo_sector < reserved_sectors).then_some(fsinfo_sector),
+            data_start,
+            cluster_count,
+            alloc: Mutex::new(AllocState { free_clusters: 0, next_free: 2 }),
+            dirs: Mutex::new(()),
+            inodes: Mutex::new(BTreeMap::new()),
+        });
+
+        *volume.alloc.lock() = Self::read_alloc_state(&volume)?;
+
+        let now = current_time();
+        let root = Arc::new(FatInode {
+            volume: volume.clone(),
+            ino: ROOT_INO,
+            state: Mutex::new(FatState {
+                entry: None,
+                first_cluster: root_cluster,
+                size: 0,
+                attr: ATTR_DIRECTORY,
+                created: now,
+                modified: now,
+                accessed: now,
+                walker: Walker::new(root_cluster),
+                orphan: false,
+            }),
+        });
+        Ok(Self { volume, root })
+    }
+
+    /// Free count and hint from FSInfo, or counted from the FAT if FSInfo
+    /// is missing or not plausible
+    fn read_alloc_state(volume: &Volume) -> Result<AllocState, VfsError> {
+        if let Some(sector) = volume.fsinfo_sector {
+            let buffer = volume.bread(sector as u64)?;
+            let data = buffer.data();
+            let free = read_u32(&data, 488);
+            let next = read_u32(&data, 492);
+            if read_u32(&data, 0) == FSINFO_LEAD_SIG
+                && read_u32(&data, 484) == FSINFO_STRUCT_SIG
+                && free != FSINFO_UNKNOWN
+                && free <= volume.cluster_count
+            {
+                let next_free = if volume.is_data_cluster(next) { next } else { 2 };
+                return Ok(AllocState { free_clusters: free, next_free });
+            }
+        }
+
+        let mut free_clusters = 0;
+        for cluster in 2..volume.cluster_count + 2 {
+            if volume.fat_entry(cluster)? == 0 {
+                free_clusters += 1;
+            }
+        }
+        let state = AllocState { free_clusters, next_free: 2 };
+        volume.write_fsinfo(&state)?;
+        Ok(state)
+    }
+
+    /// Free space in bytes
+    #[allow(dead_code)]
+    pub fn free_bytes(&self) -> u64 {
+        self.volume.alloc.lock().free_clusters as u64 * self.volume.cluster_bytes() as u64
+    }
+}
+
+impl Filesystem for Fat32Fs {
+    fn name(&self) -> &'static str {
+        "fat32"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+
+    fn sync(&self) -> Result<(), VfsError> {
+        bcache::sync_device(&self.volume.device)
+    }
+
+    fn device(&self) -> Option<Arc<dyn BlockDevice>> {
+        Some(self.volume.device.clone())
+    }
+}
@@ -0,0 +1,384 @@
+//! Advisory file locks
+//!
+//! Two independent kinds of lock, as on Linux:
+//!
+//! - `flock` locks cover the whole file and belong to the open file, so
+//!   descriptors sharing it (dup, fork) share the lock. They go away when
+//!   the last descriptor for the open file is closed.
+//! - `fcntl` record locks cover a byte range and belong to the process.
+//!   They go away when the process closes any descriptor for the file or
+//!   exits.
+//!
+//! Locks are kept per inode in a global table. Blocked lockers sleep on a
+//! single wait queue that is woken whenever a lock is released. Before a
+//! record lock request blocks, the waits-for graph is searched: if the
+//! owners of the conflicting locks are (transitively) waiting for the
+//! requester, the request fails with `Deadlock` instead.
+
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::vfs::{Inode, VfsError};
+use crate::wait::{wait_event, WaitQueue, WaitResult};
+
+/// Shared (read) or exclusive (write) access
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockKind {
+    Shared,
+    Exclusive,
+}
+
+impl LockKind {
+    fn conflicts_with(self, other: LockKind) -> bool {
+        self == LockKind::Exclusive || other == LockKind::Exclusive
+    }
+}
+
+/// A byte-range lock held by a process
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecordLock {
+    pub pid: u32,
+    pub kind: LockKind,
+    /// First byte covered
+    pub start: u64,
+    /// One past the last byte covered; `u64::MAX` reaches past any EOF
+    pub end: u64,
+}
+
+impl RecordLock {
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start < end && start < self.end
+    }
+}
+
+/// Locks held on one inode
+#[derive(Default)]
+struct InodeLocks {
+    /// flock locks by open file
+    flocks: Vec<(usize, LockKind)>,
+    records: Vec<RecordLock>,
+}
+
+impl InodeLocks {
+    fn is_empty(&self) -> bool {
+        self.flocks.is_empty() && self.records.is_empty()
+    }
+
+    /// First record lock of another process that conflicts with `request`
+    fn record_conflict(&self, request: &RecordLock) -> Option<RecordLock> {
+        self.records
+            .iter()
+            .find(|lock| {
+                lock.pid != request.pid
+                    && lock.overlaps(request.start, request.end)
+                    && lock.kind.conflicts_with(request.kind)
+            })
+            .copied()
+    }
+
+    /// Drop `pid`'s locks on `[start, end)`, splitting any that stick out
+    fn unlock_range(&mut self, pid: u32, start: u64, end: u64) {
+        let mut kept = Vec::with_capacity(self.records.len());
+        for lock in self.records.drain(..) {
+            if lock.pid != pid || !lock.overlaps(start, end) {
+                kept.push(lock);
+                continue;
+            }
+            if lock.start < start {
+                kept.push(RecordLock { end: start, ..lock });
+            }
+            if lock.end > end {
+                kept.push(RecordLock { start: end, ..lock });
+            }
+        }
+        self.records = kept;
+    }
+}
+
+struct LockTable {
+    /// Locks by inode address; an entry exists only while it holds locks
+    inodes: BTreeMap<usize, InodeLocks>,
+    /// Blocked record lock requests by process, for deadlock detection
+    waiting: BTreeMap<u32, (usize, RecordLock)>,
+}
+
+impl LockTable {
+    /// Whether blocking `request` would close a cycle: the owners of the
+    /// conflicting locks wait, directly or through other waiters, for the
+    /// requester
+    fn would_deadlock(&self, key: usize, request: &RecordLock) -> bool {
+        let mut visited = BTreeSet::new();
+        let mut pending: Vec<(usize, RecordLock)> = Vec::from([(key, *request)]);
+
+        while let Some((key, wanted)) = pending.pop() {
+            let Some(locks) = self.inodes.get(&key) else {
+                continue;
+            };
+            for blocker in locks.records.iter().filter(|lock| {
+                lock.pid != wanted.pid && lock.overlaps(wanted.start, wanted.end) && lock.kind.conflicts_with(wanted.kind)
+            }) {
+                if blocker.pid == request.pid {
+                    return true;
+                }
+                if visited.insert(blocker.pid) {
+                    if let Some(&next) = self.waiting.get(&blocker.pid) {
+                        pending.push(next);
+                    }
+                }
+            }
+        }
+
+        false
+    }
+
+    fn release_empty(&mut self, key: usize) {
+        if self.inodes.get(&key).is_some_and(InodeLocks::is_empty) {
+            self.inodes.remove(&key);
+        }
+    }
+}
+
+lazy_static! {
+    static ref LOCK_TABLE: Mutex<LockTable> = Mutex::new(LockTable {
+        inodes: BTreeMap::new(),
+        waiting: BTreeMap::new(),
+    });
+}
+
+/// Woken whenever any lock is released
+static LOCK_WAIT: WaitQueue = WaitQueue::new();
+
+/// Run `f` on the lock table
+///
+/// Interrupts stay off while the table is locked, since lock attempts also
+/// run from `wait_event` with interrupts disabled.
+fn with_table<R>(f: impl FnOnce(&mut LockTable) -> R) -> R {
+    without_interrupts(|| f(&mut LOCK_TABLE.lock()))
+}
+
+fn inode_key(inode: &Arc<dyn Inode>) -> usize {
+    Arc::as_ptr(inode) as *const () as usize
+}
+
+/// Outcome of one attempt to take a lock
+enum Attempt {
+    Acquired,
+    Conflict,
+    Deadlock,
+}
+
+/// Retry `attempt` until it stops reporting a conflict
+///
+/// Without `wait`, a conflict fails with `WouldBlock`.
+fn acquire(wait: bool, mut attempt: impl FnMut() -> Attempt) -> Result<(), VfsError> {
+    let mut outcome = attempt();
+    if matches!(outcome, Attempt::Conflict) && wait {
+        let result = wait_event(&[&LOCK_WAIT], None, || {
+            outcome = attempt();
+            !matches!(outcome, Attempt::Conflict)
+        });
+        if result == WaitResult::Interrupted {
+            return Err(VfsError::Interrupted);
+        }
+    }
+
+    match outcome {
+        Attempt::Acquired => Ok(()),
+        Attempt::Conflict => Err(VfsError::WouldBlock),
+        Attempt::Deadlock => Err(VfsError::Deadlock),
+    }
+}
+
+/// Take, convert or drop the flock lock of open file `file` on `inode`
+///
+/// `None` unlocks. Converting drops the old lock first, as on Linux.
+pub fn flock(file: usize, inode: &Arc<dyn Inode>, kind: Option<LockKind>, wait: bool) -> Result<(), VfsError> {
+    let key = inode_key(inode);
+    release_flock(file, key);
+
+    let Some(kind) = kind else {
+        return Ok(());
+    };
+
+    acquire(wait, || {
+        with_table(|table| {
+            let locks = table.inodes.entry(key).or_default();
+            if locks.flocks.iter().any(|&(_, held)| held.conflicts_with(kind)) {
+                table.release_empty(key);
+                return Attempt::Conflict;
+            }
+            locks.flocks.push((file, kind));
+            Attempt::Acquired
+        })
+    })
+}
+
+/// Set or clear a record lock of process `pid` on `[start, end)` (F_SETLK/F_SETLKW)
+///
+/// `None` for the kind unlocks the range. Locks the process already holds
+/// in the range are replaced. With `wait`, a conflicting request blocks
+/// unless that would deadlock.
+pub fn set_record_lock(
+    inode: &Arc<dyn Inode>,
+    pid: u32,
+    kind: Option<LockKind>,
+    start: u64,
+    end: u64,
+    wait: bool,
+) -> Result<(), VfsError> {
+    let key = inode_key(inode);
+
+    let Some(kind) = kind else {
+        with_table(|table| {
+            if let Some(locks) = table.inodes.get_mut(&key) {
+                locks.unlock_range(pid, start, end);
+            }
+            table.release_empty(key);
+        });
+        LOCK_WAIT.wake_all();
+        return Ok(());
+    };
+
+    let request = RecordLock { pid, kind, start, end };
+    let result = acquire(wait, || {
+        with_table(|table| {
+            let locks = table.inodes.entry(key).or_default();
+            if locks.record_conflict(&request).is_none() {
+                locks.unlock_range(pid, start, end);
+                locks.records.push(request);
+                table.waiting.remove(&pid);
+                return Attempt::Acquired;
+            }
+
+            table.release_empty(key);
+            if wait && table.would_deadlock(key, &request) {
+                table.waiting.remove(&pid);
+                return Attempt::Deadlock;
+            }
+            table.waiting.insert(pid, (key, request));
+            Attempt::Conflict
+        })
+    });
+
+    if result.is_err() {
+        with_table(|table| table.waiting.remove(&pid));
+    }
+    // A shared lock replacing an exclusive one may unblock others
+    LOCK_WAIT.wake_all();
+    result
+}
+
+/// Find a lock that would block `request` (F_GETLK)
+pub fn get_record_lock(inode: &Arc<dyn Inode>, request: &RecordLock) -> Option<RecordLock> {
+    with_table(|table| table.inodes.get(&inode_key(inode))?.record_conflict(request))
+}
+
+/// Drop the flock lock held by open file `file` on the inode at `key`
+fn release_flock(file: usize, key: usize) {
+    let released = with_table(|table| {
+        let Some(locks) = table.inodes.get_mut(&key) else {
+            return false;
+        };
+        let before = locks.flocks.len();
+        locks.flocks.retain(|&(owner, _)| owner != file);
+        let released = locks.flocks.len() != before;
+        table.release_empty(key);
+        released
+    });
+
+    if released {
+        LOCK_WAIT.wake_all();
+    }
+}
+
+/// Drop the flock lock of an open file that is being destroyed
+pub fn release_file(file: usize, inode: &Arc<dyn Inode>) {
+    release_flock(file, inode_key(inode));
+}
+
+/// Drop every record lock `pid` holds on `inode` (on close)
+pub fn release_records(pid: u32, inode: &Arc<dyn Inode>) {
+    set_record_lock(inode, pid, None, 0, u64::MAX, false).ok();
+}
+
+/// Drop every record lock of an exiting process
+pub fn release_process(pid: u32) {
+    with_table(|table| {
+        table.waiting.remove(&pid);
+        for locks in table.inodes.values_mut() {
+            locks.records.retain(|lock| lock.pid != pid);
+        }
+        table.inodes.retain(|_, locks| !locks.is_empty());
+    });
+
+    LOCK_WAIT.wake_all();
+}
+
+/// `l_type` values of `struct flock`
+pub const F_RDLCK: i16 = 0;
+pub const F_WRLCK: i16 = 1;
+pub const F_UNLCK: i16 = 2;
+
+/// flock(2) operations
+pub const LOCK_SH: u64 = 1;
+pub const LOCK_EX: u64 = 2;
+pub const LOCK_NB: u64 = 4;
+pub const LOCK_UN: u64 = 8;
+
+/// A record lock description as laid out in user memory (struct flock)
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Flock {
+    pub l_type: i16,
+    pub l_whence: i16,
+    pub l_start: i64,
+    /// 0 means up to any EOF; negative lengths extend backwards from l_start
+    pub l_len: i64,
+    pub l_pid: i32,
+}
+
+impl Flock {
+    /// Lock kind requested by `l_type`; `None` means unlock
+    pub fn kind(&self) -> Result<Option<LockKind>, VfsError> {
+        match self.l_type {
+            F_RDLCK => Ok(Some(LockKind::Shared)),
+            F_WRLCK => Ok(Some(LockKind::Exclusive)),
+            F_UNLCK => Ok(None),
+            _ => Err(VfsError::InvalidArgument),
+        }
+    }
+
+    /// Resolve the byte range, given the offset `l_whence` refers to
+    pub fn range(&self, base: u64) -> Result<(u64, u64), VfsError> {
+        let start = (base as i64).checked_add(self.l_start).ok_or(VfsError::InvalidArgument)?;
+        let (start, end) = match self.l_len {
+            0 => (start, i64::MAX),
+            len if len > 0 => (start, start.saturating_add(len)),
+            len => (start + len, start),
+        };
+        if start < 0 {
+            return Err(VfsError::InvalidArgument);
+        }
+
+        let end = if end == i64::MAX { u64::MAX } else { end as u64 };
+        Ok((start as u64, end))
+    }
+
+    /// Describe a conflicting lock for F_GETLK
+    pub fn from_lock(lock: &RecordLock) -> Self {
+        Self {
+            l_type: match lock.kind {
+                LockKind::Shared => F_RDLCK,
+                LockKind::Exclusive => F_WRLCK,
+            },
+            l_whence: 0,
+            l_start: lock.start as i64,
+            l_len: if lock.end == u64::MAX { 0 } else { (lock.end - lock.start) as i64 },
+            l_pid: lock.pid as i32,
+        }
+    }
+}
@@ -91,6 +91,21 @@ extern "x86-interrupt" fn page_fault_handler(
     error_code: PageFaultErrorCode,
 ) {
     use x86_64::registers::control::Cr2;
+    use crate::vma::{PROT_EXEC, PROT_READ, PROT_WRITE};
+
+    // Faults in user mappings are resolved by mapping the page on demand
+    let access = if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+        PROT_WRITE
+    } else if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+        PROT_EXEC
+    } else {
+        PROT_READ
+    };
+    if let Ok(addr) = Cr2::read() {
+        if crate::vma::handle_page_fault(addr.as_u64(), access).is_ok() {
+            return;
+        }
+    }
 
     panic!(
         "EXCEPTION: PAGE FAULT\nAccessed Address: {:?}\nError Code: {:?}\n{:#?}",
@@ -129,15 +144,16 @@ extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFr
     // Increment the tick counter
     crate::pit::tick();
 
-    // Perform context switch to next process (if scheduler is initialized)
-    // Note: This is called every timer tick (100Hz), enabling preemptive multitasking
-    unsafe {
-        crate::process::PROCESS_MANAGER.lock().schedule();
-    }
+    // Update the clocks and fire expired timers (sleeps, alarms)
+    crate::time::on_tick();
 
-    // Send EOI (End of Interrupt) to PIC1
+    // Switch to the next process; EOI is sent before the switch so the
+    // process we switch to keeps getting timer interrupts
+    // Note: This is called every timer tick (100Hz), enabling preemptive multitasking
     unsafe {
-        x86_64::instructions::port::Port::<u8>::new(PIC1_COMMAND).write(PIC_EOI);
+        crate::process::preempt(|| {
+            x86_64::instructions::port::Port::<u8>::new(PIC1_COMMAND).write(PIC_EOI);
+        });
     }
 }
 
@@ -1,31 +1,350 @@
 //! Initial RAM Filesystem (initramfs) support
 //!
-//! Provides support for extracting an embedded CPIO archive as the initial root filesystem
+//! Unpacks a CPIO archive in the "newc" format (as written by
+//! `cpio -H newc`) into the root tmpfs. The archive is either the ramdisk
+//! handed over by the bootloader or one built into the kernel image with
+//! `include_bytes!`. Directories, regular files (hard links included) and
+//! symlinks are created with their modes, owners and modification times;
+//! device nodes, fifos and sockets are skipped. In the "crc" variant of the
+//! format each regular file's checksum is verified.
 
-#[allow(dead_code)]
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use bootloader_api::BootInfo;
+use core::fmt;
+
+use crate::time::Timespec;
+use crate::vfs::{FileType, Inode, SetAttr, VfsError};
+
+/// Magic number of a newc header
+const NEWC_MAGIC: &[u8] = b"070701";
+/// Magic number of a newc header with a data checksum
+const CRC_MAGIC: &[u8] = b"070702";
+
+/// Size of a newc header: the magic and 13 fields of 8 hex digits
+const HEADER_LEN: usize = 110;
+
+/// Name of the entry that ends the archive
+const TRAILER: &str = "TRAILER!!!";
+
+/// File type bits of a CPIO mode
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+/// Errors from unpacking an archive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpioError {
+    /// The entry at this offset does not start with a newc magic number
+    BadMagic(usize),
+    /// The entry at this offset has a malformed field or name
+    BadHeader(usize),
+    /// The archive ends inside an entry or before the trailer
+    Truncated,
+    /// The data of the entry at this offset does not match its checksum
+    BadChecksum(usize),
+    /// Creating an entry in the filesystem failed
+    Vfs(VfsError),
+}
+
+impl fmt::Display for CpioError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CpioError::BadMagic(offset) => write!(f, "bad magic number at offset {:#x}", offset),
+            CpioError::BadHeader(offset) => write!(f, "malformed header at offset {:#x}", offset),
+            CpioError::Truncated => write!(f, "archive is truncated"),
+            CpioError::BadChecksum(offset) => write!(f, "checksum mismatch at offset {:#x}", offset),
+            CpioError::Vfs(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<VfsError> for CpioError {
+    fn from(e: VfsError) -> Self {
+        CpioError::Vfs(e)
+    }
+}
+
+/// Counts of what an extraction created
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ExtractSummary {
+    pub directories: usize,
+    pub files: usize,
+    pub symlinks: usize,
+    /// Extra names for files already extracted
+    pub hard_links: usize,
+    /// Entries of types tmpfs cannot hold
+    pub skipped: usize,
+}
+
+/// One archive entry
+struct Entry<'a> {
+    /// Offset of the header, for error reports
+    offset: usize,
+    ino: u32,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    nlink: u32,
+    mtime: u32,
+    devmajor: u32,
+    devminor: u32,
+    name: &'a str,
+    data: &'a [u8],
+}
+
+/// Walks the entries of an archive up to the trailer
+struct Entries<'a> {
+    archive: &'a [u8],
+    offset: usize,
+    done: bool,
+}
+
+impl<'a> Entries<'a> {
+    /// Parse the entry at the current offset and step past it
+    fn parse(&mut self) -> Result<Option<Entry<'a>>, CpioError> {
+        let offset = self.offset;
+        let header = self.archive.get(offset..offset + HEADER_LEN).ok_or(CpioError::Truncated)?;
+
+        let magic = &header[..6];
+        let checked = magic == CRC_MAGIC;
+        if magic != NEWC_MAGIC && !checked {
+            return Err(CpioError::BadMagic(offset));
+        }
+
+        // Fields follow the magic in this order: ino, mode, uid, gid, nlink,
+        // mtime, filesize, devmajor, devminor, rdevmajor, rdevminor,
+        // namesize, check
+        let field = |index: usize| -> Result<u32, CpioError> {
+            let start = 6 + index * 8;
+            core::str::from_utf8(&header[start..start + 8])
+                .ok()
+                .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+                .ok_or(CpioError::BadHeader(offset))
+        };
+        let file_size = field(6)? as usize;
+        let name_size = field(11)? as usize;
+        let check = field(12)?;
+
+        // The name includes its NUL; name and data are each padded to 4 bytes
+        let name_start = offset + HEADER_LEN;
+        let name_bytes = self
+            .archive
+            .get(name_start..name_start + name_size)
+            .ok_or(CpioError::Truncated)?;
+        let name = match name_bytes.split_last() {
+            Some((0, name)) => core::str::from_utf8(name).map_err(|_| CpioError::BadHeader(offset))?,
+            _ => return Err(CpioError::BadHeader(offset)),
+        };
+
+        let data_start = (name_start + name_size).next_multiple_of(4);
+        let data = self
+            .archive
+            .get(data_start..data_start + file_size)
+            .ok_or(CpioError::Truncated)?;
+        self.offset = (data_start + file_size).next_multiple_of(4);
+
+        if name == TRAILER {
+            return Ok(None);
+        }
+
+        let mode = field(1)?;
+        if checked && mode & S_IFMT == S_IFREG {
+            let sum = data.iter().fold(0u32, |sum, &byte| sum.wrapping_add(byte as u32));
+            if sum != check {
+                return Err(CpioError::BadChecksum(offset));
+            }
+        }
+
+        Ok(Some(Entry {
+            offset,
+            ino: field(0)?,
+            mode,
+            uid: field(2)?,
+            gid: field(3)?,
+            nlink: field(4)?,
+            mtime: field(5)?,
+            devmajor: field(7)?,
+            devminor: field(8)?,
+            name,
+            data,
+        }))
+    }
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Result<Entry<'a>, CpioError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let result = self.parse().transpose();
+        // Stop at the trailer or the first error
+        if !matches!(result, Some(Ok(_))) {
+            self.done = true;
+        }
+        result
+    }
+}
+
+/// A CPIO newc archive to unpack at boot
 pub struct InitRamFs {
-    // Placeholder for future CPIO archive data
+    archive: &'static [u8],
 }
 
 impl InitRamFs {
-    /// Create a new initramfs instance
-    ///
-    /// Currently a stub - full CPIO extraction will be implemented later
-    #[allow(dead_code)]
-    pub fn new() -> Self {
-        Self {}
+    /// Use an archive already in memory, e.g. one built in with `include_bytes!`
+    pub fn new(archive: &'static [u8]) -> Self {
+        Self { archive }
+    }
+
+    /// Use the ramdisk the bootloader loaded, if there is one
+    pub fn from_boot_info(boot_info: &BootInfo) -> Option<Self> {
+        let addr = boot_info.ramdisk_addr.into_option()?;
+        if boot_info.ramdisk_len == 0 {
+            return None;
+        }
+        // SAFETY: the bootloader maps the ramdisk at this virtual address for
+        // the lifetime of the kernel and nothing else uses that memory
+        let archive = unsafe { core::slice::from_raw_parts(addr as *const u8, boot_info.ramdisk_len as usize) };
+        Some(Self::new(archive))
     }
 
-    /// Extract the embedded initramfs to the given filesystem
+    /// Size of the archive in bytes
+    pub fn size(&self) -> usize {
+        self.archive.len()
+    }
+
+    /// Extract the archive into the root tmpfs
     ///
-    /// Currently a stub - returns Ok without doing anything
-    #[allow(dead_code)]
-    pub fn extract(&self) -> Result<(), &'static str> {
-        // TODO: Implement CPIO archive extraction
-        // 1. Locate embedded CPIO archive in kernel image
-        // 2. Parse CPIO headers
-        // 3. Extract files to tmpfs
-        // 4. Set up directory structure
-        Ok(())
+    /// Entries that already exist are replaced, except directories, which
+    /// only take the archive's attributes. Missing parent directories are
+    /// created. Extraction stops at the first error, leaving what was
+    /// unpacked so far in place.
+    pub fn extract(&self) -> Result<ExtractSummary, CpioError> {
+        self.extract_to(&crate::tmpfs::TMPFS.root())
     }
+
+    /// Extract the archive below the directory `root`
+    pub fn extract_to(&self, root: &Arc<dyn Inode>) -> Result<ExtractSummary, CpioError> {
+        let mut summary = ExtractSummary::default();
+        // Files with more than one link, by (devmajor, devminor, ino)
+        let mut links: BTreeMap<(u32, u32, u32), Arc<dyn Inode>> = BTreeMap::new();
+
+        let entries = Entries { archive: self.archive, offset: 0, done: false };
+        for entry in entries {
+            extract_entry(root, &entry?, &mut links, &mut summary)?;
+        }
+        Ok(summary)
+    }
+}
+
+/// Create one entry below `root`
+fn extract_entry(
+    root: &Arc<dyn Inode>,
+    entry: &Entry,
+    links: &mut BTreeMap<(u32, u32, u32), Arc<dyn Inode>>,
+    summary: &mut ExtractSummary,
+) -> Result<(), CpioError> {
+    let path = entry.name.trim_start_matches("./").trim_matches('/');
+    if path.is_empty() || path == "." {
+        // The archive root itself
+        set_attributes(root, entry)?;
+        return Ok(());
+    }
+
+    let (parent_path, name) = path.rsplit_once('/').unwrap_or(("", path));
+    if name == "." || name == ".." || parent_path.split('/').any(|component| component == "..") {
+        return Err(CpioError::BadHeader(entry.offset));
+    }
+    let parent = make_directories(root, parent_path)?;
+
+    match entry.mode & S_IFMT {
+        S_IFDIR => {
+            let directory = match parent.lookup(name) {
+                Ok(existing) if existing.file_type() == FileType::Directory => existing,
+                Ok(_) => {
+                    parent.remove(name)?;
+                    parent.create(name, FileType::Directory)?
+                }
+                Err(VfsError::NotFound) => parent.create(name, FileType::Directory)?,
+                Err(e) => return Err(e.into()),
+            };
+            set_attributes(&directory, entry)?;
+            summary.directories += 1;
+        }
+        S_IFREG => {
+            // newc stores a hard-linked file's data with the last of its names
+            let key = (entry.devmajor, entry.devminor, entry.ino);
+            if let Some(file) = links.get(&key).filter(|_| entry.nlink > 1) {
+                remove_existing(&parent, name)?;
+                parent.link(name, file)?;
+                if !entry.data.is_empty() {
+                    file.truncate(0)?;
+                    file.write(0, entry.data)?;
+                    set_attributes(file, entry)?;
+                }
+                summary.hard_links += 1;
+                return Ok(());
+            }
+
+            remove_existing(&parent, name)?;
+            let file = parent.create(name, FileType::Regular)?;
+            file.write(0, entry.data)?;
+            set_attributes(&file, entry)?;
+            if entry.nlink > 1 {
+                links.insert(key, file);
+            }
+            summary.files += 1;
+        }
+        S_IFLNK => {
+            let target = core::str::from_utf8(entry.data).map_err(|_| CpioError::BadHeader(entry.offset))?;
+            remove_existing(&parent, name)?;
+            let link = parent.symlink(name, target)?;
+            set_attributes(&link, entry)?;
+            summary.symlinks += 1;
+        }
+        _ => summary.skipped += 1,
+    }
+    Ok(())
+}
+
+/// Look up a relative directory path below `root`, creating what is missing
+fn make_directories(root: &Arc<dyn Inode>, path: &str) -> Result<Arc<dyn Inode>, VfsError> {
+    let mut current = root.clone();
+    for component in path.split('/').filter(|c| !c.is_empty() && *c != ".") {
+        current = match current.lookup(component) {
+            Ok(next) => next,
+            Err(VfsError::NotFound) => current.create(component, FileType::Directory)?,
+            Err(e) => return Err(e),
+        };
+        if current.file_type() != FileType::Directory {
+            return Err(VfsError::NotADirectory);
+        }
+    }
+    Ok(current)
+}
+
+/// Remove a non-directory entry so the archive's version can replace it
+fn remove_existing(parent: &Arc<dyn Inode>, name: &str) -> Result<(), VfsError> {
+    match parent.lookup(name) {
+        Ok(existing) if existing.file_type() == FileType::Directory => Err(VfsError::IsADirectory),
+        Ok(_) => parent.remove(name),
+        Err(VfsError::NotFound) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Apply the permissions, owner and modification time from the header
+fn set_attributes(inode: &Arc<dyn Inode>, entry: &Entry) -> Result<(), VfsError> {
+    let mtime = Timespec { tv_sec: entry.mtime as i64, tv_nsec: 0 };
+    inode.setattr(&SetAttr {
+        mode: Some((entry.mode & !S_IFMT) as u16),
+        uid: Some(entry.uid),
+        gid: Some(entry.gid),
+        atime: Some(mtime),
+        mtime: Some(mtime),
+    })
 }
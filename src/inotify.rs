@@ -0,0 +1,364 @@
+//! inotify: file change notification
+//!
+//! An inotify instance is a readable file holding a queue of events. Each
+//! watch ties an inode to an instance with a watch descriptor and an event
+//! mask. Filesystems report changes with `notify`, naming the inode that
+//! changed; a directory's watchers also see events about its entries, with
+//! the entry name attached. Identical consecutive events are merged, and
+//! when the queue is full a single `IN_Q_OVERFLOW` event replaces the rest.
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::{String, ToString};
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::time::Timespec;
+use crate::vfs::{current_time, next_ino, FileType, Inode, Metadata, Readiness, VfsError};
+use crate::wait::{wait_event, WaitQueue, WaitResult};
+
+/// Event bits (watch masks and `InotifyEvent::mask`)
+pub const IN_MODIFY: u32 = 0x0000_0002;
+pub const IN_ATTRIB: u32 = 0x0000_0004;
+pub const IN_MOVED_FROM: u32 = 0x0000_0040;
+pub const IN_MOVED_TO: u32 = 0x0000_0080;
+pub const IN_CREATE: u32 = 0x0000_0100;
+pub const IN_DELETE: u32 = 0x0000_0200;
+pub const IN_DELETE_SELF: u32 = 0x0000_0400;
+pub const IN_Q_OVERFLOW: u32 = 0x0000_4000;
+pub const IN_IGNORED: u32 = 0x0000_8000;
+pub const IN_ISDIR: u32 = 0x4000_0000;
+
+/// Every event a watch can ask for
+pub const IN_ALL_EVENTS: u32 =
+    IN_MODIFY | IN_ATTRIB | IN_MOVED_FROM | IN_MOVED_TO | IN_CREATE | IN_DELETE | IN_DELETE_SELF;
+
+/// inotify_init flags
+pub const IN_NONBLOCK: u64 = crate::vfs::O_NONBLOCK;
+pub const IN_CLOEXEC: u64 = crate::vfs::O_CLOEXEC;
+
+/// inotify_add_watch flags
+pub const IN_DONT_FOLLOW: u32 = 0x0100_0000;
+pub const IN_MASK_ADD: u32 = 0x2000_0000;
+
+/// Maximum number of queued events per instance; kept small for the heap
+const MAX_QUEUED_EVENTS: usize = 64;
+
+/// Size of the fixed part of an event record (struct inotify_event)
+const EVENT_HEADER: usize = 16;
+
+/// A queued event
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Event {
+    wd: i32,
+    mask: u32,
+    /// Pairs IN_MOVED_FROM with IN_MOVED_TO; 0 otherwise
+    cookie: u32,
+    /// Entry name for events on a watched directory's entries
+    name: Option<String>,
+}
+
+impl Event {
+    /// Bytes the event takes in a read, name padded with NULs
+    fn record_len(&self) -> usize {
+        EVENT_HEADER + self.name_len()
+    }
+
+    fn name_len(&self) -> usize {
+        self.name.as_ref().map_or(0, |name| (name.len() + 1).next_multiple_of(EVENT_HEADER))
+    }
+
+    /// Lay the event out as a struct inotify_event followed by the name
+    fn encode(&self, out: &mut [u8]) {
+        out.fill(0);
+        out[0..4].copy_from_slice(&self.wd.to_ne_bytes());
+        out[4..8].copy_from_slice(&self.mask.to_ne_bytes());
+        out[8..12].copy_from_slice(&self.cookie.to_ne_bytes());
+        out[12..16].copy_from_slice(&(self.name_len() as u32).to_ne_bytes());
+        if let Some(name) = &self.name {
+            out[EVENT_HEADER..EVENT_HEADER + name.len()].copy_from_slice(name.as_bytes());
+        }
+    }
+}
+
+struct InotifyState {
+    events: VecDeque<Event>,
+    /// Watched inodes (by address) of this instance, by watch descriptor
+    watches: BTreeMap<i32, usize>,
+    next_wd: i32,
+}
+
+/// An inotify instance
+pub struct Inotify {
+    me: Weak<Inotify>,
+    ino: u64,
+    created: Timespec,
+    state: Mutex<InotifyState>,
+    /// Woken when an event is queued
+    wait: WaitQueue,
+}
+
+/// A watch as seen from the watched inode
+struct Watch {
+    /// Keeps the inode's address from being reused while the watch exists
+    inode: Weak<dyn Inode>,
+    instance: Weak<Inotify>,
+    wd: i32,
+    mask: u32,
+}
+
+lazy_static! {
+    /// Watches by inode address
+    static ref WATCHES: Mutex<BTreeMap<usize, Vec<Watch>>> = Mutex::new(BTreeMap::new());
+}
+
+fn inode_key(inode: &dyn Inode) -> usize {
+    inode as *const dyn Inode as *const () as usize
+}
+
+impl Inotify {
+    pub fn new() -> Arc<Self> {
+        Arc::new_cyclic(|me| Self {
+            me: me.clone(),
+            ino: next_ino(),
+            created: current_time(),
+            state: Mutex::new(InotifyState {
+                events: VecDeque::new(),
+                watches: BTreeMap::new(),
+                next_wd: 1,
+            }),
+            wait: WaitQueue::new(),
+        })
+    }
+
+    /// The state is locked with interrupts off, since `wait_event` checks
+    /// it with interrupts disabled
+    fn with_state<R>(&self, f: impl FnOnce(&mut InotifyState) -> R) -> R {
+        without_interrupts(|| f(&mut self.state.lock()))
+    }
+
+    /// Watch `inode` for the events in `mask`, returning the watch descriptor
+    ///
+    /// Watching an inode again changes the mask of the existing watch (or
+    /// adds to it with `IN_MASK_ADD`) and returns the same descriptor.
+    pub fn add_watch(&self, inode: &Arc<dyn Inode>, mask: u32) -> Result<i32, VfsError> {
+        let events = mask & IN_ALL_EVENTS;
+        if events == 0 {
+            return Err(VfsError::InvalidArgument);
+        }
+
+        let key = inode_key(inode.as_ref());
+        let mut watches = WATCHES.lock();
+        let list = watches.entry(key).or_default();
+
+        if let Some(watch) = list.iter_mut().find(|w| core::ptr::eq(w.instance.as_ptr(), self)) {
+            watch.mask = if mask & IN_MASK_ADD != 0 { watch.mask | events } else { events };
+            return Ok(watch.wd);
+        }
+
+        let wd = self.with_state(|state| {
+            let wd = state.next_wd;
+            state.next_wd += 1;
+            state.watches.insert(wd, key);
+            wd
+        });
+        list.push(Watch {
+            inode: Arc::downgrade(inode),
+            instance: self.me.clone(),
+            wd,
+            mask: events,
+        });
+        Ok(wd)
+    }
+
+    /// Remove a watch; an `IN_IGNORED` event reports it
+    pub fn remove_watch(&self, wd: i32) -> Result<(), VfsError> {
+        let key = self.with_state(|state| state.watches.remove(&wd)).ok_or(VfsError::InvalidArgument)?;
+
+        let mut watches = WATCHES.lock();
+        if let Some(list) = watches.get_mut(&key) {
+            list.retain(|w| !core::ptr::eq(w.instance.as_ptr(), self));
+            if list.is_empty() {
+                watches.remove(&key);
+            }
+        }
+        drop(watches);
+
+        self.queue(Event { wd, mask: IN_IGNORED, cookie: 0, name: None });
+        Ok(())
+    }
+
+    /// Queue an event, merging it into an identical previous one
+    fn queue(&self, event: Event) {
+        let queued = self.with_state(|state| {
+            if state.events.back() == Some(&event) {
+                return false;
+            }
+            if state.events.len() >= MAX_QUEUED_EVENTS {
+                let overflow = Event { wd: -1, mask: IN_Q_OVERFLOW, cookie: 0, name: None };
+                if state.events.back() == Some(&overflow) {
+                    return false;
+                }
+                state.events.pop_back();
+                state.events.push_back(overflow);
+                return true;
+            }
+            state.events.push_back(event);
+            true
+        });
+
+        if queued {
+            self.wait.wake_all();
+        }
+    }
+
+    /// Move whole queued events into `buffer`
+    ///
+    /// Fails with `InvalidArgument` if the next event does not fit.
+    fn take_events(&self, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        self.with_state(|state| {
+            let mut filled = 0;
+            while let Some(event) = state.events.front() {
+                let len = event.record_len();
+                if filled + len > buffer.len() {
+                    break;
+                }
+                event.encode(&mut buffer[filled..filled + len]);
+                filled += len;
+                state.events.pop_front();
+            }
+
+            if filled == 0 && !state.events.is_empty() {
+                return Err(VfsError::InvalidArgument);
+            }
+            Ok(filled)
+        })
+    }
+}
+
+impl Drop for Inotify {
+    fn drop(&mut self) {
+        let me: *const Inotify = self;
+        let mut watches = WATCHES.lock();
+        for list in watches.values_mut() {
+            list.retain(|w| !core::ptr::eq(w.instance.as_ptr(), me));
+        }
+        watches.retain(|_, list| !list.is_empty());
+    }
+}
+
+/// Report a change to `inode`
+///
+/// `name` is the affected entry for events on a directory's entries;
+/// `cookie` pairs the two halves of a rename.
+pub fn notify(inode: &dyn Inode, mask: u32, name: Option<&str>, cookie: u32) {
+    let key = inode_key(inode);
+    let targets: Vec<(Arc<Inotify>, i32)> = {
+        let watches = WATCHES.lock();
+        let Some(list) = watches.get(&key) else {
+            return;
+        };
+        list.iter()
+            .filter(|w| w.mask & mask & IN_ALL_EVENTS != 0 && w.inode.strong_count() > 0)
+            .filter_map(|w| Some((w.instance.upgrade()?, w.wd)))
+            .collect()
+    };
+
+    for (instance, wd) in targets {
+        instance.queue(Event {
+            wd,
+            mask,
+            cookie,
+            name: name.map(|n| n.to_string()),
+        });
+    }
+}
+
+/// Drop every watch on an inode that is gone, reporting `IN_IGNORED`
+pub fn forget(inode: &dyn Inode) {
+    let Some(list) = WATCHES.lock().remove(&inode_key(inode)) else {
+        return;
+    };
+
+    for watch in list {
+        if let Some(instance) = watch.instance.upgrade() {
+            instance.with_state(|state| state.watches.remove(&watch.wd));
+            instance.queue(Event { wd: watch.wd, mask: IN_IGNORED, cookie: 0, name: None });
+        }
+    }
+}
+
+/// Get a fresh cookie to pair IN_MOVED_FROM with IN_MOVED_TO
+pub fn next_cookie() -> u32 {
+    static NEXT_COOKIE: AtomicU32 = AtomicU32::new(1);
+    NEXT_COOKIE.fetch_add(1, Ordering::Relaxed)
+}
+
+/// `IN_ISDIR` if the entry an event is about is a directory
+pub fn isdir_bit(file_type: FileType) -> u32 {
+    if file_type == FileType::Directory { IN_ISDIR } else { 0 }
+}
+
+impl Inode for Inotify {
+    /// Read whole events, blocking until there is at least one
+    fn read(&self, _offset: usize, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        let result = wait_event(&[&self.wait], None, || self.with_state(|state| !state.events.is_empty()));
+        if result == WaitResult::Interrupted {
+            return Err(VfsError::Interrupted);
+        }
+        self.take_events(buffer)
+    }
+
+    fn write(&self, _offset: usize, _buffer: &[u8]) -> Result<usize, VfsError> {
+        Err(VfsError::InvalidOperation)
+    }
+
+    fn file_type(&self) -> FileType {
+        FileType::Device
+    }
+
+    fn size(&self) -> usize {
+        self.with_state(|state| state.events.iter().map(Event::record_len).sum())
+    }
+
+    fn lookup(&self, _name: &str) -> Result<Arc<dyn Inode>, VfsError> {
+        Err(VfsError::NotADirectory)
+    }
+
+    fn create(&self, _name: &str, _file_type: FileType) -> Result<Arc<dyn Inode>, VfsError> {
+        Err(VfsError::NotADirectory)
+    }
+
+    fn list(&self) -> Result<Vec<String>, VfsError> {
+        Err(VfsError::NotADirectory)
+    }
+
+    fn truncate(&self, _size: usize) -> Result<(), VfsError> {
+        Err(VfsError::InvalidOperation)
+    }
+
+    fn remove(&self, _name: &str) -> Result<(), VfsError> {
+        Err(VfsError::NotADirectory)
+    }
+
+    fn metadata(&self) -> Metadata {
+        let mut meta = Metadata::synthesized(self.ino, FileType::Device, 0, self.created);
+        meta.mode = 0o600;
+        meta
+    }
+
+    fn poll(&self) -> Readiness {
+        Readiness {
+            readable: self.with_state(|state| !state.events.is_empty()),
+            writable: false,
+            hangup: false,
+        }
+    }
+
+    fn wait_queue(&self) -> Option<&WaitQueue> {
+        Some(&self.wait)
+    }
+}
@@ -0,0 +1,721 @@
+//! ISO9660 filesystem
+//!
+//! Reads CD and DVD images such as those made by `xorriso -as mkisofs -R
+//! -J`, from an ATAPI drive or any block device whose block size divides
+//! the 2048-byte logical block.
+//!
+//! Names, permissions, owners, timestamps and symbolic links come from the
+//! Rock Ridge extensions when the volume has them. Otherwise names come
+//! from the Joliet tree (UCS-2, up to 64 characters) when there is one,
+//! and failing that from the plain ISO9660 tree, lowercased and without
+//! their `;1` version suffix. Without Rock Ridge everything belongs to
+//! root and is readable by all.
+//!
+//! Directories relocated by Rock Ridge (to stay within the eight levels
+//! ISO9660 allows) are followed back to where they belong, and files
+//! recorded in several extents are read as one. The volume is read-only:
+//! changes fail with `ReadOnly`.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::bcache;
+use crate::block::BlockDevice;
+use crate::rtc::DateTime;
+use crate::time::Timespec;
+use crate::vfs::{
+    DirEntry, FileType, Filesystem, Inode, Metadata, SetAttr, VfsError, MODE_PERMISSION_MASK, S_IFBLK, S_IFCHR,
+    S_IFDIR, S_IFIFO, S_IFLNK, S_IFSOCK,
+};
+
+/// Logical block size; volumes with any other are refused
+const ISO_BLOCK_SIZE: usize = 2048;
+
+/// Volume descriptors start after the 32 KiB system area
+const DESCRIPTOR_START: u64 = 16;
+/// Most volume descriptors read before giving up on a terminator
+const MAX_DESCRIPTORS: u64 = 32;
+const STANDARD_ID: &[u8; 5] = b"CD001";
+
+/// Volume descriptor types
+const VD_PRIMARY: u8 = 1;
+const VD_SUPPLEMENTARY: u8 = 2;
+const VD_TERMINATOR: u8 = 255;
+
+/// Escape sequences of a Joliet supplementary descriptor (UCS-2 levels 1 to 3)
+const JOLIET_ESCAPES: [&[u8; 3]; 3] = [b"%/@", b"%/C", b"%/E"];
+
+/// Offsets in a primary or supplementary volume descriptor
+const VD_VOLUME_ID: usize = 40;
+const VD_ESCAPES: usize = 88;
+const VD_BLOCK_SIZE: usize = 128;
+const VD_ROOT_RECORD: usize = 156;
+
+/// Directory record layout: length, extended attribute length, extent
+/// (both-endian, little-endian half first), data length, date, flags,
+/// then the name and the system use area
+const DR_EXTENT: usize = 2;
+const DR_DATA_LENGTH: usize = 10;
+const DR_DATE: usize = 18;
+const DR_FLAGS: usize = 25;
+const DR_NAME_LENGTH: usize = 32;
+const DR_NAME: usize = 33;
+const DR_MIN_LENGTH: usize = 34;
+
+/// Directory record flags
+const FLAG_DIRECTORY: u8 = 1 << 1;
+/// More extents of the file follow in the next record
+const FLAG_MULTI_EXTENT: u8 = 1 << 7;
+
+/// Most continuation areas followed for one record, against loops
+const MAX_CONTINUATIONS: usize = 16;
+
+/// Rock Ridge NM (alternate name) and SL component flags
+const RR_CONTINUE: u8 = 1 << 0;
+const RR_CURRENT: u8 = 1 << 1;
+const RR_PARENT: u8 = 1 << 2;
+const RR_ROOT: u8 = 1 << 3;
+
+/// Rock Ridge TF flags: which timestamps are recorded, in bit order
+/// (creation, bit 0, is not kept)
+const TF_MODIFY: u8 = 1 << 1;
+const TF_ACCESS: u8 = 1 << 2;
+const TF_ATTRIBUTES: u8 = 1 << 3;
+const TF_FIELDS: u8 = 7;
+/// Timestamps are 17-byte text dates rather than 7-byte record dates
+const TF_LONG_FORM: u8 = 1 << 7;
+
+const S_IFMT: u32 = 0o170000;
+
+/// Permissions without Rock Ridge
+const DEFAULT_DIR_MODE: u16 = 0o555;
+const DEFAULT_FILE_MODE: u16 = 0o444;
+
+fn read_u16(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([bytes[at], bytes[at + 1]])
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}
+
+/// First block of the data recorded by `raw`, past any extended attributes
+fn extent_start(raw: &[u8]) -> Result<u32, VfsError> {
+    read_u32(raw, DR_EXTENT).checked_add(raw[1] as u32).ok_or(VfsError::IoError)
+}
+
+/// Seconds since the epoch of a local time `offset` quarter hours east of UTC
+fn to_timespec(date: DateTime, offset: i8) -> Timespec {
+    if date.month == 0 || date.day == 0 {
+        return Timespec { tv_sec: 0, tv_nsec: 0 };
+    }
+    Timespec {
+        tv_sec: date.to_unix() as i64 - offset as i64 * 15 * 60,
+        tv_nsec: 0,
+    }
+}
+
+/// A 7-byte directory record date: years since 1900, month, day, hour,
+/// minute, second and UTC offset
+fn record_time(raw: &[u8]) -> Timespec {
+    let date = DateTime {
+        year: 1900 + raw[0] as u32,
+        month: raw[1],
+        day: raw[2],
+        hour: raw[3],
+        minute: raw[4],
+        second: raw[5],
+    };
+    to_timespec(date, raw[6] as i8)
+}
+
+/// A 17-byte volume descriptor date: `YYYYMMDDHHMMSScc` in ASCII, then the
+/// UTC offset
+fn long_time(raw: &[u8]) -> Timespec {
+    let number = |range: core::ops::Range<usize>| {
+        raw[range].iter().fold(0u32, |value, &digit| value * 10 + digit.wrapping_sub(b'0') as u32 % 10)
+    };
+    let date = DateTime {
+        year: number(0..4),
+        month: number(4..6) as u8,
+        day: number(6..8) as u8,
+        hour: number(8..10) as u8,
+        minute: number(10..12) as u8,
+        second: number(12..14) as u8,
+    };
+    to_timespec(date, raw[16] as i8)
+}
+
+/// Turn a plain ISO9660 name (`README.TXT;1`) into `readme.txt`
+fn iso_name(raw: &[u8]) -> String {
+    let raw = match raw.iter().position(|&byte| byte == b';') {
+        Some(end) => &raw[..end],
+        None => raw,
+    };
+    let raw = raw.strip_suffix(b".").unwrap_or(raw);
+    raw.iter().map(|&byte| byte.to_ascii_lowercase() as char).collect()
+}
+
+/// Decode a Joliet name: big-endian UCS-2, with the version suffix dropped
+fn joliet_name(raw: &[u8]) -> String {
+    let units = raw.chunks_exact(2).map(|pair| u16::from_be_bytes([pair[0], pair[1]]));
+    let name: String = char::decode_utf16(units).map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER)).collect();
+    match name.rfind(';') {
+        Some(end) => String::from(&name[..end]),
+        None => name,
+    }
+}
+
+fn file_type_of(mode: u32) -> FileType {
+    match mode & S_IFMT {
+        S_IFDIR => FileType::Directory,
+        S_IFLNK => FileType::Symlink,
+        S_IFCHR => FileType::Device,
+        S_IFBLK => FileType::BlockDevice,
+        S_IFIFO => FileType::Fifo,
+        S_IFSOCK => FileType::Socket,
+        _ => FileType::Regular,
+    }
+}
+
+/// The `.` and `..` records, named by a single 0 or 1 byte
+fn is_dot_record(raw: &[u8]) -> bool {
+    raw[DR_NAME_LENGTH] == 1 && raw[DR_NAME] <= 1
+}
+
+/// A file or directory as described by its directory record
+#[derive(Clone)]
+struct Node {
+    /// Byte position of the record on the volume (for a directory, of the
+    /// `.` record at the start of its extent)
+    ino: u64,
+    name: String,
+    file_type: FileType,
+    /// Logical block and byte length of each extent, in file order
+    extents: Vec<(u32, u32)>,
+    size: u64,
+    mode: u16,
+    uid: u32,
+    gid: u32,
+    nlink: u32,
+    atime: Timespec,
+    mtime: Timespec,
+    ctime: Timespec,
+    /// Symbolic link target
+    target: String,
+    /// A Rock Ridge relocated directory, listed where it belongs instead
+    relocated: bool,
+    /// Block of the directory a Rock Ridge placeholder stands for
+    child_link: Option<u32>,
+}
+
+impl Node {
+    /// Where the data of a directory lies on the volume, in bytes
+    fn span(&self) -> (u64, u64) {
+        let (block, length) = self.extents.first().copied().unwrap_or((0, 0));
+        let start = block as u64 * ISO_BLOCK_SIZE as u64;
+        (start, start + length as u64)
+    }
+}
+
+/// Which name and attribute records a volume is read through
+struct Volume {
+    device: Arc<dyn BlockDevice>,
+    /// Names and attributes come from Rock Ridge entries
+    rock_ridge: bool,
+    /// Bytes to skip at the start of each system use area (from the SP entry)
+    susp_skip: usize,
+    /// Names are Joliet UCS-2
+    joliet: bool,
+}
+
+impl Volume {
+    /// Read logical block `block`
+    fn block(&self, block: u64) -> Result<bcache::Buffer, VfsError> {
+        bcache::bread(&self.device, block, ISO_BLOCK_SIZE)
+    }
+
+    /// Find the next directory record at or after byte `position`, before
+    /// `end`; returns where the record is, the record, and where the one
+    /// after it may start
+    ///
+    /// Records do not cross block boundaries: a zero length byte means the
+    /// rest of the block is padding.
+    fn record_at(&self, mut position: u64, end: u64) -> Result<Option<(u64, Vec<u8>, u64)>, VfsError> {
+        while position < end {
+            let block = position / ISO_BLOCK_SIZE as u64;
+            let offset = (position % ISO_BLOCK_SIZE as u64) as usize;
+            let buffer = self.block(block)?;
+            let data = buffer.data();
+            let length = data[offset] as usize;
+            if length == 0 {
+                position = (block + 1) * ISO_BLOCK_SIZE as u64;
+                continue;
+            }
+            if length < DR_MIN_LENGTH
+                || offset + length > ISO_BLOCK_SIZE
+                || DR_NAME + data[offset + DR_NAME_LENGTH] as usize > length
+            {
+                crate::serial_println!("iso9660: bad directory record at byte {}", position);
+                return Err(VfsError::IoError);
+            }
+            let raw = data[offset..offset + length].to_vec();
+            return Ok(Some((position, raw, position + length as u64)));
+        }
+        Ok(None)
+    }
+
+    /// Describe the file or directory recorded by `raw`, found at byte `position`
+    fn node(&self, raw: &[u8], position: u64) -> Result<Node, VfsError> {
+        let flags = raw[DR_FLAGS];
+        let block = extent_start(raw)?;
+        let length = read_u32(raw, DR_DATA_LENGTH);
+        let directory = flags & FLAG_DIRECTORY != 0;
+        let name_bytes = &raw[DR_NAME..DR_NAME + raw[DR_NAME_LENGTH] as usize];
+        let time = record_time(&raw[DR_DATE..DR_DATE + 7]);
+
+        let mut node = Node {
+            ino: if directory { block as u64 * ISO_BLOCK_SIZE as u64 } else { position },
+            name: if self.joliet { joliet_name(name_bytes) } else { iso_name(name_bytes) },
+            file_type: if directory { FileType::Directory } else { FileType::Regular },
+            extents: alloc::vec![(block, length)],
+            size: length as u64,
+            mode: if directory { DEFAULT_DIR_MODE } else { DEFAULT_FILE_MODE },
+            uid: 0,
+            gid: 0,
+            nlink: if directory { 2 } else { 1 },
+            atime: time,
+            mtime: time,
+            ctime: time,
+            target: String::new(),
+            relocated: false,
+            child_link: None,
+        };
+        if self.rock_ridge {
+            self.read_rock_ridge(raw, &mut node)?;
+        }
+
+        // A placeholder for a relocated directory: take the directory's own
+        // attributes from its `.` record. A CL on a `.` record is ignored, so
+        // a crafted loop of them cannot recurse without end
+        if let Some(child) = node.child_link.filter(|_| !is_dot_record(raw)) {
+            let start = child as u64 * ISO_BLOCK_SIZE as u64;
+            let (at, dot, _) = self
+                .record_at(start, start + ISO_BLOCK_SIZE as u64)?
+                .filter(|(at, dot, _)| *at == start && is_dot_record(dot))
+                .ok_or(VfsError::IoError)?;
+            let directory = self.node(&dot, at)?;
+            node = Node {
+                name: node.name,
+                ..directory
+            };
+        }
+        Ok(node)
+    }
+
+    /// Apply the Rock Ridge entries in the system use area of `raw` (and
+    /// its continuation areas) to `node`
+    fn read_rock_ridge(&self, raw: &[u8], node: &mut Node) -> Result<(), VfsError> {
+        let name_length = raw[DR_NAME_LENGTH] as usize;
+        // A padding byte keeps the system use area at an even offset
+        let start = DR_NAME + name_length + (name_length + 1) % 2 + self.susp_skip;
+        let mut area = raw.get(start..).unwrap_or(&[]).to_vec();
+
+        let mut name = Vec::new();
+        let mut has_name = false;
+        let mut target = String::new();
+        let mut component_done = true;
+        for _ in 0..MAX_CONTINUATIONS {
+            let mut continuation = None;
+            let mut at = 0;
+            while at + 4 <= area.len() {
+                let length = area[at + 2] as usize;
+                if length < 4 || at + length > area.len() {
+                    break;
+                }
+                let entry = &area[at..at + length];
+                at += length;
+                match (&entry[..2], length) {
+                    (b"CE", 28..) => {
+                        continuation = Some((read_u32(entry, 4), read_u32(entry, 12), read_u32(entry, 20)));
+                    }
+                    (b"PX", 36..) => {
+                        let mode = read_u32(entry, 4);
+                        node.file_type = file_type_of(mode);
+                        node.mode = mode as u16 & MODE_PERMISSION_MASK;
+                        node.nlink = read_u32(entry, 12);
+                        node.uid = read_u32(entry, 20);
+                        node.gid = read_u32(entry, 28);
+                    }
+                    (b"NM", 5..) => {
+                        if entry[4] & (RR_CURRENT | RR_PARENT) == 0 {
+                            name.extend_from_slice(&entry[5..]);
+                            has_name = true;
+                        }
+                    }
+                    (b"SL", 5..) => {
+                        let mut component = 5;
+                        while component + 2 <= entry.len() {
+                            let flags = entry[component];
+                            let size = entry[component + 1] as usize;
+                            let Some(content) = entry.get(component + 2..component + 2 + size) else {
+                                break;
+                            };
+                            component += 2 + size;
+                            if flags & RR_ROOT != 0 {
+                                target.push('/');
+                                component_done = false;
+                                continue;
+                            }
+                            if component_done && !target.is_empty() && !target.ends_with('/') {
+                                target.push('/');
+                            }
+                            if flags & RR_CURRENT != 0 {
+                                target.push('.');
+                            } else if flags & RR_PARENT != 0 {
+                                target.push_str("..");
+                            } else {
+                                target.push_str(&String::from_utf8_lossy(content));
+                            }
+                            component_done = flags & RR_CONTINUE == 0;
+                        }
+                    }
+                    (b"TF", 5..) => {
+                        let flags = entry[4];
+                        let size = if flags & TF_LONG_FORM != 0 { 17 } else { 7 };
+                        let mut field = 5;
+                        for bit in 0..TF_FIELDS {
+                            if flags & (1 << bit) == 0 {
+                                continue;
+                            }
+                            let Some(raw) = entry.get(field..field + size) else {
+                                break;
+                            };
+                            field += size;
+                            let time = if size == 17 { long_time(raw) } else { record_time(raw) };
+                            match 1 << bit {
+                                TF_MODIFY => node.mtime = time,
+                                TF_ACCESS => node.atime = time,
+                                TF_ATTRIBUTES => node.ctime = time,
+                                _ => {}
+                            }
+                        }
+                    }
+                    (b"CL", 12..) => node.child_link = Some(read_u32(entry, 4)),
+                    (b"RE", _) => node.relocated = true,
+                    (b"ST", _) => break,
+                    _ => {}
+                }
+            }
+
+            let Some((block, offset, length)) = continuation else {
+                break;
+            };
+            let offset = offset as usize;
+            let length = length as usize;
+            if offset + length > ISO_BLOCK_SIZE {
+                return Err(VfsError::IoError);
+            }
+            area = self.block(block as u64)?.data()[offset..offset + length].to_vec();
+        }
+
+        if has_name {
+            node.name = String::from_utf8_lossy(&name).into_owned();
+        }
+        if node.file_type == FileType::Symlink {
+            node.target = target;
+        }
+        Ok(())
+    }
+
+    /// The entry of `directory` at or after byte `cursor` of its data, and
+    /// the cursor of the one after it
+    fn entry(&self, directory: &Node, cursor: u64) -> Result<Option<(Node, u64)>, VfsError> {
+        let (start, end) = directory.span();
+        let mut position = start + cursor;
+        while let Some((at, raw, next)) = self.record_at(position, end)? {
+            position = next;
+            if is_dot_record(&raw) {
+                continue;
+            }
+            let mut node = self.node(&raw, at)?;
+
+            // The other extents of a large file follow in records of their own
+            let mut flags = raw[DR_FLAGS];
+            while flags & FLAG_MULTI_EXTENT != 0 {
+                let (_, more, next) = self.record_at(position, end)?.ok_or(VfsError::IoError)?;
+                position = next;
+                let length = read_u32(&more, DR_DATA_LENGTH);
+                node.extents.push((extent_start(&more)?, length));
+                node.size += length as u64;
+                flags = more[DR_FLAGS];
+            }
+
+            if node.relocated {
+                continue;
+            }
+            return Ok(Some((node, position - start)));
+        }
+        Ok(None)
+    }
+}
+
+/// A file or directory on an ISO9660 volume
+pub struct IsoInode {
+    volume: Arc<Volume>,
+    node: Node,
+}
+
+impl IsoInode {
+    fn check_directory(&self) -> Result<(), VfsError> {
+        if self.node.file_type == FileType::Directory {
+            Ok(())
+        } else {
+            Err(VfsError::NotADirectory)
+        }
+    }
+}
+
+impl Inode for IsoInode {
+    fn read(&self, offset: usize, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        match self.node.file_type {
+            FileType::Regular => {}
+            FileType::Directory => return Err(VfsError::IsADirectory),
+            _ => return Err(VfsError::InvalidOperation),
+        }
+        let offset = offset as u64;
+        if offset >= self.node.size {
+            return Ok(0);
+        }
+        let count = buffer.len().min((self.node.size - offset) as usize);
+
+        let mut done = 0;
+        let mut extent_start = 0u64;
+        for &(block, length) in &self.node.extents {
+            let extent_end = extent_start + length as u64;
+            while done < count && offset + (done as u64) < extent_end {
+                let within = offset + done as u64 - extent_start;
+                let at = (within % ISO_BLOCK_SIZE as u64) as usize;
+                let chunk = (ISO_BLOCK_SIZE - at).min(count - done).min((extent_end - offset - done as u64) as usize);
+                let data = self.volume.block(block as u64 + within / ISO_BLOCK_SIZE as u64)?;
+                buffer[done..done + chunk].copy_from_slice(&data.data()[at..at + chunk]);
+                done += chunk;
+            }
+            extent_start = extent_end;
+        }
+        Ok(done)
+    }
+
+    fn write(&self, _offset: usize, _buffer: &[u8]) -> Result<usize, VfsError> {
+        Err(VfsError::ReadOnly)
+    }
+
+    fn file_type(&self) -> FileType {
+        self.node.file_type
+    }
+
+    fn size(&self) -> usize {
+        self.node.size as usize
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, VfsError> {
+        self.check_directory()?;
+        let mut cursor = 0;
+        while let Some((node, next)) = self.volume.entry(&self.node, cursor)? {
+            if node.name == name {
+                return Ok(Arc::new(IsoInode {
+                    volume: self.volume.clone(),
+                    node,
+                }));
+            }
+            cursor = next;
+        }
+        Err(VfsError::NotFound)
+    }
+
+    fn create(&self, _name: &str, _file_type: FileType) -> Result<Arc<dyn Inode>, VfsError> {
+        Err(VfsError::ReadOnly)
+    }
+
+    fn list(&self) -> Result<Vec<String>, VfsError> {
+        let mut names = Vec::new();
+        let mut cursor = 0;
+        while let Some((entry, next)) = self.readdir(cursor)? {
+            names.push(entry.name);
+            cursor = next;
+        }
+        Ok(names)
+    }
+
+    /// The cursor is the byte position in the directory of the next record
+    /// to look at
+    fn readdir(&self, cursor: u64) -> Result<Option<(DirEntry, u64)>, VfsError> {
+        self.check_directory()?;
+        let Some((node, next)) = self.volume.entry(&self.node, cursor)? else {
+            return Ok(None);
+        };
+        let entry = DirEntry {
+            name: node.name,
+            ino: node.ino,
+            file_type: node.file_type,
+        };
+        Ok(Some((entry, next)))
+    }
+
+    fn truncate(&self, _size: usize) -> Result<(), VfsError> {
+        Err(VfsError::ReadOnly)
+    }
+
+    fn remove(&self, _name: &str) -> Result<(), VfsError> {
+        Err(VfsError::ReadOnly)
+    }
+
+    fn symlink(&self, _name: &str, _target: &str) -> Result<Arc<dyn Inode>, VfsError> {
+        Err(VfsError::ReadOnly)
+    }
+
+    fn readlink(&self) -> Result<String, VfsError> {
+        if self.node.file_type != FileType::Symlink {
+            return Err(VfsError::InvalidArgument);
+        }
+        Ok(self.node.target.clone())
+    }
+
+    fn link(&self, _name: &str, _inode: &Arc<dyn Inode>) -> Result<(), VfsError> {
+        Err(VfsError::ReadOnly)
+    }
+
+    fn rename(&self, _old_name: &str, _new_dir: &Arc<dyn Inode>, _new_name: &str) -> Result<(), VfsError> {
+        Err(VfsError::ReadOnly)
+    }
+
+    fn metadata(&self) -> Metadata {
+        let node = &self.node;
+        Metadata {
+            ino: node.ino,
+            file_type: node.file_type,
+            mode: node.mode,
+            uid: node.uid,
+            gid: node.gid,
+            nlink: node.nlink,
+            size: node.size,
+            blocks: node.extents.iter().map(|&(_, length)| (length as u64).div_ceil(ISO_BLOCK_SIZE as u64) * 4).sum(),
+            atime: node.atime,
+            mtime: node.mtime,
+            ctime: node.ctime,
+        }
+    }
+
+    fn setattr(&self, _attr: &SetAttr) -> Result<(), VfsError> {
+        Err(VfsError::ReadOnly)
+    }
+}
+
+/// A mounted ISO9660 volume
+pub struct IsoFs {
+    root: Arc<IsoInode>,
+    label: String,
+}
+
+impl IsoFs {
+    /// Mount the ISO9660 volume on `device`
+    pub fn mount(device: Arc<dyn BlockDevice>) -> Result<Self, VfsError> {
+        if !ISO_BLOCK_SIZE.is_multiple_of(device.block_size()) {
+            return Err(VfsError::InvalidArgument);
+        }
+
+        // The primary descriptor and any Joliet one, up to the terminator
+        let mut primary = None;
+        let mut joliet = None;
+        for block in DESCRIPTOR_START..DESCRIPTOR_START + MAX_DESCRIPTORS {
+            if (block + 1) * ISO_BLOCK_SIZE as u64 > device.size() {
+                return Err(VfsError::InvalidArgument);
+            }
+            let buffer = bcache::bread(&device, block, ISO_BLOCK_SIZE)?;
+            let data = buffer.data();
+            if &data[1..6] != STANDARD_ID {
+                return Err(VfsError::InvalidArgument);
+            }
+            let descriptor = || {
+                let root = data[VD_ROOT_RECORD..VD_ROOT_RECORD + DR_MIN_LENGTH].to_vec();
+                (root, read_u16(&data, VD_BLOCK_SIZE), data[VD_VOLUME_ID..VD_VOLUME_ID + 32].to_vec())
+            };
+            match data[0] {
+                VD_PRIMARY if primary.is_none() => primary = Some(descriptor()),
+                VD_SUPPLEMENTARY if JOLIET_ESCAPES.iter().any(|escape| data[VD_ESCAPES..VD_ESCAPES + 3] == escape[..]) => {
+                    joliet = Some(descriptor())
+                }
+                VD_TERMINATOR => break,
+                _ => {}
+            }
+        }
+        let (root_record, block_size, label) = primary.ok_or(VfsError::InvalidArgument)?;
+        if block_size as usize != ISO_BLOCK_SIZE {
+            crate::serial_println!("iso9660: unsupported logical block size {}", block_size);
+            return Err(VfsError::NotImplemented);
+        }
+
+        // Rock Ridge starts with an SP entry in the root's `.` record
+        let mut volume = Volume {
+            device,
+            rock_ridge: false,
+            susp_skip: 0,
+            joliet: false,
+        };
+        let root_block = read_u32(&root_record, DR_EXTENT) as u64;
+        let start = root_block * ISO_BLOCK_SIZE as u64;
+        if let Some((_, dot, _)) = volume.record_at(start, start + ISO_BLOCK_SIZE as u64)? {
+            let area = &dot[DR_NAME + 1..];
+            if area.len() >= 7 && &area[..2] == b"SP" && area[4..6] == [0xBE, 0xEF] {
+                volume.rock_ridge = true;
+                volume.susp_skip = area[6] as usize;
+            }
+        }
+
+        let (root_record, label) = match joliet {
+            Some((record, _, joliet_label)) if !volume.rock_ridge => {
+                volume.joliet = true;
+                (record, joliet_name(&joliet_label))
+            }
+            _ => (root_record, String::from_utf8_lossy(&label).into_owned()),
+        };
+
+        // The root's attributes are those of its `.` record
+        let volume = Arc::new(volume);
+        let start = read_u32(&root_record, DR_EXTENT) as u64 * ISO_BLOCK_SIZE as u64;
+        let (at, dot, _) = volume
+            .record_at(start, start + ISO_BLOCK_SIZE as u64)?
+            .filter(|(at, dot, _)| *at == start && is_dot_record(dot))
+            .ok_or(VfsError::InvalidArgument)?;
+        let mut node = volume.node(&dot, at)?;
+        if node.file_type != FileType::Directory {
+            return Err(VfsError::InvalidArgument);
+        }
+        node.name = String::new();
+        Ok(Self {
+            root: Arc::new(IsoInode { volume, node }),
+            label: String::from(label.trim_end()),
+        })
+    }
+
+    /// Volume identifier
+    #[allow(dead_code)]
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+}
+
+impl Filesystem for IsoFs {
+    fn name(&self) -> &'static str {
+        "iso9660"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+
+    fn device(&self) -> Option<Arc<dyn BlockDevice>> {
+        Some(self.root.volume.device.clone())
+    }
+}
@@ -1,6 +1,9 @@
 use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
 use x86_64::instructions::port::Port;
 
+use crate::wait::WaitQueue;
+
 /// PS/2 keyboard I/O ports
 const DATA_PORT: u16 = 0x60;
 const STATUS_PORT: u16 = 0x64;
@@ -291,20 +294,103 @@ pub fn init_keyboard() {
     crate::serial_println!("PS/2 keyboard driver initialized");
 }
 
+/// Size of the console input buffer
+const INPUT_BUFFER_SIZE: usize = 256;
+
+/// Characters typed but not yet read from the console
+struct InputBuffer {
+    data: [u8; INPUT_BUFFER_SIZE],
+    head: usize,
+    len: usize,
+}
+
+impl InputBuffer {
+    const fn new() -> Self {
+        Self {
+            data: [0; INPUT_BUFFER_SIZE],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    /// Append a byte, dropping it if the buffer is full
+    fn push(&mut self, byte: u8) {
+        if self.len < INPUT_BUFFER_SIZE {
+            self.data[(self.head + self.len) % INPUT_BUFFER_SIZE] = byte;
+            self.len += 1;
+        }
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let byte = self.data[self.head];
+        self.head = (self.head + 1) % INPUT_BUFFER_SIZE;
+        self.len -= 1;
+        Some(byte)
+    }
+}
+
+static INPUT_BUFFER: Mutex<InputBuffer> = Mutex::new(InputBuffer::new());
+
+/// Woken whenever a character is added to the console input buffer
+pub static INPUT_WAIT: WaitQueue = WaitQueue::new();
+
+/// Check whether console input is waiting to be read
+pub fn input_available() -> bool {
+    without_interrupts(|| INPUT_BUFFER.lock().len > 0)
+}
+
+/// Move buffered console input into `buffer` without blocking
+///
+/// Returns the number of bytes copied (0 if nothing was typed).
+pub fn read_input(buffer: &mut [u8]) -> usize {
+    without_interrupts(|| {
+        let mut input = INPUT_BUFFER.lock();
+        let mut count = 0;
+        while count < buffer.len() {
+            match input.pop() {
+                Some(byte) => {
+                    buffer[count] = byte;
+                    count += 1;
+                }
+                None => break,
+            }
+        }
+        count
+    })
+}
+
 /// Keyboard interrupt handler (called from IDT)
 pub fn keyboard_interrupt_handler() {
     let mut keyboard = KEYBOARD.lock();
     if let Some(event) = keyboard.try_read_event() {
-        match event {
+        let input = match event {
             KeyEvent::Pressed(KeyCode::Char(c)) => {
                 crate::serial_println!("Key pressed: '{}'", c as char);
+                Some(c)
             }
             KeyEvent::Pressed(keycode) => {
                 crate::serial_println!("Key pressed: {:?}", keycode);
+                match keycode {
+                    KeyCode::Enter => Some(b'\n'),
+                    KeyCode::Tab => Some(b'\t'),
+                    KeyCode::Backspace => Some(0x08),
+                    _ => None,
+                }
             }
             KeyEvent::Released(_) => {
                 // Typically we don't need to log key releases
+                None
             }
+        };
+
+        // Hand typed characters to readers of the console
+        if let Some(byte) = input {
+            INPUT_BUFFER.lock().push(byte);
+            drop(keyboard);
+            INPUT_WAIT.wake_all();
         }
     }
 }
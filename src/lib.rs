@@ -4,6 +4,7 @@
 
 extern crate alloc;
 
+use bootloader_api::config::{BootloaderConfig, Mapping};
 use bootloader_api::{entry_point, BootInfo};
 use core::panic::PanicInfo;
 
@@ -12,6 +13,8 @@ mod serial;
 mod gdt;
 mod idt;
 mod pit;
+mod rtc;  // CMOS real-time clock
+mod time; // Clocks, sleeps and interval timers
 mod physical_memory;
 mod paging;
 mod heap;
@@ -19,21 +22,47 @@ mod keyboard;
 mod ps2_mouse; // Added mouse module
 mod pci;  // New PCI enumeration module
 mod ata;  // ATA/IDE disk driver
+mod block; // Block device layer
+mod partition; // MBR and GPT partition tables
+mod bcache;   // Buffer cache for block devices
 mod process; // Process management and scheduling
+mod vma;     // Virtual memory areas (brk/mmap)
 mod syscall; // System call interface
+mod strace;  // Syscall tracing
+mod seccomp; // Syscall filtering sandbox
+mod wait;    // Wait queues for blocking I/O
+mod pipe;    // Anonymous pipes
+mod socket;  // Local stream sockets
+mod poll;    // poll/select readiness waits
+mod epoll;   // epoll readiness notification
 mod vfs;      // Virtual filesystem layer
+mod mount;    // Mount table and path resolution
+mod dcache;   // Dentry cache
+mod pagecache; // Page cache for file data
+mod filelock; // Advisory file locks (flock, fcntl)
+mod inotify;  // File change notification
 mod tmpfs;    // In-memory filesystem
+mod fat32;    // FAT32 filesystem
+mod ext2;     // ext2 filesystem
+mod rfs;      // native journaled filesystem
+mod iso9660;  // ISO9660 CD-ROM filesystem
+mod overlayfs; // Overlay of a writable tree on a read-only one
 mod devfs;    // Device filesystem
 mod procfs;   // Process filesystem
 mod initramfs; // Initial RAM filesystem support
 mod elf;      // ELF binary loader
 mod init;     // Init process (PID 1)
 mod shell;    // Shell infrastructure
+mod selftest; // Destructive self-tests (--features selftest)
 
-use x86_64::structures::paging::{PhysFrame, Size4KiB, Page};
-use x86_64::{VirtAddr, PhysAddr};
+/// Ask the bootloader to map all physical memory, so page tables can be edited
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::Dynamic);
+    config
+};
 
-entry_point!(kernel_main);
+entry_point!(kernel_main, config = &BOOTLOADER_CONFIG);
 
 fn kernel_main(_boot_info: &'static mut BootInfo) -> ! {
     // Initialize GDT first (required for IDT)
@@ -60,6 +89,9 @@ fn kernel_main(_boot_info: &'static mut BootInfo) -> ! {
 
     // Initialize the PIT timer interrupt at 100Hz
     pit::init_pit();
+
+    // Read the wall clock so CLOCK_REALTIME is available
+    time::init();
     
     // Create a physical memory allocator from boot info
     let mut frame_allocator = unsafe {
@@ -72,20 +104,17 @@ fn kernel_main(_boot_info: &'static mut BootInfo) -> ! {
     println!("Physical memory manager initialized");
     
     // Set up virtual memory using 4-level page tables
-    let mut pager_manager = paging::init_paging(0x1_0000);
-    
+    let physical_memory_offset = _boot_info
+        .physical_memory_offset
+        .into_option()
+        .expect("bootloader did not map physical memory");
+    paging::init_paging(physical_memory_offset, frame_allocator);
+
     // Test a simple mapping to ensure it works correctly
-    let test_page = Page::<Size4KiB>::containing_address(
-        VirtAddr::new(0x2_0000)
-    );
-
-    if pager_manager.map_to(test_page,
-                           PhysFrame::containing_address(PhysAddr::new(0x3_0000)),
-                           paging::TABLE_FLAGS).is_ok() {
-        println!("Virtual memory: 4-level page tables initialized successfully");
-    } else {
-        panic!("Failed to initialize virtual memory mapping!");
+    if let Err(e) = paging::self_test() {
+        panic!("Failed to initialize virtual memory mapping: {:?}", e);
     }
+    println!("Virtual memory: 4-level page tables initialized successfully");
 
     // Initialize the kernel heap allocator
     unsafe {
@@ -105,6 +134,10 @@ fn kernel_main(_boot_info: &'static mut BootInfo) -> ! {
     process::init_process_manager();
     println!("Process manager initialized (round-robin scheduler)");
 
+    // Start the page cache writeback thread
+    let flusher_pid = pagecache::start_flusher();
+    println!("Page cache flusher started (PID {})", flusher_pid);
+
     // Enable hardware interrupts
     idt::enable_interrupts();
     println!("Hardware interrupts enabled");
@@ -167,45 +200,59 @@ fn kernel_main(_boot_info: &'static mut BootInfo) -> ! {
 
     // Initialize ATA/IDE disk driver
     let ata_disks = ata::init_ata();
-    
-    if !ata_disks.is_empty() {
-        for (i, disk) in ata_disks.iter().enumerate() {
-            match disk.init() {
-                Ok(info) => println!("ATA Disk {}: Model '{}', Serial '{}' ({})", 
-                    i + 1,
-                    info.model_name().unwrap_or("Unknown"),
-                    info.serial_number_str().unwrap_or("Unknown"),
-                    if (info.device_type & 0x4) == 0 { "Fixed" } else { "Removable" }),
-                Err(_) => println!("ATA Disk {}: Failed to initialize", i + 1),
-            }
-        }
-
-        // Test reading a sector from the first disk
-        let test_lba = 256;
-        
-        if !ata_disks.is_empty() {
-            match ata_disks[0].read_sector(test_lba) {
-                Ok(data) => println!("Successfully read LBA {} (first 16 bytes: {:?})", 
-                    test_lba, &data[..16]),
-                Err(e) => println!("Failed to read from ATA disk at LBA {}: {}", test_lba, e),
-            }
-        } else {
-            println!("No ATA disks available for testing");
-        }
 
-    } else {
+    if ata_disks.is_empty() {
         println!("No ATA/IDE devices found.");
     }
 
     // Mark the task as complete
     println!("ATA/IDE driver (PIO mode) initialized successfully");
 
+    // Register block devices: the disks found above and a RAM disk
+    use block::BlockDevice;
+    println!("Registering block devices...");
+    for disk in ata_disks {
+        let name = disk.name();
+        match block::register(name, Arc::new(disk)) {
+            Ok(queue) => println!("  /dev/{}: {} KiB", name, queue.size() / 1024),
+            Err(e) => println!("  Failed to register {}: {}", name, e),
+        }
+    }
+    match block::init_ramdisk() {
+        Ok(ram0) => println!("  /dev/ram0: {} KiB", ram0.size() / 1024),
+        Err(e) => println!("  Failed to register ram0: {}", e),
+    }
+
+    // Register the partitions of every disk
+    println!("Scanning partition tables...");
+    partition::scan_all();
+
     // Initialize VFS and tmpfs
     println!("Initializing Virtual Filesystem (VFS)...");
 
-    // Use the global TMPFS instance
-    let tmpfs = tmpfs::TMPFS.lock();
-    println!("TmpFS mounted as root filesystem");
+    // Use the global TMPFS instance as the root filesystem
+    let tmpfs = tmpfs::TMPFS.clone();
+    match mount::mount("/", tmpfs.clone()) {
+        Ok(()) => println!("TmpFS mounted as root filesystem"),
+        Err(e) => println!("Failed to mount tmpfs at /: {}", e),
+    }
+
+    // Unpack the initramfs the bootloader loaded, if any, into the root
+    match initramfs::InitRamFs::from_boot_info(_boot_info) {
+        Some(archive) => match archive.extract() {
+            Ok(summary) => println!(
+                "initramfs: unpacked {} bytes ({} dirs, {} files, {} symlinks, {} links, {} skipped)",
+                archive.size(),
+                summary.directories,
+                summary.files,
+                summary.symlinks,
+                summary.hard_links,
+                summary.skipped
+            ),
+            Err(e) => println!("initramfs: {}", e),
+        },
+        None => println!("initramfs: no ramdisk provided"),
+    }
 
     // Test VFS operations
     use vfs::{OpenFlags, FileDescriptor, FileDescriptorTable};
@@ -304,8 +351,12 @@ fn kernel_main(_boot_info: &'static mut BootInfo) -> ! {
 
     // Initialize devfs
     println!("Initializing Device Filesystem (devfs)...");
-    let devfs = devfs::DevFs::new();
-    println!("devfs mounted with device nodes:");
+    let devfs = Arc::new(devfs::DevFs::new());
+    let _ = tmpfs.create_directory("/dev");
+    match mount::mount("/dev", devfs.clone()) {
+        Ok(()) => println!("devfs mounted at /dev with device nodes:"),
+        Err(e) => println!("Failed to mount devfs at /dev: {}", e),
+    }
 
     // List device nodes
     if let Ok(devices) = devfs.list() {
@@ -349,8 +400,12 @@ fn kernel_main(_boot_info: &'static mut BootInfo) -> ! {
 
     // Initialize procfs
     println!("Initializing Process Filesystem (procfs)...");
-    let procfs = procfs::ProcFs::new();
-    println!("procfs mounted with files:");
+    let procfs = Arc::new(procfs::ProcFs::new());
+    let _ = tmpfs.create_directory("/proc");
+    match mount::mount("/proc", procfs.clone()) {
+        Ok(()) => println!("procfs mounted at /proc with files:"),
+        Err(e) => println!("Failed to mount procfs at /proc: {}", e),
+    }
 
     // List proc files
     if let Ok(files) = procfs.list() {
@@ -380,8 +435,37 @@ fn kernel_main(_boot_info: &'static mut BootInfo) -> ! {
 
     println!("procfs initialized successfully");
 
-    // Release the tmpfs lock
-    drop(tmpfs);
+    // Test pipes and readiness polling
+    println!("Testing pipes and poll...");
+    {
+        use vfs::Inode;
+
+        let (reader, writer) = pipe::pipe();
+        let entries: [(Option<Arc<dyn Inode>>, i16); 1] = [(Some(reader.clone()), poll::POLLIN)];
+
+        let empty = poll::poll_inodes(&entries, Some(time::monotonic_ns()));
+        println!("  empty pipe revents: {:?}", empty);
+
+        let _ = writer.write(0, b"ping");
+        let ready = poll::poll_inodes(&entries, None);
+        println!("  after write revents: {:?}", ready);
+
+        let mut buf = [0u8; 8];
+        if let Ok(n) = reader.read(0, &mut buf) {
+            println!("  read back: {:?}", core::str::from_utf8(&buf[..n]));
+        }
+
+        drop(writer);
+        println!("  after close: hangup = {}", reader.poll().hangup);
+    }
+
+    // Check path resolution across mount points
+    for path in ["/proc/meminfo", "/dev/../dev/null", "/proc/../test.txt"] {
+        match mount::resolve_path(path) {
+            Ok(inode) => println!("  resolved {} ({:?})", path, inode.file_type()),
+            Err(e) => println!("  failed to resolve {}: {}", path, e),
+        }
+    }
 
     // Test shell commands
     println!("\n=== Testing Shell Commands ===");
@@ -410,6 +494,156 @@ fn kernel_main(_boot_info: &'static mut BootInfo) -> ! {
     let _ = shell.execute_line("cd /");
     let _ = shell.execute_line("ls");
 
+    println!("\n8. Testing mounted filesystems ('mount', 'cat /proc/meminfo'):");
+    let _ = shell.execute_line("mount");
+    let _ = shell.execute_line("cat /proc/meminfo");
+    let _ = shell.execute_line("echo hi > /dev/null");
+    let _ = shell.execute_line("cd /proc/../dev");
+    let _ = shell.execute_line("pwd");
+    let _ = shell.execute_line("cd /");
+
+    println!("\n9. Testing file attributes ('chmod', 'chown', 'ls -l', 'stat'):");
+    let _ = shell.execute_line("touch /home/attrs.txt");
+    let _ = shell.execute_line("chmod 600 /home/attrs.txt");
+    let _ = shell.execute_line("chown 1000:100 /home/attrs.txt");
+    let _ = shell.execute_line("ls -l /home");
+    let _ = shell.execute_line("stat /home/attrs.txt");
+    let _ = shell.execute_line("rm /home/attrs.txt");
+
+    println!("\n10. Testing links ('ln', 'ln -s'):");
+    let _ = shell.execute_line("ln /home/welcome.txt /home/hello.txt");
+    let _ = shell.execute_line("ln -s welcome.txt /home/greeting");
+    let _ = shell.execute_line("ls -l /home");
+    let _ = shell.execute_line("cat /home/greeting");
+    let _ = shell.execute_line("ln -s loop /home/loop");
+    let _ = shell.execute_line("cat /home/loop");
+    let _ = shell.execute_line("ln /home/welcome.txt /proc/welcome.txt");
+    let _ = shell.execute_line("rm /home/loop");
+    let _ = shell.execute_line("rm /home/greeting");
+    let _ = shell.execute_line("rm /home/hello.txt");
+
+    println!("\n11. Testing 'mv' (rename):");
+    let _ = shell.execute_line("mkdir /home/docs");
+    let _ = shell.execute_line("mv /home/welcome.txt /home/docs");
+    let _ = shell.execute_line("mv /home/docs /home/documents");
+    let _ = shell.execute_line("ls -l /home/documents");
+    let _ = shell.execute_line("mv /home/documents/welcome.txt /proc/welcome.txt");
+    let _ = shell.execute_line("mv /home/documents/welcome.txt /home/welcome.txt");
+    let _ = shell.execute_line("rmdir /home/documents");
+
+    println!("\n12. Testing the working directory across renames:");
+    let _ = shell.execute_line("mkdir /home/work");
+    let _ = shell.execute_line("cd /home/work");
+    let _ = shell.execute_line("touch notes.txt");
+    let _ = shell.execute_line("mv /home/work /home/project");
+    let _ = shell.execute_line("pwd");
+    let _ = shell.execute_line("ls");
+    let _ = shell.execute_line("rm notes.txt");
+    let _ = shell.execute_line("cd /");
+    let _ = shell.execute_line("rmdir /home/project");
+
+    println!("\n13. Testing output redirection (truncate and append):");
+    let _ = shell.execute_line("echo first > /home/log.txt");
+    let _ = shell.execute_line("echo second >> /home/log.txt");
+    let _ = shell.execute_line("cat /home/log.txt");
+    let _ = shell.execute_line("echo reset > /home/log.txt");
+    let _ = shell.execute_line("cat /home/log.txt");
+    let _ = shell.execute_line("rm /home/log.txt");
+
+    println!("\n14. Testing a FAT32 volume:");
+    // Use the first block device that holds a FAT32 filesystem
+    let fat_device = block::devices()
+        .into_iter()
+        .find(|queue| fat32::Fat32Fs::mount(queue.clone()).is_ok());
+    match fat_device {
+        Some(queue) => {
+            let _ = shell.execute_line("mkdir /mnt");
+            let _ = shell.execute_line(&alloc::format!("mount -t fat32 /dev/{} /mnt", queue.name()));
+            let _ = shell.execute_line("ls /mnt");
+            let _ = shell.execute_line("mkdir /mnt/LongDirectoryName");
+            let _ = shell.execute_line("echo hello from rustos > /mnt/LongDirectoryName/Meeting-Notes.markdown");
+            let _ = shell.execute_line("cat /mnt/LongDirectoryName/Meeting-Notes.markdown");
+            let _ = shell.execute_line("ls /mnt/LongDirectoryName");
+            let _ = shell.execute_line("rm /mnt/LongDirectoryName/Meeting-Notes.markdown");
+            let _ = shell.execute_line("rmdir /mnt/LongDirectoryName");
+            let _ = shell.execute_line("umount /mnt");
+            let _ = shell.execute_line("rmdir /mnt");
+        }
+        None => println!("No FAT32 volume found (make one with tools/mkimage)"),
+    }
+
+    println!("\n15. Testing an ext2 volume:");
+    let ext2_device = block::devices()
+        .into_iter()
+        .find(|queue| ext2::Ext2Fs::mount(queue.clone()).is_ok());
+    match ext2_device {
+        Some(queue) => {
+            let _ = shell.execute_line("mkdir /mnt");
+            let _ = shell.execute_line(&alloc::format!("mount -t ext2 /dev/{} /mnt", queue.name()));
+            let _ = shell.execute_line("ls -l /mnt");
+            let _ = shell.execute_line("mkdir /mnt/scratch");
+            let _ = shell.execute_line("echo written by rustos > /mnt/scratch/note.txt");
+            let _ = shell.execute_line("ln /mnt/scratch/note.txt /mnt/scratch/hardlink.txt");
+            let _ = shell.execute_line("ln -s note.txt /mnt/scratch/symlink.txt");
+            let _ = shell.execute_line("ls -l /mnt/scratch");
+            let _ = shell.execute_line("cat /mnt/scratch/symlink.txt");
+            let _ = shell.execute_line("rm /mnt/scratch/note.txt /mnt/scratch/hardlink.txt /mnt/scratch/symlink.txt");
+            let _ = shell.execute_line("rmdir /mnt/scratch");
+            let _ = shell.execute_line("umount /mnt");
+            let _ = shell.execute_line("rmdir /mnt");
+        }
+        None => println!("No ext2 volume found (make one with mke2fs -t ext2)"),
+    }
+
+    println!("\n16. Testing /dev/ram0 (request queue, partitions, buffer cache, rfs):");
+    if cfg!(feature = "selftest") {
+        selftest::ram0(&mut shell);
+    } else {
+        println!("Skipped: overwrites /dev/ram0 (build with --features selftest)");
+    }
+
+    println!("\n17. Testing an ISO9660 volume:");
+    let iso_device = block::devices()
+        .into_iter()
+        .find(|queue| iso9660::IsoFs::mount(queue.clone()).is_ok());
+    match iso_device {
+        Some(queue) => {
+            let _ = shell.execute_line("mkdir /mnt");
+            let _ = shell.execute_line(&alloc::format!("mount -t iso9660 /dev/{} /mnt", queue.name()));
+            let _ = shell.execute_line("ls -l /mnt");
+            let _ = shell.execute_line("touch /mnt/new.txt");
+            let _ = shell.execute_line("umount /mnt");
+            let _ = shell.execute_line("rmdir /mnt");
+        }
+        None => println!("No ISO9660 volume found (make one with xorriso -as mkisofs -R -J)"),
+    }
+
+    println!("\n18. Testing an overlay (copy-up, whiteouts, opaque directories):");
+    let _ = shell.execute_line("mkdir /overlay");
+    let _ = shell.execute_line("mkdir /overlay/lower");
+    let _ = shell.execute_line("mkdir /overlay/upper");
+    let _ = shell.execute_line("mkdir /overlay/merged");
+    let _ = shell.execute_line("mkdir /overlay/lower/etc");
+    let _ = shell.execute_line("echo from the base system > /overlay/lower/etc/motd");
+    let _ = shell.execute_line("echo obsolete > /overlay/lower/etc/old.conf");
+    let _ = shell.execute_line("mount -t overlay /overlay/lower /overlay/upper /overlay/merged");
+    let _ = shell.execute_line("echo changed in the overlay >> /overlay/merged/etc/motd");
+    let _ = shell.execute_line("rm /overlay/merged/etc/old.conf");
+    let _ = shell.execute_line("echo new > /overlay/merged/etc/new.conf");
+    let _ = shell.execute_line("ls /overlay/merged/etc");
+    let _ = shell.execute_line("cat /overlay/merged/etc/motd");
+    let _ = shell.execute_line("cat /overlay/lower/etc/motd");
+    let _ = shell.execute_line("ls /overlay/upper/etc");
+    let _ = shell.execute_line("umount /overlay/merged");
+    let _ = shell.execute_line("rm /overlay/upper/etc/motd /overlay/upper/etc/new.conf /overlay/upper/etc/.wh.old.conf");
+    let _ = shell.execute_line("rm /overlay/lower/etc/motd /overlay/lower/etc/old.conf");
+    let _ = shell.execute_line("rmdir /overlay/upper/etc");
+    let _ = shell.execute_line("rmdir /overlay/lower/etc");
+    let _ = shell.execute_line("rmdir /overlay/lower");
+    let _ = shell.execute_line("rmdir /overlay/upper");
+    let _ = shell.execute_line("rmdir /overlay/merged");
+    let _ = shell.execute_line("rmdir /overlay");
+
     println!("\n=== Shell Commands Test Complete ===\n");
 
     // ELF loader and init process infrastructure ready
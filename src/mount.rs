@@ -0,0 +1,472 @@
+//! Mount table and global path resolution
+//!
+//! Filesystems are attached to directories of the tree with `mount`.
+//! Path resolution walks one component at a time through the dentry cache,
+//! starting at the root for absolute paths and at the current process's
+//! working directory otherwise. When it reaches a mount point it continues
+//! in the mounted filesystem's root, and `..` steps back out through the
+//! mount point it came in by. Symbolic links are expanded in place, up to
+//! `MAX_SYMLINK_HOPS` per walk.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use crate::bcache;
+use crate::dcache::{self, Dentry};
+use crate::process;
+use crate::vfs::{FileDescriptor, FileType, Filesystem, Inode, OpenFlags, SetAttr, VfsError};
+
+/// A mounted filesystem
+#[derive(Clone)]
+pub struct Mount {
+    /// Absolute, normalized mount point
+    pub path: String,
+    pub fs: Arc<dyn Filesystem>,
+}
+
+/// All mounted filesystems, keyed by mount point
+pub struct MountTable {
+    mounts: BTreeMap<String, Mount>,
+    /// Dentry of the root directory, once `/` is mounted
+    root: Option<Arc<Dentry>>,
+}
+
+impl MountTable {
+    pub const fn new() -> Self {
+        Self {
+            mounts: BTreeMap::new(),
+            root: None,
+        }
+    }
+
+    /// Root of the filesystem mounted exactly at `path`, if any
+    fn root_at(&self, path: &str) -> Option<Arc<dyn Inode>> {
+        self.mounts.get(path).map(|mount| mount.fs.root())
+    }
+}
+
+impl Default for MountTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+lazy_static! {
+    /// Global mount table
+    pub static ref MOUNT_TABLE: Mutex<MountTable> = Mutex::new(MountTable::new());
+}
+
+/// Normalize an absolute path: collapse `//`, drop `.` and apply `..`
+///
+/// `..` at the root stays at the root. The result always starts with `/`
+/// and never ends with one (except for the root itself).
+pub fn normalize_path(path: &str) -> String {
+    let mut parts: Vec<&str> = Vec::new();
+
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            name => parts.push(name),
+        }
+    }
+
+    let mut normalized = String::new();
+    for part in parts {
+        normalized.push('/');
+        normalized.push_str(part);
+    }
+    if normalized.is_empty() {
+        normalized.push('/');
+    }
+    normalized
+}
+
+/// Join a parent path and a child name
+fn join(parent: &str, name: &str) -> String {
+    if parent == "/" {
+        let mut path = String::from("/");
+        path.push_str(name);
+        path
+    } else {
+        let mut path = String::from(parent);
+        path.push('/');
+        path.push_str(name);
+        path
+    }
+}
+
+/// Attach `fs` at the directory `path`
+///
+/// The first mount must be at `/`. Later mount points must be existing
+/// directories with nothing already mounted on them.
+pub fn mount(path: &str, fs: Arc<dyn Filesystem>) -> Result<(), VfsError> {
+    let path = normalize_path(path);
+
+    if path != "/" {
+        let target = resolve_path(&path)?;
+        if target.file_type() != FileType::Directory {
+            return Err(VfsError::NotADirectory);
+        }
+    }
+
+    let mut table = MOUNT_TABLE.lock();
+    if table.mounts.contains_key(&path) {
+        return Err(VfsError::Busy);
+    }
+    if path == "/" {
+        table.root = Some(Dentry::new_root(fs.root()));
+    } else if !table.mounts.contains_key("/") {
+        return Err(VfsError::NotFound);
+    }
+
+    table.mounts.insert(path.clone(), Mount { path, fs });
+    drop(table);
+
+    // Cached lookups of the mount point now name the wrong inode
+    dcache::flush_lookups();
+    Ok(())
+}
+
+/// Detach the filesystem mounted at `path`
+///
+/// Fails with `Busy` for the root or if other filesystems are mounted
+/// below it.
+pub fn umount(path: &str) -> Result<(), VfsError> {
+    let path = normalize_path(path);
+    let mut table = MOUNT_TABLE.lock();
+
+    if !table.mounts.contains_key(&path) {
+        return Err(VfsError::InvalidArgument);
+    }
+    if path == "/" {
+        return Err(VfsError::Busy);
+    }
+
+    let prefix = join(&path, "");
+    if table.mounts.keys().any(|other| other.starts_with(&prefix)) {
+        return Err(VfsError::Busy);
+    }
+
+    table.mounts[&path].fs.sync()?;
+    let device = table.mounts.remove(&path).and_then(|mount| mount.fs.device());
+    drop(table);
+
+    dcache::flush_lookups();
+    // The next filesystem on the device may use another block size
+    match device {
+        Some(device) => bcache::invalidate(&device),
+        None => Ok(()),
+    }
+}
+
+/// List the current mounts in mount-point order
+pub fn mounts() -> Vec<Mount> {
+    MOUNT_TABLE.lock().mounts.values().cloned().collect()
+}
+
+/// Symbolic links followed during one path walk before failing with ELOOP
+pub const MAX_SYMLINK_HOPS: usize = 40;
+
+/// Dentry of the root directory
+pub fn root_dentry() -> Result<Arc<Dentry>, VfsError> {
+    MOUNT_TABLE.lock().root.clone().ok_or(VfsError::NotFound)
+}
+
+/// Working directory of the current process (the root until it changes)
+fn cwd_dentry() -> Result<Arc<Dentry>, VfsError> {
+    match process::current_resources().cwd() {
+        Some(cwd) => Ok(cwd),
+        None => root_dentry(),
+    }
+}
+
+/// Find the child `name` of a directory dentry, through the dentry cache
+fn lookup_child(parent: &Arc<Dentry>, name: &str) -> Result<Arc<Dentry>, VfsError> {
+    match dcache::lookup(parent, name) {
+        Some(Some(child)) => return Ok(child),
+        Some(None) => return Err(VfsError::NotFound),
+        None => {}
+    }
+
+    let mounted = MOUNT_TABLE.lock().root_at(&join(&parent.path(), name));
+    let found = match mounted {
+        Some(root) => Ok(root),
+        None => parent.inode().lookup(name),
+    };
+
+    match found {
+        Ok(inode) => Ok(dcache::insert(parent, name, inode)),
+        Err(VfsError::NotFound) => {
+            dcache::insert_negative(parent, name);
+            Err(VfsError::NotFound)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Walk a path, crossing mount points and following symlinks
+///
+/// Absolute paths start at the root, relative ones at the current
+/// process's working directory. A symlink in the final component is only
+/// followed if `follow_final` is set (stat vs lstat).
+fn walk(path: &str, follow_final: bool) -> Result<Arc<Dentry>, VfsError> {
+    let root = root_dentry()?;
+    let mut current = if path.starts_with('/') { root.clone() } else { cwd_dentry()? };
+
+    // Components still to walk, in reverse so the next one is at the end;
+    // a symlink's target is spliced in here
+    let mut pending: Vec<String> = path.split('/').rev().map(String::from).collect();
+    let mut hops = 0;
+
+    while let Some(component) = pending.pop() {
+        match component.as_str() {
+            "" | "." => continue,
+            ".." => {
+                if let Some(parent) = current.parent() {
+                    current = parent;
+                }
+                continue;
+            }
+            _ => {}
+        }
+
+        if current.inode().file_type() != FileType::Directory {
+            return Err(VfsError::NotADirectory);
+        }
+
+        let child = lookup_child(&current, &component)?;
+
+        // A trailing slash leaves an empty component behind, so `link/`
+        // follows the link even when `follow_final` is not set
+        if child.inode().file_type() == FileType::Symlink && (follow_final || !pending.is_empty()) {
+            hops += 1;
+            if hops > MAX_SYMLINK_HOPS {
+                return Err(VfsError::SymlinkLoop);
+            }
+
+            // Relative targets continue from the directory holding the link
+            let target = child.inode().readlink()?;
+            if target.starts_with('/') {
+                current = root.clone();
+            }
+            pending.extend(target.split('/').rev().map(String::from));
+            continue;
+        }
+
+        current = child;
+    }
+
+    Ok(current)
+}
+
+/// Resolve a path to an inode, crossing mount points and following
+/// symbolic links
+///
+/// Relative paths start at the current process's working directory.
+pub fn resolve_path(path: &str) -> Result<Arc<dyn Inode>, VfsError> {
+    walk(path, true).map(|dentry| dentry.inode().clone())
+}
+
+/// Resolve a path without following a symlink in the final component
+pub fn resolve_path_no_follow(path: &str) -> Result<Arc<dyn Inode>, VfsError> {
+    walk(path, false).map(|dentry| dentry.inode().clone())
+}
+
+/// Split a path into its parent directory path and final component
+fn split_parent(path: &str) -> Result<(&str, &str), VfsError> {
+    let trimmed = path.trim_end_matches('/');
+    let (parent, name) = match trimmed.rfind('/') {
+        Some(0) => ("/", &trimmed[1..]),
+        Some(index) => (&trimmed[..index], &trimmed[index + 1..]),
+        None => (".", trimmed),
+    };
+
+    match name {
+        "" | "." | ".." => Err(VfsError::InvalidArgument),
+        _ => Ok((parent, name)),
+    }
+}
+
+/// Resolve the parent directory of `path`, returning it with the final name
+fn walk_parent(path: &str) -> Result<(Arc<Dentry>, &str), VfsError> {
+    let (parent, name) = split_parent(path)?;
+    Ok((walk(parent, true)?, name))
+}
+
+/// Filesystem holding an absolute, resolved path
+fn filesystem_of(path: &str) -> Option<Arc<dyn Filesystem>> {
+    let table = MOUNT_TABLE.lock();
+    table
+        .mounts
+        .values()
+        .filter(|mount| mount.path == "/" || path == mount.path || path.starts_with(&join(&mount.path, "")))
+        .max_by_key(|mount| mount.path.len())
+        .map(|mount| mount.fs.clone())
+}
+
+/// Check whether two directories are on the same filesystem
+fn same_filesystem(a: &Dentry, b: &Dentry) -> bool {
+    match (filesystem_of(&a.path()), filesystem_of(&b.path())) {
+        (Some(a), Some(b)) => Arc::ptr_eq(&a, &b),
+        _ => false,
+    }
+}
+
+/// Create a symbolic link at `path` pointing at `target`
+///
+/// The target is stored as given and only resolved when the link is followed.
+pub fn symlink(target: &str, path: &str) -> Result<Arc<dyn Inode>, VfsError> {
+    let (parent, name) = walk_parent(path)?;
+    let result = parent.inode().symlink(name, target);
+    dcache::invalidate(&parent, name);
+    result
+}
+
+/// Read the target of the symbolic link at `path`
+pub fn readlink(path: &str) -> Result<String, VfsError> {
+    resolve_path_no_follow(path)?.readlink()
+}
+
+/// Create a hard link `new_path` to the inode at `old_path`
+///
+/// A symlink at `old_path` is linked itself, not its target. Both paths
+/// must be on the same filesystem.
+pub fn link(old_path: &str, new_path: &str) -> Result<(), VfsError> {
+    let old = walk(old_path, false)?;
+    let old_parent = old.parent().ok_or(VfsError::PermissionDenied)?;
+    let (new_parent, name) = walk_parent(new_path)?;
+
+    if !same_filesystem(&old_parent, &new_parent) {
+        return Err(VfsError::CrossDevice);
+    }
+
+    let result = new_parent.inode().link(name, old.inode());
+    dcache::invalidate(&new_parent, name);
+    result
+}
+
+/// Move the entry at `old_path` to `new_path`, replacing a compatible target
+///
+/// Both paths must be on the same filesystem. Mount points, and
+/// directories with filesystems mounted below them, cannot be moved.
+pub fn rename(old_path: &str, new_path: &str) -> Result<(), VfsError> {
+    let (old_parent, old_name) = walk_parent(old_path)?;
+    let (new_parent, new_name) = walk_parent(new_path)?;
+
+    let old_resolved = join(&old_parent.path(), old_name);
+    let new_resolved = join(&new_parent.path(), new_name);
+    {
+        let table = MOUNT_TABLE.lock();
+        let covered = |path: &str| {
+            let prefix = join(path, "");
+            table.mounts.keys().any(|mount| mount == path || mount.starts_with(&prefix))
+        };
+        if covered(&old_resolved) || table.mounts.contains_key(&new_resolved) {
+            return Err(VfsError::Busy);
+        }
+    }
+
+    if !same_filesystem(&old_parent, &new_parent) {
+        return Err(VfsError::CrossDevice);
+    }
+
+    old_parent.inode().rename(old_name, new_parent.inode(), new_name)?;
+    dcache::rename(&old_parent, old_name, &new_parent, new_name);
+    Ok(())
+}
+
+/// Check whether a filesystem is mounted exactly at `path`
+pub fn is_mount_point(path: &str) -> bool {
+    MOUNT_TABLE.lock().mounts.contains_key(&normalize_path(path))
+}
+
+/// Create a regular file at `path`
+pub fn create_file(path: &str) -> Result<Arc<dyn Inode>, VfsError> {
+    let (parent, name) = walk_parent(path)?;
+    let result = parent.inode().create(name, FileType::Regular);
+    dcache::invalidate(&parent, name);
+    result
+}
+
+/// Open `path` as a new open file
+///
+/// O_CREAT creates a missing regular file with permission bits `mode`;
+/// with O_EXCL as well, the file must not exist yet (a symlink at `path`
+/// counts as existing). O_TRUNC empties a regular file opened for
+/// writing. Directories cannot be opened for writing.
+pub fn open(path: &str, flags: OpenFlags, mode: u16) -> Result<Arc<FileDescriptor>, VfsError> {
+    let lookup = if flags.create && flags.exclusive {
+        resolve_path_no_follow(path)
+    } else {
+        resolve_path(path)
+    };
+
+    let inode = match lookup {
+        Ok(_) if flags.create && flags.exclusive => return Err(VfsError::AlreadyExists),
+        Ok(inode) => inode,
+        Err(VfsError::NotFound) if flags.create => match create_file(path) {
+            Ok(inode) => {
+                inode.setattr(&SetAttr { mode: Some(mode), ..SetAttr::default() })?;
+                inode
+            }
+            // Someone else created it first
+            Err(VfsError::AlreadyExists) if !flags.exclusive => resolve_path(path)?,
+            Err(e) => return Err(e),
+        },
+        Err(e) => return Err(e),
+    };
+
+    let file_type = inode.file_type();
+    if file_type == FileType::Directory && flags.write {
+        return Err(VfsError::IsADirectory);
+    }
+    if file_type == FileType::Regular && flags.write && flags.truncate {
+        inode.truncate(0)?;
+    }
+
+    Ok(Arc::new(FileDescriptor::new(inode, flags)))
+}
+
+/// Create a directory at `path`
+pub fn create_directory(path: &str) -> Result<Arc<dyn Inode>, VfsError> {
+    let (parent, name) = walk_parent(path)?;
+    let result = parent.inode().create(name, FileType::Directory);
+    dcache::invalidate(&parent, name);
+    result
+}
+
+/// Remove the file or empty directory at `path`
+///
+/// Mount points cannot be removed while something is mounted on them.
+pub fn remove(path: &str) -> Result<(), VfsError> {
+    let (parent, name) = walk_parent(path)?;
+    if is_mount_point(&join(&parent.path(), name)) {
+        return Err(VfsError::Busy);
+    }
+
+    let result = parent.inode().remove(name);
+    dcache::invalidate(&parent, name);
+    result
+}
+
+/// Change the current process's working directory
+pub fn chdir(path: &str) -> Result<(), VfsError> {
+    let dentry = walk(path, true)?;
+    if dentry.inode().file_type() != FileType::Directory {
+        return Err(VfsError::NotADirectory);
+    }
+    process::current_resources().set_cwd(dentry);
+    Ok(())
+}
+
+/// Absolute path of the current process's working directory
+pub fn getcwd() -> Result<String, VfsError> {
+    Ok(cwd_dentry()?.path())
+}
@@ -0,0 +1,571 @@
+//! Overlay filesystem
+//!
+//! Merges a read-only lower directory tree (an initramfs or ISO9660
+//! volume, say) with a writable upper one (usually tmpfs) into a single
+//! tree. Entries of the upper tree hide those of the lower tree with the
+//! same name, except that two directories are merged, and listings show
+//! each name once.
+//!
+//! Nothing in the lower tree is ever changed. A file, symbolic link or
+//! directory is copied up, with its attributes and those of any missing
+//! parent directories, the first time it is changed. Removing an entry
+//! that exists in the lower tree leaves a whiteout behind: an empty file
+//! named `.wh.<name>` in the upper directory. A directory that replaces a
+//! removed one is marked opaque by a `.wh..wh..opq` file inside it, so the
+//! lower directory's entries stay hidden. These names are reserved and
+//! never show up in the merged tree.
+//!
+//! As on Linux without redirects, directories that exist in the lower
+//! tree cannot be renamed (`CrossDevice`), and inode numbers are those of
+//! the upper inode once there is one.
+//!
+//! The upper tree may be mounted on its own as well, so every change made
+//! to it here is reported to the dentry cache like a change through the VFS.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use crate::dcache;
+use crate::vfs::{downcast_inode, DirEntry, FileType, Filesystem, Inode, Metadata, SetAttr, VfsError};
+
+/// Prefix of whiteouts and other names the overlay keeps for itself
+const WHITEOUT_PREFIX: &str = ".wh.";
+/// Marker file in an upper directory that hides the lower one
+const OPAQUE_MARKER: &str = ".wh..wh..opq";
+
+/// Set in readdir cursors once the upper directory has been listed; the
+/// other bits are the lower directory's cursor
+const LOWER_PHASE: u64 = 1 << 63;
+
+/// Bytes copied at a time during copy-up
+const COPY_CHUNK: usize = 4096;
+
+/// Name of the whiteout that hides `name`
+fn whiteout_name(name: &str) -> String {
+    format!("{}{}", WHITEOUT_PREFIX, name)
+}
+
+/// Turn `NotFound` into `None`
+fn found<T>(result: Result<T, VfsError>) -> Result<Option<T>, VfsError> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(VfsError::NotFound) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Reject names the overlay keeps for whiteouts
+fn check_name(name: &str) -> Result<(), VfsError> {
+    if name.starts_with(WHITEOUT_PREFIX) {
+        return Err(VfsError::InvalidArgument);
+    }
+    Ok(())
+}
+
+/// Remove the whiteout hiding `name` from an upper directory, returning
+/// whether there was one
+fn remove_whiteout(upper: &Arc<dyn Inode>, name: &str) -> Result<bool, VfsError> {
+    let whiteout = whiteout_name(name);
+    let removed = found(upper.remove(&whiteout))?.is_some();
+    if removed {
+        dcache::entry_removed(&**upper, &whiteout);
+    }
+    Ok(removed)
+}
+
+/// Add a whiteout hiding `name` to an upper directory
+fn add_whiteout(upper: &Arc<dyn Inode>, name: &str) -> Result<(), VfsError> {
+    let whiteout = whiteout_name(name);
+    match upper.create(&whiteout, FileType::Regular) {
+        Ok(_) => {
+            dcache::entry_added(&**upper, &whiteout);
+            Ok(())
+        }
+        Err(VfsError::AlreadyExists) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Mark an upper directory opaque
+fn make_opaque(upper: &Arc<dyn Inode>) -> Result<(), VfsError> {
+    match upper.create(OPAQUE_MARKER, FileType::Regular) {
+        Ok(_) => {
+            dcache::entry_added(&**upper, OPAQUE_MARKER);
+            Ok(())
+        }
+        Err(VfsError::AlreadyExists) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Remove the whiteouts and opaque marker from an upper directory that is
+/// empty in the merged tree, so that it can be removed or replaced
+fn clear_whiteouts(upper: &Arc<dyn Inode>) -> Result<(), VfsError> {
+    for name in upper.list()? {
+        if name.starts_with(WHITEOUT_PREFIX) {
+            upper.remove(&name)?;
+            dcache::entry_removed(&**upper, &name);
+        }
+    }
+    Ok(())
+}
+
+/// The directories an overlay merges, shared by all of its inodes
+struct Layers {
+    lower: Arc<dyn Inode>,
+    upper: Arc<dyn Inode>,
+}
+
+/// An entry of the merged tree
+pub struct OverlayInode {
+    layers: Arc<Layers>,
+    /// This inode, handed to children as their parent
+    this: Weak<OverlayInode>,
+    /// Directory holding this entry and the entry's name, for copy-up
+    /// (`None` for the root, which always has an upper inode)
+    parent: Option<Arc<OverlayInode>>,
+    name: String,
+    /// The upper inode, once the entry exists in the upper tree
+    upper: Mutex<Option<Arc<dyn Inode>>>,
+    /// The lower inode: the entry itself, or for a directory with an
+    /// upper part, the lower directory merged into it
+    lower: Option<Arc<dyn Inode>>,
+}
+
+impl OverlayInode {
+    fn new(
+        layers: Arc<Layers>,
+        parent: Option<Arc<OverlayInode>>,
+        name: &str,
+        upper: Option<Arc<dyn Inode>>,
+        lower: Option<Arc<dyn Inode>>,
+    ) -> Arc<Self> {
+        Arc::new_cyclic(|this| Self {
+            layers,
+            this: this.clone(),
+            parent,
+            name: String::from(name),
+            upper: Mutex::new(upper),
+            lower,
+        })
+    }
+
+    /// A child of this directory
+    fn child(&self, name: &str, upper: Option<Arc<dyn Inode>>, lower: Option<Arc<dyn Inode>>) -> Arc<Self> {
+        Self::new(self.layers.clone(), self.this.upgrade(), name, upper, lower)
+    }
+
+    fn upper(&self) -> Option<Arc<dyn Inode>> {
+        self.upper.lock().clone()
+    }
+
+    /// The inode the entry's contents and attributes come from
+    fn current(&self) -> Arc<dyn Inode> {
+        match self.upper() {
+            Some(upper) => upper,
+            // Every entry exists in at least one layer
+            None => self.lower.clone().expect("overlay entry in neither layer"),
+        }
+    }
+
+    fn check_directory(&self) -> Result<(), VfsError> {
+        if self.file_type() == FileType::Directory {
+            Ok(())
+        } else {
+            Err(VfsError::NotADirectory)
+        }
+    }
+
+    /// The lower directory merged into this one, unless the upper
+    /// directory is opaque
+    fn lower_dir(&self) -> Result<Option<Arc<dyn Inode>>, VfsError> {
+        let Some(lower) = self.lower.clone() else {
+            return Ok(None);
+        };
+        if let Some(upper) = self.upper() {
+            if found(upper.lookup(OPAQUE_MARKER))?.is_some() {
+                return Ok(None);
+            }
+        }
+        Ok(Some(lower))
+    }
+
+    /// Whether the lower directory has an entry `name` showing through
+    fn lower_has(&self, name: &str) -> Result<bool, VfsError> {
+        match self.lower_dir()? {
+            Some(lower) => Ok(found(lower.lookup(name))?.is_some()),
+            None => Ok(false),
+        }
+    }
+
+    /// Look up `name` in the merged directory
+    fn lookup_node(&self, name: &str) -> Result<Arc<Self>, VfsError> {
+        self.check_directory()?;
+        if name.starts_with(WHITEOUT_PREFIX) {
+            return Err(VfsError::NotFound);
+        }
+
+        let upper = self.upper();
+        let upper_child = match &upper {
+            Some(upper) => found(upper.lookup(name))?,
+            None => None,
+        };
+        let lower_child = match (&upper_child, self.lower_dir()?) {
+            // An upper directory is merged with a lower one of the same name
+            (Some(child), Some(lower)) if child.file_type() == FileType::Directory => {
+                found(lower.lookup(name))?.filter(|lower| lower.file_type() == FileType::Directory)
+            }
+            (Some(_), _) | (None, None) => None,
+            (None, Some(lower)) => {
+                let whiteout = match &upper {
+                    Some(upper) => found(upper.lookup(&whiteout_name(name)))?.is_some(),
+                    None => false,
+                };
+                if whiteout {
+                    None
+                } else {
+                    found(lower.lookup(name))?
+                }
+            }
+        };
+
+        if upper_child.is_none() && lower_child.is_none() {
+            return Err(VfsError::NotFound);
+        }
+        Ok(self.child(name, upper_child, lower_child))
+    }
+
+    /// Copy the entry to the upper tree, with its parents, unless it is
+    /// there already; returns the upper inode
+    fn copy_up(&self) -> Result<Arc<dyn Inode>, VfsError> {
+        let mut upper = self.upper.lock();
+        if let Some(upper) = upper.as_ref() {
+            return Ok(upper.clone());
+        }
+        let (Some(lower), Some(parent)) = (&self.lower, &self.parent) else {
+            return Err(VfsError::IoError);
+        };
+        let parent_upper = parent.copy_up()?;
+
+        let meta = lower.metadata();
+        let created = match meta.file_type {
+            FileType::Regular | FileType::Directory => parent_upper.create(&self.name, meta.file_type),
+            FileType::Symlink => parent_upper.symlink(&self.name, &lower.readlink()?),
+            _ => return Err(VfsError::NotImplemented),
+        };
+        let copy = match created {
+            Ok(copy) => {
+                let copied = Self::copy_contents(lower, &copy, &meta);
+                if let Err(e) = copied {
+                    let _ = parent_upper.remove(&self.name);
+                    return Err(e);
+                }
+                dcache::entry_added(&*parent_upper, &self.name);
+                copy
+            }
+            // Copied up meanwhile through another inode for the same entry
+            Err(VfsError::AlreadyExists) => parent_upper.lookup(&self.name)?,
+            Err(e) => return Err(e),
+        };
+        *upper = Some(copy.clone());
+        Ok(copy)
+    }
+
+    /// Copy the data of a regular file and the attributes of any entry
+    fn copy_contents(lower: &Arc<dyn Inode>, copy: &Arc<dyn Inode>, meta: &Metadata) -> Result<(), VfsError> {
+        if meta.file_type == FileType::Regular {
+            let mut buffer = alloc::vec![0u8; COPY_CHUNK];
+            let mut offset = 0;
+            loop {
+                let read = lower.read(offset, &mut buffer)?;
+                if read == 0 {
+                    break;
+                }
+                if copy.write(offset, &buffer[..read])? != read {
+                    return Err(VfsError::NoSpace);
+                }
+                offset += read;
+            }
+        }
+        copy.setattr(&SetAttr {
+            mode: Some(meta.mode),
+            uid: Some(meta.uid),
+            gid: Some(meta.gid),
+            atime: Some(meta.atime),
+            mtime: Some(meta.mtime),
+        })
+    }
+
+    /// Fail unless `inode` is an entry of the same overlay
+    fn same_overlay(&self, inode: &Arc<dyn Inode>) -> Result<Arc<Self>, VfsError> {
+        downcast_inode::<OverlayInode>(inode)
+            .filter(|other| Arc::ptr_eq(&other.layers, &self.layers))
+            .ok_or(VfsError::CrossDevice)
+    }
+
+    /// Whether the merged directory has no entries
+    fn is_empty_dir(&self) -> Result<bool, VfsError> {
+        Ok(self.readdir(0)?.is_none())
+    }
+}
+
+impl Inode for OverlayInode {
+    fn read(&self, offset: usize, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        self.current().read(offset, buffer)
+    }
+
+    fn write(&self, offset: usize, buffer: &[u8]) -> Result<usize, VfsError> {
+        if self.file_type() != FileType::Regular {
+            return self.current().write(offset, buffer);
+        }
+        self.copy_up()?.write(offset, buffer)
+    }
+
+    fn append(&self, buffer: &[u8]) -> Result<usize, VfsError> {
+        if self.file_type() != FileType::Regular {
+            return self.current().append(buffer);
+        }
+        self.copy_up()?.append(buffer)
+    }
+
+    fn file_type(&self) -> FileType {
+        self.current().file_type()
+    }
+
+    fn size(&self) -> usize {
+        self.current().size()
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, VfsError> {
+        Ok(self.lookup_node(name)?)
+    }
+
+    fn create(&self, name: &str, file_type: FileType) -> Result<Arc<dyn Inode>, VfsError> {
+        self.check_directory()?;
+        check_name(name)?;
+        if found(self.lookup_node(name))?.is_some() {
+            return Err(VfsError::AlreadyExists);
+        }
+
+        let upper = self.copy_up()?;
+        let replaced = remove_whiteout(&upper, name)?;
+        let inode = upper.create(name, file_type)?;
+        dcache::entry_added(&*upper, name);
+        // A directory replacing a removed one must not show its entries
+        if file_type == FileType::Directory && replaced {
+            make_opaque(&inode)?;
+        }
+        Ok(self.child(name, Some(inode), None))
+    }
+
+    fn list(&self) -> Result<Vec<String>, VfsError> {
+        let mut names = Vec::new();
+        let mut cursor = 0;
+        while let Some((entry, next)) = self.readdir(cursor)? {
+            names.push(entry.name);
+            cursor = next;
+        }
+        Ok(names)
+    }
+
+    /// The upper directory's entries come first, with its cursors, then
+    /// those of the lower directory that are neither in the upper one nor
+    /// whited out, with `LOWER_PHASE` set
+    fn readdir(&self, cursor: u64) -> Result<Option<(DirEntry, u64)>, VfsError> {
+        self.check_directory()?;
+        let upper = self.upper();
+
+        let mut lower_cursor = cursor & !LOWER_PHASE;
+        if cursor & LOWER_PHASE == 0 {
+            if let Some(upper) = &upper {
+                let mut cursor = cursor;
+                while let Some((entry, next)) = upper.readdir(cursor)? {
+                    if !entry.name.starts_with(WHITEOUT_PREFIX) {
+                        return Ok(Some((entry, next)));
+                    }
+                    cursor = next;
+                }
+            }
+            lower_cursor = 0;
+        }
+
+        let Some(lower) = self.lower_dir()? else {
+            return Ok(None);
+        };
+        while let Some((entry, next)) = lower.readdir(lower_cursor)? {
+            lower_cursor = next;
+            if entry.name.starts_with(WHITEOUT_PREFIX) {
+                continue;
+            }
+            let hidden = match &upper {
+                Some(upper) => {
+                    found(upper.lookup(&entry.name))?.is_some()
+                        || found(upper.lookup(&whiteout_name(&entry.name)))?.is_some()
+                }
+                None => false,
+            };
+            if !hidden {
+                return Ok(Some((entry, next | LOWER_PHASE)));
+            }
+        }
+        Ok(None)
+    }
+
+    fn truncate(&self, size: usize) -> Result<(), VfsError> {
+        if self.file_type() != FileType::Regular {
+            return self.current().truncate(size);
+        }
+        self.copy_up()?.truncate(size)
+    }
+
+    fn remove(&self, name: &str) -> Result<(), VfsError> {
+        let child = self.lookup_node(name)?;
+        let is_dir = child.file_type() == FileType::Directory;
+        if is_dir && !child.is_empty_dir()? {
+            return Err(VfsError::DirectoryNotEmpty);
+        }
+
+        let upper = self.copy_up()?;
+        if let Some(child_upper) = child.upper() {
+            if is_dir {
+                clear_whiteouts(&child_upper)?;
+            }
+            upper.remove(name)?;
+            dcache::entry_removed(&*upper, name);
+        }
+        if self.lower_has(name)? {
+            add_whiteout(&upper, name)?;
+        }
+        Ok(())
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> Result<Arc<dyn Inode>, VfsError> {
+        self.check_directory()?;
+        check_name(name)?;
+        if found(self.lookup_node(name))?.is_some() {
+            return Err(VfsError::AlreadyExists);
+        }
+
+        let upper = self.copy_up()?;
+        remove_whiteout(&upper, name)?;
+        let inode = upper.symlink(name, target)?;
+        dcache::entry_added(&*upper, name);
+        Ok(self.child(name, Some(inode), None))
+    }
+
+    fn readlink(&self) -> Result<String, VfsError> {
+        self.current().readlink()
+    }
+
+    fn link(&self, name: &str, inode: &Arc<dyn Inode>) -> Result<(), VfsError> {
+        let target = self.same_overlay(inode)?;
+        self.check_directory()?;
+        check_name(name)?;
+        if found(self.lookup_node(name))?.is_some() {
+            return Err(VfsError::AlreadyExists);
+        }
+        if target.file_type() == FileType::Directory {
+            return Err(VfsError::PermissionDenied);
+        }
+
+        // Both names must lead to the one upper inode
+        let target_upper = target.copy_up()?;
+        let upper = self.copy_up()?;
+        remove_whiteout(&upper, name)?;
+        upper.link(name, &target_upper)?;
+        dcache::entry_added(&*upper, name);
+        Ok(())
+    }
+
+    fn rename(&self, old_name: &str, new_dir: &Arc<dyn Inode>, new_name: &str) -> Result<(), VfsError> {
+        let new_dir = self.same_overlay(new_dir)?;
+        new_dir.check_directory()?;
+        check_name(new_name)?;
+        let source = self.lookup_node(old_name)?;
+        if core::ptr::eq(self, Arc::as_ptr(&new_dir)) && old_name == new_name {
+            return Ok(());
+        }
+
+        // Moving a lower directory would mean copying up all of it
+        let source_is_dir = source.file_type() == FileType::Directory;
+        if source_is_dir && source.lower.is_some() {
+            return Err(VfsError::CrossDevice);
+        }
+
+        let target = found(new_dir.lookup_node(new_name))?;
+        if let Some(target) = &target {
+            match (source_is_dir, target.file_type() == FileType::Directory) {
+                (true, true) => {
+                    if !target.is_empty_dir()? {
+                        return Err(VfsError::DirectoryNotEmpty);
+                    }
+                }
+                (true, false) => return Err(VfsError::NotADirectory),
+                (false, true) => return Err(VfsError::IsADirectory),
+                (false, false) => {}
+            }
+        }
+
+        let source_upper = source.copy_up()?;
+        let old_upper = self.copy_up()?;
+        let new_upper = new_dir.copy_up()?;
+        if let Some(target_upper) = target.as_ref().and_then(|target| target.upper()) {
+            if source_is_dir {
+                clear_whiteouts(&target_upper)?;
+            }
+        }
+        remove_whiteout(&new_upper, new_name)?;
+        // The directory must not merge with a lower one at its new name
+        if source_is_dir && new_dir.lower_has(new_name)? {
+            make_opaque(&source_upper)?;
+        }
+
+        old_upper.rename(old_name, &new_upper, new_name)?;
+        dcache::entry_removed(&*old_upper, old_name);
+        dcache::entry_removed(&*new_upper, new_name);
+        if self.lower_has(old_name)? {
+            add_whiteout(&old_upper, old_name)?;
+        }
+        Ok(())
+    }
+
+    fn metadata(&self) -> Metadata {
+        self.current().metadata()
+    }
+
+    fn setattr(&self, attr: &SetAttr) -> Result<(), VfsError> {
+        self.copy_up()?.setattr(attr)
+    }
+}
+
+/// A lower and an upper directory tree, merged
+pub struct OverlayFs {
+    root: Arc<OverlayInode>,
+}
+
+impl OverlayFs {
+    /// Merge the directories `lower` and `upper`; only `upper` is changed
+    pub fn new(lower: Arc<dyn Inode>, upper: Arc<dyn Inode>) -> Result<Self, VfsError> {
+        if lower.file_type() != FileType::Directory || upper.file_type() != FileType::Directory {
+            return Err(VfsError::NotADirectory);
+        }
+        let layers = Arc::new(Layers { lower, upper });
+        let (upper, lower) = (layers.upper.clone(), layers.lower.clone());
+        Ok(Self {
+            root: OverlayInode::new(layers, None, "", Some(upper), Some(lower)),
+        })
+    }
+}
+
+impl Filesystem for OverlayFs {
+    fn name(&self) -> &'static str {
+        "overlay"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
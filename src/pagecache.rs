@@ -0,0 +1,534 @@
+//! Page cache
+//!
+//! File data is cached in 4 KiB frames keyed by (inode, page index).
+//! Filesystems whose data lives on a device route `Inode::read`/`write`
+//! through `read`/`write` here and implement `Inode::read_page`/
+//! `write_page` for the actual I/O, so repeated reads are served from
+//! memory. File-backed mmap maps the same pages.
+//!
+//! A miss also reads ahead the next few pages while free frames remain.
+//! Writes only dirty the cached page; dirty pages reach the filesystem on
+//! `fsync`/`sync` or when the flusher thread finds them older than
+//! `DIRTY_EXPIRE_NS`. A dirty page keeps its inode alive until then, so
+//! closing the last reference loses no data. When the frame pool runs
+//! out, the least recently used clean page that nobody maps is evicted.
+
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use crate::time::{self, NSEC_PER_SEC};
+use crate::vfs::{Inode, VfsError};
+
+/// Size of a cached page in bytes
+pub const PAGE_SIZE: usize = 4096;
+
+/// Number of frames in the page cache pool
+const POOL_FRAMES: usize = 64;
+
+/// Pages read ahead after a miss
+const READAHEAD_PAGES: u64 = 4;
+
+/// Dirty pages older than this are written back by the flusher
+const DIRTY_EXPIRE_NS: u64 = 5 * NSEC_PER_SEC;
+
+/// How often the flusher wakes up
+const FLUSH_INTERVAL_NS: u64 = NSEC_PER_SEC;
+
+/// Backing memory of the frame pool, kept out of the (small) kernel heap
+#[repr(C, align(4096))]
+struct FramePool([[u8; PAGE_SIZE]; POOL_FRAMES]);
+
+static mut FRAME_POOL: FramePool = FramePool([[0; PAGE_SIZE]; POOL_FRAMES]);
+
+/// Allocation bitmap of the frame pool (bit N = frame N in use)
+static FRAME_BITMAP: Mutex<u64> = Mutex::new(0);
+
+/// A 4 KiB frame from the page cache pool, freed on drop
+pub struct Frame {
+    index: usize,
+}
+
+impl Frame {
+    /// Take a free frame from the pool, zero-filled
+    fn alloc() -> Option<Self> {
+        let mut bitmap = FRAME_BITMAP.lock();
+        let index = (!*bitmap).trailing_zeros() as usize;
+        if index >= POOL_FRAMES {
+            return None;
+        }
+        *bitmap |= 1 << index;
+        drop(bitmap);
+
+        let mut frame = Self { index };
+        frame.as_mut_slice().fill(0);
+        Some(frame)
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        // Safety: the bitmap hands each frame to exactly one `Frame`
+        unsafe { &(*core::ptr::addr_of!(FRAME_POOL)).0[self.index] }
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        // Safety: the bitmap hands each frame to exactly one `Frame`
+        unsafe { &mut (*core::ptr::addr_of_mut!(FRAME_POOL)).0[self.index] }
+    }
+
+    /// Physical address of the frame, `None` before paging is set up
+    fn phys_addr(&self) -> Option<u64> {
+        crate::paging::translate(self.as_slice().as_ptr() as u64)
+    }
+}
+
+impl Drop for Frame {
+    fn drop(&mut self) {
+        *FRAME_BITMAP.lock() &= !(1 << self.index);
+    }
+}
+
+/// Number of unallocated frames in the pool
+fn free_frames() -> usize {
+    POOL_FRAMES - FRAME_BITMAP.lock().count_ones() as usize
+}
+
+/// One page of file data
+pub struct CachedPage {
+    state: Mutex<PageState>,
+    /// A copy-on-write copy owned by one mapping, not part of the cache
+    private: bool,
+}
+
+struct PageState {
+    frame: Frame,
+    /// When the page was first dirtied since its last writeback
+    dirty_since: Option<u64>,
+}
+
+impl CachedPage {
+    fn new(frame: Frame, private: bool) -> Arc<Self> {
+        Arc::new(Self {
+            state: Mutex::new(PageState { frame, dirty_since: None }),
+            private,
+        })
+    }
+
+    /// Copy out of the page starting at `offset`
+    fn read(&self, offset: usize, buffer: &mut [u8]) {
+        let state = self.state.lock();
+        buffer.copy_from_slice(&state.frame.as_slice()[offset..offset + buffer.len()]);
+    }
+
+    /// Copy into the page starting at `offset` and mark it dirty
+    fn write(&self, offset: usize, buffer: &[u8]) {
+        let mut state = self.state.lock();
+        state.frame.as_mut_slice()[offset..offset + buffer.len()].copy_from_slice(buffer);
+        state.dirty_since.get_or_insert_with(time::monotonic_ns);
+    }
+
+    /// Zero the page from `offset` to the end
+    fn zero_from(&self, offset: usize) {
+        self.state.lock().frame.as_mut_slice()[offset..].fill(0);
+    }
+
+    /// Record a store through a shared mapping
+    fn mark_dirty(&self) {
+        self.state.lock().dirty_since.get_or_insert_with(time::monotonic_ns);
+    }
+
+    fn is_dirty(&self) -> bool {
+        self.state.lock().dirty_since.is_some()
+    }
+
+    /// Physical address to map into a user address space
+    pub fn phys_addr(&self) -> Option<u64> {
+        self.state.lock().frame.phys_addr()
+    }
+
+    /// Make a private copy (copy-on-write for private mappings)
+    ///
+    /// The copy is not part of the cache and is never written back.
+    pub fn duplicate(&self) -> Result<Arc<CachedPage>, VfsError> {
+        let mut frame = allocate_frame()?;
+        frame.as_mut_slice().copy_from_slice(self.state.lock().frame.as_slice());
+        Ok(CachedPage::new(frame, true))
+    }
+
+    /// Whether this is a private copy made by `duplicate`
+    pub fn is_private(&self) -> bool {
+        self.private
+    }
+}
+
+/// Cache hit/miss and writeback counters
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PageCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub readahead: u64,
+    pub writebacks: u64,
+    pub evictions: u64,
+}
+
+struct CacheEntry {
+    /// Owner of the page; weak so cached pages do not keep files alive
+    inode: Weak<dyn Inode>,
+    /// The owner again while the page is dirty, so it is not dropped
+    /// before the page is written back
+    pin: Option<Arc<dyn Inode>>,
+    page: Arc<CachedPage>,
+    last_used: u64,
+}
+
+/// (inode address, page index)
+type PageKey = (usize, u64);
+
+/// Cached pages keyed by (inode address, page index)
+///
+/// An entry's weak reference keeps the inode's allocation alive, so the
+/// address cannot be reused by another inode while the entry exists.
+struct PageCache {
+    pages: BTreeMap<PageKey, CacheEntry>,
+    clock: u64,
+    stats: PageCacheStats,
+}
+
+impl PageCache {
+    const fn new() -> Self {
+        Self {
+            pages: BTreeMap::new(),
+            clock: 0,
+            stats: PageCacheStats {
+                hits: 0,
+                misses: 0,
+                readahead: 0,
+                writebacks: 0,
+                evictions: 0,
+            },
+        }
+    }
+
+    fn lookup(&mut self, key: PageKey) -> Option<Arc<CachedPage>> {
+        self.clock += 1;
+        let clock = self.clock;
+        let entry = self.pages.get_mut(&key)?;
+        entry.last_used = clock;
+        Some(entry.page.clone())
+    }
+
+    fn insert(&mut self, key: PageKey, inode: &Arc<dyn Inode>, page: Arc<CachedPage>) -> Arc<CachedPage> {
+        self.clock += 1;
+        let entry = self.pages.entry(key).or_insert(CacheEntry {
+            inode: Arc::downgrade(inode),
+            pin: None,
+            page,
+            last_used: self.clock,
+        });
+        entry.page.clone()
+    }
+
+    /// Keep the owner of a page that was just dirtied alive
+    fn pin(&mut self, key: PageKey, inode: &Arc<dyn Inode>) {
+        if let Some(entry) = self.pages.get_mut(&key) {
+            entry.pin.get_or_insert_with(|| inode.clone());
+        }
+    }
+
+    /// Drop the least recently used page that is clean and not mapped
+    fn evict_one(&mut self) -> bool {
+        let victim = self
+            .pages
+            .iter()
+            .filter(|(_, entry)| Arc::strong_count(&entry.page) == 1 && !entry.page.is_dirty())
+            .min_by_key(|(_, entry)| entry.last_used)
+            .map(|(&key, _)| key);
+
+        match victim {
+            Some(key) => {
+                self.pages.remove(&key);
+                self.stats.evictions += 1;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+lazy_static! {
+    static ref PAGE_CACHE: Mutex<PageCache> = Mutex::new(PageCache::new());
+}
+
+fn inode_key(inode: &Arc<dyn Inode>) -> usize {
+    Arc::as_ptr(inode) as *const () as usize
+}
+
+/// Get a frame, evicting a clean page if the pool is exhausted
+///
+/// If every page is dirty, they are written back first so they can be
+/// evicted.
+fn allocate_frame() -> Result<Frame, VfsError> {
+    let mut wrote_back = false;
+    loop {
+        if let Some(frame) = Frame::alloc() {
+            return Ok(frame);
+        }
+        if PAGE_CACHE.lock().evict_one() {
+            continue;
+        }
+        // Every frame holds a mapped page: nothing can be freed until munmap
+        if wrote_back {
+            return Err(VfsError::IoError);
+        }
+        writeback_all()?;
+        wrote_back = true;
+    }
+}
+
+/// Read page `index` of `inode` into a new cache entry
+fn load_page(inode: &Arc<dyn Inode>, index: u64, frame: Frame) -> Result<Arc<CachedPage>, VfsError> {
+    let mut frame = frame;
+    inode.read_page(index, frame.as_mut_slice())?;
+    let page = CachedPage::new(frame, false);
+    // Another reader may have loaded it meanwhile; keep the first copy
+    Ok(PAGE_CACHE.lock().insert((inode_key(inode), index), inode, page))
+}
+
+/// Get page `index` of `inode`, reading it (and the pages after it) on a miss
+pub fn get_page(inode: &Arc<dyn Inode>, index: u64) -> Result<Arc<CachedPage>, VfsError> {
+    let key = inode_key(inode);
+    {
+        let mut cache = PAGE_CACHE.lock();
+        if let Some(page) = cache.lookup((key, index)) {
+            cache.stats.hits += 1;
+            return Ok(page);
+        }
+        cache.stats.misses += 1;
+    }
+
+    let page = load_page(inode, index, allocate_frame()?)?;
+
+    // Read ahead into free frames only, so it never evicts useful pages
+    let last_page = (inode.size() as u64).div_ceil(PAGE_SIZE as u64);
+    for ahead in index + 1..(index + 1 + READAHEAD_PAGES).min(last_page) {
+        if PAGE_CACHE.lock().pages.contains_key(&(key, ahead)) {
+            continue;
+        }
+        let Some(frame) = Frame::alloc() else {
+            break;
+        };
+        if load_page(inode, ahead, frame).is_err() {
+            break;
+        }
+        PAGE_CACHE.lock().stats.readahead += 1;
+    }
+
+    Ok(page)
+}
+
+/// Read file data through the cache
+///
+/// Reads stop at the inode's current size.
+pub fn read(inode: &Arc<dyn Inode>, offset: usize, buffer: &mut [u8]) -> Result<usize, VfsError> {
+    let size = inode.size();
+    if offset >= size {
+        return Ok(0);
+    }
+    let total = buffer.len().min(size - offset);
+
+    let mut done = 0;
+    while done < total {
+        let position = offset + done;
+        let in_page = position % PAGE_SIZE;
+        let count = (PAGE_SIZE - in_page).min(total - done);
+
+        let page = get_page(inode, (position / PAGE_SIZE) as u64)?;
+        page.read(in_page, &mut buffer[done..done + count]);
+        done += count;
+    }
+
+    Ok(total)
+}
+
+/// Write file data into the cache, dirtying the pages
+///
+/// The caller extends the inode's size if the write goes past the end.
+pub fn write(inode: &Arc<dyn Inode>, offset: usize, buffer: &[u8]) -> Result<usize, VfsError> {
+    let key = inode_key(inode);
+    let mut done = 0;
+    while done < buffer.len() {
+        let position = offset + done;
+        let in_page = position % PAGE_SIZE;
+        let count = (PAGE_SIZE - in_page).min(buffer.len() - done);
+
+        let index = (position / PAGE_SIZE) as u64;
+        let page = get_page(inode, index)?;
+        // Dirty before pinning: writeback unpins only pages it finds clean
+        page.write(in_page, &buffer[done..done + count]);
+        PAGE_CACHE.lock().pin((key, index), inode);
+        done += count;
+    }
+
+    Ok(done)
+}
+
+/// Record a store through a shared mapping of page `index` of `inode`
+pub fn mark_dirty(inode: &Arc<dyn Inode>, index: u64, page: &CachedPage) {
+    page.mark_dirty();
+    PAGE_CACHE.lock().pin((inode_key(inode), index), inode);
+}
+
+/// Drop cached pages past a new file size and zero the tail of the last one
+pub fn truncate(inode: &Arc<dyn Inode>, size: usize) {
+    let key = inode_key(inode);
+    let first_dropped = size.div_ceil(PAGE_SIZE) as u64;
+
+    let mut cache = PAGE_CACHE.lock();
+    let doomed: Vec<PageKey> = cache.pages.range((key, first_dropped)..=(key, u64::MAX)).map(|(&k, _)| k).collect();
+    let dropped: Vec<CacheEntry> = doomed.iter().filter_map(|k| cache.pages.remove(k)).collect();
+
+    if !size.is_multiple_of(PAGE_SIZE) {
+        if let Some(entry) = cache.pages.get(&(key, (size / PAGE_SIZE) as u64)) {
+            entry.page.zero_from(size % PAGE_SIZE);
+        }
+    }
+
+    // Unpinning may drop the last reference to another inode's owner; do
+    // it without the cache lock
+    drop(cache);
+    drop(dropped);
+}
+
+/// Write back the dirty pages selected by `filter`
+///
+/// Pages are written without holding the cache lock. Pages whose inode is
+/// gone are dropped.
+fn writeback(filter: impl Fn(usize, &CachedPage) -> bool) -> Result<(), VfsError> {
+    let dirty: Vec<(PageKey, Weak<dyn Inode>, Arc<CachedPage>)> = {
+        let mut cache = PAGE_CACHE.lock();
+        cache.pages.retain(|_, entry| entry.inode.strong_count() > 0);
+        cache
+            .pages
+            .iter()
+            .filter(|(&(owner, _), entry)| entry.page.is_dirty() && filter(owner, &entry.page))
+            .map(|(&key, entry)| (key, entry.inode.clone(), entry.page.clone()))
+            .collect()
+    };
+
+    let mut result = Ok(());
+    for (key, inode, page) in dirty {
+        let Some(inode) = inode.upgrade() else {
+            continue;
+        };
+
+        // Hold the page across the write so a concurrent store re-dirties it
+        // only after the data went out
+        let mut state = page.state.lock();
+        match inode.write_page(key.1, state.frame.as_slice()) {
+            Ok(()) => {
+                state.dirty_since = None;
+                drop(state);
+                let unpinned = {
+                    let mut cache = PAGE_CACHE.lock();
+                    cache.stats.writebacks += 1;
+                    cache
+                        .pages
+                        .get_mut(&key)
+                        .filter(|entry| !entry.page.is_dirty())
+                        .and_then(|entry| entry.pin.take())
+                };
+                drop(unpinned);
+            }
+            Err(e) => result = Err(e),
+        }
+    }
+
+    result
+}
+
+/// Write back the dirty pages of one inode
+pub fn fsync(inode: &Arc<dyn Inode>) -> Result<(), VfsError> {
+    let key = inode_key(inode);
+    writeback(|owner, _| owner == key)
+}
+
+/// Write back every dirty page
+pub fn writeback_all() -> Result<(), VfsError> {
+    writeback(|_, _| true)
+}
+
+/// Write back every dirty page and buffer, then flush the block devices
+pub fn sync() -> Result<(), VfsError> {
+    writeback_all()?;
+    crate::bcache::sync()?;
+    Ok(crate::block::sync_all()?)
+}
+
+/// Write back pages that have been dirty for longer than `DIRTY_EXPIRE_NS`
+fn writeback_expired() -> Result<(), VfsError> {
+    let cutoff = time::monotonic_ns().saturating_sub(DIRTY_EXPIRE_NS);
+    writeback(|_, page| page.state.lock().dirty_since.is_some_and(|since| since <= cutoff))
+}
+
+/// Render the cache state for /proc/pagecache
+pub fn render_stats() -> String {
+    let (cached, dirty, stats) = {
+        let cache = PAGE_CACHE.lock();
+        let dirty = cache.pages.values().filter(|entry| entry.page.is_dirty()).count();
+        (cache.pages.len(), dirty, cache.stats)
+    };
+
+    format!(
+        "frames:     {}\n\
+         free:       {}\n\
+         cached:     {}\n\
+         dirty:      {}\n\
+         hits:       {}\n\
+         misses:     {}\n\
+         readahead:  {}\n\
+         writebacks: {}\n\
+         evictions:  {}\n",
+        POOL_FRAMES,
+        free_frames(),
+        cached,
+        dirty,
+        stats.hits,
+        stats.misses,
+        stats.readahead,
+        stats.writebacks,
+        stats.evictions,
+    )
+}
+
+/// Stack of the flusher kernel thread
+#[repr(C, align(16))]
+struct FlusherStack([u8; 8192]);
+
+static mut FLUSHER_STACK: FlusherStack = FlusherStack([0; 8192]);
+
+extern "C" fn flusher_main() -> ! {
+    loop {
+        time::sleep_ns(FLUSH_INTERVAL_NS);
+        if let Err(e) = writeback_expired() {
+            crate::serial_println!("pagecache: writeback failed: {}", e);
+        }
+        if let Err(e) = crate::block::unplug_all() {
+            crate::serial_println!("pagecache: block I/O failed: {}", e);
+        }
+    }
+}
+
+/// Start the flusher thread that writes back expired dirty pages
+pub fn start_flusher() -> u32 {
+    // Safety: the stack is used by this one thread only, and `flusher_main`
+    // never returns
+    unsafe {
+        let stack_top = core::ptr::addr_of!(FLUSHER_STACK) as u64 + core::mem::size_of::<FlusherStack>() as u64;
+        crate::process::PROCESS_MANAGER
+            .lock()
+            .spawn_kernel_thread(flusher_main, stack_top)
+    }
+}
@@ -1,75 +1,212 @@
+use spin::Mutex;
 use x86_64::{
-    structures::paging::{PhysFrame, PageSize, Size4KiB, Page, PageTableFlags},
+    instructions::interrupts::without_interrupts,
+    structures::paging::{
+        mapper::MapToError,
+        Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB, Translate,
+    },
     VirtAddr, PhysAddr,
     registers::control::Cr3,
 };
-use crate::physical_memory::BitmapFrameAllocator;
+use crate::physical_memory::{BitmapFrameAllocator, FRAME_SIZE};
 
 pub const TABLE_FLAGS: PageTableFlags = PageTableFlags::PRESENT.union(PageTableFlags::WRITABLE);
 
+/// Virtual address used by the boot-time mapping self-test
+const SCRATCH_PAGE: u64 = 0x0000_7fff_fffe_0000;
+
+/// Errors returned by page table operations
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PagingError {
+    /// `init_paging` has not run yet
+    NotInitialized,
+    /// No free frame for the page or its page tables
+    OutOfFrames,
+    /// The page (or a huge page covering it) is already mapped
+    AlreadyMapped,
+    /// The page is not mapped
+    NotMapped,
+}
+
 // Page table manager that holds an allocator for frames
+//
+// Page tables are reached through the bootloader's mapping of all physical
+// memory at `physical_memory_offset`.
 pub struct PagerManager {
-    #[allow(dead_code)]
+    mapper: OffsetPageTable<'static>,
     allocator: BitmapFrameAllocator,
+    physical_memory_offset: u64,
 }
 
 impl PagerManager {
+    /// Map `page` to `frame`
+    ///
+    /// Page tables created on the way are user accessible when `flags` is,
+    /// so user pages can be reached from ring 3.
     pub fn map_to(
         &mut self,
-        _page: Page<Size4KiB>,
-        _frame: PhysFrame,
-        _flags: PageTableFlags,
-    ) -> Result<(), ()> {
-        // For now, just return Ok since we're doing identity mapping
-        // In a real implementation, you'd update page tables here
-        Ok(())
+        page: Page<Size4KiB>,
+        frame: PhysFrame,
+        flags: PageTableFlags,
+    ) -> Result<(), PagingError> {
+        let table_flags = TABLE_FLAGS | (flags & PageTableFlags::USER_ACCESSIBLE);
+
+        // Safety: the frames handed to this function are owned by the caller
+        // (a user address space or the self-test), never kernel memory
+        let result = unsafe {
+            self.mapper.map_to_with_table_flags(page, frame, flags, table_flags, &mut self.allocator)
+        };
+
+        match result {
+            Ok(flush) => {
+                flush.flush();
+                Ok(())
+            }
+            Err(MapToError::FrameAllocationFailed) => Err(PagingError::OutOfFrames),
+            Err(MapToError::ParentEntryHugePage | MapToError::PageAlreadyMapped(_)) => {
+                Err(PagingError::AlreadyMapped)
+            }
+        }
     }
-}
 
-// Initialize paging with a simple identity mapping for kernel code and data
-pub fn init_paging(_physical_memory_offset: u64) -> PagerManager {
-    // Create the physical memory allocator
-    let mut frame_allocator = unsafe {
-        BitmapFrameAllocator::new(&[])
-    };
-
-    // Initialize the bitmap with reserved areas marked as used
-    frame_allocator.init();
-
-    // Create a new page table manager with the initialized components
-    let mut pager_manager = PagerManager {
-        allocator: frame_allocator,
-    };
-
-    // Set up identity mappings for kernel code and data (0x1_0000 to 0x2_0000)
-    const KERNEL_START: u64 = 0x1_0000;
-    const KERNEL_END: u64 = 0x2_0000;
-
-    let mut current_addr = KERNEL_START;
-
-    while current_addr < KERNEL_END {
-        // Create a page for this address
-        let virt_page = Page::<Size4KiB>::containing_address(
-            VirtAddr::new(current_addr)
-        );
-
-        // Map it to itself (identity mapping) with read/write access
-        if pager_manager.map_to(virt_page,
-                                PhysFrame::containing_address(PhysAddr::new(current_addr)),
-                                TABLE_FLAGS).is_err() {
-            panic!("Failed to initialize kernel identity mapping!");
+    /// Unmap `page`, returning the frame it was mapped to
+    pub fn unmap(&mut self, page: Page<Size4KiB>) -> Result<PhysFrame, PagingError> {
+        match self.mapper.unmap(page) {
+            Ok((frame, flush)) => {
+                flush.flush();
+                Ok(frame)
+            }
+            Err(_) => Err(PagingError::NotMapped),
         }
+    }
 
-        current_addr += Size4KiB::SIZE;
+    /// Change the flags of a mapped page
+    pub fn update_flags(&mut self, page: Page<Size4KiB>, flags: PageTableFlags) -> Result<(), PagingError> {
+        // Safety: only the flags of an existing user mapping change
+        match unsafe { self.mapper.update_flags(page, flags) } {
+            Ok(flush) => {
+                flush.flush();
+                Ok(())
+            }
+            Err(_) => Err(PagingError::NotMapped),
+        }
     }
 
-    // Set up the CR3 register to point to our page table
-    unsafe {
-        let (pml4_frame, flags) = Cr3::read();
+    /// Take a free frame and zero it
+    pub fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let frame = self.allocator.allocate_frame()?;
+        let frame = PhysFrame::containing_address(PhysAddr::new(frame.start_address()));
+
+        // Safety: the frame was free, so nothing else refers to it
+        unsafe {
+            core::ptr::write_bytes(self.phys_to_virt(frame.start_address()), 0, FRAME_SIZE);
+        }
+        Some(frame)
+    }
+
+    /// Return a frame taken with `allocate_frame`
+    pub fn free_frame(&mut self, frame: PhysFrame) {
+        self.allocator
+            .deallocate_frame(crate::physical_memory::PhysFrame::containing_address(frame.start_address().as_u64()));
+    }
+
+    /// Kernel pointer to physical address `phys`
+    fn phys_to_virt(&self, phys: PhysAddr) -> *mut u8 {
+        (self.physical_memory_offset + phys.as_u64()) as *mut u8
+    }
+}
+
+/// The kernel's page table manager, set up by `init_paging`
+///
+/// Only taken with interrupts disabled: the page fault handler needs it.
+static PAGER: Mutex<Option<PagerManager>> = Mutex::new(None);
+
+fn with_pager<R>(f: impl FnOnce(&mut PagerManager) -> R) -> Result<R, PagingError> {
+    without_interrupts(|| match PAGER.lock().as_mut() {
+        Some(pager) => Ok(f(pager)),
+        None => Err(PagingError::NotInitialized),
+    })
+}
+
+// Initialize paging on top of the page tables the bootloader set up
+//
+// The bootloader already maps the kernel and all of physical memory at
+// `physical_memory_offset`; new mappings are added to the active tables.
+pub fn init_paging(physical_memory_offset: u64, allocator: BitmapFrameAllocator) {
+    let (pml4_frame, _) = Cr3::read();
+    let pml4 = (physical_memory_offset + pml4_frame.start_address().as_u64()) as *mut PageTable;
+
+    // Safety: the bootloader maps all physical memory at the offset, and the
+    // active level 4 table is only ever accessed through this mapper
+    let mapper = unsafe { OffsetPageTable::new(&mut *pml4, VirtAddr::new(physical_memory_offset)) };
+
+    *PAGER.lock() = Some(PagerManager {
+        mapper,
+        allocator,
+        physical_memory_offset,
+    });
+}
+
+/// Map the page at `addr` to the frame at `phys`
+pub fn map_page(addr: u64, phys: u64, flags: PageTableFlags) -> Result<(), PagingError> {
+    let page = Page::containing_address(VirtAddr::new(addr));
+    let frame = PhysFrame::containing_address(PhysAddr::new(phys));
+    with_pager(|pager| pager.map_to(page, frame, flags))?
+}
 
-        // Write back the CR3 with current settings (identity mapped)
-        Cr3::write(pml4_frame, flags);
+/// Unmap the page at `addr`, returning the physical address it mapped
+pub fn unmap_page(addr: u64) -> Result<u64, PagingError> {
+    let page = Page::containing_address(VirtAddr::new(addr));
+    with_pager(|pager| pager.unmap(page))?.map(|frame| frame.start_address().as_u64())
+}
+
+/// Change the flags of the page at `addr`
+pub fn update_flags(addr: u64, flags: PageTableFlags) -> Result<(), PagingError> {
+    let page = Page::containing_address(VirtAddr::new(addr));
+    with_pager(|pager| pager.update_flags(page, flags))?
+}
+
+/// Translate a virtual address to a physical one
+pub fn translate(addr: u64) -> Option<u64> {
+    with_pager(|pager| pager.mapper.translate_addr(VirtAddr::new(addr)))
+        .ok()
+        .flatten()
+        .map(PhysAddr::as_u64)
+}
+
+/// Allocate a zeroed physical frame, returning its address
+pub fn allocate_frame() -> Option<u64> {
+    with_pager(|pager| pager.allocate_frame()).ok().flatten().map(|frame| frame.start_address().as_u64())
+}
+
+/// Free a frame returned by `allocate_frame`
+pub fn free_frame(phys: u64) {
+    let frame = PhysFrame::containing_address(PhysAddr::new(phys));
+    let _ = with_pager(|pager| pager.free_frame(frame));
+}
+
+/// Map a fresh frame at a scratch address, check that a store through it
+/// lands in the frame, then unmap it again
+pub fn self_test() -> Result<(), PagingError> {
+    const PATTERN: u64 = 0x5a5a_1234_a5a5_4321;
+
+    let phys = allocate_frame().ok_or(PagingError::OutOfFrames)?;
+    if let Err(e) = map_page(SCRATCH_PAGE, phys, TABLE_FLAGS) {
+        free_frame(phys);
+        return Err(e);
     }
 
-    pager_manager
+    // Safety: the scratch page was just mapped to a frame we own
+    unsafe { core::ptr::write_volatile(SCRATCH_PAGE as *mut u64, PATTERN) };
+    let seen = with_pager(|pager| unsafe { core::ptr::read_volatile(pager.phys_to_virt(PhysAddr::new(phys)) as *const u64) });
+
+    let unmapped = unmap_page(SCRATCH_PAGE);
+    free_frame(phys);
+    unmapped?;
+
+    if seen? == PATTERN && translate(SCRATCH_PAGE).is_none() {
+        Ok(())
+    } else {
+        Err(PagingError::NotMapped)
+    }
 }
@@ -0,0 +1,463 @@
+//! Partition tables: MBR and GPT
+//!
+//! `scan` reads the partition table of a registered disk and registers
+//! each partition as a block device of its own, named after the disk
+//! (`hda1`, or `ram0p1` when the disk name ends in a digit). A `Partition`
+//! translates block numbers and forwards to the disk's request queue.
+//!
+//! MBR disks may carry an extended partition whose chain of extended boot
+//! records describes logical partitions, numbered from 5. A protective MBR
+//! (type 0xEE) means the disk is GPT: the primary header and entry array
+//! are CRC-checked and the backup at the end of the disk is used if the
+//! primary is damaged.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use crate::block::{self, check_request, BlockDevice, BlockError, RequestQueue};
+use crate::vfs::VfsError;
+
+/// Offset of the partition entries in an MBR or EBR
+const MBR_ENTRIES: usize = 446;
+
+/// MBR partition types that hold a chain of EBRs
+const MBR_EXTENDED_TYPES: [u8; 3] = [0x05, 0x0F, 0x85];
+/// MBR partition type of a protective MBR in front of a GPT
+const MBR_GPT_PROTECTIVE: u8 = 0xEE;
+
+/// Longest EBR chain followed, in case the links form a loop
+const MAX_LOGICAL_PARTITIONS: usize = 64;
+
+/// Largest GPT entry array read (128 entries of 128 bytes is the norm)
+const MAX_GPT_ENTRY_BYTES: usize = 32 * 1024;
+
+/// Errors from reading a partition table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionError {
+    /// No 0x55AA boot signature: the disk is not partitioned
+    NoTable,
+    /// Neither the primary nor the backup GPT header is valid
+    BadGpt,
+    /// An entry points outside the disk
+    BadEntry,
+    Io(BlockError),
+}
+
+impl fmt::Display for PartitionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PartitionError::NoTable => write!(f, "No partition table"),
+            PartitionError::BadGpt => write!(f, "GPT header or entries fail their checksum"),
+            PartitionError::BadEntry => write!(f, "Partition lies outside the disk"),
+            PartitionError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<BlockError> for PartitionError {
+    fn from(e: BlockError) -> Self {
+        PartitionError::Io(e)
+    }
+}
+
+/// Which kind of table a disk has
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TableKind {
+    Mbr,
+    Gpt,
+}
+
+/// The type recorded for a partition
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionType {
+    Mbr(u8),
+    /// Type GUID, in on-disk byte order
+    Gpt([u8; 16]),
+}
+
+impl PartitionType {
+    /// Human-readable name, as lsblk shows it
+    pub fn description(&self) -> String {
+        match self {
+            PartitionType::Mbr(kind) => String::from(match kind {
+                0x01 => "FAT12",
+                0x04 | 0x06 | 0x0E => "FAT16",
+                0x07 => "NTFS/exFAT",
+                0x0B => "FAT32",
+                0x0C => "FAT32 (LBA)",
+                0x82 => "Linux swap",
+                0x83 => "Linux",
+                0xEF => "EFI System",
+                _ => return format!("MBR type 0x{:02x}", kind),
+            }),
+            PartitionType::Gpt(guid) => match GPT_TYPES.iter().find(|(known, _)| known == guid) {
+                Some((_, name)) => String::from(*name),
+                None => format_guid(guid),
+            },
+        }
+    }
+}
+
+/// Well-known GPT partition type GUIDs
+const GPT_TYPES: [([u8; 16], &str); 5] = [
+    (guid(0xC12A7328, 0xF81F, 0x11D2, [0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9, 0x3B]), "EFI System"),
+    (guid(0xEBD0A0A2, 0xB9E5, 0x4433, [0x87, 0xC0, 0x68, 0xB6, 0xB7, 0x26, 0x99, 0xC7]), "Basic data"),
+    (guid(0x0FC63DAF, 0x8483, 0x4772, [0x8E, 0x79, 0x3D, 0x69, 0xD8, 0x47, 0x7D, 0xE4]), "Linux filesystem"),
+    (guid(0x0657FD6D, 0xA4AB, 0x43C4, [0x84, 0xE5, 0x09, 0x33, 0xC8, 0x4B, 0x4F, 0x4F]), "Linux swap"),
+    (guid(0x21686148, 0x6449, 0x6E6F, [0x74, 0x4E, 0x65, 0x65, 0x64, 0x45, 0x46, 0x49]), "BIOS boot"),
+];
+
+/// A GUID in its on-disk form (first three fields little-endian)
+const fn guid(a: u32, b: u16, c: u16, d: [u8; 8]) -> [u8; 16] {
+    let a = a.to_le_bytes();
+    let b = b.to_le_bytes();
+    let c = c.to_le_bytes();
+    [a[0], a[1], a[2], a[3], b[0], b[1], c[0], c[1], d[0], d[1], d[2], d[3], d[4], d[5], d[6], d[7]]
+}
+
+/// Format an on-disk GUID the usual way (8-4-4-4-12)
+fn format_guid(g: &[u8; 16]) -> String {
+    format!(
+        "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-{:02X}{:02X}{:02X}{:02X}{:02X}{:02X}",
+        u32::from_le_bytes([g[0], g[1], g[2], g[3]]),
+        u16::from_le_bytes([g[4], g[5]]),
+        u16::from_le_bytes([g[6], g[7]]),
+        g[8], g[9], g[10], g[11], g[12], g[13], g[14], g[15],
+    )
+}
+
+/// One entry of a partition table
+#[derive(Debug, Clone)]
+pub struct PartitionEntry {
+    /// Partition number: MBR primaries are 1-4 and logicals 5 and up,
+    /// GPT entries are numbered by their slot in the entry array
+    pub number: u32,
+    /// First block on the disk
+    pub start: u64,
+    /// Length in blocks
+    pub blocks: u64,
+    pub kind: PartitionType,
+    /// GPT partition name (empty for MBR)
+    pub label: String,
+}
+
+/// A disk's partition table
+#[derive(Debug, Clone)]
+pub struct PartitionTable {
+    pub kind: TableKind,
+    pub entries: Vec<PartitionEntry>,
+}
+
+/// Read the partition table of `disk`
+pub fn read_table(disk: &dyn BlockDevice) -> Result<PartitionTable, PartitionError> {
+    let mut mbr = vec![0u8; disk.block_size()];
+    disk.read_blocks(0, &mut mbr)?;
+    if mbr[510] != 0x55 || mbr[511] != 0xAA {
+        return Err(PartitionError::NoTable);
+    }
+
+    if mbr_entries(&mbr).iter().any(|entry| entry.kind == MBR_GPT_PROTECTIVE) {
+        return read_gpt(disk);
+    }
+
+    let mut entries = Vec::new();
+    for (index, entry) in mbr_entries(&mbr).iter().enumerate() {
+        if entry.kind == 0 || entry.count == 0 {
+            continue;
+        }
+        if MBR_EXTENDED_TYPES.contains(&entry.kind) {
+            read_logical(disk, entry.start, &mut entries)?;
+            continue;
+        }
+        entries.push(checked_entry(disk, index as u32 + 1, entry.start, entry.count, PartitionType::Mbr(entry.kind), String::new())?);
+    }
+    entries.sort_by_key(|entry| entry.number);
+    Ok(PartitionTable { kind: TableKind::Mbr, entries })
+}
+
+/// One of the four 16-byte MBR/EBR entries
+struct MbrEntry {
+    kind: u8,
+    start: u64,
+    count: u64,
+}
+
+fn mbr_entries(sector: &[u8]) -> [MbrEntry; 4] {
+    core::array::from_fn(|i| {
+        let raw = &sector[MBR_ENTRIES + i * 16..MBR_ENTRIES + (i + 1) * 16];
+        MbrEntry {
+            kind: raw[4],
+            start: u32::from_le_bytes([raw[8], raw[9], raw[10], raw[11]]) as u64,
+            count: u32::from_le_bytes([raw[12], raw[13], raw[14], raw[15]]) as u64,
+        }
+    })
+}
+
+/// Follow the EBR chain of the extended partition starting at `extended_start`
+///
+/// Each EBR's first entry is a logical partition relative to the EBR
+/// itself; its second entry links to the next EBR, relative to the start
+/// of the extended partition.
+fn read_logical(disk: &dyn BlockDevice, extended_start: u64, entries: &mut Vec<PartitionEntry>) -> Result<(), PartitionError> {
+    let mut sector = vec![0u8; disk.block_size()];
+    let mut ebr = extended_start;
+
+    for number in 5..5 + MAX_LOGICAL_PARTITIONS as u32 {
+        if ebr >= disk.block_count() {
+            return Err(PartitionError::BadEntry);
+        }
+        disk.read_blocks(ebr, &mut sector)?;
+        if sector[510] != 0x55 || sector[511] != 0xAA {
+            return Err(PartitionError::BadEntry);
+        }
+
+        let [logical, next, ..] = mbr_entries(&sector);
+        if logical.kind != 0 && logical.count != 0 {
+            entries.push(checked_entry(disk, number, ebr + logical.start, logical.count, PartitionType::Mbr(logical.kind), String::new())?);
+        }
+        if next.kind == 0 || next.start == 0 {
+            return Ok(());
+        }
+        ebr = extended_start + next.start;
+    }
+    Ok(())
+}
+
+/// A GPT header's fields that matter here
+struct GptHeader {
+    entries_lba: u64,
+    entry_count: usize,
+    entry_size: usize,
+    entries_crc: u32,
+}
+
+/// Read the GPT, falling back to the backup if the primary is damaged
+fn read_gpt(disk: &dyn BlockDevice) -> Result<PartitionTable, PartitionError> {
+    let last_lba = disk.block_count().saturating_sub(1);
+    let mut found = None;
+    for header_lba in [1, last_lba] {
+        if let Some(header) = read_gpt_header(disk, header_lba)? {
+            if let Some(entries) = read_gpt_entries(disk, &header)? {
+                found = Some((header, entries));
+                break;
+            }
+        }
+        crate::serial_println!("partition: GPT at block {} is damaged", header_lba);
+    }
+    let (header, raw) = found.ok_or(PartitionError::BadGpt)?;
+
+    let mut entries = Vec::new();
+    for (index, entry) in raw.chunks(header.entry_size).enumerate() {
+        let mut kind = [0u8; 16];
+        kind.copy_from_slice(&entry[0..16]);
+        if kind == [0; 16] {
+            continue; // unused slot
+        }
+        let first = u64::from_le_bytes(entry[32..40].try_into().unwrap_or_default());
+        let last = u64::from_le_bytes(entry[40..48].try_into().unwrap_or_default());
+        if last < first {
+            return Err(PartitionError::BadEntry);
+        }
+        // The name is up to 36 UTF-16LE units, zero padded
+        let units: Vec<u16> = entry[56..128]
+            .chunks(2)
+            .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+            .take_while(|&unit| unit != 0)
+            .collect();
+        let label = String::from_utf16_lossy(&units);
+        entries.push(checked_entry(disk, index as u32 + 1, first, last - first + 1, PartitionType::Gpt(kind), label)?);
+    }
+    Ok(PartitionTable { kind: TableKind::Gpt, entries })
+}
+
+/// Read and validate the GPT header at `lba`; None if it is not valid
+fn read_gpt_header(disk: &dyn BlockDevice, lba: u64) -> Result<Option<GptHeader>, PartitionError> {
+    let mut sector = vec![0u8; disk.block_size()];
+    disk.read_blocks(lba, &mut sector)?;
+
+    let u32_at = |at: usize| u32::from_le_bytes([sector[at], sector[at + 1], sector[at + 2], sector[at + 3]]);
+    let u64_at = |at: usize| u64::from_le_bytes(sector[at..at + 8].try_into().unwrap_or_default());
+
+    let header_size = u32_at(12) as usize;
+    if &sector[0..8] != b"EFI PART" || !(92..=sector.len()).contains(&header_size) || u64_at(24) != lba {
+        return Ok(None);
+    }
+
+    // The CRC covers the header with its own CRC field zeroed
+    let stored_crc = u32_at(16);
+    let mut header = sector[..header_size].to_vec();
+    header[16..20].fill(0);
+    if crc32(&header) != stored_crc {
+        return Ok(None);
+    }
+
+    let entry_size = u32_at(84) as usize;
+    let entry_count = u32_at(80) as usize;
+    if entry_size < 128 || !entry_size.is_multiple_of(8) || entry_count.saturating_mul(entry_size) > MAX_GPT_ENTRY_BYTES {
+        return Ok(None);
+    }
+
+    Ok(Some(GptHeader {
+        entries_lba: u64_at(72),
+        entry_count,
+        entry_size,
+        entries_crc: u32_at(88),
+    }))
+}
+
+/// Read the entry array a header points at; None if its CRC does not match
+fn read_gpt_entries(disk: &dyn BlockDevice, header: &GptHeader) -> Result<Option<Vec<u8>>, PartitionError> {
+    let len = header.entry_count * header.entry_size;
+    let mut entries = vec![0u8; len];
+    let read = block::read_bytes(disk, header.entries_lba * disk.block_size() as u64, &mut entries)?;
+    if read != len || crc32(&entries) != header.entries_crc {
+        return Ok(None);
+    }
+    Ok(Some(entries))
+}
+
+/// Build an entry, rejecting partitions that do not fit on the disk
+fn checked_entry(disk: &dyn BlockDevice, number: u32, start: u64, blocks: u64, kind: PartitionType, label: String) -> Result<PartitionEntry, PartitionError> {
+    match start.checked_add(blocks) {
+        Some(end) if start > 0 && end <= disk.block_count() => Ok(PartitionEntry { number, start, blocks, kind, label }),
+        _ => Err(PartitionError::BadEntry),
+    }
+}
+
+/// CRC-32 (IEEE 802.3), as GPT uses for its header and entries
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/// A partition as a block device of its own
+pub struct Partition {
+    disk: Arc<RequestQueue>,
+    start: u64,
+    blocks: u64,
+}
+
+impl BlockDevice for Partition {
+    fn block_size(&self) -> usize {
+        self.disk.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.blocks
+    }
+
+    fn read_blocks(&self, start: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self, start, buffer.len())?;
+        self.disk.read_blocks(self.start + start, buffer)
+    }
+
+    fn write_blocks(&self, start: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        check_request(self, start, buffer.len())?;
+        self.disk.write_blocks(self.start + start, buffer)
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.disk.flush()
+    }
+}
+
+/// A registered partition, for lsblk
+#[derive(Clone)]
+pub struct PartitionInfo {
+    /// Device name of the partition, e.g. "hda1"
+    pub name: String,
+    pub entry: PartitionEntry,
+}
+
+/// A scanned disk: its table kind and partitions
+#[derive(Clone)]
+pub struct DiskInfo {
+    pub name: String,
+    pub table: TableKind,
+    pub partitions: Vec<PartitionInfo>,
+}
+
+lazy_static! {
+    /// Disks whose partitions have been registered
+    static ref DISKS: Mutex<Vec<DiskInfo>> = Mutex::new(Vec::new());
+}
+
+/// Name of partition `number` of `disk`: "hda1", but "ram0p1"
+fn partition_name(disk: &str, number: u32) -> String {
+    if disk.ends_with(|c: char| c.is_ascii_digit()) {
+        format!("{}p{}", disk, number)
+    } else {
+        format!("{}{}", disk, number)
+    }
+}
+
+/// Read the partition table of registered disk `name` and register its
+/// partitions; a disk is only scanned once
+pub fn scan(name: &str) -> Result<DiskInfo, VfsError> {
+    if let Some(disk) = DISKS.lock().iter().find(|disk| disk.name == name) {
+        return Ok(disk.clone());
+    }
+    let queue = block::get(name).ok_or(VfsError::NotFound)?;
+    let table = read_table(queue.as_ref()).map_err(|e| match e {
+        PartitionError::NoTable => VfsError::NotFound,
+        PartitionError::Io(e) => e.into(),
+        PartitionError::BadGpt | PartitionError::BadEntry => VfsError::InvalidArgument,
+    })?;
+
+    let mut partitions = Vec::new();
+    for entry in table.entries {
+        let part_name = partition_name(name, entry.number);
+        let device = Arc::new(Partition {
+            disk: queue.clone(),
+            start: entry.start,
+            blocks: entry.blocks,
+        });
+        block::register_stacked(&part_name, device)?;
+        partitions.push(PartitionInfo { name: part_name, entry });
+    }
+
+    let disk = DiskInfo { name: String::from(name), table: table.kind, partitions };
+    DISKS.lock().push(disk.clone());
+    Ok(disk)
+}
+
+/// Scan every registered disk that is not itself a partition
+pub fn scan_all() {
+    for queue in block::devices() {
+        if find(queue.name()).is_some() {
+            continue;
+        }
+        match scan(queue.name()) {
+            Ok(disk) => crate::println!("  {}: {:?} partition table, {} partition(s)", disk.name, disk.table, disk.partitions.len()),
+            Err(VfsError::NotFound) => {}
+            Err(e) => crate::println!("  {}: bad partition table: {}", queue.name(), e),
+        }
+    }
+}
+
+/// The partition registered as `name`, if it is one
+pub fn find(name: &str) -> Option<PartitionInfo> {
+    DISKS
+        .lock()
+        .iter()
+        .flat_map(|disk| disk.partitions.iter())
+        .find(|part| part.name == name)
+        .cloned()
+}
+
+/// The scan result for disk `name`, if it has been scanned and has a table
+pub fn disk(name: &str) -> Option<DiskInfo> {
+    DISKS.lock().iter().find(|disk| disk.name == name).cloned()
+}
@@ -1,4 +1,6 @@
 use bootloader_api::info::{MemoryRegion, MemoryRegionKind};
+use x86_64::structures::paging::{FrameAllocator, Size4KiB};
+use x86_64::PhysAddr;
 
 /// Size of a physical frame (4 KiB)
 pub const FRAME_SIZE: usize = 4096;
@@ -19,13 +21,11 @@ pub struct PhysFrame(pub u64);
 
 impl PhysFrame {
     /// Create a frame from a physical address (must be 4K aligned)
-    #[allow(dead_code)]
     pub fn containing_address(addr: u64) -> Self {
         PhysFrame(addr / FRAME_SIZE as u64)
     }
 
     /// Get the start address of this frame
-    #[allow(dead_code)]
     pub fn start_address(&self) -> u64 {
         self.0 * FRAME_SIZE as u64
     }
@@ -36,7 +36,6 @@ pub struct BitmapFrameAllocator {
     /// Memory regions from bootloader
     memory_regions: &'static [MemoryRegion],
     /// Next frame to check for allocation
-    #[allow(dead_code)]
     next_frame: usize,
 }
 
@@ -84,7 +83,6 @@ impl BitmapFrameAllocator {
     }
 
     /// Mark a frame as used (bit = 1)
-    #[allow(dead_code)]
     fn mark_frame_used(&mut self, frame: usize) {
         if frame < MAX_FRAMES {
             let byte_idx = frame / 8;
@@ -96,7 +94,6 @@ impl BitmapFrameAllocator {
     }
 
     /// Check if a frame is free
-    #[allow(dead_code)]
     fn is_frame_free(&self, frame: usize) -> bool {
         if frame >= MAX_FRAMES {
             return false;
@@ -107,7 +104,6 @@ impl BitmapFrameAllocator {
     }
 
     /// Allocate a single physical frame
-    #[allow(dead_code)]
     pub fn allocate_frame(&mut self) -> Option<PhysFrame> {
         // Start from next_frame and wrap around
         for offset in 0..MAX_FRAMES {
@@ -122,7 +118,6 @@ impl BitmapFrameAllocator {
     }
 
     /// Free a previously allocated frame
-    #[allow(dead_code)]
     pub fn deallocate_frame(&mut self, frame: PhysFrame) {
         self.mark_frame_free(frame.0 as usize);
     }
@@ -139,3 +134,11 @@ impl BitmapFrameAllocator {
         count
     }
 }
+
+// Frames for new page tables come from the same bitmap
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<x86_64::structures::paging::PhysFrame<Size4KiB>> {
+        let frame = BitmapFrameAllocator::allocate_frame(self)?;
+        Some(x86_64::structures::paging::PhysFrame::containing_address(PhysAddr::new(frame.start_address())))
+    }
+}
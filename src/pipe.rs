@@ -0,0 +1,309 @@
+//! Anonymous pipes
+//!
+//! A pipe is a bounded byte buffer with a read end and a write end, each
+//! exposed as an inode so it can sit in a file descriptor table. Reads
+//! block while the buffer is empty and writers remain; writes block while
+//! it is full and readers remain.
+
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::time::Timespec;
+use crate::vfs::{current_time, next_ino, FileType, Inode, Metadata, Readiness, VfsError};
+use crate::wait::{wait_event, WaitQueue, WaitResult};
+
+/// Maximum number of bytes buffered in a pipe
+pub const PIPE_CAPACITY: usize = 4096;
+
+struct PipeState {
+    buffer: VecDeque<u8>,
+    readers: usize,
+    writers: usize,
+}
+
+/// The buffer shared by both ends of a pipe
+pub struct Pipe {
+    /// Both ends share one inode number, as on Unix
+    ino: u64,
+    created: Timespec,
+    state: Mutex<PipeState>,
+    /// Woken when data arrives or the last writer goes away
+    read_wait: WaitQueue,
+    /// Woken when space frees up or the last reader goes away
+    write_wait: WaitQueue,
+}
+
+impl Pipe {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            ino: next_ino(),
+            created: current_time(),
+            state: Mutex::new(PipeState {
+                buffer: VecDeque::new(),
+                readers: 0,
+                writers: 0,
+            }),
+            read_wait: WaitQueue::new(),
+            write_wait: WaitQueue::new(),
+        })
+    }
+
+    fn with_state<R>(&self, f: impl FnOnce(&mut PipeState) -> R) -> R {
+        without_interrupts(|| f(&mut self.state.lock()))
+    }
+
+    /// Read up to `buffer.len()` bytes, blocking until data or EOF
+    ///
+    /// Returns 0 at EOF (empty and no writers left).
+    pub fn read(&self, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+
+        let result = wait_event(&[&self.read_wait], None, || {
+            self.with_state(|s| !s.buffer.is_empty() || s.writers == 0)
+        });
+        if result == WaitResult::Interrupted {
+            return Err(VfsError::Interrupted);
+        }
+
+        let count = self.with_state(|s| {
+            let count = buffer.len().min(s.buffer.len());
+            for (dst, src) in buffer.iter_mut().zip(s.buffer.drain(..count)) {
+                *dst = src;
+            }
+            count
+        });
+
+        if count > 0 {
+            self.write_wait.wake_all();
+        }
+        Ok(count)
+    }
+
+    /// Write all of `buffer`, blocking while the pipe is full
+    ///
+    /// Fails with `BrokenPipe` if no reader is left. If a signal interrupts
+    /// a partial write, the number of bytes already written is returned.
+    pub fn write(&self, buffer: &[u8]) -> Result<usize, VfsError> {
+        let mut written = 0;
+
+        while written < buffer.len() {
+            let result = wait_event(&[&self.write_wait], None, || {
+                self.with_state(|s| s.buffer.len() < PIPE_CAPACITY || s.readers == 0)
+            });
+            if result == WaitResult::Interrupted {
+                return if written > 0 { Ok(written) } else { Err(VfsError::Interrupted) };
+            }
+
+            match self.write_some(&buffer[written..]) {
+                Some(chunk) => written += chunk,
+                None => return Err(VfsError::BrokenPipe),
+            }
+            self.read_wait.wake_all();
+        }
+
+        Ok(written)
+    }
+
+    /// Write as much of `buffer` as fits without waiting (O_NONBLOCK)
+    ///
+    /// Fails with `WouldBlock` if the pipe is full.
+    pub fn try_write(&self, buffer: &[u8]) -> Result<usize, VfsError> {
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+
+        match self.write_some(buffer) {
+            Some(0) => Err(VfsError::WouldBlock),
+            Some(count) => {
+                self.read_wait.wake_all();
+                Ok(count)
+            }
+            None => Err(VfsError::BrokenPipe),
+        }
+    }
+
+    /// Append what fits of `buffer`; `None` if no reader is left
+    fn write_some(&self, buffer: &[u8]) -> Option<usize> {
+        self.with_state(|s| {
+            if s.readers == 0 {
+                return None;
+            }
+            let chunk = (PIPE_CAPACITY - s.buffer.len()).min(buffer.len());
+            s.buffer.extend(&buffer[..chunk]);
+            Some(chunk)
+        })
+    }
+
+    /// Readiness of the read end
+    pub fn read_readiness(&self) -> Readiness {
+        self.with_state(|s| Readiness {
+            readable: !s.buffer.is_empty() || s.writers == 0,
+            writable: false,
+            hangup: s.writers == 0,
+        })
+    }
+
+    /// Readiness of the write end
+    pub fn write_readiness(&self) -> Readiness {
+        self.with_state(|s| Readiness {
+            readable: false,
+            writable: s.buffer.len() < PIPE_CAPACITY || s.readers == 0,
+            hangup: s.readers == 0,
+        })
+    }
+
+    /// Number of bytes currently buffered
+    pub fn buffered(&self) -> usize {
+        self.with_state(|s| s.buffer.len())
+    }
+
+    /// Synthesized metadata for an inode backed by this pipe
+    pub fn metadata(&self, file_type: FileType) -> Metadata {
+        let mut meta = Metadata::synthesized(self.ino, file_type, self.buffered() as u64, self.created);
+        meta.mode = 0o600;
+        meta
+    }
+
+    pub fn read_wait(&self) -> &WaitQueue {
+        &self.read_wait
+    }
+
+    pub fn write_wait(&self) -> &WaitQueue {
+        &self.write_wait
+    }
+
+    pub fn add_reader(&self) {
+        self.with_state(|s| s.readers += 1);
+    }
+
+    pub fn add_writer(&self) {
+        self.with_state(|s| s.writers += 1);
+    }
+
+    /// Drop a reader; writers are woken so they see the broken pipe
+    pub fn remove_reader(&self) {
+        self.with_state(|s| s.readers -= 1);
+        self.write_wait.wake_all();
+    }
+
+    /// Drop a writer; readers are woken so they see EOF
+    pub fn remove_writer(&self) {
+        self.with_state(|s| s.writers -= 1);
+        self.read_wait.wake_all();
+    }
+}
+
+/// Which end of a pipe an inode is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PipeEnd {
+    Read,
+    Write,
+}
+
+/// One end of a pipe
+pub struct PipeInode {
+    pipe: Arc<Pipe>,
+    end: PipeEnd,
+}
+
+impl PipeInode {
+    fn new(pipe: Arc<Pipe>, end: PipeEnd) -> Arc<Self> {
+        match end {
+            PipeEnd::Read => pipe.add_reader(),
+            PipeEnd::Write => pipe.add_writer(),
+        }
+        Arc::new(Self { pipe, end })
+    }
+}
+
+impl Drop for PipeInode {
+    fn drop(&mut self) {
+        match self.end {
+            PipeEnd::Read => self.pipe.remove_reader(),
+            PipeEnd::Write => self.pipe.remove_writer(),
+        }
+    }
+}
+
+impl Inode for PipeInode {
+    fn read(&self, _offset: usize, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        match self.end {
+            PipeEnd::Read => self.pipe.read(buffer),
+            PipeEnd::Write => Err(VfsError::PermissionDenied),
+        }
+    }
+
+    fn write(&self, _offset: usize, buffer: &[u8]) -> Result<usize, VfsError> {
+        match self.end {
+            PipeEnd::Write => self.pipe.write(buffer),
+            PipeEnd::Read => Err(VfsError::PermissionDenied),
+        }
+    }
+
+    fn write_nonblocking(&self, _offset: usize, buffer: &[u8]) -> Result<usize, VfsError> {
+        match self.end {
+            PipeEnd::Write => self.pipe.try_write(buffer),
+            PipeEnd::Read => Err(VfsError::PermissionDenied),
+        }
+    }
+
+    fn file_type(&self) -> FileType {
+        FileType::Fifo
+    }
+
+    fn size(&self) -> usize {
+        self.pipe.buffered()
+    }
+
+    fn lookup(&self, _name: &str) -> Result<Arc<dyn Inode>, VfsError> {
+        Err(VfsError::NotADirectory)
+    }
+
+    fn create(&self, _name: &str, _file_type: FileType) -> Result<Arc<dyn Inode>, VfsError> {
+        Err(VfsError::NotADirectory)
+    }
+
+    fn list(&self) -> Result<Vec<String>, VfsError> {
+        Err(VfsError::NotADirectory)
+    }
+
+    fn truncate(&self, _size: usize) -> Result<(), VfsError> {
+        Err(VfsError::InvalidOperation)
+    }
+
+    fn remove(&self, _name: &str) -> Result<(), VfsError> {
+        Err(VfsError::NotADirectory)
+    }
+
+    fn metadata(&self) -> Metadata {
+        self.pipe.metadata(FileType::Fifo)
+    }
+
+    fn poll(&self) -> Readiness {
+        match self.end {
+            PipeEnd::Read => self.pipe.read_readiness(),
+            PipeEnd::Write => self.pipe.write_readiness(),
+        }
+    }
+
+    fn wait_queue(&self) -> Option<&WaitQueue> {
+        match self.end {
+            PipeEnd::Read => Some(self.pipe.read_wait()),
+            PipeEnd::Write => Some(self.pipe.write_wait()),
+        }
+    }
+}
+
+/// Create a pipe, returning its (read end, write end)
+pub fn pipe() -> (Arc<PipeInode>, Arc<PipeInode>) {
+    let pipe = Pipe::new();
+    let reader = PipeInode::new(pipe.clone(), PipeEnd::Read);
+    let writer = PipeInode::new(pipe, PipeEnd::Write);
+    (reader, writer)
+}
@@ -0,0 +1,107 @@
+//! poll and select: wait for readiness on a set of inodes
+//!
+//! Both syscalls reduce to `poll_inodes`, which checks `Inode::poll` on
+//! every entry and sleeps on their wait queues until one becomes ready,
+//! the deadline passes or a signal arrives.
+
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::vfs::{Inode, Readiness, VfsError};
+use crate::wait::{wait_event, WaitQueue, WaitResult};
+
+/// Poll event bits (`PollFd::events` / `PollFd::revents`)
+pub const POLLIN: i16 = 0x001;
+pub const POLLOUT: i16 = 0x004;
+pub const POLLERR: i16 = 0x008;
+pub const POLLHUP: i16 = 0x010;
+pub const POLLNVAL: i16 = 0x020;
+
+/// Maximum number of descriptors in a select fd_set
+pub const FD_SETSIZE: usize = 1024;
+
+/// An entry of the poll array in user memory (struct pollfd)
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PollFd {
+    pub fd: i32,
+    pub events: i16,
+    pub revents: i16,
+}
+
+/// A select descriptor bitmap (fd_set)
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct FdSet {
+    pub bits: [u64; FD_SETSIZE / 64],
+}
+
+impl FdSet {
+    pub const fn new() -> Self {
+        Self { bits: [0; FD_SETSIZE / 64] }
+    }
+
+    pub fn contains(&self, fd: usize) -> bool {
+        fd < FD_SETSIZE && self.bits[fd / 64] & (1 << (fd % 64)) != 0
+    }
+
+    pub fn insert(&mut self, fd: usize) {
+        if fd < FD_SETSIZE {
+            self.bits[fd / 64] |= 1 << (fd % 64);
+        }
+    }
+}
+
+impl Default for FdSet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Translate inode readiness into poll event bits
+pub fn readiness_events(readiness: Readiness) -> i16 {
+    let mut events = 0;
+    if readiness.readable {
+        events |= POLLIN;
+    }
+    if readiness.writable {
+        events |= POLLOUT;
+    }
+    if readiness.hangup {
+        events |= POLLHUP;
+    }
+    events
+}
+
+/// Wait until at least one inode reports a requested event
+///
+/// `entries` pairs each inode (`None` for a bad descriptor) with the
+/// events wanted. POLLERR, POLLHUP and POLLNVAL are always reported.
+/// Returns the events that occurred for every entry; all zero means the
+/// deadline passed first.
+pub fn poll_inodes(entries: &[(Option<Arc<dyn Inode>>, i16)], deadline: Option<u64>) -> Result<Vec<i16>, VfsError> {
+    let queues: Vec<&WaitQueue> = entries
+        .iter()
+        .filter_map(|(inode, _)| inode.as_ref().and_then(|inode| inode.wait_queue()))
+        .collect();
+
+    let mut revents = vec![0; entries.len()];
+
+    let result = wait_event(&queues, deadline, || {
+        let mut any = false;
+        for ((inode, events), revents) in entries.iter().zip(revents.iter_mut()) {
+            *revents = match inode {
+                Some(inode) => readiness_events(inode.poll()) & (*events | POLLERR | POLLHUP),
+                None => POLLNVAL,
+            };
+            any |= *revents != 0;
+        }
+        any
+    });
+
+    match result {
+        WaitResult::Interrupted => Err(VfsError::Interrupted),
+        WaitResult::Ready | WaitResult::TimedOut => Ok(revents),
+    }
+}
@@ -3,6 +3,15 @@
 
 use core::arch::asm;
 use alloc::vec::Vec;
+use alloc::sync::Arc;
+use alloc::collections::BTreeMap;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::dcache::Dentry;
+use crate::seccomp::FilterChain;
+use crate::strace::SyscallTrace;
+use crate::vfs::FileDescriptorTable;
+use crate::vma::AddressSpace;
 
 /// Represents a process control block.
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -245,13 +254,22 @@ impl RoundRobinScheduler {
     }
 
     /// Get the next process to run (round-robin)
+    ///
+    /// Blocked processes and zombies are skipped; if nothing else is
+    /// runnable the current process is returned.
     pub fn get_next(&mut self) -> Option<&mut ProcessControlBlock> {
         if self.processes.is_empty() {
             return None;
         }
 
         // Move to next process in round-robin fashion
-        self.current_index = (self.current_index + 1) % self.processes.len();
+        for _ in 0..self.processes.len() {
+            self.current_index = (self.current_index + 1) % self.processes.len();
+            match self.processes[self.current_index].state {
+                ProcessState::Ready | ProcessState::Running => break,
+                ProcessState::Blocked | ProcessState::Zombie => {}
+            }
+        }
         Some(&mut self.processes[self.current_index])
     }
 
@@ -269,6 +287,16 @@ impl RoundRobinScheduler {
     pub fn process_count(&self) -> usize {
         self.processes.len()
     }
+
+    /// Get the PIDs of all processes in the queue
+    pub fn pids(&self) -> Vec<u32> {
+        self.processes.iter().map(|p| p.pid).collect()
+    }
+
+    /// Find a process by PID
+    pub fn find_mut(&mut self, pid: u32) -> Option<&mut ProcessControlBlock> {
+        self.processes.iter_mut().find(|p| p.pid == pid)
+    }
 }
 
 /// Global process manager
@@ -301,7 +329,6 @@ impl ProcessManager {
     /// # Safety
     /// The entry point must be a valid function pointer that never returns.
     /// The stack must be properly aligned and valid.
-    #[allow(dead_code)]
     pub unsafe fn spawn_kernel_thread(
         &mut self,
         entry_point: extern "C" fn() -> !,
@@ -328,51 +355,291 @@ impl ProcessManager {
         pid
     }
 
-    /// Perform a context switch to the next ready process
+    /// Pick the next ready process and mark it running
     ///
-    /// # Safety
-    /// This should only be called from an interrupt handler or with interrupts disabled
-    pub unsafe fn schedule(&mut self) {
+    /// Returns the contexts to switch between, or `None` if the current
+    /// process keeps the CPU. The switch itself is left to the caller (see
+    /// `preempt`) so it can happen after the manager lock is released.
+    pub fn schedule(&mut self) -> Option<(*mut CpuContext, *const CpuContext)> {
         // Get current and next process
-        let current = match self.scheduler.get_current() {
-            Some(p) => p as *mut ProcessControlBlock,
-            None => return,
-        };
-
-        let next = match self.scheduler.get_next() {
-            Some(p) => p as *mut ProcessControlBlock,
-            None => return,
-        };
+        let current = self.scheduler.get_current()? as *mut ProcessControlBlock;
+        let next = self.scheduler.get_next()? as *mut ProcessControlBlock;
 
         // Don't switch if it's the same process
         if current == next {
-            return;
+            return None;
         }
 
-        // Update states
-        (*current).state = ProcessState::Ready;
-        (*next).state = ProcessState::Running;
-
-        // Perform context switch
-        let old_ctx = &mut (*current).context as *mut CpuContext;
-        let new_ctx = &(*next).context as *const CpuContext;
+        // Safety: both point into the scheduler's queue, which is not
+        // modified until we return
+        unsafe {
+            // Update states (a process that blocked or was killed keeps its state)
+            if (*current).state == ProcessState::Running {
+                (*current).state = ProcessState::Ready;
+            }
+            (*next).state = ProcessState::Running;
 
-        ContextSwitcher::switch_context(old_ctx, new_ctx);
+            Some((&mut (*current).context as *mut CpuContext, &(*next).context as *const CpuContext))
+        }
     }
 
     /// Get the number of processes
-    #[allow(dead_code)]
     pub fn process_count(&self) -> usize {
         self.scheduler.process_count()
     }
+
+    /// Get the PID of the currently running process
+    pub fn current_pid(&mut self) -> u32 {
+        self.scheduler.get_current().map(|p| p.pid).unwrap_or(0)
+    }
+
+    /// Get the PIDs of all processes
+    pub fn pids(&self) -> Vec<u32> {
+        self.scheduler.pids()
+    }
+
+    /// Terminate a process; it becomes a zombie and is no longer scheduled
+    pub fn kill(&mut self, pid: u32) {
+        if let Some(pcb) = self.scheduler.find_mut(pid) {
+            pcb.state = ProcessState::Zombie;
+        }
+    }
+
+    /// Block a process until `wake` is called for it
+    pub fn block(&mut self, pid: u32) {
+        if let Some(pcb) = self.scheduler.find_mut(pid) {
+            if pcb.state != ProcessState::Zombie {
+                pcb.state = ProcessState::Blocked;
+            }
+        }
+    }
+
+    /// Make a blocked process runnable again
+    pub fn wake(&mut self, pid: u32) {
+        if let Some(pcb) = self.scheduler.find_mut(pid) {
+            if pcb.state == ProcessState::Blocked {
+                pcb.state = ProcessState::Ready;
+            }
+        }
+    }
+
+    /// Check whether a process is blocked
+    pub fn is_blocked(&mut self, pid: u32) -> bool {
+        self.scheduler
+            .find_mut(pid)
+            .is_some_and(|pcb| pcb.state == ProcessState::Blocked)
+    }
+}
+
+/// Per-process kernel resources
+///
+/// The PCB only carries what the scheduler needs for a context switch.
+/// Everything else a process owns (open files, memory mappings) lives here,
+/// keyed by PID in `PROCESS_RESOURCES`.
+pub struct ProcessResources {
+    /// Open file descriptors
+    pub fd_table: FileDescriptorTable,
+
+    /// User address space (heap and mmap regions)
+    pub address_space: Mutex<AddressSpace>,
+
+    /// Syscall trace buffer (see `strace`)
+    pub trace: SyscallTrace,
+
+    /// Installed syscall filters (see `seccomp`); kept across exec
+    pub filters: Mutex<FilterChain>,
+
+    /// Bitmask of signals waiting to be delivered (bit N = signal N)
+    pending_signals: AtomicU64,
+
+    /// Working directory; `None` means the root
+    cwd: Mutex<Option<Arc<Dentry>>>,
+}
+
+impl ProcessResources {
+    pub fn new() -> Self {
+        Self {
+            fd_table: FileDescriptorTable::new(),
+            address_space: Mutex::new(AddressSpace::new()),
+            trace: SyscallTrace::new(),
+            filters: Mutex::new(FilterChain::new()),
+            pending_signals: AtomicU64::new(0),
+            cwd: Mutex::new(None),
+        }
+    }
+
+    /// Get the working directory (`None` for the root)
+    pub fn cwd(&self) -> Option<Arc<Dentry>> {
+        self.cwd.lock().clone()
+    }
+
+    /// Change the working directory
+    pub fn set_cwd(&self, dentry: Arc<Dentry>) {
+        *self.cwd.lock() = Some(dentry);
+    }
+
+    /// Get the bitmask of pending signals
+    pub fn pending_signals(&self) -> u64 {
+        self.pending_signals.load(Ordering::Acquire)
+    }
+
+    /// Take and clear the pending signals, for delivery on return to user mode
+    #[allow(dead_code)]
+    pub fn take_pending_signals(&self) -> u64 {
+        self.pending_signals.swap(0, Ordering::AcqRel)
+    }
+
+    /// Give back everything the process holds (on exit)
+    ///
+    /// The structure itself may outlive the process while something still
+    /// holds a reference, so it is emptied in place.
+    fn release(&self) {
+        self.fd_table.close_all();
+        *self.address_space.lock() = AddressSpace::new();
+        *self.cwd.lock() = None;
+    }
+
+    /// Create the resources of a forked child
+    ///
+    /// Syscall filters are inherited so a sandboxed process cannot escape
+    /// by forking. The child starts in the parent's working directory, with
+    /// copies of its descriptors that share the open files.
+    pub fn fork(&self) -> Self {
+        let child = Self {
+            fd_table: self.fd_table.fork(),
+            ..Self::new()
+        };
+        *child.filters.lock() = self.filters.lock().clone();
+        *child.cwd.lock() = self.cwd();
+        child
+    }
+}
+
+impl Default for ProcessResources {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 use spin::Mutex;
 use lazy_static::lazy_static;
+use x86_64::instructions::interrupts::without_interrupts;
 
 lazy_static! {
     /// Global process manager instance
     pub static ref PROCESS_MANAGER: Mutex<ProcessManager> = Mutex::new(ProcessManager::new());
+
+    /// Resources owned by each process, keyed by PID
+    static ref PROCESS_RESOURCES: Mutex<BTreeMap<u32, Arc<ProcessResources>>> =
+        Mutex::new(BTreeMap::new());
+}
+
+/// Signal numbers
+pub const SIGALRM: u32 = 14;
+
+/// Get the PID of the currently running process
+pub fn current_pid() -> u32 {
+    without_interrupts(|| PROCESS_MANAGER.lock().current_pid())
+}
+
+/// Preempt the current process from the timer interrupt
+///
+/// The manager lock is released and `end_of_interrupt` is called before
+/// switching, so the next process can take the lock and keeps receiving
+/// timer interrupts. A tick that finds the lock held (the interrupted code
+/// is inside the manager) skips scheduling.
+///
+/// # Safety
+/// Must be called from the timer interrupt handler, with interrupts disabled.
+pub unsafe fn preempt(end_of_interrupt: impl FnOnce()) {
+    let switch = PROCESS_MANAGER.try_lock().and_then(|mut manager| manager.schedule());
+    end_of_interrupt();
+
+    // The contexts live in the scheduler's queue; with interrupts off
+    // nothing can change it before the switch has read and written them
+    if let Some((old_ctx, new_ctx)) = switch {
+        ContextSwitcher::switch_context(old_ctx, new_ctx);
+    }
+}
+
+/// Get the PIDs of all processes
+pub fn pids() -> Vec<u32> {
+    without_interrupts(|| PROCESS_MANAGER.lock().pids())
+}
+
+/// Get the number of processes
+pub fn process_count() -> usize {
+    without_interrupts(|| PROCESS_MANAGER.lock().process_count())
+}
+
+/// Mark a process a zombie so it is no longer scheduled (see `exit`)
+fn kill(pid: u32) {
+    without_interrupts(|| PROCESS_MANAGER.lock().kill(pid));
+}
+
+/// Get the resources of a process, creating an empty set on first use
+///
+/// Safe to call from interrupt handlers: the table lock is only ever held
+/// with interrupts disabled.
+pub fn resources(pid: u32) -> Arc<ProcessResources> {
+    without_interrupts(|| {
+        PROCESS_RESOURCES
+            .lock()
+            .entry(pid)
+            .or_insert_with(|| Arc::new(ProcessResources::new()))
+            .clone()
+    })
+}
+
+/// Get the resources of a process if it has any, without creating them
+pub fn find_resources(pid: u32) -> Option<Arc<ProcessResources>> {
+    without_interrupts(|| PROCESS_RESOURCES.lock().get(&pid).cloned())
+}
+
+/// Mark a signal pending for a process
+///
+/// There is no user handler delivery yet; the signal stays pending and
+/// interrupts any sleep the process is in.
+pub fn send_signal(pid: u32, signal: u32) {
+    if signal == 0 || signal >= 64 {
+        return;
+    }
+    resources(pid).pending_signals.fetch_or(1 << signal, Ordering::AcqRel);
+}
+
+/// Get the resources of the currently running process
+pub fn current_resources() -> Arc<ProcessResources> {
+    resources(current_pid())
+}
+
+/// Set up the resources of a child process forked from `parent`
+///
+/// Not called yet: there is no fork syscall.
+#[allow(dead_code)]
+pub fn fork_resources(parent: u32, child: u32) {
+    let inherited = Arc::new(resources(parent).fork());
+    without_interrupts(|| PROCESS_RESOURCES.lock().insert(child, inherited));
+}
+
+/// Release all resources held by a process
+///
+/// Closing its descriptors drops flock locks on files no one else has
+/// open; its record locks are dropped explicitly. Unmapping its address
+/// space frees the frames it faulted in.
+pub fn release_resources(pid: u32) {
+    if let Some(resources) = without_interrupts(|| PROCESS_RESOURCES.lock().remove(&pid)) {
+        resources.release();
+    }
+    crate::filelock::release_process(pid);
+}
+
+/// Terminate a process and release its resources
+///
+/// This is the one way a process dies: it becomes a zombie, so it is not
+/// scheduled again, and everything it held is given back.
+pub fn exit(pid: u32) {
+    kill(pid);
+    release_resources(pid);
 }
 
 /// Initialize the process manager (must be called once at boot)
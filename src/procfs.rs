@@ -2,8 +2,8 @@
 //!
 //! Provides virtual files for system and process information like /proc/meminfo
 
-use crate::vfs::{Inode, FileType, VfsError};
-use alloc::string::String;
+use crate::vfs::{current_time, next_ino, DirEntry, Filesystem, Inode, FileType, Metadata, VfsError};
+use alloc::string::{String, ToString};
 use alloc::vec::Vec;
 use alloc::sync::Arc;
 use alloc::format;
@@ -13,21 +13,49 @@ use spin::Mutex;
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ProcFileType {
     MemInfo,  // Memory statistics
+    PageCache, // Page cache hit/miss and writeback counters
+    Buffers,   // Buffer cache hit/miss and writeback counters
+    Syscalls(u32), // Syscall trace buffer of a process
     // More can be added: CpuInfo, Uptime, etc.
 }
 
+/// Inode numbers of per-process entries, derived from the PID so they stay
+/// stable even though the nodes are created on every lookup
+const PID_INO_BASE: u64 = 1 << 32;
+
+/// readdir cursors of per-process directories in the procfs root start here
+const PID_CURSOR_BASE: u64 = 1 << 32;
+
+fn pid_ino(pid: u32, entry: u64) -> u64 {
+    PID_INO_BASE + ((pid as u64) << 8) + entry
+}
+
+/// Synthesized metadata for procfs: read-only, owned by root, timestamped now
+fn proc_metadata(ino: u64, file_type: FileType, size: u64) -> Metadata {
+    let mut meta = Metadata::synthesized(ino, file_type, size, current_time());
+    meta.mode = if file_type == FileType::Directory { 0o555 } else { 0o444 };
+    meta
+}
+
 /// A proc file node
 pub struct ProcFile {
     file_type: ProcFileType,
     #[allow(dead_code)]
     name: String,
+    ino: u64,
 }
 
 impl ProcFile {
     pub fn new(file_type: ProcFileType, name: &str) -> Arc<Mutex<Self>> {
+        let ino = match file_type {
+            ProcFileType::Syscalls(pid) => pid_ino(pid, 1),
+            ProcFileType::MemInfo | ProcFileType::PageCache | ProcFileType::Buffers => next_ino(),
+        };
+
         Arc::new(Mutex::new(Self {
             file_type,
             name: String::from(name),
+            ino,
         }))
     }
 
@@ -44,6 +72,11 @@ impl ProcFile {
                     768
                 )
             }
+            ProcFileType::PageCache => crate::pagecache::render_stats(),
+            ProcFileType::Buffers => crate::bcache::render_stats(),
+            ProcFileType::Syscalls(pid) => crate::process::find_resources(pid)
+                .map(|resources| resources.trace.render())
+                .unwrap_or_default(),
         }
     }
 }
@@ -96,42 +129,216 @@ impl Inode for Mutex<ProcFile> {
     fn remove(&self, _name: &str) -> Result<(), VfsError> {
         Err(VfsError::NotADirectory)
     }
+
+    fn metadata(&self) -> Metadata {
+        let ino = self.lock().ino;
+        proc_metadata(ino, FileType::Regular, self.size() as u64)
+    }
+}
+
+/// Per-process directory (/proc/<pid>)
+pub struct ProcPidDir {
+    pid: u32,
+}
+
+impl ProcPidDir {
+    /// Entries present in every process directory
+    const ENTRIES: [&'static str; 1] = ["syscalls"];
+
+    pub fn new(pid: u32) -> Arc<Self> {
+        Arc::new(Self { pid })
+    }
+}
+
+impl Inode for ProcPidDir {
+    fn read(&self, _offset: usize, _buffer: &mut [u8]) -> Result<usize, VfsError> {
+        Err(VfsError::IsADirectory)
+    }
+
+    fn write(&self, _offset: usize, _buffer: &[u8]) -> Result<usize, VfsError> {
+        Err(VfsError::IsADirectory)
+    }
+
+    fn file_type(&self) -> FileType {
+        FileType::Directory
+    }
+
+    fn size(&self) -> usize {
+        0
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, VfsError> {
+        match name {
+            "syscalls" => Ok(ProcFile::new(ProcFileType::Syscalls(self.pid), name)),
+            _ => Err(VfsError::NotFound),
+        }
+    }
+
+    fn create(&self, _name: &str, _file_type: FileType) -> Result<Arc<dyn Inode>, VfsError> {
+        Err(VfsError::PermissionDenied)
+    }
+
+    fn list(&self) -> Result<Vec<String>, VfsError> {
+        Ok(Self::ENTRIES.iter().map(|name| name.to_string()).collect())
+    }
+
+    fn truncate(&self, _size: usize) -> Result<(), VfsError> {
+        Err(VfsError::IsADirectory)
+    }
+
+    fn remove(&self, _name: &str) -> Result<(), VfsError> {
+        Err(VfsError::PermissionDenied)
+    }
+
+    fn metadata(&self) -> Metadata {
+        proc_metadata(pid_ino(self.pid, 0), FileType::Directory, 0)
+    }
+}
+
+/// Root directory of procfs: global files plus one directory per process
+pub struct ProcFsRoot {
+    files: Mutex<Vec<(String, Arc<dyn Inode>)>>,
+    ino: u64,
+}
+
+impl Inode for ProcFsRoot {
+    fn read(&self, _offset: usize, _buffer: &mut [u8]) -> Result<usize, VfsError> {
+        Err(VfsError::IsADirectory)
+    }
+
+    fn write(&self, _offset: usize, _buffer: &[u8]) -> Result<usize, VfsError> {
+        Err(VfsError::IsADirectory)
+    }
+
+    fn file_type(&self) -> FileType {
+        FileType::Directory
+    }
+
+    fn size(&self) -> usize {
+        0
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, VfsError> {
+        // Numeric names are per-process directories
+        if let Ok(pid) = name.parse::<u32>() {
+            if crate::process::pids().contains(&pid) {
+                return Ok(ProcPidDir::new(pid));
+            }
+            return Err(VfsError::NotFound);
+        }
+
+        let files = self.files.lock();
+        for (file_name, file_inode) in files.iter() {
+            if file_name == name {
+                return Ok(file_inode.clone());
+            }
+        }
+        Err(VfsError::NotFound)
+    }
+
+    fn create(&self, _name: &str, _file_type: FileType) -> Result<Arc<dyn Inode>, VfsError> {
+        Err(VfsError::PermissionDenied)
+    }
+
+    fn list(&self) -> Result<Vec<String>, VfsError> {
+        let files = self.files.lock();
+        let mut entries: Vec<String> = files.iter().map(|(name, _)| name.clone()).collect();
+
+        for pid in crate::process::pids() {
+            entries.push(pid.to_string());
+        }
+
+        Ok(entries)
+    }
+
+    /// Global files use their position as the cursor, process directories
+    /// `PID_CURSOR_BASE + pid`, so processes coming and going do not
+    /// disturb a cursor
+    fn readdir(&self, cursor: u64) -> Result<Option<(DirEntry, u64)>, VfsError> {
+        if let Some((name, file)) = self.files.lock().get(cursor as usize) {
+            let entry = DirEntry {
+                name: name.clone(),
+                ino: file.metadata().ino,
+                file_type: file.file_type(),
+            };
+            return Ok(Some((entry, cursor + 1)));
+        }
+
+        let first_pid = cursor.saturating_sub(PID_CURSOR_BASE);
+        let mut pids = crate::process::pids();
+        pids.sort_unstable();
+        let Some(&pid) = pids.iter().find(|&&pid| pid as u64 >= first_pid) else {
+            return Ok(None);
+        };
+
+        let entry = DirEntry {
+            name: pid.to_string(),
+            ino: pid_ino(pid, 0),
+            file_type: FileType::Directory,
+        };
+        Ok(Some((entry, PID_CURSOR_BASE + pid as u64 + 1)))
+    }
+
+    fn truncate(&self, _size: usize) -> Result<(), VfsError> {
+        Err(VfsError::IsADirectory)
+    }
+
+    fn remove(&self, _name: &str) -> Result<(), VfsError> {
+        Err(VfsError::PermissionDenied)
+    }
+
+    /// Process directories come and go with the processes
+    fn cache_lookups(&self) -> bool {
+        false
+    }
+
+    fn metadata(&self) -> Metadata {
+        proc_metadata(self.ino, FileType::Directory, 0)
+    }
 }
 
 /// ProcFS - Process filesystem
 pub struct ProcFs {
-    files: Mutex<Vec<(String, Arc<dyn Inode>)>>,
+    root: Arc<ProcFsRoot>,
 }
 
 impl ProcFs {
     pub fn new() -> Self {
         let procfs = Self {
-            files: Mutex::new(Vec::new()),
+            root: Arc::new(ProcFsRoot {
+                files: Mutex::new(Vec::new()),
+                ino: next_ino(),
+            }),
         };
 
         // Create standard proc files
         procfs.add_file("meminfo", ProcFile::new(ProcFileType::MemInfo, "meminfo"));
+        procfs.add_file("pagecache", ProcFile::new(ProcFileType::PageCache, "pagecache"));
+        procfs.add_file("buffers", ProcFile::new(ProcFileType::Buffers, "buffers"));
 
         procfs
     }
 
     fn add_file(&self, name: &str, file: Arc<dyn Inode>) {
-        let mut files = self.files.lock();
+        let mut files = self.root.files.lock();
         files.push((String::from(name), file));
     }
 
     pub fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, VfsError> {
-        let files = self.files.lock();
-        for (file_name, file_inode) in files.iter() {
-            if file_name == name {
-                return Ok(file_inode.clone());
-            }
-        }
-        Err(VfsError::NotFound)
+        self.root.lookup(name)
     }
 
     pub fn list(&self) -> Result<Vec<String>, VfsError> {
-        let files = self.files.lock();
-        Ok(files.iter().map(|(name, _)| name.clone()).collect())
+        self.root.list()
+    }
+}
+
+impl Filesystem for ProcFs {
+    fn name(&self) -> &'static str {
+        "procfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
     }
 }
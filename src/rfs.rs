@@ -0,0 +1,2636 @@
+//! rfs, the native filesystem
+//!
+//! A small filesystem designed for this kernel, built to survive a crash
+//! at any point. A volume is made of 4 KiB blocks, one buffer cache
+//! buffer each:
+//!
+//! ```text
+//! superblock | journal | block bitmap | inode bitmap | inode table | data
+//! ```
+//!
+//! Files map their blocks with extents (runs of contiguous blocks): four
+//! in the inode, or, once a file needs more, up to four leaf blocks of 255
+//! extents each. Directories are B-trees keyed by a 63-bit hash of the
+//! name, whose root block never moves; two names with the same hash
+//! cannot share a directory.
+//!
+//! Every operation is one transaction. The metadata blocks it changes stay
+//! pinned in the buffer cache until it commits: they are written to the
+//! journal, then a commit block, and only then to their home locations.
+//! Mounting replays a committed transaction left in the journal and
+//! ignores one whose commit block never made it, so after a crash the tree
+//! is as it was just before or just after each operation. File data is
+//! not journaled but reaches the disk before the metadata that points at
+//! it, so a file never shows blocks it did not write.
+//!
+//! `mkfs` formats a device and `fsck` checks one (replaying the journal
+//! first). `crash_test` runs a workload on a `CrashDevice`, which drops
+//! every write after a set number of blocks as if the power had failed,
+//! then checks that the volume recovers.
+
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::{Mutex, MutexGuard};
+
+use crate::bcache::{self, Buffer};
+use crate::block::{BlockDevice, BlockError};
+use crate::partition::crc32;
+use crate::time::Timespec;
+use crate::vfs::{
+    current_time, downcast_inode, DirEntry, FileType, Filesystem, Inode, Metadata, SetAttr, VfsError, MODE_PERMISSION_MASK,
+    S_IFBLK, S_IFCHR, S_IFDIR, S_IFIFO, S_IFLNK, S_IFSOCK, STAT_BLOCK_SIZE,
+};
+
+const BLOCK_SIZE: usize = bcache::BUFFER_SIZE;
+const BITS_PER_BLOCK: u64 = (BLOCK_SIZE * 8) as u64;
+
+/// "RFS1", "RFSJ", "RFSD" and "RFSC" in little-endian byte order
+const MAGIC: u32 = 0x3153_4652;
+const JOURNAL_MAGIC: u32 = 0x4A53_4652;
+const DESCRIPTOR_MAGIC: u32 = 0x4453_4652;
+const COMMIT_MAGIC: u32 = 0x4353_4652;
+const VERSION: u32 = 1;
+/// Where the superblock keeps the CRC-32 of the bytes before it
+const SB_CHECKSUM: usize = BLOCK_SIZE - 4;
+const LABEL_LEN: usize = 16;
+
+const INODE_SIZE: usize = 128;
+const INODES_PER_BLOCK: u32 = (BLOCK_SIZE / INODE_SIZE) as u32;
+/// mkfs makes one inode per this many bytes
+const BYTES_PER_INODE: u64 = 16 * 1024;
+const MAX_INODES: u64 = 1 << 24;
+const ROOT_INO: u32 = 1;
+
+/// The journal holds one transaction at a time: a header block, then a
+/// descriptor, the block copies and a commit block
+const JOURNAL_BLOCKS: u64 = 32;
+const JOURNAL_HEADER: u64 = 0;
+const JOURNAL_DESCRIPTOR: u64 = 1;
+const JOURNAL_COPIES: u64 = 2;
+/// Most metadata blocks one transaction may change
+const MAX_TXN_BLOCKS: usize = 24;
+/// Blocks kept free in a transaction for one more step of a long
+/// operation (an extent, a bitmap block, a node split, the inode)
+const TXN_HEADROOM: usize = 8;
+/// Smallest data area mkfs accepts
+const MIN_DATA_BLOCKS: u64 = 16;
+
+/// Block header of directory and extent tree nodes: magic, level,
+/// record count and bytes of records used
+const NODE_HEADER: usize = 16;
+const DIR_NODE_MAGIC: u16 = 0x4452;
+const EXTENT_NODE_MAGIC: u16 = 0x4552;
+/// Deepest directory tree, far beyond any real directory
+const MAX_DEPTH: usize = 8;
+
+/// An extent: first file block, length, first disk block
+const EXTENT_SIZE: usize = 16;
+/// Extents (or leaf index entries) kept in the inode
+const ROOT_SLOTS: usize = 4;
+const NODE_SLOTS: usize = (BLOCK_SIZE - NODE_HEADER) / EXTENT_SIZE;
+
+/// Directory leaf record: hash, inode, type, name length, then the name
+const RECORD_HEADER: usize = 14;
+/// Directory index record: lowest hash below the child, then the child
+const INDEX_RECORD: usize = 16;
+
+const NAME_MAX: usize = 255;
+const LINK_MAX: u16 = 32000;
+const S_IFMT: u16 = 0o170000;
+
+/// First few problems `fsck` describes; the rest are only counted
+const MAX_MESSAGES: usize = 16;
+/// Inodes whose links `fsck` counts per pass over the tree
+const INODE_CHUNK: u32 = 2048;
+
+fn read_u16(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([bytes[at], bytes[at + 1]])
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}
+
+fn read_u64(bytes: &[u8], at: usize) -> u64 {
+    let mut raw = [0u8; 8];
+    raw.copy_from_slice(&bytes[at..at + 8]);
+    u64::from_le_bytes(raw)
+}
+
+fn put_u16(bytes: &mut [u8], at: usize, value: u16) {
+    bytes[at..at + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(bytes: &mut [u8], at: usize, value: u32) {
+    bytes[at..at + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u64(bytes: &mut [u8], at: usize, value: u64) {
+    bytes[at..at + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+fn from_rfs_time(secs: i64) -> Timespec {
+    Timespec { tv_sec: secs, tv_nsec: 0 }
+}
+
+fn file_type_of(mode: u16) -> FileType {
+    match (mode & S_IFMT) as u32 {
+        S_IFDIR => FileType::Directory,
+        S_IFLNK => FileType::Symlink,
+        S_IFCHR => FileType::Device,
+        S_IFBLK => FileType::BlockDevice,
+        S_IFIFO => FileType::Fifo,
+        S_IFSOCK => FileType::Socket,
+        _ => FileType::Regular,
+    }
+}
+
+/// The type byte of a directory record: the top four bits of the mode
+fn kind_of(file_type: FileType) -> u8 {
+    (file_type.mode_bits() >> 12) as u8
+}
+
+fn kind_file_type(kind: u8) -> FileType {
+    file_type_of((kind as u16) << 12)
+}
+
+/// FNV-1a with the top bit cleared, so `hash + 1` is always a valid
+/// readdir cursor
+fn name_hash(name: &[u8]) -> u64 {
+    let mut hash: u64 = 0xCBF2_9CE4_8422_2325;
+    for &byte in name {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01B3);
+    }
+    hash >> 1
+}
+
+fn validate_name(name: &str) -> Result<(), VfsError> {
+    if name.is_empty() || name == "." || name == ".." || name.len() > NAME_MAX || name.contains(['/', '\0']) {
+        return Err(VfsError::InvalidArgument);
+    }
+    Ok(())
+}
+
+/// Device blocks per filesystem block
+fn device_blocks(device: &dyn BlockDevice) -> u64 {
+    (BLOCK_SIZE / device.block_size()) as u64
+}
+
+/// Filesystem blocks on `device`, if its block size suits rfs
+fn usable_blocks(device: &dyn BlockDevice) -> Result<u64, VfsError> {
+    let block_size = device.block_size();
+    if block_size == 0 || block_size > BLOCK_SIZE || !BLOCK_SIZE.is_multiple_of(block_size) {
+        return Err(VfsError::InvalidArgument);
+    }
+    Ok(device.size() / BLOCK_SIZE as u64)
+}
+
+/// Read a journal block, bypassing the buffer cache
+fn read_raw(device: &dyn BlockDevice, block: u64, buffer: &mut [u8]) -> Result<(), VfsError> {
+    Ok(device.read_blocks(block * device_blocks(device), buffer)?)
+}
+
+/// Write a journal block, bypassing the buffer cache
+fn write_raw(device: &dyn BlockDevice, block: u64, data: &[u8]) -> Result<(), VfsError> {
+    Ok(device.write_blocks(block * device_blocks(device), data)?)
+}
+
+/// Where everything is on a volume
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Layout {
+    block_count: u64,
+    inode_count: u32,
+    journal_start: u64,
+    bitmap_start: u64,
+    inode_bitmap_start: u64,
+    inode_table_start: u64,
+    data_start: u64,
+}
+
+impl Layout {
+    /// The layout of a volume of `block_count` blocks and `inode_count` inodes
+    fn new(block_count: u64, inode_count: u32) -> Option<Self> {
+        if inode_count == 0 || !inode_count.is_multiple_of(INODES_PER_BLOCK) {
+            return None;
+        }
+        let journal_start = 1;
+        let bitmap_start = journal_start + JOURNAL_BLOCKS;
+        let inode_bitmap_start = bitmap_start + block_count.div_ceil(BITS_PER_BLOCK);
+        let inode_table_start = inode_bitmap_start + (inode_count as u64).div_ceil(BITS_PER_BLOCK);
+        let data_start = inode_table_start + (inode_count / INODES_PER_BLOCK) as u64;
+        (data_start + MIN_DATA_BLOCKS <= block_count).then_some(Self {
+            block_count,
+            inode_count,
+            journal_start,
+            bitmap_start,
+            inode_bitmap_start,
+            inode_table_start,
+            data_start,
+        })
+    }
+
+    /// The layout mkfs gives a device of `block_count` blocks
+    fn for_blocks(block_count: u64) -> Option<Self> {
+        let inodes = (block_count * BLOCK_SIZE as u64 / BYTES_PER_INODE).clamp(INODES_PER_BLOCK as u64, MAX_INODES);
+        Self::new(block_count, (inodes as u32).next_multiple_of(INODES_PER_BLOCK))
+    }
+
+    /// Read the layout from a superblock, checking it
+    fn parse(sb: &[u8]) -> Option<Self> {
+        let valid = read_u32(sb, 0) == MAGIC
+            && read_u32(sb, 4) == VERSION
+            && read_u32(sb, 8) == BLOCK_SIZE as u32
+            && read_u32(sb, 12) == INODE_SIZE as u32
+            && read_u32(sb, 28) == JOURNAL_BLOCKS as u32
+            && read_u32(sb, SB_CHECKSUM) == crc32(&sb[..SB_CHECKSUM]);
+        if !valid {
+            return None;
+        }
+        let layout = Self::new(read_u64(sb, 16), read_u32(sb, 24))?;
+        let stored = [read_u64(sb, 32), read_u64(sb, 40), read_u64(sb, 48), read_u64(sb, 56), read_u64(sb, 64)];
+        let expected = [
+            layout.journal_start,
+            layout.bitmap_start,
+            layout.inode_bitmap_start,
+            layout.inode_table_start,
+            layout.data_start,
+        ];
+        (stored == expected && read_u32(sb, 72) == ROOT_INO).then_some(layout)
+    }
+
+    fn write(&self, sb: &mut [u8], label: &str, created: i64) {
+        sb.fill(0);
+        put_u32(sb, 0, MAGIC);
+        put_u32(sb, 4, VERSION);
+        put_u32(sb, 8, BLOCK_SIZE as u32);
+        put_u32(sb, 12, INODE_SIZE as u32);
+        put_u64(sb, 16, self.block_count);
+        put_u32(sb, 24, self.inode_count);
+        put_u32(sb, 28, JOURNAL_BLOCKS as u32);
+        put_u64(sb, 32, self.journal_start);
+        put_u64(sb, 40, self.bitmap_start);
+        put_u64(sb, 48, self.inode_bitmap_start);
+        put_u64(sb, 56, self.inode_table_start);
+        put_u64(sb, 64, self.data_start);
+        put_u32(sb, 72, ROOT_INO);
+        put_u64(sb, 80, created as u64);
+        let label = &label.as_bytes()[..label.len().min(LABEL_LEN)];
+        sb[88..88 + label.len()].copy_from_slice(label);
+        let checksum = crc32(&sb[..SB_CHECKSUM]);
+        put_u32(sb, SB_CHECKSUM, checksum);
+    }
+}
+
+/// Read and check the superblock of `device`
+fn read_layout(device: &Arc<dyn BlockDevice>) -> Result<Layout, VfsError> {
+    let blocks = usable_blocks(device.as_ref())?;
+    if blocks == 0 {
+        return Err(VfsError::InvalidArgument);
+    }
+    let buffer = bcache::bread(device, 0, BLOCK_SIZE)?;
+    let layout = Layout::parse(&buffer.data()).ok_or(VfsError::InvalidArgument)?;
+    if layout.block_count > blocks {
+        crate::serial_println!("rfs: volume is larger than its device");
+        return Err(VfsError::InvalidArgument);
+    }
+    Ok(layout)
+}
+
+/// An extent: `len` blocks of the file from `logical` on, stored from
+/// `physical` on. Leaf index entries have the same form, with `len` 0
+#[derive(Clone, Copy)]
+struct Extent {
+    logical: u32,
+    len: u32,
+    physical: u64,
+}
+
+impl Extent {
+    fn parse(bytes: &[u8], at: usize) -> Self {
+        Self {
+            logical: read_u32(bytes, at),
+            len: read_u32(bytes, at + 4),
+            physical: read_u64(bytes, at + 8),
+        }
+    }
+
+    fn write(&self, bytes: &mut [u8], at: usize) {
+        put_u32(bytes, at, self.logical);
+        put_u32(bytes, at + 4, self.len);
+        put_u64(bytes, at + 8, self.physical);
+    }
+
+    fn end(&self) -> u64 {
+        self.logical as u64 + self.len as u64
+    }
+
+    /// Whether file block `index` stored at `physical` would continue this extent
+    fn continued_by(&self, index: u64, physical: u64) -> bool {
+        self.end() == index && self.physical + self.len as u64 == physical && self.len < u32::MAX
+    }
+}
+
+/// Position of the entry covering file block `index`: the last one
+/// starting at or before it (or the first)
+fn covering(entries: &[Extent], index: u64) -> usize {
+    entries.iter().rposition(|entry| entry.logical as u64 <= index).unwrap_or(0)
+}
+
+/// Add file block `index` at `physical` to sorted `extents`, growing the
+/// extent before it when the two are contiguous
+fn insert_extent(extents: &mut Vec<Extent>, index: u32, physical: u64) {
+    let at = extents.partition_point(|extent| extent.logical <= index);
+    if at > 0 && extents[at - 1].continued_by(index as u64, physical) {
+        extents[at - 1].len += 1;
+    } else {
+        extents.insert(at, Extent { logical: index, len: 1, physical });
+    }
+}
+
+fn parse_leaf(node: &[u8]) -> Result<Vec<Extent>, VfsError> {
+    let count = read_u16(node, 4) as usize;
+    if read_u16(node, 0) != EXTENT_NODE_MAGIC || count > NODE_SLOTS {
+        crate::serial_println!("rfs: bad extent block");
+        return Err(VfsError::IoError);
+    }
+    Ok((0..count).map(|i| Extent::parse(node, NODE_HEADER + i * EXTENT_SIZE)).collect())
+}
+
+fn write_leaf(node: &mut [u8], extents: &[Extent]) {
+    node.fill(0);
+    put_u16(node, 0, EXTENT_NODE_MAGIC);
+    put_u16(node, 4, extents.len() as u16);
+    for (i, extent) in extents.iter().enumerate() {
+        extent.write(node, NODE_HEADER + i * EXTENT_SIZE);
+    }
+}
+
+/// Length of the directory node record at `at` in `records`
+fn record_len(level: u8, records: &[u8], at: usize) -> usize {
+    if level == 0 {
+        RECORD_HEADER + records[at + 13] as usize
+    } else {
+        INDEX_RECORD
+    }
+}
+
+/// Offsets of the records in a node's record area
+fn record_offsets(level: u8, records: &[u8]) -> impl Iterator<Item = usize> + '_ {
+    let mut at = 0;
+    core::iter::from_fn(move || {
+        (at < records.len()).then(|| {
+            let here = at;
+            at += record_len(level, records, here);
+            here
+        })
+    })
+}
+
+fn record_name(records: &[u8], at: usize) -> &[u8] {
+    &records[at + RECORD_HEADER..at + RECORD_HEADER + records[at + 13] as usize]
+}
+
+/// Check a directory node's header and that its records fill exactly the
+/// space it claims, returning its level and its records
+fn check_node(node: &[u8]) -> Result<(u8, &[u8]), VfsError> {
+    let level = node[2];
+    let count = read_u16(node, 4) as usize;
+    let used = read_u16(node, 6) as usize;
+    let mut valid = read_u16(node, 0) == DIR_NODE_MAGIC && (level as usize) < MAX_DEPTH && used <= BLOCK_SIZE - NODE_HEADER;
+    if valid {
+        let records = &node[NODE_HEADER..NODE_HEADER + used];
+        let mut at = 0;
+        let mut seen = 0;
+        while at < used {
+            let fits = if level == 0 {
+                at + RECORD_HEADER <= used && records[at + 13] != 0 && at + record_len(0, records, at) <= used
+            } else {
+                at + INDEX_RECORD <= used
+            };
+            if !fits {
+                break;
+            }
+            at += record_len(level, records, at);
+            seen += 1;
+        }
+        valid = at == used && seen == count;
+    }
+    if !valid {
+        crate::serial_println!("rfs: bad directory node");
+        return Err(VfsError::IoError);
+    }
+    Ok((level, &node[NODE_HEADER..NODE_HEADER + used]))
+}
+
+fn write_node(node: &mut [u8], level: u8, records: &[u8]) {
+    node.fill(0);
+    put_u16(node, 0, DIR_NODE_MAGIC);
+    node[2] = level;
+    put_u16(node, 4, record_offsets(level, records).count() as u16);
+    put_u16(node, 6, records.len() as u16);
+    node[NODE_HEADER..NODE_HEADER + records.len()].copy_from_slice(records);
+}
+
+fn leaf_record(hash: u64, ino: u32, kind: u8, name: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(RECORD_HEADER + name.len());
+    record.extend_from_slice(&hash.to_le_bytes());
+    record.extend_from_slice(&ino.to_le_bytes());
+    record.push(kind);
+    record.push(name.len() as u8);
+    record.extend_from_slice(name);
+    record
+}
+
+fn index_record(hash: u64, child: u64) -> [u8; INDEX_RECORD] {
+    let mut record = [0u8; INDEX_RECORD];
+    put_u64(&mut record, 0, hash);
+    put_u64(&mut record, 8, child);
+    record
+}
+
+/// Offset of the child record to follow for `hash`: the last whose hash is
+/// not above it (or the first)
+fn child_at(level: u8, records: &[u8], hash: u64) -> usize {
+    record_offsets(level, records)
+        .take_while(|&at| read_u64(records, at) <= hash)
+        .last()
+        .unwrap_or(0)
+}
+
+/// Add `record` to a node in hash order, if it fits
+fn node_insert(node: &mut [u8], record: &[u8]) -> bool {
+    let level = node[2];
+    let count = read_u16(node, 4);
+    let used = read_u16(node, 6) as usize;
+    if used + record.len() > BLOCK_SIZE - NODE_HEADER {
+        return false;
+    }
+    let hash = read_u64(record, 0);
+    let records = &node[NODE_HEADER..NODE_HEADER + used];
+    let at = NODE_HEADER + record_offsets(level, records).find(|&at| read_u64(records, at) > hash).unwrap_or(used);
+    node.copy_within(at..NODE_HEADER + used, at + record.len());
+    node[at..at + record.len()].copy_from_slice(record);
+    put_u16(node, 4, count + 1);
+    put_u16(node, 6, (used + record.len()) as u16);
+    true
+}
+
+fn node_remove(node: &mut [u8], at: usize) {
+    let level = node[2];
+    let count = read_u16(node, 4);
+    let used = read_u16(node, 6) as usize;
+    let len = record_len(level, &node[NODE_HEADER..NODE_HEADER + used], at);
+    let at = NODE_HEADER + at;
+    node.copy_within(at + len..NODE_HEADER + used, at);
+    node[NODE_HEADER + used - len..NODE_HEADER + used].fill(0);
+    put_u16(node, 4, count.saturating_sub(1));
+    put_u16(node, 6, (used - len) as u16);
+}
+
+/// A node's records with `record` added in hash order
+fn merge_record(level: u8, records: &[u8], record: &[u8]) -> Vec<u8> {
+    let hash = read_u64(record, 0);
+    let at = record_offsets(level, records).find(|&at| read_u64(records, at) > hash).unwrap_or(records.len());
+    let mut merged = Vec::with_capacity(records.len() + record.len());
+    merged.extend_from_slice(&records[..at]);
+    merged.extend_from_slice(record);
+    merged.extend_from_slice(&records[at..]);
+    merged
+}
+
+/// Where to split an overfull node's records: the first record boundary
+/// past half way
+fn split_point(level: u8, records: &[u8]) -> usize {
+    record_offsets(level, records)
+        .find(|&at| at > 0 && at >= records.len() / 2)
+        .unwrap_or(records.len())
+}
+
+/// A directory entry as stored
+struct DirRecord {
+    hash: u64,
+    ino: u32,
+    kind: u8,
+    name: String,
+}
+
+/// An inode as stored in the inode table
+struct RawInode {
+    /// Type and permission bits; 0 for a free inode
+    mode: u16,
+    links: u16,
+    uid: u32,
+    gid: u32,
+    /// Directories: the directory holding this one
+    parent: u32,
+    size: u64,
+    atime: i64,
+    mtime: i64,
+    ctime: i64,
+    /// Blocks in use, including extent leaves and directory nodes
+    blocks: u64,
+    /// Extent tree depth: 0 keeps the extents in `root`, 1 keeps leaf
+    /// block index entries there
+    depth: u16,
+    /// Entries used in `root`
+    count: u16,
+    /// Directories: number of entries
+    entries: u32,
+    /// Extents or leaf index entries; for a directory, its root node
+    root: [u8; ROOT_SLOTS * EXTENT_SIZE],
+}
+
+impl RawInode {
+    fn new(file_type: FileType, mode: u16, parent: u32) -> Self {
+        let now = current_time().tv_sec;
+        let mut inode = Self::parse(&[0; INODE_SIZE]);
+        inode.mode = file_type.mode_bits() as u16 | (mode & MODE_PERMISSION_MASK);
+        inode.links = if file_type == FileType::Directory { 2 } else { 1 };
+        inode.parent = parent;
+        inode.atime = now;
+        inode.mtime = now;
+        inode.ctime = now;
+        inode
+    }
+
+    fn parse(raw: &[u8]) -> Self {
+        let mut root = [0u8; ROOT_SLOTS * EXTENT_SIZE];
+        root.copy_from_slice(&raw[64..128]);
+        Self {
+            mode: read_u16(raw, 0),
+            links: read_u16(raw, 2),
+            uid: read_u32(raw, 4),
+            gid: read_u32(raw, 8),
+            parent: read_u32(raw, 12),
+            size: read_u64(raw, 16),
+            atime: read_u64(raw, 24) as i64,
+            mtime: read_u64(raw, 32) as i64,
+            ctime: read_u64(raw, 40) as i64,
+            blocks: read_u64(raw, 48),
+            depth: read_u16(raw, 56),
+            count: read_u16(raw, 58),
+            entries: read_u32(raw, 60),
+            root,
+        }
+    }
+
+    fn write(&self, raw: &mut [u8]) {
+        put_u16(raw, 0, self.mode);
+        put_u16(raw, 2, self.links);
+        put_u32(raw, 4, self.uid);
+        put_u32(raw, 8, self.gid);
+        put_u32(raw, 12, self.parent);
+        put_u64(raw, 16, self.size);
+        put_u64(raw, 24, self.atime as u64);
+        put_u64(raw, 32, self.mtime as u64);
+        put_u64(raw, 40, self.ctime as u64);
+        put_u64(raw, 48, self.blocks);
+        put_u16(raw, 56, self.depth);
+        put_u16(raw, 58, self.count);
+        put_u32(raw, 60, self.entries);
+        raw[64..128].copy_from_slice(&self.root);
+    }
+
+    fn file_type(&self) -> FileType {
+        file_type_of(self.mode)
+    }
+
+    fn touch(&mut self) {
+        let now = current_time().tv_sec;
+        self.mtime = now;
+        self.ctime = now;
+    }
+
+    /// The extents, or leaf index entries, kept in the inode
+    fn extents(&self) -> Result<Vec<Extent>, VfsError> {
+        if self.depth > 1 || self.count as usize > ROOT_SLOTS {
+            crate::serial_println!("rfs: bad extent tree root");
+            return Err(VfsError::IoError);
+        }
+        Ok((0..self.count as usize).map(|i| Extent::parse(&self.root, i * EXTENT_SIZE)).collect())
+    }
+
+    fn set_extents(&mut self, extents: &[Extent]) {
+        self.root.fill(0);
+        for (i, extent) in extents.iter().enumerate() {
+            extent.write(&mut self.root, i * EXTENT_SIZE);
+        }
+        self.count = extents.len() as u16;
+    }
+
+    /// A directory's B-tree root node
+    fn tree_root(&self) -> u64 {
+        read_u64(&self.root, 0)
+    }
+
+    fn set_tree_root(&mut self, block: u64) {
+        put_u64(&mut self.root, 0, block);
+    }
+}
+
+/// Write the journal header: the sequence number the next transaction
+/// will carry, which retires every transaction before it
+fn write_journal_header(device: &dyn BlockDevice, layout: &Layout, sequence: u64) -> Result<(), VfsError> {
+    let mut block = vec![0u8; BLOCK_SIZE];
+    put_u32(&mut block, 0, JOURNAL_MAGIC);
+    put_u64(&mut block, 8, sequence);
+    write_raw(device, layout.journal_start + JOURNAL_HEADER, &block)?;
+    Ok(device.flush()?)
+}
+
+/// Add a block to a transaction's checksum
+fn fold_checksum(checksum: u32, block: &[u8]) -> u32 {
+    checksum.rotate_left(5) ^ crc32(block)
+}
+
+/// Write `blocks` to the journal as transaction `sequence` and commit it
+fn journal_commit(device: &dyn BlockDevice, layout: &Layout, sequence: u64, blocks: &[Buffer]) -> Result<(), VfsError> {
+    let mut block = vec![0u8; BLOCK_SIZE];
+    put_u32(&mut block, 0, DESCRIPTOR_MAGIC);
+    put_u32(&mut block, 4, blocks.len() as u32);
+    put_u64(&mut block, 8, sequence);
+    for (i, buffer) in blocks.iter().enumerate() {
+        put_u64(&mut block, 16 + i * 8, buffer.block());
+    }
+    let mut checksum = fold_checksum(0, &block);
+    write_raw(device, layout.journal_start + JOURNAL_DESCRIPTOR, &block)?;
+    for (i, buffer) in blocks.iter().enumerate() {
+        let data = buffer.data();
+        checksum = fold_checksum(checksum, &data);
+        write_raw(device, layout.journal_start + JOURNAL_COPIES + i as u64, &data)?;
+    }
+
+    // The commit block goes out only once everything it covers is on disk
+    device.flush()?;
+    block.fill(0);
+    put_u32(&mut block, 0, COMMIT_MAGIC);
+    put_u32(&mut block, 4, blocks.len() as u32);
+    put_u64(&mut block, 8, sequence);
+    put_u32(&mut block, 16, checksum);
+    write_raw(device, layout.journal_start + JOURNAL_COPIES + blocks.len() as u64, &block)?;
+    Ok(device.flush()?)
+}
+
+/// Write back a committed transaction left in the journal by a crash
+///
+/// Returns the sequence number of the next transaction and how many blocks
+/// were replayed. A transaction without a valid commit block never
+/// happened, and is ignored.
+fn replay_journal(device: &Arc<dyn BlockDevice>, layout: &Layout) -> Result<(u64, usize), VfsError> {
+    let journal = layout.journal_start;
+    let mut block = vec![0u8; BLOCK_SIZE];
+    read_raw(device.as_ref(), journal + JOURNAL_HEADER, &mut block)?;
+    if read_u32(&block, 0) != JOURNAL_MAGIC {
+        crate::serial_println!("rfs: bad journal header");
+        return Err(VfsError::InvalidArgument);
+    }
+    let sequence = read_u64(&block, 8);
+
+    read_raw(device.as_ref(), journal + JOURNAL_DESCRIPTOR, &mut block)?;
+    let count = read_u32(&block, 4) as usize;
+    if read_u32(&block, 0) != DESCRIPTOR_MAGIC || read_u64(&block, 8) != sequence || count == 0 || count > MAX_TXN_BLOCKS {
+        return Ok((sequence, 0));
+    }
+    let homes: Vec<u64> = (0..count).map(|i| read_u64(&block, 16 + i * 8)).collect();
+    let mut checksum = fold_checksum(0, &block);
+    let mut copy = vec![0u8; BLOCK_SIZE];
+    for i in 0..count {
+        read_raw(device.as_ref(), journal + JOURNAL_COPIES + i as u64, &mut copy)?;
+        checksum = fold_checksum(checksum, &copy);
+    }
+    read_raw(device.as_ref(), journal + JOURNAL_COPIES + count as u64, &mut block)?;
+    let committed = read_u32(&block, 0) == COMMIT_MAGIC
+        && read_u32(&block, 4) as usize == count
+        && read_u64(&block, 8) == sequence
+        && read_u32(&block, 16) == checksum;
+    if !committed {
+        return Ok((sequence, 0));
+    }
+    if homes.iter().any(|&home| home >= layout.block_count || (journal..journal + JOURNAL_BLOCKS).contains(&home)) {
+        crate::serial_println!("rfs: journal transaction {} writes outside the volume", sequence);
+        return Err(VfsError::IoError);
+    }
+
+    for (i, &home) in homes.iter().enumerate() {
+        read_raw(device.as_ref(), journal + JOURNAL_COPIES + i as u64, &mut copy)?;
+        let buffer = bcache::bget(device, home, BLOCK_SIZE)?;
+        buffer.data().copy_from_slice(&copy);
+        bcache::bwrite(&buffer);
+    }
+    bcache::sync_device(device)?;
+    write_journal_header(device.as_ref(), layout, sequence + 1)?;
+    Ok((sequence + 1, count))
+}
+
+/// Clear bits of a bitmap from bit `from` up to `limit`
+fn count_clear(device: &Arc<dyn BlockDevice>, bitmap: u64, limit: u64) -> Result<u64, VfsError> {
+    let mut clear = 0;
+    for block in 0..limit.div_ceil(BITS_PER_BLOCK) {
+        let bits = (limit - block * BITS_PER_BLOCK).min(BITS_PER_BLOCK) as usize;
+        let buffer = bcache::bread(device, bitmap + block, BLOCK_SIZE)?;
+        let data = buffer.data();
+        clear += (0..bits).filter(|&i| data[i / 8] & (1 << (i % 8)) == 0).count() as u64;
+    }
+    Ok(clear)
+}
+
+/// Journal and allocation state, held for the whole of each operation
+struct VolumeState {
+    /// Sequence number of the next transaction
+    sequence: u64,
+    free_blocks: u64,
+    free_inodes: u32,
+    /// Where to look for a free block when there is no better place
+    next_block: u64,
+}
+
+/// A mounted rfs volume
+struct Volume {
+    device: Arc<dyn BlockDevice>,
+    layout: Layout,
+    /// Locked by each transaction, so operations run one at a time.
+    /// Lock order: the inode table, then this
+    state: Mutex<VolumeState>,
+    inodes: Mutex<BTreeMap<u32, Weak<RfsInode>>>,
+}
+
+impl Volume {
+    fn begin(&self) -> Txn<'_> {
+        let state = self.state.lock();
+        let saved = (state.free_blocks, state.free_inodes, state.next_block);
+        Txn {
+            volume: self,
+            state,
+            blocks: Vec::new(),
+            saved,
+            done: false,
+        }
+    }
+
+    /// Look an inode up in the live inode table, or load it
+    ///
+    /// Must not be called with a transaction open: dropping the result may
+    /// free the inode, which takes a transaction of its own.
+    fn get_inode(self: &Arc<Self>, ino: u32) -> Result<Arc<RfsInode>, VfsError> {
+        let mut inodes = self.inodes.lock();
+        if let Some(inode) = inodes.get(&ino).and_then(Weak::upgrade) {
+            return Ok(inode);
+        }
+        inodes.retain(|_, inode| inode.strong_count() > 0);
+
+        let raw = self.begin().read_inode(ino)?;
+        if raw.mode == 0 {
+            crate::serial_println!("rfs: directory entry for free inode {}", ino);
+            return Err(VfsError::IoError);
+        }
+        let inode = Arc::new(RfsInode {
+            volume: self.clone(),
+            ino,
+            file_type: raw.file_type(),
+        });
+        inodes.insert(ino, Arc::downgrade(&inode));
+        Ok(inode)
+    }
+
+    /// Free an inode with no links left, and its blocks, in as many
+    /// transactions as that takes
+    fn release(&self, ino: u32) -> Result<(), VfsError> {
+        loop {
+            let mut txn = self.begin();
+            let mut inode = txn.read_inode(ino)?;
+            if inode.links != 0 || inode.mode == 0 {
+                return Ok(());
+            }
+            let done = if inode.file_type() == FileType::Directory {
+                // Only empty directories lose their last link: just the root node
+                txn.free_blocks(inode.tree_root(), 1)?;
+                true
+            } else {
+                txn.truncate_extents(&mut inode, 0)?
+            };
+            if done {
+                txn.free_inode(ino)?;
+                txn.write_inode(ino, &RawInode::parse(&[0; INODE_SIZE]))?;
+            } else {
+                txn.write_inode(ino, &inode)?;
+            }
+            txn.commit()?;
+            if done {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Free inodes that a crash left with no links, which were still open
+    /// when their last link went
+    fn sweep_orphans(&self) -> Result<usize, VfsError> {
+        let layout = self.layout;
+        let mut orphans = Vec::new();
+        {
+            let txn = self.begin();
+            for index in 0..(layout.inode_count / INODES_PER_BLOCK) as u64 {
+                txn.read(layout.inode_table_start + index, |data| {
+                    for slot in 0..INODES_PER_BLOCK as usize {
+                        let raw = &data[slot * INODE_SIZE..];
+                        if read_u16(raw, 0) != 0 && read_u16(raw, 2) == 0 {
+                            orphans.push(index as u32 * INODES_PER_BLOCK + slot as u32 + 1);
+                        }
+                    }
+                })?;
+            }
+        }
+        for &ino in &orphans {
+            self.release(ino)?;
+        }
+        Ok(orphans.len())
+    }
+}
+
+/// One operation's changes to metadata: the blocks it changed, pinned in
+/// the buffer cache until they are committed to the journal
+///
+/// Dropping a transaction without committing it throws its changes away.
+struct Txn<'a> {
+    volume: &'a Volume,
+    state: MutexGuard<'a, VolumeState>,
+    blocks: Vec<Buffer>,
+    /// Allocation state at the start, restored if the transaction is dropped
+    saved: (u64, u32, u64),
+    done: bool,
+}
+
+impl Txn<'_> {
+    fn check_block(&self, block: u64) -> Result<(), VfsError> {
+        if block >= self.volume.layout.block_count {
+            crate::serial_println!("rfs: block {} is outside the volume", block);
+            return Err(VfsError::IoError);
+        }
+        Ok(())
+    }
+
+    fn read<T>(&self, block: u64, f: impl FnOnce(&[u8]) -> T) -> Result<T, VfsError> {
+        self.check_block(block)?;
+        let buffer = bcache::bread(&self.volume.device, block, BLOCK_SIZE)?;
+        let data = buffer.data();
+        Ok(f(&data))
+    }
+
+    /// Add a block to the transaction; a `fresh` one is zeroed instead of read
+    fn pin(&mut self, block: u64, fresh: bool) -> Result<&Buffer, VfsError> {
+        self.check_block(block)?;
+        let at = match self.blocks.iter().position(|buffer| buffer.block() == block) {
+            Some(at) => at,
+            None => {
+                if self.blocks.len() == MAX_TXN_BLOCKS {
+                    crate::serial_println!("rfs: transaction too large");
+                    return Err(VfsError::NoSpace);
+                }
+                let device = &self.volume.device;
+                let buffer = if fresh { bcache::bget(device, block, BLOCK_SIZE)? } else { bcache::bread(device, block, BLOCK_SIZE)? };
+                self.blocks.push(buffer);
+                self.blocks.len() - 1
+            }
+        };
+        let buffer = &self.blocks[at];
+        if fresh {
+            buffer.data().fill(0);
+        }
+        Ok(buffer)
+    }
+
+    fn modify<T>(&mut self, block: u64, f: impl FnOnce(&mut [u8]) -> T) -> Result<T, VfsError> {
+        let buffer = self.pin(block, false)?;
+        let mut data = buffer.data();
+        Ok(f(&mut data))
+    }
+
+    /// Whether there is room for another step of a long operation
+    fn has_room(&self) -> bool {
+        self.blocks.len() + TXN_HEADROOM <= MAX_TXN_BLOCKS
+    }
+
+    /// Write the transaction to the journal, then its blocks home
+    fn commit(mut self) -> Result<(), VfsError> {
+        self.done = true;
+        if self.blocks.is_empty() {
+            return Ok(());
+        }
+        let volume = self.volume;
+        let device = volume.device.as_ref();
+        let sequence = self.state.sequence;
+        let blocks = &self.blocks;
+        let result = (|| {
+            // File data first, so committed metadata never points at blocks
+            // that do not hold it yet
+            bcache::sync_device(&volume.device)?;
+            journal_commit(device, &volume.layout, sequence, blocks)?;
+            for buffer in blocks {
+                bcache::bwrite(buffer);
+            }
+            bcache::sync_device(&volume.device)?;
+            write_journal_header(device, &volume.layout, sequence + 1)
+        })();
+        match result {
+            Ok(()) => {
+                self.state.sequence += 1;
+                Ok(())
+            }
+            Err(e) => {
+                // Throw the changes away; if the commit block made it to
+                // disk, the next mount replays them
+                self.done = false;
+                Err(e)
+            }
+        }
+    }
+
+    /// First clear bit from `from` up to `limit` in the bitmap starting at block `bitmap`
+    fn find_clear(&self, bitmap: u64, from: u64, limit: u64) -> Result<Option<u64>, VfsError> {
+        let mut bit = from;
+        while bit < limit {
+            let base = bit - bit % BITS_PER_BLOCK;
+            let end = limit.min(base + BITS_PER_BLOCK);
+            let found = self.read(bitmap + base / BITS_PER_BLOCK, |data| {
+                (bit..end).find(|&b| {
+                    let i = (b - base) as usize;
+                    data[i / 8] & (1 << (i % 8)) == 0
+                })
+            })?;
+            if found.is_some() {
+                return Ok(found);
+            }
+            bit = end;
+        }
+        Ok(None)
+    }
+
+    /// Set or clear a bit, returning its old value
+    fn set_bit(&mut self, bitmap: u64, bit: u64, value: bool) -> Result<bool, VfsError> {
+        let i = (bit % BITS_PER_BLOCK) as usize;
+        self.modify(bitmap + bit / BITS_PER_BLOCK, |data| {
+            let old = data[i / 8] & (1 << (i % 8)) != 0;
+            if value {
+                data[i / 8] |= 1 << (i % 8);
+            } else {
+                data[i / 8] &= !(1 << (i % 8));
+            }
+            old
+        })
+    }
+
+    /// Allocate a data block, preferably `goal`
+    fn allocate_block(&mut self, goal: u64) -> Result<u64, VfsError> {
+        let layout = self.volume.layout;
+        if self.state.free_blocks == 0 {
+            return Err(VfsError::NoSpace);
+        }
+        let goal = if (layout.data_start..layout.block_count).contains(&goal) { goal } else { layout.data_start };
+        let block = match self.find_clear(layout.bitmap_start, goal, layout.block_count)? {
+            Some(block) => block,
+            None => self
+                .find_clear(layout.bitmap_start, layout.data_start, goal)?
+                .ok_or(VfsError::NoSpace)?,
+        };
+        self.set_bit(layout.bitmap_start, block, true)?;
+        self.state.free_blocks -= 1;
+        self.state.next_block = block + 1;
+        Ok(block)
+    }
+
+    /// Allocate a zeroed metadata block, as part of the transaction
+    fn allocate_node(&mut self, goal: u64) -> Result<u64, VfsError> {
+        let block = self.allocate_block(goal)?;
+        self.pin(block, true)?;
+        Ok(block)
+    }
+
+    fn free_blocks(&mut self, start: u64, count: u64) -> Result<(), VfsError> {
+        let layout = self.volume.layout;
+        if start < layout.data_start || start.saturating_add(count) > layout.block_count {
+            crate::serial_println!("rfs: freeing blocks {}+{} outside the data area", start, count);
+            return Err(VfsError::IoError);
+        }
+        for block in start..start + count {
+            if self.set_bit(layout.bitmap_start, block, false)? {
+                self.state.free_blocks += 1;
+            } else {
+                crate::serial_println!("rfs: block {} was already free", block);
+            }
+        }
+        Ok(())
+    }
+
+    fn allocate_inode(&mut self) -> Result<u32, VfsError> {
+        let layout = self.volume.layout;
+        if self.state.free_inodes == 0 {
+            return Err(VfsError::NoSpace);
+        }
+        let bit = self
+            .find_clear(layout.inode_bitmap_start, 0, layout.inode_count as u64)?
+            .ok_or(VfsError::NoSpace)?;
+        self.set_bit(layout.inode_bitmap_start, bit, true)?;
+        self.state.free_inodes -= 1;
+        Ok(bit as u32 + 1)
+    }
+
+    fn free_inode(&mut self, ino: u32) -> Result<(), VfsError> {
+        if self.set_bit(self.volume.layout.inode_bitmap_start, (ino - 1) as u64, false)? {
+            self.state.free_inodes += 1;
+        } else {
+            crate::serial_println!("rfs: inode {} was already free", ino);
+        }
+        Ok(())
+    }
+
+    /// Inode table block and byte offset of inode `ino`
+    fn inode_location(&self, ino: u32) -> Result<(u64, usize), VfsError> {
+        let layout = self.volume.layout;
+        if ino == 0 || ino > layout.inode_count {
+            crate::serial_println!("rfs: bad inode number {}", ino);
+            return Err(VfsError::IoError);
+        }
+        let index = ino - 1;
+        Ok((layout.inode_table_start + (index / INODES_PER_BLOCK) as u64, (index % INODES_PER_BLOCK) as usize * INODE_SIZE))
+    }
+
+    fn read_inode(&self, ino: u32) -> Result<RawInode, VfsError> {
+        let (block, at) = self.inode_location(ino)?;
+        self.read(block, |data| RawInode::parse(&data[at..at + INODE_SIZE]))
+    }
+
+    fn write_inode(&mut self, ino: u32, inode: &RawInode) -> Result<(), VfsError> {
+        let (block, at) = self.inode_location(ino)?;
+        self.modify(block, |data| inode.write(&mut data[at..at + INODE_SIZE]))
+    }
+
+    /// Allocate and write a new inode; a directory gets an empty root node
+    fn new_inode(&mut self, file_type: FileType, mode: u16, parent: u32) -> Result<(u32, RawInode), VfsError> {
+        let ino = self.allocate_inode()?;
+        let mut inode = RawInode::new(file_type, mode, parent);
+        if file_type == FileType::Directory {
+            let root = self.allocate_node(self.state.next_block)?;
+            self.modify(root, |node| write_node(node, 0, &[]))?;
+            inode.set_tree_root(root);
+            inode.blocks = 1;
+        }
+        self.write_inode(ino, &inode)?;
+        Ok((ino, inode))
+    }
+
+    /// The disk block holding block `index` of a file, if it has one
+    fn map(&self, inode: &RawInode, index: u64) -> Result<Option<u64>, VfsError> {
+        let mut extents = inode.extents()?;
+        if inode.depth > 0 {
+            let Some(entry) = extents.get(covering(&extents, index)) else {
+                return Ok(None);
+            };
+            extents = self.read(entry.physical, parse_leaf)??;
+        }
+        Ok(extents
+            .get(covering(&extents, index))
+            .filter(|extent| extent.logical as u64 <= index && index < extent.end())
+            .map(|extent| extent.physical + (index - extent.logical as u64)))
+    }
+
+    /// Map unmapped file block `index` to `physical`
+    ///
+    /// Fails with `NoSpace`, changing nothing, when the file has no room
+    /// for another extent.
+    fn add_extent(&mut self, inode: &mut RawInode, index: u64, physical: u64) -> Result<(), VfsError> {
+        let index = u32::try_from(index).map_err(|_| VfsError::NoSpace)?;
+        let mut entries = inode.extents()?;
+        if inode.depth == 0 {
+            insert_extent(&mut entries, index, physical);
+            if entries.len() <= ROOT_SLOTS {
+                inode.set_extents(&entries);
+                return Ok(());
+            }
+            // Out of room in the inode: the extents move to a leaf block
+            let leaf = self.allocate_node(physical + 1)?;
+            self.modify(leaf, |node| write_leaf(node, &entries))?;
+            inode.blocks += 1;
+            inode.depth = 1;
+            inode.set_extents(&[Extent { logical: 0, len: 0, physical: leaf }]);
+            return Ok(());
+        }
+
+        let at = covering(&entries, index as u64);
+        let leaf = entries[at].physical;
+        let mut extents = self.read(leaf, parse_leaf)??;
+        insert_extent(&mut extents, index, physical);
+        if extents.len() <= NODE_SLOTS {
+            return self.modify(leaf, |node| write_leaf(node, &extents));
+        }
+        if entries.len() == ROOT_SLOTS {
+            return Err(VfsError::NoSpace); // too fragmented
+        }
+        let right = extents.split_off(extents.len() / 2);
+        let new_leaf = self.allocate_node(leaf + 1)?;
+        self.modify(leaf, |node| write_leaf(node, &extents))?;
+        self.modify(new_leaf, |node| write_leaf(node, &right))?;
+        inode.blocks += 1;
+        entries.insert(at + 1, Extent { logical: right[0].logical, len: 0, physical: new_leaf });
+        inode.set_extents(&entries);
+        Ok(())
+    }
+
+    /// Free the blocks of a file from block `keep` on, last first, while the
+    /// transaction has room; returns whether it got to the end
+    fn truncate_extents(&mut self, inode: &mut RawInode, keep: u64) -> Result<bool, VfsError> {
+        loop {
+            if !self.has_room() {
+                return Ok(false);
+            }
+            let mut entries = inode.extents()?;
+            let (mut extents, leaf) = if inode.depth == 0 {
+                (entries.clone(), None)
+            } else {
+                let Some(entry) = entries.last() else {
+                    inode.depth = 0;
+                    continue;
+                };
+                (self.read(entry.physical, parse_leaf)??, Some(entry.physical))
+            };
+
+            match extents.last_mut() {
+                Some(last) if last.end() > keep => {
+                    // Free the tail, at most one bitmap block's worth at a time
+                    let physical_end = last.physical + last.len as u64;
+                    let bitmap_base = (physical_end - 1) - (physical_end - 1) % BITS_PER_BLOCK;
+                    let cut = (last.end() - keep.max(last.logical as u64)).min(physical_end - bitmap_base.max(last.physical));
+                    self.free_blocks(physical_end - cut, cut)?;
+                    inode.blocks = inode.blocks.saturating_sub(cut);
+                    last.len -= cut as u32;
+                    if last.len == 0 {
+                        extents.pop();
+                    }
+                }
+                Some(_) => return Ok(true),
+                None if leaf.is_none() => return Ok(true),
+                None => {}
+            }
+
+            match leaf {
+                None => inode.set_extents(&extents),
+                Some(block) if extents.is_empty() => {
+                    self.free_blocks(block, 1)?;
+                    inode.blocks = inode.blocks.saturating_sub(1);
+                    entries.pop();
+                    if entries.is_empty() {
+                        inode.depth = 0;
+                    }
+                    inode.set_extents(&entries);
+                }
+                Some(block) => self.modify(block, |node| write_leaf(node, &extents))?,
+            }
+        }
+    }
+
+    fn read_data(&self, inode: &RawInode, offset: u64, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        if offset >= inode.size {
+            return Ok(0);
+        }
+        let len = buffer.len().min((inode.size - offset) as usize);
+        let mut done = 0;
+        while done < len {
+            let position = offset + done as u64;
+            let within = (position % BLOCK_SIZE as u64) as usize;
+            let chunk = (BLOCK_SIZE - within).min(len - done);
+            let out = &mut buffer[done..done + chunk];
+            match self.map(inode, position / BLOCK_SIZE as u64)? {
+                Some(block) => self.read(block, |data| out.copy_from_slice(&data[within..within + chunk]))?,
+                None => out.fill(0), // a hole
+            }
+            done += chunk;
+        }
+        Ok(len)
+    }
+
+    /// Write file data from `offset` on, allocating blocks as needed, while
+    /// the transaction has room
+    ///
+    /// Returns the bytes written and whether the volume (or the file's
+    /// extent tree) is full.
+    fn write_data(&mut self, inode: &mut RawInode, offset: u64, data: &[u8]) -> Result<(usize, bool), VfsError> {
+        let mut done = 0;
+        while done < data.len() && self.has_room() {
+            let position = offset + done as u64;
+            let index = position / BLOCK_SIZE as u64;
+            let within = (position % BLOCK_SIZE as u64) as usize;
+            let chunk = (BLOCK_SIZE - within).min(data.len() - done);
+
+            let (block, fresh) = match self.map(inode, index)? {
+                Some(block) => (block, false),
+                None => {
+                    let previous = match index.checked_sub(1) {
+                        Some(previous) => self.map(inode, previous)?,
+                        None => None,
+                    };
+                    let goal = previous.map_or(self.state.next_block, |block| block + 1);
+                    let block = match self.allocate_block(goal) {
+                        Ok(block) => block,
+                        Err(VfsError::NoSpace) => return Ok((done, true)),
+                        Err(e) => return Err(e),
+                    };
+                    match self.add_extent(inode, index, block) {
+                        Ok(()) => {}
+                        Err(VfsError::NoSpace) => {
+                            self.free_blocks(block, 1)?;
+                            return Ok((done, true));
+                        }
+                        Err(e) => return Err(e),
+                    }
+                    inode.blocks += 1;
+                    (block, true)
+                }
+            };
+
+            // Data goes through the cache as delayed writes, flushed at commit
+            let device = &self.volume.device;
+            let buffer = if fresh || chunk == BLOCK_SIZE {
+                bcache::bget(device, block, BLOCK_SIZE)?
+            } else {
+                bcache::bread(device, block, BLOCK_SIZE)?
+            };
+            {
+                let mut contents = buffer.data();
+                if fresh {
+                    contents.fill(0);
+                }
+                contents[within..within + chunk].copy_from_slice(&data[done..done + chunk]);
+            }
+            bcache::bwrite(&buffer);
+            done += chunk;
+        }
+        Ok((done, false))
+    }
+
+    /// Zero the rest of the block holding byte `size`, so the file reads
+    /// back zeros there if it grows again
+    fn zero_tail(&mut self, inode: &RawInode, size: u64) -> Result<(), VfsError> {
+        let within = (size % BLOCK_SIZE as u64) as usize;
+        if within == 0 {
+            return Ok(());
+        }
+        if let Some(block) = self.map(inode, size / BLOCK_SIZE as u64)? {
+            let buffer = bcache::bread(&self.volume.device, block, BLOCK_SIZE)?;
+            buffer.data()[within..].fill(0);
+            bcache::bwrite(&buffer);
+        }
+        Ok(())
+    }
+
+    /// Run `f` on a directory node's level and records
+    fn node<T>(&self, block: u64, f: impl FnOnce(u8, &[u8]) -> T) -> Result<T, VfsError> {
+        self.read(block, |node| check_node(node).map(|(level, records)| f(level, records)))?
+    }
+
+    /// The nodes from a directory's root down to the leaf for `hash`, each
+    /// with the offset of the record followed in it
+    fn path_to(&self, root: u64, hash: u64) -> Result<Vec<(u64, usize)>, VfsError> {
+        let mut path = Vec::new();
+        let mut block = root;
+        while path.len() < MAX_DEPTH {
+            let step = self.node(block, |level, records| {
+                (level > 0).then(|| (!records.is_empty()).then(|| {
+                    let at = child_at(level, records, hash);
+                    (at, read_u64(records, at + 8))
+                }))
+            })?;
+            match step {
+                None => {
+                    path.push((block, 0));
+                    return Ok(path);
+                }
+                Some(Some((at, child))) => {
+                    path.push((block, at));
+                    block = child;
+                }
+                Some(None) => break,
+            }
+        }
+        crate::serial_println!("rfs: bad directory tree at block {}", block);
+        Err(VfsError::IoError)
+    }
+
+    fn dir_find(&self, dir: &RawInode, name: &str) -> Result<Option<(u32, u8)>, VfsError> {
+        let hash = name_hash(name.as_bytes());
+        let path = self.path_to(dir.tree_root(), hash)?;
+        self.node(path[path.len() - 1].0, |_, records| {
+            record_offsets(0, records)
+                .find(|&at| read_u64(records, at) == hash && record_name(records, at) == name.as_bytes())
+                .map(|at| (read_u32(records, at + 8), records[at + 12]))
+        })
+    }
+
+    /// Add an entry, splitting full nodes on the way back up from the leaf
+    fn dir_insert(&mut self, dir: &mut RawInode, name: &str, ino: u32, kind: u8) -> Result<(), VfsError> {
+        let hash = name_hash(name.as_bytes());
+        let path = self.path_to(dir.tree_root(), hash)?;
+        let leaf = path[path.len() - 1].0;
+        // Also refuses a different name with the same hash
+        if self.node(leaf, |_, records| record_offsets(0, records).any(|at| read_u64(records, at) == hash))? {
+            return Err(VfsError::AlreadyExists);
+        }
+
+        let mut record = leaf_record(hash, ino, kind, name.as_bytes());
+        for depth in (0..path.len()).rev() {
+            let block = path[depth].0;
+            if self.modify(block, |node| node_insert(node, &record))? {
+                break;
+            }
+            if depth == 0 {
+                self.split_root(dir, block, &record)?;
+                break;
+            }
+            let (right, first_hash) = self.split_node(dir, block, &record)?;
+            record = index_record(first_hash, right).to_vec();
+        }
+        dir.entries += 1;
+        Ok(())
+    }
+
+    /// Split full node `block` to make room for `record`, returning the new
+    /// right-hand node and its lowest hash
+    fn split_node(&mut self, dir: &mut RawInode, block: u64, record: &[u8]) -> Result<(u64, u64), VfsError> {
+        let (level, merged) = self.node(block, |level, records| (level, merge_record(level, records, record)))?;
+        let split = split_point(level, &merged);
+        let right = self.allocate_node(block + 1)?;
+        dir.blocks += 1;
+        self.modify(block, |node| write_node(node, level, &merged[..split]))?;
+        self.modify(right, |node| write_node(node, level, &merged[split..]))?;
+        Ok((right, read_u64(&merged, split)))
+    }
+
+    /// Split the full root node: its records move to two new children and
+    /// the tree grows a level, with the root staying where it is
+    fn split_root(&mut self, dir: &mut RawInode, root: u64, record: &[u8]) -> Result<(), VfsError> {
+        let (level, merged) = self.node(root, |level, records| (level, merge_record(level, records, record)))?;
+        if level as usize + 1 >= MAX_DEPTH {
+            return Err(VfsError::NoSpace);
+        }
+        let split = split_point(level, &merged);
+        let left = self.allocate_node(root + 1)?;
+        let right = self.allocate_node(left + 1)?;
+        dir.blocks += 2;
+        self.modify(left, |node| write_node(node, level, &merged[..split]))?;
+        self.modify(right, |node| write_node(node, level, &merged[split..]))?;
+        let mut index = [0u8; 2 * INDEX_RECORD];
+        index[..INDEX_RECORD].copy_from_slice(&index_record(0, left));
+        index[INDEX_RECORD..].copy_from_slice(&index_record(read_u64(&merged, split), right));
+        self.modify(root, |node| write_node(node, level + 1, &index))
+    }
+
+    /// Remove an entry, freeing nodes it leaves empty
+    fn dir_remove(&mut self, dir: &mut RawInode, name: &str) -> Result<Option<(u32, u8)>, VfsError> {
+        let hash = name_hash(name.as_bytes());
+        let path = self.path_to(dir.tree_root(), hash)?;
+        let leaf = path[path.len() - 1].0;
+        let found = self.node(leaf, |_, records| {
+            record_offsets(0, records)
+                .find(|&at| read_u64(records, at) == hash && record_name(records, at) == name.as_bytes())
+                .map(|at| (at, read_u32(records, at + 8), records[at + 12]))
+        })?;
+        let Some((at, ino, kind)) = found else {
+            return Ok(None);
+        };
+        self.modify(leaf, |node| node_remove(node, at))?;
+        dir.entries = dir.entries.saturating_sub(1);
+
+        let mut depth = path.len() - 1;
+        while depth > 0 && self.read(path[depth].0, |node| read_u16(node, 4) == 0)? {
+            self.free_blocks(path[depth].0, 1)?;
+            dir.blocks = dir.blocks.saturating_sub(1);
+            depth -= 1;
+            let (parent, at) = path[depth];
+            self.modify(parent, |node| node_remove(node, at))?;
+        }
+        self.shrink_root(dir)?;
+        Ok(Some((ino, kind)))
+    }
+
+    /// Let a root index node with a single child take the child's place,
+    /// and turn an empty one back into a leaf
+    fn shrink_root(&mut self, dir: &mut RawInode) -> Result<(), VfsError> {
+        let root = dir.tree_root();
+        loop {
+            let (level, children, first) = self.read(root, |node| {
+                (node[2], read_u16(node, 4), read_u64(node, NODE_HEADER + 8))
+            })?;
+            match (level, children) {
+                (0, _) => return Ok(()),
+                (_, 0) => return self.modify(root, |node| write_node(node, 0, &[])),
+                (_, 1) => {
+                    let copy = self.read(first, |node| node.to_vec())?;
+                    self.modify(root, |node| node.copy_from_slice(&copy))?;
+                    self.free_blocks(first, 1)?;
+                    dir.blocks = dir.blocks.saturating_sub(1);
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    /// Point an existing entry at another inode
+    fn dir_replace(&mut self, dir: &RawInode, name: &str, ino: u32, kind: u8) -> Result<(), VfsError> {
+        let hash = name_hash(name.as_bytes());
+        let path = self.path_to(dir.tree_root(), hash)?;
+        let leaf = path[path.len() - 1].0;
+        let at = self
+            .node(leaf, |_, records| {
+                record_offsets(0, records).find(|&at| read_u64(records, at) == hash && record_name(records, at) == name.as_bytes())
+            })?
+            .ok_or(VfsError::NotFound)?;
+        self.modify(leaf, |node| {
+            put_u32(node, NODE_HEADER + at + 8, ino);
+            node[NODE_HEADER + at + 12] = kind;
+        })
+    }
+
+    /// The first entry at or below `block` whose hash is at least `cursor`
+    fn dir_next(&self, block: u64, cursor: u64, depth: usize) -> Result<Option<DirRecord>, VfsError> {
+        if depth == MAX_DEPTH {
+            return Err(VfsError::IoError);
+        }
+        let children: Option<Vec<u64>> = self.node(block, |level, records| {
+            (level > 0).then(|| {
+                let first = child_at(level, records, cursor);
+                record_offsets(level, records)
+                    .filter(|&at| at >= first)
+                    .map(|at| read_u64(records, at + 8))
+                    .collect()
+            })
+        })?;
+        match children {
+            Some(children) => {
+                for child in children {
+                    if let Some(record) = self.dir_next(child, cursor, depth + 1)? {
+                        return Ok(Some(record));
+                    }
+                }
+                Ok(None)
+            }
+            None => self.node(block, |_, records| {
+                record_offsets(0, records).find(|&at| read_u64(records, at) >= cursor).map(|at| DirRecord {
+                    hash: read_u64(records, at),
+                    ino: read_u32(records, at + 8),
+                    kind: records[at + 12],
+                    name: String::from_utf8_lossy(record_name(records, at)).into_owned(),
+                })
+            }),
+        }
+    }
+
+    /// Whether directory `ino` is `ancestor` or lies below it
+    fn is_within(&self, mut ino: u32, ancestor: u32) -> Result<bool, VfsError> {
+        for _ in 0..self.volume.layout.inode_count {
+            if ino == ancestor {
+                return Ok(true);
+            }
+            if ino == ROOT_INO {
+                return Ok(false);
+            }
+            ino = self.read_inode(ino)?.parent;
+        }
+        Err(VfsError::IoError)
+    }
+}
+
+impl Drop for Txn<'_> {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        for buffer in self.blocks.drain(..) {
+            bcache::bforget(buffer);
+        }
+        (self.state.free_blocks, self.state.free_inodes, self.state.next_block) = self.saved;
+    }
+}
+
+/// A file, directory or symbolic link on an rfs volume
+///
+/// Only the inode number is kept: the inode itself is read through the
+/// buffer cache inside each operation's transaction.
+pub struct RfsInode {
+    volume: Arc<Volume>,
+    ino: u32,
+    file_type: FileType,
+}
+
+impl RfsInode {
+    fn load_directory(&self, txn: &Txn) -> Result<RawInode, VfsError> {
+        let inode = txn.read_inode(self.ino)?;
+        if inode.file_type() != FileType::Directory {
+            return Err(VfsError::NotADirectory);
+        }
+        if inode.links == 0 {
+            return Err(VfsError::NotFound); // removed
+        }
+        Ok(inode)
+    }
+
+    /// Write at `offset`, or at the end of the file when it is `None`,
+    /// returning where the write started and how much was written
+    fn write_at(&self, offset: Option<u64>, buffer: &[u8]) -> Result<(u64, usize), VfsError> {
+        let mut start = offset;
+        let mut written = 0;
+        loop {
+            let mut txn = self.volume.begin();
+            let mut inode = txn.read_inode(self.ino)?;
+            match inode.file_type() {
+                FileType::Regular => {}
+                FileType::Directory => return Err(VfsError::IsADirectory),
+                _ => return Err(VfsError::InvalidOperation),
+            }
+            let offset = *start.get_or_insert(inode.size);
+            if buffer.is_empty() {
+                return Ok((offset, 0));
+            }
+
+            let position = offset + written as u64;
+            let (count, full) = txn.write_data(&mut inode, position, &buffer[written..])?;
+            if count > 0 {
+                written += count;
+                inode.size = inode.size.max(position + count as u64);
+                inode.touch();
+                txn.write_inode(self.ino, &inode)?;
+                txn.commit()?;
+            }
+            if written == buffer.len() || full || count == 0 {
+                break;
+            }
+        }
+        match (written, start) {
+            (0, _) => Err(VfsError::NoSpace),
+            (written, Some(offset)) => Ok((offset, written)),
+            (_, None) => Err(VfsError::IoError),
+        }
+    }
+
+    /// Drop the reference to an inode whose last link was just removed,
+    /// which frees it unless it is still open
+    fn forget(&self, ino: u32) {
+        if let Err(e) = self.volume.get_inode(ino) {
+            crate::serial_println!("rfs: inode {}: {}", ino, e);
+        }
+    }
+}
+
+impl Drop for RfsInode {
+    fn drop(&mut self) {
+        let links = self.volume.begin().read_inode(self.ino).map(|inode| inode.links);
+        if links == Ok(0) {
+            if let Err(e) = self.volume.release(self.ino) {
+                crate::serial_println!("rfs: freeing inode {} failed: {}", self.ino, e);
+            }
+        }
+    }
+}
+
+impl Inode for RfsInode {
+    fn read(&self, offset: usize, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        let txn = self.volume.begin();
+        let inode = txn.read_inode(self.ino)?;
+        match inode.file_type() {
+            FileType::Regular => txn.read_data(&inode, offset as u64, buffer),
+            FileType::Directory => Err(VfsError::IsADirectory),
+            _ => Err(VfsError::InvalidOperation),
+        }
+    }
+
+    fn write(&self, offset: usize, buffer: &[u8]) -> Result<usize, VfsError> {
+        Ok(self.write_at(Some(offset as u64), buffer)?.1)
+    }
+
+    fn append(&self, buffer: &[u8]) -> Result<usize, VfsError> {
+        let (offset, written) = self.write_at(None, buffer)?;
+        Ok(offset as usize + written)
+    }
+
+    fn file_type(&self) -> FileType {
+        self.file_type
+    }
+
+    fn size(&self) -> usize {
+        self.metadata().size as usize
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, VfsError> {
+        let ino = {
+            let txn = self.volume.begin();
+            let dir = self.load_directory(&txn)?;
+            txn.dir_find(&dir, name)?.ok_or(VfsError::NotFound)?.0
+        };
+        Ok(self.volume.get_inode(ino)?)
+    }
+
+    fn create(&self, name: &str, file_type: FileType) -> Result<Arc<dyn Inode>, VfsError> {
+        validate_name(name)?;
+        if !matches!(file_type, FileType::Regular | FileType::Directory) {
+            return Err(VfsError::NotImplemented);
+        }
+        let ino = {
+            let mut txn = self.volume.begin();
+            let mut dir = self.load_directory(&txn)?;
+            if txn.dir_find(&dir, name)?.is_some() {
+                return Err(VfsError::AlreadyExists);
+            }
+            let directory = file_type == FileType::Directory;
+            if directory && dir.links >= LINK_MAX {
+                return Err(VfsError::InvalidOperation);
+            }
+            let (ino, _) = txn.new_inode(file_type, file_type.default_mode(), self.ino)?;
+            txn.dir_insert(&mut dir, name, ino, kind_of(file_type))?;
+            if directory {
+                dir.links += 1;
+            }
+            dir.touch();
+            txn.write_inode(self.ino, &dir)?;
+            txn.commit()?;
+            ino
+        };
+        Ok(self.volume.get_inode(ino)?)
+    }
+
+    fn list(&self) -> Result<Vec<String>, VfsError> {
+        let mut names = Vec::new();
+        let mut cursor = 0;
+        while let Some((entry, next)) = self.readdir(cursor)? {
+            names.push(entry.name);
+            cursor = next;
+        }
+        Ok(names)
+    }
+
+    /// Entries come in hash order; the cursor is the lowest hash not yet
+    /// returned, so entries added or removed meanwhile do not upset it
+    fn readdir(&self, cursor: u64) -> Result<Option<(DirEntry, u64)>, VfsError> {
+        let txn = self.volume.begin();
+        let dir = self.load_directory(&txn)?;
+        Ok(txn.dir_next(dir.tree_root(), cursor, 0)?.map(|record| {
+            let entry = DirEntry {
+                name: record.name,
+                ino: record.ino as u64,
+                file_type: kind_file_type(record.kind),
+            };
+            (entry, record.hash + 1)
+        }))
+    }
+
+    fn truncate(&self, size: usize) -> Result<(), VfsError> {
+        let size = size as u64;
+        loop {
+            let mut txn = self.volume.begin();
+            let mut inode = txn.read_inode(self.ino)?;
+            match inode.file_type() {
+                FileType::Regular => {}
+                FileType::Directory => return Err(VfsError::IsADirectory),
+                _ => return Err(VfsError::InvalidOperation),
+            }
+            if size.div_ceil(BLOCK_SIZE as u64) > u32::MAX as u64 {
+                return Err(VfsError::NoSpace);
+            }
+
+            // Large files lose their blocks over several transactions; the
+            // size changes with the last
+            if txn.truncate_extents(&mut inode, size.div_ceil(BLOCK_SIZE as u64))? {
+                if size < inode.size {
+                    txn.zero_tail(&inode, size)?;
+                }
+                inode.size = size;
+                inode.touch();
+                txn.write_inode(self.ino, &inode)?;
+                return txn.commit();
+            }
+            txn.write_inode(self.ino, &inode)?;
+            txn.commit()?;
+        }
+    }
+
+    fn remove(&self, name: &str) -> Result<(), VfsError> {
+        if name == "." || name == ".." {
+            return Err(VfsError::InvalidArgument);
+        }
+        let ino = {
+            let mut txn = self.volume.begin();
+            let mut dir = self.load_directory(&txn)?;
+            let (ino, _) = txn.dir_find(&dir, name)?.ok_or(VfsError::NotFound)?;
+            let mut child = txn.read_inode(ino)?;
+            let directory = child.file_type() == FileType::Directory;
+            if directory && child.entries != 0 {
+                return Err(VfsError::DirectoryNotEmpty);
+            }
+
+            txn.dir_remove(&mut dir, name)?;
+            if directory {
+                child.links = 0;
+                dir.links = dir.links.saturating_sub(1);
+            } else {
+                child.links = child.links.saturating_sub(1);
+            }
+            child.ctime = current_time().tv_sec;
+            dir.touch();
+            txn.write_inode(ino, &child)?;
+            txn.write_inode(self.ino, &dir)?;
+            txn.commit()?;
+            ino
+        };
+        self.forget(ino);
+        Ok(())
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> Result<Arc<dyn Inode>, VfsError> {
+        validate_name(name)?;
+        if target.is_empty() || target.len() >= BLOCK_SIZE {
+            return Err(VfsError::InvalidArgument);
+        }
+        let ino = {
+            let mut txn = self.volume.begin();
+            let mut dir = self.load_directory(&txn)?;
+            if txn.dir_find(&dir, name)?.is_some() {
+                return Err(VfsError::AlreadyExists);
+            }
+            let (ino, mut inode) = txn.new_inode(FileType::Symlink, FileType::Symlink.default_mode(), self.ino)?;
+            // The target is stored like file data
+            if txn.write_data(&mut inode, 0, target.as_bytes())?.0 != target.len() {
+                return Err(VfsError::NoSpace);
+            }
+            inode.size = target.len() as u64;
+            txn.write_inode(ino, &inode)?;
+            txn.dir_insert(&mut dir, name, ino, kind_of(FileType::Symlink))?;
+            dir.touch();
+            txn.write_inode(self.ino, &dir)?;
+            txn.commit()?;
+            ino
+        };
+        Ok(self.volume.get_inode(ino)?)
+    }
+
+    fn readlink(&self) -> Result<String, VfsError> {
+        let txn = self.volume.begin();
+        let inode = txn.read_inode(self.ino)?;
+        if inode.file_type() != FileType::Symlink {
+            return Err(VfsError::InvalidArgument);
+        }
+        let mut target = vec![0u8; (inode.size as usize).min(BLOCK_SIZE)];
+        let read = txn.read_data(&inode, 0, &mut target)?;
+        target.truncate(read);
+        String::from_utf8(target).map_err(|_| VfsError::IoError)
+    }
+
+    fn link(&self, name: &str, inode: &Arc<dyn Inode>) -> Result<(), VfsError> {
+        let target = downcast_inode::<RfsInode>(inode).ok_or(VfsError::CrossDevice)?;
+        if !Arc::ptr_eq(&self.volume, &target.volume) {
+            return Err(VfsError::CrossDevice);
+        }
+        validate_name(name)?;
+        // Directory hard links would make the tree a graph
+        if target.file_type == FileType::Directory {
+            return Err(VfsError::PermissionDenied);
+        }
+
+        let mut txn = self.volume.begin();
+        let mut dir = self.load_directory(&txn)?;
+        if txn.dir_find(&dir, name)?.is_some() {
+            return Err(VfsError::AlreadyExists);
+        }
+        let mut raw = txn.read_inode(target.ino)?;
+        if raw.links == 0 {
+            return Err(VfsError::NotFound);
+        }
+        if raw.links >= LINK_MAX {
+            return Err(VfsError::InvalidOperation);
+        }
+        txn.dir_insert(&mut dir, name, target.ino, kind_of(target.file_type))?;
+        raw.links += 1;
+        raw.ctime = current_time().tv_sec;
+        dir.touch();
+        txn.write_inode(target.ino, &raw)?;
+        txn.write_inode(self.ino, &dir)?;
+        txn.commit()
+    }
+
+    fn rename(&self, old_name: &str, new_dir: &Arc<dyn Inode>, new_name: &str) -> Result<(), VfsError> {
+        let new_dir = downcast_inode::<RfsInode>(new_dir).ok_or(VfsError::CrossDevice)?;
+        if !Arc::ptr_eq(&self.volume, &new_dir.volume) {
+            return Err(VfsError::CrossDevice);
+        }
+        validate_name(new_name)?;
+        let same_dir = self.ino == new_dir.ino;
+
+        let replaced = {
+            let mut txn = self.volume.begin();
+            let mut source_dir = self.load_directory(&txn)?;
+            let mut target_dir = if same_dir { None } else { Some(new_dir.load_directory(&txn)?) };
+            let (ino, kind) = txn.dir_find(&source_dir, old_name)?.ok_or(VfsError::NotFound)?;
+            if same_dir && old_name == new_name {
+                return Ok(());
+            }
+            let directory = kind_file_type(kind) == FileType::Directory;
+            // A directory cannot be moved below itself
+            if directory && !same_dir && txn.is_within(new_dir.ino, ino)? {
+                return Err(VfsError::InvalidArgument);
+            }
+
+            let existing = txn.dir_find(target_dir.as_ref().unwrap_or(&source_dir), new_name)?;
+            let replaced = match existing {
+                // Both names already refer to the same inode
+                Some((target_ino, _)) if target_ino == ino => return Ok(()),
+                Some((target_ino, target_kind)) => {
+                    let mut target = txn.read_inode(target_ino)?;
+                    match (directory, kind_file_type(target_kind) == FileType::Directory) {
+                        (true, true) => {
+                            if target.entries != 0 {
+                                return Err(VfsError::DirectoryNotEmpty);
+                            }
+                        }
+                        (true, false) => return Err(VfsError::NotADirectory),
+                        (false, true) => return Err(VfsError::IsADirectory),
+                        (false, false) => {}
+                    }
+                    let dir = target_dir.as_mut().unwrap_or(&mut source_dir);
+                    txn.dir_replace(dir, new_name, ino, kind)?;
+                    if directory {
+                        target.links = 0;
+                        dir.links = dir.links.saturating_sub(1);
+                    } else {
+                        target.links = target.links.saturating_sub(1);
+                    }
+                    target.ctime = current_time().tv_sec;
+                    txn.write_inode(target_ino, &target)?;
+                    Some(target_ino)
+                }
+                None => {
+                    let dir = target_dir.as_mut().unwrap_or(&mut source_dir);
+                    if directory && !same_dir && dir.links >= LINK_MAX {
+                        return Err(VfsError::InvalidOperation);
+                    }
+                    txn.dir_insert(dir, new_name, ino, kind)?;
+                    None
+                }
+            };
+            txn.dir_remove(&mut source_dir, old_name)?;
+
+            let mut moved = txn.read_inode(ino)?;
+            if let Some(target_dir) = target_dir.as_mut() {
+                if directory {
+                    source_dir.links = source_dir.links.saturating_sub(1);
+                    target_dir.links += 1;
+                    moved.parent = new_dir.ino;
+                }
+                target_dir.touch();
+                txn.write_inode(new_dir.ino, target_dir)?;
+            }
+            moved.ctime = current_time().tv_sec;
+            source_dir.touch();
+            txn.write_inode(ino, &moved)?;
+            txn.write_inode(self.ino, &source_dir)?;
+            txn.commit()?;
+            replaced
+        };
+        if let Some(ino) = replaced {
+            self.forget(ino);
+        }
+        Ok(())
+    }
+
+    fn metadata(&self) -> Metadata {
+        let inode = match self.volume.begin().read_inode(self.ino) {
+            Ok(inode) => inode,
+            Err(e) => {
+                crate::serial_println!("rfs: inode {}: {}", self.ino, e);
+                return Metadata::synthesized(self.ino as u64, self.file_type, 0, current_time());
+            }
+        };
+        let size = if self.file_type == FileType::Directory { inode.blocks * BLOCK_SIZE as u64 } else { inode.size };
+        Metadata {
+            ino: self.ino as u64,
+            file_type: self.file_type,
+            mode: inode.mode & MODE_PERMISSION_MASK,
+            uid: inode.uid,
+            gid: inode.gid,
+            nlink: inode.links as u32,
+            size,
+            blocks: inode.blocks * (BLOCK_SIZE as u64 / STAT_BLOCK_SIZE),
+            atime: from_rfs_time(inode.atime),
+            mtime: from_rfs_time(inode.mtime),
+            ctime: from_rfs_time(inode.ctime),
+        }
+    }
+
+    fn setattr(&self, attr: &SetAttr) -> Result<(), VfsError> {
+        let mut txn = self.volume.begin();
+        let mut inode = txn.read_inode(self.ino)?;
+        if let Some(mode) = attr.mode {
+            inode.mode = (inode.mode & S_IFMT) | (mode & MODE_PERMISSION_MASK);
+        }
+        if let Some(uid) = attr.uid {
+            inode.uid = uid;
+        }
+        if let Some(gid) = attr.gid {
+            inode.gid = gid;
+        }
+        if let Some(atime) = attr.atime {
+            inode.atime = atime.tv_sec;
+        }
+        if let Some(mtime) = attr.mtime {
+            inode.mtime = mtime.tv_sec;
+        }
+        inode.ctime = current_time().tv_sec;
+        txn.write_inode(self.ino, &inode)?;
+        txn.commit()
+    }
+}
+
+/// A mounted rfs volume
+pub struct RfsFs {
+    volume: Arc<Volume>,
+    root: Arc<RfsInode>,
+}
+
+impl RfsFs {
+    /// Mount the rfs volume on `device`, replaying its journal
+    pub fn mount(device: Arc<dyn BlockDevice>) -> Result<Self, VfsError> {
+        let layout = read_layout(&device)?;
+        let (sequence, replayed) = replay_journal(&device, &layout)?;
+        if replayed > 0 {
+            crate::serial_println!("rfs: replayed {} journaled blocks", replayed);
+        }
+        let free_blocks = count_clear(&device, layout.bitmap_start, layout.block_count)?;
+        let free_inodes = count_clear(&device, layout.inode_bitmap_start, layout.inode_count as u64)? as u32;
+
+        let volume = Arc::new(Volume {
+            device,
+            layout,
+            state: Mutex::new(VolumeState {
+                sequence,
+                free_blocks,
+                free_inodes,
+                next_block: layout.data_start,
+            }),
+            inodes: Mutex::new(BTreeMap::new()),
+        });
+        let orphans = volume.sweep_orphans()?;
+        if orphans > 0 {
+            crate::serial_println!("rfs: freed {} orphaned inodes", orphans);
+        }
+
+        let root = volume.get_inode(ROOT_INO)?;
+        if root.file_type != FileType::Directory {
+            return Err(VfsError::InvalidArgument);
+        }
+        Ok(Self { volume, root })
+    }
+
+    /// Free space in bytes
+    #[allow(dead_code)]
+    pub fn free_bytes(&self) -> u64 {
+        self.volume.state.lock().free_blocks * BLOCK_SIZE as u64
+    }
+}
+
+impl Filesystem for RfsFs {
+    fn name(&self) -> &'static str {
+        "rfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+
+    /// Every operation is on disk once it returns; this only writes back
+    /// whatever the cache still holds
+    fn sync(&self) -> Result<(), VfsError> {
+        bcache::sync_device(&self.volume.device)
+    }
+
+    fn device(&self) -> Option<Arc<dyn BlockDevice>> {
+        Some(self.volume.device.clone())
+    }
+}
+
+/// The shape of a freshly made volume
+pub struct Geometry {
+    pub blocks: u64,
+    pub block_size: usize,
+    pub inodes: u32,
+    pub journal_blocks: u64,
+}
+
+/// Make an empty rfs volume on `device`, with volume label `label`
+pub fn mkfs(device: &Arc<dyn BlockDevice>, label: &str) -> Result<Geometry, VfsError> {
+    let layout = Layout::for_blocks(usable_blocks(device.as_ref())?).ok_or(VfsError::NoSpace)?;
+    bcache::invalidate(device)?;
+
+    // Everything before the data area is in use, as is the root
+    // directory's node, the first data block
+    let root_node = layout.data_start;
+    for block in layout.bitmap_start..layout.data_start {
+        let buffer = bcache::bget(device, block, BLOCK_SIZE)?;
+        {
+            let mut data = buffer.data();
+            data.fill(0);
+            if block < layout.inode_bitmap_start {
+                let base = (block - layout.bitmap_start) * BITS_PER_BLOCK;
+                for bit in base..(base + BITS_PER_BLOCK).min(root_node + 1) {
+                    let i = (bit - base) as usize;
+                    data[i / 8] |= 1 << (i % 8);
+                }
+            } else if block == layout.inode_bitmap_start {
+                data[0] = 1; // the root directory
+            } else if block == layout.inode_table_start {
+                let mut root = RawInode::new(FileType::Directory, FileType::Directory.default_mode(), ROOT_INO);
+                root.set_tree_root(root_node);
+                root.blocks = 1;
+                root.write(&mut data[..INODE_SIZE]);
+            }
+        }
+        bcache::bwrite(&buffer);
+    }
+    let buffer = bcache::bget(device, root_node, BLOCK_SIZE)?;
+    write_node(&mut buffer.data(), 0, &[]);
+    bcache::bwrite(&buffer);
+    drop(buffer);
+
+    // An empty journal, and a descriptor that cannot pass for a transaction
+    write_raw(device.as_ref(), layout.journal_start + JOURNAL_DESCRIPTOR, &vec![0u8; BLOCK_SIZE])?;
+    write_journal_header(device.as_ref(), &layout, 1)?;
+    bcache::sync_device(device)?;
+
+    // The superblock goes last, once the rest is in place
+    let buffer = bcache::bget(device, 0, BLOCK_SIZE)?;
+    layout.write(&mut buffer.data(), label, current_time().tv_sec);
+    bcache::bwrite(&buffer);
+    drop(buffer);
+    bcache::sync_device(device)?;
+
+    Ok(Geometry {
+        blocks: layout.block_count,
+        block_size: BLOCK_SIZE,
+        inodes: layout.inode_count,
+        journal_blocks: JOURNAL_BLOCKS,
+    })
+}
+
+/// What `fsck` found
+#[derive(Default)]
+pub struct FsckReport {
+    /// Journaled blocks written back before checking
+    pub replayed: usize,
+    pub files: u32,
+    pub directories: u32,
+    /// Inodes with no links left, freed at the next mount
+    pub orphans: u32,
+    pub used_blocks: u64,
+    pub blocks: u64,
+    /// Problems found, and descriptions of the first few
+    pub errors: u32,
+    pub messages: Vec<String>,
+}
+
+/// Lowest and highest hash a directory node's records may have
+type HashRange = (u64, u64);
+
+/// What `Checker::check_node` learned about one directory node
+struct NodeSummary {
+    level: u8,
+    records: u32,
+    /// Index records: child, and the range of hashes below it
+    children: Vec<(u64, HashRange)>,
+    problems: Vec<String>,
+}
+
+/// State of an `fsck` run
+struct Checker<'a> {
+    device: &'a Arc<dyn BlockDevice>,
+    layout: Layout,
+    report: FsckReport,
+    /// Set on passes after the first, which would find the same problems again
+    quiet: bool,
+}
+
+impl Checker<'_> {
+    fn problem(&mut self, message: String) {
+        if self.quiet {
+            return;
+        }
+        self.report.errors += 1;
+        if self.report.messages.len() < MAX_MESSAGES {
+            self.report.messages.push(message);
+        }
+    }
+
+    fn read<T>(&self, block: u64, f: impl FnOnce(&[u8]) -> T) -> Result<T, VfsError> {
+        let buffer = bcache::bread(self.device, block, BLOCK_SIZE)?;
+        let data = buffer.data();
+        Ok(f(&data))
+    }
+
+    fn bit(&self, bitmap: u64, bit: u64) -> Result<bool, VfsError> {
+        let i = (bit % BITS_PER_BLOCK) as usize;
+        self.read(bitmap + bit / BITS_PER_BLOCK, |data| data[i / 8] & (1 << (i % 8)) != 0)
+    }
+
+    fn inode(&self, ino: u32) -> Result<RawInode, VfsError> {
+        let index = ino - 1;
+        let at = (index % INODES_PER_BLOCK) as usize * INODE_SIZE;
+        self.read(self.layout.inode_table_start + (index / INODES_PER_BLOCK) as u64, |data| {
+            RawInode::parse(&data[at..at + INODE_SIZE])
+        })
+    }
+
+    /// Note that `ino` uses `block`, in the pass covering blocks from `base`
+    fn mark(&mut self, seen: &mut [u8], base: u64, ino: u32, block: u64) -> bool {
+        if block < self.layout.data_start || block >= self.layout.block_count {
+            self.problem(format!("inode {} uses block {}, outside the data area", ino, block));
+            return false;
+        }
+        if (base..base + BITS_PER_BLOCK).contains(&block) {
+            let i = (block - base) as usize;
+            if seen[i / 8] & (1 << (i % 8)) != 0 {
+                self.problem(format!("block {} is used twice (again by inode {})", block, ino));
+            }
+            seen[i / 8] |= 1 << (i % 8);
+        }
+        true
+    }
+
+    /// Check a file's extents, marking its blocks
+    fn check_extents(&mut self, seen: &mut [u8], base: u64, ino: u32, inode: &RawInode) -> Result<(), VfsError> {
+        let Ok(entries) = inode.extents() else {
+            self.problem(format!("inode {} has a bad extent tree root", ino));
+            return Ok(());
+        };
+        let mut extents = Vec::new();
+        let mut used = 0;
+        if inode.depth == 0 {
+            extents = entries;
+        } else {
+            for (i, entry) in entries.iter().enumerate() {
+                if !self.mark(seen, base, ino, entry.physical) {
+                    continue;
+                }
+                used += 1;
+                let Ok(leaf) = self.read(entry.physical, parse_leaf)? else {
+                    self.problem(format!("inode {} has a bad extent block {}", ino, entry.physical));
+                    continue;
+                };
+                let upper = entries.get(i + 1).map_or(u64::MAX, |next| next.logical as u64);
+                let lower = if i == 0 { 0 } else { entry.logical as u64 };
+                if leaf.is_empty() || leaf.iter().any(|extent| (extent.logical as u64) < lower || extent.end() > upper) {
+                    self.problem(format!("inode {}: extent block {} is empty or out of order", ino, entry.physical));
+                }
+                extents.extend(leaf);
+            }
+        }
+
+        let mut end = 0;
+        for extent in &extents {
+            if extent.len == 0 || (extent.logical as u64) < end {
+                self.problem(format!("inode {}: extents overlap or are out of order", ino));
+            }
+            end = extent.end();
+            for block in extent.physical..extent.physical.saturating_add(extent.len as u64) {
+                if !self.mark(seen, base, ino, block) {
+                    break;
+                }
+            }
+            used += extent.len as u64;
+        }
+        if end > inode.size.div_ceil(BLOCK_SIZE as u64) {
+            self.problem(format!("inode {} has blocks past its size", ino));
+        }
+        if used != inode.blocks {
+            self.problem(format!("inode {} uses {} blocks but counts {}", ino, used, inode.blocks));
+        }
+        Ok(())
+    }
+
+    /// Check one directory node's records against the hash range it covers
+    fn check_node(&self, block: u64, range: HashRange) -> Result<NodeSummary, VfsError> {
+        self.read(block, |node| {
+            let mut summary = NodeSummary {
+                level: 0,
+                records: 0,
+                children: Vec::new(),
+                problems: Vec::new(),
+            };
+            let Ok((level, records)) = check_node(node) else {
+                summary.problems.push(format!("directory node {} is damaged", block));
+                return summary;
+            };
+            summary.level = level;
+            let offsets: Vec<usize> = record_offsets(level, records).collect();
+            let mut previous = None;
+            for (i, &at) in offsets.iter().enumerate() {
+                let hash = read_u64(records, at);
+                if previous.is_some_and(|previous| hash <= previous) || (i > 0 || level == 0) && (hash < range.0 || hash > range.1) {
+                    summary.problems.push(format!("directory node {} is out of order", block));
+                }
+                previous = Some(hash);
+                if level == 0 {
+                    if name_hash(record_name(records, at)) != hash {
+                        summary.problems.push(format!("directory node {}: entry has the wrong hash", block));
+                    }
+                    summary.records += 1;
+                } else {
+                    let lower = if i == 0 { range.0 } else { hash };
+                    let upper = offsets.get(i + 1).map_or(range.1, |&next| read_u64(records, next).saturating_sub(1));
+                    summary.children.push((read_u64(records, at + 8), (lower, upper)));
+                }
+            }
+            summary
+        })
+    }
+
+    /// Check a directory's tree, marking its nodes
+    fn check_directory(&mut self, seen: &mut [u8], base: u64, ino: u32, inode: &RawInode) -> Result<(), VfsError> {
+        let mut pending = vec![(inode.tree_root(), (0, u64::MAX), None::<u8>, true)];
+        let mut nodes = 0;
+        let mut records = 0;
+        while let Some((block, range, expected_level, is_root)) = pending.pop() {
+            if !self.mark(seen, base, ino, block) {
+                continue;
+            }
+            nodes += 1;
+            let summary = self.check_node(block, range)?;
+            for problem in summary.problems {
+                self.problem(format!("directory {}: {}", ino, problem));
+            }
+            if expected_level.is_some_and(|level| level != summary.level) {
+                self.problem(format!("directory {}: node {} is at the wrong level", ino, block));
+                continue;
+            }
+            if !is_root && summary.records == 0 && summary.children.is_empty() {
+                self.problem(format!("directory {}: node {} is empty", ino, block));
+            }
+            records += summary.records;
+            if let Some(level) = summary.level.checked_sub(1) {
+                pending.extend(summary.children.into_iter().map(|(child, range)| (child, range, Some(level), false)));
+            }
+        }
+        if nodes != inode.blocks {
+            self.problem(format!("directory {} uses {} blocks but counts {}", ino, nodes, inode.blocks));
+        }
+        if records != inode.entries {
+            self.problem(format!("directory {} has {} entries but counts {}", ino, records, inode.entries));
+        }
+        Ok(())
+    }
+
+    /// Check every inode and the block bitmap, a bitmap block at a time
+    fn check_blocks(&mut self) -> Result<(), VfsError> {
+        let layout = self.layout;
+        for pass in 0..layout.block_count.div_ceil(BITS_PER_BLOCK) {
+            self.quiet = pass > 0;
+            let base = pass * BITS_PER_BLOCK;
+            let mut seen = vec![0u8; BLOCK_SIZE];
+            for block in base..layout.data_start.min(base + BITS_PER_BLOCK) {
+                let i = (block - base) as usize;
+                seen[i / 8] |= 1 << (i % 8);
+            }
+
+            for ino in 1..=layout.inode_count {
+                let inode = self.inode(ino)?;
+                let allocated = self.bit(layout.inode_bitmap_start, (ino - 1) as u64)?;
+                if allocated != (inode.mode != 0) {
+                    let state = if allocated { "free but marked in use" } else { "in use but marked free" };
+                    self.problem(format!("inode {} is {}", ino, state));
+                }
+                if inode.mode == 0 {
+                    continue;
+                }
+                if !self.quiet {
+                    match inode.file_type() {
+                        FileType::Directory => self.report.directories += 1,
+                        _ => self.report.files += 1,
+                    }
+                    if inode.links == 0 {
+                        self.report.orphans += 1;
+                    }
+                }
+                match inode.file_type() {
+                    FileType::Directory => self.check_directory(&mut seen, base, ino, &inode)?,
+                    FileType::Regular | FileType::Symlink => self.check_extents(&mut seen, base, ino, &inode)?,
+                    _ => {
+                        if inode.blocks != 0 || inode.count != 0 {
+                            self.problem(format!("special file {} has blocks", ino));
+                        }
+                    }
+                }
+            }
+
+            // The bitmap must match what the inodes use
+            let end = (base + BITS_PER_BLOCK).min(layout.block_count);
+            let on_disk = self.read(layout.bitmap_start + pass, |data| data.to_vec())?;
+            for block in base..end {
+                let i = (block - base) as usize;
+                let used = seen[i / 8] & (1 << (i % 8)) != 0;
+                if used != (on_disk[i / 8] & (1 << (i % 8)) != 0) {
+                    let state = if used { "in use but marked free" } else { "marked in use but unused" };
+                    self.quiet = false;
+                    self.problem(format!("block {} is {}", block, state));
+                }
+                if used {
+                    self.report.used_blocks += 1;
+                }
+            }
+        }
+        self.quiet = false;
+        Ok(())
+    }
+
+    /// Entries of a directory, by following its tree
+    fn entries(&self, inode: &RawInode) -> Result<Vec<(u32, u8)>, VfsError> {
+        let mut entries = Vec::new();
+        let mut pending = vec![(inode.tree_root(), 0)];
+        while let Some((block, depth)) = pending.pop() {
+            if depth == MAX_DEPTH || block >= self.layout.block_count {
+                continue;
+            }
+            self.read(block, |node| {
+                let Ok((level, records)) = check_node(node) else {
+                    return;
+                };
+                for at in record_offsets(level, records) {
+                    if level == 0 {
+                        entries.push((read_u32(records, at + 8), records[at + 12]));
+                    } else {
+                        pending.push((read_u64(records, at + 8), depth + 1));
+                    }
+                }
+            })?;
+        }
+        Ok(entries)
+    }
+
+    /// Walk the tree from the root and compare link counts, a range of
+    /// inodes at a time
+    fn check_links(&mut self) -> Result<(), VfsError> {
+        let inode_count = self.layout.inode_count;
+        let mut first = 1;
+        while first <= inode_count {
+            let last = (first + INODE_CHUNK - 1).min(inode_count);
+            self.quiet = first > 1;
+            // Each directory links to itself, and the root has no entry
+            let mut refs = vec![0u16; (last - first + 1) as usize];
+            let count = |refs: &mut Vec<u16>, ino: u32| {
+                if (first..=last).contains(&ino) {
+                    let slot = &mut refs[(ino - first) as usize];
+                    *slot = slot.saturating_add(1);
+                }
+            };
+            count(&mut refs, ROOT_INO);
+
+            let mut pending = vec![ROOT_INO];
+            while let Some(dir) = pending.pop() {
+                let inode = self.inode(dir)?;
+                if inode.file_type() != FileType::Directory {
+                    self.problem(format!("inode {} should be a directory", dir));
+                    continue;
+                }
+                count(&mut refs, dir);
+                for (child, kind) in self.entries(&inode)? {
+                    if child == 0 || child > inode_count {
+                        self.problem(format!("directory {} has an entry for bad inode {}", dir, child));
+                        continue;
+                    }
+                    count(&mut refs, child);
+                    let raw = self.inode(child)?;
+                    if raw.mode == 0 {
+                        self.problem(format!("directory {} has an entry for free inode {}", dir, child));
+                        continue;
+                    }
+                    if kind_file_type(kind) != raw.file_type() {
+                        self.problem(format!("directory {}: entry for inode {} has the wrong type", dir, child));
+                    }
+                    if raw.file_type() == FileType::Directory {
+                        // A subdirectory links back to its parent
+                        count(&mut refs, dir);
+                        if raw.parent == dir {
+                            pending.push(child);
+                        } else {
+                            self.problem(format!("directory {} is in directory {} but names {} as parent", child, dir, raw.parent));
+                        }
+                    }
+                }
+            }
+
+            for ino in first..=last {
+                let inode = self.inode(ino)?;
+                let found = refs[(ino - first) as usize];
+                if inode.mode == 0 || (inode.links == 0 && found == 0) {
+                    continue;
+                }
+                if found == 0 {
+                    self.problem(format!("inode {} is not in any directory", ino));
+                } else if inode.links != found {
+                    self.problem(format!("inode {} has {} links, but {} were found", ino, inode.links, found));
+                }
+            }
+            first = last + 1;
+        }
+        self.quiet = false;
+        Ok(())
+    }
+}
+
+/// Check the rfs volume on `device`
+///
+/// Like e2fsck, this first replays a transaction left in the journal. The
+/// volume must not be mounted.
+pub fn fsck(device: &Arc<dyn BlockDevice>) -> Result<FsckReport, VfsError> {
+    let layout = read_layout(device)?;
+    let (_, replayed) = replay_journal(device, &layout)?;
+    let mut checker = Checker {
+        device,
+        layout,
+        report: FsckReport {
+            replayed,
+            blocks: layout.block_count,
+            ..FsckReport::default()
+        },
+        quiet: false,
+    };
+    let root = checker.inode(ROOT_INO)?;
+    if root.file_type() != FileType::Directory || root.parent != ROOT_INO {
+        checker.problem(String::from("the root inode is not a directory"));
+        return Ok(checker.report);
+    }
+    checker.check_blocks()?;
+    checker.check_links()?;
+    Ok(checker.report)
+}
+
+/// A device that stops writing after a set number of blocks, as if the
+/// power had failed: later writes are dropped without an error, so the
+/// filesystem carries on unaware
+pub struct CrashDevice {
+    device: Arc<dyn BlockDevice>,
+    /// Bytes that may still be written
+    budget: AtomicU64,
+    /// Bytes the filesystem has tried to write
+    attempted: AtomicU64,
+}
+
+impl CrashDevice {
+    /// Wrap `device`, letting `blocks` rfs blocks through (all, if `None`)
+    pub fn new(device: Arc<dyn BlockDevice>, blocks: Option<u64>) -> Self {
+        Self {
+            device,
+            budget: AtomicU64::new(blocks.map_or(u64::MAX, |blocks| blocks * BLOCK_SIZE as u64)),
+            attempted: AtomicU64::new(0),
+        }
+    }
+
+    /// rfs blocks written so far, including those dropped
+    pub fn attempted(&self) -> u64 {
+        self.attempted.load(Ordering::Relaxed) / BLOCK_SIZE as u64
+    }
+}
+
+impl BlockDevice for CrashDevice {
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.device.block_count()
+    }
+
+    fn read_blocks(&self, start: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        self.device.read_blocks(start, buffer)
+    }
+
+    /// Writes past the budget are dropped; the one that crosses it is torn,
+    /// keeping whole device blocks
+    fn write_blocks(&self, start: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        crate::block::check_request(self, start, buffer.len())?;
+        self.attempted.fetch_add(buffer.len() as u64, Ordering::Relaxed);
+        let budget = self.budget.load(Ordering::Relaxed);
+        let keep = (buffer.len() as u64).min(budget) as usize;
+        let keep = keep - keep % self.block_size();
+        self.budget.store(budget - keep as u64, Ordering::Relaxed);
+        if keep == 0 {
+            return Ok(());
+        }
+        self.device.write_blocks(start, &buffer[..keep])
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.device.flush()
+    }
+}
+
+/// Outcome of one `crash_test` run
+pub struct CrashReport {
+    /// Blocks the workload wrote or tried to write
+    pub attempted: u64,
+    /// The check after recovery, including the blocks it replayed
+    pub fsck: FsckReport,
+    /// Files that came back with contents they were never given
+    pub bad_files: u32,
+}
+
+/// Files made by the crash test workload
+const CRASH_FILES: usize = 48;
+
+/// Name of crash test file `i`: long, so directory nodes fill up and split
+fn crash_name(i: usize) -> String {
+    format!("{:0>200}", i)
+}
+
+/// Contents of crash test file `i`
+fn crash_data(i: usize) -> Vec<u8> {
+    let len = (i % 3 + 1) * 2000;
+    (0..len).map(|k| (k as u8).wrapping_mul(31).wrapping_add(i as u8)).collect()
+}
+
+/// The crash test workload: it splits and merges directory nodes, moves a
+/// file's extents out to a leaf block, renames, truncates and unlinks
+fn crash_workload(root: &Arc<dyn Inode>) -> Result<(), VfsError> {
+    let dir = root.create("crash", FileType::Directory)?;
+    for i in 0..CRASH_FILES {
+        dir.create(&crash_name(i), FileType::Regular)?.write(0, &crash_data(i))?;
+    }
+
+    // Appending to two files in turn leaves each in many extents
+    let first = root.create("frag-a", FileType::Regular)?;
+    let second = root.create("frag-b", FileType::Regular)?;
+    let block = vec![0x5Au8; BLOCK_SIZE];
+    for i in 0..12 {
+        first.write(i * BLOCK_SIZE, &block)?;
+        second.write(i * BLOCK_SIZE, &block)?;
+    }
+
+    for i in (0..CRASH_FILES).step_by(2) {
+        dir.remove(&crash_name(i))?;
+    }
+    for i in (1..CRASH_FILES).step_by(4) {
+        dir.rename(&crash_name(i), root, &format!("moved-{}", i))?;
+    }
+    first.truncate(3 * BLOCK_SIZE + 100)?;
+    root.remove("frag-b")?;
+    root.symlink("link", "crash")?;
+    Ok(())
+}
+
+/// Count workload files whose contents are not a prefix of what was written
+fn crash_verify(root: &Arc<dyn Inode>) -> Result<u32, VfsError> {
+    let dir = root.lookup("crash").ok();
+    let mut bad = 0;
+    for i in 0..CRASH_FILES {
+        let file = match dir.as_ref().map(|dir| dir.lookup(&crash_name(i))) {
+            Some(Ok(file)) => file,
+            _ => match root.lookup(&format!("moved-{}", i)) {
+                Ok(file) => file,
+                Err(_) => continue,
+            },
+        };
+        let expected = crash_data(i);
+        let mut contents = vec![0u8; file.size()];
+        let read = file.read(0, &mut contents)?;
+        if read > expected.len() || contents[..read] != expected[..read] {
+            bad += 1;
+        }
+    }
+    Ok(bad)
+}
+
+/// Format `device`, run the crash test workload on it through a
+/// `CrashDevice` that lets `budget` blocks through, then recover and check
+/// the volume
+///
+/// The device's contents are lost.
+pub fn crash_test(device: &Arc<dyn BlockDevice>, budget: Option<u64>) -> Result<CrashReport, VfsError> {
+    mkfs(device, "crashtest")?;
+    bcache::invalidate(device)?;
+
+    let crashing = Arc::new(CrashDevice::new(device.clone(), budget));
+    let crashing_device: Arc<dyn BlockDevice> = crashing.clone();
+    if let Ok(fs) = RfsFs::mount(crashing_device.clone()) {
+        // Past the budget the filesystem reads back stale blocks, so the
+        // workload may well fail; only the recovery matters
+        let _ = crash_workload(&fs.root());
+    }
+    bcache::invalidate(&crashing_device)?;
+    bcache::invalidate(device)?;
+
+    let fsck = fsck(device)?;
+    let fs = RfsFs::mount(device.clone())?;
+    let bad_files = crash_verify(&fs.root())?;
+    drop(fs);
+    bcache::invalidate(device)?;
+    Ok(CrashReport {
+        attempted: crashing.attempted(),
+        fsck,
+        bad_files,
+    })
+}
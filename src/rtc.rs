@@ -0,0 +1,163 @@
+//! CMOS real-time clock (RTC) driver
+//!
+//! Reads the battery-backed wall clock once at boot so the kernel can
+//! provide CLOCK_REALTIME on top of the PIT's monotonic tick count.
+
+use x86_64::instructions::port::Port;
+
+// CMOS ports
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+// CMOS registers
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0A;
+const REG_STATUS_B: u8 = 0x0B;
+const REG_CENTURY: u8 = 0x32;
+
+// Status register bits
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+
+/// A calendar date and time as read from the RTC (UTC)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u32,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Convert to seconds since the Unix epoch
+    pub fn to_unix(self) -> u64 {
+        // Days from civil date (proleptic Gregorian), see H. Hinnant's algorithm
+        let y = if self.month <= 2 { self.year as i64 - 1 } else { self.year as i64 };
+        let era = y.div_euclid(400);
+        let yoe = y - era * 400;
+        let m = self.month as i64;
+        let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + self.day as i64 - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        let days = era * 146097 + doe - 719468;
+
+        let secs = days * 86400
+            + self.hour as i64 * 3600
+            + self.minute as i64 * 60
+            + self.second as i64;
+
+        secs.max(0) as u64
+    }
+
+    /// Convert from seconds since the Unix epoch
+    pub fn from_unix(secs: u64) -> Self {
+        // Civil date from days (inverse of the above)
+        let days = (secs / 86400) as i64 + 719468;
+        let rem = secs % 86400;
+        let era = days.div_euclid(146097);
+        let doe = days - era * 146097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        Self {
+            year: year as u32,
+            month: month as u8,
+            day: day as u8,
+            hour: (rem / 3600) as u8,
+            minute: (rem / 60 % 60) as u8,
+            second: (rem % 60) as u8,
+        }
+    }
+}
+
+fn read_register(reg: u8) -> u8 {
+    unsafe {
+        Port::<u8>::new(CMOS_ADDRESS).write(reg);
+        Port::<u8>::new(CMOS_DATA).read()
+    }
+}
+
+fn update_in_progress() -> bool {
+    read_register(REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0
+}
+
+fn bcd_to_binary(value: u8) -> u8 {
+    (value & 0x0F) + (value >> 4) * 10
+}
+
+/// Raw register snapshot: seconds, minutes, hours, day, month, year, century
+fn read_raw() -> [u8; 7] {
+    while update_in_progress() {
+        core::hint::spin_loop();
+    }
+
+    [
+        read_register(REG_SECONDS),
+        read_register(REG_MINUTES),
+        read_register(REG_HOURS),
+        read_register(REG_DAY),
+        read_register(REG_MONTH),
+        read_register(REG_YEAR),
+        read_register(REG_CENTURY),
+    ]
+}
+
+/// Read the current date and time from the RTC
+pub fn read_datetime() -> DateTime {
+    // Read until two consecutive snapshots agree, so we never see a
+    // value torn by an update in the middle of reading
+    let mut raw = read_raw();
+    loop {
+        let again = read_raw();
+        if again == raw {
+            break;
+        }
+        raw = again;
+    }
+
+    let status_b = read_register(REG_STATUS_B);
+    let [mut second, mut minute, mut hour, mut day, mut month, mut year, mut century] = raw;
+
+    if status_b & STATUS_B_BINARY == 0 {
+        second = bcd_to_binary(second);
+        minute = bcd_to_binary(minute);
+        hour = bcd_to_binary(hour & 0x7F) | (hour & 0x80);
+        day = bcd_to_binary(day);
+        month = bcd_to_binary(month);
+        year = bcd_to_binary(year);
+        century = bcd_to_binary(century);
+    }
+
+    // Convert 12-hour clock (PM flag in bit 7, 12 meaning 0) to 24-hour
+    if status_b & STATUS_B_24_HOUR == 0 {
+        let pm = hour & 0x80 != 0;
+        hour = (hour & 0x7F) % 12 + if pm { 12 } else { 0 };
+    }
+
+    // The century register is not present on every chipset
+    let full_year = if (19..=99).contains(&century) {
+        century as u32 * 100 + year as u32
+    } else {
+        2000 + year as u32
+    };
+
+    DateTime {
+        year: full_year,
+        month,
+        day,
+        hour,
+        minute,
+        second,
+    }
+}
@@ -0,0 +1,233 @@
+//! Syscall filtering sandbox (seccomp-style allowlists)
+//!
+//! A process installs a filter with the `SetSyscallFilter` syscall. Every
+//! later syscall is checked against it in `dispatch_syscall` before the
+//! handler runs. Filters can only be added, never removed: when several are
+//! installed, the most restrictive verdict wins.
+//!
+//! `ProcessResources::fork` copies a process's filters into a child's
+//! resources, but the kernel has no fork or exec yet (both return
+//! `NotImplemented`), so nothing is inherited in practice.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::syscall::SyscallError;
+
+/// Matches any syscall number in a rule
+pub const FILTER_ANY_SYSCALL: u64 = u64::MAX;
+
+/// Rule applies regardless of arguments
+pub const FILTER_NO_ARG: u32 = u32::MAX;
+
+/// Maximum number of rules in a single filter
+pub const MAX_FILTER_RULES: usize = 256;
+
+/// Action codes (`FilterRule::action`)
+pub const ACTION_ALLOW: u32 = 0;
+pub const ACTION_DENY: u32 = 1;
+pub const ACTION_ERRNO: u32 = 2;
+pub const ACTION_KILL: u32 = 3;
+
+/// Argument comparison operators (`FilterRule::op`)
+pub const CMP_EQ: u32 = 0;
+pub const CMP_NE: u32 = 1;
+pub const CMP_LT: u32 = 2;
+pub const CMP_LE: u32 = 3;
+pub const CMP_GT: u32 = 4;
+pub const CMP_GE: u32 = 5;
+pub const CMP_MASKED_EQ: u32 = 6;
+
+/// A filter rule as laid out in user memory
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct FilterRule {
+    /// Syscall number, or `FILTER_ANY_SYSCALL`
+    pub syscall: u64,
+    /// One of the `ACTION_*` codes
+    pub action: u32,
+    /// Error number returned for `ACTION_ERRNO`
+    pub errno: u32,
+    /// Argument to compare (0-5), or `FILTER_NO_ARG`
+    pub arg_index: u32,
+    /// One of the `CMP_*` operators
+    pub op: u32,
+    /// Value to compare the argument against
+    pub value: u64,
+    /// Mask applied to the argument for `CMP_MASKED_EQ`
+    pub mask: u64,
+}
+
+/// What to do with a filtered syscall
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterAction {
+    Allow,
+    /// Fail with EPERM
+    Deny,
+    /// Fail with the given error number
+    Errno(u16),
+    /// Terminate the calling process
+    Kill,
+}
+
+impl FilterAction {
+    fn from_raw(action: u32, errno: u32) -> Option<Self> {
+        match action {
+            ACTION_ALLOW => Some(FilterAction::Allow),
+            ACTION_DENY => Some(FilterAction::Deny),
+            ACTION_ERRNO => u16::try_from(errno).ok().map(FilterAction::Errno),
+            ACTION_KILL => Some(FilterAction::Kill),
+            _ => None,
+        }
+    }
+
+    /// Higher values are more restrictive
+    fn severity(&self) -> u8 {
+        match self {
+            FilterAction::Allow => 0,
+            FilterAction::Errno(_) => 1,
+            FilterAction::Deny => 2,
+            FilterAction::Kill => 3,
+        }
+    }
+}
+
+/// Argument comparison
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArgCmp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    MaskedEq(u64),
+}
+
+impl ArgCmp {
+    fn from_raw(op: u32, mask: u64) -> Option<Self> {
+        match op {
+            CMP_EQ => Some(ArgCmp::Eq),
+            CMP_NE => Some(ArgCmp::Ne),
+            CMP_LT => Some(ArgCmp::Lt),
+            CMP_LE => Some(ArgCmp::Le),
+            CMP_GT => Some(ArgCmp::Gt),
+            CMP_GE => Some(ArgCmp::Ge),
+            CMP_MASKED_EQ => Some(ArgCmp::MaskedEq(mask)),
+            _ => None,
+        }
+    }
+
+    fn matches(&self, arg: u64, value: u64) -> bool {
+        match *self {
+            ArgCmp::Eq => arg == value,
+            ArgCmp::Ne => arg != value,
+            ArgCmp::Lt => arg < value,
+            ArgCmp::Le => arg <= value,
+            ArgCmp::Gt => arg > value,
+            ArgCmp::Ge => arg >= value,
+            ArgCmp::MaskedEq(mask) => arg & mask == value,
+        }
+    }
+}
+
+/// A validated rule
+#[derive(Debug, Clone, Copy)]
+struct Rule {
+    syscall: Option<u64>,
+    arg: Option<(usize, ArgCmp, u64)>,
+    action: FilterAction,
+}
+
+impl Rule {
+    fn matches(&self, syscall_num: u64, args: &[u64; 6]) -> bool {
+        if let Some(num) = self.syscall {
+            if num != syscall_num {
+                return false;
+            }
+        }
+
+        match self.arg {
+            Some((index, cmp, value)) => cmp.matches(args[index], value),
+            None => true,
+        }
+    }
+}
+
+/// A syscall filter: ordered rules plus a default action
+///
+/// Rules are checked in order and the first match decides; if none match,
+/// the default action applies.
+#[derive(Debug, Clone)]
+pub struct SyscallFilter {
+    rules: Vec<Rule>,
+    default: FilterAction,
+}
+
+impl SyscallFilter {
+    /// Build a filter from raw rules, validating every field
+    pub fn new(raw: &[FilterRule], default_action: u32, default_errno: u32) -> Result<Self, SyscallError> {
+        if raw.len() > MAX_FILTER_RULES {
+            return Err(SyscallError::InvalidArgument);
+        }
+
+        let default = FilterAction::from_raw(default_action, default_errno)
+            .ok_or(SyscallError::InvalidArgument)?;
+
+        let mut rules = Vec::with_capacity(raw.len());
+        for r in raw {
+            let action = FilterAction::from_raw(r.action, r.errno)
+                .ok_or(SyscallError::InvalidArgument)?;
+
+            let arg = if r.arg_index == FILTER_NO_ARG {
+                None
+            } else if (r.arg_index as usize) < 6 {
+                let cmp = ArgCmp::from_raw(r.op, r.mask).ok_or(SyscallError::InvalidArgument)?;
+                Some((r.arg_index as usize, cmp, r.value))
+            } else {
+                return Err(SyscallError::InvalidArgument);
+            };
+
+            let syscall = if r.syscall == FILTER_ANY_SYSCALL { None } else { Some(r.syscall) };
+
+            rules.push(Rule { syscall, arg, action });
+        }
+
+        Ok(Self { rules, default })
+    }
+
+    /// Decide what happens to a syscall
+    pub fn evaluate(&self, syscall_num: u64, args: &[u64; 6]) -> FilterAction {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(syscall_num, args))
+            .map(|rule| rule.action)
+            .unwrap_or(self.default)
+    }
+}
+
+/// The stack of filters installed on a process
+#[derive(Clone, Default)]
+pub struct FilterChain {
+    filters: Vec<Arc<SyscallFilter>>,
+}
+
+impl FilterChain {
+    pub fn new() -> Self {
+        Self { filters: Vec::new() }
+    }
+
+    /// Add a filter on top of those already installed
+    pub fn install(&mut self, filter: SyscallFilter) {
+        self.filters.push(Arc::new(filter));
+    }
+
+    /// Evaluate every installed filter and return the most restrictive verdict
+    pub fn evaluate(&self, syscall_num: u64, args: &[u64; 6]) -> FilterAction {
+        self.filters
+            .iter()
+            .map(|filter| filter.evaluate(syscall_num, args))
+            .max_by_key(|action| action.severity())
+            .unwrap_or(FilterAction::Allow)
+    }
+}
@@ -0,0 +1,191 @@
+//! Destructive boot-time self-tests
+//!
+//! These write all over /dev/ram0: merged request-queue writes, a scratch
+//! partition table, the buffer cache, an rfs volume and a
+//! crash-injection run, then an ISO9660 volume mounted where the rfs one
+//! was. Anything kept on ram0 is lost, so `kernel_main`
+//! only runs them in a kernel built with `--features selftest`.
+
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::bcache;
+use crate::block::{self, BlockDevice, RequestQueue};
+use crate::partition;
+use crate::println;
+use crate::shell::Shell;
+
+/// Run every test on /dev/ram0
+pub fn ram0(shell: &mut Shell) {
+    let Some(ram0) = block::get("ram0") else {
+        println!("  no /dev/ram0");
+        return;
+    };
+    request_queue(&ram0);
+    partition_table(&ram0);
+    buffer_cache(&ram0);
+    rfs(shell);
+    block_sizes(shell, &ram0);
+}
+
+/// Three adjacent single-block writes should reach the disk as one
+fn request_queue(ram0: &Arc<RequestQueue>) {
+    let pattern: Vec<u8> = (0..3 * block::SECTOR_SIZE).map(|i| (i / block::SECTOR_SIZE) as u8 + b'A').collect();
+    let mut readback = vec![0u8; pattern.len()];
+    let result = pattern
+        .chunks(block::SECTOR_SIZE)
+        .enumerate()
+        .try_for_each(|(i, chunk)| ram0.write_blocks(10 + i as u64, chunk))
+        .and_then(|()| ram0.read_blocks(10, &mut readback))
+        .and_then(|()| ram0.flush());
+    let stats = ram0.stats();
+    match result {
+        Ok(()) if readback == pattern => println!(
+            "  ram0 request queue: {} writes, {} merges, {} dispatched",
+            stats.writes, stats.merges, stats.dispatched
+        ),
+        Ok(()) => println!("  ram0 read back different data"),
+        Err(e) => println!("  ram0 I/O failed: {}", e),
+    }
+}
+
+/// Parse a scratch MBR: two primaries, one of them extended with two
+/// logical partitions, then wipe it again
+fn partition_table(ram0: &Arc<RequestQueue>) {
+    let mut mbr = vec![0u8; block::SECTOR_SIZE];
+    let mut ebrs = [vec![0u8; block::SECTOR_SIZE], vec![0u8; block::SECTOR_SIZE]];
+    let entry = |sector: &mut [u8], index: usize, kind: u8, start: u32, count: u32| {
+        let at = 446 + index * 16;
+        sector[at + 4] = kind;
+        sector[at + 8..at + 12].copy_from_slice(&start.to_le_bytes());
+        sector[at + 12..at + 16].copy_from_slice(&count.to_le_bytes());
+        sector[510] = 0x55;
+        sector[511] = 0xAA;
+    };
+    entry(&mut mbr, 0, 0x0C, 64, 960);
+    entry(&mut mbr, 1, 0x05, 1024, 1024);
+    entry(&mut ebrs[0], 0, 0x83, 32, 480); // logical 5 at 1056
+    entry(&mut ebrs[0], 1, 0x05, 512, 512); // next EBR at 1536
+    entry(&mut ebrs[1], 0, 0x83, 32, 480); // logical 6 at 1568
+
+    let result = ram0
+        .write_blocks(0, &mbr)
+        .and_then(|()| ram0.write_blocks(1024, &ebrs[0]))
+        .and_then(|()| ram0.write_blocks(1536, &ebrs[1]))
+        .map_err(partition::PartitionError::Io)
+        .and_then(|()| partition::read_table(ram0.as_ref()));
+    match result {
+        Ok(table) => {
+            for part in &table.entries {
+                println!(
+                    "  ram0 partition {}: start {}, {} blocks, {}",
+                    part.number,
+                    part.start,
+                    part.blocks,
+                    part.kind.description()
+                );
+            }
+        }
+        Err(e) => println!("  ram0 partition table: {}", e),
+    }
+    let _ = ram0.write_blocks(0, &vec![0u8; block::SECTOR_SIZE]);
+}
+
+/// The second read of a block is a hit, and the change made through it
+/// reaches the disk on sync
+fn buffer_cache(ram0: &Arc<RequestQueue>) {
+    let device: Arc<dyn BlockDevice> = ram0.clone();
+    let result = bcache::bread(&device, 4, 1024).and_then(|buffer| {
+        buffer.data()[..6].copy_from_slice(b"cached");
+        bcache::bwrite(&buffer);
+        bcache::brelse(buffer);
+        let again = bcache::bread(&device, 4, 1024)?;
+        let hit = &again.data()[..6] == b"cached";
+        bcache::brelse(again);
+        bcache::sync()?;
+        let mut sector = [0u8; block::SECTOR_SIZE];
+        ram0.read_blocks(8, &mut sector)?;
+        Ok(hit && &sector[..6] == b"cached")
+    });
+    match result {
+        Ok(true) => println!("  buffer cache: hit on re-read, written back on sync"),
+        Ok(false) => println!("  buffer cache: data mismatch"),
+        Err(e) => println!("  buffer cache: {}", e),
+    }
+}
+
+/// Make an rfs volume, use it, check it, then crash it on purpose
+fn rfs(shell: &mut Shell) {
+    let _ = shell.execute_line("mkfs -t rfs -L scratch /dev/ram0");
+    let _ = shell.execute_line("mkdir /mnt");
+    let _ = shell.execute_line("mount -t rfs /dev/ram0 /mnt");
+    let _ = shell.execute_line("mkdir /mnt/docs");
+    let _ = shell.execute_line("echo journaled by rustos > /mnt/docs/note.txt");
+    let _ = shell.execute_line("cat /mnt/docs/note.txt");
+    let _ = shell.execute_line("ls -l /mnt/docs");
+    let _ = shell.execute_line("rm /mnt/docs/note.txt");
+    let _ = shell.execute_line("umount /mnt");
+    let _ = shell.execute_line("rmdir /mnt");
+    let _ = shell.execute_line("fsck -t rfs /dev/ram0");
+    let _ = shell.execute_line("crashtest -n 600 /dev/ram0");
+}
+
+/// Mount rfs (4096-byte blocks), then ISO9660 (2048-byte blocks) on the
+/// same device: the second must not see the first one's buffers
+fn block_sizes(shell: &mut Shell, ram0: &Arc<RequestQueue>) {
+    let rfs = ["mkfs -t rfs /dev/ram0", "mkdir /mnt", "mount -t rfs /dev/ram0 /mnt", "ls /mnt", "umount /mnt"];
+    let iso = ["mount -t iso9660 /dev/ram0 /mnt", "ls /mnt", "umount /mnt"];
+    let result = rfs
+        .iter()
+        .try_for_each(|line| shell.execute_line(line))
+        .and_then(|()| write_iso(ram0).map_err(|_| "writing the ISO9660 image failed"))
+        .and_then(|()| iso.iter().try_for_each(|line| shell.execute_line(line)));
+    let _ = shell.execute_line("rmdir /mnt");
+    match result {
+        Ok(()) => println!("  block sizes: rfs then iso9660 on one device"),
+        Err(e) => println!("  block sizes: {}", e),
+    }
+}
+
+/// Write an empty ISO9660 volume straight to the device: a primary
+/// descriptor, the terminator, and a root directory holding `.` and `..`
+fn write_iso(ram0: &Arc<RequestQueue>) -> Result<(), block::BlockError> {
+    const BLOCK: usize = 2048;
+    const ROOT: u32 = 18;
+    let sectors = |iso_block: u32| iso_block as u64 * (BLOCK / block::SECTOR_SIZE) as u64;
+    let record = |to: &mut [u8], name: u8| {
+        to[0] = 34;
+        to[2..6].copy_from_slice(&ROOT.to_le_bytes());
+        to[6..10].copy_from_slice(&ROOT.to_be_bytes());
+        to[10..14].copy_from_slice(&(BLOCK as u32).to_le_bytes());
+        to[14..18].copy_from_slice(&(BLOCK as u32).to_be_bytes());
+        to[25] = 2; // directory
+        to[28..32].copy_from_slice(&[1, 0, 0, 1]); // volume sequence number
+        to[32] = 1;
+        to[33] = name;
+    };
+
+    let mut block = vec![0u8; BLOCK];
+    block[0] = 1;
+    block[1..6].copy_from_slice(b"CD001");
+    block[6] = 1;
+    block[40..72].fill(b' ');
+    block[40..47].copy_from_slice(b"SCRATCH");
+    block[128..130].copy_from_slice(&(BLOCK as u16).to_le_bytes());
+    block[130..132].copy_from_slice(&(BLOCK as u16).to_be_bytes());
+    record(&mut block[156..190], 0);
+    ram0.write_blocks(sectors(16), &block)?;
+
+    block.fill(0);
+    block[0] = 255;
+    block[1..6].copy_from_slice(b"CD001");
+    block[6] = 1;
+    ram0.write_blocks(sectors(17), &block)?;
+
+    block.fill(0);
+    record(&mut block[..34], 0);
+    record(&mut block[34..68], 1);
+    ram0.write_blocks(sectors(ROOT), &block)?;
+    ram0.flush()
+}
@@ -7,6 +7,8 @@ use alloc::format;
 use alloc::sync::Arc;
 use spin::Mutex;
 
+use crate::syscall::{SyscallError, SyscallNumber, SyscallResult};
+
 /// Job state for background process management
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[allow(dead_code)]
@@ -97,7 +99,6 @@ impl EnvironmentVariables {
 /// Shell state structure
 pub struct Shell {
     env: EnvironmentVariables,
-    cwd: String,
     /// Command history buffer (circular, max 100 commands)
     #[allow(dead_code)]
     history: Vec<String>,
@@ -129,7 +130,6 @@ impl Shell {
     pub fn new() -> Self {
         Self {
             env: EnvironmentVariables::new(),
-            cwd: String::from("/"),
             history: Vec::new(),
             history_pos: -1,
             max_history: 100,
@@ -334,10 +334,10 @@ impl Shell {
     #[allow(dead_code)]
     fn complete_command(&self, prefix: &str) -> Option<Vec<String>> {
         let commands = [
-            "alias", "bg", "cat", "cd", "clear/cls", "cp", "echo", "export",
-            "fg", "grep", "head", "help", "jobs", "ls", "mkdir", "mv", "ps",
-            "pwd", "reboot", "rm", "rmdir", "source", "tail", "touch",
-            "unalias", "unset", "uptime", "wc",
+            "alias", "bg", "cat", "cd", "chmod", "chown", "clear/cls", "cp", "echo",
+            "export", "fg", "grep", "head", "help", "jobs", "ln", "ls", "lsblk", "mkdir", "mount",
+            "mv", "ps", "pwd", "reboot", "rm", "rmdir", "source", "stat", "strace",
+            "sync", "tail", "touch", "umount", "unalias", "unset", "uptime", "wc",
         ];
 
         let matches: Vec<String> = commands
@@ -364,18 +364,22 @@ impl Shell {
             let file_part = &prefix[last_slash + 1..];
             (self.resolve_path(dir_part), file_part.to_string())
         } else {
-            // Just a filename in current directory
-            (self.cwd.clone(), prefix.to_string())
+            // Just a filename in current directory, looked up from the cwd dentry
+            (String::from("."), prefix.to_string())
         };
 
         // Try to get directory listing
-        let tmpfs = crate::tmpfs::TMPFS.lock();
 
-        if let Ok(inode) = tmpfs.resolve_path(&search_dir) {
-            if let Ok(entries) = inode.list() {
+        if let Ok(inode) = crate::mount::resolve_path(&search_dir) {
+            if let Ok(entries) = crate::vfs::read_dir(&inode).collect::<Result<Vec<_>, _>>() {
+                // Directories complete with a trailing slash
                 let matches: Vec<String> = entries
                     .into_iter()
-                    .filter(|name| name.starts_with(&file_prefix))
+                    .filter(|entry| entry.name.starts_with(&file_prefix))
+                    .map(|entry| match entry.file_type {
+                        crate::vfs::FileType::Directory => format!("{}/", entry.name),
+                        _ => entry.name,
+                    })
                     .collect();
 
                 if matches.is_empty() {
@@ -432,17 +436,14 @@ impl Shell {
                 // Process substitution: create a temporary file with command output
                 if let Ok(output) = self.capture_command_output(&cmd) {
                     // In a real shell, this would create a named pipe or /dev/fd/N
-                    // For now, we'll create a temporary file in tmpfs
+                    // For now, we'll create a temporary file under /tmp
                     let temp_filename = format!("/tmp/procsub_{}", self.next_job_id);
                     self.next_job_id += 1;
 
-                    let tmpfs = crate::tmpfs::TMPFS.lock();
-                    if let Ok(inode) = tmpfs.create_file(&temp_filename) {
+                    if let Ok(inode) = crate::mount::create_file(&temp_filename) {
                         let _ = inode.write(0, &output);
-                        drop(tmpfs);
                         result.push_str(&temp_filename);
                     } else {
-                        drop(tmpfs);
                         // Failed to create temp file, just skip
                         crate::println!("Warning: Failed to create process substitution temp file");
                     }
@@ -473,14 +474,11 @@ impl Shell {
                 let temp_filename = format!("/tmp/procsub_{}", self.next_job_id);
                 self.next_job_id += 1;
 
-                let tmpfs = crate::tmpfs::TMPFS.lock();
-                if let Ok(_) = tmpfs.create_file(&temp_filename) {
-                    drop(tmpfs);
+                if let Ok(_) = crate::mount::create_file(&temp_filename) {
                     result.push_str(&temp_filename);
                     crate::println!("Note: Process substitution >(cmd) created temp file: {}", temp_filename);
                     crate::println!("      Command '{}' would consume data written to this file", cmd);
                 } else {
-                    drop(tmpfs);
                     crate::println!("Warning: Failed to create process substitution temp file");
                 }
             } else if ch == '$' && chars.peek() == Some(&'(') {
@@ -756,38 +754,35 @@ impl Shell {
 
         // Extract directory path and filename pattern
         let (dir_path, file_pattern) = if let Some(last_slash) = pattern.rfind('/') {
-            (&pattern[..last_slash], &pattern[last_slash+1..])
+            let dir = if last_slash == 0 { "/" } else { &pattern[..last_slash] };
+            (dir, &pattern[last_slash+1..])
         } else {
-            // No directory, use current directory
-            (self.cwd.as_str(), pattern)
+            // No directory, use current directory (resolved from the cwd dentry)
+            (".", pattern)
         };
 
         // Try to list directory contents
-        let tmpfs = crate::tmpfs::TMPFS.lock();
-        let dir_inode = match tmpfs.resolve_path(dir_path) {
+        let dir_inode = match crate::mount::resolve_path(dir_path) {
             Ok(inode) => inode,
             Err(_) => {
-                drop(tmpfs);
                 return vec![pattern.to_string()];
             }
         };
 
-        let entries = match dir_inode.list() {
+        let entries = match crate::vfs::read_dir(&dir_inode).collect::<Result<Vec<_>, _>>() {
             Ok(entries) => entries,
             Err(_) => {
-                drop(tmpfs);
                 return vec![pattern.to_string()];
             }
         };
-        drop(tmpfs);
 
         // Filter entries that match the glob pattern
         let mut matches = Vec::new();
-        for entry in entries {
+        for entry in entries.into_iter().map(|entry| entry.name) {
             if self.glob_match(file_pattern, &entry) {
                 let full_path = if dir_path == "/" {
                     format!("/{}", entry)
-                } else if dir_path == "." || dir_path == self.cwd.as_str() {
+                } else if dir_path == "." {
                     entry
                 } else {
                     format!("{}/{}", dir_path, entry)
@@ -1032,18 +1027,18 @@ impl Shell {
             match cmd {
                 "ls" => {
                     let path = if args.is_empty() {
-                        self.cwd.clone()
+                        self.cwd()
                     } else {
                         self.resolve_path(args[0])
                     };
 
-                    let tmpfs = crate::tmpfs::TMPFS.lock();
-                    match tmpfs.resolve_path(&path) {
+                    match crate::mount::resolve_path(&path) {
                         Ok(inode) => {
-                            if let Ok(entries) = inode.list() {
+                            if let Ok(mut entries) = crate::vfs::read_dir(&inode).collect::<Result<Vec<_>, _>>() {
+                                entries.sort_by(|a, b| a.name.cmp(&b.name));
                                 let mut output = Vec::new();
                                 for entry in entries {
-                                    output.extend_from_slice(entry.as_bytes());
+                                    output.extend_from_slice(entry.name.as_bytes());
                                     output.push(b'\n');
                                 }
                                 Ok(output)
@@ -1060,9 +1055,8 @@ impl Shell {
                     }
 
                     let path = self.resolve_path(args[0]);
-                    let tmpfs = crate::tmpfs::TMPFS.lock();
 
-                    match tmpfs.resolve_path(&path) {
+                    match crate::mount::resolve_path(&path) {
                         Ok(inode) => {
                             let mut output = Vec::new();
                             let mut offset = 0;
@@ -1083,6 +1077,11 @@ impl Shell {
                         Err(_) => Err("file not found")
                     }
                 }
+                "echo" => {
+                    let mut output = args.join(" ").into_bytes();
+                    output.push(b'\n');
+                    Ok(output)
+                }
                 _ => {
                     crate::println!("Error: Command '{}' cannot be used in pipes yet", cmd);
                     Err("command not pipeable")
@@ -1219,103 +1218,33 @@ impl Shell {
         // Capture command output
         let output = self.capture_command_output(line)?;
 
-        // Write to file
+        // `>` truncates, `>>` appends; both create a missing file
         let path = self.resolve_path(output_file);
-        let tmpfs = crate::tmpfs::TMPFS.lock();
-
-        if append {
-            // Append mode: read existing content, append new content
-            match tmpfs.resolve_path(&path) {
-                Ok(inode) => {
-                    // Read existing content
-                    let mut existing = Vec::new();
-                    let mut offset = 0;
-                    let mut buffer = [0u8; 1024];
-
-                    loop {
-                        match inode.read(offset, &mut buffer) {
-                            Ok(0) => break,
-                            Ok(bytes_read) => {
-                                existing.extend_from_slice(&buffer[..bytes_read]);
-                                offset += bytes_read;
-                            }
-                            Err(_) => break
-                        }
-                    }
+        let mut flags = crate::vfs::OpenFlags::write_only();
+        flags.create = true;
+        flags.truncate = !append;
+        flags.append = append;
 
-                    // Append new content
-                    existing.extend_from_slice(&output);
+        let file = match crate::mount::open(&path, flags, crate::vfs::FileType::Regular.default_mode()) {
+            Ok(file) => file,
+            Err(e) => {
+                crate::println!("Error opening {}: {}", output_file, e);
+                return Err("file error");
+            }
+        };
 
-                    // Write back
-                    match inode.write(0, &existing) {
-                        Ok(_) => {
-                            crate::println!("Output appended to {}", output_file);
-                            Ok(())
-                        }
-                        Err(e) => {
-                            crate::println!("Error writing to file: {}", e);
-                            Err("write error")
-                        }
-                    }
-                }
-                Err(_) => {
-                    // File doesn't exist, create it
-                    match tmpfs.create_file(&path) {
-                        Ok(inode) => {
-                            match inode.write(0, &output) {
-                                Ok(_) => {
-                                    crate::println!("Output written to {}", output_file);
-                                    Ok(())
-                                }
-                                Err(e) => {
-                                    crate::println!("Error writing to file: {}", e);
-                                    Err("write error")
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            crate::println!("Error creating file: {}", e);
-                            Err("file creation error")
-                        }
-                    }
+        match file.write(&output) {
+            Ok(_) => {
+                if append {
+                    crate::println!("Output appended to {}", output_file);
+                } else {
+                    crate::println!("Output written to {}", output_file);
                 }
+                Ok(())
             }
-        } else {
-            // Overwrite mode
-            match tmpfs.create_file(&path) {
-                Ok(inode) => {
-                    match inode.write(0, &output) {
-                        Ok(_) => {
-                            crate::println!("Output written to {}", output_file);
-                            Ok(())
-                        }
-                        Err(e) => {
-                            crate::println!("Error writing to file: {}", e);
-                            Err("write error")
-                        }
-                    }
-                }
-                Err(_) => {
-                    // Try to open existing file
-                    match tmpfs.resolve_path(&path) {
-                        Ok(inode) => {
-                            match inode.write(0, &output) {
-                                Ok(_) => {
-                                    crate::println!("Output written to {}", output_file);
-                                    Ok(())
-                                }
-                                Err(e) => {
-                                    crate::println!("Error writing to file: {}", e);
-                                    Err("write error")
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            crate::println!("Error: {}", e);
-                            Err("file error")
-                        }
-                    }
-                }
+            Err(e) => {
+                crate::println!("Error writing to file: {}", e);
+                Err("write error")
             }
         }
     }
@@ -1385,6 +1314,18 @@ impl Shell {
             "alias" => self.cmd_alias(args),
             "unalias" => self.cmd_unalias(args),
             "source" => self.cmd_source(args),
+            "strace" => self.cmd_strace(args),
+            "mount" => self.cmd_mount(args),
+            "umount" => self.cmd_umount(args),
+            "lsblk" => self.cmd_lsblk(args),
+            "mkfs" => self.cmd_mkfs(args),
+            "fsck" => self.cmd_fsck(args),
+            "crashtest" => self.cmd_crashtest(args),
+            "stat" => self.cmd_stat(args),
+            "ln" => self.cmd_ln(args),
+            "sync" => self.cmd_sync(args),
+            "chmod" => self.cmd_chmod(args),
+            "chown" => self.cmd_chown(args),
             _ => {
                 crate::println!("Command not found: {}", cmd);
                 Err("command not found")
@@ -1394,24 +1335,28 @@ impl Shell {
 
     /// Echo command - print arguments
     fn cmd_echo(&mut self, args: &[&str]) -> Result<(), &'static str> {
+        let mut line = String::new();
         for (i, arg) in args.iter().enumerate() {
             if i > 0 {
-                crate::print!(" ");
+                line.push(' ');
             }
 
             // Simple variable expansion for $VAR
             if let Some(var_name) = arg.strip_prefix('$') {
                 if let Some(value) = self.env.get(var_name) {
-                    crate::print!("{}", value);
-                } else {
-                    crate::print!("");
+                    line.push_str(value);
                 }
             } else {
-                crate::print!("{}", arg);
+                line.push_str(arg);
             }
         }
-        crate::println!();
-        Ok(())
+        line.push('\n');
+
+        // Written with write(2) to stdout so that `strace echo` shows it
+        match syscall(SyscallNumber::Write, STDOUT_FD, line.as_ptr() as u64, line.len() as u64) {
+            Ok(_) => Ok(()),
+            Err(_) => Err("write error"),
+        }
     }
 
     /// Export command - set environment variable
@@ -1457,7 +1402,7 @@ impl Shell {
         crate::println!("  help             - Show this help message");
         crate::println!("  ps               - List running processes");
         crate::println!("  cat <file>       - Display file contents");
-        crate::println!("  ls [dir]         - List directory contents");
+        crate::println!("  ls [-l] [dir]    - List directory contents (-l: long format)");
         crate::println!("  pwd              - Print working directory");
         crate::println!("  cd <dir>         - Change directory");
         crate::println!("  mkdir <dir>      - Create directory");
@@ -1465,11 +1410,13 @@ impl Shell {
         crate::println!("  rm <file>        - Remove file");
         crate::println!("  cp <src> <dst>   - Copy file");
         crate::println!("  mv <src> <dst>   - Move/rename file");
-        crate::println!("  touch <file>     - Create empty file");
+        crate::println!("  ln [-s] <t> <l>  - Create a hard or symbolic link");
+        crate::println!("  sync             - Write cached file data back");
+        crate::println!("  touch <file>     - Create empty file or update its times");
         crate::println!("  wc <file>        - Count lines, words, and characters");
         crate::println!("  grep <pat> <f>   - Search for pattern in file (-i -n)");
         crate::println!("  head [-n N] <f>  - Display first N lines of file");
-        crate::println!("  tail [-n N] <f>  - Display last N lines of file");
+        crate::println!("  tail [-f] <f>    - Display last N lines of file (-n N, -f follow)");
         crate::println!("  uptime           - Show system uptime");
         crate::println!("  free             - Display memory information");
         crate::println!("  env              - Display environment variables");
@@ -1483,6 +1430,16 @@ impl Shell {
         crate::println!("  alias [name=cmd] - Create or list command aliases");
         crate::println!("  unalias <name>   - Remove alias");
         crate::println!("  source <file>    - Execute shell script");
+        crate::println!("  strace <cmd>     - Trace syscalls made by cat/echo (-p PID)");
+        crate::println!("  mount [-t T [dev] dir] - List mounts or mount a filesystem");
+        crate::println!("  umount <dir>     - Unmount a filesystem");
+        crate::println!("  lsblk            - List block devices and partitions");
+        crate::println!("  mkfs -t rfs <dev> - Make an rfs filesystem (-L label)");
+        crate::println!("  fsck -t rfs <dev> - Check an unmounted rfs filesystem");
+        crate::println!("  crashtest <dev>  - Crash rfs after -n blocks and check recovery");
+        crate::println!("  stat <file>      - Show file attributes");
+        crate::println!("  chmod <mode> <f> - Change file permissions (octal)");
+        crate::println!("  chown <u[:g]> <f> - Change file owner and group");
         crate::println!("");
         crate::println!("Advanced Features:");
         crate::println!("  cmd1 | cmd2      - Pipe output (ls | grep pattern)");
@@ -1503,10 +1460,7 @@ impl Shell {
 
     /// Process list command - show running processes
     fn cmd_ps(&mut self) -> Result<(), &'static str> {
-        use crate::process::PROCESS_MANAGER;
-
-        let pm = PROCESS_MANAGER.lock();
-        let count = pm.process_count();
+        let count = crate::process::process_count();
 
         crate::println!("PID   STATE    NAME");
         crate::println!("---   -----    ----");
@@ -1528,90 +1482,123 @@ impl Shell {
             return Err("missing file argument");
         }
 
-        let path = self.resolve_path(args[0]);
-        let tmpfs = crate::tmpfs::TMPFS.lock();
-
-        match tmpfs.resolve_path(&path) {
-            Ok(inode) => {
-                if inode.file_type() == crate::vfs::FileType::Directory {
-                    crate::println!("cat: {}: Is a directory", args[0]);
-                    return Err("is a directory");
-                }
-
-                let mut offset = 0;
-                let mut buffer = [0u8; 1024];
+        // Goes through open/read/close like a user program, so `strace cat`
+        // has something to show
+        let mut path = self.resolve_path(args[0]).into_bytes();
+        path.push(0);
+        let fd = match syscall(SyscallNumber::Open, path.as_ptr() as u64, crate::vfs::O_RDONLY, 0) {
+            Ok(fd) => fd,
+            Err(e) => {
+                crate::println!("cat: {}: {}", args[0], errno_text(e));
+                return Err("file not found");
+            }
+        };
 
-                loop {
-                    match inode.read(offset, &mut buffer) {
-                        Ok(0) => break, // EOF
-                        Ok(bytes_read) => {
-                            if let Ok(s) = core::str::from_utf8(&buffer[..bytes_read]) {
-                                crate::print!("{}", s);
-                            } else {
-                                crate::println!("cat: {}: Binary file", args[0]);
-                                return Err("binary file");
-                            }
-                            offset += bytes_read;
-                        }
-                        Err(e) => {
-                            crate::println!("cat: {}: {}", args[0], e);
-                            return Err("read error");
-                        }
+        let mut buffer = [0u8; 1024];
+        let result = loop {
+            match syscall(SyscallNumber::Read, fd, buffer.as_mut_ptr() as u64, buffer.len() as u64) {
+                Ok(0) => break Ok(()), // EOF
+                Ok(bytes_read) => {
+                    if let Ok(s) = core::str::from_utf8(&buffer[..bytes_read as usize]) {
+                        crate::print!("{}", s);
+                    } else {
+                        crate::println!("cat: {}: Binary file", args[0]);
+                        break Err("binary file");
                     }
                 }
-                Ok(())
-            }
-            Err(e) => {
-                crate::println!("cat: {}: {}", args[0], e);
-                Err("file not found")
+                Err(e) => {
+                    crate::println!("cat: {}: {}", args[0], errno_text(e));
+                    break Err("read error");
+                }
             }
-        }
+        };
+
+        let _ = syscall(SyscallNumber::Close, fd, 0, 0);
+        result
     }
 
     /// Ls command - list directory contents
     fn cmd_ls(&mut self, args: &[&str]) -> Result<(), &'static str> {
-        let path = if args.is_empty() {
-            self.cwd.clone()
-        } else {
-            self.resolve_path(args[0])
+        let long = args.contains(&"-l");
+        let target = args.iter().find(|arg| !arg.starts_with('-')).copied();
+        let path = match target {
+            Some(dir) => self.resolve_path(dir),
+            None => self.cwd(),
         };
+        let shown = target.unwrap_or(".");
 
-        let tmpfs = crate::tmpfs::TMPFS.lock();
-
-        match tmpfs.resolve_path(&path) {
+        match crate::mount::resolve_path(&path) {
             Ok(inode) => {
                 if inode.file_type() != crate::vfs::FileType::Directory {
-                    crate::println!("ls: {}: Not a directory", args.get(0).unwrap_or(&"."));
+                    crate::println!("ls: {}: Not a directory", shown);
                     return Err("not a directory");
                 }
 
-                match inode.list() {
-                    Ok(entries) => {
+                match crate::vfs::read_dir(&inode).collect::<Result<Vec<_>, _>>() {
+                    Ok(mut entries) => {
+                        entries.sort_by(|a, b| a.name.cmp(&b.name));
                         for entry in entries {
-                            // Check if it's a directory by looking it up
-                            if let Ok(child_inode) = inode.lookup(&entry) {
-                                if child_inode.file_type() == crate::vfs::FileType::Directory {
-                                    crate::println!("{}/", entry);
-                                } else {
-                                    crate::println!("{}", entry);
+                            if !long {
+                                // The entry carries its type, no lookup needed
+                                match entry.file_type {
+                                    crate::vfs::FileType::Directory => crate::println!("{}/", entry.name),
+                                    _ => crate::println!("{}", entry.name),
+                                }
+                                continue;
+                            }
+
+                            // Attributes still need the inode
+                            let child_path = format!("{}/{}", path.trim_end_matches('/'), entry.name);
+                            let Ok(child_inode) = crate::mount::resolve_path_no_follow(&child_path) else {
+                                continue;
+                            };
+                            let mut line = Self::format_long_entry(&entry.name, &child_inode.metadata());
+                            if entry.file_type == crate::vfs::FileType::Symlink {
+                                if let Ok(target) = child_inode.readlink() {
+                                    line.push_str(" -> ");
+                                    line.push_str(&target);
                                 }
                             }
+                            crate::println!("{}", line);
                         }
                         Ok(())
                     }
                     Err(e) => {
-                        crate::println!("ls: {}: {}", args.get(0).unwrap_or(&"."), e);
+                        crate::println!("ls: {}: {}", shown, e);
                         Err("read error")
                     }
                 }
             }
             Err(e) => {
-                crate::println!("ls: {}: {}", args.get(0).unwrap_or(&"."), e);
+                crate::println!("ls: {}: {}", shown, e);
                 Err("directory not found")
             }
         }
     }
 
+    /// Format one line of `ls -l` output
+    fn format_long_entry(name: &str, meta: &crate::vfs::Metadata) -> String {
+        format!(
+            "{} {:>2} {:>4} {:>4} {:>8} {} {}",
+            meta.mode_string(),
+            meta.nlink,
+            meta.uid,
+            meta.gid,
+            meta.size,
+            Self::format_time(meta.mtime),
+            name
+        )
+    }
+
+    /// Format a timestamp as `YYYY-MM-DD HH:MM:SS` (UTC)
+    fn format_time(time: crate::time::Timespec) -> String {
+        let dt = crate::rtc::DateTime::from_unix(time.tv_sec.max(0) as u64);
+        format!(
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            dt.year, dt.month, dt.day, dt.hour, dt.minute, dt.second
+        )
+    }
+
     /// Pwd command - print working directory
     fn cmd_pwd(&mut self, args: &[&str]) -> Result<(), &'static str> {
         if !args.is_empty() {
@@ -1619,7 +1606,7 @@ impl Shell {
             return Err("too many arguments");
         }
 
-        crate::println!("{}", self.cwd);
+        crate::println!("{}", self.cwd());
         Ok(())
     }
 
@@ -1631,17 +1618,11 @@ impl Shell {
             self.resolve_path(args[0])
         };
 
-        let tmpfs = crate::tmpfs::TMPFS.lock();
-
-        match tmpfs.resolve_path(&new_path) {
-            Ok(inode) => {
-                if inode.file_type() != crate::vfs::FileType::Directory {
-                    crate::println!("cd: {}: Not a directory", args[0]);
-                    return Err("not a directory");
-                }
-                drop(tmpfs); // Release lock before modifying self
-                self.cwd = new_path;
-                Ok(())
+        match crate::mount::chdir(&new_path) {
+            Ok(()) => Ok(()),
+            Err(crate::vfs::VfsError::NotADirectory) => {
+                crate::println!("cd: {}: Not a directory", args[0]);
+                Err("not a directory")
             }
             Err(e) => {
                 crate::println!("cd: {}: {}", args.get(0).unwrap_or(&""), e);
@@ -1658,9 +1639,8 @@ impl Shell {
         }
 
         let path = self.resolve_path(args[0]);
-        let tmpfs = crate::tmpfs::TMPFS.lock();
 
-        match tmpfs.create_directory(&path) {
+        match crate::mount::create_directory(&path) {
             Ok(_) => {
                 crate::println!("Created directory: {}", args[0]);
                 Ok(())
@@ -1681,10 +1661,9 @@ impl Shell {
 
         for arg in args {
             let path = self.resolve_path(arg);
-            let tmpfs = crate::tmpfs::TMPFS.lock();
 
             // Check if the path exists and is a directory
-            match tmpfs.resolve_path(&path) {
+            match crate::mount::resolve_path_no_follow(&path) {
                 Ok(inode) => {
                     if inode.file_type() != crate::vfs::FileType::Directory {
                         crate::println!("rmdir: {}: Not a directory", arg);
@@ -1692,7 +1671,7 @@ impl Shell {
                     }
 
                     // Remove the directory
-                    match tmpfs.remove(&path) {
+                    match crate::mount::remove(&path) {
                         Ok(()) => crate::println!("Removed directory: {}", arg),
                         Err(e) => {
                             crate::println!("rmdir: {}: {}", arg, e);
@@ -1718,10 +1697,9 @@ impl Shell {
 
         for arg in args {
             let path = self.resolve_path(arg);
-            let tmpfs = crate::tmpfs::TMPFS.lock();
 
             // Check if the path exists and is not a directory
-            match tmpfs.resolve_path(&path) {
+            match crate::mount::resolve_path_no_follow(&path) {
                 Ok(inode) => {
                     if inode.file_type() == crate::vfs::FileType::Directory {
                         crate::println!("rm: {}: Is a directory (use rmdir)", arg);
@@ -1729,7 +1707,7 @@ impl Shell {
                     }
 
                     // Remove the file
-                    match tmpfs.remove(&path) {
+                    match crate::mount::remove(&path) {
                         Ok(()) => crate::println!("Removed: {}", arg),
                         Err(e) => {
                             crate::println!("rm: {}: {}", arg, e);
@@ -1755,10 +1733,9 @@ impl Shell {
 
         let src_path = self.resolve_path(args[0]);
         let dst_path = self.resolve_path(args[1]);
-        let tmpfs = crate::tmpfs::TMPFS.lock();
 
         // Read the source file
-        match tmpfs.resolve_path(&src_path) {
+        match crate::mount::resolve_path(&src_path) {
             Ok(src_inode) => {
                 if src_inode.file_type() == crate::vfs::FileType::Directory {
                     crate::println!("cp: {}: Is a directory (directory copy not supported)", args[0]);
@@ -1771,7 +1748,7 @@ impl Shell {
                 match src_inode.read(0, &mut buffer) {
                     Ok(bytes_read) => {
                         // Create destination file
-                        match tmpfs.create_file(&dst_path) {
+                        match crate::mount::create_file(&dst_path) {
                             Ok(dst_inode) => {
                                 // Write data to destination
                                 match dst_inode.write(0, &buffer[..bytes_read]) {
@@ -1812,65 +1789,28 @@ impl Shell {
         }
 
         let src_path = self.resolve_path(args[0]);
-        let dst_path = self.resolve_path(args[1]);
-        let tmpfs = crate::tmpfs::TMPFS.lock();
-
-        // Check if source exists
-        match tmpfs.resolve_path(&src_path) {
-            Ok(src_inode) => {
-                let src_type = src_inode.file_type();
+        let mut dst_path = self.resolve_path(args[1]);
 
-                // Read all data from source
-                let size = src_inode.size();
-                let mut buffer = alloc::vec![0u8; size];
+        // Moving onto an existing directory moves into it
+        if let Ok(dst_inode) = crate::mount::resolve_path(&dst_path) {
+            if dst_inode.file_type() == crate::vfs::FileType::Directory {
+                let name = src_path.rsplit('/').next().unwrap_or_default();
+                dst_path = format!("{}/{}", dst_path.trim_end_matches('/'), name);
+            }
+        }
 
-                if src_type == crate::vfs::FileType::Regular {
-                    // For files, copy the content
-                    match src_inode.read(0, &mut buffer) {
-                        Ok(bytes_read) => {
-                            // Create destination
-                            match tmpfs.create_file(&dst_path) {
-                                Ok(dst_inode) => {
-                                    // Write data to destination
-                                    match dst_inode.write(0, &buffer[..bytes_read]) {
-                                        Ok(_) => {
-                                            // Remove source
-                                            match tmpfs.remove(&src_path) {
-                                                Ok(()) => {
-                                                    crate::println!("Moved {} to {}", args[0], args[1]);
-                                                    Ok(())
-                                                }
-                                                Err(e) => {
-                                                    crate::println!("mv: Failed to remove source {}: {}", args[0], e);
-                                                    Err("remove failed")
-                                                }
-                                            }
-                                        }
-                                        Err(e) => {
-                                            crate::println!("mv: Failed to write to {}: {}", args[1], e);
-                                            Err("write failed")
-                                        }
-                                    }
-                                }
-                                Err(e) => {
-                                    crate::println!("mv: Failed to create {}: {}", args[1], e);
-                                    Err("create failed")
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            crate::println!("mv: Failed to read {}: {}", args[0], e);
-                            Err("read failed")
-                        }
-                    }
-                } else {
-                    crate::println!("mv: Directory move not yet supported");
-                    Err("directory move not supported")
-                }
+        match crate::mount::rename(&src_path, &dst_path) {
+            Ok(()) => {
+                crate::println!("Moved {} to {}", args[0], args[1]);
+                Ok(())
+            }
+            Err(crate::vfs::VfsError::CrossDevice) => {
+                crate::println!("mv: cannot move {} to {}: Invalid cross-device link", args[0], args[1]);
+                Err("cross-device move")
             }
             Err(e) => {
                 crate::println!("mv: {}: {}", args[0], e);
-                Err("source not found")
+                Err("rename failed")
             }
         }
     }
@@ -2059,10 +1999,9 @@ impl Shell {
         }
 
         let script_path = self.resolve_path(args[0]);
-        let tmpfs = crate::tmpfs::TMPFS.lock();
 
         // Read the script file
-        match tmpfs.resolve_path(&script_path) {
+        match crate::mount::resolve_path(&script_path) {
             Ok(inode) => {
                 if inode.file_type() == crate::vfs::FileType::Directory {
                     crate::println!("source: {}: Is a directory", args[0]);
@@ -2088,9 +2027,6 @@ impl Shell {
                     }
                 }
 
-                // Release the tmpfs lock before executing commands
-                drop(tmpfs);
-
                 // Parse and execute each line
                 if let Ok(script_text) = core::str::from_utf8(&content) {
                     for line in script_text.lines() {
@@ -2129,22 +2065,28 @@ impl Shell {
         }
 
         let file_path = self.resolve_path(args[0]);
-        let tmpfs = crate::tmpfs::TMPFS.lock();
 
         // Check if file already exists
-        match tmpfs.resolve_path(&file_path) {
+        match crate::mount::resolve_path(&file_path) {
             Ok(inode) => {
-                // File exists, would update timestamp but we don't have timestamps yet
-                if inode.file_type() == crate::vfs::FileType::Directory {
-                    crate::println!("touch: {}: Is a directory", args[0]);
-                    return Err("is a directory");
+                // File exists, update its access and modification times
+                let now = crate::vfs::current_time();
+                let attr = crate::vfs::SetAttr {
+                    atime: Some(now),
+                    mtime: Some(now),
+                    ..Default::default()
+                };
+                match inode.setattr(&attr) {
+                    Ok(()) => Ok(()),
+                    Err(e) => {
+                        crate::println!("touch: {}: {}", args[0], e);
+                        Err("failed to update timestamps")
+                    }
                 }
-                crate::println!("touch: {}: File already exists", args[0]);
-                Ok(())
             }
             Err(_) => {
                 // File doesn't exist, create it
-                match tmpfs.create_file(&file_path) {
+                match crate::mount::create_file(&file_path) {
                     Ok(_) => {
                         crate::println!("Created empty file: {}", args[0]);
                         Ok(())
@@ -2166,9 +2108,8 @@ impl Shell {
         }
 
         let file_path = self.resolve_path(args[0]);
-        let tmpfs = crate::tmpfs::TMPFS.lock();
 
-        match tmpfs.resolve_path(&file_path) {
+        match crate::mount::resolve_path(&file_path) {
             Ok(inode) => {
                 if inode.file_type() == crate::vfs::FileType::Directory {
                     crate::println!("wc: {}: Is a directory", args[0]);
@@ -2250,9 +2191,8 @@ impl Shell {
 
         let pattern = args[pattern_idx];
         let file_path = self.resolve_path(args[file_idx]);
-        let tmpfs = crate::tmpfs::TMPFS.lock();
 
-        match tmpfs.resolve_path(&file_path) {
+        match crate::mount::resolve_path(&file_path) {
             Ok(inode) => {
                 if inode.file_type() == crate::vfs::FileType::Directory {
                     crate::println!("grep: {}: Is a directory", args[file_idx]);
@@ -2364,9 +2304,8 @@ impl Shell {
         }
 
         let file_path = self.resolve_path(file_path.unwrap());
-        let tmpfs = crate::tmpfs::TMPFS.lock();
 
-        match tmpfs.resolve_path(&file_path) {
+        match crate::mount::resolve_path(&file_path) {
             Ok(inode) => {
                 if inode.file_type() == crate::vfs::FileType::Directory {
                     crate::println!("head: {}: Is a directory", file_path);
@@ -2419,11 +2358,16 @@ impl Shell {
     fn cmd_tail(&mut self, args: &[&str]) -> Result<(), &'static str> {
         let mut num_lines = 10; // Default to 10 lines
         let mut file_path = None;
+        let mut follow = false;
 
         // Parse arguments
         let mut i = 0;
         while i < args.len() {
-            if args[i] == "-n" {
+            if args[i] == "-f" {
+                follow = true;
+                i += 1;
+                continue;
+            } else if args[i] == "-n" {
                 // Next arg should be number
                 if i + 1 < args.len() {
                     if let Ok(n) = args[i + 1].parse::<usize>() {
@@ -2456,15 +2400,14 @@ impl Shell {
         }
 
         if file_path.is_none() {
-            crate::println!("Usage: tail [-n NUM] <file>");
-            crate::println!("       tail -NUM <file>");
+            crate::println!("Usage: tail [-f] [-n NUM] <file>");
+            crate::println!("       tail [-f] -NUM <file>");
             return Err("missing file argument");
         }
 
         let file_path = self.resolve_path(file_path.unwrap());
-        let tmpfs = crate::tmpfs::TMPFS.lock();
 
-        match tmpfs.resolve_path(&file_path) {
+        match crate::mount::resolve_path(&file_path) {
             Ok(inode) => {
                 if inode.file_type() == crate::vfs::FileType::Directory {
                     crate::println!("tail: {}: Is a directory", file_path);
@@ -2506,6 +2449,10 @@ impl Shell {
                     for line in &lines[start_line..] {
                         crate::println!("{}", line);
                     }
+
+                    if follow {
+                        return Self::follow_file(&inode, offset);
+                    }
                     Ok(())
                 } else {
                     crate::println!("tail: {}: Binary file or invalid encoding", file_path);
@@ -2519,6 +2466,72 @@ impl Shell {
         }
     }
 
+    /// Print data appended to a file as it arrives, until a key is pressed
+    ///
+    /// Used by `tail -f`: an inotify watch on the file wakes us on each
+    /// change, so nothing is polled.
+    fn follow_file(inode: &Arc<dyn crate::vfs::Inode>, mut offset: usize) -> Result<(), &'static str> {
+        use crate::inotify::{Inotify, IN_DELETE_SELF, IN_IGNORED, IN_MODIFY};
+        use crate::vfs::Inode;
+        use crate::wait::{wait_event, WaitResult};
+
+        let watcher = Inotify::new();
+        if let Err(e) = watcher.add_watch(inode, IN_MODIFY | IN_DELETE_SELF) {
+            crate::println!("tail: cannot watch file: {}", e);
+            return Err("watch failed");
+        }
+        let Some(changes) = watcher.wait_queue() else {
+            return Err("watch failed");
+        };
+
+        let mut events = [0u8; 256];
+        let mut buffer = [0u8; 1024];
+        loop {
+            let result = wait_event(&[changes, &crate::keyboard::INPUT_WAIT], None, || {
+                watcher.poll().readable || crate::keyboard::input_available()
+            });
+            if result == WaitResult::Interrupted || crate::keyboard::input_available() {
+                // Swallow the key that stopped us
+                crate::keyboard::read_input(&mut buffer);
+                return Ok(());
+            }
+
+            // Each record is an inotify_event: mask at byte 4, name length at 12
+            let len = watcher.read_nonblocking(0, &mut events).unwrap_or(0);
+            let mut gone = false;
+            let mut record = 0;
+            while record + 16 <= len {
+                let mask = u32::from_ne_bytes(events[record + 4..record + 8].try_into().unwrap());
+                let name_len = u32::from_ne_bytes(events[record + 12..record + 16].try_into().unwrap());
+                gone |= mask & (IN_DELETE_SELF | IN_IGNORED) != 0;
+                record += 16 + name_len as usize;
+            }
+
+            if inode.size() < offset {
+                crate::println!("tail: file truncated");
+                offset = 0;
+            }
+            loop {
+                match inode.read(offset, &mut buffer) {
+                    Ok(0) => break,
+                    Ok(count) => {
+                        crate::print!("{}", String::from_utf8_lossy(&buffer[..count]));
+                        offset += count;
+                    }
+                    Err(e) => {
+                        crate::println!("tail: read error: {}", e);
+                        return Err("read error");
+                    }
+                }
+            }
+
+            if gone {
+                crate::println!("tail: file removed");
+                return Ok(());
+            }
+        }
+    }
+
     /// Uptime command - show system uptime
     fn cmd_uptime(&mut self, _args: &[&str]) -> Result<(), &'static str> {
         let total_seconds = crate::pit::get_seconds();
@@ -2588,7 +2601,8 @@ impl Shell {
             "echo", "export", "unset", "clear", "cls", "help", "ps", "cat", "ls",
             "pwd", "cd", "mkdir", "rmdir", "rm", "cp", "mv", "touch", "wc", "grep",
             "head", "tail", "uptime", "free", "env", "which", "diff", "patch",
-            "reboot", "jobs", "fg", "bg", "alias", "unalias", "source",
+            "reboot", "jobs", "fg", "bg", "alias", "unalias", "source", "strace",
+            "mount", "umount", "lsblk", "stat", "chmod", "chown", "ln", "sync",
         ];
 
         if builtins.contains(&command) {
@@ -2599,7 +2613,6 @@ impl Shell {
         // Check in PATH directories
         if let Some(path_var) = self.env.get("PATH") {
             let paths: Vec<&str> = path_var.split(':').collect();
-            let tmpfs = crate::tmpfs::TMPFS.lock();
 
             for path_dir in paths {
                 let full_path = if path_dir.ends_with('/') {
@@ -2609,7 +2622,7 @@ impl Shell {
                 };
 
                 // Check if file exists
-                if let Ok(inode) = tmpfs.resolve_path(&full_path) {
+                if let Ok(inode) = crate::mount::resolve_path(&full_path) {
                     if inode.file_type() == crate::vfs::FileType::Regular {
                         crate::println!("{}", full_path);
                         return Ok(());
@@ -2631,10 +2644,9 @@ impl Shell {
 
         let file1_path = self.resolve_path(args[0]);
         let file2_path = self.resolve_path(args[1]);
-        let tmpfs = crate::tmpfs::TMPFS.lock();
 
         // Read both files
-        let file1_content = match tmpfs.resolve_path(&file1_path) {
+        let file1_content = match crate::mount::resolve_path(&file1_path) {
             Ok(inode) => {
                 if inode.file_type() == crate::vfs::FileType::Directory {
                     crate::println!("diff: {}: Is a directory", args[0]);
@@ -2666,7 +2678,7 @@ impl Shell {
             }
         };
 
-        let file2_content = match tmpfs.resolve_path(&file2_path) {
+        let file2_content = match crate::mount::resolve_path(&file2_path) {
             Ok(inode) => {
                 if inode.file_type() == crate::vfs::FileType::Directory {
                     crate::println!("diff: {}: Is a directory", args[1]);
@@ -2772,10 +2784,9 @@ impl Shell {
 
         let file_path = self.resolve_path(args[0]);
         let patch_path = self.resolve_path(args[1]);
-        let tmpfs = crate::tmpfs::TMPFS.lock();
 
         // Read the original file
-        let original_content = match tmpfs.resolve_path(&file_path) {
+        let original_content = match crate::mount::resolve_path(&file_path) {
             Ok(inode) => {
                 if inode.file_type() == crate::vfs::FileType::Directory {
                     crate::println!("patch: {}: Is a directory", args[0]);
@@ -2808,7 +2819,7 @@ impl Shell {
         };
 
         // Read the patch file
-        let patch_content = match tmpfs.resolve_path(&patch_path) {
+        let patch_content = match crate::mount::resolve_path(&patch_path) {
             Ok(inode) => {
                 if inode.file_type() == crate::vfs::FileType::Directory {
                     crate::println!("patch: {}: Is a directory", args[1]);
@@ -2921,7 +2932,7 @@ impl Shell {
         let patched_content = original_lines.join("\n");
         let patched_bytes = patched_content.as_bytes();
 
-        match tmpfs.resolve_path(&file_path) {
+        match crate::mount::resolve_path(&file_path) {
             Ok(inode) => {
                 match inode.write(0, patched_bytes) {
                     Ok(_) => {
@@ -2941,6 +2952,538 @@ impl Shell {
         }
     }
 
+    /// Strace command - trace syscalls made while a command runs
+    ///
+    /// Only syscalls are traced, and most builtins call into the kernel
+    /// directly; `cat` and `echo` go through `dispatch_syscall` instead.
+    fn cmd_strace(&mut self, args: &[&str]) -> Result<(), &'static str> {
+        if args.is_empty() {
+            crate::println!("Usage: strace <cat|echo> [args...]");
+            crate::println!("       strace -p <pid>");
+            return Err("missing command");
+        }
+
+        // Show the trace buffer of another process
+        if args[0] == "-p" {
+            let pid = match args.get(1).and_then(|p| p.parse::<u32>().ok()) {
+                Some(pid) => pid,
+                None => {
+                    crate::println!("strace: -p requires a numeric PID");
+                    return Err("invalid pid");
+                }
+            };
+
+            let Some(resources) = crate::process::find_resources(pid) else {
+                crate::println!("strace: {}: no such process", pid);
+                return Err("no such process");
+            };
+            for record in resources.trace.snapshot() {
+                crate::println!("{}", record.format());
+            }
+            return Ok(());
+        }
+
+        // Other builtins call the kernel directly and would show an empty trace
+        if !TRACEABLE.contains(&args[0]) {
+            crate::println!("strace: {}: only cat and echo make syscalls that can be traced", args[0]);
+            return Err("command cannot be traced");
+        }
+
+        let resources = crate::process::current_resources();
+        let was_enabled = resources.trace.is_enabled();
+
+        resources.trace.clear();
+        resources.trace.set_enabled(true);
+        let result = self.execute_line(&args.join(" "));
+        resources.trace.set_enabled(was_enabled);
+
+        let records = resources.trace.snapshot();
+        for record in &records {
+            crate::println!("{}", record.format());
+        }
+        crate::println!("+++ {} syscall(s) traced +++", records.len());
+
+        result
+    }
+
+    /// Mount command - list mounts or attach a new filesystem
+    fn cmd_mount(&mut self, args: &[&str]) -> Result<(), &'static str> {
+        if args.is_empty() {
+            for mount in crate::mount::mounts() {
+                crate::println!("{} on {}", mount.fs.name(), mount.path);
+            }
+            return Ok(());
+        }
+
+        // Disk filesystems take a block device before the mount point, and
+        // an overlay its lower and upper directories
+        let (fs_type, device, dir) = match args {
+            ["-t", "overlay", lower, upper, dir] => {
+                let fs = self.overlay(lower, upper)?;
+                return self.attach(dir, Arc::new(fs));
+            }
+            ["-t", fs_type, dir] => (*fs_type, None, *dir),
+            ["-t", fs_type, device, dir] => (*fs_type, Some(*device), *dir),
+            _ => {
+                crate::println!("Usage: mount [-t <tmpfs|devfs|procfs> <dir>] [-t <fat32|ext2|rfs|iso9660> <device> <dir>] [-t overlay <lower> <upper> <dir>]");
+                return Err("invalid arguments");
+            }
+        };
+
+        let fs: Arc<dyn crate::vfs::Filesystem> = match (fs_type, device) {
+            ("tmpfs", None) => Arc::new(crate::tmpfs::TmpFs::new()),
+            ("devfs", None) => Arc::new(crate::devfs::DevFs::new()),
+            ("procfs", None) => Arc::new(crate::procfs::ProcFs::new()),
+            ("fat32", Some(device)) => {
+                let queue = Self::find_block_device("mount", device)?;
+                match crate::fat32::Fat32Fs::mount(queue) {
+                    Ok(fs) => Arc::new(fs),
+                    Err(e) => {
+                        crate::println!("mount: {}: not a FAT32 volume ({})", device, e);
+                        return Err("mount failed");
+                    }
+                }
+            }
+            ("ext2", Some(device)) => {
+                let queue = Self::find_block_device("mount", device)?;
+                match crate::ext2::Ext2Fs::mount(queue) {
+                    Ok(fs) => Arc::new(fs),
+                    Err(e) => {
+                        crate::println!("mount: {}: not a usable ext2 volume ({})", device, e);
+                        return Err("mount failed");
+                    }
+                }
+            }
+            ("rfs", Some(device)) => {
+                let queue = Self::find_block_device("mount", device)?;
+                match crate::rfs::RfsFs::mount(queue) {
+                    Ok(fs) => Arc::new(fs),
+                    Err(e) => {
+                        crate::println!("mount: {}: not a usable rfs volume ({})", device, e);
+                        return Err("mount failed");
+                    }
+                }
+            }
+            ("iso9660", Some(device)) => {
+                let queue = Self::find_block_device("mount", device)?;
+                match crate::iso9660::IsoFs::mount(queue) {
+                    Ok(fs) => Arc::new(fs),
+                    Err(e) => {
+                        crate::println!("mount: {}: not an ISO9660 volume ({})", device, e);
+                        return Err("mount failed");
+                    }
+                }
+            }
+            ("tmpfs" | "devfs" | "procfs", Some(_)) | ("fat32" | "ext2" | "rfs" | "iso9660", None) | ("overlay", _) => {
+                crate::println!("Usage: mount [-t <tmpfs|devfs|procfs> <dir>] [-t <fat32|ext2|rfs|iso9660> <device> <dir>] [-t overlay <lower> <upper> <dir>]");
+                return Err("invalid arguments");
+            }
+            (other, _) => {
+                crate::println!("mount: unknown filesystem type '{}'", other);
+                return Err("unknown filesystem type");
+            }
+        };
+
+        self.attach(dir, fs)
+    }
+
+    /// Mount `fs` on the directory `dir`
+    fn attach(&self, dir: &str, fs: Arc<dyn crate::vfs::Filesystem>) -> Result<(), &'static str> {
+        let path = self.resolve_path(dir);
+        match crate::mount::mount(&path, fs) {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                crate::println!("mount: {}: {}", dir, e);
+                Err("mount failed")
+            }
+        }
+    }
+
+    /// Merge the directories `lower` and `upper` into an overlay
+    fn overlay(&self, lower: &str, upper: &str) -> Result<crate::overlayfs::OverlayFs, &'static str> {
+        let layer = |dir: &str| {
+            crate::mount::resolve_path(&self.resolve_path(dir)).map_err(|e| {
+                crate::println!("mount: {}: {}", dir, e);
+                "mount failed"
+            })
+        };
+        let (lower_inode, upper_inode) = (layer(lower)?, layer(upper)?);
+        crate::overlayfs::OverlayFs::new(lower_inode, upper_inode).map_err(|e| {
+            crate::println!("mount: {} and {}: {}", lower, upper, e);
+            "mount failed"
+        })
+    }
+
+    /// Look up a registered block device by name or as /dev/<name>
+    fn find_block_device(command: &str, device: &str) -> Result<Arc<crate::block::RequestQueue>, &'static str> {
+        let name = device.strip_prefix("/dev/").unwrap_or(device);
+        crate::block::get(name).ok_or_else(|| {
+            crate::println!("{}: {}: no such block device", command, device);
+            "no such block device"
+        })
+    }
+
+    /// Umount command - detach a mounted filesystem
+    fn cmd_umount(&mut self, args: &[&str]) -> Result<(), &'static str> {
+        if args.len() != 1 {
+            crate::println!("Usage: umount <dir>");
+            return Err("missing directory argument");
+        }
+
+        let path = self.resolve_path(args[0]);
+        match crate::mount::umount(&path) {
+            Ok(()) => Ok(()),
+            Err(crate::vfs::VfsError::InvalidArgument) => {
+                crate::println!("umount: {}: not mounted", args[0]);
+                Err("not mounted")
+            }
+            Err(e) => {
+                crate::println!("umount: {}: {}", args[0], e);
+                Err("umount failed")
+            }
+        }
+    }
+
+    /// Lsblk command - list block devices, each disk followed by its partitions
+    fn cmd_lsblk(&mut self, args: &[&str]) -> Result<(), &'static str> {
+        use crate::block::BlockDevice;
+
+        if !args.is_empty() {
+            crate::println!("lsblk: too many arguments");
+            return Err("too many arguments");
+        }
+
+        crate::println!("{:<12} {:>10} {:>8}  {:<5} {}", "NAME", "START", "SIZE", "TYPE", "DESCRIPTION");
+        for queue in crate::block::devices() {
+            let name = queue.name();
+            if crate::partition::find(name).is_some() {
+                continue; // listed under its disk
+            }
+
+            let table = match crate::partition::disk(name) {
+                Some(disk) => format!("{:?} partition table", disk.table),
+                None => String::from("no partition table"),
+            };
+            crate::println!("{:<12} {:>10} {:>8}  {:<5} {}", name, "-", format_size(queue.size()), "disk", table);
+
+            for part in crate::partition::disk(name).map(|disk| disk.partitions).unwrap_or_default() {
+                let mut description = part.entry.kind.description();
+                if !part.entry.label.is_empty() {
+                    description = format!("{} \"{}\"", description, part.entry.label);
+                }
+                let size = part.entry.blocks * queue.block_size() as u64;
+                crate::println!(
+                    "{:<12} {:>10} {:>8}  {:<5} {}",
+                    format!("`-{}", part.name),
+                    part.entry.start,
+                    format_size(size),
+                    "part",
+                    description
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Mkfs command - make a filesystem on a block device
+    fn cmd_mkfs(&mut self, args: &[&str]) -> Result<(), &'static str> {
+        let (label, device) = match args {
+            ["-t", "rfs", device] => ("", *device),
+            ["-t", "rfs", "-L", label, device] => (*label, *device),
+            ["-t", other, ..] if *other != "rfs" => {
+                crate::println!("mkfs: {}: unsupported filesystem type (only rfs)", other);
+                return Err("unsupported filesystem type");
+            }
+            _ => {
+                crate::println!("Usage: mkfs -t rfs [-L <label>] <device>");
+                return Err("invalid arguments");
+            }
+        };
+
+        let queue: Arc<dyn crate::block::BlockDevice> = Self::find_block_device("mkfs", device)?;
+        match crate::rfs::mkfs(&queue, label) {
+            Ok(geometry) => {
+                crate::println!(
+                    "mkfs: {}: rfs, {} blocks of {} bytes, {} inodes, {}-block journal",
+                    device,
+                    geometry.blocks,
+                    geometry.block_size,
+                    geometry.inodes,
+                    geometry.journal_blocks
+                );
+                Ok(())
+            }
+            Err(e) => {
+                crate::println!("mkfs: {}: {}", device, e);
+                Err("mkfs failed")
+            }
+        }
+    }
+
+    /// Fsck command - check an unmounted filesystem
+    fn cmd_fsck(&mut self, args: &[&str]) -> Result<(), &'static str> {
+        let device = match args {
+            ["-t", "rfs", device] => *device,
+            _ => {
+                crate::println!("Usage: fsck -t rfs <device>");
+                return Err("invalid arguments");
+            }
+        };
+
+        let queue: Arc<dyn crate::block::BlockDevice> = Self::find_block_device("fsck", device)?;
+        let report = match crate::rfs::fsck(&queue) {
+            Ok(report) => report,
+            Err(e) => {
+                crate::println!("fsck: {}: {}", device, e);
+                return Err("fsck failed");
+            }
+        };
+        if report.replayed > 0 {
+            crate::println!("fsck: {}: replayed {} journaled blocks", device, report.replayed);
+        }
+        for message in &report.messages {
+            crate::println!("fsck: {}", message);
+        }
+        if report.errors as usize > report.messages.len() {
+            crate::println!("fsck: ... and {} more", report.errors as usize - report.messages.len());
+        }
+        crate::println!(
+            "fsck: {}: {} files, {} directories, {}/{} blocks, {} orphans, {} errors",
+            device,
+            report.files,
+            report.directories,
+            report.used_blocks,
+            report.blocks,
+            report.orphans,
+            report.errors
+        );
+        if report.errors > 0 {
+            return Err("filesystem has errors");
+        }
+        Ok(())
+    }
+
+    /// Crashtest command - cut rfs writes off part way through a workload
+    /// and check that the volume recovers
+    ///
+    /// Without -n, tries crash points spread over the whole workload.
+    fn cmd_crashtest(&mut self, args: &[&str]) -> Result<(), &'static str> {
+        const CRASH_POINTS: u64 = 16;
+
+        let (blocks, device) = match args {
+            [device] => (None, *device),
+            ["-n", blocks, device] => match blocks.parse::<u64>() {
+                Ok(blocks) => (Some(blocks), *device),
+                Err(_) => {
+                    crate::println!("crashtest: invalid block count '{}'", blocks);
+                    return Err("invalid block count");
+                }
+            },
+            _ => {
+                crate::println!("Usage: crashtest [-n <blocks>] <device>");
+                return Err("invalid arguments");
+            }
+        };
+        let queue: Arc<dyn crate::block::BlockDevice> = Self::find_block_device("crashtest", device)?;
+
+        let budgets = match blocks {
+            Some(blocks) => vec![blocks],
+            None => {
+                let total = match crate::rfs::crash_test(&queue, None) {
+                    Ok(report) => report.attempted,
+                    Err(e) => {
+                        crate::println!("crashtest: {}: {}", device, e);
+                        return Err("crash test failed");
+                    }
+                };
+                crate::println!("crashtest: the workload writes {} blocks", total);
+                (1..=CRASH_POINTS).map(|point| total * point / (CRASH_POINTS + 1)).collect()
+            }
+        };
+
+        let mut failures = 0;
+        for budget in budgets {
+            let report = match crate::rfs::crash_test(&queue, Some(budget)) {
+                Ok(report) => report,
+                Err(e) => {
+                    crate::println!("crashtest: crash after {} blocks: recovery failed: {}", budget, e);
+                    failures += 1;
+                    continue;
+                }
+            };
+            let ok = report.fsck.errors == 0 && report.bad_files == 0;
+            crate::println!(
+                "crashtest: crash after {}/{} blocks: replayed {}, {} errors, {} bad files: {}",
+                budget,
+                report.attempted,
+                report.fsck.replayed,
+                report.fsck.errors,
+                report.bad_files,
+                if ok { "ok" } else { "FAILED" }
+            );
+            for message in &report.fsck.messages {
+                crate::println!("crashtest:   {}", message);
+            }
+            if !ok {
+                failures += 1;
+            }
+        }
+        if failures > 0 {
+            return Err("volume did not recover");
+        }
+        Ok(())
+    }
+
+    /// Stat command - show file attributes
+    fn cmd_stat(&mut self, args: &[&str]) -> Result<(), &'static str> {
+        if args.is_empty() {
+            crate::println!("Usage: stat <file>");
+            return Err("missing file argument");
+        }
+
+        // Like lstat: a symlink is described itself, not its target
+        let path = self.resolve_path(args[0]);
+        let inode = match crate::mount::resolve_path_no_follow(&path) {
+            Ok(inode) => inode,
+            Err(e) => {
+                crate::println!("stat: {}: {}", args[0], e);
+                return Err("file not found");
+            }
+        };
+        let meta = inode.metadata();
+
+        let kind = match meta.file_type {
+            crate::vfs::FileType::Regular => "regular file",
+            crate::vfs::FileType::Directory => "directory",
+            crate::vfs::FileType::Device => "character special file",
+            crate::vfs::FileType::BlockDevice => "block special file",
+            crate::vfs::FileType::Symlink => "symbolic link",
+            crate::vfs::FileType::Fifo => "fifo",
+            crate::vfs::FileType::Socket => "socket",
+        };
+
+        match inode.readlink() {
+            Ok(target) => crate::println!("  File: {} -> {}", args[0], target),
+            Err(_) => crate::println!("  File: {}", args[0]),
+        }
+        crate::println!("  Size: {:<10} Blocks: {:<6} {}", meta.size, meta.blocks, kind);
+        crate::println!(" Inode: {:<10} Links: {}", meta.ino, meta.nlink);
+        crate::println!("Access: ({:04o}/{})  Uid: {}  Gid: {}", meta.mode, meta.mode_string(), meta.uid, meta.gid);
+        crate::println!("Access: {}", Self::format_time(meta.atime));
+        crate::println!("Modify: {}", Self::format_time(meta.mtime));
+        crate::println!("Change: {}", Self::format_time(meta.ctime));
+        Ok(())
+    }
+
+    /// Ln command - create a hard link, or a symbolic link with -s
+    fn cmd_ln(&mut self, args: &[&str]) -> Result<(), &'static str> {
+        let (symbolic, args) = match args.first() {
+            Some(&"-s") => (true, &args[1..]),
+            _ => (false, args),
+        };
+
+        if args.len() != 2 {
+            crate::println!("Usage: ln [-s] <target> <link>");
+            return Err("invalid arguments");
+        }
+
+        let link_path = self.resolve_path(args[1]);
+        let result = if symbolic {
+            // The target is stored verbatim and resolved relative to the link
+            crate::mount::symlink(args[0], &link_path).map(|_| ())
+        } else {
+            crate::mount::link(&self.resolve_path(args[0]), &link_path)
+        };
+
+        match result {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                crate::println!("ln: {}: {}", args[1], e);
+                Err("failed to create link")
+            }
+        }
+    }
+
+    /// Sync command - write back every dirty cached page
+    fn cmd_sync(&mut self, args: &[&str]) -> Result<(), &'static str> {
+        if !args.is_empty() {
+            crate::println!("sync: too many arguments");
+            return Err("too many arguments");
+        }
+
+        crate::pagecache::sync().map_err(|e| {
+            crate::println!("sync: {}", e);
+            "writeback failed"
+        })
+    }
+
+    /// Chmod command - change permission bits
+    fn cmd_chmod(&mut self, args: &[&str]) -> Result<(), &'static str> {
+        if args.len() != 2 {
+            crate::println!("Usage: chmod <octal-mode> <file>");
+            return Err("invalid arguments");
+        }
+
+        let mode = match u16::from_str_radix(args[0], 8) {
+            Ok(mode) if mode <= crate::vfs::MODE_PERMISSION_MASK => mode,
+            _ => {
+                crate::println!("chmod: invalid mode: '{}'", args[0]);
+                return Err("invalid mode");
+            }
+        };
+
+        let attr = crate::vfs::SetAttr {
+            mode: Some(mode),
+            ..Default::default()
+        };
+        self.apply_setattr("chmod", args[1], &attr)
+    }
+
+    /// Chown command - change owner and group
+    fn cmd_chown(&mut self, args: &[&str]) -> Result<(), &'static str> {
+        if args.len() != 2 {
+            crate::println!("Usage: chown <uid>[:<gid>] <file>");
+            return Err("invalid arguments");
+        }
+
+        let (uid, gid) = match args[0].split_once(':') {
+            Some((uid, gid)) => (uid, Some(gid)),
+            None => (args[0], None),
+        };
+        let parse = |id: &str| id.parse::<u32>().ok();
+
+        let attr = match (parse(uid), gid.map(parse)) {
+            (Some(uid), None) => crate::vfs::SetAttr { uid: Some(uid), ..Default::default() },
+            (Some(uid), Some(Some(gid))) => crate::vfs::SetAttr {
+                uid: Some(uid),
+                gid: Some(gid),
+                ..Default::default()
+            },
+            _ => {
+                crate::println!("chown: invalid owner: '{}'", args[0]);
+                return Err("invalid owner");
+            }
+        };
+        self.apply_setattr("chown", args[1], &attr)
+    }
+
+    /// Apply an attribute change to the file at `arg`, reporting errors as `cmd`
+    fn apply_setattr(&mut self, cmd: &str, arg: &str, attr: &crate::vfs::SetAttr) -> Result<(), &'static str> {
+        let path = self.resolve_path(arg);
+        match crate::mount::resolve_path(&path).and_then(|inode| inode.setattr(attr)) {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                crate::println!("{}: {}: {}", cmd, arg, e);
+                Err("failed to change attributes")
+            }
+        }
+    }
+
+    /// Working directory of the shell's process
+    fn cwd(&self) -> String {
+        crate::mount::getcwd().unwrap_or_else(|_| String::from("/"))
+    }
+
     /// Resolve a path (handle relative paths)
     fn resolve_path(&self, path: &str) -> String {
         // Handle tilde expansion first
@@ -2955,16 +3498,53 @@ impl Shell {
             path.to_string()
         };
 
-        if path.starts_with('/') {
+        let absolute = if path.starts_with('/') {
             // Absolute path
             path
         } else {
             // Relative path
-            if self.cwd == "/" {
+            let cwd = self.cwd();
+            if cwd == "/" {
                 format!("/{}", path)
             } else {
-                format!("{}/{}", self.cwd, path)
+                format!("{}/{}", cwd, path)
             }
-        }
+        };
+
+        // Collapse `.` and `..` so the cwd stays canonical
+        crate::mount::normalize_path(&absolute)
     }
-}
\ No newline at end of file
+}
+
+/// Descriptor number of standard output
+const STDOUT_FD: u64 = 1;
+
+/// Builtins that make real syscalls, the only ones `strace` can trace
+const TRACEABLE: [&str; 2] = ["cat", "echo"];
+
+/// Make a system call through `dispatch_syscall`, as a user program would,
+/// so it is checked by the process's filter and recorded by `strace`
+fn syscall(number: SyscallNumber, arg1: u64, arg2: u64, arg3: u64) -> SyscallResult {
+    crate::syscall::dispatch_syscall(number as u64, arg1, arg2, arg3, 0, 0, 0)
+}
+
+/// Symbolic errno of a failed syscall, for error messages
+fn errno_text(error: SyscallError) -> &'static str {
+    crate::strace::errno_name(error.errno()).unwrap_or("error")
+}
+
+/// Format a byte count for humans: 512B, 1.0K, 63.0M, 2.5G
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["K", "M", "G", "T"];
+    if bytes < 1024 {
+        return format!("{}B", bytes);
+    }
+    // Work in tenths of a unit to keep one decimal without floating point
+    let mut tenths = bytes * 10 / 1024;
+    let mut unit = 0;
+    while tenths >= 10 * 1024 && unit < UNITS.len() - 1 {
+        tenths /= 1024;
+        unit += 1;
+    }
+    format!("{}.{}{}", tenths / 10, tenths % 10, UNITS[unit])
+}
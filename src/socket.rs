@@ -0,0 +1,120 @@
+//! Local stream sockets
+//!
+//! `socketpair` returns two connected endpoints. Each direction is a pipe
+//! buffer, so a socket is readable when its incoming pipe has data and
+//! writable when its outgoing pipe has room. Closing one endpoint shows up
+//! as EOF and hangup on the other.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::pipe::Pipe;
+use crate::vfs::{FileType, Inode, Metadata, Readiness, VfsError};
+use crate::wait::WaitQueue;
+
+/// One endpoint of a connected socket pair
+pub struct SocketInode {
+    /// Data sent by the peer
+    incoming: Arc<Pipe>,
+    /// Data sent to the peer
+    outgoing: Arc<Pipe>,
+    /// Woken on any change in either direction
+    wait: Arc<WaitQueue>,
+}
+
+impl SocketInode {
+    fn new(incoming: Arc<Pipe>, outgoing: Arc<Pipe>, wait: Arc<WaitQueue>) -> Arc<Self> {
+        incoming.add_reader();
+        outgoing.add_writer();
+        Arc::new(Self { incoming, outgoing, wait })
+    }
+}
+
+impl Drop for SocketInode {
+    fn drop(&mut self) {
+        self.incoming.remove_reader();
+        self.outgoing.remove_writer();
+        self.wait.wake_all();
+    }
+}
+
+impl Inode for SocketInode {
+    fn read(&self, _offset: usize, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        let count = self.incoming.read(buffer)?;
+        self.wait.wake_all();
+        Ok(count)
+    }
+
+    fn write(&self, _offset: usize, buffer: &[u8]) -> Result<usize, VfsError> {
+        let count = self.outgoing.write(buffer)?;
+        self.wait.wake_all();
+        Ok(count)
+    }
+
+    fn write_nonblocking(&self, _offset: usize, buffer: &[u8]) -> Result<usize, VfsError> {
+        let count = self.outgoing.try_write(buffer)?;
+        self.wait.wake_all();
+        Ok(count)
+    }
+
+    fn file_type(&self) -> FileType {
+        FileType::Socket
+    }
+
+    fn size(&self) -> usize {
+        0
+    }
+
+    fn lookup(&self, _name: &str) -> Result<Arc<dyn Inode>, VfsError> {
+        Err(VfsError::NotADirectory)
+    }
+
+    fn create(&self, _name: &str, _file_type: FileType) -> Result<Arc<dyn Inode>, VfsError> {
+        Err(VfsError::NotADirectory)
+    }
+
+    fn list(&self) -> Result<Vec<String>, VfsError> {
+        Err(VfsError::NotADirectory)
+    }
+
+    fn truncate(&self, _size: usize) -> Result<(), VfsError> {
+        Err(VfsError::InvalidOperation)
+    }
+
+    fn remove(&self, _name: &str) -> Result<(), VfsError> {
+        Err(VfsError::NotADirectory)
+    }
+
+    /// Each endpoint reports the inode of the pipe it reads from
+    fn metadata(&self) -> Metadata {
+        let mut meta = self.incoming.metadata(FileType::Socket);
+        meta.mode = 0o777;
+        meta
+    }
+
+    fn poll(&self) -> Readiness {
+        let incoming = self.incoming.read_readiness();
+        let outgoing = self.outgoing.write_readiness();
+        Readiness {
+            readable: incoming.readable,
+            writable: outgoing.writable,
+            hangup: incoming.hangup && outgoing.hangup,
+        }
+    }
+
+    fn wait_queue(&self) -> Option<&WaitQueue> {
+        Some(&self.wait)
+    }
+}
+
+/// Create a pair of connected sockets
+pub fn socketpair() -> (Arc<SocketInode>, Arc<SocketInode>) {
+    let a_to_b = Pipe::new();
+    let b_to_a = Pipe::new();
+    let wait = Arc::new(WaitQueue::new());
+
+    let a = SocketInode::new(b_to_a.clone(), a_to_b.clone(), wait.clone());
+    let b = SocketInode::new(a_to_b, b_to_a, wait);
+    (a, b)
+}
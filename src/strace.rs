@@ -0,0 +1,289 @@
+//! Syscall tracing (strace-like)
+//!
+//! When tracing is enabled for a process, `dispatch_syscall` records every
+//! call it makes into a per-process ring buffer. The buffer can be read
+//! back through `/proc/<pid>/syscalls` or printed by the shell's `strace`.
+
+use alloc::collections::VecDeque;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+use crate::syscall::{SyscallNumber, SyscallResult};
+
+/// Maximum number of records kept per process (oldest are dropped first)
+pub const TRACE_BUFFER_SIZE: usize = 128;
+
+/// A single traced system call
+#[derive(Debug, Clone, Copy)]
+pub struct SyscallRecord {
+    /// Raw syscall number from rax
+    pub number: u64,
+    /// Arguments in calling-convention order
+    pub args: [u64; 6],
+    /// Value returned to the caller
+    pub result: SyscallResult,
+    /// Timer tick at which the call started
+    pub tick: u64,
+    /// Time spent in the kernel, in TSC cycles
+    pub cycles: u64,
+}
+
+impl SyscallRecord {
+    /// Format the record as a single strace-style line
+    pub fn format(&self) -> String {
+        let syscall = SyscallNumber::from_u64(self.number);
+
+        let call = match syscall {
+            Some(sc) => format!("{}({})", sc.name(), decode_args(sc, &self.args)),
+            None => format!("syscall_{}({:#x}, {:#x}, {:#x})",
+                self.number, self.args[0], self.args[1], self.args[2]),
+        };
+
+        let result = match self.result {
+            Ok(value) if returns_address(syscall) => format!("{:#x}", value),
+            Ok(value) => format!("{}", value),
+            Err(err) => match errno_name(err.errno()) {
+                Some(name) => format!("-1 {} ({:?})", name, err),
+                None => format!("-1 errno {} ({:?})", err.errno(), err),
+            },
+        };
+
+        format!("[{:>8}] {} = {} <{} cycles>", self.tick, call, result, self.cycles)
+    }
+}
+
+/// Per-process trace state
+pub struct SyscallTrace {
+    enabled: AtomicBool,
+    records: Mutex<VecDeque<SyscallRecord>>,
+}
+
+impl SyscallTrace {
+    pub fn new() -> Self {
+        Self {
+            enabled: AtomicBool::new(false),
+            records: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Check whether tracing is on for this process
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Turn tracing on or off
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Append a record, evicting the oldest one if the buffer is full
+    pub fn record(&self, record: SyscallRecord) {
+        let mut records = self.records.lock();
+        if records.len() >= TRACE_BUFFER_SIZE {
+            records.pop_front();
+        }
+        records.push_back(record);
+    }
+
+    /// Get a copy of all buffered records, oldest first
+    pub fn snapshot(&self) -> Vec<SyscallRecord> {
+        self.records.lock().iter().copied().collect()
+    }
+
+    /// Discard all buffered records
+    pub fn clear(&self) {
+        self.records.lock().clear();
+    }
+
+    /// Render the buffer as text, one call per line
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for record in self.records.lock().iter() {
+            out.push_str(&record.format());
+            out.push('\n');
+        }
+        out
+    }
+}
+
+impl Default for SyscallTrace {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Read the CPU timestamp counter
+#[inline]
+pub fn read_tsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Check whether a syscall's return value is an address (shown in hex)
+fn returns_address(syscall: Option<SyscallNumber>) -> bool {
+    matches!(
+        syscall,
+        Some(SyscallNumber::Brk) | Some(SyscallNumber::Sbrk) | Some(SyscallNumber::Mmap)
+    )
+}
+
+/// Decode the arguments of a known syscall into readable form
+fn decode_args(syscall: SyscallNumber, args: &[u64; 6]) -> String {
+    match syscall {
+        SyscallNumber::Write | SyscallNumber::Read => {
+            format!("{}, {:#x}, {}", args[0], args[1], args[2])
+        }
+        SyscallNumber::Exit => format!("{}", args[0] as i64),
+        SyscallNumber::GetPid | SyscallNumber::Fork | SyscallNumber::Sync => String::new(),
+        SyscallNumber::Exec => format!("{:#x}", args[0]),
+        SyscallNumber::Brk => format!("{:#x}", args[0]),
+        SyscallNumber::Sbrk => format!("{}", args[0] as i64),
+        SyscallNumber::Mmap => format!(
+            "{:#x}, {}, {}, {}, {}, {:#x}",
+            args[0], args[1], decode_prot(args[2]), decode_map_flags(args[3]),
+            args[4] as i64, args[5]
+        ),
+        SyscallNumber::Munmap => format!("{:#x}, {}", args[0], args[1]),
+        SyscallNumber::Mprotect => {
+            format!("{:#x}, {}, {}", args[0], args[1], decode_prot(args[2]))
+        }
+        SyscallNumber::SetSyscallFilter => {
+            format!("{:#x}, {}, {}, {}", args[0], args[1], args[2], args[3])
+        }
+        SyscallNumber::ClockGettime => format!("{}, {:#x}", decode_clock(args[0]), args[1]),
+        SyscallNumber::Nanosleep => format!("{:#x}, {:#x}", args[0], args[1]),
+        SyscallNumber::Gettimeofday => format!("{:#x}", args[0]),
+        SyscallNumber::Alarm => format!("{}", args[0]),
+        SyscallNumber::Setitimer => format!("{}, {:#x}, {:#x}", args[0], args[1], args[2]),
+        SyscallNumber::Getitimer => format!("{}, {:#x}", args[0], args[1]),
+        SyscallNumber::Close | SyscallNumber::Fsync | SyscallNumber::Dup => format!("{}", args[0]),
+        SyscallNumber::Pipe | SyscallNumber::Socketpair => format!("{:#x}", args[0]),
+        SyscallNumber::Poll => format!("{:#x}, {}, {}", args[0], args[1], args[2] as i64),
+        SyscallNumber::Select => format!(
+            "{}, {:#x}, {:#x}, {:#x}, {:#x}",
+            args[0], args[1], args[2], args[3], args[4]
+        ),
+        SyscallNumber::EpollCreate => format!("{:#x}", args[0]),
+        SyscallNumber::EpollCtl => format!("{}, {}, {}, {:#x}", args[0], decode_epoll_op(args[1]), args[2], args[3]),
+        SyscallNumber::EpollWait => format!("{}, {:#x}, {}, {}", args[0], args[1], args[2], args[3] as i64),
+        SyscallNumber::Stat
+        | SyscallNumber::Lstat
+        | SyscallNumber::Utimes
+        | SyscallNumber::Symlink
+        | SyscallNumber::Link
+        | SyscallNumber::Rename => format!("{:#x}, {:#x}", args[0], args[1]),
+        SyscallNumber::Chdir => format!("{:#x}", args[0]),
+        SyscallNumber::Getcwd => format!("{:#x}, {}", args[0], args[1]),
+        SyscallNumber::Readlink => format!("{:#x}, {:#x}, {}", args[0], args[1], args[2]),
+        SyscallNumber::Fstat => format!("{}, {:#x}", args[0], args[1]),
+        SyscallNumber::Chmod => format!("{:#x}, {:#o}", args[0], args[1]),
+        SyscallNumber::Chown => format!("{:#x}, {}, {}", args[0], args[1] as i32, args[2] as i32),
+        SyscallNumber::Open => format!("{:#x}, {:#o}, {:#o}", args[0], args[1], args[2]),
+        SyscallNumber::Lseek => format!("{}, {}, {}", args[0], args[1] as i64, args[2]),
+        SyscallNumber::Dup2 => format!("{}, {}", args[0], args[1]),
+        SyscallNumber::Flock => format!("{}, {:#x}", args[0], args[1]),
+        SyscallNumber::Getdents64 => format!("{}, {:#x}, {}", args[0], args[1], args[2]),
+        SyscallNumber::Fcntl => format!("{}, {}, {:#x}", args[0], args[1], args[2]),
+        SyscallNumber::InotifyInit => format!("{:#o}", args[0]),
+        SyscallNumber::InotifyAddWatch => format!("{}, {:#x}, {:#x}", args[0], args[1], args[2]),
+        SyscallNumber::InotifyRmWatch => format!("{}, {}", args[0], args[1] as i32),
+    }
+}
+
+/// Join the names of the set bits in `value`, or print it in hex if none match
+fn decode_bits(value: u64, names: &[(u64, &str)], zero: &str) -> String {
+    if value == 0 {
+        return String::from(zero);
+    }
+
+    let mut parts: Vec<&str> = Vec::new();
+    let mut rest = value;
+    for &(bit, name) in names {
+        if value & bit != 0 {
+            parts.push(name);
+            rest &= !bit;
+        }
+    }
+
+    let mut out = parts.join("|");
+    if rest != 0 {
+        if !out.is_empty() {
+            out.push('|');
+        }
+        out.push_str(&format!("{:#x}", rest));
+    }
+    out
+}
+
+fn decode_prot(prot: u64) -> String {
+    use crate::vma::{PROT_EXEC, PROT_READ, PROT_WRITE};
+    decode_bits(prot, &[
+        (PROT_READ as u64, "PROT_READ"),
+        (PROT_WRITE as u64, "PROT_WRITE"),
+        (PROT_EXEC as u64, "PROT_EXEC"),
+    ], "PROT_NONE")
+}
+
+fn decode_map_flags(flags: u64) -> String {
+    use crate::vma::{MAP_ANONYMOUS, MAP_FIXED, MAP_PRIVATE, MAP_SHARED};
+    decode_bits(flags, &[
+        (MAP_SHARED as u64, "MAP_SHARED"),
+        (MAP_PRIVATE as u64, "MAP_PRIVATE"),
+        (MAP_FIXED as u64, "MAP_FIXED"),
+        (MAP_ANONYMOUS as u64, "MAP_ANONYMOUS"),
+    ], "0")
+}
+
+fn decode_clock(clock_id: u64) -> String {
+    use crate::time::{CLOCK_MONOTONIC, CLOCK_REALTIME};
+    match clock_id {
+        CLOCK_REALTIME => String::from("CLOCK_REALTIME"),
+        CLOCK_MONOTONIC => String::from("CLOCK_MONOTONIC"),
+        other => format!("{}", other),
+    }
+}
+
+fn decode_epoll_op(op: u64) -> String {
+    use crate::epoll::{EPOLL_CTL_ADD, EPOLL_CTL_DEL, EPOLL_CTL_MOD};
+    match op {
+        EPOLL_CTL_ADD => String::from("EPOLL_CTL_ADD"),
+        EPOLL_CTL_DEL => String::from("EPOLL_CTL_DEL"),
+        EPOLL_CTL_MOD => String::from("EPOLL_CTL_MOD"),
+        other => format!("{}", other),
+    }
+}
+
+/// Get the symbolic name of an errno value for trace output
+pub fn errno_name(errno: u16) -> Option<&'static str> {
+    match errno {
+        1 => Some("EPERM"),
+        2 => Some("ENOENT"),
+        4 => Some("EINTR"),
+        5 => Some("EIO"),
+        9 => Some("EBADF"),
+        11 => Some("EAGAIN"),
+        12 => Some("ENOMEM"),
+        13 => Some("EACCES"),
+        14 => Some("EFAULT"),
+        16 => Some("EBUSY"),
+        17 => Some("EEXIST"),
+        18 => Some("EXDEV"),
+        20 => Some("ENOTDIR"),
+        21 => Some("EISDIR"),
+        22 => Some("EINVAL"),
+        24 => Some("EMFILE"),
+        28 => Some("ENOSPC"),
+        29 => Some("ESPIPE"),
+        30 => Some("EROFS"),
+        32 => Some("EPIPE"),
+        34 => Some("ERANGE"),
+        35 => Some("EDEADLK"),
+        36 => Some("ENAMETOOLONG"),
+        38 => Some("ENOSYS"),
+        39 => Some("ENOTEMPTY"),
+        40 => Some("ELOOP"),
+        _ => None,
+    }
+}
@@ -2,6 +2,21 @@
 // System call interface for user mode programs
 
 use crate::{println, print, serial_println, serial_print};
+use crate::process;
+use crate::seccomp::{FilterAction, FilterRule, SyscallFilter, MAX_FILTER_RULES};
+use crate::strace::{self, SyscallRecord};
+use crate::time::{self, Itimerval, SleepResult, Timespec, Timeval, ITIMER_REAL, NSEC_PER_SEC};
+use crate::vma::VmError;
+use crate::epoll::{Epoll, EpollEvent, EPOLL_CTL_ADD, EPOLL_CTL_DEL, EPOLL_CTL_MOD};
+use crate::poll::{self, FdSet, PollFd, FD_SETSIZE, POLLIN, POLLOUT};
+use crate::filelock::{self, Flock, LockKind, RecordLock};
+use crate::inotify::{self, Inotify};
+use crate::mount;
+use crate::pagecache;
+use crate::vfs::{self, FileDescriptor, FileType, Inode, OpenFlags, SeekFrom, SetAttr, Stat, VfsError, MODE_PERMISSION_MASK};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
 
 /// System call numbers
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -13,6 +28,50 @@ pub enum SyscallNumber {
     GetPid = 3,
     Fork = 4,
     Exec = 5,
+    Brk = 6,
+    Sbrk = 7,
+    Mmap = 8,
+    Munmap = 9,
+    Mprotect = 10,
+    SetSyscallFilter = 11,
+    ClockGettime = 12,
+    Nanosleep = 13,
+    Gettimeofday = 14,
+    Alarm = 15,
+    Setitimer = 16,
+    Getitimer = 17,
+    Close = 18,
+    Pipe = 19,
+    Socketpair = 20,
+    Poll = 21,
+    Select = 22,
+    EpollCreate = 23,
+    EpollCtl = 24,
+    EpollWait = 25,
+    Stat = 26,
+    Fstat = 27,
+    Chmod = 28,
+    Chown = 29,
+    Utimes = 30,
+    Lstat = 31,
+    Symlink = 32,
+    Readlink = 33,
+    Link = 34,
+    Rename = 35,
+    Chdir = 36,
+    Getcwd = 37,
+    Fsync = 38,
+    Sync = 39,
+    Open = 40,
+    Lseek = 41,
+    Dup = 42,
+    Dup2 = 43,
+    Fcntl = 44,
+    Getdents64 = 45,
+    Flock = 46,
+    InotifyInit = 47,
+    InotifyAddWatch = 48,
+    InotifyRmWatch = 49,
 }
 
 impl SyscallNumber {
@@ -24,9 +83,109 @@ impl SyscallNumber {
             3 => Some(SyscallNumber::GetPid),
             4 => Some(SyscallNumber::Fork),
             5 => Some(SyscallNumber::Exec),
+            6 => Some(SyscallNumber::Brk),
+            7 => Some(SyscallNumber::Sbrk),
+            8 => Some(SyscallNumber::Mmap),
+            9 => Some(SyscallNumber::Munmap),
+            10 => Some(SyscallNumber::Mprotect),
+            11 => Some(SyscallNumber::SetSyscallFilter),
+            12 => Some(SyscallNumber::ClockGettime),
+            13 => Some(SyscallNumber::Nanosleep),
+            14 => Some(SyscallNumber::Gettimeofday),
+            15 => Some(SyscallNumber::Alarm),
+            16 => Some(SyscallNumber::Setitimer),
+            17 => Some(SyscallNumber::Getitimer),
+            18 => Some(SyscallNumber::Close),
+            19 => Some(SyscallNumber::Pipe),
+            20 => Some(SyscallNumber::Socketpair),
+            21 => Some(SyscallNumber::Poll),
+            22 => Some(SyscallNumber::Select),
+            23 => Some(SyscallNumber::EpollCreate),
+            24 => Some(SyscallNumber::EpollCtl),
+            25 => Some(SyscallNumber::EpollWait),
+            26 => Some(SyscallNumber::Stat),
+            27 => Some(SyscallNumber::Fstat),
+            28 => Some(SyscallNumber::Chmod),
+            29 => Some(SyscallNumber::Chown),
+            30 => Some(SyscallNumber::Utimes),
+            31 => Some(SyscallNumber::Lstat),
+            32 => Some(SyscallNumber::Symlink),
+            33 => Some(SyscallNumber::Readlink),
+            34 => Some(SyscallNumber::Link),
+            35 => Some(SyscallNumber::Rename),
+            36 => Some(SyscallNumber::Chdir),
+            37 => Some(SyscallNumber::Getcwd),
+            38 => Some(SyscallNumber::Fsync),
+            39 => Some(SyscallNumber::Sync),
+            40 => Some(SyscallNumber::Open),
+            41 => Some(SyscallNumber::Lseek),
+            42 => Some(SyscallNumber::Dup),
+            43 => Some(SyscallNumber::Dup2),
+            44 => Some(SyscallNumber::Fcntl),
+            45 => Some(SyscallNumber::Getdents64),
+            46 => Some(SyscallNumber::Flock),
+            47 => Some(SyscallNumber::InotifyInit),
+            48 => Some(SyscallNumber::InotifyAddWatch),
+            49 => Some(SyscallNumber::InotifyRmWatch),
             _ => None,
         }
     }
+
+    /// Get the syscall's name as used in trace output
+    pub fn name(&self) -> &'static str {
+        match self {
+            SyscallNumber::Write => "write",
+            SyscallNumber::Read => "read",
+            SyscallNumber::Exit => "exit",
+            SyscallNumber::GetPid => "getpid",
+            SyscallNumber::Fork => "fork",
+            SyscallNumber::Exec => "exec",
+            SyscallNumber::Brk => "brk",
+            SyscallNumber::Sbrk => "sbrk",
+            SyscallNumber::Mmap => "mmap",
+            SyscallNumber::Munmap => "munmap",
+            SyscallNumber::Mprotect => "mprotect",
+            SyscallNumber::SetSyscallFilter => "set_syscall_filter",
+            SyscallNumber::ClockGettime => "clock_gettime",
+            SyscallNumber::Nanosleep => "nanosleep",
+            SyscallNumber::Gettimeofday => "gettimeofday",
+            SyscallNumber::Alarm => "alarm",
+            SyscallNumber::Setitimer => "setitimer",
+            SyscallNumber::Getitimer => "getitimer",
+            SyscallNumber::Close => "close",
+            SyscallNumber::Pipe => "pipe",
+            SyscallNumber::Socketpair => "socketpair",
+            SyscallNumber::Poll => "poll",
+            SyscallNumber::Select => "select",
+            SyscallNumber::EpollCreate => "epoll_create",
+            SyscallNumber::EpollCtl => "epoll_ctl",
+            SyscallNumber::EpollWait => "epoll_wait",
+            SyscallNumber::Stat => "stat",
+            SyscallNumber::Fstat => "fstat",
+            SyscallNumber::Chmod => "chmod",
+            SyscallNumber::Chown => "chown",
+            SyscallNumber::Utimes => "utimes",
+            SyscallNumber::Lstat => "lstat",
+            SyscallNumber::Symlink => "symlink",
+            SyscallNumber::Readlink => "readlink",
+            SyscallNumber::Link => "link",
+            SyscallNumber::Rename => "rename",
+            SyscallNumber::Chdir => "chdir",
+            SyscallNumber::Getcwd => "getcwd",
+            SyscallNumber::Fsync => "fsync",
+            SyscallNumber::Sync => "sync",
+            SyscallNumber::Open => "open",
+            SyscallNumber::Lseek => "lseek",
+            SyscallNumber::Dup => "dup",
+            SyscallNumber::Dup2 => "dup2",
+            SyscallNumber::Fcntl => "fcntl",
+            SyscallNumber::Getdents64 => "getdents64",
+            SyscallNumber::Flock => "flock",
+            SyscallNumber::InotifyInit => "inotify_init",
+            SyscallNumber::InotifyAddWatch => "inotify_add_watch",
+            SyscallNumber::InotifyRmWatch => "inotify_rm_watch",
+        }
+    }
 }
 
 /// System call result type
@@ -37,13 +196,77 @@ pub type SyscallResult = Result<u64, SyscallError>;
 pub enum SyscallError {
     InvalidSyscall,
     InvalidFileDescriptor,
-    #[allow(dead_code)]
     InvalidBuffer,
     NotImplemented,
+    InvalidArgument,
+    OutOfMemory,
+    PermissionDenied,
+    /// Rejected by the process's syscall filter
+    Denied,
+    /// Rejected by the syscall filter with a specific error number
+    Errno(u16),
+    /// A blocking call was interrupted by a signal
+    Interrupted,
+}
+
+impl SyscallError {
+    /// Get the Unix errno value for this error
+    pub fn errno(&self) -> u16 {
+        match self {
+            SyscallError::Denied => 1,                        // EPERM
+            SyscallError::Interrupted => 4,                   // EINTR
+            SyscallError::InvalidFileDescriptor => 9,         // EBADF
+            SyscallError::OutOfMemory => 12,                  // ENOMEM
+            SyscallError::PermissionDenied => 13,             // EACCES
+            SyscallError::InvalidBuffer => 14,                // EFAULT
+            SyscallError::InvalidArgument => 22,              // EINVAL
+            SyscallError::InvalidSyscall
+            | SyscallError::NotImplemented => 38,             // ENOSYS
+            SyscallError::Errno(errno) => *errno,
+        }
+    }
+}
+
+impl From<VmError> for SyscallError {
+    fn from(err: VmError) -> Self {
+        match err {
+            VmError::InvalidArgument | VmError::NotMappable => SyscallError::InvalidArgument,
+            VmError::OutOfMemory => SyscallError::OutOfMemory,
+            VmError::PermissionDenied => SyscallError::PermissionDenied,
+            VmError::BadAddress => SyscallError::InvalidBuffer,
+            VmError::IoError => SyscallError::Errno(5), // EIO
+        }
+    }
+}
+
+impl From<VfsError> for SyscallError {
+    fn from(err: VfsError) -> Self {
+        match err {
+            VfsError::NotFound => SyscallError::Errno(2),           // ENOENT
+            VfsError::IoError => SyscallError::Errno(5),            // EIO
+            VfsError::Busy => SyscallError::Errno(16),              // EBUSY
+            VfsError::AlreadyExists => SyscallError::Errno(17),     // EEXIST
+            VfsError::CrossDevice => SyscallError::Errno(18),       // EXDEV
+            VfsError::NotADirectory => SyscallError::Errno(20),     // ENOTDIR
+            VfsError::IsADirectory => SyscallError::Errno(21),      // EISDIR
+            VfsError::WouldBlock => SyscallError::Errno(11),        // EAGAIN
+            VfsError::BrokenPipe => SyscallError::Errno(32),        // EPIPE
+            VfsError::Deadlock => SyscallError::Errno(35),          // EDEADLK
+            VfsError::NoSpace => SyscallError::Errno(28),           // ENOSPC
+            VfsError::ReadOnly => SyscallError::Errno(30),          // EROFS
+            VfsError::TooManyOpenFiles => SyscallError::Errno(24),  // EMFILE
+            VfsError::DirectoryNotEmpty => SyscallError::Errno(39), // ENOTEMPTY
+            VfsError::SymlinkLoop => SyscallError::Errno(40),       // ELOOP
+            VfsError::PermissionDenied => SyscallError::PermissionDenied,
+            VfsError::InvalidArgument
+            | VfsError::InvalidOperation => SyscallError::InvalidArgument,
+            VfsError::NotImplemented => SyscallError::NotImplemented,
+            VfsError::Interrupted => SyscallError::Interrupted,
+        }
+    }
 }
 
 /// File descriptors
-#[allow(dead_code)]
 pub const STDIN: u64 = 0;
 pub const STDOUT: u64 = 1;
 pub const STDERR: u64 = 2;
@@ -59,14 +282,75 @@ pub const STDERR: u64 = 2;
 /// - r10: arg4
 /// - r8: arg5
 /// - r9: arg6
+///
+/// The call is first checked against the process's syscall filter. If the
+/// process has tracing enabled, the call is recorded in its trace buffer
+/// along with its result and duration.
 pub fn dispatch_syscall(
     syscall_num: u64,
     arg1: u64,
     arg2: u64,
     arg3: u64,
-    _arg4: u64,
-    _arg5: u64,
-    _arg6: u64,
+    arg4: u64,
+    arg5: u64,
+    arg6: u64,
+) -> SyscallResult {
+    let pid = process::current_pid();
+    let resources = process::resources(pid);
+    let args = [arg1, arg2, arg3, arg4, arg5, arg6];
+
+    if !resources.trace.is_enabled() {
+        return filter_and_invoke(pid, &resources, syscall_num, &args);
+    }
+
+    let tick = crate::pit::get_ticks();
+    let start = strace::read_tsc();
+    let result = filter_and_invoke(pid, &resources, syscall_num, &args);
+    let cycles = strace::read_tsc().wrapping_sub(start);
+
+    resources.trace.record(SyscallRecord {
+        number: syscall_num,
+        args,
+        result,
+        tick,
+        cycles,
+    });
+
+    result
+}
+
+/// Apply the process's syscall filter, then run the syscall if allowed
+fn filter_and_invoke(
+    pid: u32,
+    resources: &process::ProcessResources,
+    syscall_num: u64,
+    args: &[u64; 6],
+) -> SyscallResult {
+    let action = resources.filters.lock().evaluate(syscall_num, args);
+
+    match action {
+        FilterAction::Allow => {
+            invoke_syscall(syscall_num, args[0], args[1], args[2], args[3], args[4], args[5])
+        }
+        FilterAction::Deny => Err(SyscallError::Denied),
+        FilterAction::Errno(errno) => Err(SyscallError::Errno(errno)),
+        FilterAction::Kill => {
+            serial_println!("seccomp: killing PID {} on syscall {}", pid, syscall_num);
+            process::exit(pid);
+            wait_for_reschedule()
+        }
+    }
+}
+
+/// Decode the syscall number and run the handler
+fn invoke_syscall(
+    syscall_num: u64,
+    arg1: u64,
+    arg2: u64,
+    arg3: u64,
+    arg4: u64,
+    arg5: u64,
+    arg6: u64,
 ) -> SyscallResult {
     let syscall = SyscallNumber::from_u64(syscall_num)
         .ok_or(SyscallError::InvalidSyscall)?;
@@ -78,6 +362,50 @@ pub fn dispatch_syscall(
         SyscallNumber::GetPid => sys_getpid(),
         SyscallNumber::Fork => Err(SyscallError::NotImplemented),
         SyscallNumber::Exec => Err(SyscallError::NotImplemented),
+        SyscallNumber::Brk => sys_brk(arg1),
+        SyscallNumber::Sbrk => sys_sbrk(arg1),
+        SyscallNumber::Mmap => sys_mmap(arg1, arg2, arg3, arg4, arg5, arg6),
+        SyscallNumber::Munmap => sys_munmap(arg1, arg2),
+        SyscallNumber::Mprotect => sys_mprotect(arg1, arg2, arg3),
+        SyscallNumber::SetSyscallFilter => sys_set_syscall_filter(arg1, arg2, arg3, arg4),
+        SyscallNumber::ClockGettime => sys_clock_gettime(arg1, arg2),
+        SyscallNumber::Nanosleep => sys_nanosleep(arg1, arg2),
+        SyscallNumber::Gettimeofday => sys_gettimeofday(arg1),
+        SyscallNumber::Alarm => sys_alarm(arg1),
+        SyscallNumber::Setitimer => sys_setitimer(arg1, arg2, arg3),
+        SyscallNumber::Getitimer => sys_getitimer(arg1, arg2),
+        SyscallNumber::Close => sys_close(arg1),
+        SyscallNumber::Pipe => sys_pipe(arg1),
+        SyscallNumber::Socketpair => sys_socketpair(arg1),
+        SyscallNumber::Poll => sys_poll(arg1, arg2, arg3),
+        SyscallNumber::Select => sys_select(arg1, arg2, arg3, arg4, arg5),
+        SyscallNumber::EpollCreate => sys_epoll_create(arg1),
+        SyscallNumber::EpollCtl => sys_epoll_ctl(arg1, arg2, arg3, arg4),
+        SyscallNumber::EpollWait => sys_epoll_wait(arg1, arg2, arg3, arg4),
+        SyscallNumber::Stat => sys_stat(arg1, arg2),
+        SyscallNumber::Fstat => sys_fstat(arg1, arg2),
+        SyscallNumber::Chmod => sys_chmod(arg1, arg2),
+        SyscallNumber::Chown => sys_chown(arg1, arg2, arg3),
+        SyscallNumber::Utimes => sys_utimes(arg1, arg2),
+        SyscallNumber::Lstat => sys_lstat(arg1, arg2),
+        SyscallNumber::Symlink => sys_symlink(arg1, arg2),
+        SyscallNumber::Readlink => sys_readlink(arg1, arg2, arg3),
+        SyscallNumber::Link => sys_link(arg1, arg2),
+        SyscallNumber::Rename => sys_rename(arg1, arg2),
+        SyscallNumber::Chdir => sys_chdir(arg1),
+        SyscallNumber::Getcwd => sys_getcwd(arg1, arg2),
+        SyscallNumber::Fsync => sys_fsync(arg1),
+        SyscallNumber::Sync => sys_sync(),
+        SyscallNumber::Open => sys_open(arg1, arg2, arg3),
+        SyscallNumber::Lseek => sys_lseek(arg1, arg2, arg3),
+        SyscallNumber::Dup => sys_dup(arg1),
+        SyscallNumber::Dup2 => sys_dup2(arg1, arg2),
+        SyscallNumber::Fcntl => sys_fcntl(arg1, arg2, arg3),
+        SyscallNumber::Getdents64 => sys_getdents64(arg1, arg2, arg3),
+        SyscallNumber::Flock => sys_flock(arg1, arg2),
+        SyscallNumber::InotifyInit => sys_inotify_init(arg1),
+        SyscallNumber::InotifyAddWatch => sys_inotify_add_watch(arg1, arg2, arg3),
+        SyscallNumber::InotifyRmWatch => sys_inotify_rm_watch(arg1, arg2),
     }
 }
 
@@ -90,16 +418,24 @@ pub fn dispatch_syscall(
 ///
 /// Returns: number of bytes written, or error
 fn sys_write(fd: u64, buf: u64, len: u64) -> SyscallResult {
-    // Validate file descriptor
-    if fd != STDOUT && fd != STDERR {
-        return Err(SyscallError::InvalidFileDescriptor);
-    }
-
     // Validate buffer pointer (basic check - should be more thorough)
     if buf == 0 || len == 0 {
         return Ok(0);
     }
 
+    // Descriptors opened by the process (pipes, sockets, files)
+    if let Ok(descriptor) = process::current_resources().fd_table.get(fd as usize) {
+        // Safety: We're trusting the user pointer for now
+        // TODO: Add proper user space memory validation
+        let slice = unsafe { core::slice::from_raw_parts(buf as *const u8, len as usize) };
+        return Ok(descriptor.write(slice)? as u64);
+    }
+
+    // Otherwise stdout/stderr go to the kernel console
+    if fd != STDOUT && fd != STDERR {
+        return Err(SyscallError::InvalidFileDescriptor);
+    }
+
     // For now, we'll just print to kernel console
     // In a real implementation, we'd validate the user buffer is mapped
     // and copy data safely from user space
@@ -139,10 +475,35 @@ fn sys_write(fd: u64, buf: u64, len: u64) -> SyscallResult {
 /// - len: maximum number of bytes to read
 ///
 /// Returns: number of bytes read, or error
-fn sys_read(_fd: u64, _buf: u64, _len: u64) -> SyscallResult {
-    // TODO: Implement keyboard input buffering
-    // For now, return 0 (EOF)
-    Err(SyscallError::NotImplemented)
+fn sys_read(fd: u64, buf: u64, len: u64) -> SyscallResult {
+    if len == 0 {
+        return Ok(0);
+    }
+    if buf == 0 {
+        return Err(SyscallError::InvalidBuffer);
+    }
+
+    // Safety: We're trusting the user pointer for now
+    // TODO: Add proper user space memory validation
+    let slice = unsafe { core::slice::from_raw_parts_mut(buf as *mut u8, len as usize) };
+
+    if let Ok(descriptor) = process::current_resources().fd_table.get(fd as usize) {
+        return Ok(descriptor.read(slice)? as u64);
+    }
+
+    // Otherwise stdin reads typed characters, blocking until there are some
+    if fd != STDIN {
+        return Err(SyscallError::InvalidFileDescriptor);
+    }
+    let result = crate::wait::wait_event(
+        &[&crate::keyboard::INPUT_WAIT],
+        None,
+        crate::keyboard::input_available,
+    );
+    if result == crate::wait::WaitResult::Interrupted {
+        return Err(SyscallError::Interrupted);
+    }
+    Ok(crate::keyboard::read_input(slice) as u64)
 }
 
 /// sys_exit: Terminate the current process
@@ -155,12 +516,18 @@ fn sys_exit(code: u64) -> SyscallResult {
     println!("Process exiting with code: {}", code);
     serial_println!("Process exiting with code: {}", code);
 
-    // TODO: Properly terminate the current process
-    // For now, just mark it as zombie in the process manager
-    // This is a placeholder until we have proper process cleanup
+    process::exit(process::current_pid());
+    wait_for_reschedule()
+}
 
-    // Return success (though this should not return in final implementation)
-    Ok(0)
+/// Idle until the next timer tick switches away from the current process
+///
+/// Used once the current process has become a zombie: it is never
+/// scheduled again, so this does not return.
+fn wait_for_reschedule() -> ! {
+    loop {
+        x86_64::instructions::interrupts::enable_and_hlt();
+    }
 }
 
 /// sys_getpid: Get current process ID
@@ -171,3 +538,1133 @@ fn sys_getpid() -> SyscallResult {
     // For now, return a dummy PID
     Ok(1)
 }
+
+/// sys_brk: Set the program break
+///
+/// Arguments:
+/// - addr: requested break, or 0 to query the current one
+///
+/// Returns: the new program break (unchanged if the request failed)
+fn sys_brk(addr: u64) -> SyscallResult {
+    let resources = process::current_resources();
+    let mut space = resources.address_space.lock();
+
+    if addr == 0 {
+        return Ok(space.current_brk());
+    }
+
+    Ok(space.brk(addr))
+}
+
+/// sys_sbrk: Grow or shrink the heap
+///
+/// Arguments:
+/// - increment: signed number of bytes to add to the program break
+///
+/// Returns: the previous program break, or error
+fn sys_sbrk(increment: u64) -> SyscallResult {
+    let resources = process::current_resources();
+    let mut space = resources.address_space.lock();
+    Ok(space.sbrk(increment as i64)?)
+}
+
+/// sys_mmap: Map anonymous memory or a file into the address space
+///
+/// Arguments:
+/// - addr: placement hint (or exact address with MAP_FIXED)
+/// - len: length in bytes
+/// - prot: PROT_* protection bits
+/// - flags: MAP_* flags
+/// - fd: file descriptor to map (ignored with MAP_ANONYMOUS)
+/// - offset: page-aligned offset into the file
+///
+/// Returns: start address of the mapping, or error
+fn sys_mmap(addr: u64, len: u64, prot: u64, flags: u64, fd: u64, offset: u64) -> SyscallResult {
+    let resources = process::current_resources();
+
+    let file = if flags as u32 & crate::vma::MAP_ANONYMOUS == 0 {
+        let descriptor = resources
+            .fd_table
+            .get(fd as usize)
+            .map_err(|_| SyscallError::InvalidFileDescriptor)?;
+        let open_flags = descriptor.flags();
+
+        if !open_flags.read {
+            return Err(SyscallError::PermissionDenied);
+        }
+
+        Some((descriptor.inode().clone(), open_flags.write))
+    } else {
+        None
+    };
+
+    let mut space = resources.address_space.lock();
+    Ok(space.mmap(addr, len, prot as u32, flags as u32, file, offset)?)
+}
+
+/// sys_munmap: Remove a mapping
+///
+/// Arguments:
+/// - addr: page-aligned start address
+/// - len: length in bytes
+///
+/// Returns: 0 on success, or error
+fn sys_munmap(addr: u64, len: u64) -> SyscallResult {
+    let resources = process::current_resources();
+    resources.address_space.lock().munmap(addr, len)?;
+    Ok(0)
+}
+
+/// sys_mprotect: Change the protection of mapped pages
+///
+/// Arguments:
+/// - addr: page-aligned start address
+/// - len: length in bytes
+/// - prot: new PROT_* protection bits
+///
+/// Returns: 0 on success, or error
+fn sys_mprotect(addr: u64, len: u64, prot: u64) -> SyscallResult {
+    let resources = process::current_resources();
+    resources.address_space.lock().mprotect(addr, len, prot as u32)?;
+    Ok(0)
+}
+
+/// sys_set_syscall_filter: Install a syscall filter on the calling process
+///
+/// Arguments:
+/// - rules: pointer to an array of `FilterRule` in user space
+/// - count: number of rules in the array
+/// - default_action: action when no rule matches (`ACTION_*`)
+/// - default_errno: error number used if `default_action` is `ACTION_ERRNO`
+///
+/// The filter is added on top of any already installed; it cannot be removed.
+///
+/// Returns: 0 on success, or error
+fn sys_set_syscall_filter(rules: u64, count: u64, default_action: u64, default_errno: u64) -> SyscallResult {
+    if count as usize > MAX_FILTER_RULES {
+        return Err(SyscallError::InvalidArgument);
+    }
+    if rules == 0 && count != 0 {
+        return Err(SyscallError::InvalidBuffer);
+    }
+
+    // Safety: We're trusting the user pointer for now
+    // TODO: Add proper user space memory validation
+    let raw: &[FilterRule] = if count == 0 {
+        &[]
+    } else {
+        unsafe { core::slice::from_raw_parts(rules as *const FilterRule, count as usize) }
+    };
+
+    let filter = SyscallFilter::new(raw, default_action as u32, default_errno as u32)?;
+    process::current_resources().filters.lock().install(filter);
+
+    Ok(0)
+}
+
+/// sys_clock_gettime: Read a clock
+///
+/// Arguments:
+/// - clock_id: `CLOCK_REALTIME` or `CLOCK_MONOTONIC`
+/// - tp: pointer to a `Timespec` in user space
+///
+/// Returns: 0 on success, or error
+fn sys_clock_gettime(clock_id: u64, tp: u64) -> SyscallResult {
+    let ns = time::clock_ns(clock_id).ok_or(SyscallError::InvalidArgument)?;
+    write_user(tp, Timespec::from_ns(ns))?;
+    Ok(0)
+}
+
+/// sys_nanosleep: Sleep for a duration
+///
+/// Arguments:
+/// - req: pointer to the requested `Timespec`
+/// - rem: pointer to a `Timespec` receiving the unslept time if a signal
+///   interrupts the sleep (may be 0)
+///
+/// Returns: 0 on success, or EINTR if interrupted
+fn sys_nanosleep(req: u64, rem: u64) -> SyscallResult {
+    let request: Timespec = read_user(req)?;
+    let duration = request.to_ns().ok_or(SyscallError::InvalidArgument)?;
+
+    match time::sleep_ns(duration) {
+        SleepResult::Completed => Ok(0),
+        SleepResult::Interrupted(remaining) => {
+            if rem != 0 {
+                write_user(rem, Timespec::from_ns(remaining))?;
+            }
+            Err(SyscallError::Interrupted)
+        }
+    }
+}
+
+/// sys_gettimeofday: Get the wall-clock time
+///
+/// Arguments:
+/// - tv: pointer to a `Timeval` in user space
+///
+/// Returns: 0 on success, or error
+fn sys_gettimeofday(tv: u64) -> SyscallResult {
+    write_user(tv, Timeval::from_ns(time::realtime_ns()))?;
+    Ok(0)
+}
+
+/// sys_alarm: Deliver SIGALRM after a number of seconds
+///
+/// Arguments:
+/// - seconds: delay, or 0 to cancel a pending alarm
+///
+/// Returns: seconds remaining on the previous alarm (rounded up), or 0
+fn sys_alarm(seconds: u64) -> SyscallResult {
+    let value = seconds.checked_mul(NSEC_PER_SEC).ok_or(SyscallError::InvalidArgument)?;
+    let (remaining, _) = time::set_real_timer(value, 0);
+    Ok(remaining.div_ceil(NSEC_PER_SEC))
+}
+
+/// sys_setitimer: Arm or disarm an interval timer
+///
+/// Arguments:
+/// - which: timer to set (only `ITIMER_REAL` is supported)
+/// - new_value: pointer to the new `Itimerval`
+/// - old_value: pointer receiving the previous `Itimerval` (may be 0)
+///
+/// Returns: 0 on success, or error
+fn sys_setitimer(which: u64, new_value: u64, old_value: u64) -> SyscallResult {
+    if which != ITIMER_REAL {
+        return Err(SyscallError::InvalidArgument);
+    }
+
+    let new: Itimerval = read_user(new_value)?;
+    let value = new.it_value.to_ns().ok_or(SyscallError::InvalidArgument)?;
+    let interval = new.it_interval.to_ns().ok_or(SyscallError::InvalidArgument)?;
+
+    let (remaining, old_interval) = time::set_real_timer(value, interval);
+
+    if old_value != 0 {
+        write_user(old_value, Itimerval {
+            it_interval: Timeval::from_ns(old_interval),
+            it_value: Timeval::from_ns(remaining),
+        })?;
+    }
+
+    Ok(0)
+}
+
+/// sys_getitimer: Read an interval timer
+///
+/// Arguments:
+/// - which: timer to read (only `ITIMER_REAL` is supported)
+/// - curr_value: pointer receiving the `Itimerval`
+///
+/// Returns: 0 on success, or error
+fn sys_getitimer(which: u64, curr_value: u64) -> SyscallResult {
+    if which != ITIMER_REAL {
+        return Err(SyscallError::InvalidArgument);
+    }
+
+    let (remaining, interval) = time::get_real_timer();
+    write_user(curr_value, Itimerval {
+        it_interval: Timeval::from_ns(interval),
+        it_value: Timeval::from_ns(remaining),
+    })?;
+
+    Ok(0)
+}
+
+/// sys_close: Close a file descriptor
+///
+/// Arguments:
+/// - fd: file descriptor to close
+///
+/// Returns: 0 on success, or error
+fn sys_close(fd: u64) -> SyscallResult {
+    let resources = process::current_resources();
+    let descriptor = resources.fd_table.get(fd as usize).map_err(|_| SyscallError::InvalidFileDescriptor)?;
+    resources.fd_table.close(fd as usize)?;
+    // Closing any descriptor for a file drops the process's record locks on it
+    filelock::release_records(process::current_pid(), descriptor.inode());
+    Ok(0)
+}
+
+/// sys_pipe: Create a pipe
+///
+/// Arguments:
+/// - fds: pointer to two `i32`s receiving the read and write descriptors
+///
+/// Returns: 0 on success, or error
+fn sys_pipe(fds: u64) -> SyscallResult {
+    let (reader, writer) = crate::pipe::pipe();
+    install_pair(
+        fds,
+        Arc::new(FileDescriptor::new(reader, OpenFlags::read_only())),
+        Arc::new(FileDescriptor::new(writer, OpenFlags::write_only())),
+    )
+}
+
+/// sys_socketpair: Create a pair of connected local stream sockets
+///
+/// Arguments:
+/// - fds: pointer to two `i32`s receiving the socket descriptors
+///
+/// Returns: 0 on success, or error
+fn sys_socketpair(fds: u64) -> SyscallResult {
+    let (a, b) = crate::socket::socketpair();
+    install_pair(
+        fds,
+        Arc::new(FileDescriptor::new(a, OpenFlags::read_write())),
+        Arc::new(FileDescriptor::new(b, OpenFlags::read_write())),
+    )
+}
+
+/// Allocate descriptors for both ends of a pipe or socket pair and store
+/// their numbers at `fds`
+fn install_pair(fds: u64, first: Arc<FileDescriptor>, second: Arc<FileDescriptor>) -> SyscallResult {
+    let resources = process::current_resources();
+    let table = &resources.fd_table;
+
+    let first = table.allocate(first)?;
+    let second = match table.allocate(second) {
+        Ok(fd) => fd,
+        Err(err) => {
+            let _ = table.close(first);
+            return Err(err.into());
+        }
+    };
+
+    if let Err(err) = write_user(fds, [first as i32, second as i32]) {
+        let _ = table.close(first);
+        let _ = table.close(second);
+        return Err(err);
+    }
+
+    Ok(0)
+}
+
+/// sys_poll: Wait for events on a set of file descriptors
+///
+/// Arguments:
+/// - fds: pointer to an array of `PollFd` in user space
+/// - nfds: number of entries in the array
+/// - timeout_ms: timeout in milliseconds (negative waits forever)
+///
+/// Negative descriptors are ignored; unknown ones report POLLNVAL.
+///
+/// Returns: number of entries with events, 0 on timeout, or error
+fn sys_poll(fds: u64, nfds: u64, timeout_ms: u64) -> SyscallResult {
+    if nfds as usize > FD_SETSIZE {
+        return Err(SyscallError::InvalidArgument);
+    }
+    if fds == 0 && nfds != 0 {
+        return Err(SyscallError::InvalidBuffer);
+    }
+
+    // Safety: We're trusting the user pointer for now
+    // TODO: Add proper user space memory validation
+    let pollfds: &mut [PollFd] = if nfds == 0 {
+        &mut []
+    } else {
+        unsafe { core::slice::from_raw_parts_mut(fds as *mut PollFd, nfds as usize) }
+    };
+
+    let resources = process::current_resources();
+    let mut entries = Vec::new();
+    let mut slots = Vec::new();
+    for (i, pollfd) in pollfds.iter_mut().enumerate() {
+        pollfd.revents = 0;
+        if pollfd.fd < 0 {
+            continue;
+        }
+        let inode = resources.fd_table.get(pollfd.fd as usize).ok().map(|d| d.inode().clone());
+        entries.push((inode, pollfd.events));
+        slots.push(i);
+    }
+
+    let revents = poll::poll_inodes(&entries, timeout_deadline_ms(timeout_ms as i64))?;
+
+    let mut ready = 0;
+    for (slot, revents) in slots.into_iter().zip(revents) {
+        pollfds[slot].revents = revents;
+        if revents != 0 {
+            ready += 1;
+        }
+    }
+    Ok(ready)
+}
+
+/// sys_select: Wait for descriptors in fd_set bitmaps to become ready
+///
+/// Arguments:
+/// - nfds: highest descriptor to check plus one
+/// - readfds/writefds/exceptfds: pointers to `FdSet`s (may be 0); updated
+///   in place to the ready descriptors
+/// - timeout: pointer to a `Timeval` (0 waits forever)
+///
+/// Returns: total number of ready descriptors across all sets, or error
+fn sys_select(nfds: u64, readfds: u64, writefds: u64, exceptfds: u64, timeout: u64) -> SyscallResult {
+    if nfds as usize > FD_SETSIZE {
+        return Err(SyscallError::InvalidArgument);
+    }
+
+    let read_set: FdSet = if readfds != 0 { read_user(readfds)? } else { FdSet::new() };
+    let write_set: FdSet = if writefds != 0 { read_user(writefds)? } else { FdSet::new() };
+
+    let deadline = if timeout != 0 {
+        let tv: Timeval = read_user(timeout)?;
+        let ns = tv.to_ns().ok_or(SyscallError::InvalidArgument)?;
+        Some(time::monotonic_ns().saturating_add(ns))
+    } else {
+        None
+    };
+
+    let resources = process::current_resources();
+    let mut entries: Vec<(Option<Arc<dyn Inode>>, i16)> = Vec::new();
+    let mut fds = Vec::new();
+    for fd in 0..nfds as usize {
+        let mut events = 0;
+        if read_set.contains(fd) {
+            events |= POLLIN;
+        }
+        if write_set.contains(fd) {
+            events |= POLLOUT;
+        }
+        if events == 0 {
+            continue;
+        }
+
+        let descriptor = resources.fd_table.get(fd).map_err(|_| SyscallError::InvalidFileDescriptor)?;
+        entries.push((Some(descriptor.inode().clone()), events));
+        fds.push(fd);
+    }
+
+    let revents = poll::poll_inodes(&entries, deadline)?;
+
+    // Hangup and errors count as readable, as on Linux
+    let mut ready_read = FdSet::new();
+    let mut ready_write = FdSet::new();
+    let mut ready = 0;
+    for ((fd, (_, events)), revents) in fds.into_iter().zip(&entries).zip(revents) {
+        if events & POLLIN != 0 && revents & !POLLOUT != 0 {
+            ready_read.insert(fd);
+            ready += 1;
+        }
+        if events & POLLOUT != 0 && revents & POLLOUT != 0 {
+            ready_write.insert(fd);
+            ready += 1;
+        }
+    }
+
+    if readfds != 0 {
+        write_user(readfds, ready_read)?;
+    }
+    if writefds != 0 {
+        write_user(writefds, ready_write)?;
+    }
+    // No inode reports exceptional conditions
+    if exceptfds != 0 {
+        write_user(exceptfds, FdSet::new())?;
+    }
+
+    Ok(ready)
+}
+
+/// sys_epoll_create: Create an epoll instance
+///
+/// Arguments:
+/// - flags: must be 0
+///
+/// Returns: the new epoll descriptor, or error
+fn sys_epoll_create(flags: u64) -> SyscallResult {
+    if flags != 0 {
+        return Err(SyscallError::InvalidArgument);
+    }
+
+    let descriptor = Arc::new(FileDescriptor::new(Epoll::new(), OpenFlags::read_only()));
+    Ok(process::current_resources().fd_table.allocate(descriptor)? as u64)
+}
+
+/// Get the epoll instance behind a descriptor
+fn epoll_instance(epfd: u64) -> Result<Arc<Epoll>, SyscallError> {
+    let descriptor = process::current_resources()
+        .fd_table
+        .get(epfd as usize)
+        .map_err(|_| SyscallError::InvalidFileDescriptor)?;
+    vfs::downcast_inode::<Epoll>(descriptor.inode()).ok_or(SyscallError::InvalidArgument)
+}
+
+/// sys_epoll_ctl: Add, modify or remove a descriptor in an epoll instance
+///
+/// Arguments:
+/// - epfd: epoll descriptor
+/// - op: `EPOLL_CTL_ADD`, `EPOLL_CTL_MOD` or `EPOLL_CTL_DEL`
+/// - fd: descriptor to watch
+/// - event: pointer to an `EpollEvent` (ignored for `EPOLL_CTL_DEL`)
+///
+/// Returns: 0 on success, or error
+fn sys_epoll_ctl(epfd: u64, op: u64, fd: u64, event: u64) -> SyscallResult {
+    let epoll = epoll_instance(epfd)?;
+    let target = process::current_resources()
+        .fd_table
+        .get(fd as usize)
+        .map_err(|_| SyscallError::InvalidFileDescriptor)?;
+
+    match op {
+        EPOLL_CTL_ADD => epoll.add(fd as usize, target.inode().clone(), read_user_unaligned(event)?)?,
+        EPOLL_CTL_MOD => epoll.modify(fd as usize, read_user_unaligned(event)?)?,
+        EPOLL_CTL_DEL => epoll.delete(fd as usize)?,
+        _ => return Err(SyscallError::InvalidArgument),
+    }
+
+    Ok(0)
+}
+
+/// sys_epoll_wait: Wait for events on an epoll instance
+///
+/// Arguments:
+/// - epfd: epoll descriptor
+/// - events: pointer to an array of `EpollEvent` receiving the events
+/// - max_events: capacity of the array (must be positive)
+/// - timeout_ms: timeout in milliseconds (negative waits forever)
+///
+/// Returns: number of events stored, 0 on timeout, or error
+fn sys_epoll_wait(epfd: u64, events: u64, max_events: u64, timeout_ms: u64) -> SyscallResult {
+    if max_events == 0 || max_events as usize > FD_SETSIZE {
+        return Err(SyscallError::InvalidArgument);
+    }
+    if events == 0 {
+        return Err(SyscallError::InvalidBuffer);
+    }
+
+    let epoll = epoll_instance(epfd)?;
+    let ready = epoll.wait(max_events as usize, timeout_deadline_ms(timeout_ms as i64))?;
+
+    // Safety: We're trusting the user pointer for now
+    // TODO: Add proper user space memory validation
+    let out = unsafe { core::slice::from_raw_parts_mut(events as *mut EpollEvent, max_events as usize) };
+    out[..ready.len()].copy_from_slice(&ready);
+
+    Ok(ready.len() as u64)
+}
+
+/// sys_stat: Get the attributes of a file by path, following symlinks
+///
+/// Arguments:
+/// - path: pointer to a NUL-terminated absolute path
+/// - statbuf: pointer to a `Stat` receiving the attributes
+///
+/// Returns: 0 on success, or error
+fn sys_stat(path: u64, statbuf: u64) -> SyscallResult {
+    let inode = mount::resolve_path(&read_user_path(path)?)?;
+    write_user(statbuf, Stat::from(&inode.metadata()))?;
+    Ok(0)
+}
+
+/// sys_lstat: Get the attributes of a file by path
+///
+/// Like `sys_stat`, but a symlink in the final component is reported
+/// itself rather than followed.
+///
+/// Arguments:
+/// - path: pointer to a NUL-terminated absolute path
+/// - statbuf: pointer to a `Stat` receiving the attributes
+///
+/// Returns: 0 on success, or error
+fn sys_lstat(path: u64, statbuf: u64) -> SyscallResult {
+    let inode = mount::resolve_path_no_follow(&read_user_path(path)?)?;
+    write_user(statbuf, Stat::from(&inode.metadata()))?;
+    Ok(0)
+}
+
+/// sys_fstat: Get the attributes of an open file
+///
+/// Arguments:
+/// - fd: file descriptor
+/// - statbuf: pointer to a `Stat` receiving the attributes
+///
+/// Returns: 0 on success, or error
+fn sys_fstat(fd: u64, statbuf: u64) -> SyscallResult {
+    let descriptor = process::current_resources()
+        .fd_table
+        .get(fd as usize)
+        .map_err(|_| SyscallError::InvalidFileDescriptor)?;
+    write_user(statbuf, Stat::from(&descriptor.inode().metadata()))?;
+    Ok(0)
+}
+
+/// sys_chmod: Change the permission bits of a file
+///
+/// Arguments:
+/// - path: pointer to a NUL-terminated absolute path
+/// - mode: new permission bits (file type bits are ignored)
+///
+/// Returns: 0 on success, or error
+fn sys_chmod(path: u64, mode: u64) -> SyscallResult {
+    let inode = mount::resolve_path(&read_user_path(path)?)?;
+    inode.setattr(&SetAttr {
+        mode: Some(mode as u16 & MODE_PERMISSION_MASK),
+        ..SetAttr::default()
+    })?;
+    Ok(0)
+}
+
+/// sys_chown: Change the owner and group of a file
+///
+/// Arguments:
+/// - path: pointer to a NUL-terminated absolute path
+/// - uid: new owner, or -1 to leave it unchanged
+/// - gid: new group, or -1 to leave it unchanged
+///
+/// Returns: 0 on success, or error
+fn sys_chown(path: u64, uid: u64, gid: u64) -> SyscallResult {
+    let unchanged = |id: u64| (id as u32 != u32::MAX).then_some(id as u32);
+
+    let inode = mount::resolve_path(&read_user_path(path)?)?;
+    inode.setattr(&SetAttr {
+        uid: unchanged(uid),
+        gid: unchanged(gid),
+        ..SetAttr::default()
+    })?;
+    Ok(0)
+}
+
+/// sys_utimes: Change the access and modification times of a file
+///
+/// Arguments:
+/// - path: pointer to a NUL-terminated absolute path
+/// - times: pointer to two `Timeval`s (access, modification), or 0 to set
+///   both to the current time
+///
+/// Returns: 0 on success, or error
+fn sys_utimes(path: u64, times: u64) -> SyscallResult {
+    let (atime, mtime) = if times == 0 {
+        let now = vfs::current_time();
+        (now, now)
+    } else {
+        let [atime, mtime]: [Timeval; 2] = read_user(times)?;
+        let to_timespec = |tv: Timeval| tv.to_ns().map(Timespec::from_ns).ok_or(SyscallError::InvalidArgument);
+        (to_timespec(atime)?, to_timespec(mtime)?)
+    };
+
+    let inode = mount::resolve_path(&read_user_path(path)?)?;
+    inode.setattr(&SetAttr {
+        atime: Some(atime),
+        mtime: Some(mtime),
+        ..SetAttr::default()
+    })?;
+    Ok(0)
+}
+
+/// sys_symlink: Create a symbolic link
+///
+/// Arguments:
+/// - target: pointer to the NUL-terminated link target (stored as given)
+/// - linkpath: pointer to the NUL-terminated path of the new link
+///
+/// Returns: 0 on success, or error
+fn sys_symlink(target: u64, linkpath: u64) -> SyscallResult {
+    let target = read_user_str(target)?;
+    mount::symlink(&target, &read_user_path(linkpath)?)?;
+    Ok(0)
+}
+
+/// sys_readlink: Read the target of a symbolic link
+///
+/// Arguments:
+/// - path: pointer to a NUL-terminated absolute path
+/// - buf: buffer receiving the target (not NUL-terminated)
+/// - size: capacity of the buffer; longer targets are truncated
+///
+/// Returns: number of bytes stored, or error
+fn sys_readlink(path: u64, buf: u64, size: u64) -> SyscallResult {
+    if buf == 0 || size == 0 {
+        return Err(SyscallError::InvalidArgument);
+    }
+
+    let target = mount::readlink(&read_user_path(path)?)?;
+    let count = target.len().min(size as usize);
+
+    // Safety: We're trusting the user pointer for now
+    // TODO: Add proper user space memory validation
+    let out = unsafe { core::slice::from_raw_parts_mut(buf as *mut u8, count) };
+    out.copy_from_slice(&target.as_bytes()[..count]);
+
+    Ok(count as u64)
+}
+
+/// sys_link: Create a hard link
+///
+/// Arguments:
+/// - oldpath: pointer to the NUL-terminated path of an existing file
+/// - newpath: pointer to the NUL-terminated path of the new link
+///
+/// Returns: 0 on success, or error (EXDEV across filesystems)
+fn sys_link(oldpath: u64, newpath: u64) -> SyscallResult {
+    mount::link(&read_user_path(oldpath)?, &read_user_path(newpath)?)?;
+    Ok(0)
+}
+
+/// sys_rename: Move a file or directory
+///
+/// Arguments:
+/// - oldpath: pointer to the NUL-terminated path of an existing entry
+/// - newpath: pointer to the NUL-terminated destination path; an existing
+///   compatible entry there is replaced
+///
+/// Returns: 0 on success, or error (EXDEV across filesystems)
+fn sys_rename(oldpath: u64, newpath: u64) -> SyscallResult {
+    mount::rename(&read_user_path(oldpath)?, &read_user_path(newpath)?)?;
+    Ok(0)
+}
+
+/// sys_chdir: Change the working directory
+///
+/// Arguments:
+/// - path: pointer to a NUL-terminated path of a directory
+///
+/// Returns: 0 on success, or error
+fn sys_chdir(path: u64) -> SyscallResult {
+    mount::chdir(&read_user_path(path)?)?;
+    Ok(0)
+}
+
+/// sys_getcwd: Get the path of the working directory
+///
+/// Arguments:
+/// - buf: buffer receiving the NUL-terminated path
+/// - size: capacity of the buffer
+///
+/// Returns: length of the path including the NUL, or ERANGE if it does not fit
+fn sys_getcwd(buf: u64, size: u64) -> SyscallResult {
+    if buf == 0 {
+        return Err(SyscallError::InvalidBuffer);
+    }
+
+    let cwd = mount::getcwd()?;
+    let len = cwd.len() + 1;
+    if len > size as usize {
+        return Err(SyscallError::Errno(34));
+    }
+
+    // Safety: We're trusting the user pointer for now
+    // TODO: Add proper user space memory validation
+    let out = unsafe { core::slice::from_raw_parts_mut(buf as *mut u8, len) };
+    out[..cwd.len()].copy_from_slice(cwd.as_bytes());
+    out[cwd.len()] = 0;
+
+    Ok(len as u64)
+}
+
+/// sys_fsync: Write back the cached dirty pages of a file
+///
+/// Arguments:
+/// - fd: file descriptor
+///
+/// Returns: 0 on success, or error
+fn sys_fsync(fd: u64) -> SyscallResult {
+    let descriptor = process::current_resources()
+        .fd_table
+        .get(fd as usize)
+        .map_err(|_| SyscallError::InvalidFileDescriptor)?;
+    pagecache::fsync(descriptor.inode())?;
+    Ok(0)
+}
+
+/// sys_sync: Write back every dirty page in the page cache
+///
+/// Returns: 0 on success, or error
+fn sys_sync() -> SyscallResult {
+    pagecache::sync()?;
+    Ok(0)
+}
+
+/// Highest descriptor number dup2 and F_DUPFD may ask for
+const MAX_FD: u64 = vfs::MAX_FD as u64;
+
+/// fcntl commands
+const F_DUPFD: u64 = 0;
+const F_GETFD: u64 = 1;
+const F_SETFD: u64 = 2;
+const F_GETFL: u64 = 3;
+const F_SETFL: u64 = 4;
+const F_GETLK: u64 = 5;
+const F_SETLK: u64 = 6;
+const F_SETLKW: u64 = 7;
+const F_DUPFD_CLOEXEC: u64 = 1030;
+
+/// Descriptor flag for F_GETFD/F_SETFD
+const FD_CLOEXEC: u64 = 1;
+
+/// sys_open: Open a file
+///
+/// Arguments:
+/// - path: pointer to a NUL-terminated path
+/// - flags: O_* flags (access mode, O_CREAT, O_EXCL, O_TRUNC, O_APPEND,
+///   O_NONBLOCK, O_CLOEXEC)
+/// - mode: permission bits for a file created by O_CREAT
+///
+/// Returns: the new file descriptor, or error
+fn sys_open(path: u64, flags: u64, mode: u64) -> SyscallResult {
+    let path = read_user_path(path)?;
+    let flags = OpenFlags::from_bits(flags)?;
+    let descriptor = mount::open(&path, flags, mode as u16 & MODE_PERMISSION_MASK)?;
+    Ok(process::current_resources().fd_table.allocate(descriptor)? as u64)
+}
+
+/// sys_lseek: Move the offset of an open file
+///
+/// Arguments:
+/// - fd: file descriptor
+/// - offset: signed offset
+/// - whence: SEEK_SET, SEEK_CUR or SEEK_END
+///
+/// Returns: the new offset, or error (ESPIPE for pipes and sockets)
+fn sys_lseek(fd: u64, offset: u64, whence: u64) -> SyscallResult {
+    let descriptor = process::current_resources()
+        .fd_table
+        .get(fd as usize)
+        .map_err(|_| SyscallError::InvalidFileDescriptor)?;
+    if matches!(descriptor.inode().file_type(), FileType::Fifo | FileType::Socket) {
+        return Err(SyscallError::Errno(29)); // ESPIPE
+    }
+
+    let position = SeekFrom::from_whence(offset as i64, whence)?;
+    Ok(descriptor.seek(position)? as u64)
+}
+
+/// sys_dup: Duplicate a file descriptor onto the lowest free number
+///
+/// The new descriptor shares the offset and status flags; FD_CLOEXEC is
+/// cleared.
+///
+/// Returns: the new file descriptor, or error
+fn sys_dup(fd: u64) -> SyscallResult {
+    let resources = process::current_resources();
+    let table = &resources.fd_table;
+    table.get(fd as usize).map_err(|_| SyscallError::InvalidFileDescriptor)?;
+    // A full table is EMFILE
+    Ok(table.dup(fd as usize)? as u64)
+}
+
+/// sys_dup2: Duplicate a file descriptor onto a chosen number
+///
+/// Arguments:
+/// - old_fd: descriptor to duplicate
+/// - new_fd: number to use, closing whatever was open there
+///
+/// Returns: `new_fd`, or error
+fn sys_dup2(old_fd: u64, new_fd: u64) -> SyscallResult {
+    if new_fd >= MAX_FD {
+        return Err(SyscallError::InvalidFileDescriptor);
+    }
+    let resources = process::current_resources();
+    resources.fd_table.get(old_fd as usize).map_err(|_| SyscallError::InvalidFileDescriptor)?;
+    let replaced = resources.fd_table.get(new_fd as usize).ok();
+    let fd = resources.fd_table.dup2(old_fd as usize, new_fd as usize)?;
+
+    // The descriptor closed implicitly releases record locks like close
+    if let Some(replaced) = replaced.filter(|_| old_fd != new_fd) {
+        filelock::release_records(process::current_pid(), replaced.inode());
+    }
+    Ok(fd as u64)
+}
+
+/// sys_fcntl: Manipulate a file descriptor
+///
+/// Arguments:
+/// - fd: file descriptor
+/// - cmd: F_DUPFD, F_DUPFD_CLOEXEC, F_GETFD, F_SETFD, F_GETFL, F_SETFL,
+///   F_GETLK, F_SETLK or F_SETLKW
+/// - arg: command argument (a `struct flock` pointer for the lock commands)
+///
+/// Returns: depends on the command, or error
+fn sys_fcntl(fd: u64, cmd: u64, arg: u64) -> SyscallResult {
+    let resources = process::current_resources();
+    let table = &resources.fd_table;
+    let descriptor = table.get(fd as usize).map_err(|_| SyscallError::InvalidFileDescriptor)?;
+
+    match cmd {
+        F_DUPFD | F_DUPFD_CLOEXEC => {
+            if arg >= MAX_FD {
+                return Err(SyscallError::InvalidArgument);
+            }
+            Ok(table.dup_from(fd as usize, arg as usize, cmd == F_DUPFD_CLOEXEC)? as u64)
+        }
+        F_GETFD => Ok(if table.cloexec(fd as usize)? { FD_CLOEXEC } else { 0 }),
+        F_SETFD => {
+            table.set_cloexec(fd as usize, arg & FD_CLOEXEC != 0)?;
+            Ok(0)
+        }
+        F_GETFL => Ok(descriptor.flags().status_bits()),
+        F_SETFL => {
+            descriptor.set_status_flags(arg);
+            Ok(0)
+        }
+        F_GETLK | F_SETLK | F_SETLKW => record_lock(&descriptor, cmd, arg),
+        _ => Err(SyscallError::InvalidArgument),
+    }
+}
+
+/// F_GETLK, F_SETLK and F_SETLKW on an open file
+///
+/// F_SETLKW blocks on a conflicting lock, failing with EDEADLK if the
+/// wait would never end; F_SETLK fails with EAGAIN instead.
+fn record_lock(descriptor: &FileDescriptor, cmd: u64, arg: u64) -> SyscallResult {
+    let mut request: Flock = read_user(arg)?;
+    let base = match request.l_whence as u64 {
+        vfs::SEEK_SET => 0,
+        vfs::SEEK_CUR => descriptor.tell() as u64,
+        vfs::SEEK_END => descriptor.inode().size() as u64,
+        _ => return Err(SyscallError::InvalidArgument),
+    };
+    let (start, end) = request.range(base)?;
+    let kind = request.kind()?;
+    let pid = process::current_pid();
+
+    if cmd == F_GETLK {
+        let probe = RecordLock { pid, kind: kind.ok_or(SyscallError::InvalidArgument)?, start, end };
+        request = match filelock::get_record_lock(descriptor.inode(), &probe) {
+            Some(conflict) => Flock::from_lock(&conflict),
+            None => Flock { l_type: filelock::F_UNLCK, ..request },
+        };
+        write_user(arg, request)?;
+        return Ok(0);
+    }
+
+    // A read lock needs read access, a write lock write access
+    let flags = descriptor.flags();
+    match kind {
+        Some(LockKind::Shared) if !flags.read => return Err(SyscallError::InvalidFileDescriptor),
+        Some(LockKind::Exclusive) if !flags.write => return Err(SyscallError::InvalidFileDescriptor),
+        _ => {}
+    }
+
+    filelock::set_record_lock(descriptor.inode(), pid, kind, start, end, cmd == F_SETLKW)?;
+    Ok(0)
+}
+
+/// sys_flock: Apply or remove a whole-file advisory lock
+///
+/// Arguments:
+/// - fd: file descriptor
+/// - operation: LOCK_SH, LOCK_EX or LOCK_UN, optionally with LOCK_NB
+///
+/// The lock belongs to the open file and is shared by dup'd descriptors.
+///
+/// Returns: 0 on success, EAGAIN if LOCK_NB and the lock is held, or error
+fn sys_flock(fd: u64, operation: u64) -> SyscallResult {
+    let descriptor = process::current_resources()
+        .fd_table
+        .get(fd as usize)
+        .map_err(|_| SyscallError::InvalidFileDescriptor)?;
+
+    let kind = match operation & !filelock::LOCK_NB {
+        filelock::LOCK_SH => Some(LockKind::Shared),
+        filelock::LOCK_EX => Some(LockKind::Exclusive),
+        filelock::LOCK_UN => None,
+        _ => return Err(SyscallError::InvalidArgument),
+    };
+
+    filelock::flock(descriptor.id(), descriptor.inode(), kind, operation & filelock::LOCK_NB == 0)?;
+    Ok(0)
+}
+
+/// sys_inotify_init: Create an inotify instance
+///
+/// Arguments:
+/// - flags: IN_NONBLOCK and/or IN_CLOEXEC
+///
+/// Reading the descriptor returns whole `inotify_event` records.
+///
+/// Returns: the new inotify descriptor, or error
+fn sys_inotify_init(flags: u64) -> SyscallResult {
+    if flags & !(inotify::IN_NONBLOCK | inotify::IN_CLOEXEC) != 0 {
+        return Err(SyscallError::InvalidArgument);
+    }
+
+    let open_flags = OpenFlags::from_bits(vfs::O_RDONLY | flags)?;
+    let descriptor = Arc::new(FileDescriptor::new(Inotify::new(), open_flags));
+    Ok(process::current_resources().fd_table.allocate(descriptor)? as u64)
+}
+
+/// Get the inotify instance behind a descriptor
+fn inotify_instance(fd: u64) -> Result<Arc<Inotify>, SyscallError> {
+    let descriptor = process::current_resources()
+        .fd_table
+        .get(fd as usize)
+        .map_err(|_| SyscallError::InvalidFileDescriptor)?;
+    vfs::downcast_inode::<Inotify>(descriptor.inode()).ok_or(SyscallError::InvalidArgument)
+}
+
+/// sys_inotify_add_watch: Watch a file or directory for changes
+///
+/// Arguments:
+/// - fd: inotify descriptor
+/// - path: pointer to a NUL-terminated absolute path
+/// - mask: IN_* events, optionally with IN_DONT_FOLLOW and IN_MASK_ADD
+///
+/// Returns: the watch descriptor, or error
+fn sys_inotify_add_watch(fd: u64, path: u64, mask: u64) -> SyscallResult {
+    let instance = inotify_instance(fd)?;
+    let mask = u32::try_from(mask).map_err(|_| SyscallError::InvalidArgument)?;
+    let path = read_user_path(path)?;
+    let inode = if mask & inotify::IN_DONT_FOLLOW != 0 {
+        mount::resolve_path_no_follow(&path)?
+    } else {
+        mount::resolve_path(&path)?
+    };
+
+    Ok(instance.add_watch(&inode, mask)? as u64)
+}
+
+/// sys_inotify_rm_watch: Remove a watch from an inotify instance
+///
+/// Arguments:
+/// - fd: inotify descriptor
+/// - wd: watch descriptor returned by inotify_add_watch
+///
+/// Returns: 0 on success, or error
+fn sys_inotify_rm_watch(fd: u64, wd: u64) -> SyscallResult {
+    inotify_instance(fd)?.remove_watch(wd as i32)?;
+    Ok(0)
+}
+
+/// Size of the fixed part of a `linux_dirent64` record
+/// (d_ino, d_off, d_reclen, d_type)
+const DIRENT64_HEADER: usize = 19;
+
+/// sys_getdents64: Read directory entries
+///
+/// Arguments:
+/// - fd: file descriptor of an open directory
+/// - dirp: buffer receiving `linux_dirent64` records
+/// - count: size of the buffer
+///
+/// Each record holds the inode number, the cursor of the next entry
+/// (d_off), its own length, a DT_* type and the NUL-terminated name,
+/// padded to 8 bytes. The file offset advances past the returned entries.
+///
+/// Returns: number of bytes filled, 0 at the end, or EINVAL if the buffer
+/// cannot hold the next entry
+fn sys_getdents64(fd: u64, dirp: u64, count: u64) -> SyscallResult {
+    if dirp == 0 {
+        return Err(SyscallError::InvalidBuffer);
+    }
+
+    let descriptor = process::current_resources()
+        .fd_table
+        .get(fd as usize)
+        .map_err(|_| SyscallError::InvalidFileDescriptor)?;
+    if descriptor.inode().file_type() != FileType::Directory {
+        return Err(SyscallError::Errno(20)); // ENOTDIR
+    }
+
+    // Safety: We're trusting the user pointer for now
+    // TODO: Add proper user space memory validation
+    let out = unsafe { core::slice::from_raw_parts_mut(dirp as *mut u8, count as usize) };
+    let mut filled = 0;
+
+    descriptor.read_dir_entries(|entry, next| {
+        let reclen = (DIRENT64_HEADER + entry.name.len() + 1).next_multiple_of(8);
+        if filled + reclen > out.len() {
+            return false;
+        }
+
+        let record = &mut out[filled..filled + reclen];
+        record.fill(0);
+        record[0..8].copy_from_slice(&entry.ino.to_ne_bytes());
+        record[8..16].copy_from_slice(&next.to_ne_bytes());
+        record[16..18].copy_from_slice(&(reclen as u16).to_ne_bytes());
+        record[18] = entry.file_type.dirent_type();
+        record[DIRENT64_HEADER..DIRENT64_HEADER + entry.name.len()].copy_from_slice(entry.name.as_bytes());
+        filled += reclen;
+        true
+    })?;
+
+    // Entries remain but not even the first one fit
+    if filled == 0 && descriptor.inode().readdir(descriptor.tell() as u64)?.is_some() {
+        return Err(SyscallError::InvalidArgument);
+    }
+
+    Ok(filled as u64)
+}
+
+/// Convert a millisecond timeout into an absolute deadline
+///
+/// Negative timeouts mean no deadline.
+fn timeout_deadline_ms(timeout_ms: i64) -> Option<u64> {
+    if timeout_ms < 0 {
+        return None;
+    }
+    let ns = (timeout_ms as u64).saturating_mul(1_000_000);
+    Some(time::monotonic_ns().saturating_add(ns))
+}
+
+/// Longest path or string accepted from user space, including the terminating NUL
+const PATH_MAX: usize = 4096;
+
+/// Copy a NUL-terminated string in from user space
+fn read_user_str(ptr: u64) -> Result<String, SyscallError> {
+    if ptr == 0 {
+        return Err(SyscallError::InvalidBuffer);
+    }
+
+    let mut bytes = Vec::new();
+    loop {
+        if bytes.len() >= PATH_MAX {
+            return Err(SyscallError::Errno(36));
+        }
+        // Safety: We're trusting the user pointer for now
+        // TODO: Add proper user space memory validation
+        let byte = unsafe { core::ptr::read((ptr as usize + bytes.len()) as *const u8) };
+        if byte == 0 {
+            break;
+        }
+        bytes.push(byte);
+    }
+
+    let string = String::from_utf8(bytes).map_err(|_| SyscallError::InvalidArgument)?;
+    if string.is_empty() {
+        return Err(SyscallError::Errno(2));
+    }
+    Ok(string)
+}
+
+/// Copy a NUL-terminated path in from user space
+///
+/// Relative paths are resolved later from the process's working directory.
+fn read_user_path(ptr: u64) -> Result<String, SyscallError> {
+    read_user_str(ptr)
+}
+
+/// Copy a packed value in from user space (no alignment requirement)
+fn read_user_unaligned<T: Copy>(ptr: u64) -> Result<T, SyscallError> {
+    if ptr == 0 {
+        return Err(SyscallError::InvalidBuffer);
+    }
+
+    // Safety: We're trusting the user pointer for now
+    // TODO: Add proper user space memory validation
+    Ok(unsafe { core::ptr::read_unaligned(ptr as *const T) })
+}
+
+/// Copy a value in from user space
+fn read_user<T: Copy>(ptr: u64) -> Result<T, SyscallError> {
+    if ptr == 0 || !(ptr as usize).is_multiple_of(core::mem::align_of::<T>()) {
+        return Err(SyscallError::InvalidBuffer);
+    }
+
+    // Safety: We're trusting the user pointer for now
+    // TODO: Add proper user space memory validation
+    Ok(unsafe { core::ptr::read(ptr as *const T) })
+}
+
+/// Copy a value out to user space
+fn write_user<T: Copy>(ptr: u64, value: T) -> Result<(), SyscallError> {
+    if ptr == 0 || !(ptr as usize).is_multiple_of(core::mem::align_of::<T>()) {
+        return Err(SyscallError::InvalidBuffer);
+    }
+
+    // Safety: We're trusting the user pointer for now
+    // TODO: Add proper user space memory validation
+    unsafe { core::ptr::write(ptr as *mut T, value) };
+    Ok(())
+}
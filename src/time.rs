@@ -0,0 +1,356 @@
+//! Kernel clocks and timers
+//!
+//! CLOCK_MONOTONIC is derived from the PIT tick count, refined between
+//! ticks with the CPU timestamp counter. CLOCK_REALTIME adds the wall-clock
+//! time read from the CMOS RTC at boot. Sleeping processes and interval
+//! timers (alarm/setitimer) wait on a deadline-ordered timer queue that is
+//! checked on every timer interrupt.
+//!
+//! The clock state lives in a page-aligned `VdsoData` block that user
+//! address spaces map read-only, so user code can read the time without a
+//! syscall using the same seqlock protocol as `monotonic_ns`.
+
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::pit::TIMER_FREQUENCY_HZ;
+use crate::process::{self, PROCESS_MANAGER};
+use crate::wait::{wait_event, WaitResult};
+
+pub const NSEC_PER_SEC: u64 = 1_000_000_000;
+pub const NSEC_PER_USEC: u64 = 1_000;
+const NSEC_PER_TICK: u64 = NSEC_PER_SEC / TIMER_FREQUENCY_HZ as u64;
+
+/// Clock IDs for clock_gettime
+pub const CLOCK_REALTIME: u64 = 0;
+pub const CLOCK_MONOTONIC: u64 = 1;
+
+/// Interval timer IDs for setitimer/getitimer
+pub const ITIMER_REAL: u64 = 0;
+
+/// Time in seconds and nanoseconds (struct timespec)
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Timespec {
+    pub tv_sec: i64,
+    pub tv_nsec: i64,
+}
+
+impl Timespec {
+    pub fn from_ns(ns: u64) -> Self {
+        Self {
+            tv_sec: (ns / NSEC_PER_SEC) as i64,
+            tv_nsec: (ns % NSEC_PER_SEC) as i64,
+        }
+    }
+
+    /// Convert to nanoseconds, rejecting negative or out-of-range fields
+    pub fn to_ns(self) -> Option<u64> {
+        if self.tv_sec < 0 || !(0..NSEC_PER_SEC as i64).contains(&self.tv_nsec) {
+            return None;
+        }
+        (self.tv_sec as u64)
+            .checked_mul(NSEC_PER_SEC)?
+            .checked_add(self.tv_nsec as u64)
+    }
+}
+
+/// Time in seconds and microseconds (struct timeval)
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Timeval {
+    pub tv_sec: i64,
+    pub tv_usec: i64,
+}
+
+impl Timeval {
+    pub fn from_ns(ns: u64) -> Self {
+        Self {
+            tv_sec: (ns / NSEC_PER_SEC) as i64,
+            tv_usec: ((ns % NSEC_PER_SEC) / NSEC_PER_USEC) as i64,
+        }
+    }
+
+    /// Convert to nanoseconds, rejecting negative or out-of-range fields
+    pub fn to_ns(self) -> Option<u64> {
+        if self.tv_sec < 0 || !(0..1_000_000).contains(&self.tv_usec) {
+            return None;
+        }
+        (self.tv_sec as u64)
+            .checked_mul(NSEC_PER_SEC)?
+            .checked_add(self.tv_usec as u64 * NSEC_PER_USEC)
+    }
+}
+
+/// Interval timer value (struct itimerval)
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Itimerval {
+    pub it_interval: Timeval,
+    pub it_value: Timeval,
+}
+
+/// Clock data shared with user space (the vDSO data page)
+///
+/// Writers bump `seq` to an odd value, update the fields, then bump it
+/// back to even. Readers retry while `seq` is odd or changed under them.
+#[repr(C, align(4096))]
+pub struct VdsoData {
+    pub seq: AtomicU32,
+    /// PIT ticks since boot
+    pub ticks: AtomicU64,
+    /// TSC value at the last tick
+    pub tsc_at_tick: AtomicU64,
+    /// Measured TSC cycles per tick (0 until calibrated)
+    pub tsc_per_tick: AtomicU64,
+    /// Wall-clock seconds since the epoch at boot
+    pub boot_epoch_sec: AtomicU64,
+}
+
+/// The clock data page
+pub static VDSO_DATA: VdsoData = VdsoData {
+    seq: AtomicU32::new(0),
+    ticks: AtomicU64::new(0),
+    tsc_at_tick: AtomicU64::new(0),
+    tsc_per_tick: AtomicU64::new(0),
+    boot_epoch_sec: AtomicU64::new(0),
+};
+
+/// Physical/kernel address of the clock data page
+pub fn vdso_data_address() -> u64 {
+    &VDSO_DATA as *const VdsoData as u64
+}
+
+/// Initialize the clocks from the RTC (must be called after the PIT is set up)
+pub fn init() {
+    let now = crate::rtc::read_datetime();
+    let epoch = now.to_unix();
+
+    VDSO_DATA.boot_epoch_sec.store(epoch.saturating_sub(monotonic_ns() / NSEC_PER_SEC), Ordering::Relaxed);
+
+    crate::serial_println!(
+        "RTC: {:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC (epoch {})",
+        now.year, now.month, now.day, now.hour, now.minute, now.second, epoch
+    );
+}
+
+/// Nanoseconds since boot (CLOCK_MONOTONIC)
+pub fn monotonic_ns() -> u64 {
+    let data = &VDSO_DATA;
+
+    let (ticks, tsc_at_tick, tsc_per_tick) = loop {
+        let seq = data.seq.load(Ordering::Acquire);
+        if seq & 1 != 0 {
+            core::hint::spin_loop();
+            continue;
+        }
+
+        let ticks = data.ticks.load(Ordering::Relaxed);
+        let tsc_at_tick = data.tsc_at_tick.load(Ordering::Relaxed);
+        let tsc_per_tick = data.tsc_per_tick.load(Ordering::Relaxed);
+
+        if data.seq.load(Ordering::Acquire) == seq {
+            break (ticks, tsc_at_tick, tsc_per_tick);
+        }
+    };
+
+    let mut ns = ticks * NSEC_PER_TICK;
+
+    // Interpolate within the current tick, never reaching the next one
+    if tsc_per_tick != 0 {
+        let elapsed = crate::strace::read_tsc().saturating_sub(tsc_at_tick) as u128;
+        let sub_tick = elapsed * NSEC_PER_TICK as u128 / tsc_per_tick as u128;
+        ns += (sub_tick as u64).min(NSEC_PER_TICK - 1);
+    }
+
+    ns
+}
+
+/// Nanoseconds since the Unix epoch (CLOCK_REALTIME)
+pub fn realtime_ns() -> u64 {
+    VDSO_DATA.boot_epoch_sec.load(Ordering::Relaxed) * NSEC_PER_SEC + monotonic_ns()
+}
+
+/// Read a clock by ID
+pub fn clock_ns(clock_id: u64) -> Option<u64> {
+    match clock_id {
+        CLOCK_REALTIME => Some(realtime_ns()),
+        CLOCK_MONOTONIC => Some(monotonic_ns()),
+        _ => None,
+    }
+}
+
+/// Kinds of timer queue entries
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum TimerKind {
+    /// Wake a process blocked with a timeout (nanosleep, poll)
+    Wakeup,
+    /// Deliver SIGALRM (ITIMER_REAL / alarm)
+    Alarm,
+}
+
+/// State of a process's ITIMER_REAL
+#[derive(Debug, Clone, Copy)]
+struct RealTimer {
+    deadline: u64,
+    interval: u64,
+}
+
+/// Deadline-ordered queue of pending timers
+struct TimerQueue {
+    /// (deadline in monotonic ns, pid, kind)
+    pending: BTreeSet<(u64, u32, TimerKind)>,
+    /// Armed ITIMER_REAL timers by pid
+    real_timers: BTreeMap<u32, RealTimer>,
+}
+
+impl TimerQueue {
+    const fn new() -> Self {
+        Self {
+            pending: BTreeSet::new(),
+            real_timers: BTreeMap::new(),
+        }
+    }
+
+    /// Pop every entry whose deadline is at or before `now`
+    fn pop_expired(&mut self, now: u64) -> Vec<(u32, TimerKind)> {
+        let mut expired = Vec::new();
+
+        while let Some(&(deadline, pid, kind)) = self.pending.first() {
+            if deadline > now {
+                break;
+            }
+            self.pending.pop_first();
+
+            if kind == TimerKind::Alarm {
+                // Re-arm periodic timers, skipping any periods already missed
+                match self.real_timers.get_mut(&pid) {
+                    Some(timer) if timer.interval != 0 => {
+                        timer.deadline += timer.interval;
+                        if timer.deadline <= now {
+                            timer.deadline = now + timer.interval;
+                        }
+                        self.pending.insert((timer.deadline, pid, TimerKind::Alarm));
+                    }
+                    _ => {
+                        self.real_timers.remove(&pid);
+                    }
+                }
+            }
+
+            expired.push((pid, kind));
+        }
+
+        expired
+    }
+
+    /// Replace a process's ITIMER_REAL, returning the previous setting as
+    /// (time remaining, interval)
+    fn set_real_timer(&mut self, pid: u32, now: u64, value: u64, interval: u64) -> (u64, u64) {
+        let old = match self.real_timers.remove(&pid) {
+            Some(timer) => {
+                self.pending.remove(&(timer.deadline, pid, TimerKind::Alarm));
+                (timer.deadline.saturating_sub(now), timer.interval)
+            }
+            None => (0, 0),
+        };
+
+        if value != 0 {
+            let deadline = now + value;
+            self.real_timers.insert(pid, RealTimer { deadline, interval });
+            self.pending.insert((deadline, pid, TimerKind::Alarm));
+        }
+
+        old
+    }
+
+    fn get_real_timer(&self, pid: u32, now: u64) -> (u64, u64) {
+        match self.real_timers.get(&pid) {
+            Some(timer) => (timer.deadline.saturating_sub(now).max(1), timer.interval),
+            None => (0, 0),
+        }
+    }
+}
+
+lazy_static! {
+    static ref TIMER_QUEUE: Mutex<TimerQueue> = Mutex::new(TimerQueue::new());
+}
+
+/// Called from the timer interrupt after the tick count is incremented
+pub fn on_tick() {
+    let data = &VDSO_DATA;
+    let tsc = crate::strace::read_tsc();
+
+    data.seq.fetch_add(1, Ordering::AcqRel);
+
+    let previous = data.tsc_at_tick.load(Ordering::Relaxed);
+    if previous != 0 {
+        let measured = tsc.saturating_sub(previous);
+        let old = data.tsc_per_tick.load(Ordering::Relaxed);
+        // Smooth out jitter from interrupt latency
+        let smoothed = if old == 0 { measured } else { (old * 7 + measured) / 8 };
+        data.tsc_per_tick.store(smoothed, Ordering::Relaxed);
+    }
+    data.tsc_at_tick.store(tsc, Ordering::Relaxed);
+    data.ticks.store(crate::pit::get_ticks(), Ordering::Relaxed);
+
+    data.seq.fetch_add(1, Ordering::AcqRel);
+
+    let expired = TIMER_QUEUE.lock().pop_expired(monotonic_ns());
+    for (pid, kind) in expired {
+        match kind {
+            TimerKind::Wakeup => PROCESS_MANAGER.lock().wake(pid),
+            TimerKind::Alarm => process::send_signal(pid, process::SIGALRM),
+        }
+    }
+}
+
+/// Why a sleep ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SleepResult {
+    /// The full duration elapsed
+    Completed,
+    /// A signal woke the process early; the remaining time is included
+    Interrupted(u64),
+}
+
+/// Schedule a wake-up of `pid` at `deadline` (monotonic ns)
+pub fn add_wakeup(deadline: u64, pid: u32) {
+    without_interrupts(|| TIMER_QUEUE.lock().pending.insert((deadline, pid, TimerKind::Wakeup)));
+}
+
+/// Cancel a wake-up scheduled with `add_wakeup`, if it has not fired
+pub fn cancel_wakeup(deadline: u64, pid: u32) {
+    without_interrupts(|| TIMER_QUEUE.lock().pending.remove(&(deadline, pid, TimerKind::Wakeup)));
+}
+
+/// Block the current process for `duration` nanoseconds
+pub fn sleep_ns(duration: u64) -> SleepResult {
+    let deadline = monotonic_ns().saturating_add(duration);
+
+    match wait_event(&[], Some(deadline), || false) {
+        WaitResult::Interrupted => {
+            SleepResult::Interrupted(deadline.saturating_sub(monotonic_ns()).max(1))
+        }
+        WaitResult::Ready | WaitResult::TimedOut => SleepResult::Completed,
+    }
+}
+
+/// Arm or disarm the current process's ITIMER_REAL
+///
+/// A `value` of 0 disarms the timer. Returns the previous
+/// (time remaining, interval) in nanoseconds.
+pub fn set_real_timer(value: u64, interval: u64) -> (u64, u64) {
+    let pid = process::current_pid();
+    without_interrupts(|| TIMER_QUEUE.lock().set_real_timer(pid, monotonic_ns(), value, interval))
+}
+
+/// Get the current process's ITIMER_REAL as (time remaining, interval)
+pub fn get_real_timer() -> (u64, u64) {
+    let pid = process::current_pid();
+    without_interrupts(|| TIMER_QUEUE.lock().get_real_timer(pid, monotonic_ns()))
+}
@@ -8,10 +8,19 @@ use alloc::sync::Arc;
 use alloc::collections::BTreeMap;
 use spin::Mutex;
 
-use crate::vfs::{Inode, FileType, VfsError};
+use crate::dcache;
+use crate::inotify::{
+    self, IN_ATTRIB, IN_CREATE, IN_DELETE, IN_DELETE_SELF, IN_ISDIR, IN_MODIFY, IN_MOVED_FROM, IN_MOVED_TO,
+};
+use crate::time::Timespec;
+use crate::vfs::{
+    current_time, downcast_inode, next_ino, DirEntry, Filesystem, FileType, Inode, Metadata, SetAttr, VfsError,
+    MODE_PERMISSION_MASK, STAT_BLOCK_SIZE,
+};
 
 /// TmpFS inode - can be a file or directory
 pub struct TmpFsInode {
+    ino: u64,
     inner: Mutex<TmpFsInodeInner>,
 }
 
@@ -19,39 +28,116 @@ struct TmpFsInodeInner {
     file_type: FileType,
     data: Vec<u8>,
     children: BTreeMap<String, Arc<TmpFsInode>>,
+    /// Entry names by readdir cookie; cookies only ever increase, so a
+    /// readdir cursor stays valid while entries come and go
+    cookies: BTreeMap<u64, String>,
+    next_cookie: u64,
+    /// Hard links to a non-directory; directory link counts are derived
+    nlink: u32,
+    mode: u16,
+    uid: u32,
+    gid: u32,
+    atime: Timespec,
+    mtime: Timespec,
+    ctime: Timespec,
+}
+
+impl TmpFsInodeInner {
+    /// Record a change to the contents
+    fn touch_modified(&mut self) {
+        let now = current_time();
+        self.mtime = now;
+        self.ctime = now;
+    }
+
+    /// Add or replace a directory entry, giving it a fresh cookie
+    fn insert_child(&mut self, name: &str, child: Arc<TmpFsInode>) {
+        self.remove_child(name);
+        self.children.insert(name.to_string(), child);
+        self.cookies.insert(self.next_cookie, name.to_string());
+        self.next_cookie += 1;
+    }
+
+    /// Remove a directory entry and its cookie
+    fn remove_child(&mut self, name: &str) -> Option<Arc<TmpFsInode>> {
+        let child = self.children.remove(name)?;
+        self.cookies.retain(|_, entry| entry != name);
+        Some(child)
+    }
+
+    /// Fail unless this inode holds file contents
+    fn check_regular(&self) -> Result<(), VfsError> {
+        match self.file_type {
+            FileType::Regular => Ok(()),
+            FileType::Directory => Err(VfsError::IsADirectory),
+            _ => Err(VfsError::InvalidOperation),
+        }
+    }
 }
 
 impl TmpFsInode {
-    /// Create a new file inode
-    pub fn new_file() -> Arc<Self> {
+    fn new(file_type: FileType) -> Arc<Self> {
+        let now = current_time();
         Arc::new(Self {
+            ino: next_ino(),
             inner: Mutex::new(TmpFsInodeInner {
-                file_type: FileType::Regular,
+                file_type,
                 data: Vec::new(),
                 children: BTreeMap::new(),
+                cookies: BTreeMap::new(),
+                next_cookie: 0,
+                nlink: 1,
+                mode: file_type.default_mode(),
+                uid: 0,
+                gid: 0,
+                atime: now,
+                mtime: now,
+                ctime: now,
             }),
         })
     }
 
+    /// Create a new file inode
+    pub fn new_file() -> Arc<Self> {
+        Self::new(FileType::Regular)
+    }
+
     /// Create a new directory inode
     pub fn new_directory() -> Arc<Self> {
-        Arc::new(Self {
-            inner: Mutex::new(TmpFsInodeInner {
-                file_type: FileType::Directory,
-                data: Vec::new(),
-                children: BTreeMap::new(),
-            }),
-        })
+        Self::new(FileType::Directory)
+    }
+
+    /// Check whether `other` is this directory or lies somewhere below it
+    fn contains(&self, other: &TmpFsInode) -> bool {
+        if core::ptr::eq(self, other) {
+            return true;
+        }
+        let subdirs: Vec<Arc<TmpFsInode>> = self
+            .inner
+            .lock()
+            .children
+            .values()
+            .filter(|child| child.file_type() == FileType::Directory)
+            .cloned()
+            .collect();
+        subdirs.iter().any(|child| child.contains(other))
+    }
+
+    /// Create a new symbolic link inode; the target is stored as its data
+    pub fn new_symlink(target: &str) -> Arc<Self> {
+        let inode = Self::new(FileType::Symlink);
+        inode.inner.lock().data = target.as_bytes().to_vec();
+        inode
     }
 }
 
+
 impl Inode for TmpFsInode {
     fn read(&self, offset: usize, buffer: &mut [u8]) -> Result<usize, VfsError> {
-        let inner = self.inner.lock();
+        let mut inner = self.inner.lock();
+        inner.check_regular()?;
 
-        if inner.file_type != FileType::Regular {
-            return Err(VfsError::IsADirectory);
-        }
+        inner.atime = current_time();
 
         if offset >= inner.data.len() {
             return Ok(0);
@@ -66,10 +152,7 @@ impl Inode for TmpFsInode {
 
     fn write(&self, offset: usize, buffer: &[u8]) -> Result<usize, VfsError> {
         let mut inner = self.inner.lock();
-
-        if inner.file_type != FileType::Regular {
-            return Err(VfsError::IsADirectory);
-        }
+        inner.check_regular()?;
 
         // Extend the data vector if necessary
         let required_size = offset + buffer.len();
@@ -79,9 +162,26 @@ impl Inode for TmpFsInode {
 
         // Write the data
         inner.data[offset..offset + buffer.len()].copy_from_slice(buffer);
+        inner.touch_modified();
+        drop(inner);
+
+        inotify::notify(self, IN_MODIFY, None, 0);
         Ok(buffer.len())
     }
 
+    fn append(&self, buffer: &[u8]) -> Result<usize, VfsError> {
+        let mut inner = self.inner.lock();
+        inner.check_regular()?;
+
+        inner.data.extend_from_slice(buffer);
+        inner.touch_modified();
+        let end = inner.data.len();
+        drop(inner);
+
+        inotify::notify(self, IN_MODIFY, None, 0);
+        Ok(end)
+    }
+
     fn file_type(&self) -> FileType {
         self.inner.lock().file_type
     }
@@ -122,7 +222,12 @@ impl Inode for TmpFsInode {
             _ => return Err(VfsError::NotImplemented),
         };
 
-        inner.children.insert(name.to_string(), new_inode.clone());
+        inner.insert_child(name, new_inode.clone());
+        inner.touch_modified();
+        drop(inner);
+
+        dcache::entry_added(self, name);
+        inotify::notify(self, IN_CREATE | inotify::isdir_bit(file_type), Some(name), 0);
         Ok(new_inode)
     }
 
@@ -136,14 +241,35 @@ impl Inode for TmpFsInode {
         Ok(inner.children.keys().cloned().collect())
     }
 
-    fn truncate(&self, size: usize) -> Result<(), VfsError> {
-        let mut inner = self.inner.lock();
+    /// Entries come in creation order, with the cookie as the cursor
+    fn readdir(&self, cursor: u64) -> Result<Option<(DirEntry, u64)>, VfsError> {
+        let inner = self.inner.lock();
 
-        if inner.file_type != FileType::Regular {
-            return Err(VfsError::IsADirectory);
+        if inner.file_type != FileType::Directory {
+            return Err(VfsError::NotADirectory);
         }
 
+        let Some((&cookie, name)) = inner.cookies.range(cursor..).next() else {
+            return Ok(None);
+        };
+        let child = &inner.children[name];
+        let entry = DirEntry {
+            name: name.clone(),
+            ino: child.ino,
+            file_type: child.file_type(),
+        };
+        Ok(Some((entry, cookie + 1)))
+    }
+
+    fn truncate(&self, size: usize) -> Result<(), VfsError> {
+        let mut inner = self.inner.lock();
+        inner.check_regular()?;
+
         inner.data.resize(size, 0);
+        inner.touch_modified();
+        drop(inner);
+
+        inotify::notify(self, IN_MODIFY, None, 0);
         Ok(())
     }
 
@@ -158,15 +284,235 @@ impl Inode for TmpFsInode {
             return Err(VfsError::NotFound);
         }
 
-        // Check if it's a directory and not empty
-        if let Some(child) = inner.children.get(name) {
-            let child_inner = child.inner.lock();
-            if child_inner.file_type == FileType::Directory && !child_inner.children.is_empty() {
-                return Err(VfsError::DirectoryNotEmpty);
+        // Check if it's a directory and not empty; otherwise drop one link
+        let (isdir, gone) = if let Some(child) = inner.children.get(name) {
+            let mut child_inner = child.inner.lock();
+            if child_inner.file_type == FileType::Directory {
+                if !child_inner.children.is_empty() {
+                    return Err(VfsError::DirectoryNotEmpty);
+                }
+                (IN_ISDIR, true)
+            } else {
+                child_inner.nlink -= 1;
+                child_inner.ctime = current_time();
+                (0, child_inner.nlink == 0)
+            }
+        } else {
+            (0, false)
+        };
+
+        let child = inner.remove_child(name);
+        inner.touch_modified();
+        drop(inner);
+
+        dcache::entry_removed(self, name);
+        inotify::notify(self, IN_DELETE | isdir, Some(name), 0);
+        // With its last name gone the inode's own watches end
+        if let Some(child) = child.filter(|_| gone) {
+            inotify::notify(&*child, IN_DELETE_SELF, None, 0);
+            inotify::forget(&*child);
+        }
+        Ok(())
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> Result<Arc<dyn Inode>, VfsError> {
+        let mut inner = self.inner.lock();
+
+        if inner.file_type != FileType::Directory {
+            return Err(VfsError::NotADirectory);
+        }
+
+        if inner.children.contains_key(name) {
+            return Err(VfsError::AlreadyExists);
+        }
+
+        let new_inode = TmpFsInode::new_symlink(target);
+        inner.insert_child(name, new_inode.clone());
+        inner.touch_modified();
+        drop(inner);
+
+        dcache::entry_added(self, name);
+        inotify::notify(self, IN_CREATE, Some(name), 0);
+        Ok(new_inode)
+    }
+
+    fn readlink(&self) -> Result<String, VfsError> {
+        let mut inner = self.inner.lock();
+
+        if inner.file_type != FileType::Symlink {
+            return Err(VfsError::InvalidArgument);
+        }
+
+        inner.atime = current_time();
+        String::from_utf8(inner.data.clone()).map_err(|_| VfsError::IoError)
+    }
+
+    fn link(&self, name: &str, inode: &Arc<dyn Inode>) -> Result<(), VfsError> {
+        let target = downcast_inode::<TmpFsInode>(inode).ok_or(VfsError::CrossDevice)?;
+        let mut inner = self.inner.lock();
+
+        if inner.file_type != FileType::Directory {
+            return Err(VfsError::NotADirectory);
+        }
+
+        if inner.children.contains_key(name) {
+            return Err(VfsError::AlreadyExists);
+        }
+
+        {
+            let mut target_inner = target.inner.lock();
+            // Directory hard links would make the tree a graph
+            if target_inner.file_type == FileType::Directory {
+                return Err(VfsError::PermissionDenied);
             }
+            target_inner.nlink += 1;
+            target_inner.ctime = current_time();
         }
 
-        inner.children.remove(name);
+        inner.insert_child(name, target);
+        inner.touch_modified();
+        drop(inner);
+
+        dcache::entry_added(self, name);
+        inotify::notify(self, IN_CREATE, Some(name), 0);
+        Ok(())
+    }
+
+    fn rename(&self, old_name: &str, new_dir: &Arc<dyn Inode>, new_name: &str) -> Result<(), VfsError> {
+        let new_dir = downcast_inode::<TmpFsInode>(new_dir).ok_or(VfsError::CrossDevice)?;
+        let same_dir = core::ptr::eq(self, Arc::as_ptr(&new_dir));
+
+        let source = self.inner.lock().children.get(old_name).cloned().ok_or(VfsError::NotFound)?;
+        if same_dir && old_name == new_name {
+            return Ok(());
+        }
+
+        // A directory cannot be moved below itself
+        if source.file_type() == FileType::Directory && source.contains(&new_dir) {
+            return Err(VfsError::InvalidArgument);
+        }
+
+        // Lock both directories in address order so concurrent renames in
+        // opposite directions cannot deadlock
+        let (mut old_inner, mut new_inner) = if same_dir {
+            (self.inner.lock(), None)
+        } else if (self as *const Self) < Arc::as_ptr(&new_dir) {
+            let old_inner = self.inner.lock();
+            (old_inner, Some(new_dir.inner.lock()))
+        } else {
+            let new_inner = new_dir.inner.lock();
+            (self.inner.lock(), Some(new_inner))
+        };
+
+        if new_inner.as_ref().map_or(old_inner.file_type, |inner| inner.file_type) != FileType::Directory {
+            return Err(VfsError::NotADirectory);
+        }
+
+        // The entry may have changed while no lock was held
+        match old_inner.children.get(old_name) {
+            Some(current) if Arc::ptr_eq(current, &source) => {}
+            _ => return Err(VfsError::NotFound),
+        }
+
+        let target_dir = new_inner.as_deref_mut().unwrap_or(&mut *old_inner);
+        if let Some(target) = target_dir.children.get(new_name) {
+            // Both names already refer to the same inode
+            if Arc::ptr_eq(target, &source) {
+                return Ok(());
+            }
+
+            let mut target_inner = target.inner.lock();
+            match (source.file_type(), target_inner.file_type) {
+                (FileType::Directory, FileType::Directory) => {
+                    if !target_inner.children.is_empty() {
+                        return Err(VfsError::DirectoryNotEmpty);
+                    }
+                }
+                (FileType::Directory, _) => return Err(VfsError::NotADirectory),
+                (_, FileType::Directory) => return Err(VfsError::IsADirectory),
+                _ => {
+                    target_inner.nlink -= 1;
+                    target_inner.ctime = current_time();
+                }
+            }
+        }
+
+        target_dir.insert_child(new_name, source.clone());
+        target_dir.touch_modified();
+        old_inner.remove_child(old_name);
+        old_inner.touch_modified();
+        drop(old_inner);
+        drop(new_inner);
+
+        let isdir = {
+            let mut source_inner = source.inner.lock();
+            source_inner.ctime = current_time();
+            inotify::isdir_bit(source_inner.file_type)
+        };
+        // The dentry of the old name is moved by `dcache::rename`; only a
+        // replaced target or a negative entry for the new name goes here
+        dcache::entry_removed(&*new_dir, new_name);
+        let cookie = inotify::next_cookie();
+        inotify::notify(self, IN_MOVED_FROM | isdir, Some(old_name), cookie);
+        inotify::notify(&*new_dir, IN_MOVED_TO | isdir, Some(new_name), cookie);
+        Ok(())
+    }
+
+    fn metadata(&self) -> Metadata {
+        let inner = self.inner.lock();
+
+        // A directory is linked from its parent, its own `.` and each
+        // subdirectory's `..`
+        let nlink = match inner.file_type {
+            FileType::Directory => {
+                let subdirs = inner
+                    .children
+                    .values()
+                    .filter(|child| child.file_type() == FileType::Directory)
+                    .count();
+                2 + subdirs as u32
+            }
+            _ => inner.nlink,
+        };
+
+        let size = inner.data.len() as u64;
+        Metadata {
+            ino: self.ino,
+            file_type: inner.file_type,
+            mode: inner.mode,
+            uid: inner.uid,
+            gid: inner.gid,
+            nlink,
+            size,
+            blocks: size.div_ceil(STAT_BLOCK_SIZE),
+            atime: inner.atime,
+            mtime: inner.mtime,
+            ctime: inner.ctime,
+        }
+    }
+
+    fn setattr(&self, attr: &SetAttr) -> Result<(), VfsError> {
+        let mut inner = self.inner.lock();
+
+        if let Some(mode) = attr.mode {
+            inner.mode = mode & MODE_PERMISSION_MASK;
+        }
+        if let Some(uid) = attr.uid {
+            inner.uid = uid;
+        }
+        if let Some(gid) = attr.gid {
+            inner.gid = gid;
+        }
+        if let Some(atime) = attr.atime {
+            inner.atime = atime;
+        }
+        if let Some(mtime) = attr.mtime {
+            inner.mtime = mtime;
+        }
+        inner.ctime = current_time();
+        drop(inner);
+
+        inotify::notify(self, IN_ATTRIB, None, 0);
         Ok(())
     }
 }
@@ -264,6 +610,7 @@ impl TmpFs {
     }
 
     /// Remove a file or directory at the given path
+    #[allow(dead_code)]
     pub fn remove(&self, path: &str) -> Result<(), VfsError> {
         let path = path.trim_start_matches('/');
 
@@ -296,9 +643,19 @@ impl Default for TmpFs {
     }
 }
 
+impl Filesystem for TmpFs {
+    fn name(&self) -> &'static str {
+        "tmpfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
 use lazy_static::lazy_static;
 
 lazy_static! {
-    /// Global TmpFS instance
-    pub static ref TMPFS: Mutex<TmpFs> = Mutex::new(TmpFs::new());
+    /// Global TmpFS instance, mounted at `/` during boot
+    pub static ref TMPFS: Arc<TmpFs> = Arc::new(TmpFs::new());
 }
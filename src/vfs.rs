@@ -5,9 +5,15 @@
 use alloc::string::String;
 use alloc::vec::Vec;
 use alloc::sync::Arc;
+use core::any::Any;
 use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
 use spin::Mutex;
 
+use crate::block::BlockDevice;
+use crate::time::Timespec;
+use crate::wait::WaitQueue;
+
 /// File types supported by the VFS
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[allow(dead_code)]
@@ -15,57 +21,368 @@ pub enum FileType {
     Regular,
     Directory,
     Device,
+    /// A disk or other block device (`/dev/hda`)
+    BlockDevice,
     Symlink,
+    Fifo,
+    Socket,
 }
 
+/// open(2) flag bits
+pub const O_ACCMODE: u64 = 0o3;
+pub const O_RDONLY: u64 = 0o0;
+pub const O_WRONLY: u64 = 0o1;
+pub const O_RDWR: u64 = 0o2;
+pub const O_CREAT: u64 = 0o100;
+pub const O_EXCL: u64 = 0o200;
+pub const O_TRUNC: u64 = 0o1000;
+pub const O_APPEND: u64 = 0o2000;
+pub const O_NONBLOCK: u64 = 0o4000;
+pub const O_CLOEXEC: u64 = 0o2000000;
+
 /// File open flags
 #[derive(Debug, Clone, Copy)]
 #[allow(dead_code)]
 pub struct OpenFlags {
     pub read: bool,
     pub write: bool,
+    /// Every write goes to the end of the file
     pub append: bool,
     pub create: bool,
     pub truncate: bool,
+    /// With `create`, fail if the file already exists
+    pub exclusive: bool,
+    /// Reads and writes fail with `WouldBlock` instead of waiting
+    pub nonblock: bool,
+    /// Close the descriptor on exec (a descriptor flag, not kept in the
+    /// open file)
+    pub cloexec: bool,
 }
 
 impl OpenFlags {
-    #[allow(dead_code)]
-    pub const fn read_only() -> Self {
+    const fn access(read: bool, write: bool) -> Self {
         Self {
-            read: true,
-            write: false,
+            read,
+            write,
             append: false,
             create: false,
             truncate: false,
+            exclusive: false,
+            nonblock: false,
+            cloexec: false,
         }
     }
 
+    #[allow(dead_code)]
+    pub const fn read_only() -> Self {
+        Self::access(true, false)
+    }
+
     #[allow(dead_code)]
     pub const fn write_only() -> Self {
+        Self::access(false, true)
+    }
+
+    pub const fn read_write() -> Self {
+        Self::access(true, true)
+    }
+
+    /// Decode the flags argument of open(2)
+    pub fn from_bits(bits: u64) -> Result<Self, VfsError> {
+        let mut flags = match bits & O_ACCMODE {
+            O_RDONLY => Self::read_only(),
+            O_WRONLY => Self::write_only(),
+            O_RDWR => Self::read_write(),
+            _ => return Err(VfsError::InvalidArgument),
+        };
+        flags.create = bits & O_CREAT != 0;
+        flags.exclusive = bits & O_EXCL != 0;
+        flags.truncate = bits & O_TRUNC != 0;
+        flags.set_status_bits(bits);
+        flags.cloexec = bits & O_CLOEXEC != 0;
+        Ok(flags)
+    }
+
+    /// Access mode and status flags as reported by F_GETFL
+    pub fn status_bits(&self) -> u64 {
+        let mut bits = match (self.read, self.write) {
+            (true, true) => O_RDWR,
+            (false, true) => O_WRONLY,
+            _ => O_RDONLY,
+        };
+        if self.append {
+            bits |= O_APPEND;
+        }
+        if self.nonblock {
+            bits |= O_NONBLOCK;
+        }
+        bits
+    }
+
+    /// Apply the status flags that F_SETFL may change
+    pub fn set_status_bits(&mut self, bits: u64) {
+        self.append = bits & O_APPEND != 0;
+        self.nonblock = bits & O_NONBLOCK != 0;
+    }
+}
+
+/// Where a seek is relative to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+/// lseek(2) `whence` values
+pub const SEEK_SET: u64 = 0;
+pub const SEEK_CUR: u64 = 1;
+pub const SEEK_END: u64 = 2;
+
+impl SeekFrom {
+    /// Decode the offset and whence arguments of lseek(2)
+    pub fn from_whence(offset: i64, whence: u64) -> Result<Self, VfsError> {
+        match whence {
+            SEEK_SET if offset >= 0 => Ok(SeekFrom::Start(offset as u64)),
+            SEEK_CUR => Ok(SeekFrom::Current(offset)),
+            SEEK_END => Ok(SeekFrom::End(offset)),
+            _ => Err(VfsError::InvalidArgument),
+        }
+    }
+}
+
+/// File type bits of `Stat::st_mode`
+pub const S_IFSOCK: u32 = 0o140000;
+pub const S_IFLNK: u32 = 0o120000;
+pub const S_IFREG: u32 = 0o100000;
+pub const S_IFDIR: u32 = 0o040000;
+pub const S_IFBLK: u32 = 0o060000;
+pub const S_IFCHR: u32 = 0o020000;
+pub const S_IFIFO: u32 = 0o010000;
+
+/// File type values of `d_type` in getdents64 records
+pub const DT_FIFO: u8 = 1;
+pub const DT_CHR: u8 = 2;
+pub const DT_DIR: u8 = 4;
+pub const DT_BLK: u8 = 6;
+pub const DT_REG: u8 = 8;
+pub const DT_LNK: u8 = 10;
+pub const DT_SOCK: u8 = 12;
+
+/// Permission bits that can be changed with chmod (including setuid/setgid/sticky)
+pub const MODE_PERMISSION_MASK: u16 = 0o7777;
+
+/// Size of the blocks counted in `Metadata::blocks`
+pub const STAT_BLOCK_SIZE: u64 = 512;
+
+impl FileType {
+    /// The `S_IF*` bits for this type
+    pub fn mode_bits(self) -> u32 {
+        match self {
+            FileType::Regular => S_IFREG,
+            FileType::Directory => S_IFDIR,
+            FileType::Device => S_IFCHR,
+            FileType::BlockDevice => S_IFBLK,
+            FileType::Symlink => S_IFLNK,
+            FileType::Fifo => S_IFIFO,
+            FileType::Socket => S_IFSOCK,
+        }
+    }
+
+    /// Character used for this type in `ls -l` output
+    pub fn type_char(self) -> char {
+        match self {
+            FileType::Regular => '-',
+            FileType::Directory => 'd',
+            FileType::Device => 'c',
+            FileType::BlockDevice => 'b',
+            FileType::Symlink => 'l',
+            FileType::Fifo => 'p',
+            FileType::Socket => 's',
+        }
+    }
+
+    /// The `DT_*` value for this type in getdents64 records
+    pub fn dirent_type(self) -> u8 {
+        match self {
+            FileType::Fifo => DT_FIFO,
+            FileType::Device => DT_CHR,
+            FileType::BlockDevice => DT_BLK,
+            FileType::Directory => DT_DIR,
+            FileType::Regular => DT_REG,
+            FileType::Symlink => DT_LNK,
+            FileType::Socket => DT_SOCK,
+        }
+    }
+
+    /// Default permissions for a newly created inode of this type
+    pub fn default_mode(self) -> u16 {
+        match self {
+            FileType::Directory => 0o755,
+            FileType::Device => 0o666,
+            FileType::BlockDevice => 0o660,
+            FileType::Symlink => 0o777,
+            FileType::Regular | FileType::Fifo | FileType::Socket => 0o644,
+        }
+    }
+}
+
+/// A directory entry returned by `Inode::readdir`
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub name: String,
+    pub ino: u64,
+    pub file_type: FileType,
+}
+
+/// Inode attributes, as returned by stat
+#[derive(Debug, Clone, Copy)]
+pub struct Metadata {
+    /// Inode number, unique within the filesystem
+    pub ino: u64,
+    pub file_type: FileType,
+    /// Permission bits (`MODE_PERMISSION_MASK`)
+    pub mode: u16,
+    pub uid: u32,
+    pub gid: u32,
+    /// Number of hard links (directories count `.` and each subdirectory's `..`)
+    pub nlink: u32,
+    pub size: u64,
+    /// Number of `STAT_BLOCK_SIZE` blocks allocated
+    pub blocks: u64,
+    /// Last access
+    pub atime: Timespec,
+    /// Last content modification
+    pub mtime: Timespec,
+    /// Last attribute or content change
+    pub ctime: Timespec,
+}
+
+impl Metadata {
+    /// Metadata for an inode with no stored attributes
+    ///
+    /// Used by synthetic filesystems: owned by root, default permissions
+    /// for the type, all timestamps at `time`.
+    pub fn synthesized(ino: u64, file_type: FileType, size: u64, time: Timespec) -> Self {
         Self {
-            read: false,
-            write: true,
-            append: false,
-            create: false,
-            truncate: false,
+            ino,
+            file_type,
+            mode: file_type.default_mode(),
+            uid: 0,
+            gid: 0,
+            nlink: if file_type == FileType::Directory { 2 } else { 1 },
+            size,
+            blocks: 0,
+            atime: time,
+            mtime: time,
+            ctime: time,
         }
     }
 
-    pub const fn read_write() -> Self {
+    /// Render the type and permissions as in `ls -l` (e.g. `drwxr-xr-x`)
+    pub fn mode_string(&self) -> String {
+        let mut out = String::with_capacity(10);
+        out.push(self.file_type.type_char());
+
+        const BITS: [(u16, char); 9] = [
+            (0o400, 'r'), (0o200, 'w'), (0o100, 'x'),
+            (0o040, 'r'), (0o020, 'w'), (0o010, 'x'),
+            (0o004, 'r'), (0o002, 'w'), (0o001, 'x'),
+        ];
+        for (bit, c) in BITS {
+            out.push(if self.mode & bit != 0 { c } else { '-' });
+        }
+        out
+    }
+}
+
+/// Attribute changes applied by `Inode::setattr`; `None` leaves a field as is
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SetAttr {
+    pub mode: Option<u16>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub atime: Option<Timespec>,
+    pub mtime: Option<Timespec>,
+}
+
+/// File status as laid out in user memory (struct stat on x86_64)
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Stat {
+    pub st_dev: u64,
+    pub st_ino: u64,
+    pub st_nlink: u64,
+    pub st_mode: u32,
+    pub st_uid: u32,
+    pub st_gid: u32,
+    pub pad0: u32,
+    pub st_rdev: u64,
+    pub st_size: i64,
+    pub st_blksize: i64,
+    pub st_blocks: i64,
+    pub st_atime: Timespec,
+    pub st_mtime: Timespec,
+    pub st_ctime: Timespec,
+    pub reserved: [i64; 3],
+}
+
+impl From<&Metadata> for Stat {
+    fn from(meta: &Metadata) -> Self {
         Self {
-            read: true,
-            write: true,
-            append: false,
-            create: false,
-            truncate: false,
+            st_ino: meta.ino,
+            st_nlink: meta.nlink as u64,
+            st_mode: meta.file_type.mode_bits() | meta.mode as u32,
+            st_uid: meta.uid,
+            st_gid: meta.gid,
+            st_size: meta.size as i64,
+            st_blksize: 4096,
+            st_blocks: meta.blocks as i64,
+            st_atime: meta.atime,
+            st_mtime: meta.mtime,
+            st_ctime: meta.ctime,
+            ..Self::default()
+        }
+    }
+}
+
+/// Allocate a fresh inode number
+///
+/// Shared by all filesystems that do not store inode numbers on disk, so
+/// numbers stay unique across mounts.
+pub fn next_ino() -> u64 {
+    static NEXT_INO: AtomicU64 = AtomicU64::new(2);
+    NEXT_INO.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Current wall-clock time, for inode timestamps
+pub fn current_time() -> Timespec {
+    Timespec::from_ns(crate::time::realtime_ns())
+}
+
+/// I/O readiness of an inode, as reported to poll/select/epoll
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Readiness {
+    pub readable: bool,
+    pub writable: bool,
+    /// The other end is gone (closed pipe writer, disconnected peer)
+    pub hangup: bool,
+}
+
+impl Readiness {
+    /// Readable and writable without blocking (regular files, directories)
+    pub const fn always() -> Self {
+        Self {
+            readable: true,
+            writable: true,
+            hangup: false,
         }
     }
 }
 
 /// Inode - represents a file or directory in the filesystem
 #[allow(dead_code)]
-pub trait Inode: Send + Sync {
+pub trait Inode: Any + Send + Sync {
     /// Read data from the inode at the given offset
     fn read(&self, offset: usize, buffer: &mut [u8]) -> Result<usize, VfsError>;
 
@@ -92,6 +409,206 @@ pub trait Inode: Send + Sync {
 
     /// Remove a child entry by name (for directories)
     fn remove(&self, name: &str) -> Result<(), VfsError>;
+
+    /// Read the directory entry at `cursor` (for directories)
+    ///
+    /// Cursor 0 is the first entry. Returns the entry and the cursor of the
+    /// one after it, or `None` at the end. `.` and `..` are not reported.
+    /// Cursors stay valid while entries are added and removed: no entry is
+    /// skipped or returned twice, though entries added meanwhile may not
+    /// show up.
+    ///
+    /// The default goes through `list` and `lookup`, using the entry's
+    /// position as the cursor; filesystems that can do better override it.
+    fn readdir(&self, cursor: u64) -> Result<Option<(DirEntry, u64)>, VfsError> {
+        let names = self.list()?;
+        for (index, name) in names.iter().enumerate().skip(cursor as usize) {
+            // Skip entries removed since the listing
+            let Ok(inode) = self.lookup(name) else {
+                continue;
+            };
+            let entry = DirEntry {
+                name: name.clone(),
+                ino: inode.metadata().ino,
+                file_type: inode.file_type(),
+            };
+            return Ok(Some((entry, index as u64 + 1)));
+        }
+        Ok(None)
+    }
+
+    /// Create a symbolic link `name` pointing at `target` (for directories)
+    fn symlink(&self, _name: &str, _target: &str) -> Result<Arc<dyn Inode>, VfsError> {
+        Err(VfsError::NotImplemented)
+    }
+
+    /// Read the target of a symbolic link
+    fn readlink(&self) -> Result<String, VfsError> {
+        Err(VfsError::InvalidArgument)
+    }
+
+    /// Add a hard link `name` to an existing inode (for directories)
+    ///
+    /// Filesystems refuse inodes that belong to another filesystem with
+    /// `CrossDevice`.
+    fn link(&self, _name: &str, _inode: &Arc<dyn Inode>) -> Result<(), VfsError> {
+        Err(VfsError::NotImplemented)
+    }
+
+    /// Move the entry `old_name` of this directory to `new_name` in `new_dir`
+    ///
+    /// An existing target is replaced if it is compatible (a file by a
+    /// file, an empty directory by a directory). Filesystems refuse a
+    /// `new_dir` from another filesystem with `CrossDevice`.
+    fn rename(&self, _old_name: &str, _new_dir: &Arc<dyn Inode>, _new_name: &str) -> Result<(), VfsError> {
+        Err(VfsError::NotImplemented)
+    }
+
+    /// Whether lookups in this directory may be kept in the dentry cache
+    ///
+    /// Directories whose entries change behind the VFS's back (such as
+    /// the per-process entries of procfs) opt out.
+    fn cache_lookups(&self) -> bool {
+        true
+    }
+
+    /// Write `buffer` at the current end of the file
+    ///
+    /// Returns the offset just past the written data. Filesystems should
+    /// override this so that finding the end and writing happen under one
+    /// lock; the default can interleave with a concurrent append.
+    fn append(&self, buffer: &[u8]) -> Result<usize, VfsError> {
+        let offset = self.size();
+        let written = self.write(offset, buffer)?;
+        Ok(offset + written)
+    }
+
+    /// Read without waiting, for descriptors opened with O_NONBLOCK
+    ///
+    /// Fails with `WouldBlock` if `poll` reports that a read would wait.
+    fn read_nonblocking(&self, offset: usize, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        let readiness = self.poll();
+        if !readiness.readable && !readiness.hangup {
+            return Err(VfsError::WouldBlock);
+        }
+        self.read(offset, buffer)
+    }
+
+    /// Write without waiting, for descriptors opened with O_NONBLOCK
+    ///
+    /// Fails with `WouldBlock` if `poll` reports that a write would wait.
+    /// Inodes that can accept part of a write (pipes) override this to
+    /// write what fits.
+    fn write_nonblocking(&self, offset: usize, buffer: &[u8]) -> Result<usize, VfsError> {
+        let readiness = self.poll();
+        if !readiness.writable && !readiness.hangup {
+            return Err(VfsError::WouldBlock);
+        }
+        self.write(offset, buffer)
+    }
+
+    /// Fill `buffer` with page `index` of the file for the page cache
+    ///
+    /// Bytes past the end of the file are left zeroed. Filesystems whose
+    /// `read`/`write` go through the page cache must override this (and
+    /// `write_page`) with direct I/O.
+    fn read_page(&self, index: u64, buffer: &mut [u8]) -> Result<(), VfsError> {
+        self.read(index as usize * buffer.len(), buffer)?;
+        Ok(())
+    }
+
+    /// Write a dirty cached page back, up to the end of the file
+    fn write_page(&self, index: u64, buffer: &[u8]) -> Result<(), VfsError> {
+        let offset = index as usize * buffer.len();
+        let count = self.size().saturating_sub(offset).min(buffer.len());
+        if count > 0 {
+            self.write(offset, &buffer[..count])?;
+        }
+        Ok(())
+    }
+
+    /// Get the inode's attributes
+    fn metadata(&self) -> Metadata;
+
+    /// Change attributes (chmod, chown, utimes)
+    ///
+    /// Inodes without stored attributes refuse.
+    fn setattr(&self, _attr: &SetAttr) -> Result<(), VfsError> {
+        Err(VfsError::PermissionDenied)
+    }
+
+    /// Report whether a read or write would currently block
+    fn poll(&self) -> Readiness {
+        Readiness::always()
+    }
+
+    /// Wait queue woken whenever `poll` may return something new
+    ///
+    /// Inodes that are always ready have none.
+    fn wait_queue(&self) -> Option<&WaitQueue> {
+        None
+    }
+}
+
+/// A mountable filesystem
+pub trait Filesystem: Send + Sync {
+    /// Filesystem type name, as shown in the mount table
+    fn name(&self) -> &'static str;
+
+    /// Root directory of the filesystem
+    fn root(&self) -> Arc<dyn Inode>;
+
+    /// Write cached state back to the backing device, e.g. before unmount
+    fn sync(&self) -> Result<(), VfsError> {
+        Ok(())
+    }
+
+    /// Block device the filesystem reads through the buffer cache, if any
+    fn device(&self) -> Option<Arc<dyn BlockDevice>> {
+        None
+    }
+}
+
+/// Get the concrete type behind an inode, e.g. to recognize an epoll instance
+pub fn downcast_inode<T: Inode>(inode: &Arc<dyn Inode>) -> Option<Arc<T>> {
+    let any: Arc<dyn Any + Send + Sync> = inode.clone();
+    any.downcast::<T>().ok()
+}
+
+/// Iterator over the entries of a directory, driven by `Inode::readdir`
+pub struct ReadDir {
+    inode: Arc<dyn Inode>,
+    cursor: Option<u64>,
+}
+
+impl Iterator for ReadDir {
+    type Item = Result<DirEntry, VfsError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let cursor = self.cursor?;
+        match self.inode.readdir(cursor) {
+            Ok(Some((entry, next))) => {
+                self.cursor = Some(next);
+                Some(Ok(entry))
+            }
+            Ok(None) => {
+                self.cursor = None;
+                None
+            }
+            Err(e) => {
+                self.cursor = None;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Iterate over the entries of a directory
+pub fn read_dir(inode: &Arc<dyn Inode>) -> ReadDir {
+    ReadDir {
+        inode: inode.clone(),
+        cursor: Some(0),
+    }
 }
 
 /// Errors that can occur in the VFS layer
@@ -108,6 +625,26 @@ pub enum VfsError {
     IoError,
     NotImplemented,
     InvalidOperation,
+    /// Write to a pipe or socket with no reader left
+    BrokenPipe,
+    /// A blocking operation was interrupted by a signal
+    Interrupted,
+    /// The mount point or filesystem is in use
+    Busy,
+    /// Too many symbolic links followed while resolving a path
+    SymlinkLoop,
+    /// Link or rename between different filesystems
+    CrossDevice,
+    /// A non-blocking operation would have to wait
+    WouldBlock,
+    /// Waiting for a lock would deadlock
+    Deadlock,
+    /// No room left on the device
+    NoSpace,
+    /// The filesystem is mounted read-only
+    ReadOnly,
+    /// The process has `MAX_FD` descriptors open
+    TooManyOpenFiles,
 }
 
 impl fmt::Display for VfsError {
@@ -123,15 +660,28 @@ impl fmt::Display for VfsError {
             VfsError::IoError => write!(f, "I/O error"),
             VfsError::NotImplemented => write!(f, "Not implemented"),
             VfsError::InvalidOperation => write!(f, "Invalid operation"),
+            VfsError::BrokenPipe => write!(f, "Broken pipe"),
+            VfsError::Interrupted => write!(f, "Interrupted system call"),
+            VfsError::Busy => write!(f, "Device or resource busy"),
+            VfsError::SymlinkLoop => write!(f, "Too many levels of symbolic links"),
+            VfsError::CrossDevice => write!(f, "Invalid cross-device link"),
+            VfsError::WouldBlock => write!(f, "Resource temporarily unavailable"),
+            VfsError::Deadlock => write!(f, "Resource deadlock avoided"),
+            VfsError::NoSpace => write!(f, "No space left on device"),
+            VfsError::ReadOnly => write!(f, "Read-only file system"),
+            VfsError::TooManyOpenFiles => write!(f, "Too many open files"),
         }
     }
 }
 
 /// File descriptor - represents an open file
+///
+/// Descriptors made by dup, dup2 or fork share one `FileDescriptor`, and
+/// with it the offset and status flags.
 pub struct FileDescriptor {
     inode: Arc<dyn Inode>,
     offset: Mutex<usize>,
-    flags: OpenFlags,
+    flags: Mutex<OpenFlags>,
 }
 
 #[allow(dead_code)]
@@ -140,38 +690,98 @@ impl FileDescriptor {
         Self {
             inode,
             offset: Mutex::new(0),
-            flags,
+            flags: Mutex::new(flags),
         }
     }
 
     /// Read from the file at the current offset
     pub fn read(&self, buffer: &mut [u8]) -> Result<usize, VfsError> {
-        if !self.flags.read {
+        let flags = self.flags();
+        if !flags.read {
             return Err(VfsError::PermissionDenied);
         }
 
+        let mut read_at = |offset| {
+            if flags.nonblock {
+                self.inode.read_nonblocking(offset, buffer)
+            } else {
+                self.inode.read(offset, buffer)
+            }
+        };
+
+        if !self.has_position() {
+            let offset = self.tell();
+            let bytes_read = read_at(offset)?;
+            *self.offset.lock() += bytes_read;
+            return Ok(bytes_read);
+        }
+
         let mut offset = self.offset.lock();
-        let bytes_read = self.inode.read(*offset, buffer)?;
+        let bytes_read = read_at(*offset)?;
         *offset += bytes_read;
         Ok(bytes_read)
     }
 
     /// Write to the file at the current offset
+    ///
+    /// With O_APPEND the data goes to the end of the file, found and
+    /// written atomically by the inode, and the offset moves past it.
     pub fn write(&self, buffer: &[u8]) -> Result<usize, VfsError> {
-        if !self.flags.write {
+        let flags = self.flags();
+        if !flags.write {
             return Err(VfsError::PermissionDenied);
         }
 
+        let write_at = |offset| {
+            if flags.nonblock {
+                self.inode.write_nonblocking(offset, buffer)
+            } else {
+                self.inode.write(offset, buffer)
+            }
+        };
+
+        if !self.has_position() {
+            let offset = self.tell();
+            let bytes_written = write_at(offset)?;
+            *self.offset.lock() += bytes_written;
+            return Ok(bytes_written);
+        }
+
         let mut offset = self.offset.lock();
-        let bytes_written = self.inode.write(*offset, buffer)?;
+        if flags.append && self.inode.file_type() == FileType::Regular {
+            let end = self.inode.append(buffer)?;
+            *offset = end;
+            return Ok(buffer.len());
+        }
+
+        let bytes_written = write_at(*offset)?;
         *offset += bytes_written;
         Ok(bytes_written)
     }
 
-    /// Seek to a new position in the file
-    pub fn seek(&self, position: usize) -> Result<(), VfsError> {
-        *self.offset.lock() = position;
-        Ok(())
+    /// Whether reads and writes happen at the file offset
+    ///
+    /// Those are serialized by holding the offset lock. Pipes, sockets and
+    /// character devices ignore the offset and may block for as long as
+    /// they like, so the lock is not held while they do.
+    fn has_position(&self) -> bool {
+        !matches!(self.inode.file_type(), FileType::Fifo | FileType::Socket | FileType::Device)
+    }
+
+    /// Move the file offset, returning the new one
+    ///
+    /// Seeking past the end is allowed; a negative result is not.
+    pub fn seek(&self, position: SeekFrom) -> Result<usize, VfsError> {
+        let mut offset = self.offset.lock();
+        let (base, delta) = match position {
+            SeekFrom::Start(position) => (0, position as i64),
+            SeekFrom::Current(delta) => (*offset as i64, delta),
+            SeekFrom::End(delta) => (self.inode.size() as i64, delta),
+        };
+
+        let new_offset = base.checked_add(delta).filter(|&o| o >= 0).ok_or(VfsError::InvalidArgument)?;
+        *offset = new_offset as usize;
+        Ok(*offset)
     }
 
     /// Get the current file offset
@@ -183,11 +793,60 @@ impl FileDescriptor {
     pub fn inode(&self) -> &Arc<dyn Inode> {
         &self.inode
     }
+
+    /// Identity of the open file, shared by all descriptors referring to it
+    pub fn id(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    /// Get the flags the file was opened with, as changed by F_SETFL
+    pub fn flags(&self) -> OpenFlags {
+        *self.flags.lock()
+    }
+
+    /// Read directory entries starting at the file offset (getdents64)
+    ///
+    /// The offset is used as the readdir cursor. `emit` gets each entry and
+    /// the cursor after it; when it returns false (no room for the entry),
+    /// reading stops and that entry is returned again by the next call.
+    pub fn read_dir_entries(&self, mut emit: impl FnMut(&DirEntry, u64) -> bool) -> Result<(), VfsError> {
+        let mut offset = self.offset.lock();
+        while let Some((entry, next)) = self.inode.readdir(*offset as u64)? {
+            if !emit(&entry, next) {
+                break;
+            }
+            *offset = next as usize;
+        }
+        Ok(())
+    }
+
+    /// Change the status flags (F_SETFL); other bits are ignored
+    pub fn set_status_flags(&self, bits: u64) {
+        self.flags.lock().set_status_bits(bits);
+    }
 }
 
+impl Drop for FileDescriptor {
+    /// The last descriptor for the open file is gone: drop its flock lock
+    fn drop(&mut self) {
+        crate::filelock::release_file(self.id(), &self.inode);
+    }
+}
+
+/// An entry of a descriptor table
+#[derive(Clone)]
+struct FdSlot {
+    file: Arc<FileDescriptor>,
+    /// FD_CLOEXEC: close this descriptor on exec
+    cloexec: bool,
+}
+
+/// Number of descriptors a process can have open (numbers `0..MAX_FD`)
+pub const MAX_FD: usize = 1024;
+
 /// File descriptor table for a process
 pub struct FileDescriptorTable {
-    descriptors: Mutex<Vec<Option<Arc<FileDescriptor>>>>,
+    descriptors: Mutex<Vec<Option<FdSlot>>>,
 }
 
 #[allow(dead_code)]
@@ -201,19 +860,33 @@ impl FileDescriptorTable {
 
     /// Allocate a new file descriptor
     pub fn allocate(&self, fd: Arc<FileDescriptor>) -> Result<usize, VfsError> {
+        let cloexec = fd.flags().cloexec;
+        self.allocate_from(0, fd, cloexec)
+    }
+
+    /// Allocate the lowest free descriptor number at or above `min`
+    ///
+    /// Fails with `TooManyOpenFiles` if there is none below `MAX_FD`.
+    fn allocate_from(&self, min: usize, file: Arc<FileDescriptor>, cloexec: bool) -> Result<usize, VfsError> {
         let mut descriptors = self.descriptors.lock();
 
         // Try to find an empty slot
-        for (i, slot) in descriptors.iter_mut().enumerate() {
+        for (i, slot) in descriptors.iter_mut().enumerate().take(MAX_FD).skip(min) {
             if slot.is_none() {
-                *slot = Some(fd);
+                *slot = Some(FdSlot { file, cloexec });
                 return Ok(i);
             }
         }
 
         // No empty slot, append to the end
+        if descriptors.len() < min {
+            descriptors.resize(min, None);
+        }
         let fd_num = descriptors.len();
-        descriptors.push(Some(fd));
+        if fd_num >= MAX_FD {
+            return Err(VfsError::TooManyOpenFiles);
+        }
+        descriptors.push(Some(FdSlot { file, cloexec }));
         Ok(fd_num)
     }
 
@@ -222,7 +895,8 @@ impl FileDescriptorTable {
         let descriptors = self.descriptors.lock();
         descriptors
             .get(fd)
-            .and_then(|slot| slot.clone())
+            .and_then(|slot| slot.as_ref())
+            .map(|slot| slot.file.clone())
             .ok_or(VfsError::InvalidArgument)
     }
 
@@ -237,10 +911,81 @@ impl FileDescriptorTable {
         }
     }
 
-    /// Duplicate a file descriptor
+    /// Duplicate a file descriptor onto the lowest free number
+    ///
+    /// The copy shares the open file (offset and status flags) but not
+    /// FD_CLOEXEC, which starts cleared.
     pub fn dup(&self, old_fd: usize) -> Result<usize, VfsError> {
+        self.dup_from(old_fd, 0, false)
+    }
+
+    /// Duplicate onto the lowest free number at or above `min` (F_DUPFD)
+    pub fn dup_from(&self, old_fd: usize, min: usize, cloexec: bool) -> Result<usize, VfsError> {
         let fd = self.get(old_fd)?;
-        self.allocate(fd)
+        self.allocate_from(min, fd, cloexec)
+    }
+
+    /// Duplicate onto `new_fd`, closing whatever was open there
+    ///
+    /// Duplicating a descriptor onto itself does nothing.
+    pub fn dup2(&self, old_fd: usize, new_fd: usize) -> Result<usize, VfsError> {
+        let file = self.get(old_fd)?;
+        if old_fd == new_fd {
+            return Ok(new_fd);
+        }
+
+        let mut descriptors = self.descriptors.lock();
+        if descriptors.len() <= new_fd {
+            descriptors.resize(new_fd + 1, None);
+        }
+        descriptors[new_fd] = Some(FdSlot { file, cloexec: false });
+        Ok(new_fd)
+    }
+
+    /// Get the FD_CLOEXEC flag of a descriptor
+    pub fn cloexec(&self, fd: usize) -> Result<bool, VfsError> {
+        let descriptors = self.descriptors.lock();
+        descriptors
+            .get(fd)
+            .and_then(|slot| slot.as_ref())
+            .map(|slot| slot.cloexec)
+            .ok_or(VfsError::InvalidArgument)
+    }
+
+    /// Set or clear the FD_CLOEXEC flag of a descriptor
+    pub fn set_cloexec(&self, fd: usize, cloexec: bool) -> Result<(), VfsError> {
+        let mut descriptors = self.descriptors.lock();
+        let slot = descriptors
+            .get_mut(fd)
+            .and_then(|slot| slot.as_mut())
+            .ok_or(VfsError::InvalidArgument)?;
+        slot.cloexec = cloexec;
+        Ok(())
+    }
+
+    /// Close every descriptor marked FD_CLOEXEC (on exec)
+    pub fn close_on_exec(&self) {
+        for slot in self.descriptors.lock().iter_mut() {
+            if slot.as_ref().is_some_and(|s| s.cloexec) {
+                *slot = None;
+            }
+        }
+    }
+
+    /// Close every descriptor (on exit)
+    pub fn close_all(&self) {
+        // Dropping the files releases their locks; do it outside the table lock
+        let closed = core::mem::take(&mut *self.descriptors.lock());
+        drop(closed);
+    }
+
+    /// Copy the table for a forked child
+    ///
+    /// Both tables refer to the same open files, so offsets are shared.
+    pub fn fork(&self) -> Self {
+        Self {
+            descriptors: Mutex::new(self.descriptors.lock().clone()),
+        }
     }
 }
 
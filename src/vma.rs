@@ -0,0 +1,595 @@
+//! Virtual memory areas (VMAs) for user address spaces
+//!
+//! Tracks the heap (brk) and mmap regions of a process, their protection
+//! bits and what backs them (anonymous memory or a file inode).
+//!
+//! Pages are mapped on demand by the page fault handler: anonymous and
+//! heap pages get a zeroed frame, file-backed pages come from the page
+//! cache. A shared mapping maps the cached page itself, a private one gets
+//! its own copy on the first write. munmap and mprotect update the page
+//! tables of the pages already mapped.
+//!
+//! All processes still share the kernel's page tables, so only one address
+//! space should have pages mapped at a time.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use x86_64::structures::paging::PageTableFlags;
+
+use crate::pagecache::{self, CachedPage};
+use crate::paging;
+use crate::vfs::{FileType, Inode};
+
+/// Size of a page in bytes
+pub const PAGE_SIZE: u64 = 4096;
+
+/// Start of the user heap (the initial program break)
+pub const USER_HEAP_START: u64 = 0x0000_1000_0000;
+
+/// Maximum size the heap may grow to
+pub const USER_HEAP_MAX: u64 = 0x0000_4000_0000;
+
+/// Lowest address handed out by mmap when no hint is given
+pub const USER_MMAP_BASE: u64 = 0x0000_2000_0000_0000;
+
+/// Address of the read-only clock data page (vDSO) in every address space
+pub const VDSO_BASE: u64 = 0x0000_7fff_ffff_0000;
+
+/// End of the user half of the address space
+pub const USER_SPACE_END: u64 = 0x0000_8000_0000_0000;
+
+/// Page protection bits (mmap/mprotect `prot` argument)
+pub const PROT_NONE: u32 = 0x0;
+pub const PROT_READ: u32 = 0x1;
+pub const PROT_WRITE: u32 = 0x2;
+pub const PROT_EXEC: u32 = 0x4;
+
+/// Mapping flags (mmap `flags` argument)
+pub const MAP_SHARED: u32 = 0x01;
+pub const MAP_PRIVATE: u32 = 0x02;
+pub const MAP_FIXED: u32 = 0x10;
+pub const MAP_ANONYMOUS: u32 = 0x20;
+
+/// Errors returned by address space operations
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmError {
+    /// Misaligned address, zero length or unknown flags
+    InvalidArgument,
+    /// No free range large enough, or the heap limit was reached
+    OutOfMemory,
+    /// The requested protection is not allowed by the backing file
+    PermissionDenied,
+    /// The file cannot be mapped (not a regular file)
+    NotMappable,
+    /// The access is outside any VMA or not allowed by its protection
+    BadAddress,
+    /// Reading the backing file failed
+    IoError,
+}
+
+impl From<paging::PagingError> for VmError {
+    fn from(error: paging::PagingError) -> Self {
+        match error {
+            paging::PagingError::OutOfFrames => VmError::OutOfMemory,
+            _ => VmError::BadAddress,
+        }
+    }
+}
+
+/// What backs the pages of a VMA
+#[derive(Clone)]
+#[allow(dead_code)]
+pub enum VmaBacking {
+    /// The process heap, grown and shrunk with brk
+    Heap,
+    /// Zero-filled anonymous memory
+    Anonymous,
+    /// A file mapped at the given byte offset; `writable` records whether
+    /// the file was opened for writing
+    File { inode: Arc<dyn Inode>, offset: u64, writable: bool },
+    /// The kernel's clock data page (`time::VDSO_DATA`) at the given address
+    Vdso { kernel_addr: u64 },
+}
+
+/// A contiguous, page-aligned region of a user address space
+#[derive(Clone)]
+pub struct Vma {
+    pub start: u64,
+    pub end: u64,
+    pub prot: u32,
+    pub shared: bool,
+    pub backing: VmaBacking,
+}
+
+impl Vma {
+    /// Page table flags corresponding to this region's protection
+    ///
+    /// Private writable mappings are mapped read-only so the first write
+    /// faults and can be resolved by copying the page.
+    pub fn page_flags(&self) -> PageTableFlags {
+        let mut flags = PageTableFlags::USER_ACCESSIBLE;
+
+        if self.prot != PROT_NONE {
+            flags |= PageTableFlags::PRESENT;
+        }
+        if self.prot & PROT_WRITE != 0 && (self.shared || !self.is_file()) {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if self.prot & PROT_EXEC == 0 {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+
+        flags
+    }
+
+    fn is_file(&self) -> bool {
+        matches!(self.backing, VmaBacking::File { .. })
+    }
+
+    /// Split this VMA at `addr`, returning the upper half
+    fn split_off(&mut self, addr: u64) -> Vma {
+        let mut upper = self.clone();
+        upper.start = addr;
+        if let VmaBacking::File { offset, .. } = &mut upper.backing {
+            *offset += addr - self.start;
+        }
+        self.end = addr;
+        upper
+    }
+}
+
+/// A page mapped into the page tables on behalf of an address space
+enum MappedPage {
+    /// A zeroed frame owned by the address space, freed on unmap
+    Anonymous(u64),
+    /// A page cache page, or a private copy of one
+    File(Arc<CachedPage>),
+    /// A kernel page (the vDSO clock page), never freed
+    Kernel,
+}
+
+/// A user address space: the list of VMAs owned by one process
+pub struct AddressSpace {
+    /// VMAs keyed by start address
+    vmas: BTreeMap<u64, Vma>,
+    /// Current program break
+    brk: u64,
+    /// Pages faulted in, keyed by user page address
+    pages: BTreeMap<u64, MappedPage>,
+}
+
+impl AddressSpace {
+    /// Create an address space with the break at `USER_HEAP_START`
+    ///
+    /// The only initial mapping is the read-only vDSO clock page.
+    pub fn new() -> Self {
+        let mut vmas = BTreeMap::new();
+        vmas.insert(VDSO_BASE, Vma {
+            start: VDSO_BASE,
+            end: VDSO_BASE + PAGE_SIZE,
+            prot: PROT_READ,
+            shared: true,
+            backing: VmaBacking::Vdso { kernel_addr: crate::time::vdso_data_address() },
+        });
+
+        Self {
+            vmas,
+            brk: USER_HEAP_START,
+            pages: BTreeMap::new(),
+        }
+    }
+
+    /// Iterate over all VMAs in address order
+    #[allow(dead_code)]
+    pub fn vmas(&self) -> impl Iterator<Item = &Vma> {
+        self.vmas.values()
+    }
+
+    /// Find the VMA containing `addr`
+    pub fn find(&self, addr: u64) -> Option<&Vma> {
+        self.vmas
+            .range(..=addr)
+            .next_back()
+            .map(|(_, vma)| vma)
+            .filter(|vma| addr < vma.end)
+    }
+
+    /// Check whether `[start, end)` overlaps any existing VMA
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        if let Some((_, vma)) = self.vmas.range(..end).next_back() {
+            if vma.end > start {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Find the lowest free range of `len` bytes at or above `hint`
+    fn find_free_range(&self, hint: u64, len: u64) -> Option<u64> {
+        let mut candidate = hint.max(USER_MMAP_BASE);
+
+        for vma in self.vmas.range(..).map(|(_, v)| v) {
+            if vma.end <= candidate {
+                continue;
+            }
+            if vma.start >= candidate.checked_add(len)? {
+                break;
+            }
+            candidate = vma.end;
+        }
+
+        if candidate.checked_add(len)? <= USER_SPACE_END {
+            Some(candidate)
+        } else {
+            None
+        }
+    }
+
+    /// Split any VMA straddling `addr` so that a VMA boundary falls there
+    fn split_at(&mut self, addr: u64) {
+        let key = match self.vmas.range(..addr).next_back() {
+            Some((&key, vma)) if vma.end > addr => key,
+            _ => return,
+        };
+
+        if let Some(vma) = self.vmas.get_mut(&key) {
+            let upper = vma.split_off(addr);
+            self.vmas.insert(addr, upper);
+        }
+    }
+
+    /// Remove all mappings in `[start, end)`, splitting partially covered VMAs
+    fn unmap_range(&mut self, start: u64, end: u64) {
+        self.split_at(start);
+        self.split_at(end);
+
+        let doomed: Vec<u64> = self.vmas.range(start..end).map(|(&k, _)| k).collect();
+        for key in doomed {
+            self.vmas.remove(&key);
+        }
+
+        self.release_pages(start, end);
+    }
+
+    /// Unmap the pages faulted in within `[start, end)` and free their frames
+    fn release_pages(&mut self, start: u64, end: u64) {
+        let doomed: Vec<u64> = self.pages.range(start..end).map(|(&k, _)| k).collect();
+        for key in doomed {
+            // A PROT_NONE page has its present bit cleared; set it again so
+            // the entry can be removed
+            if paging::unmap_page(key).is_err() && paging::update_flags(key, PageTableFlags::PRESENT).is_ok() {
+                let _ = paging::unmap_page(key);
+            }
+            if let Some(MappedPage::Anonymous(phys)) = self.pages.remove(&key) {
+                paging::free_frame(phys);
+            }
+        }
+    }
+
+    /// Resolve a page fault at `addr`
+    ///
+    /// `access` is the PROT_* bit of the faulting access. A page that is not
+    /// mapped yet is mapped with its VMA's protection; file pages are first
+    /// mapped read-only so their first write can mark them dirty (shared
+    /// mappings) or copy them (private ones).
+    pub fn handle_fault(&mut self, addr: u64, access: u32) -> Result<(), VmError> {
+        let vma = self.find(addr).ok_or(VmError::BadAddress)?;
+        if vma.prot & access == 0 {
+            return Err(VmError::BadAddress);
+        }
+
+        let page_addr = addr & !(PAGE_SIZE - 1);
+        let mut flags = vma.page_flags();
+
+        match vma.backing {
+            VmaBacking::File { .. } => {
+                let write = access == PROT_WRITE;
+                if write {
+                    flags |= PageTableFlags::WRITABLE;
+                } else {
+                    flags.remove(PageTableFlags::WRITABLE);
+                }
+                let phys = self.fault_file_page(addr, write)?;
+
+                // A write to a page mapped read-only replaces its entry
+                let _ = paging::unmap_page(page_addr);
+                paging::map_page(page_addr, phys, flags)?;
+            }
+            _ if self.pages.contains_key(&page_addr) => {
+                // Already mapped with the VMA's protection: a real violation
+                return Err(VmError::BadAddress);
+            }
+            VmaBacking::Heap | VmaBacking::Anonymous => {
+                let phys = paging::allocate_frame().ok_or(VmError::OutOfMemory)?;
+                if let Err(e) = paging::map_page(page_addr, phys, flags) {
+                    paging::free_frame(phys);
+                    return Err(e.into());
+                }
+                self.pages.insert(page_addr, MappedPage::Anonymous(phys));
+            }
+            VmaBacking::Vdso { kernel_addr } => {
+                let phys = paging::translate(kernel_addr).ok_or(VmError::BadAddress)?;
+                paging::map_page(page_addr, phys, flags)?;
+                self.pages.insert(page_addr, MappedPage::Kernel);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Resolve a page fault at `addr` in a file-backed VMA
+    ///
+    /// Returns the physical address of the page to map. Shared mappings
+    /// map the page cache's page, and a write marks it dirty so it is
+    /// written back. A write to a private mapping replaces the page with a
+    /// private copy.
+    fn fault_file_page(&mut self, addr: u64, write: bool) -> Result<u64, VmError> {
+        let vma = self.find(addr).ok_or(VmError::BadAddress)?;
+        let VmaBacking::File { inode, offset, .. } = &vma.backing else {
+            return Err(VmError::BadAddress);
+        };
+
+        let page_addr = addr & !(PAGE_SIZE - 1);
+        let index = (offset + (page_addr - vma.start)) / PAGE_SIZE;
+        let shared = vma.shared;
+
+        let page = match self.pages.get(&page_addr) {
+            Some(MappedPage::File(page)) => page.clone(),
+            _ => pagecache::get_page(inode, index).map_err(|_| VmError::IoError)?,
+        };
+
+        let page = if !write {
+            page
+        } else if shared {
+            pagecache::mark_dirty(inode, index, &page);
+            page
+        } else if page.is_private() {
+            page
+        } else {
+            page.duplicate().map_err(|_| VmError::OutOfMemory)?
+        };
+
+        let phys = page.phys_addr().ok_or(VmError::OutOfMemory)?;
+        self.pages.insert(page_addr, MappedPage::File(page));
+        Ok(phys)
+    }
+
+    /// Get the current program break
+    pub fn current_brk(&self) -> u64 {
+        self.brk
+    }
+
+    /// Move the program break to `new_brk`
+    ///
+    /// Returns the resulting break. A request of 0, or one that would leave
+    /// the heap range or collide with another mapping, leaves the break
+    /// unchanged, matching Linux semantics.
+    pub fn brk(&mut self, new_brk: u64) -> u64 {
+        if !(USER_HEAP_START..=USER_HEAP_START + USER_HEAP_MAX).contains(&new_brk) {
+            return self.brk;
+        }
+
+        // Both are within the heap range, so rounding up cannot overflow
+        let (Some(old_end), Some(new_end)) = (align_up(self.brk), align_up(new_brk)) else {
+            return self.brk;
+        };
+
+        if new_end > old_end {
+            if self.overlaps(old_end, new_end) {
+                return self.brk;
+            }
+
+            // Extend the existing heap VMA or create it on first growth
+            match self.vmas.range_mut(..old_end).next_back() {
+                Some((_, vma)) if vma.end == old_end && matches!(vma.backing, VmaBacking::Heap) => {
+                    vma.end = new_end;
+                }
+                _ => {
+                    self.vmas.insert(old_end, Vma {
+                        start: old_end,
+                        end: new_end,
+                        prot: PROT_READ | PROT_WRITE,
+                        shared: false,
+                        backing: VmaBacking::Heap,
+                    });
+                }
+            }
+        } else if new_end < old_end {
+            self.unmap_range(new_end, old_end);
+        }
+
+        self.brk = new_brk;
+        self.brk
+    }
+
+    /// Adjust the program break by `increment` bytes, returning the old break
+    pub fn sbrk(&mut self, increment: i64) -> Result<u64, VmError> {
+        let old = self.brk;
+        let requested = old.checked_add_signed(increment).ok_or(VmError::InvalidArgument)?;
+
+        if self.brk(requested) != requested {
+            return Err(VmError::OutOfMemory);
+        }
+
+        Ok(old)
+    }
+
+    /// Create a new mapping and return its start address
+    ///
+    /// `file` must be `Some` unless `MAP_ANONYMOUS` is set. `writable_file`
+    /// tells whether the file was opened for writing, which is required for
+    /// shared writable mappings. A `MAP_FIXED` range may not overlap the
+    /// heap window or the vDSO page.
+    pub fn mmap(
+        &mut self,
+        addr: u64,
+        len: u64,
+        prot: u32,
+        flags: u32,
+        file: Option<(Arc<dyn Inode>, bool)>,
+        offset: u64,
+    ) -> Result<u64, VmError> {
+        if len == 0 || prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+            return Err(VmError::InvalidArgument);
+        }
+
+        let shared = match flags & (MAP_SHARED | MAP_PRIVATE) {
+            MAP_SHARED => true,
+            MAP_PRIVATE => false,
+            _ => return Err(VmError::InvalidArgument),
+        };
+
+        if !offset.is_multiple_of(PAGE_SIZE) {
+            return Err(VmError::InvalidArgument);
+        }
+
+        let len = align_up(len).ok_or(VmError::InvalidArgument)?;
+
+        let backing = if flags & MAP_ANONYMOUS != 0 {
+            VmaBacking::Anonymous
+        } else {
+            let (inode, writable) = file.ok_or(VmError::InvalidArgument)?;
+
+            if inode.file_type() != FileType::Regular {
+                return Err(VmError::NotMappable);
+            }
+            if shared && prot & PROT_WRITE != 0 && !writable {
+                return Err(VmError::PermissionDenied);
+            }
+
+            VmaBacking::File { inode, offset, writable }
+        };
+
+        let start = if flags & MAP_FIXED != 0 {
+            if !addr.is_multiple_of(PAGE_SIZE) || addr.checked_add(len).is_none_or(|end| end > USER_SPACE_END) {
+                return Err(VmError::InvalidArgument);
+            }
+            // The heap window and the vDSO page are not the caller's to replace
+            let overlaps = |start: u64, end: u64| addr < end && start < addr + len;
+            if overlaps(USER_HEAP_START, USER_HEAP_START + USER_HEAP_MAX) || overlaps(VDSO_BASE, VDSO_BASE + PAGE_SIZE) {
+                return Err(VmError::InvalidArgument);
+            }
+            // MAP_FIXED replaces whatever was mapped there before
+            self.unmap_range(addr, addr + len);
+            addr
+        } else {
+            let hint = align_up(addr).ok_or(VmError::InvalidArgument)?;
+            self.find_free_range(hint, len).ok_or(VmError::OutOfMemory)?
+        };
+
+        self.vmas.insert(start, Vma {
+            start,
+            end: start + len,
+            prot,
+            shared,
+            backing,
+        });
+
+        Ok(start)
+    }
+
+    /// Remove mappings covering `[addr, addr + len)`
+    pub fn munmap(&mut self, addr: u64, len: u64) -> Result<(), VmError> {
+        if !addr.is_multiple_of(PAGE_SIZE) || len == 0 {
+            return Err(VmError::InvalidArgument);
+        }
+
+        let end = align_up(len)
+            .and_then(|len| addr.checked_add(len))
+            .ok_or(VmError::InvalidArgument)?;
+        self.unmap_range(addr, end);
+        Ok(())
+    }
+
+    /// Change the protection of pages in `[addr, addr + len)`
+    ///
+    /// Every page in the range must be mapped.
+    pub fn mprotect(&mut self, addr: u64, len: u64, prot: u32) -> Result<(), VmError> {
+        if !addr.is_multiple_of(PAGE_SIZE) || prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+            return Err(VmError::InvalidArgument);
+        }
+
+        let end = align_up(len)
+            .and_then(|len| addr.checked_add(len))
+            .ok_or(VmError::InvalidArgument)?;
+        if end == addr {
+            return Ok(());
+        }
+
+        // The range must be fully covered, without holes
+        let mut cursor = addr;
+        while cursor < end {
+            match self.find(cursor) {
+                Some(vma) => {
+                    if let VmaBacking::File { writable: false, .. } = vma.backing {
+                        if vma.shared && prot & PROT_WRITE != 0 {
+                            return Err(VmError::PermissionDenied);
+                        }
+                    }
+                    // The clock page is shared with the kernel and stays read-only
+                    if matches!(vma.backing, VmaBacking::Vdso { .. }) && prot & PROT_WRITE != 0 {
+                        return Err(VmError::PermissionDenied);
+                    }
+                    cursor = vma.end;
+                }
+                None => return Err(VmError::OutOfMemory),
+            }
+        }
+
+        self.split_at(addr);
+        self.split_at(end);
+
+        for (_, vma) in self.vmas.range_mut(addr..end) {
+            vma.prot = prot;
+        }
+
+        // Re-protect the pages already mapped; file pages stay read-only
+        // until their next write fault
+        for (&page_addr, page) in self.pages.range(addr..end) {
+            let Some(vma) = self.find(page_addr) else {
+                continue;
+            };
+            let mut flags = vma.page_flags();
+            if let MappedPage::File(_) = page {
+                flags.remove(PageTableFlags::WRITABLE);
+            }
+            paging::update_flags(page_addr, flags)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for AddressSpace {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        self.release_pages(0, USER_SPACE_END);
+    }
+}
+
+/// Resolve a page fault at `addr` in the current process's address space
+///
+/// Returns an error if the address is not mapped by a VMA allowing the
+/// access; the caller then treats the fault as fatal.
+pub fn handle_page_fault(addr: u64, access: u32) -> Result<(), VmError> {
+    if addr >= USER_SPACE_END {
+        return Err(VmError::BadAddress);
+    }
+    crate::process::current_resources().address_space.lock().handle_fault(addr, access)
+}
+
+/// Round `value` up to the next page boundary
+///
+/// Returns `None` if the result does not fit in 64 bits.
+pub const fn align_up(value: u64) -> Option<u64> {
+    match value.checked_add(PAGE_SIZE - 1) {
+        Some(sum) => Some(sum & !(PAGE_SIZE - 1)),
+        None => None,
+    }
+}
@@ -0,0 +1,197 @@
+//! Wait queues for blocking on I/O readiness
+//!
+//! Anything whose readiness can change (a pipe, the console, an epoll
+//! instance) owns a `WaitQueue` and calls `wake_all` when it does. Blocked
+//! processes register once per wait and are removed when woken; epoll
+//! instances register a persistent callback instead so they can keep their
+//! ready list up to date without rescanning every file.
+
+use alloc::sync::Weak;
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::interrupts::{self, without_interrupts};
+
+use crate::process::{self, PROCESS_MANAGER};
+use crate::time;
+
+/// Receiver of wake-ups registered with `WaitQueue::add_callback`
+pub trait Wakeable: Send + Sync {
+    /// Called with the key given at registration whenever the queue wakes
+    fn wake(&self, key: u64);
+}
+
+/// An entry on a wait queue
+enum Waiter {
+    /// A blocked process, removed from the queue when woken
+    Process(u32),
+    /// A persistent callback, kept until explicitly removed
+    Callback(Weak<dyn Wakeable>, u64),
+}
+
+/// A list of waiters to wake when some state changes
+///
+/// Safe to wake from interrupt handlers: the list is only locked with
+/// interrupts disabled.
+pub struct WaitQueue {
+    waiters: Mutex<Vec<Waiter>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            waiters: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Add a process to be woken on the next `wake_all`
+    pub fn register(&self, pid: u32) {
+        without_interrupts(|| {
+            let mut waiters = self.waiters.lock();
+            if !waiters.iter().any(|w| matches!(w, Waiter::Process(p) if *p == pid)) {
+                waiters.push(Waiter::Process(pid));
+            }
+        });
+    }
+
+    /// Remove a process that stopped waiting without being woken
+    pub fn unregister(&self, pid: u32) {
+        without_interrupts(|| {
+            self.waiters
+                .lock()
+                .retain(|w| !matches!(w, Waiter::Process(p) if *p == pid));
+        });
+    }
+
+    /// Add a callback invoked with `key` on every `wake_all`
+    pub fn add_callback(&self, callback: Weak<dyn Wakeable>, key: u64) {
+        without_interrupts(|| self.waiters.lock().push(Waiter::Callback(callback, key)));
+    }
+
+    /// Remove the callback registered for `callback` with `key`
+    pub fn remove_callback(&self, callback: &Weak<dyn Wakeable>, key: u64) {
+        without_interrupts(|| {
+            self.waiters.lock().retain(|w| match w {
+                Waiter::Callback(cb, k) => !(Weak::ptr_eq(cb, callback) && *k == key),
+                Waiter::Process(_) => true,
+            });
+        });
+    }
+
+    /// Wake every waiting process and notify every callback
+    pub fn wake_all(&self) {
+        let mut pids = Vec::new();
+        let mut callbacks = Vec::new();
+
+        without_interrupts(|| {
+            let mut waiters = self.waiters.lock();
+            waiters.retain(|w| match w {
+                Waiter::Process(pid) => {
+                    pids.push(*pid);
+                    false
+                }
+                // Drop callbacks whose owner has gone away
+                Waiter::Callback(cb, key) => match cb.upgrade() {
+                    Some(cb) => {
+                        callbacks.push((cb, *key));
+                        true
+                    }
+                    None => false,
+                },
+            });
+        });
+
+        // Run callbacks and wake processes without holding the queue lock
+        for (callback, key) in callbacks {
+            callback.wake(key);
+        }
+        if !pids.is_empty() {
+            without_interrupts(|| {
+                let mut manager = PROCESS_MANAGER.lock();
+                for pid in pids {
+                    manager.wake(pid);
+                }
+            });
+        }
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// How a wait ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitResult {
+    /// The condition became true
+    Ready,
+    /// The deadline passed first
+    TimedOut,
+    /// A signal arrived first
+    Interrupted,
+}
+
+/// Block the current process until `ready` returns true
+///
+/// The process sleeps on every queue in `queues` and re-checks `ready`
+/// after each wake-up. `deadline` is an absolute `CLOCK_MONOTONIC` time in
+/// nanoseconds; `None` waits forever. Signals arriving during the wait end
+/// it early. The caller's interrupt state is restored on return.
+pub fn wait_event<F: FnMut() -> bool>(queues: &[&WaitQueue], deadline: Option<u64>, mut ready: F) -> WaitResult {
+    let pid = process::current_pid();
+    let resources = process::resources(pid);
+    let signals_before = resources.pending_signals();
+    let were_enabled = interrupts::are_enabled();
+
+    let result = loop {
+        if ready() {
+            break WaitResult::Ready;
+        }
+        if deadline.is_some_and(|d| time::monotonic_ns() >= d) {
+            break WaitResult::TimedOut;
+        }
+        if resources.pending_signals() & !signals_before != 0 {
+            break WaitResult::Interrupted;
+        }
+
+        // Register and block with interrupts off so a wake-up between the
+        // check above and blocking cannot be lost
+        interrupts::disable();
+        for queue in queues {
+            queue.register(pid);
+        }
+        if let Some(d) = deadline {
+            time::add_wakeup(d, pid);
+        }
+        PROCESS_MANAGER.lock().block(pid);
+
+        if ready() {
+            PROCESS_MANAGER.lock().wake(pid);
+        }
+
+        while PROCESS_MANAGER.lock().is_blocked(pid) {
+            if resources.pending_signals() & !signals_before != 0 {
+                PROCESS_MANAGER.lock().wake(pid);
+                break;
+            }
+            interrupts::enable_and_hlt();
+            interrupts::disable();
+        }
+
+        for queue in queues {
+            queue.unregister(pid);
+        }
+        if let Some(d) = deadline {
+            time::cancel_wakeup(d, pid);
+        }
+    };
+
+    if were_enabled {
+        interrupts::enable();
+    } else {
+        interrupts::disable();
+    }
+
+    result
+}
@@ -0,0 +1,128 @@
+//! CPIO "newc" archive writer
+//!
+//! Produces the format the kernel's initramfs unpacker reads: a 110-byte
+//! ASCII header per entry, the NUL-terminated name and the data, each
+//! padded to 4 bytes, ending with a `TRAILER!!!` entry. With `crc` set the
+//! "070702" variant is written, carrying a checksum of each file's data.
+
+use std::collections::HashMap;
+
+use crate::tree::{Node, NodeKind};
+
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+/// An entry waiting to be written, with its path in the archive
+struct Pending<'a> {
+    path: String,
+    node: &'a Node,
+}
+
+/// Pack `nodes` (the contents of the archive root) into an archive
+///
+/// Every entry gets owner 0:0 and modification time `mtime`. Files that
+/// are hard links of each other on the host stay linked: they share an
+/// inode number and, as newc expects, only the last name carries the data.
+pub fn pack(nodes: &[Node], mtime: u32, crc: bool) -> Vec<u8> {
+    let mut pending = Vec::new();
+    collect(nodes, "", &mut pending);
+
+    // Names per hard-linked host file, and the inode number it gets
+    let mut link_counts: HashMap<(u64, u64), u32> = HashMap::new();
+    for entry in &pending {
+        if let NodeKind::File { link_id: Some(id), .. } = entry.node.kind {
+            *link_counts.entry(id).or_default() += 1;
+        }
+    }
+    let mut link_inos: HashMap<(u64, u64), u32> = HashMap::new();
+    let mut links_written: HashMap<(u64, u64), u32> = HashMap::new();
+
+    let mut out = Vec::new();
+    let mut next_ino = 0;
+    let mut take_ino = || {
+        next_ino += 1;
+        next_ino
+    };
+    for entry in &pending {
+        let node = entry.node;
+        let (kind_bits, data, nlink, ino) = match &node.kind {
+            NodeKind::Directory(_) => (S_IFDIR, &[][..], 2, take_ino()),
+            NodeKind::Symlink(target) => (S_IFLNK, target.as_bytes(), 1, take_ino()),
+            NodeKind::File { data, link_id: None } => (S_IFREG, &data[..], 1, take_ino()),
+            NodeKind::File { data, link_id: Some(id) } => {
+                let count = link_counts[id];
+                let written = links_written.entry(*id).or_default();
+                *written += 1;
+                let data = if *written == count { &data[..] } else { &[][..] };
+                let ino = *link_inos.entry(*id).or_insert_with(&mut take_ino);
+                (S_IFREG, data, count, ino)
+            }
+        };
+
+        let header = Header { ino, mode: kind_bits | node.mode, nlink, mtime, crc };
+        write_entry(&mut out, &header, &entry.path, data);
+    }
+
+    let trailer = Header { ino: 0, mode: 0, nlink: 1, mtime: 0, crc };
+    write_entry(&mut out, &trailer, "TRAILER!!!", &[]);
+    out
+}
+
+/// List entries depth-first, each directory before its contents
+fn collect<'a>(nodes: &'a [Node], prefix: &str, pending: &mut Vec<Pending<'a>>) {
+    for node in nodes {
+        let path = format!("{}{}", prefix, node.name);
+        pending.push(Pending { path: path.clone(), node });
+        if let NodeKind::Directory(children) = &node.kind {
+            collect(children, &format!("{}/", path), pending);
+        }
+    }
+}
+
+struct Header {
+    ino: u32,
+    mode: u32,
+    nlink: u32,
+    mtime: u32,
+    crc: bool,
+}
+
+fn write_entry(out: &mut Vec<u8>, header: &Header, name: &str, data: &[u8]) {
+    let check = if header.crc && header.mode & S_IFMT == S_IFREG {
+        data.iter().fold(0u32, |sum, &byte| sum.wrapping_add(byte as u32))
+    } else {
+        0
+    };
+
+    out.extend_from_slice(if header.crc { b"070702" } else { b"070701" });
+    let fields = [
+        header.ino,
+        header.mode,
+        0, // uid
+        0, // gid
+        header.nlink,
+        header.mtime,
+        data.len() as u32,
+        0, // devmajor
+        0, // devminor
+        0, // rdevmajor
+        0, // rdevminor
+        name.len() as u32 + 1,
+        check,
+    ];
+    for field in fields {
+        out.extend_from_slice(format!("{:08x}", field).as_bytes());
+    }
+
+    out.extend_from_slice(name.as_bytes());
+    out.push(0);
+    pad(out);
+    out.extend_from_slice(data);
+    pad(out);
+}
+
+fn pad(out: &mut Vec<u8>) {
+    out.resize(out.len().next_multiple_of(4), 0);
+}
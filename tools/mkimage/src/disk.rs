@@ -0,0 +1,174 @@
+//! Partition tables: MBR and GPT
+//!
+//! Images get a single partition starting at 1 MiB and running to the end
+//! of the disk (GPT keeps the last 33 sectors for its backup copy). GUIDs
+//! are derived from the image label rather than drawn at random, so the
+//! same inputs give the same bytes.
+
+pub const SECTOR_SIZE: usize = 512;
+
+/// First sector of the partition (1 MiB, the usual alignment)
+pub const PARTITION_START: u64 = 2048;
+
+/// MBR partition type for FAT32 with LBA addressing
+const MBR_TYPE_FAT32_LBA: u8 = 0x0C;
+/// MBR partition type marking a GPT disk
+const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xEE;
+
+/// GPT "Microsoft basic data" partition type, used for FAT filesystems
+const GPT_TYPE_BASIC_DATA: [u8; 16] = guid(0xEBD0A0A2, 0xB9E5, 0x4433, [0x87, 0xC0, 0x68, 0xB6, 0xB7, 0x26, 0x99, 0xC7]);
+
+const GPT_ENTRY_COUNT: usize = 128;
+const GPT_ENTRY_SIZE: usize = 128;
+/// Sectors taken by the partition entry array
+const GPT_ENTRY_SECTORS: u64 = (GPT_ENTRY_COUNT * GPT_ENTRY_SIZE / SECTOR_SIZE) as u64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionTable {
+    Mbr,
+    Gpt,
+}
+
+impl PartitionTable {
+    /// First and last sector (inclusive) of the partition on a disk of
+    /// `total_sectors`, or None if the disk is too small
+    pub fn partition_range(self, total_sectors: u64) -> Option<(u64, u64)> {
+        let last = match self {
+            PartitionTable::Mbr => total_sectors.checked_sub(1)?,
+            PartitionTable::Gpt => total_sectors.checked_sub(2 + GPT_ENTRY_SECTORS)?,
+        };
+        (last > PARTITION_START).then_some((PARTITION_START, last))
+    }
+
+    /// Write the partition table for one partition spanning `first..=last`
+    pub fn write(self, image: &mut [u8], first: u64, last: u64, label: &str) {
+        match self {
+            PartitionTable::Mbr => write_mbr(image, first, last, label),
+            PartitionTable::Gpt => write_gpt(image, first, last, label),
+        }
+    }
+}
+
+/// Lay out a classic MBR with one FAT32 partition
+fn write_mbr(image: &mut [u8], first: u64, last: u64, label: &str) {
+    let signature = crc32(label.as_bytes());
+    image[440..444].copy_from_slice(&signature.to_le_bytes());
+    write_mbr_entry(image, 0, MBR_TYPE_FAT32_LBA, first, last - first + 1);
+    image[510] = 0x55;
+    image[511] = 0xAA;
+}
+
+/// Fill in one of the four 16-byte MBR partition entries
+fn write_mbr_entry(image: &mut [u8], index: usize, kind: u8, first: u64, count: u64) {
+    let entry = &mut image[446 + index * 16..446 + (index + 1) * 16];
+    entry[0] = 0x00; // not bootable
+    // CHS addresses are unused; this is the customary "use LBA" value
+    entry[1..4].copy_from_slice(&[0xFE, 0xFF, 0xFF]);
+    entry[4] = kind;
+    entry[5..8].copy_from_slice(&[0xFE, 0xFF, 0xFF]);
+    entry[8..12].copy_from_slice(&(first.min(u32::MAX as u64) as u32).to_le_bytes());
+    entry[12..16].copy_from_slice(&(count.min(u32::MAX as u64) as u32).to_le_bytes());
+}
+
+/// Lay out a protective MBR, the primary GPT and its backup
+fn write_gpt(image: &mut [u8], first: u64, last: u64, label: &str) {
+    let total_sectors = (image.len() / SECTOR_SIZE) as u64;
+    let last_lba = total_sectors - 1;
+
+    write_mbr_entry(image, 0, MBR_TYPE_GPT_PROTECTIVE, 1, total_sectors - 1);
+    image[510] = 0x55;
+    image[511] = 0xAA;
+
+    // One partition entry; the rest of the array stays zero (unused)
+    let mut entries = vec![0u8; GPT_ENTRY_COUNT * GPT_ENTRY_SIZE];
+    entries[0..16].copy_from_slice(&GPT_TYPE_BASIC_DATA);
+    entries[16..32].copy_from_slice(&derived_guid(label, "partition"));
+    entries[32..40].copy_from_slice(&first.to_le_bytes());
+    entries[40..48].copy_from_slice(&last.to_le_bytes());
+    // Attributes (48..56) stay zero; the name is UTF-16LE, 36 units at most
+    for (i, unit) in label.encode_utf16().take(36).enumerate() {
+        entries[56 + i * 2..58 + i * 2].copy_from_slice(&unit.to_le_bytes());
+    }
+    let entries_crc = crc32(&entries);
+
+    let backup_entries_lba = last_lba - GPT_ENTRY_SECTORS;
+    let header = GptHeader {
+        disk_guid: derived_guid(label, "disk"),
+        first_usable: 2 + GPT_ENTRY_SECTORS,
+        last_usable: backup_entries_lba - 1,
+        entries_crc,
+    };
+
+    put_sectors(image, 2, &entries);
+    put_sectors(image, 1, &header.encode(1, last_lba, 2));
+    put_sectors(image, backup_entries_lba, &entries);
+    put_sectors(image, last_lba, &header.encode(last_lba, 1, backup_entries_lba));
+}
+
+struct GptHeader {
+    disk_guid: [u8; 16],
+    first_usable: u64,
+    last_usable: u64,
+    entries_crc: u32,
+}
+
+impl GptHeader {
+    /// Encode the header as stored at `my_lba`, pointing at the other copy
+    /// and at its own entry array
+    fn encode(&self, my_lba: u64, alternate_lba: u64, entries_lba: u64) -> [u8; 92] {
+        let mut header = [0u8; 92];
+        header[0..8].copy_from_slice(b"EFI PART");
+        header[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes()); // revision 1.0
+        header[12..16].copy_from_slice(&92u32.to_le_bytes());
+        // 16..20 is the header CRC, computed with the field zeroed
+        header[24..32].copy_from_slice(&my_lba.to_le_bytes());
+        header[32..40].copy_from_slice(&alternate_lba.to_le_bytes());
+        header[40..48].copy_from_slice(&self.first_usable.to_le_bytes());
+        header[48..56].copy_from_slice(&self.last_usable.to_le_bytes());
+        header[56..72].copy_from_slice(&self.disk_guid);
+        header[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+        header[80..84].copy_from_slice(&(GPT_ENTRY_COUNT as u32).to_le_bytes());
+        header[84..88].copy_from_slice(&(GPT_ENTRY_SIZE as u32).to_le_bytes());
+        header[88..92].copy_from_slice(&self.entries_crc.to_le_bytes());
+        let crc = crc32(&header);
+        header[16..20].copy_from_slice(&crc.to_le_bytes());
+        header
+    }
+}
+
+fn put_sectors(image: &mut [u8], lba: u64, data: &[u8]) {
+    let start = lba as usize * SECTOR_SIZE;
+    image[start..start + data.len()].copy_from_slice(data);
+}
+
+/// Encode a GUID in its on-disk form (first three fields little-endian)
+const fn guid(a: u32, b: u16, c: u16, d: [u8; 8]) -> [u8; 16] {
+    let a = a.to_le_bytes();
+    let b = b.to_le_bytes();
+    let c = c.to_le_bytes();
+    [a[0], a[1], a[2], a[3], b[0], b[1], c[0], c[1], d[0], d[1], d[2], d[3], d[4], d[5], d[6], d[7]]
+}
+
+/// A version 4 style GUID whose "random" bits come from hashing the inputs
+fn derived_guid(label: &str, purpose: &str) -> [u8; 16] {
+    let mut bytes = [0u8; 16];
+    for (i, chunk) in bytes.chunks_mut(4).enumerate() {
+        let seed = format!("{}/{}/{}", label, purpose, i);
+        chunk.copy_from_slice(&crc32(seed.as_bytes()).to_le_bytes());
+    }
+    bytes[7] = (bytes[7] & 0x0F) | 0x40; // version 4
+    bytes[8] = (bytes[8] & 0x3F) | 0x80; // RFC 4122 variant
+    bytes
+}
+
+/// CRC-32 (IEEE 802.3), as GPT uses for its header and entries
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
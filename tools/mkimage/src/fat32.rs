@@ -0,0 +1,443 @@
+//! FAT32 filesystem builder
+//!
+//! Formats a FAT32 volume and fills it from a directory tree in one pass:
+//! clusters are handed out in order, so every file and directory is
+//! contiguous. Names that do not fit 8.3 get long file name (VFAT) entries.
+//! FAT has no symlinks, so those are skipped, and hard links become copies.
+
+use std::collections::HashSet;
+
+use crate::disk::SECTOR_SIZE;
+use crate::tree::{Node, NodeKind};
+
+const RESERVED_SECTORS: u32 = 32;
+const FAT_COUNT: u32 = 2;
+/// Sector of the FSInfo structure, and of the backup boot sector
+const FSINFO_SECTOR: u32 = 1;
+const BACKUP_BOOT_SECTOR: u32 = 6;
+const ROOT_CLUSTER: u32 = 2;
+
+const END_OF_CHAIN: u32 = 0x0FFF_FFFF;
+/// FAT[0]: the media byte (0xF8, fixed disk) with the other bits set
+const MEDIA_ENTRY: u32 = 0x0FFF_FFF8;
+
+const DIR_ENTRY_SIZE: usize = 32;
+/// UTF-16 units held by one long file name entry
+const LFN_UNITS: usize = 13;
+
+const ATTR_READ_ONLY: u8 = 0x01;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = 0x0F;
+
+/// FAT dates start in 1980
+const FAT_EPOCH: u32 = 315_532_800;
+
+/// Characters allowed in a short name besides letters and digits
+const SHORT_NAME_SPECIALS: &str = "$%'-_@~`!(){}^#&";
+
+/// Build a FAT32 volume of `total_sectors` holding `nodes`
+///
+/// `hidden_sectors` is the volume's first sector on the disk (0 for an
+/// unpartitioned image) and `mtime` the Unix time stamped on every entry.
+pub fn build(nodes: &[Node], total_sectors: u32, label: &str, hidden_sectors: u32, mtime: u32) -> Result<Vec<u8>, String> {
+    let sectors_per_cluster = sectors_per_cluster(total_sectors);
+    let fat_sectors = fat_sectors(total_sectors, sectors_per_cluster).ok_or("volume too small for FAT32")?;
+    let data_start = RESERVED_SECTORS + FAT_COUNT * fat_sectors;
+    let cluster_count = (total_sectors - data_start) / sectors_per_cluster;
+
+    let mut builder = Builder {
+        image: vec![0; total_sectors as usize * SECTOR_SIZE],
+        sectors_per_cluster,
+        data_start,
+        fat: vec![0; cluster_count as usize + 2],
+        next_free: ROOT_CLUSTER,
+        timestamp: fat_timestamp(mtime),
+    };
+    builder.fat[0] = MEDIA_ENTRY;
+    builder.fat[1] = END_OF_CHAIN;
+
+    let label = volume_label(label);
+    let root_clusters = builder.directory_clusters(nodes, true);
+    let root = builder.allocate(root_clusters)?;
+    debug_assert_eq!(root, ROOT_CLUSTER);
+    builder.write_directory(nodes, root, 0, Some(&label))?;
+
+    let free = builder.fat.iter().filter(|&&entry| entry == 0).count() as u32;
+    let layout = Layout {
+        total_sectors,
+        hidden_sectors,
+        sectors_per_cluster,
+        fat_sectors,
+        free_clusters: free,
+        next_free: builder.next_free,
+        label,
+        volume_id: crate::disk::crc32(&label),
+    };
+    builder.finish(&layout);
+    Ok(builder.image)
+}
+
+/// Cluster size by volume size, following the usual FAT32 table
+fn sectors_per_cluster(total_sectors: u32) -> u32 {
+    match total_sectors {
+        0..=532_480 => 1,           // up to 260 MiB
+        532_481..=16_777_216 => 8,  // up to 8 GiB
+        16_777_217..=33_554_432 => 16,
+        33_554_433..=67_108_864 => 32,
+        _ => 64,
+    }
+}
+
+/// Smallest FAT size (in sectors) that covers every data cluster
+fn fat_sectors(total_sectors: u32, sectors_per_cluster: u32) -> Option<u32> {
+    let entries_per_sector = (SECTOR_SIZE / 4) as u32;
+    let mut fat = 1;
+    loop {
+        let data = total_sectors.checked_sub(RESERVED_SECTORS + FAT_COUNT * fat)?;
+        let clusters = data / sectors_per_cluster;
+        if clusters == 0 {
+            return None;
+        }
+        if clusters + 2 <= fat * entries_per_sector {
+            return Some(fat);
+        }
+        fat += 1;
+    }
+}
+
+/// The 11-byte label, upper case and space padded
+fn volume_label(label: &str) -> [u8; 11] {
+    let mut bytes = *b"NO NAME    ";
+    if !label.is_empty() {
+        bytes = [b' '; 11];
+        for (slot, byte) in bytes.iter_mut().zip(label.bytes().filter(u8::is_ascii)) {
+            *slot = byte.to_ascii_uppercase();
+        }
+    }
+    bytes
+}
+
+/// FAT (time, date) words for a Unix time
+fn fat_timestamp(unix: u32) -> (u16, u16) {
+    let unix = unix.max(FAT_EPOCH);
+    let days = unix / 86_400;
+    let seconds = unix % 86_400;
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    let time = ((seconds / 3600) << 11) | (((seconds / 60) % 60) << 5) | ((seconds % 60) / 2);
+    let date = ((year - 1980) << 9) | (month << 5) | day;
+    (time as u16, date as u16)
+}
+
+/// Everything the boot sector and FSInfo record
+struct Layout {
+    total_sectors: u32,
+    hidden_sectors: u32,
+    sectors_per_cluster: u32,
+    fat_sectors: u32,
+    free_clusters: u32,
+    next_free: u32,
+    label: [u8; 11],
+    volume_id: u32,
+}
+
+/// A directory entry's names: the 8.3 name and, if that loses
+/// information, the long name
+struct EntryName {
+    short: [u8; 11],
+    long: Option<Vec<u16>>,
+}
+
+impl EntryName {
+    /// Directory slots taken: the LFN entries plus the short entry
+    fn slots(&self) -> usize {
+        1 + self.long.as_ref().map_or(0, |long| long.len().div_ceil(LFN_UNITS))
+    }
+}
+
+struct Builder {
+    image: Vec<u8>,
+    sectors_per_cluster: u32,
+    data_start: u32,
+    fat: Vec<u32>,
+    next_free: u32,
+    timestamp: (u16, u16),
+}
+
+impl Builder {
+    fn cluster_bytes(&self) -> usize {
+        self.sectors_per_cluster as usize * SECTOR_SIZE
+    }
+
+    /// Take `count` consecutive clusters and chain them, returning the first
+    /// (0 for no clusters, as an empty file has)
+    fn allocate(&mut self, count: usize) -> Result<u32, String> {
+        if count == 0 {
+            return Ok(0);
+        }
+        let first = self.next_free as usize;
+        if first + count > self.fat.len() {
+            return Err("volume is full".into());
+        }
+        for cluster in first..first + count - 1 {
+            self.fat[cluster] = cluster as u32 + 1;
+        }
+        self.fat[first + count - 1] = END_OF_CHAIN;
+        self.next_free += count as u32;
+        Ok(first as u32)
+    }
+
+    /// Copy `data` into the clusters starting at `first`
+    fn write_data(&mut self, first: u32, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        let start = (self.data_start as usize + (first - 2) as usize * self.sectors_per_cluster as usize) * SECTOR_SIZE;
+        self.image[start..start + data.len()].copy_from_slice(data);
+    }
+
+    /// Clusters a directory holding `nodes` takes
+    fn directory_clusters(&self, nodes: &[Node], root: bool) -> usize {
+        // The root holds the volume label; others hold "." and ".."
+        let fixed = if root { 1 } else { 2 };
+        let slots: usize = short_and_long_names(nodes).iter().map(|(_, name)| name.slots()).sum();
+        ((fixed + slots) * DIR_ENTRY_SIZE).div_ceil(self.cluster_bytes()).max(1)
+    }
+
+    /// Fill the directory at `cluster` and everything below it
+    ///
+    /// `parent` is the parent's cluster (0 for the root); `label` is given
+    /// for the root only.
+    fn write_directory(&mut self, nodes: &[Node], cluster: u32, parent: u32, label: Option<&[u8; 11]>) -> Result<(), String> {
+        let mut entries = Vec::new();
+        match label {
+            Some(label) => entries.extend_from_slice(&self.short_entry(label, ATTR_VOLUME_ID, 0, 0)),
+            None => {
+                entries.extend_from_slice(&self.short_entry(b".          ", ATTR_DIRECTORY, cluster, 0));
+                entries.extend_from_slice(&self.short_entry(b"..         ", ATTR_DIRECTORY, parent, 0));
+            }
+        }
+
+        for node in nodes.iter().filter(|node| matches!(node.kind, NodeKind::Symlink(_))) {
+            eprintln!("mkimage: skipping symlink {}: FAT has no symlinks", node.name);
+        }
+
+        let mut subdirectories = Vec::new();
+        for (node, name) in short_and_long_names(nodes) {
+            let read_only = if node.mode & 0o200 == 0 { ATTR_READ_ONLY } else { 0 };
+            let (first, attr, size) = match &node.kind {
+                NodeKind::Directory(children) => {
+                    let first = self.allocate(self.directory_clusters(children, false))?;
+                    subdirectories.push((children, first));
+                    (first, ATTR_DIRECTORY | read_only, 0)
+                }
+                NodeKind::File { data, .. } => {
+                    let first = self.allocate(data.len().div_ceil(self.cluster_bytes()))?;
+                    self.write_data(first, data);
+                    let size = u32::try_from(data.len()).map_err(|_| format!("{}: too large for FAT32", node.name))?;
+                    (first, ATTR_ARCHIVE | read_only, size)
+                }
+                NodeKind::Symlink(_) => unreachable!("symlinks have no FAT name"),
+            };
+
+            if let Some(long) = &name.long {
+                entries.extend_from_slice(&long_entries(long, &name.short));
+            }
+            entries.extend_from_slice(&self.short_entry(&name.short, attr, first, size));
+        }
+
+        self.write_data(cluster, &entries);
+        let own = if label.is_some() { 0 } else { cluster };
+        for (children, first) in subdirectories {
+            self.write_directory(children, first, own, None)?;
+        }
+        Ok(())
+    }
+
+    /// A 32-byte short directory entry
+    fn short_entry(&self, name: &[u8; 11], attr: u8, cluster: u32, size: u32) -> [u8; DIR_ENTRY_SIZE] {
+        let (time, date) = self.timestamp;
+        let mut entry = [0u8; DIR_ENTRY_SIZE];
+        entry[0..11].copy_from_slice(name);
+        entry[11] = attr;
+        if attr & ATTR_VOLUME_ID == 0 {
+            entry[14..16].copy_from_slice(&time.to_le_bytes()); // creation
+            entry[16..18].copy_from_slice(&date.to_le_bytes());
+            entry[18..20].copy_from_slice(&date.to_le_bytes()); // last access
+        }
+        entry[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+        entry[22..24].copy_from_slice(&time.to_le_bytes()); // last write
+        entry[24..26].copy_from_slice(&date.to_le_bytes());
+        entry[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+        entry[28..32].copy_from_slice(&size.to_le_bytes());
+        entry
+    }
+
+    /// Write the boot sector, its backup, FSInfo and both FATs
+    fn finish(&mut self, layout: &Layout) {
+        let mut boot = [0u8; SECTOR_SIZE];
+        boot[0..3].copy_from_slice(&[0xEB, 0x58, 0x90]); // jmp short, nop
+        boot[3..11].copy_from_slice(b"MKIMAGE ");
+        boot[11..13].copy_from_slice(&(SECTOR_SIZE as u16).to_le_bytes());
+        boot[13] = layout.sectors_per_cluster as u8;
+        boot[14..16].copy_from_slice(&(RESERVED_SECTORS as u16).to_le_bytes());
+        boot[16] = FAT_COUNT as u8;
+        // Root entry count and 16-bit sector counts stay 0 on FAT32
+        boot[21] = 0xF8; // fixed disk
+        boot[24..26].copy_from_slice(&32u16.to_le_bytes()); // sectors per track
+        boot[26..28].copy_from_slice(&64u16.to_le_bytes()); // heads
+        boot[28..32].copy_from_slice(&layout.hidden_sectors.to_le_bytes());
+        boot[32..36].copy_from_slice(&layout.total_sectors.to_le_bytes());
+        boot[36..40].copy_from_slice(&layout.fat_sectors.to_le_bytes());
+        // Flags and version (40..44) stay 0: both FATs mirrored, version 0.0
+        boot[44..48].copy_from_slice(&ROOT_CLUSTER.to_le_bytes());
+        boot[48..50].copy_from_slice(&(FSINFO_SECTOR as u16).to_le_bytes());
+        boot[50..52].copy_from_slice(&(BACKUP_BOOT_SECTOR as u16).to_le_bytes());
+        boot[64] = 0x80; // drive number
+        boot[66] = 0x29; // extended boot signature: the next three fields are valid
+        boot[67..71].copy_from_slice(&layout.volume_id.to_le_bytes());
+        boot[71..82].copy_from_slice(&layout.label);
+        boot[82..90].copy_from_slice(b"FAT32   ");
+        boot[510] = 0x55;
+        boot[511] = 0xAA;
+
+        let mut fsinfo = [0u8; SECTOR_SIZE];
+        fsinfo[0..4].copy_from_slice(&0x4161_5252u32.to_le_bytes());
+        fsinfo[484..488].copy_from_slice(&0x6141_7272u32.to_le_bytes());
+        fsinfo[488..492].copy_from_slice(&layout.free_clusters.to_le_bytes());
+        fsinfo[492..496].copy_from_slice(&layout.next_free.to_le_bytes());
+        fsinfo[508..512].copy_from_slice(&0xAA55_0000u32.to_le_bytes());
+
+        for (sector, data) in [
+            (0, &boot),
+            (FSINFO_SECTOR, &fsinfo),
+            (BACKUP_BOOT_SECTOR, &boot),
+            (BACKUP_BOOT_SECTOR + FSINFO_SECTOR, &fsinfo),
+        ] {
+            let start = sector as usize * SECTOR_SIZE;
+            self.image[start..start + SECTOR_SIZE].copy_from_slice(data);
+        }
+
+        let fat: Vec<u8> = self.fat.iter().flat_map(|entry| entry.to_le_bytes()).collect();
+        for copy in 0..FAT_COUNT {
+            let start = (RESERVED_SECTORS + copy * layout.fat_sectors) as usize * SECTOR_SIZE;
+            self.image[start..start + fat.len()].copy_from_slice(&fat);
+        }
+    }
+}
+
+/// Names for the entries of one directory, symlinks left out
+fn short_and_long_names(nodes: &[Node]) -> Vec<(&Node, EntryName)> {
+    let mut used = HashSet::new();
+    let mut names = Vec::new();
+    for node in nodes {
+        if let NodeKind::Symlink(_) = node.kind {
+            continue;
+        }
+        names.push((node, entry_name(&node.name, &mut used)));
+    }
+    names
+}
+
+/// Pick a unique 8.3 name for `name`, with a long name when needed
+fn entry_name(name: &str, used: &mut HashSet<[u8; 11]>) -> EntryName {
+    let (base, ext) = match name.rfind('.') {
+        Some(dot) if dot > 0 => (&name[..dot], &name[dot + 1..]),
+        _ => (name, ""),
+    };
+    let (base, base_lossy) = short_part(base, 8);
+    let (ext, ext_lossy) = short_part(ext, 3);
+    let exact = !base_lossy && !ext_lossy && !base.is_empty();
+
+    let mut short = [b' '; 11];
+    short[8..8 + ext.len()].copy_from_slice(&ext);
+    if exact {
+        short[..base.len()].copy_from_slice(&base);
+    }
+
+    if !exact || !used.insert(short) {
+        // Numeric tail: "LONGNA~1", "LONGNA~2", ...
+        for n in 1.. {
+            let tail = format!("~{}", n);
+            let keep = base.len().min(8 - tail.len());
+            short[..8].fill(b' ');
+            short[..keep].copy_from_slice(&base[..keep]);
+            short[keep..keep + tail.len()].copy_from_slice(tail.as_bytes());
+            if used.insert(short) {
+                break;
+            }
+        }
+    }
+
+    // The long name records what the short one cannot: case, length, symbols
+    let long = (!exact || name.chars().any(|c| c.is_ascii_lowercase())).then(|| name.encode_utf16().collect());
+    EntryName { short, long }
+}
+
+/// Upper-case `part` into short-name characters, at most `max` of them;
+/// reports whether anything was changed or dropped
+fn short_part(part: &str, max: usize) -> (Vec<u8>, bool) {
+    let mut lossy = false;
+    let mut out = Vec::new();
+    for c in part.chars() {
+        let byte = match c {
+            ' ' | '.' => {
+                lossy = true;
+                continue;
+            }
+            c if c.is_ascii_alphanumeric() || SHORT_NAME_SPECIALS.contains(c) => c.to_ascii_uppercase() as u8,
+            _ => {
+                lossy = true;
+                b'_'
+            }
+        };
+        if out.len() == max {
+            lossy = true;
+            break;
+        }
+        out.push(byte);
+    }
+    (out, lossy)
+}
+
+/// The LFN entries for `long`, last part first as they are stored
+fn long_entries(long: &[u16], short: &[u8; 11]) -> Vec<u8> {
+    let checksum = short.iter().fold(0u8, |sum, &byte| sum.rotate_right(1).wrapping_add(byte));
+
+    // NUL-terminated unless it fills the last entry exactly, then 0xFFFF padded
+    let count = long.len().div_ceil(LFN_UNITS);
+    let mut units = long.to_vec();
+    if units.len() < count * LFN_UNITS {
+        units.push(0);
+    }
+    units.resize(count * LFN_UNITS, 0xFFFF);
+
+    let mut entries = Vec::with_capacity(count * DIR_ENTRY_SIZE);
+    for index in (0..count).rev() {
+        let mut entry = [0u8; DIR_ENTRY_SIZE];
+        entry[0] = (index + 1) as u8 | if index == count - 1 { 0x40 } else { 0 };
+        entry[11] = ATTR_LONG_NAME;
+        entry[13] = checksum;
+
+        // Characters sit in three runs: bytes 1..11, 14..26 and 28..32
+        let chunk = &units[index * LFN_UNITS..(index + 1) * LFN_UNITS];
+        let offsets = (1..11).step_by(2).chain((14..26).step_by(2)).chain((28..32).step_by(2));
+        for (unit, offset) in chunk.iter().zip(offsets) {
+            entry[offset..offset + 2].copy_from_slice(&unit.to_le_bytes());
+        }
+        entries.extend_from_slice(&entry);
+    }
+    entries
+}
@@ -0,0 +1,258 @@
+//! mkimage: build initramfs archives and disk images for rusteze
+//!
+//! A host-side tool, built apart from the kernel:
+//!
+//! ```text
+//! cd tools/mkimage
+//! cargo run -- cpio [--crc] <dir> <out.cpio>
+//! cargo run -- fat32 [--size SIZE] [--label LABEL] <dir> <out.img>
+//! cargo run -- disk [--table mbr|gpt] [--size SIZE] [--label LABEL] <dir> <out.img>
+//! cargo run -- rfs [--size SIZE] [--label LABEL] [<dir>] <out.img>
+//! cargo run -- fsck <image>
+//! cargo run -- fixtures <out-dir>
+//! ```
+//!
+//! `cpio` packs a directory into the newc archive the kernel unpacks as its
+//! initramfs. `fat32` formats a bare FAT32 volume holding the directory;
+//! `disk` puts one in a single partition behind an MBR or GPT. `rfs` makes
+//! a volume of the kernel's own filesystem, empty if no directory is given,
+//! and `fsck` checks one. `fixtures` builds all of these from
+//! `tools/fixtures`, the checked-in test data.
+//! `cargo test` builds the same images and reads each one back.
+//!
+//! Output depends only on the input files and their permissions: owners
+//! are dropped and every time stamp is `SOURCE_DATE_EPOCH` (0, or 1980 on
+//! FAT, if unset).
+
+mod cpio;
+mod disk;
+mod fat32;
+mod rfs;
+#[cfg(test)]
+mod tests;
+mod tree;
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use disk::{PartitionTable, SECTOR_SIZE};
+
+/// Image size when --size is not given
+const DEFAULT_SIZE: u64 = 64 * 1024 * 1024;
+const DEFAULT_LABEL: &str = "RUSTEZE";
+
+const USAGE: &str = "\
+usage: mkimage cpio [--crc] <dir> <out.cpio>
+       mkimage fat32 [--size SIZE] [--label LABEL] <dir> <out.img>
+       mkimage disk [--table mbr|gpt] [--size SIZE] [--label LABEL] <dir> <out.img>
+       mkimage rfs [--size SIZE] [--label LABEL] [<dir>] <out.img>
+       mkimage fsck <image>
+       mkimage fixtures <out-dir>
+
+SIZE is in bytes, or with a K, M or G suffix (default 64M).";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(message) => {
+            eprintln!("mkimage: {}", message);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(args: &[String]) -> Result<(), String> {
+    let Some((command, rest)) = args.split_first() else {
+        return Err(USAGE.into());
+    };
+    let options = Options::parse(rest)?;
+    let mtime = source_date_epoch()?;
+
+    match (command.as_str(), options.paths.as_slice()) {
+        ("cpio", [dir, out]) => {
+            let archive = cpio::pack(&read_tree(dir)?, mtime, options.crc);
+            write(out, &archive)
+        }
+        ("fat32", [dir, out]) => {
+            let sectors = sector_count(options.size)?;
+            let image = fat32::build(&read_tree(dir)?, sectors, &options.label, 0, mtime)?;
+            write(out, &image)
+        }
+        ("disk", [dir, out]) => {
+            let image = disk_image(&read_tree(dir)?, options.table, options.size, &options.label, mtime)?;
+            write(out, &image)
+        }
+        ("rfs", [dir, out]) => {
+            let image = rfs::build(&read_tree(dir)?, rfs_blocks(options.size)?, &options.label, mtime)?;
+            write(out, &image)
+        }
+        ("rfs", [out]) => {
+            let image = rfs::build(&[], rfs_blocks(options.size)?, &options.label, mtime)?;
+            write(out, &image)
+        }
+        ("fsck", [image]) => fsck(image),
+        ("fixtures", [out]) => build_fixtures(Path::new(out), mtime),
+        _ => Err(USAGE.into()),
+    }
+}
+
+struct Options {
+    crc: bool,
+    size: u64,
+    label: String,
+    table: PartitionTable,
+    paths: Vec<String>,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> Result<Self, String> {
+        let mut options = Options {
+            crc: false,
+            size: DEFAULT_SIZE,
+            label: DEFAULT_LABEL.into(),
+            table: PartitionTable::Mbr,
+            paths: Vec::new(),
+        };
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+            match arg.as_str() {
+                "--crc" => options.crc = true,
+                "--size" => options.size = parse_size(value()?)?,
+                "--label" => options.label = value()?.clone(),
+                "--table" => {
+                    options.table = match value()?.as_str() {
+                        "mbr" => PartitionTable::Mbr,
+                        "gpt" => PartitionTable::Gpt,
+                        other => return Err(format!("unknown partition table '{}'", other)),
+                    }
+                }
+                flag if flag.starts_with("--") => return Err(format!("unknown option '{}'\n{}", flag, USAGE)),
+                path => options.paths.push(path.into()),
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// Parse "512", "64K", "64M" or "1G"
+fn parse_size(text: &str) -> Result<u64, String> {
+    let (digits, unit) = match text.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+        Some((index, _)) => text.split_at(index),
+        None => (text, ""),
+    };
+    let multiplier = match unit.to_ascii_uppercase().as_str() {
+        "" => 1,
+        "K" => 1024,
+        "M" => 1024 * 1024,
+        "G" => 1024 * 1024 * 1024,
+        _ => return Err(format!("bad size '{}'", text)),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format!("bad size '{}'", text))
+}
+
+fn sector_count(size: u64) -> Result<u32, String> {
+    if !size.is_multiple_of(SECTOR_SIZE as u64) {
+        return Err(format!("size must be a multiple of {} bytes", SECTOR_SIZE));
+    }
+    u32::try_from(size / SECTOR_SIZE as u64).map_err(|_| "size too large".into())
+}
+
+/// Size in rfs blocks
+fn rfs_blocks(size: u64) -> Result<u64, String> {
+    if !size.is_multiple_of(4096) {
+        return Err("size must be a multiple of 4096 bytes".into());
+    }
+    Ok(size / 4096)
+}
+
+/// Check an rfs image, failing if anything is wrong with it
+fn fsck(path: &str) -> Result<(), String> {
+    let image = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+    let report = rfs::check(&image).map_err(|e| format!("{}: {}", path, e))?;
+    if report.replayed > 0 {
+        println!("{}: replayed {} journaled blocks", path, report.replayed);
+    }
+    for problem in &report.problems {
+        println!("{}: {}", path, problem);
+    }
+    println!(
+        "{}: {} files, {} directories, {}/{} blocks, {} orphans",
+        path, report.files, report.directories, report.used_blocks, report.block_count, report.orphans
+    );
+    match report.problems.len() {
+        0 => Ok(()),
+        errors => Err(format!("{}: {} errors", path, errors)),
+    }
+}
+
+/// Time stamp for every entry, from SOURCE_DATE_EPOCH
+fn source_date_epoch() -> Result<u32, String> {
+    match env::var("SOURCE_DATE_EPOCH") {
+        Ok(value) => value.parse().map_err(|_| format!("bad SOURCE_DATE_EPOCH '{}'", value)),
+        Err(_) => Ok(0),
+    }
+}
+
+fn read_tree(dir: &str) -> Result<Vec<tree::Node>, String> {
+    tree::read_tree(Path::new(dir)).map_err(|e| format!("{}: {}", dir, e))
+}
+
+fn write(path: impl AsRef<Path>, data: &[u8]) -> Result<(), String> {
+    let path = path.as_ref();
+    fs::write(path, data).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// A partitioned disk with one FAT32 partition holding `nodes`
+fn disk_image(nodes: &[tree::Node], table: PartitionTable, size: u64, label: &str, mtime: u32) -> Result<Vec<u8>, String> {
+    let total_sectors = sector_count(size)?;
+    let (first, last) = table
+        .partition_range(total_sectors as u64)
+        .ok_or("disk too small for a partition")?;
+
+    let volume = fat32::build(nodes, (last - first + 1) as u32, label, first as u32, mtime)?;
+    let mut image = vec![0; size as usize];
+    table.write(&mut image, first, last, label);
+    let start = first as usize * SECTOR_SIZE;
+    image[start..start + volume.len()].copy_from_slice(&volume);
+    Ok(image)
+}
+
+/// Build every test image from the checked-in fixture directory
+fn build_fixtures(out: &Path, mtime: u32) -> Result<(), String> {
+    let nodes = read_fixtures()?;
+    fs::create_dir_all(out).map_err(|e| format!("{}: {}", out.display(), e))?;
+
+    for (name, data) in fixture_images(&nodes, mtime)? {
+        let path = out.join(name);
+        write(&path, &data)?;
+        println!("{}", path.display());
+    }
+    Ok(())
+}
+
+/// The checked-in test data in `tools/fixtures`
+fn read_fixtures() -> Result<Vec<tree::Node>, String> {
+    let source: PathBuf = [env!("CARGO_MANIFEST_DIR"), "..", "fixtures"].iter().collect();
+    read_tree(&source.to_string_lossy())
+}
+
+/// Every test image, by file name
+fn fixture_images(nodes: &[tree::Node], mtime: u32) -> Result<Vec<(&'static str, Vec<u8>)>, String> {
+    Ok(vec![
+        ("initramfs.cpio", cpio::pack(nodes, mtime, false)),
+        ("initramfs-crc.cpio", cpio::pack(nodes, mtime, true)),
+        ("fat32.img", fat32::build(nodes, sector_count(DEFAULT_SIZE)?, DEFAULT_LABEL, 0, mtime)?),
+        ("disk-mbr.img", disk_image(nodes, PartitionTable::Mbr, DEFAULT_SIZE, DEFAULT_LABEL, mtime)?),
+        ("disk-gpt.img", disk_image(nodes, PartitionTable::Gpt, DEFAULT_SIZE, DEFAULT_LABEL, mtime)?),
+        ("rfs.img", rfs::build(nodes, rfs_blocks(DEFAULT_SIZE)?, DEFAULT_LABEL, mtime)?),
+    ])
+}
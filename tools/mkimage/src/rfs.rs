@@ -0,0 +1,864 @@
+//! rfs image builder and checker
+//!
+//! rfs is the kernel's own journaled filesystem (see `src/rfs.rs` in the
+//! kernel for the format). `build` lays a directory tree out in one pass:
+//! inodes and blocks are handed out in order, every file is a single
+//! extent, and each directory's B-tree is bulk-loaded from its entries
+//! sorted by name hash. `check` is the host side of the kernel's fsck: it
+//! replays a committed journal transaction in memory, then checks every
+//! inode, extent, directory node, bitmap and link count.
+
+use std::collections::HashMap;
+
+use crate::disk::crc32;
+use crate::tree::{Node, NodeKind};
+
+const BLOCK_SIZE: usize = 4096;
+const BITS_PER_BLOCK: u64 = (BLOCK_SIZE * 8) as u64;
+
+const MAGIC: u32 = 0x3153_4652; // "RFS1"
+const JOURNAL_MAGIC: u32 = 0x4A53_4652; // "RFSJ"
+const DESCRIPTOR_MAGIC: u32 = 0x4453_4652; // "RFSD"
+const COMMIT_MAGIC: u32 = 0x4353_4652; // "RFSC"
+const VERSION: u32 = 1;
+const SB_CHECKSUM: usize = BLOCK_SIZE - 4;
+const LABEL_LEN: usize = 16;
+
+const INODE_SIZE: usize = 128;
+const INODES_PER_BLOCK: u32 = (BLOCK_SIZE / INODE_SIZE) as u32;
+const BYTES_PER_INODE: u64 = 16 * 1024;
+const MAX_INODES: u64 = 1 << 24;
+const ROOT_INO: u32 = 1;
+
+const JOURNAL_BLOCKS: u64 = 32;
+const MAX_TXN_BLOCKS: usize = 24;
+const MIN_DATA_BLOCKS: u64 = 16;
+
+const NODE_HEADER: usize = 16;
+const NODE_SPACE: usize = BLOCK_SIZE - NODE_HEADER;
+const DIR_NODE_MAGIC: u16 = 0x4452;
+const EXTENT_NODE_MAGIC: u16 = 0x4552;
+const MAX_DEPTH: u8 = 8;
+const EXTENT_SIZE: usize = 16;
+const ROOT_SLOTS: usize = 4;
+const NODE_SLOTS: usize = NODE_SPACE / EXTENT_SIZE;
+const RECORD_HEADER: usize = 14;
+const INDEX_RECORD: usize = 16;
+
+const S_IFMT: u16 = 0o170000;
+const S_IFDIR: u16 = 0o040000;
+const S_IFREG: u16 = 0o100000;
+const S_IFLNK: u16 = 0o120000;
+
+fn get_u16(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes(bytes[at..at + 2].try_into().unwrap())
+}
+
+fn get_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+fn get_u64(bytes: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap())
+}
+
+fn put(bytes: &mut [u8], at: usize, value: &[u8]) {
+    bytes[at..at + value.len()].copy_from_slice(value);
+}
+
+/// FNV-1a of the name with the top bit cleared: the directory key
+fn name_hash(name: &[u8]) -> u64 {
+    let hash = name.iter().fold(0xCBF2_9CE4_8422_2325u64, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01B3)
+    });
+    hash >> 1
+}
+
+/// Where everything is on a volume
+struct Layout {
+    block_count: u64,
+    inode_count: u32,
+    journal_start: u64,
+    bitmap_start: u64,
+    inode_bitmap_start: u64,
+    inode_table_start: u64,
+    data_start: u64,
+}
+
+impl Layout {
+    fn new(block_count: u64, inode_count: u32) -> Option<Self> {
+        if inode_count == 0 || !inode_count.is_multiple_of(INODES_PER_BLOCK) {
+            return None;
+        }
+        let journal_start = 1;
+        let bitmap_start = journal_start + JOURNAL_BLOCKS;
+        let inode_bitmap_start = bitmap_start + block_count.div_ceil(BITS_PER_BLOCK);
+        let inode_table_start = inode_bitmap_start + (inode_count as u64).div_ceil(BITS_PER_BLOCK);
+        let data_start = inode_table_start + (inode_count / INODES_PER_BLOCK) as u64;
+        (data_start + MIN_DATA_BLOCKS <= block_count).then_some(Layout {
+            block_count,
+            inode_count,
+            journal_start,
+            bitmap_start,
+            inode_bitmap_start,
+            inode_table_start,
+            data_start,
+        })
+    }
+
+    /// The layout the kernel's mkfs picks: one inode per 16 KiB
+    fn for_blocks(block_count: u64) -> Option<Self> {
+        let inodes = (block_count * BLOCK_SIZE as u64 / BYTES_PER_INODE).clamp(INODES_PER_BLOCK as u64, MAX_INODES);
+        Self::new(block_count, (inodes as u32).next_multiple_of(INODES_PER_BLOCK))
+    }
+
+    fn parse(sb: &[u8]) -> Result<Self, String> {
+        if get_u32(sb, 0) != MAGIC || get_u32(sb, 4) != VERSION {
+            return Err("not an rfs volume".into());
+        }
+        if get_u32(sb, SB_CHECKSUM) != crc32(&sb[..SB_CHECKSUM]) {
+            return Err("superblock checksum mismatch".into());
+        }
+        if get_u32(sb, 8) != BLOCK_SIZE as u32 || get_u32(sb, 12) != INODE_SIZE as u32 || get_u32(sb, 28) != JOURNAL_BLOCKS as u32 {
+            return Err("unsupported block, inode or journal size".into());
+        }
+        let layout = Self::new(get_u64(sb, 16), get_u32(sb, 24)).ok_or("bad volume geometry")?;
+        let stored: Vec<u64> = (32..72).step_by(8).map(|at| get_u64(sb, at)).collect();
+        let expected = [
+            layout.journal_start,
+            layout.bitmap_start,
+            layout.inode_bitmap_start,
+            layout.inode_table_start,
+            layout.data_start,
+        ];
+        if stored != expected || get_u32(sb, 72) != ROOT_INO {
+            return Err("superblock layout does not match its geometry".into());
+        }
+        Ok(layout)
+    }
+
+    fn superblock(&self, label: &str, created: i64) -> [u8; BLOCK_SIZE] {
+        let mut sb = [0u8; BLOCK_SIZE];
+        put(&mut sb, 0, &MAGIC.to_le_bytes());
+        put(&mut sb, 4, &VERSION.to_le_bytes());
+        put(&mut sb, 8, &(BLOCK_SIZE as u32).to_le_bytes());
+        put(&mut sb, 12, &(INODE_SIZE as u32).to_le_bytes());
+        put(&mut sb, 16, &self.block_count.to_le_bytes());
+        put(&mut sb, 24, &self.inode_count.to_le_bytes());
+        put(&mut sb, 28, &(JOURNAL_BLOCKS as u32).to_le_bytes());
+        put(&mut sb, 32, &self.journal_start.to_le_bytes());
+        put(&mut sb, 40, &self.bitmap_start.to_le_bytes());
+        put(&mut sb, 48, &self.inode_bitmap_start.to_le_bytes());
+        put(&mut sb, 56, &self.inode_table_start.to_le_bytes());
+        put(&mut sb, 64, &self.data_start.to_le_bytes());
+        put(&mut sb, 72, &ROOT_INO.to_le_bytes());
+        put(&mut sb, 80, &created.to_le_bytes());
+        let label = &label.as_bytes()[..label.len().min(LABEL_LEN)];
+        put(&mut sb, 88, label);
+        let checksum = crc32(&sb[..SB_CHECKSUM]);
+        put(&mut sb, SB_CHECKSUM, &checksum.to_le_bytes());
+        sb
+    }
+}
+
+/// The fields of an inode that the builder and checker use
+struct Inode {
+    mode: u16,
+    links: u16,
+    parent: u32,
+    size: u64,
+    blocks: u64,
+    depth: u16,
+    count: u16,
+    entries: u32,
+    root: [u8; ROOT_SLOTS * EXTENT_SIZE],
+}
+
+impl Default for Inode {
+    fn default() -> Self {
+        Self::parse(&[0; INODE_SIZE])
+    }
+}
+
+impl Inode {
+    fn parse(raw: &[u8]) -> Self {
+        Inode {
+            mode: get_u16(raw, 0),
+            links: get_u16(raw, 2),
+            parent: get_u32(raw, 12),
+            size: get_u64(raw, 16),
+            blocks: get_u64(raw, 48),
+            depth: get_u16(raw, 56),
+            count: get_u16(raw, 58),
+            entries: get_u32(raw, 60),
+            root: raw[64..128].try_into().unwrap(),
+        }
+    }
+
+    /// The inode with every time stamp set to `mtime`, and no owner
+    fn encode(&self, mtime: i64) -> [u8; INODE_SIZE] {
+        let mut raw = [0u8; INODE_SIZE];
+        put(&mut raw, 0, &self.mode.to_le_bytes());
+        put(&mut raw, 2, &self.links.to_le_bytes());
+        put(&mut raw, 12, &self.parent.to_le_bytes());
+        put(&mut raw, 16, &self.size.to_le_bytes());
+        for at in [24, 32, 40] {
+            put(&mut raw, at, &mtime.to_le_bytes());
+        }
+        put(&mut raw, 48, &self.blocks.to_le_bytes());
+        put(&mut raw, 56, &self.depth.to_le_bytes());
+        put(&mut raw, 58, &self.count.to_le_bytes());
+        put(&mut raw, 60, &self.entries.to_le_bytes());
+        raw[64..128].copy_from_slice(&self.root);
+        raw
+    }
+}
+
+/// A file's extent: `len` blocks from file block `logical`, stored from
+/// disk block `physical`
+struct Extent {
+    logical: u64,
+    len: u64,
+    physical: u64,
+}
+
+fn extent_at(bytes: &[u8], at: usize) -> Extent {
+    Extent {
+        logical: get_u32(bytes, at) as u64,
+        len: get_u32(bytes, at + 4) as u64,
+        physical: get_u64(bytes, at + 8),
+    }
+}
+
+/// Build an rfs volume of `blocks` 4 KiB blocks holding `nodes`
+///
+/// `mtime` is the Unix time stamped on every inode.
+pub fn build(nodes: &[Node], blocks: u64, label: &str, mtime: u32) -> Result<Vec<u8>, String> {
+    let layout = Layout::for_blocks(blocks).ok_or("volume too small for rfs")?;
+    let mut builder = Builder {
+        image: vec![0; blocks as usize * BLOCK_SIZE],
+        next_block: layout.data_start,
+        next_inode: ROOT_INO + 1,
+        links: HashMap::new(),
+        mtime: mtime as i64,
+        layout,
+    };
+    builder.write_directory(nodes, ROOT_INO, ROOT_INO, 0o755)?;
+    builder.finish(label);
+    Ok(builder.image)
+}
+
+struct Builder {
+    image: Vec<u8>,
+    layout: Layout,
+    next_block: u64,
+    next_inode: u32,
+    /// Inode given to each host file with several links
+    links: HashMap<(u64, u64), u32>,
+    mtime: i64,
+}
+
+impl Builder {
+    fn block_mut(&mut self, block: u64) -> &mut [u8] {
+        let start = block as usize * BLOCK_SIZE;
+        &mut self.image[start..start + BLOCK_SIZE]
+    }
+
+    fn allocate(&mut self, count: u64) -> Result<u64, String> {
+        let first = self.next_block;
+        if first + count > self.layout.block_count {
+            return Err("volume is full".into());
+        }
+        self.next_block += count;
+        Ok(first)
+    }
+
+    fn allocate_inode(&mut self) -> Result<u32, String> {
+        let ino = self.next_inode;
+        if ino > self.layout.inode_count {
+            return Err("volume is out of inodes".into());
+        }
+        self.next_inode += 1;
+        Ok(ino)
+    }
+
+    fn inode_offset(&self, ino: u32) -> usize {
+        let index = ino - 1;
+        (self.layout.inode_table_start as usize + (index / INODES_PER_BLOCK) as usize) * BLOCK_SIZE
+            + (index % INODES_PER_BLOCK) as usize * INODE_SIZE
+    }
+
+    fn write_inode(&mut self, ino: u32, inode: &Inode) {
+        let at = self.inode_offset(ino);
+        let raw = inode.encode(self.mtime);
+        self.image[at..at + INODE_SIZE].copy_from_slice(&raw);
+    }
+
+    /// Write a file or symlink's contents as one extent
+    fn write_file(&mut self, ino: u32, mode: u16, data: &[u8]) -> Result<(), String> {
+        let count = data.len().div_ceil(BLOCK_SIZE) as u64;
+        if count > u32::MAX as u64 {
+            return Err("file too large for one extent".into());
+        }
+        let mut inode = Inode {
+            mode,
+            links: 1,
+            size: data.len() as u64,
+            blocks: count,
+            ..Inode::default()
+        };
+        if count > 0 {
+            let first = self.allocate(count)?;
+            let start = first as usize * BLOCK_SIZE;
+            self.image[start..start + data.len()].copy_from_slice(data);
+            put(&mut inode.root, 4, &(count as u32).to_le_bytes());
+            put(&mut inode.root, 8, &first.to_le_bytes());
+            inode.count = 1;
+        }
+        self.write_inode(ino, &inode);
+        Ok(())
+    }
+
+    /// Write directory `ino` holding `nodes`, and everything below it
+    fn write_directory(&mut self, nodes: &[Node], ino: u32, parent: u32, mode: u32) -> Result<(), String> {
+        // The root node comes first and never moves
+        let root = self.allocate(1)?;
+        let mut records = Vec::new();
+        let mut subdirectories = Vec::new();
+        for node in nodes {
+            let permissions = (node.mode & 0o7777) as u16;
+            let (child, kind) = match &node.kind {
+                NodeKind::Directory(children) => {
+                    let child = self.allocate_inode()?;
+                    subdirectories.push((children, child, node.mode));
+                    (child, S_IFDIR)
+                }
+                NodeKind::File { data, link_id } => {
+                    let existing = link_id.and_then(|id| self.links.get(&id).copied());
+                    let child = match existing {
+                        Some(child) => {
+                            let at = self.inode_offset(child) + 2;
+                            let links = get_u16(&self.image, at) + 1;
+                            put(&mut self.image, at, &links.to_le_bytes());
+                            child
+                        }
+                        None => {
+                            let child = self.allocate_inode()?;
+                            self.write_file(child, S_IFREG | permissions, data)?;
+                            if let Some(id) = link_id {
+                                self.links.insert(*id, child);
+                            }
+                            child
+                        }
+                    };
+                    (child, S_IFREG)
+                }
+                NodeKind::Symlink(target) => {
+                    if target.is_empty() || target.len() >= BLOCK_SIZE {
+                        return Err(format!("{}: symlink target too long", node.name));
+                    }
+                    let child = self.allocate_inode()?;
+                    self.write_file(child, S_IFLNK | 0o777, target.as_bytes())?;
+                    (child, S_IFLNK)
+                }
+            };
+            if node.name.len() > 255 {
+                return Err(format!("{}: name too long", node.name));
+            }
+            let mut record = Vec::with_capacity(RECORD_HEADER + node.name.len());
+            record.extend_from_slice(&name_hash(node.name.as_bytes()).to_le_bytes());
+            record.extend_from_slice(&child.to_le_bytes());
+            record.push((kind >> 12) as u8);
+            record.push(node.name.len() as u8);
+            record.extend_from_slice(node.name.as_bytes());
+            records.push(record);
+        }
+
+        records.sort_by_key(|record| get_u64(record, 0));
+        if let Some(pair) = records.windows(2).find(|pair| get_u64(&pair[0], 0) == get_u64(&pair[1], 0)) {
+            let name = |record: &[u8]| String::from_utf8_lossy(&record[RECORD_HEADER..]).into_owned();
+            return Err(format!("'{}' and '{}' have the same name hash", name(&pair[0]), name(&pair[1])));
+        }
+        let entries = records.len() as u32;
+        let nodes_used = self.write_tree(root, records)?;
+
+        let inode = Inode {
+            mode: S_IFDIR | (mode & 0o7777) as u16,
+            links: 2 + subdirectories.len() as u16,
+            parent,
+            blocks: nodes_used,
+            entries,
+            root: {
+                let mut root_bytes = [0u8; ROOT_SLOTS * EXTENT_SIZE];
+                put(&mut root_bytes, 0, &root.to_le_bytes());
+                root_bytes
+            },
+            ..Inode::default()
+        };
+        self.write_inode(ino, &inode);
+
+        for (children, child, mode) in subdirectories {
+            self.write_directory(children, child, ino, mode)?;
+        }
+        Ok(())
+    }
+
+    /// Bulk-load a directory B-tree from records sorted by hash, with its
+    /// root at `root`; returns the number of nodes
+    fn write_tree(&mut self, root: u64, records: Vec<Vec<u8>>) -> Result<u64, String> {
+        let mut level = 0u8;
+        let mut nodes = pack(records);
+        let mut used = 1;
+        while nodes.len() > 1 {
+            if level + 1 >= MAX_DEPTH {
+                return Err("directory too large".into());
+            }
+            let mut index = Vec::with_capacity(nodes.len());
+            for node in nodes {
+                let block = self.allocate(1)?;
+                used += 1;
+                let lowest = get_u64(&node[0], 0);
+                write_node(self.block_mut(block), level, &node);
+                let mut record = Vec::with_capacity(INDEX_RECORD);
+                record.extend_from_slice(&lowest.to_le_bytes());
+                record.extend_from_slice(&block.to_le_bytes());
+                index.push(record);
+            }
+            nodes = pack(index);
+            level += 1;
+        }
+        let top = nodes.pop().unwrap_or_default();
+        write_node(self.block_mut(root), level, &top);
+        Ok(used)
+    }
+
+    /// Write the bitmaps, an empty journal and the superblock
+    fn finish(&mut self, label: &str) {
+        let layout = &self.layout;
+        let (bitmap_start, inode_bitmap_start) = (layout.bitmap_start, layout.inode_bitmap_start);
+        let (used_blocks, used_inodes) = (self.next_block, self.next_inode as u64 - 1);
+        for (start, count) in [(bitmap_start, used_blocks), (inode_bitmap_start, used_inodes)] {
+            let base = start as usize * BLOCK_SIZE;
+            for bit in 0..count as usize {
+                self.image[base + bit / 8] |= 1 << (bit % 8);
+            }
+        }
+
+        let header = self.block_mut(self.layout.journal_start);
+        put(header, 0, &JOURNAL_MAGIC.to_le_bytes());
+        put(header, 8, &1u64.to_le_bytes());
+
+        let sb = self.layout.superblock(label, self.mtime);
+        self.block_mut(0).copy_from_slice(&sb);
+    }
+}
+
+/// Group records into node-sized runs
+fn pack(records: Vec<Vec<u8>>) -> Vec<Vec<Vec<u8>>> {
+    let mut nodes: Vec<Vec<Vec<u8>>> = vec![Vec::new()];
+    let mut space = NODE_SPACE;
+    for record in records {
+        if record.len() > space {
+            nodes.push(Vec::new());
+            space = NODE_SPACE;
+        }
+        space -= record.len();
+        nodes.last_mut().unwrap().push(record);
+    }
+    nodes
+}
+
+fn write_node(block: &mut [u8], level: u8, records: &[Vec<u8>]) {
+    let used: usize = records.iter().map(Vec::len).sum();
+    put(block, 0, &DIR_NODE_MAGIC.to_le_bytes());
+    block[2] = level;
+    put(block, 4, &(records.len() as u16).to_le_bytes());
+    put(block, 6, &(used as u16).to_le_bytes());
+    let mut at = NODE_HEADER;
+    for record in records {
+        put(block, at, record);
+        at += record.len();
+    }
+}
+
+/// What `check` found
+pub struct Report {
+    /// Blocks written back from the journal before checking
+    pub replayed: usize,
+    pub files: u32,
+    pub directories: u32,
+    /// Inodes with no links, which the kernel frees at its next mount
+    pub orphans: u32,
+    pub used_blocks: u64,
+    pub block_count: u64,
+    pub problems: Vec<String>,
+}
+
+/// Check the rfs volume in `image`
+///
+/// Fails only if the image is not an rfs volume at all; damage is listed
+/// in the report.
+pub fn check(image: &[u8]) -> Result<Report, String> {
+    if image.len() < BLOCK_SIZE {
+        return Err("image too small".into());
+    }
+    let layout = Layout::parse(&image[..BLOCK_SIZE])?;
+    if layout.block_count as usize * BLOCK_SIZE > image.len() {
+        return Err("image is shorter than the volume".into());
+    }
+    let mut image = image[..layout.block_count as usize * BLOCK_SIZE].to_vec();
+    let replayed = replay(&mut image, &layout)?;
+
+    let mut checker = Checker {
+        image: &image,
+        layout: &layout,
+        owner: vec![0; layout.block_count as usize],
+        report: Report {
+            replayed,
+            files: 0,
+            directories: 0,
+            orphans: 0,
+            used_blocks: 0,
+            block_count: layout.block_count,
+            problems: Vec::new(),
+        },
+    };
+    checker.check_inodes();
+    checker.check_bitmap();
+    checker.check_links();
+    Ok(checker.report)
+}
+
+/// Apply a committed journal transaction to `image`, as mounting would
+fn replay(image: &mut [u8], layout: &Layout) -> Result<usize, String> {
+    let block = |image: &[u8], n: u64| image[n as usize * BLOCK_SIZE..(n as usize + 1) * BLOCK_SIZE].to_vec();
+    let journal = layout.journal_start;
+    let header = block(image, journal);
+    if get_u32(&header, 0) != JOURNAL_MAGIC {
+        return Err("bad journal header".into());
+    }
+    let sequence = get_u64(&header, 8);
+
+    let descriptor = block(image, journal + 1);
+    let count = get_u32(&descriptor, 4) as usize;
+    if get_u32(&descriptor, 0) != DESCRIPTOR_MAGIC || get_u64(&descriptor, 8) != sequence || count == 0 || count > MAX_TXN_BLOCKS {
+        return Ok(0);
+    }
+    let copies: Vec<Vec<u8>> = (0..count as u64).map(|i| block(image, journal + 2 + i)).collect();
+    let checksum = copies
+        .iter()
+        .fold(crc32(&descriptor), |sum, copy| sum.rotate_left(5) ^ crc32(copy));
+    let commit = block(image, journal + 2 + count as u64);
+    if get_u32(&commit, 0) != COMMIT_MAGIC
+        || get_u32(&commit, 4) as usize != count
+        || get_u64(&commit, 8) != sequence
+        || get_u32(&commit, 16) != checksum
+    {
+        return Ok(0); // never committed
+    }
+
+    for (i, copy) in copies.iter().enumerate() {
+        let home = get_u64(&descriptor, 16 + i * 8);
+        if home >= layout.block_count || (journal..journal + JOURNAL_BLOCKS).contains(&home) {
+            return Err(format!("journal writes to block {}, outside the volume", home));
+        }
+        let start = home as usize * BLOCK_SIZE;
+        image[start..start + BLOCK_SIZE].copy_from_slice(copy);
+    }
+    let start = journal as usize * BLOCK_SIZE;
+    put(image, start + 8, &(sequence + 1).to_le_bytes());
+    Ok(count)
+}
+
+struct Checker<'a> {
+    image: &'a [u8],
+    layout: &'a Layout,
+    /// Inode using each block (u32::MAX for the volume's own metadata)
+    owner: Vec<u32>,
+    report: Report,
+}
+
+impl<'a> Checker<'a> {
+    fn block(&self, n: u64) -> &'a [u8] {
+        &self.image[n as usize * BLOCK_SIZE..(n as usize + 1) * BLOCK_SIZE]
+    }
+
+    fn bit(&self, bitmap: u64, bit: u64) -> bool {
+        self.image[bitmap as usize * BLOCK_SIZE + (bit / 8) as usize] & (1 << (bit % 8)) != 0
+    }
+
+    fn inode(&self, ino: u32) -> Inode {
+        let index = ino - 1;
+        let block = self.block(self.layout.inode_table_start + (index / INODES_PER_BLOCK) as u64);
+        let at = (index % INODES_PER_BLOCK) as usize * INODE_SIZE;
+        Inode::parse(&block[at..at + INODE_SIZE])
+    }
+
+    fn problem(&mut self, message: String) {
+        self.report.problems.push(message);
+    }
+
+    /// Claim `block` for `ino`; false if it is not a data block
+    fn claim(&mut self, ino: u32, block: u64) -> bool {
+        if block < self.layout.data_start || block >= self.layout.block_count {
+            self.problem(format!("inode {} uses block {}, outside the data area", ino, block));
+            return false;
+        }
+        match self.owner[block as usize] {
+            0 => self.owner[block as usize] = ino,
+            other => self.problem(format!("block {} is used by inodes {} and {}", block, other, ino)),
+        }
+        true
+    }
+
+    fn check_inodes(&mut self) {
+        for block in 0..self.layout.data_start {
+            self.owner[block as usize] = u32::MAX;
+        }
+        for ino in 1..=self.layout.inode_count {
+            let inode = self.inode(ino);
+            let allocated = self.bit(self.layout.inode_bitmap_start, (ino - 1) as u64);
+            if allocated != (inode.mode != 0) {
+                let state = if allocated { "free but marked in use" } else { "in use but marked free" };
+                self.problem(format!("inode {} is {}", ino, state));
+            }
+            if inode.mode == 0 {
+                continue;
+            }
+            if inode.links == 0 {
+                self.report.orphans += 1;
+            }
+            match inode.mode & S_IFMT {
+                S_IFDIR => {
+                    self.report.directories += 1;
+                    self.check_directory(ino, &inode);
+                }
+                S_IFREG | S_IFLNK => {
+                    self.report.files += 1;
+                    self.check_extents(ino, &inode);
+                }
+                _ => {
+                    self.report.files += 1;
+                    if inode.blocks != 0 || inode.count != 0 {
+                        self.problem(format!("special file {} has blocks", ino));
+                    }
+                }
+            }
+        }
+    }
+
+    fn check_extents(&mut self, ino: u32, inode: &Inode) {
+        if inode.depth > 1 || inode.count as usize > ROOT_SLOTS {
+            self.problem(format!("inode {} has a bad extent tree root", ino));
+            return;
+        }
+        let entries: Vec<Extent> = (0..inode.count as usize).map(|i| extent_at(&inode.root, i * EXTENT_SIZE)).collect();
+        let mut extents = Vec::new();
+        let mut used = 0;
+        if inode.depth == 0 {
+            extents = entries;
+        } else {
+            for entry in &entries {
+                if !self.claim(ino, entry.physical) {
+                    continue;
+                }
+                used += 1;
+                let leaf = self.block(entry.physical);
+                let count = get_u16(leaf, 4) as usize;
+                if get_u16(leaf, 0) != EXTENT_NODE_MAGIC || count == 0 || count > NODE_SLOTS {
+                    self.problem(format!("inode {} has a bad extent block {}", ino, entry.physical));
+                    continue;
+                }
+                extents.extend((0..count).map(|i| extent_at(leaf, NODE_HEADER + i * EXTENT_SIZE)));
+            }
+        }
+
+        let mut end = 0;
+        for extent in &extents {
+            if extent.len == 0 || extent.logical < end {
+                self.problem(format!("inode {}: extents overlap or are out of order", ino));
+            }
+            end = extent.logical + extent.len;
+            for block in extent.physical..extent.physical.saturating_add(extent.len) {
+                if !self.claim(ino, block) {
+                    break;
+                }
+            }
+            used += extent.len;
+        }
+        if end > inode.size.div_ceil(BLOCK_SIZE as u64) {
+            self.problem(format!("inode {} has blocks past its size", ino));
+        }
+        if used != inode.blocks {
+            self.problem(format!("inode {} uses {} blocks but counts {}", ino, used, inode.blocks));
+        }
+    }
+
+    fn check_directory(&mut self, ino: u32, inode: &Inode) {
+        let root = get_u64(&inode.root, 0);
+        let mut pending = vec![(root, 0, u64::MAX, None::<u8>)];
+        let (mut nodes, mut entries) = (0, 0);
+        while let Some((block, low, high, expected_level)) = pending.pop() {
+            if !self.claim(ino, block) {
+                continue;
+            }
+            nodes += 1;
+            let node = self.block(block);
+            let (level, count, used) = (node[2], get_u16(node, 4) as usize, get_u16(node, 6) as usize);
+            if get_u16(node, 0) != DIR_NODE_MAGIC || level >= MAX_DEPTH || used > NODE_SPACE {
+                self.problem(format!("directory {}: node {} is damaged", ino, block));
+                continue;
+            }
+            if expected_level.is_some_and(|expected| expected != level) {
+                self.problem(format!("directory {}: node {} is at the wrong level", ino, block));
+                continue;
+            }
+
+            // Walk the records: (offset, hash)
+            let records = &node[NODE_HEADER..NODE_HEADER + used];
+            let mut offsets = Vec::new();
+            let mut at = 0;
+            while at < used {
+                let len = if level == 0 {
+                    if at + RECORD_HEADER > used || records[at + 13] == 0 {
+                        break;
+                    }
+                    RECORD_HEADER + records[at + 13] as usize
+                } else {
+                    INDEX_RECORD
+                };
+                if at + len > used {
+                    break;
+                }
+                offsets.push(at);
+                at += len;
+            }
+            if at != used || offsets.len() != count {
+                self.problem(format!("directory {}: node {} is damaged", ino, block));
+                continue;
+            }
+            if expected_level.is_some() && count == 0 {
+                self.problem(format!("directory {}: node {} is empty", ino, block));
+            }
+
+            let hashes: Vec<u64> = offsets.iter().map(|&at| get_u64(records, at)).collect();
+            let ordered = hashes.windows(2).all(|pair| pair[0] < pair[1]);
+            let skip = usize::from(level > 0); // an index node's first key may be below its range
+            let in_range = hashes.iter().skip(skip).all(|&hash| (low..=high).contains(&hash));
+            if !ordered || !in_range {
+                self.problem(format!("directory {}: node {} is out of order", ino, block));
+            }
+            for (i, &at) in offsets.iter().enumerate() {
+                if level == 0 {
+                    let name = &records[at + RECORD_HEADER..at + RECORD_HEADER + records[at + 13] as usize];
+                    if name_hash(name) != hashes[i] {
+                        self.problem(format!("directory {}: entry '{}' has the wrong hash", ino, String::from_utf8_lossy(name)));
+                    }
+                    entries += 1;
+                } else {
+                    let child_low = if i == 0 { low } else { hashes[i] };
+                    let child_high = hashes.get(i + 1).map_or(high, |next| next.saturating_sub(1));
+                    pending.push((get_u64(records, at + 8), child_low, child_high, Some(level - 1)));
+                }
+            }
+        }
+        if nodes != inode.blocks {
+            self.problem(format!("directory {} uses {} blocks but counts {}", ino, nodes, inode.blocks));
+        }
+        if entries != inode.entries {
+            self.problem(format!("directory {} has {} entries but counts {}", ino, entries, inode.entries));
+        }
+    }
+
+    fn check_bitmap(&mut self) {
+        for block in 0..self.layout.block_count {
+            let used = self.owner[block as usize] != 0;
+            if used != self.bit(self.layout.bitmap_start, block) {
+                let state = if used { "in use but marked free" } else { "marked in use but unused" };
+                self.problem(format!("block {} is {}", block, state));
+            }
+            if used {
+                self.report.used_blocks += 1;
+            }
+        }
+    }
+
+    /// Entries (inode, type bits) of a directory whose tree checked out
+    fn entries(&self, inode: &Inode) -> Vec<(u32, u16)> {
+        let mut entries = Vec::new();
+        let mut pending = vec![(get_u64(&inode.root, 0), 0)];
+        while let Some((block, depth)) = pending.pop() {
+            if depth == MAX_DEPTH || block >= self.layout.block_count {
+                continue;
+            }
+            let node = self.block(block);
+            let (level, used) = (node[2], (get_u16(node, 6) as usize).min(NODE_SPACE));
+            let records = &node[NODE_HEADER..NODE_HEADER + used];
+            let mut at = 0;
+            while at + if level == 0 { RECORD_HEADER } else { INDEX_RECORD } <= used {
+                if level == 0 {
+                    entries.push((get_u32(records, at + 8), (records[at + 12] as u16) << 12));
+                    at += RECORD_HEADER + records[at + 13] as usize;
+                } else {
+                    pending.push((get_u64(records, at + 8), depth + 1));
+                    at += INDEX_RECORD;
+                }
+            }
+        }
+        entries
+    }
+
+    /// Walk the tree from the root, counting each inode's links
+    fn check_links(&mut self) {
+        let count = self.layout.inode_count;
+        let mut refs = vec![0u32; count as usize + 1];
+        refs[ROOT_INO as usize] = 1; // the root's parent link to itself
+        let mut pending = vec![ROOT_INO];
+        let mut visited = vec![false; count as usize + 1];
+        while let Some(dir) = pending.pop() {
+            let inode = self.inode(dir);
+            if inode.mode & S_IFMT != S_IFDIR || visited[dir as usize] {
+                self.problem(format!("inode {} is not a directory, or is reached twice", dir));
+                continue;
+            }
+            visited[dir as usize] = true;
+            refs[dir as usize] += 1; // its own link
+            for (child, kind) in self.entries(&inode) {
+                if child == 0 || child > count {
+                    self.problem(format!("directory {} has an entry for bad inode {}", dir, child));
+                    continue;
+                }
+                refs[child as usize] += 1;
+                let target = self.inode(child);
+                if target.mode == 0 {
+                    self.problem(format!("directory {} has an entry for free inode {}", dir, child));
+                    continue;
+                }
+                if target.mode & S_IFMT != kind {
+                    self.problem(format!("directory {}: entry for inode {} has the wrong type", dir, child));
+                }
+                if target.mode & S_IFMT == S_IFDIR {
+                    refs[dir as usize] += 1; // the child's link back
+                    if target.parent == dir {
+                        pending.push(child);
+                    } else {
+                        self.problem(format!("directory {} is in directory {} but names {} as parent", child, dir, target.parent));
+                    }
+                }
+            }
+        }
+
+        for ino in 1..=count {
+            let inode = self.inode(ino);
+            let found = refs[ino as usize];
+            if inode.mode == 0 || (inode.links == 0 && found == 0) {
+                continue;
+            }
+            if found == 0 {
+                self.problem(format!("inode {} is not in any directory", ino));
+            } else if inode.links as u32 != found {
+                self.problem(format!("inode {} has {} links, but {} were found", ino, inode.links, found));
+            }
+        }
+    }
+}
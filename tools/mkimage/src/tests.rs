@@ -0,0 +1,395 @@
+//! Checks on the images built from `tools/fixtures`
+//!
+//! Each image is read back with a minimal reader of its own format and
+//! compared with the fixture tree; rfs volumes go through `rfs::check`.
+
+use std::collections::BTreeMap;
+
+use crate::disk::{crc32, PARTITION_START, SECTOR_SIZE};
+use crate::tree::{Node, NodeKind};
+use crate::{fat32, fixture_images, read_fixtures, rfs, DEFAULT_LABEL, DEFAULT_SIZE};
+
+/// Time stamp given to every entry
+const MTIME: u32 = 1_700_000_000;
+
+fn fixtures() -> Vec<Node> {
+    read_fixtures().expect("reading tools/fixtures")
+}
+
+fn image(name: &str) -> Vec<u8> {
+    let images = fixture_images(&fixtures(), MTIME).expect("building the fixture images");
+    images.into_iter().find(|(image, _)| *image == name).expect("no such fixture image").1
+}
+
+/// What an image should hold at a path
+#[derive(Debug, PartialEq, Eq)]
+enum Expected {
+    Directory,
+    File(Vec<u8>),
+    Symlink(String),
+}
+
+/// Every entry of the tree by path, with its permission bits
+fn flatten(nodes: &[Node], prefix: &str, out: &mut BTreeMap<String, (u32, Expected)>) {
+    for node in nodes {
+        let path = format!("{}{}", prefix, node.name);
+        let expected = match &node.kind {
+            NodeKind::Directory(children) => {
+                flatten(children, &format!("{}/", path), out);
+                Expected::Directory
+            }
+            NodeKind::File { data, .. } => Expected::File(data.clone()),
+            NodeKind::Symlink(target) => Expected::Symlink(target.clone()),
+        };
+        out.insert(path, (node.mode, expected));
+    }
+}
+
+fn expected_entries() -> BTreeMap<String, (u32, Expected)> {
+    let mut entries = BTreeMap::new();
+    flatten(&fixtures(), "", &mut entries);
+    entries
+}
+
+#[test]
+fn fixture_images_are_reproducible() {
+    let nodes = fixtures();
+    let first = fixture_images(&nodes, MTIME).unwrap();
+    let second = fixture_images(&read_fixtures().unwrap(), MTIME).unwrap();
+    assert_eq!(first.len(), second.len());
+    for ((name, a), (_, b)) in first.iter().zip(&second) {
+        assert!(a == b, "{} differs between two builds", name);
+    }
+}
+
+/// One entry of a newc archive
+struct CpioEntry {
+    magic: String,
+    mode: u32,
+    nlink: u32,
+    mtime: u32,
+    check: u32,
+    path: String,
+    data: Vec<u8>,
+}
+
+fn parse_cpio(archive: &[u8]) -> Vec<CpioEntry> {
+    let mut entries = Vec::new();
+    let mut at = 0;
+    loop {
+        let header = std::str::from_utf8(&archive[at..at + 110]).expect("header is not ASCII");
+        let field = |index: usize| u32::from_str_radix(&header[6 + index * 8..14 + index * 8], 16).unwrap();
+        let (name_size, file_size) = (field(11) as usize, field(6) as usize);
+
+        let name_start = at + 110;
+        let path = std::str::from_utf8(&archive[name_start..name_start + name_size - 1]).unwrap().to_string();
+        assert_eq!(archive[name_start + name_size - 1], 0, "{}: name is not NUL-terminated", path);
+        let data_start = (name_start + name_size).next_multiple_of(4);
+        let data = archive[data_start..data_start + file_size].to_vec();
+        at = (data_start + file_size).next_multiple_of(4);
+
+        if path == "TRAILER!!!" {
+            assert_eq!(at, archive.len(), "data after the trailer");
+            return entries;
+        }
+        entries.push(CpioEntry {
+            magic: header[..6].to_string(),
+            mode: field(1),
+            nlink: field(4),
+            mtime: field(5),
+            check: field(12),
+            path,
+            data,
+        });
+    }
+}
+
+fn check_cpio(name: &str, magic: &str) {
+    let entries = parse_cpio(&image(name));
+    let mut expected = expected_entries();
+
+    for entry in &entries {
+        assert_eq!(entry.magic, magic);
+        assert_eq!(entry.mtime, MTIME, "{}: time stamp", entry.path);
+        let (mode, kind) = expected.remove(&entry.path).unwrap_or_else(|| panic!("{}: not in the fixtures", entry.path));
+        assert_eq!(entry.mode & 0o7777, mode, "{}: permissions", entry.path);
+
+        let found = match entry.mode & 0o170000 {
+            0o040000 => Expected::Directory,
+            0o100000 => {
+                assert_eq!(entry.nlink, 1, "{}: link count", entry.path);
+                Expected::File(entry.data.clone())
+            }
+            0o120000 => Expected::Symlink(String::from_utf8(entry.data.clone()).unwrap()),
+            other => panic!("{}: unexpected file type {:o}", entry.path, other),
+        };
+        assert_eq!(found, kind, "{}", entry.path);
+
+        let sum = entry.data.iter().fold(0u32, |sum, &byte| sum.wrapping_add(byte as u32));
+        let want = if magic == "070702" && matches!(found, Expected::File(_)) { sum } else { 0 };
+        assert_eq!(entry.check, want, "{}: checksum", entry.path);
+    }
+    assert!(expected.is_empty(), "missing from the archive: {:?}", expected.keys().collect::<Vec<_>>());
+}
+
+#[test]
+fn cpio_round_trip() {
+    check_cpio("initramfs.cpio", "070701");
+}
+
+#[test]
+fn cpio_crc_round_trip() {
+    check_cpio("initramfs-crc.cpio", "070702");
+}
+
+#[test]
+fn rfs_image_checks_clean() {
+    let report = rfs::check(&image("rfs.img")).expect("not an rfs volume");
+    assert!(report.problems.is_empty(), "{:?}", report.problems);
+    assert_eq!(report.replayed, 0);
+    assert_eq!(report.orphans, 0);
+
+    let entries = expected_entries();
+    let directories = entries.values().filter(|(_, kind)| *kind == Expected::Directory).count();
+    // The root directory is not in the fixture list; symlinks count as files
+    assert_eq!(report.directories as usize, directories + 1);
+    assert_eq!(report.files as usize, entries.len() - directories);
+    assert_eq!(report.block_count, DEFAULT_SIZE / 4096);
+}
+
+/// A FAT32 volume being read back
+struct FatVolume<'a> {
+    image: &'a [u8],
+    sectors_per_cluster: usize,
+    data_start: usize,
+    fat: Vec<u32>,
+}
+
+/// A directory entry with its long name put back together
+struct FatEntry {
+    name: String,
+    attr: u8,
+    cluster: u32,
+    size: u32,
+}
+
+impl<'a> FatVolume<'a> {
+    /// Check the boot sector, FSInfo and FATs, then open the volume
+    fn open(image: &'a [u8]) -> Self {
+        let u16_at = |at: usize| u16::from_le_bytes(image[at..at + 2].try_into().unwrap()) as usize;
+        let u32_at = |at: usize| u32::from_le_bytes(image[at..at + 4].try_into().unwrap());
+
+        assert_eq!(&image[510..512], &[0x55, 0xAA], "boot signature");
+        assert_eq!(u16_at(11), SECTOR_SIZE, "bytes per sector");
+        assert_eq!(&image[82..90], b"FAT32   ");
+        assert_eq!(u32_at(32) as usize, image.len() / SECTOR_SIZE, "total sectors");
+        assert_eq!(u32_at(44), 2, "root cluster");
+
+        let sectors_per_cluster = image[13] as usize;
+        let reserved = u16_at(14);
+        let fat_count = image[16] as usize;
+        let fat_sectors = u32_at(36) as usize;
+        let data_start = reserved + fat_count * fat_sectors;
+        let cluster_count = (image.len() / SECTOR_SIZE - data_start) / sectors_per_cluster;
+
+        let backup = u16_at(50) * SECTOR_SIZE;
+        assert_eq!(&image[..SECTOR_SIZE], &image[backup..backup + SECTOR_SIZE], "backup boot sector");
+
+        let fat_bytes = |copy: usize| {
+            let start = (reserved + copy * fat_sectors) * SECTOR_SIZE;
+            &image[start..start + (cluster_count + 2) * 4]
+        };
+        for copy in 1..fat_count {
+            assert!(fat_bytes(0) == fat_bytes(copy), "FAT copy {} differs", copy);
+        }
+        let fat: Vec<u32> = fat_bytes(0)
+            .chunks(4)
+            .map(|entry| u32::from_le_bytes(entry.try_into().unwrap()) & 0x0FFF_FFFF)
+            .collect();
+
+        let fsinfo = u16_at(48) * SECTOR_SIZE;
+        assert_eq!(u32_at(fsinfo), 0x4161_5252, "FSInfo lead signature");
+        assert_eq!(u32_at(fsinfo + 484), 0x6141_7272, "FSInfo struct signature");
+        let free = fat[2..].iter().filter(|&&entry| entry == 0).count();
+        assert_eq!(u32_at(fsinfo + 488) as usize, free, "FSInfo free cluster count");
+
+        Self { image, sectors_per_cluster, data_start, fat }
+    }
+
+    fn chain(&self, first: u32) -> Vec<u32> {
+        let mut clusters = Vec::new();
+        let mut cluster = first;
+        while cluster != 0 && cluster < 0x0FFF_FFF8 {
+            assert!((2..self.fat.len() as u32).contains(&cluster), "cluster {} out of range", cluster);
+            assert!(clusters.len() < self.fat.len(), "cluster chain loops");
+            clusters.push(cluster);
+            cluster = self.fat[cluster as usize];
+        }
+        clusters
+    }
+
+    fn read(&self, first: u32) -> Vec<u8> {
+        let cluster_bytes = self.sectors_per_cluster * SECTOR_SIZE;
+        let mut data = Vec::new();
+        for cluster in self.chain(first) {
+            let start = self.data_start * SECTOR_SIZE + (cluster as usize - 2) * cluster_bytes;
+            data.extend_from_slice(&self.image[start..start + cluster_bytes]);
+        }
+        data
+    }
+
+    /// Entries of the directory at `first`, "." and ".." included
+    fn directory(&self, first: u32) -> Vec<FatEntry> {
+        let mut entries = Vec::new();
+        let mut long: Vec<(u8, Vec<u16>)> = Vec::new();
+        for raw in self.read(first).chunks(32) {
+            match raw[0] {
+                0 => break,
+                0xE5 => continue,
+                _ => {}
+            }
+            let attr = raw[11];
+            if attr == 0x0F {
+                let units = [1..11, 14..26, 28..32]
+                    .into_iter()
+                    .flat_map(|run| raw[run].chunks(2).map(|unit| u16::from_le_bytes([unit[0], unit[1]])).collect::<Vec<_>>())
+                    .collect();
+                long.push((raw[13], units));
+                continue;
+            }
+            if attr & 0x08 != 0 {
+                continue; // volume label
+            }
+
+            let short: &[u8; 11] = raw[..11].try_into().unwrap();
+            let name = if long.is_empty() {
+                let base = String::from_utf8_lossy(&short[..8]).trim_end().to_string();
+                let ext = String::from_utf8_lossy(&short[8..]).trim_end().to_string();
+                if ext.is_empty() { base } else { format!("{}.{}", base, ext) }
+            } else {
+                let checksum = short.iter().fold(0u8, |sum, &byte| sum.rotate_right(1).wrapping_add(byte));
+                assert!(long.iter().all(|(sum, _)| *sum == checksum), "long name checksum");
+                let units: Vec<u16> = long.drain(..).rev().flat_map(|(_, units)| units).take_while(|&unit| unit != 0).collect();
+                String::from_utf16(&units).unwrap()
+            };
+            let cluster = (u16::from_le_bytes([raw[20], raw[21]]) as u32) << 16 | u16::from_le_bytes([raw[26], raw[27]]) as u32;
+            let size = u32::from_le_bytes(raw[28..32].try_into().unwrap());
+            entries.push(FatEntry { name, attr, cluster, size });
+        }
+        entries
+    }
+
+    /// Compare the directory at `cluster` with `nodes`, recursively
+    fn compare(&self, nodes: &[Node], cluster: u32, parent: u32, path: &str) {
+        let mut entries = self.directory(cluster);
+        if path != "/" {
+            let dots: Vec<_> = entries.drain(..2).map(|entry| (entry.name, entry.cluster)).collect();
+            assert_eq!(dots, [(".".to_string(), cluster), ("..".to_string(), parent)], "{}: dot entries", path);
+        }
+
+        // FAT has no symlinks, so those are left out
+        let nodes: Vec<&Node> = nodes.iter().filter(|node| !matches!(node.kind, NodeKind::Symlink(_))).collect();
+        let names: Vec<&str> = entries.iter().map(|entry| entry.name.as_str()).collect();
+        let expected: Vec<&str> = nodes.iter().map(|node| node.name.as_str()).collect();
+        assert_eq!(names, expected, "{}: names", path);
+
+        let own = if path == "/" { 0 } else { cluster };
+        for (node, entry) in nodes.iter().zip(&entries) {
+            let path = format!("{}{}", path, node.name);
+            assert_eq!(entry.attr & 0x01 != 0, node.mode & 0o200 == 0, "{}: read-only bit", path);
+            match &node.kind {
+                NodeKind::Directory(children) => {
+                    assert!(entry.attr & 0x10 != 0, "{}: not a directory", path);
+                    self.compare(children, entry.cluster, own, &format!("{}/", path));
+                }
+                NodeKind::File { data, .. } => {
+                    assert!(entry.attr & 0x10 == 0, "{}: is a directory", path);
+                    assert_eq!(entry.size as usize, data.len(), "{}: size", path);
+                    let read = self.read(entry.cluster);
+                    assert!(&read[..data.len()] == data.as_slice(), "{}: contents", path);
+                }
+                NodeKind::Symlink(_) => unreachable!(),
+            }
+        }
+    }
+}
+
+#[test]
+fn fat32_image_holds_the_fixtures() {
+    let image = image("fat32.img");
+    assert_eq!(image.len() as u64, DEFAULT_SIZE);
+    FatVolume::open(&image).compare(&fixtures(), 2, 0, "/");
+}
+
+#[test]
+fn mbr_disk_holds_the_fat32_volume() {
+    let image = image("disk-mbr.img");
+    assert_eq!(&image[510..512], &[0x55, 0xAA]);
+
+    let entry = &image[446..462];
+    assert_eq!(entry[4], 0x0C, "partition type");
+    let first = u32::from_le_bytes(entry[8..12].try_into().unwrap()) as u64;
+    let count = u32::from_le_bytes(entry[12..16].try_into().unwrap()) as u64;
+    assert_eq!(first, PARTITION_START);
+    assert_eq!(first + count, DEFAULT_SIZE / SECTOR_SIZE as u64);
+    assert!(image[462..510].iter().all(|&byte| byte == 0), "other partition entries in use");
+
+    check_partition(&image, first, first + count - 1);
+}
+
+#[test]
+fn gpt_disk_holds_the_fat32_volume() {
+    let image = image("disk-gpt.img");
+    let last_lba = (image.len() / SECTOR_SIZE - 1) as u64;
+    assert_eq!(image[446 + 4], 0xEE, "protective MBR");
+
+    let primary = gpt_header(&image, 1);
+    let backup = gpt_header(&image, last_lba);
+    let field = |header: &[u8], at: usize| u64::from_le_bytes(header[at..at + 8].try_into().unwrap());
+    assert_eq!((field(&primary, 24), field(&primary, 32)), (1, last_lba));
+    assert_eq!((field(&backup, 24), field(&backup, 32)), (last_lba, 1));
+    assert_eq!(&primary[40..72], &backup[40..72], "usable range and disk GUID");
+
+    let entries = gpt_entries(&image, &primary);
+    assert!(entries == gpt_entries(&image, &backup), "backup partition entries differ");
+    let first = u64::from_le_bytes(entries[32..40].try_into().unwrap());
+    let last = u64::from_le_bytes(entries[40..48].try_into().unwrap());
+    assert_eq!(first, PARTITION_START);
+    assert!(first >= field(&primary, 40) && last <= field(&primary, 48), "partition outside the usable range");
+    assert!(entries[128..].iter().all(|&byte| byte == 0), "other partition entries in use");
+
+    check_partition(&image, first, last);
+}
+
+/// The 92-byte GPT header at `lba`, after checking its CRC
+fn gpt_header(image: &[u8], lba: u64) -> Vec<u8> {
+    let start = lba as usize * SECTOR_SIZE;
+    let mut header = image[start..start + 92].to_vec();
+    assert_eq!(&header[..8], b"EFI PART", "GPT header at {}", lba);
+    let crc = u32::from_le_bytes(header[16..20].try_into().unwrap());
+    header[16..20].fill(0);
+    assert_eq!(crc32(&header), crc, "GPT header CRC at {}", lba);
+    header
+}
+
+/// The partition entry array a GPT header points at, after checking its CRC
+fn gpt_entries(image: &[u8], header: &[u8]) -> Vec<u8> {
+    let lba = u64::from_le_bytes(header[72..80].try_into().unwrap()) as usize;
+    let count = u32::from_le_bytes(header[80..84].try_into().unwrap()) as usize;
+    let size = u32::from_le_bytes(header[84..88].try_into().unwrap()) as usize;
+    let entries = image[lba * SECTOR_SIZE..lba * SECTOR_SIZE + count * size].to_vec();
+    assert_eq!(crc32(&entries), u32::from_le_bytes(header[88..92].try_into().unwrap()), "GPT entries CRC");
+    entries
+}
+
+/// The partition `first..=last` holds a FAT32 volume of the fixtures that
+/// knows where it starts
+fn check_partition(image: &[u8], first: u64, last: u64) {
+    let volume = &image[first as usize * SECTOR_SIZE..(last as usize + 1) * SECTOR_SIZE];
+    let hidden = u32::from_le_bytes(volume[28..32].try_into().unwrap());
+    assert_eq!(hidden as u64, first, "hidden sectors");
+
+    let built = fat32::build(&fixtures(), (last - first + 1) as u32, DEFAULT_LABEL, first as u32, MTIME).unwrap();
+    assert!(volume == built.as_slice(), "partition differs from a FAT32 build of the fixtures");
+    FatVolume::open(volume).compare(&fixtures(), 2, 0, "/");
+}
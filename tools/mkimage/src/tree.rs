@@ -0,0 +1,85 @@
+//! Reading a host directory into memory
+//!
+//! Entries are sorted by name and ownership is dropped, so the same
+//! checkout always produces the same images.
+
+use std::fs;
+use std::io;
+#[cfg(unix)]
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+/// A file or directory to put into an image
+pub struct Node {
+    pub name: String,
+    /// Permission bits (the low 12 bits of st_mode)
+    pub mode: u32,
+    pub kind: NodeKind,
+}
+
+pub enum NodeKind {
+    Directory(Vec<Node>),
+    File {
+        data: Vec<u8>,
+        /// Host (device, inode) when the file has more than one link,
+        /// so hard links can be kept
+        link_id: Option<(u64, u64)>,
+    },
+    Symlink(String),
+}
+
+/// Read the directory at `path` and everything below it
+pub fn read_tree(path: &Path) -> io::Result<Vec<Node>> {
+    let mut entries: Vec<_> = fs::read_dir(path)?.collect::<io::Result<_>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    let mut nodes = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let name = entry.file_name().into_string().map_err(|name| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("{:?}: name is not UTF-8", name))
+        })?;
+        let meta = fs::symlink_metadata(entry.path())?;
+        let file_type = meta.file_type();
+
+        let kind = if file_type.is_dir() {
+            NodeKind::Directory(read_tree(&entry.path())?)
+        } else if file_type.is_file() {
+            NodeKind::File { data: fs::read(entry.path())?, link_id: link_id(&meta) }
+        } else if file_type.is_symlink() {
+            let target = fs::read_link(entry.path())?;
+            let target = target.into_os_string().into_string().map_err(|target| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("{:?}: target is not UTF-8", target))
+            })?;
+            NodeKind::Symlink(target)
+        } else {
+            eprintln!("mkimage: skipping {}: not a file, directory or symlink", entry.path().display());
+            continue;
+        };
+
+        nodes.push(Node { name, mode: permissions(&meta, &kind), kind });
+    }
+    Ok(nodes)
+}
+
+#[cfg(unix)]
+fn permissions(meta: &fs::Metadata, _kind: &NodeKind) -> u32 {
+    meta.mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn permissions(_meta: &fs::Metadata, kind: &NodeKind) -> u32 {
+    match kind {
+        NodeKind::File { .. } => 0o644,
+        NodeKind::Directory(_) | NodeKind::Symlink(_) => 0o755,
+    }
+}
+
+#[cfg(unix)]
+fn link_id(meta: &fs::Metadata) -> Option<(u64, u64)> {
+    (meta.nlink() > 1).then(|| (meta.dev(), meta.ino()))
+}
+
+#[cfg(not(unix))]
+fn link_id(_meta: &fs::Metadata) -> Option<(u64, u64)> {
+    None
+}